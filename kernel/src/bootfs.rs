@@ -0,0 +1,103 @@
+//! The filesystem holding the files needed to boot, like the `init` executable.
+//!
+//! The initrd is used if the bootloader provided one, otherwise the first virtio block device is
+//! mounted as a tar archive.
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use kernel_core::{
+    fs::{ArchiveFs, FileSystem, SectorReader, TarFs},
+    init::{archive::Archive, initrd_region},
+    io::{self, BlockDevice},
+    platform::{
+        boot_args::{BootArgs, Value},
+        device_tree::DeviceTree,
+        virtio::block::VirtioBlock,
+    },
+    process::{loader, Privilege},
+};
+use log::{info, warn};
+use spin::Once;
+
+use crate::{
+    kthread, process,
+    virtio::{self, MmioTransport},
+};
+
+/// The path of the `init` executable if the `init_exec_name` boot argument isn't given.
+const DEFAULT_INIT_PATH: &[u8] = b"/init";
+
+/// A boot filesystem.
+type BootFs = Box<dyn FileSystem + Send + Sync>;
+
+/// The boot filesystem, once it has been mounted.
+static BOOT_FS: Once<BootFs> = Once::new();
+
+/// A virtio block device holding a boot filesystem.
+struct Disk(Arc<VirtioBlock<MmioTransport>>);
+
+impl SectorReader for Disk {
+    fn num_sectors(&self) -> u64 {
+        self.0.num_sectors()
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        virtio::read_disk(self.0.as_ref(), sector, buf)
+    }
+}
+
+/// Mount the boot filesystem and spawn the `init` process from it.
+///
+/// Reading from a disk waits for the device, so this is done in a kernel thread.
+pub fn init(device_tree: &DeviceTree, boot_args: &BootArgs) {
+    let init_path: Vec<u8> = match boot_args.get(b"init_exec_name") {
+        Some(Value::String(name)) => name.to_vec(),
+        _ => DEFAULT_INIT_PATH.to_vec(),
+    };
+    let initrd = match initrd_region(device_tree) {
+        // SAFETY: the initrd is reserved, so it is never modified
+        Ok(Some((start, len))) => match unsafe { Archive::from_physical(start, len) } {
+            Ok(archive) => Some(archive),
+            Err(e) => {
+                warn!("failed to read initrd: {e}");
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            warn!("failed to find initrd: {e}");
+            None
+        }
+    };
+    let disk = virtio::DISKS.lock().first().cloned();
+    kthread::spawn("bootfs", move || {
+        let fs: BootFs = if let Some(archive) = initrd {
+            Box::new(ArchiveFs::new(archive))
+        } else if let Some(disk) = disk {
+            match TarFs::mount(Disk(disk)) {
+                Ok(fs) => Box::new(fs),
+                Err(e) => {
+                    warn!("failed to mount boot disk: {e}");
+                    return;
+                }
+            }
+        } else {
+            warn!("no initrd or disk to boot from");
+            return;
+        };
+        let fs = BOOT_FS.call_once(|| fs);
+        let init = match loader::read_executable(fs.as_ref(), &init_path) {
+            Ok(init) => init,
+            Err(e) => {
+                warn!("failed to read init executable: {e}");
+                return;
+            }
+        };
+        info!(
+            "found init executable {} ({} bytes)",
+            init_path.escape_ascii(),
+            init.image.len()
+        );
+        if let Err(e) = process::spawn("init", &init_path, &init, None, Privilege::Driver) {
+            warn!("failed to spawn init: {e}");
+        }
+    });
+}
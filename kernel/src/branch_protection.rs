@@ -0,0 +1,104 @@
+//! Pointer authentication and branch target identification (see
+//! [`kernel_core::platform::branch_protection`]).
+//!
+//! The kernel is built to sign its return addresses and to mark the targets of its indirect
+//! branches (see `.cargo/config.toml`), which is harmless on cores without the features.
+use core::ptr::addr_of_mut;
+
+use kernel_core::platform::branch_protection::{Features, Key, Keys};
+use spin::Once;
+
+use crate::cpu;
+
+/// Set once the kernel's key has been chosen.
+static KERNEL_KEY_CHOSEN: Once = Once::new();
+
+extern "C" {
+    /// The instruction A key the kernel signs its return addresses with, loaded by the exception
+    /// vector on entry from user space. Zero if pointer authentication is disabled.
+    static mut _kernel_pointer_auth_key: Key;
+}
+
+/// The branch protection features in use.
+pub fn features() -> Features {
+    cpu::features().branch_protection()
+}
+
+/// Turn on pointer authentication and branch target identification on the current core, if it
+/// supports them.
+///
+/// The first call chooses the kernel's key, so the kernel's entropy pool must be seeded by then.
+///
+/// Return addresses signed before this point can't be authenticated afterwards, so this is
+/// always inlined, and must be called directly by a function that never returns.
+#[allow(clippy::inline_always)]
+#[inline(always)]
+pub fn enable_for_core() {
+    let features = features();
+    if features.pointer_auth {
+        KERNEL_KEY_CHOSEN.call_once(|| {
+            let mut key = Key::random(kernel_core::rand::next_u64);
+            // a zero key tells the exception vector that pointer authentication is disabled
+            key.lo |= 1;
+            unsafe {
+                addr_of_mut!(_kernel_pointer_auth_key).write_volatile(key);
+            }
+        });
+    }
+    let bits = features.sctlr_bits();
+    if bits == 0 {
+        return;
+    }
+    unsafe {
+        if features.pointer_auth {
+            let key = addr_of_mut!(_kernel_pointer_auth_key).read_volatile();
+            core::arch::asm!(
+                "msr S3_0_C2_C1_0, {lo}",
+                "msr S3_0_C2_C1_1, {hi}",
+                lo = in(reg) key.lo,
+                hi = in(reg) key.hi,
+            );
+        }
+        core::arch::asm!(
+            "mrs {v}, SCTLR_EL1",
+            "orr {v}, {v}, {bits}",
+            "msr SCTLR_EL1, {v}",
+            "isb",
+            v = out(reg) _,
+            bits = in(reg) bits,
+        );
+    }
+}
+
+/// Load the pointer authentication keys of a user thread, other than the instruction A key, which
+/// is loaded by the exception vector on return to user space.
+///
+/// # Safety
+/// This must only be called just before returning from an exception.
+pub unsafe fn write_user_keys(keys: &Keys) {
+    let features = features();
+    if features.pointer_auth {
+        core::arch::asm!(
+            "msr S3_0_C2_C1_2, {ib_lo}",
+            "msr S3_0_C2_C1_3, {ib_hi}",
+            "msr S3_0_C2_C2_0, {da_lo}",
+            "msr S3_0_C2_C2_1, {da_hi}",
+            "msr S3_0_C2_C2_2, {db_lo}",
+            "msr S3_0_C2_C2_3, {db_hi}",
+            ib_lo = in(reg) keys.instruction_b.lo,
+            ib_hi = in(reg) keys.instruction_b.hi,
+            da_lo = in(reg) keys.data_a.lo,
+            da_hi = in(reg) keys.data_a.hi,
+            db_lo = in(reg) keys.data_b.lo,
+            db_hi = in(reg) keys.data_b.hi,
+        );
+    }
+    if features.generic_auth {
+        core::arch::asm!(
+            "msr S3_0_C2_C3_0, {lo}",
+            "msr S3_0_C2_C3_1, {hi}",
+            lo = in(reg) keys.generic.lo,
+            hi = in(reg) keys.generic.hi,
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Detection of the features of the cores and how they are arranged (see
+//! [`kernel_core::platform::cpu::Features`] and [`kernel_core::platform::cpu::Topology`]).
+use kernel_core::platform::{
+    cpu::{Features, IdRegisters, Topology},
+    device_tree::DeviceTree,
+};
+use log::{info, warn};
+use spin::Once;
+
+/// The features of the boot core, which every core is assumed to share.
+static FEATURES: Once<Features> = Once::new();
+
+/// The arrangement of the cores into clusters.
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// Read the ID registers of the current core.
+fn read_id_registers() -> IdRegisters {
+    let mut r = IdRegisters::default();
+    unsafe {
+        core::arch::asm!(
+            "mrs {midr}, MIDR_EL1",
+            "mrs {isar0}, ID_AA64ISAR0_EL1",
+            "mrs {isar1}, ID_AA64ISAR1_EL1",
+            // ID_AA64ISAR2_EL1, which reads as zero on cores that predate it
+            "mrs {isar2}, S3_0_C0_C6_2",
+            "mrs {mmfr0}, ID_AA64MMFR0_EL1",
+            "mrs {mmfr1}, ID_AA64MMFR1_EL1",
+            "mrs {mmfr2}, ID_AA64MMFR2_EL1",
+            "mrs {pfr0}, ID_AA64PFR0_EL1",
+            "mrs {pfr1}, ID_AA64PFR1_EL1",
+            midr = out(reg) r.midr,
+            isar0 = out(reg) r.isar0,
+            isar1 = out(reg) r.isar1,
+            isar2 = out(reg) r.isar2,
+            mmfr0 = out(reg) r.mmfr0,
+            mmfr1 = out(reg) r.mmfr1,
+            mmfr2 = out(reg) r.mmfr2,
+            pfr0 = out(reg) r.pfr0,
+            pfr1 = out(reg) r.pfr1,
+        );
+    }
+    r
+}
+
+/// The features of the cores, read from the current core the first time this is called.
+pub fn features() -> &'static Features {
+    FEATURES.call_once(|| Features::from_id_registers(read_id_registers()))
+}
+
+/// Detect the features of the boot core and log a summary of them.
+pub fn init() {
+    info!("CPU: {}", features());
+}
+
+/// Read the arrangement of the cores from the device tree. This doesn't need the kernel heap.
+///
+/// If the topology can't be read, nothing is assumed about it.
+pub fn init_topology(device_tree: &DeviceTree) {
+    let topology = TOPOLOGY.call_once(|| {
+        Topology::from_device_tree(device_tree).unwrap_or_else(|e| {
+            warn!("failed to read CPU topology: {e}");
+            Topology::flat([])
+        })
+    });
+    info!(
+        "CPU topology: {} cores in {} clusters",
+        topology.cores().count(),
+        topology.cluster_count()
+    );
+}
+
+/// The arrangement of the cores, read by [`init_topology`].
+pub fn topology() -> &'static Topology {
+    TOPOLOGY.wait()
+}
@@ -0,0 +1,58 @@
+//! Kernel crash diagnostics.
+use kernel_core::{
+    debug::{
+        backtrace::{Backtrace, FrameRecordReader},
+        symbols::SymbolTable,
+    },
+    memory::KERNEL_SPACE_START,
+    platform::branch_protection,
+};
+
+extern "C" {
+    static __ksymtab_start: u8;
+    static __ksymtab_end: u8;
+}
+
+/// Reads frame records from kernel stacks.
+pub struct KernelFrameReader;
+
+impl FrameRecordReader for KernelFrameReader {
+    fn read_frame_record(&self, fp: usize) -> Option<(usize, usize)> {
+        // kernel stacks are always mapped in the kernel's half of the address space
+        if fp < KERNEL_SPACE_START || fp.checked_add(16).is_none() {
+            return None;
+        }
+        let record = fp as *const usize;
+        let (next_fp, return_address) =
+            unsafe { (record.read_volatile(), record.add(1).read_volatile()) };
+        // return addresses are signed when the kernel is built with pac-ret
+        Some((
+            next_fp,
+            branch_protection::strip_kernel_pointer(return_address),
+        ))
+    }
+}
+
+/// Create a backtrace of the current kernel stack, starting with the caller.
+#[inline(never)]
+pub fn current_backtrace() -> Backtrace<'static, KernelFrameReader> {
+    let fp: usize;
+    unsafe {
+        core::arch::asm!("mov {fp}, x29", fp = out(reg) fp);
+    }
+    // this function's own frame will be gone by the time the backtrace is displayed
+    let caller_fp = KernelFrameReader
+        .read_frame_record(fp)
+        .map_or(0, |(fp, _)| fp);
+    Backtrace::new(KernelFrameReader, caller_fp)
+}
+
+/// The kernel's symbol table, if one was embedded in the image after linking.
+pub fn symbol_table() -> Option<SymbolTable<'static>> {
+    let blob = unsafe {
+        let start = core::ptr::addr_of!(__ksymtab_start);
+        let end = core::ptr::addr_of!(__ksymtab_end);
+        core::slice::from_raw_parts(start, end.offset_from(start).unsigned_abs())
+    };
+    SymbolTable::parse(blob).ok()
+}
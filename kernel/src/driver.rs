@@ -0,0 +1,115 @@
+//! The drivers built into the kernel, bound to the devices in the device tree (see
+//! [`kernel_core::driver`]).
+use alloc::{boxed::Box, vec::Vec};
+use kernel_core::{
+    driver::Registry,
+    exceptions::{interrupt::TriggerMode, InterruptController, InterruptId},
+    platform::device_tree::DeviceTree,
+};
+use log::{debug, warn};
+use spin::Mutex;
+
+use crate::{exceptions::controller, gpio, rtc, smmu, timer, uart, virtio};
+
+/// Every driver built into the kernel.
+static REGISTRY: Registry = Registry::new(&[
+    uart::DRIVER,
+    controller::gic2::DRIVER,
+    controller::gic3::DRIVER,
+    timer::DRIVER,
+    virtio::DRIVER,
+    gpio::DRIVER,
+    rtc::DRIVER,
+    smmu::DRIVER,
+]);
+
+/// An interrupt handler for a device.
+pub type DeviceHandler = Box<dyn Fn() + Send + Sync>;
+
+/// Work to do once a device's interrupt has been registered.
+pub type OnRegistered = Box<dyn FnOnce() + Send>;
+
+/// The interrupts of the devices that have been set up, as the `interrupts` property of each
+/// device's node, the handler for it, and what to do once it is registered, until they are
+/// registered.
+static INTERRUPTS: Mutex<Vec<(Vec<u8>, DeviceHandler, OnRegistered)>> = Mutex::new(Vec::new());
+
+/// Call `handler` for the first interrupt in a device's `interrupts` property in a dedicated
+/// kernel thread, once interrupts have been initialized. Handlers for devices that are not needed
+/// to keep the system running should be threaded, so that interrupts aren't masked while they run.
+pub fn add_threaded_interrupt(interrupts: &[u8], handler: DeviceHandler) {
+    add_threaded_interrupt_then(interrupts, handler, Box::new(|| {}));
+}
+
+/// Like [`add_threaded_interrupt`], but call `registered` once the interrupt has been registered.
+/// Devices that can't be used without their interrupt are made available this way, so that they
+/// never are if the interrupt can't be registered.
+pub fn add_threaded_interrupt_then(
+    interrupts: &[u8],
+    handler: DeviceHandler,
+    registered: OnRegistered,
+) {
+    INTERRUPTS
+        .lock()
+        .push((interrupts.to_vec(), handler, registered));
+}
+
+/// Take the interrupts added by drivers, with their handlers and what to do once they are
+/// registered, to be registered with the interrupt controller. Interrupts that the controller
+/// doesn't understand are dropped.
+pub fn take_interrupts(
+    intc: &impl InterruptController,
+) -> Vec<(InterruptId, TriggerMode, DeviceHandler, OnRegistered)> {
+    INTERRUPTS
+        .lock()
+        .drain(..)
+        .filter_map(|(blob, handler, registered)| {
+            let Some((id, mode)) = intc.interrupt_in_device_tree(&blob, 0) else {
+                warn!("device interrupt {blob:x?} is not understood, ignoring");
+                return None;
+            };
+            Some((id, mode, handler, registered))
+        })
+        .collect()
+}
+
+/// Bind the node at `path` to its driver, before the heap is available.
+pub fn probe_node(device_tree: &DeviceTree, path: &[u8]) {
+    match REGISTRY.probe_node(device_tree, path) {
+        Some((driver, Ok(()))) => debug!("{driver} bound to {}", path.escape_ascii()),
+        Some((driver, Err(e))) => warn!("{driver} failed on {}: {e}", path.escape_ascii()),
+        None => debug!("no driver for {}", path.escape_ascii()),
+    }
+}
+
+/// Bind every device in the device tree to its driver.
+///
+/// The memory subsystem must be initialized first, since drivers map their registers and allocate
+/// DMA buffers.
+pub fn probe_all(device_tree: &DeviceTree) {
+    let mut never_probed = 0;
+    for probed in REGISTRY.probe_all(device_tree) {
+        let path = probed.path.escape_ascii();
+        match (probed.result, probed.waiting_for) {
+            (Ok(()), _) => debug!("{} bound to {path}", probed.driver),
+            (Err(e), Some(dependency)) => {
+                never_probed += 1;
+                warn!(
+                    "{} not probed on {path}: {e} ({})",
+                    probed.driver,
+                    dependency.escape_ascii()
+                );
+            }
+            (Err(e), None) => warn!("{} failed on {path}: {e}", probed.driver),
+        }
+    }
+    if never_probed > 0 {
+        warn!("{never_probed} devices were never probed because of their dependencies");
+    }
+}
+
+/// The stream IDs of the devices with a driver in the kernel that are behind the IOMMU with the
+/// phandle `iommu`.
+pub fn iommu_streams(device_tree: &DeviceTree, iommu: u32) -> Vec<u32> {
+    REGISTRY.iommu_streams(device_tree, iommu)
+}
@@ -1,8 +1,25 @@
 //! The exception vector and handler functions.
 
-use kernel_core::{exceptions::ExceptionSyndromeRegister, process::thread::Registers};
+use core::ptr::addr_of_mut;
 
-use crate::thread::{restore_current_thread_state, save_current_thread_state};
+use kernel_core::{
+    exceptions::{
+        emergency_stack_slot, interrupt::HandlerError, DataAbortCause, ExceptionSyndromeRegister,
+        EMERGENCY_STACK_SLOTS,
+    },
+    memory::VirtualAddress,
+    platform::{branch_protection::Key, cpu::Id as CpuId},
+    process::{
+        debug::DebugEventKind,
+        system_call::SystemCall,
+        thread::{
+            kernel_thread::KernelThreadCall, FloatingPointRegisters, Registers, Scheduler as _,
+        },
+    },
+};
+use log::warn;
+
+use crate::thread::{read_saved_program_status, switch_threads_around, SCHEDULER};
 
 // assembly definition of the exception vector table and the low level code that installs the table
 // and the low level handlers that calls into the Rust code.
@@ -17,29 +34,163 @@ extern "C" {
     /// # Safety
     /// This function should be safe as long as `table.S` is correct.
     pub fn install_exception_vector();
+
+    /// The top of the emergency stack of each core, by [`emergency_stack_slot`], followed by an
+    /// unused word.
+    static mut _emergency_stacks: [[usize; 2]; EMERGENCY_STACK_SLOTS];
+}
+
+/// Set the stack that the exception vector switches to if the kernel stack of the core `id`
+/// overflows, with `top` as its initial stack pointer, or remove it if `top` is `None`.
+///
+/// Returns false if the core can't have an emergency stack.
+///
+/// # Safety
+/// The stack must stay mapped until it is removed, and must not be changed while the core `id` is
+/// running.
+pub unsafe fn set_emergency_stack(id: CpuId, top: Option<VirtualAddress>) -> bool {
+    let Some(slot) = emergency_stack_slot(id) else {
+        return false;
+    };
+    addr_of_mut!(_emergency_stacks[slot][0]).write_volatile(top.map_or(0, usize::from));
+    true
+}
+
+/// The exception frame that the exception vector pushes on the kernel stack.
+#[repr(C)]
+pub struct ExceptionFrame {
+    /// The interrupted thread's general purpose registers.
+    pub registers: Registers,
+    _padding: u64,
+    /// The instruction A pointer authentication key of the user thread that runs when the exception
+    /// returns. The exception vector saves it on entry from user space and loads it on return to
+    /// user space, if pointer authentication is enabled.
+    pub user_instruction_key: Key,
+    /// The interrupted thread's floating point and SIMD registers.
+    pub fp: FloatingPointRegisters,
 }
 
+// the exception vector pushes and pops frames of exactly this layout
+const _: () = assert!(core::mem::offset_of!(ExceptionFrame, fp) == 34 * 8);
+const _: () = assert!(size_of::<ExceptionFrame>() == 34 * 8 + 32 * 16 + 2 * 8);
+
 #[no_mangle]
-unsafe extern "C" fn handle_synchronous_exception(regs: *mut Registers, esr: usize, far: usize) {
+unsafe extern "C" fn handle_synchronous_exception(
+    frame: *mut ExceptionFrame,
+    esr: usize,
+    far: usize,
+) {
+    let regs = frame.cast::<Registers>();
+    let esr = ExceptionSyndromeRegister(esr as u64);
+    // only kernel threads make calls for now, from EL1
+    if let Some(immediate) = esr
+        .system_call_immediate()
+        .filter(|_| read_saved_program_status().el() == 1)
+    {
+        let frame = frame
+            .as_mut()
+            .expect("asm exception vector code passes non-null ptr to exception frame");
+        let call = KernelThreadCall::decode(immediate, &frame.registers)
+            .unwrap_or_else(|| panic!("unknown kernel thread call {immediate}"));
+        switch_threads_around(frame, || crate::kthread::handle_call(call));
+        return;
+    }
+    if read_saved_program_status().el() == 0 {
+        let frame = frame
+            .as_mut()
+            .expect("asm exception vector code passes non-null ptr to exception frame");
+        switch_threads_around(frame, || handle_user_exception(&esr, far));
+        return;
+    }
+    if esr.classify_data_abort(
+        VirtualAddress::from(far),
+        crate::memory::is_kernel_stack_guard,
+    ) == Some(DataAbortCause::StackOverflow)
+    {
+        panic!(
+            "kernel stack overflow! FAR={far:x}, registers = {:x?}",
+            regs.as_ref()
+        );
+    }
     panic!(
-        "synchronous exception! {}, FAR={far:x}, registers = {:x?}",
-        ExceptionSyndromeRegister(esr as u64),
+        "synchronous exception! {esr}, FAR={far:x}, registers = {:x?}",
         regs.as_ref()
     );
 }
 
+/// Handle a synchronous exception caused by the current thread, which is a user space thread.
+///
+/// System calls the process is permitted to make are handled by
+/// [`crate::process::handle_system_call`]. A fault that the process may be able to recover from is
+/// delivered to its fault handler, if it has one. Otherwise, a fault, a `brk` instruction or a
+/// completed single step stops the thread for its process' debugger. A step with no debugger is
+/// left over from a debugger that has detached, so the thread carries on. Anything else, including
+/// a system call the process isn't permitted to make, makes the process exit.
+fn handle_user_exception(esr: &ExceptionSyndromeRegister, far: usize) {
+    let scheduler = SCHEDULER.wait();
+    let thread = scheduler.current_thread();
+    let Some(process) = crate::process::process_of(&thread) else {
+        // the process exited on another core while the thread was running, so it has exited too
+        scheduler.next_time_slice();
+        return;
+    };
+    let call = esr.system_call_immediate().and_then(|immediate| {
+        SystemCall::decode(immediate, &thread.processor_state.lock().registers)
+            .filter(|call| call.is_permitted(&process))
+    });
+    if let Some(call) = call {
+        crate::process::handle_system_call(&thread, &process, call);
+        return;
+    }
+    // the program counter was saved when the exception was taken
+    let address = thread.processor_state.lock().program_counter;
+    let fault_address = VirtualAddress::from(far);
+    let event = if let Some(immediate) = esr.breakpoint_immediate() {
+        DebugEventKind::Breakpoint { address, immediate }
+    } else if esr.is_software_step() {
+        DebugEventKind::Step { address }
+    } else {
+        if let Some(fault) = esr.classify_user_fault() {
+            if process.deliver_fault(&thread, fault, fault_address, esr.0) {
+                return;
+            }
+        }
+        DebugEventKind::Fault {
+            address,
+            fault_address,
+            syndrome: esr.0,
+        }
+    };
+    if !process.report_debug_event(&thread, event) {
+        if esr.is_software_step() {
+            return;
+        }
+        warn!("unhandled exception in user thread {thread}: {esr}, FAR={far:x}");
+        crate::process::exit(&thread, crate::process::UNHANDLED_EXCEPTION_EXIT_CODE);
+    }
+    // the thread has stopped or exited, so switch away from it before it runs again
+    scheduler.next_time_slice();
+}
+
 #[no_mangle]
-unsafe extern "C" fn handle_interrupt(regs: *mut Registers, _esr: usize, _far: usize) {
-    let regs = regs
+unsafe extern "C" fn handle_interrupt(frame: *mut ExceptionFrame, _esr: usize, _far: usize) {
+    let frame = frame
         .as_mut()
-        .expect("asm exception vector code passes non-null ptr to registers object");
-    save_current_thread_state(regs);
-    super::interrupt::HANDLER_POLICY
-        .get()
-        .expect("interrupt handler policy to be initialized before interrupts are enabled")
-        .process_interrupts()
-        .expect("interrupt handlers to complete successfully");
-    restore_current_thread_state(regs);
+        .expect("asm exception vector code passes non-null ptr to exception frame");
+    switch_threads_around(frame, || {
+        let result = super::interrupt::HANDLER_POLICY
+            .get()
+            .expect("interrupt handler policy to be initialized before interrupts are enabled")
+            .process_interrupts();
+        // unknown interrupts have already been finished and counted as spurious, so the other
+        // interrupts were still handled
+        if let Err(HandlerError::UnknownInterrupt(id)) = result {
+            warn!("interrupt {id} has no handler");
+        }
+        crate::watchdog::heartbeat();
+        crate::lockup::wake();
+        crate::memory::check_core_stack();
+    });
 }
 
 #[no_mangle]
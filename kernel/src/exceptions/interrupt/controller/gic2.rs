@@ -1,24 +1,35 @@
 //! Driver for ARM Generic Interrupt Controller version 2.
 //!
+//! Message-signaled interrupts are supported with the `GICv2m` extension, which reserves a range of
+//! SPIs that can be triggered by writing to a doorbell register in the `v2m` frame.
+//!
 //! # Reference Documentation
 //! - `GICv2` specification: <https://developer.arm.com/documentation/ihi0048>
 //! - Device tree node: [Linux Kernel Documentation](https://git.kernel.org/pub/scm/linux/kernel/git/stable/linux.git/tree/Documentation/devicetree/bindings/interrupt-controller/arm,gic.yaml)
+//! - `v2m` frame device tree node: [Linux Kernel Documentation](https://git.kernel.org/pub/scm/linux/kernel/git/stable/linux.git/tree/Documentation/devicetree/bindings/interrupt-controller/arm,gic-v2m-frame.yaml)
 
-use byteorder::{BigEndian, ByteOrder};
 use kernel_core::{
-    exceptions::interrupt::{Config, Controller, Id, TriggerMode},
+    driver::Driver,
+    exceptions::interrupt::{
+        msi::MsiFrame, Acknowledged, Config, Controller, CoreSet, Id, IpiTarget, Msi, TriggerMode,
+    },
     memory::PhysicalAddress,
     platform::device_tree::{
         iter::NodePropertyIter, ParseError, PropertyNotFoundSnafu, UnexpectedValueSnafu,
     },
+    sync::Mutex,
 };
 use log::{debug, trace};
 use snafu::{ensure, OptionExt as _};
-use spin::Mutex;
+
+use crate::memory::map_device;
+
+use super::parse_interrupt_specifier;
 
 pub struct GenericV2 {
     distributor_base: Mutex<*mut u32>,
     cpu_base: *mut u32,
+    msi_frame: Option<MsiFrame>,
 }
 
 /// SAFETY: The GIC CPU registers which are unprotected are actually unique for each core, so they
@@ -41,12 +52,25 @@ const COMPATIBLE: &[&[u8]] = &[
     b"qcom,msm-qgic2",
 ];
 
+/// The driver for the controller, bound to the system interrupt controller.
+pub const DRIVER: Driver = Driver {
+    name: "gicv2",
+    compatible: COMPATIBLE,
+    probe: super::probe,
+};
+
 impl GenericV2 {
     /// Create the GIC driver from configuration found in the device tree.
-    pub fn in_device_tree(node: NodePropertyIter) -> Result<Self, ParseError> {
+    /// If present, `v2m_node` is the `v2m` child node of the controller that provides MSIs.
+    pub fn in_device_tree<'dt>(
+        node: NodePropertyIter<'dt>,
+        v2m_node: Option<NodePropertyIter<'dt>>,
+    ) -> Result<Self, ParseError<'dt>> {
         let mut found_marker_property = false;
         let mut dist_base = PhysicalAddress::null();
         let mut cpu_base = PhysicalAddress::null();
+        let mut dist_len = 0;
+        let mut cpu_len = 0;
 
         for (name, value) in node {
             match name {
@@ -83,18 +107,22 @@ impl GenericV2 {
                 b"reg" => {
                     let registers = value.as_reg(name)?;
                     let mut regs = registers.iter();
-                    let (dist_base_raw, _) = regs.next().with_context(|| UnexpectedValueSnafu {
-                        name,
-                        value: value.clone(),
-                        reason: "distributor register region to be present",
-                    })?;
+                    let (dist_base_raw, dist_len_raw) =
+                        regs.next().with_context(|| UnexpectedValueSnafu {
+                            name,
+                            value: value.clone(),
+                            reason: "distributor register region to be present",
+                        })?;
                     dist_base = dist_base_raw.into();
-                    let (cpu_base_raw, _) = regs.next().with_context(|| UnexpectedValueSnafu {
-                        name,
-                        value: value.clone(),
-                        reason: "cpu register region to be present",
-                    })?;
+                    dist_len = dist_len_raw;
+                    let (cpu_base_raw, cpu_len_raw) =
+                        regs.next().with_context(|| UnexpectedValueSnafu {
+                            name,
+                            value: value.clone(),
+                            reason: "cpu register region to be present",
+                        })?;
                     cpu_base = cpu_base_raw.into();
+                    cpu_len = cpu_len_raw;
                 }
                 _ => {}
             }
@@ -123,12 +151,65 @@ impl GenericV2 {
         );
 
         Ok(Self {
-            distributor_base: Mutex::new(dist_base.cast().into()),
-            cpu_base: cpu_base.cast().into(),
+            distributor_base: Mutex::new(map_device(dist_base, dist_len).cast()),
+            cpu_base: map_device(cpu_base, cpu_len).cast(),
+            msi_frame: v2m_node.map(msi_frame_in_device_tree).transpose()?,
         })
     }
 }
 
+/// Compatible string for `GICv2m` MSI frames.
+const V2M_COMPATIBLE: &[u8] = b"arm,gic-v2m-frame";
+
+/// Configure the MSIs provided by a `GICv2m` frame from its device tree node.
+fn msi_frame_in_device_tree(node: NodePropertyIter) -> Result<MsiFrame, ParseError> {
+    let mut frame = None;
+    let mut base_spi = None;
+    let mut num_spis = None;
+
+    for (name, value) in node {
+        match name {
+            b"compatible" => {
+                ensure!(
+                    value.as_strings(name)?.contains(V2M_COMPATIBLE),
+                    UnexpectedValueSnafu {
+                        name,
+                        value,
+                        reason: "incompatible"
+                    }
+                );
+            }
+            b"reg" => {
+                frame = Some(value.as_reg(name)?.iter().next().with_context(|| {
+                    UnexpectedValueSnafu {
+                        name,
+                        value: value.clone(),
+                        reason: "frame register region to be present",
+                    }
+                })?);
+            }
+            // these properties override the values in `MSI_TYPER` for broken hardware
+            b"arm,msi-base-spi" => base_spi = Some(*value.as_u32(name)?),
+            b"arm,msi-num-spis" => num_spis = Some(*value.as_u32(name)?),
+            _ => {}
+        }
+    }
+
+    let (frame_base, frame_len) = frame.context(PropertyNotFoundSnafu { name: "reg" })?;
+    let frame_base = PhysicalAddress::from(frame_base);
+    let frame: *mut u32 = map_device(frame_base, frame_len).cast();
+    let typer = unsafe { frame.add(v2m_regs::MSI_TYPER).read_volatile() };
+    let base_spi = base_spi.unwrap_or((typer >> 16) & 0x3ff);
+    let num_spis = num_spis.unwrap_or(typer & 0x3ff);
+    debug!("GICv2m frame @ {frame_base:?} provides {num_spis} MSIs starting at {base_spi}");
+
+    Ok(MsiFrame::new(
+        frame_base.byte_add(v2m_regs::MSI_SETSPI_NS * size_of::<u32>()),
+        base_spi,
+        num_spis as usize,
+    ))
+}
+
 fn id_to_bit_offset(id: Id) -> (usize, u32) {
     ((id / 32) as usize, (id % 32))
 }
@@ -183,36 +264,7 @@ impl Controller for GenericV2 {
     }
 
     fn interrupt_in_device_tree(&self, data: &[u8], index: usize) -> Option<(Id, TriggerMode)> {
-        if (index + 1) * 12 > data.len() {
-            return None;
-        }
-
-        let d = &data[index * 12..(index + 1) * 12];
-        let first_cell = BigEndian::read_u32(&d[0..4]);
-        let second_cell = BigEndian::read_u32(&d[4..8]);
-        let flags = d[11];
-
-        let id = match first_cell {
-            0 => {
-                // SPI interrupt
-                // defined in device tree as 0-987, mapped to interrupt ids 32-1019
-                32 + second_cell
-            }
-            1 => {
-                // PPI interrupt
-                // defined in device tree as 0-15, mapped to interrupt ids 16-31
-                16 + second_cell
-            }
-            _ => return None,
-        };
-
-        let trigger_mode = match flags {
-            0b0001 | 0b0010 => TriggerMode::Edge,
-            0b0100 | 0b1000 => TriggerMode::Level,
-            _ => return None,
-        };
-
-        Some((id, trigger_mode))
+        parse_interrupt_specifier(data, index)
     }
 
     fn configure(&self, id: Id, config: &Config) {
@@ -225,9 +277,23 @@ impl Controller for GenericV2 {
                 id,
                 config.priority,
             );
+        }
+        drop(distributor_base);
+        self.set_affinity(id, config.targets);
+    }
 
-            // for now, make sure that all CPUs recieve this interrupt.
-            write_byte_for_id(*distributor_base, dist_regs::ITARGETSR_N, id, 0xff);
+    fn set_affinity(&self, id: Id, cores: CoreSet) {
+        // the target registers for private interrupts are read-only
+        if id < 32 {
+            return;
+        }
+        debug!("routing interrupt {id} to {cores:?}");
+        // GICv2 supports at most 8 cores, and CPU interface numbers match the order of the cores
+        // in the device tree
+        let targets = cores.bits().to_le_bytes()[0];
+        let distributor_base = self.distributor_base.lock();
+        unsafe {
+            write_byte_for_id(*distributor_base, dist_regs::ITARGETSR_N, id, targets);
         }
     }
 
@@ -254,18 +320,47 @@ impl Controller for GenericV2 {
         }
     }
 
-    fn ack_interrupt(&self) -> Option<Id> {
-        let id = unsafe { self.cpu_base.add(cpu_regs::IAR).read_volatile() };
+    fn ack_interrupt(&self) -> Option<Acknowledged> {
+        let raw = unsafe { self.cpu_base.add(cpu_regs::IAR).read_volatile() };
+        // bits 12:10 hold the core that sent an SGI, and must be written back to EOIR unchanged
+        let id = raw & IAR_INTERRUPT_ID;
         if id == INTID_NONE_PENDING {
             None
         } else {
-            Some(id)
+            Some(Acknowledged { id, raw })
         }
     }
 
-    fn finish_interrupt(&self, id: Id) {
+    fn finish_interrupt(&self, interrupt: Acknowledged) {
         unsafe {
-            self.cpu_base.add(cpu_regs::EOIR).write_volatile(id);
+            self.cpu_base
+                .add(cpu_regs::EOIR)
+                .write_volatile(interrupt.raw);
+        }
+    }
+
+    fn send_ipi(&self, id: Id, target: IpiTarget) {
+        // bits 25:24 select how the target list in bits 23:16 is interpreted
+        let value = match target {
+            IpiTarget::Core(index) => (1 << (16 + index)) | id,
+            IpiTarget::AllButSelf => (0b01 << 24) | id,
+        };
+        let distributor_base = self.distributor_base.lock();
+        unsafe {
+            distributor_base.add(dist_regs::SGIR).write_volatile(value);
+        }
+    }
+
+    fn allocate_msi(&self) -> Option<Msi> {
+        let msi = self.msi_frame.as_ref()?.allocate();
+        debug!("allocated MSI {msi:?}");
+        msi
+    }
+
+    fn free_msi(&self, id: Id) {
+        debug!("freeing MSI {id}");
+        if let Some(frame) = self.msi_frame.as_ref() {
+            frame.free(id);
         }
     }
 }
@@ -324,3 +419,14 @@ mod cpu_regs {
 
 /// Interupt ID that represents no interrupt pending.
 const INTID_NONE_PENDING: Id = 1023;
+
+/// The bits of `GICC_IAR` that hold the interrupt ID.
+const IAR_INTERRUPT_ID: u32 = 0x3ff;
+
+/// Register offsets for the `GICv2m` MSI frame (relative to its base address, by u32s).
+#[allow(unused, missing_docs)]
+mod v2m_regs {
+    pub const MSI_TYPER: usize = 0x008 >> 2;
+    pub const MSI_SETSPI_NS: usize = 0x040 >> 2;
+    pub const MSI_IIDR: usize = 0xfcc >> 2;
+}
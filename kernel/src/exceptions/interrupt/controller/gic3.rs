@@ -0,0 +1,485 @@
+//! Driver for ARM Generic Interrupt Controller version 3 (and version 4, which is compatible).
+//!
+//! Unlike `GICv2`, the CPU interface is accessed via system registers and each core has its own
+//! redistributor that manages its private (SGI and PPI) interrupts. Shared peripheral interrupts
+//! are routed by affinity.
+//!
+//! # Reference Documentation
+//! - `GICv3`/`GICv4` specification: <https://developer.arm.com/documentation/ihi0069>
+//! - Device tree node: [Linux Kernel Documentation](https://git.kernel.org/pub/scm/linux/kernel/git/stable/linux.git/tree/Documentation/devicetree/bindings/interrupt-controller/arm,gic-v3.yaml)
+
+use alloc::vec::Vec;
+use kernel_core::{
+    driver::Driver,
+    exceptions::interrupt::{
+        Acknowledged, Config, Controller, CoreSet, Id, IpiTarget, TriggerMode,
+    },
+    memory::PhysicalAddress,
+    platform::cpu::CoreInfo,
+    platform::device_tree::{
+        iter::NodePropertyIter, ParseError, PropertyNotFoundSnafu, UnexpectedValueSnafu,
+    },
+    sync::Mutex,
+};
+use log::{debug, trace};
+use snafu::{ensure, OptionExt as _};
+
+use crate::memory::map_device;
+
+use super::parse_interrupt_specifier;
+
+pub struct GenericV3 {
+    distributor_base: Mutex<*mut u32>,
+    redistributors_base: *mut u32,
+    redistributors_length: usize,
+    /// Distance in bytes between each core's redistributor.
+    redistributor_stride: usize,
+    /// The affinity of each core in the system, in the format used by `IROUTER`.
+    core_affinities: Vec<u64>,
+}
+
+/// SAFETY: Each core only accesses its own redistributor, so they do not need to be synchronized.
+unsafe impl Send for GenericV3 {}
+unsafe impl Sync for GenericV3 {}
+
+/// A list of device tree `compatible` strings (see section 2.3.1 of the spec) that this driver is compatible with.
+pub const COMPATIBLE: &[&[u8]] = &[b"arm,gic-v3" as &[u8], b"qcom,msm8996-gic-v3"];
+
+/// The driver for the controller, bound to the system interrupt controller.
+pub const DRIVER: Driver = Driver {
+    name: "gicv3",
+    compatible: COMPATIBLE,
+    probe: super::probe,
+};
+
+/// Size of a single redistributor frame (`RD_base` or `SGI_base`) in bytes.
+const FRAME_SIZE: usize = 0x1_0000;
+
+/// Value of the acknowledge register when there is no pending interrupt.
+const INTID_NONE_PENDING: Id = 1023;
+
+impl GenericV3 {
+    /// Create the GIC driver from configuration found in the device tree.
+    /// The `cores` are the cores in the system that interrupts can be routed to.
+    pub fn in_device_tree<'dt>(
+        node: NodePropertyIter<'dt>,
+        cores: &[CoreInfo],
+    ) -> Result<Self, ParseError<'dt>> {
+        let mut found_marker_property = false;
+        let mut dist = None;
+        let mut redists = None;
+        let mut redistributor_stride = None;
+
+        for (name, value) in node {
+            match name {
+                b"compatible" => {
+                    let strings = value.as_strings(name)?;
+                    ensure!(
+                        strings.iter().any(|model_name| COMPATIBLE
+                            .iter()
+                            .any(|supported_model_name| model_name.to_bytes()
+                                == *supported_model_name)),
+                        UnexpectedValueSnafu {
+                            name,
+                            value,
+                            reason: "incompatible"
+                        }
+                    );
+                    debug!("GICv3 compatible device: {strings:?}");
+                }
+                b"#interrupt-cells" => {
+                    let n = value.as_bytes(name)?;
+                    ensure!(
+                        n.len() == 4 && n[3] == 3,
+                        UnexpectedValueSnafu {
+                            name,
+                            value,
+                            reason: "driver supports GICv3 with #interrupt-cells=3 only"
+                        }
+                    );
+                }
+                b"#redistributor-regions" => {
+                    let n = value.as_bytes(name)?;
+                    ensure!(
+                        n.len() == 4 && n[3] == 1,
+                        UnexpectedValueSnafu {
+                            name,
+                            value,
+                            reason: "driver supports a single redistributor region only"
+                        }
+                    );
+                }
+                b"redistributor-stride" => {
+                    let n = value.as_bytes(name)?;
+                    ensure!(
+                        n.len() == 8,
+                        UnexpectedValueSnafu {
+                            name,
+                            value,
+                            reason: "stride to be a u64"
+                        }
+                    );
+                    redistributor_stride =
+                        Some(usize::try_from(u64::from_be_bytes(n.try_into().unwrap())).unwrap());
+                }
+                b"interrupt-controller" => {
+                    found_marker_property = true;
+                }
+                b"reg" => {
+                    let registers = value.as_reg(name)?;
+                    let mut regs = registers.iter();
+                    dist = Some(regs.next().with_context(|| UnexpectedValueSnafu {
+                        name,
+                        value: value.clone(),
+                        reason: "distributor register region to be present",
+                    })?);
+                    redists = Some(regs.next().with_context(|| UnexpectedValueSnafu {
+                        name,
+                        value: value.clone(),
+                        reason: "redistributor register region to be present",
+                    })?);
+                }
+                _ => {}
+            }
+        }
+
+        // per spec this property must be present, check for sanity
+        ensure!(
+            found_marker_property,
+            PropertyNotFoundSnafu {
+                name: "interrupt-controller"
+            }
+        );
+
+        let (dist_base, dist_len) = dist.context(PropertyNotFoundSnafu {
+            name: "distributor base address",
+        })?;
+        let (redist_base, redist_len) = redists.context(PropertyNotFoundSnafu {
+            name: "redistributor base address",
+        })?;
+
+        Ok(Self {
+            distributor_base: Mutex::new(
+                map_device(PhysicalAddress::from(dist_base), dist_len).cast(),
+            ),
+            redistributors_base: map_device(PhysicalAddress::from(redist_base), redist_len).cast(),
+            redistributors_length: redist_len,
+            redistributor_stride: redistributor_stride.unwrap_or(0),
+            core_affinities: cores
+                .iter()
+                .map(|core| core.id as u64 & dist_regs::IROUTER_AFFINITY_MASK)
+                .collect(),
+        })
+    }
+
+    /// Find the `RD_base` frame of the redistributor for the current core.
+    fn current_redistributor(&self) -> *mut u32 {
+        let affinity = current_affinity();
+        let mut offset = 0;
+        while offset < self.redistributors_length {
+            unsafe {
+                let rd = self.redistributors_base.byte_add(offset);
+                // the upper word of `GICR_TYPER` contains the affinity of the redistributor
+                let typer = rd.add(redist_regs::TYPER).read_volatile();
+                if rd.add(redist_regs::TYPER + 1).read_volatile() == affinity {
+                    return rd;
+                }
+                if typer & redist_regs::TYPER_LAST != 0 {
+                    break;
+                }
+                // GICv4 redistributors have two extra frames for virtual LPIs
+                offset += if self.redistributor_stride != 0 {
+                    self.redistributor_stride
+                } else if typer & redist_regs::TYPER_VLPIS != 0 {
+                    4 * FRAME_SIZE
+                } else {
+                    2 * FRAME_SIZE
+                };
+            }
+        }
+        panic!("no GICv3 redistributor for core with affinity {affinity:x}");
+    }
+
+    /// Find the `SGI_base` frame of the redistributor for the current core.
+    fn current_sgi_frame(&self) -> *mut u32 {
+        unsafe { self.current_redistributor().byte_add(FRAME_SIZE) }
+    }
+
+    /// Call `f` with the base of the registers that control interrupt `id` on this core, which is
+    /// either the redistributor for private interrupts or the distributor for shared interrupts.
+    fn with_registers_for<R>(&self, id: Id, f: impl FnOnce(*mut u32) -> R) -> R {
+        if id < 32 {
+            f(self.current_sgi_frame())
+        } else {
+            f(*self.distributor_base.lock())
+        }
+    }
+}
+
+/// Returns the affinity of the current core in the format used by `GICR_TYPER`, `Aff3.Aff2.Aff1.Aff0`.
+fn current_affinity() -> u32 {
+    let mut mpidr: u64;
+    unsafe {
+        core::arch::asm!(
+            "mrs {mpidr}, MPIDR_EL1",
+            mpidr = out(reg) mpidr
+        );
+    }
+    // the result is masked to 32 bits, so truncation is intended
+    #[allow(clippy::cast_possible_truncation)]
+    let affinity = ((mpidr & 0x00ff_ffff) | (((mpidr >> 32) & 0xff) << 24)) as u32;
+    affinity
+}
+
+/// Wait for a register write to complete, as indicated by the register write pending bit of `ctlr`.
+unsafe fn wait_for_rwp(ctlr: *mut u32) {
+    while ctlr.read_volatile() & (1 << 31) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+fn id_to_bit_offset(id: Id) -> (usize, u32) {
+    ((id / 32) as usize, (id % 32))
+}
+
+/// Set the bit flag of `register` for the interrupt `id` high.
+unsafe fn write_bit_for_id(interface: *mut u32, register: usize, id: Id) {
+    let (word_offset, bit_offset) = id_to_bit_offset(id);
+    let ptr = interface.add(register).add(word_offset);
+    trace!(
+        "writing GIC register bit 0x{:x} for id={id} (byte=0x{word_offset:x}, bit={bit_offset})",
+        ptr as usize
+    );
+    ptr.write_volatile(1 << bit_offset);
+}
+
+/// Set the byte of `register` for interrupt `id`.
+unsafe fn write_byte_for_id(interface: *mut u32, register: usize, id: Id, value: u8) {
+    trace!("writing GIC register byte {interface:x?}+{register:x} for id={id}, value={value:x}");
+    interface
+        .add(register)
+        .cast::<u8>()
+        .add(id as usize)
+        .write_volatile(value);
+}
+
+impl Controller for GenericV3 {
+    /// Prepare the controller for handling interrupts generally.
+    fn global_initialize(&self) {
+        let dist_base = self.distributor_base.lock();
+        debug!("Initializing GICv3 Distributor @ {dist_base:x?}");
+        unsafe {
+            // disable the distributor while it is configured
+            dist_base.add(dist_regs::CTLR).write_volatile(0);
+            wait_for_rwp(dist_base.add(dist_regs::CTLR));
+
+            // put all shared interrupts in group 1
+            let num_lines = ((dist_base.add(dist_regs::TYPER).read_volatile() & 0x1f) + 1) as usize;
+            for i in 1..num_lines {
+                dist_base
+                    .add(dist_regs::IGROUPR_N + i)
+                    .write_volatile(0xffff_ffff);
+            }
+
+            // enable affinity routing and group 1 interrupts
+            dist_base
+                .add(dist_regs::CTLR)
+                .write_volatile(dist_regs::CTLR_ARE | dist_regs::CTLR_ENABLE_GRP1);
+            wait_for_rwp(dist_base.add(dist_regs::CTLR));
+        }
+    }
+
+    /// Prepare the controller for handling interrupts for the current core.
+    fn initialize_for_core(&self) {
+        let rd = self.current_redistributor();
+        debug!("Initializing GICv3 redistributor @ {rd:x?}");
+        unsafe {
+            // wake up the redistributor
+            let waker = rd.add(redist_regs::WAKER);
+            waker.write_volatile(waker.read_volatile() & !redist_regs::WAKER_PROCESSOR_SLEEP);
+            while waker.read_volatile() & redist_regs::WAKER_CHILDREN_ASLEEP != 0 {
+                core::hint::spin_loop();
+            }
+
+            // put all private interrupts in group 1
+            self.current_sgi_frame()
+                .add(dist_regs::IGROUPR_N)
+                .write_volatile(0xffff_ffff);
+
+            core::arch::asm!(
+                // enable the system register interface
+                "mrs {tmp}, S3_0_C12_C12_5", // ICC_SRE_EL1
+                "orr {tmp}, {tmp}, #1",
+                "msr S3_0_C12_C12_5, {tmp}",
+                "isb",
+                // set minimum priority to lowest possible
+                "mov {tmp}, #0xff",
+                "msr S3_0_C4_C6_0, {tmp}", // ICC_PMR_EL1
+                // enable group 1 interrupts
+                "mov {tmp}, #1",
+                "msr S3_0_C12_C12_7, {tmp}", // ICC_IGRPEN1_EL1
+                "isb",
+                tmp = out(reg) _
+            );
+        }
+    }
+
+    fn interrupt_in_device_tree(&self, data: &[u8], index: usize) -> Option<(Id, TriggerMode)> {
+        parse_interrupt_specifier(data, index)
+    }
+
+    fn configure(&self, id: Id, config: &Config) {
+        debug!("configuring interrupt {id} {config:?}");
+        self.with_registers_for(id, |regs| unsafe {
+            write_byte_for_id(regs, dist_regs::IPRIORITYR_N, id, config.priority);
+
+            // two bits per interrupt, where the high bit selects edge triggering
+            let cfg = regs.add(dist_regs::ICFGR_N + (id / 16) as usize);
+            let shift = (id % 16) * 2 + 1;
+            let value = match config.mode {
+                TriggerMode::Level => cfg.read_volatile() & !(1 << shift),
+                TriggerMode::Edge => cfg.read_volatile() | (1 << shift),
+            };
+            cfg.write_volatile(value);
+        });
+        self.set_affinity(id, config.targets);
+    }
+
+    fn set_affinity(&self, id: Id, cores: CoreSet) {
+        // private interrupts are always delivered to their own core
+        if id < 32 {
+            return;
+        }
+        debug!("routing interrupt {id} to {cores:?}");
+        // affinity routing can only target a single core or any core
+        let route = if cores.contains_all(self.core_affinities.len()) {
+            dist_regs::IROUTER_ANY
+        } else {
+            cores
+                .iter()
+                .find_map(|i| self.core_affinities.get(i))
+                .copied()
+                .unwrap_or(dist_regs::IROUTER_ANY)
+        };
+        let distributor_base = self.distributor_base.lock();
+        unsafe {
+            // `IROUTER` registers are 64-bit, so they are always aligned
+            #[allow(clippy::cast_ptr_alignment)]
+            distributor_base
+                .add(dist_regs::IROUTER_N + 2 * id as usize)
+                .cast::<u64>()
+                .write_volatile(route);
+        }
+    }
+
+    fn enable(&self, id: Id) {
+        debug!("enable interrupt {id}");
+        self.with_registers_for(id, |regs| unsafe {
+            write_bit_for_id(regs, dist_regs::ISENABLER_N, id);
+        });
+    }
+
+    fn disable(&self, id: Id) {
+        debug!("disable interrupt {id}");
+        self.with_registers_for(id, |regs| unsafe {
+            write_bit_for_id(regs, dist_regs::ICENABLER_N, id);
+        });
+    }
+
+    fn clear_pending(&self, id: Id) {
+        self.with_registers_for(id, |regs| unsafe {
+            write_bit_for_id(regs, dist_regs::ICPENDR_N, id);
+        });
+    }
+
+    fn ack_interrupt(&self) -> Option<Acknowledged> {
+        let id: u64;
+        unsafe {
+            core::arch::asm!(
+                "mrs {id}, S3_0_C12_C12_0", // ICC_IAR1_EL1
+                id = out(reg) id
+            );
+        }
+        let id = (id & 0xff_ffff) as Id;
+        if id == INTID_NONE_PENDING {
+            None
+        } else {
+            Some(Acknowledged::from(id))
+        }
+    }
+
+    fn send_ipi(&self, id: Id, target: IpiTarget) {
+        let value = match target {
+            IpiTarget::Core(index) => {
+                let affinity = self.core_affinities[index];
+                // Aff3, Aff2 and Aff1 fields, with Aff0 selected by the target list in bits 15:0
+                ((affinity & 0xff_0000_0000) << 16)
+                    | ((affinity & 0xff_0000) << 16)
+                    | ((affinity & 0xff00) << 8)
+                    | (1 << (affinity & 0xf))
+            }
+            // the interrupt routing mode bit selects all cores but this one
+            IpiTarget::AllButSelf => 1 << 40,
+        } | (u64::from(id) << 24);
+        unsafe {
+            core::arch::asm!(
+                "msr S3_0_C12_C11_5, {v}", // ICC_SGI1R_EL1
+                "isb",
+                v = in(reg) value
+            );
+        }
+    }
+
+    fn finish_interrupt(&self, interrupt: Acknowledged) {
+        unsafe {
+            core::arch::asm!(
+                "msr S3_0_C12_C12_1, {id}", // ICC_EOIR1_EL1
+                id = in(reg) u64::from(interrupt.raw)
+            );
+        }
+    }
+}
+
+/// Register offsets for the GIC distributor (relative to its base address, by u32s).
+///
+/// The private interrupt registers in the redistributor `SGI_base` frame have the same offsets.
+/// In the specification, these are named `GICD_*` and `GICR_*`.
+#[allow(unused, missing_docs)]
+mod dist_regs {
+    pub const CTLR: usize = 0x0000 >> 2;
+    pub const TYPER: usize = 0x0004 >> 2;
+    pub const IGROUPR_N: usize = 0x0080 >> 2;
+    pub const ISENABLER_N: usize = 0x0100 >> 2;
+    pub const ICENABLER_N: usize = 0x0180 >> 2;
+    pub const ISPENDR_N: usize = 0x0200 >> 2;
+    pub const ICPENDR_N: usize = 0x0280 >> 2;
+    pub const ISACTIVER_N: usize = 0x0300 >> 2;
+    pub const ICACTIVER_N: usize = 0x0380 >> 2;
+    pub const IPRIORITYR_N: usize = 0x0400 >> 2;
+    pub const ICFGR_N: usize = 0x0c00 >> 2;
+    pub const IGRPMOD_N: usize = 0x0d00 >> 2;
+    pub const IROUTER_N: usize = 0x6000 >> 2;
+
+    pub const CTLR_ENABLE_GRP1: u32 = 1 << 1;
+    pub const CTLR_ARE: u32 = 1 << 4;
+    /// `IROUTER` value that allows an interrupt to be delivered to any core.
+    pub const IROUTER_ANY: u64 = 1 << 31;
+    /// Bits of `IROUTER` that hold the target affinity, which match those of `MPIDR_EL1`.
+    pub const IROUTER_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+}
+
+/// Register offsets for the GIC redistributor `RD_base` frame (relative to its base address, by u32s).
+///
+/// In the specification, these are named `GICR_*`.
+#[allow(unused, missing_docs)]
+mod redist_regs {
+    pub const CTLR: usize = 0x0000 >> 2;
+    pub const IIDR: usize = 0x0004 >> 2;
+    pub const TYPER: usize = 0x0008 >> 2;
+    pub const WAKER: usize = 0x0014 >> 2;
+
+    pub const TYPER_VLPIS: u32 = 1 << 1;
+    pub const TYPER_LAST: u32 = 1 << 4;
+    pub const WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+    pub const WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+}
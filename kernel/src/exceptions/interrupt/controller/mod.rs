@@ -1,5 +1,189 @@
 //! Interrupt controllers. (Implementations of [`kernel_core::exceptions::InterruptController`])
 
+use byteorder::{BigEndian, ByteOrder};
+use kernel_core::{
+    driver::{Device, ProbeError},
+    exceptions::interrupt::{
+        Acknowledged, Config, Controller, CoreSet, Id, IpiTarget, Msi, TriggerMode,
+    },
+    platform::cpu::CoreInfo,
+    platform::device_tree::{
+        interrupts::InterruptTree, iter::NodePropertyIter, ParseError, PropertyNotFoundSnafu,
+    },
+    platform::info::PlatformInfo as _,
+};
+use log::warn;
+use snafu::OptionExt as _;
+
+use super::CONTROLLER;
+
 pub mod gic2;
+pub mod gic3;
+
+/// The interrupt controller present in the system, selected at boot from the device tree.
+pub enum PlatformController {
+    /// A `GICv2` interrupt controller.
+    GicV2(gic2::GenericV2),
+    /// A `GICv3` or `GICv4` interrupt controller.
+    GicV3(gic3::GenericV3),
+}
+
+impl PlatformController {
+    /// Create the driver for the interrupt controller described by a device tree node, based on
+    /// its `compatible` property.
+    /// If present, `v2m_node` is the `GICv2m` MSI frame child node of the controller.
+    /// The `cores` are the cores in the system that interrupts can be routed to.
+    pub fn in_device_tree<'dt>(
+        node: NodePropertyIter<'dt>,
+        v2m_node: Option<NodePropertyIter<'dt>>,
+        cores: &[CoreInfo],
+    ) -> Result<Self, ParseError<'dt>> {
+        let (name, value) = node
+            .clone()
+            .find(|(name, _)| name == b"compatible")
+            .context(PropertyNotFoundSnafu { name: "compatible" })?;
+        let is_gic3 = value.as_strings(name)?.iter().any(|model_name| {
+            gic3::COMPATIBLE
+                .iter()
+                .any(|supported_model_name| model_name.to_bytes() == *supported_model_name)
+        });
+        if is_gic3 {
+            gic3::GenericV3::in_device_tree(node, cores).map(Self::GicV3)
+        } else {
+            gic2::GenericV2::in_device_tree(node, v2m_node).map(Self::GicV2)
+        }
+    }
+}
+
+/// Set up the system interrupt controller, which is the interrupt parent of the root node. Any other
+/// interrupt controllers are declined.
+///
+/// The distributor is initialized here, so that devices can configure their interrupts as soon as
+/// they are bound.
+fn probe(device: &Device) -> Result<(), ProbeError> {
+    let interrupt_tree = InterruptTree::new(device.device_tree);
+    let is_root_parent = interrupt_tree
+        .find(b"/")
+        .and_then(|root| interrupt_tree.interrupt_parent(root).ok())
+        .is_some_and(|intc| interrupt_tree.path(intc) == device.path);
+    if !is_root_parent || CONTROLLER.get().is_some() {
+        return Err(ProbeError::Declined);
+    }
+    let node = device.properties().ok_or(ProbeError::Declined)?;
+    let v2m_node = device
+        .device_tree
+        .iter_nodes_named(device.path, b"v2m")
+        .and_then(|mut nodes| nodes.next())
+        .map(|node| node.properties);
+    let cores = device.device_tree.cores().map_err(|e| {
+        warn!("failed to list cores for interrupt routing: {e}");
+        ProbeError::Failed {
+            reason: "no cores to route interrupts to",
+        }
+    })?;
+    let controller = PlatformController::in_device_tree(node, v2m_node, &cores).map_err(|e| {
+        warn!("failed to configure interrupt controller: {e}");
+        ProbeError::Failed {
+            reason: "invalid device tree node",
+        }
+    })?;
+    CONTROLLER.call_once(|| controller).global_initialize();
+    Ok(())
+}
+
+macro_rules! dispatch {
+    ($self:ident, $c:ident => $e:expr) => {
+        match $self {
+            PlatformController::GicV2($c) => $e,
+            PlatformController::GicV3($c) => $e,
+        }
+    };
+}
+
+impl Controller for PlatformController {
+    fn global_initialize(&self) {
+        dispatch!(self, c => c.global_initialize());
+    }
+
+    fn initialize_for_core(&self) {
+        dispatch!(self, c => c.initialize_for_core());
+    }
+
+    fn interrupt_in_device_tree(&self, data: &[u8], index: usize) -> Option<(Id, TriggerMode)> {
+        dispatch!(self, c => c.interrupt_in_device_tree(data, index))
+    }
+
+    fn configure(&self, id: Id, config: &Config) {
+        dispatch!(self, c => c.configure(id, config));
+    }
+
+    fn set_affinity(&self, id: Id, cores: CoreSet) {
+        dispatch!(self, c => c.set_affinity(id, cores));
+    }
+
+    fn enable(&self, id: Id) {
+        dispatch!(self, c => c.enable(id));
+    }
+
+    fn disable(&self, id: Id) {
+        dispatch!(self, c => c.disable(id));
+    }
+
+    fn clear_pending(&self, id: Id) {
+        dispatch!(self, c => c.clear_pending(id));
+    }
+
+    fn ack_interrupt(&self) -> Option<Acknowledged> {
+        dispatch!(self, c => c.ack_interrupt())
+    }
+
+    fn finish_interrupt(&self, interrupt: Acknowledged) {
+        dispatch!(self, c => c.finish_interrupt(interrupt));
+    }
+
+    fn send_ipi(&self, id: Id, target: IpiTarget) {
+        dispatch!(self, c => c.send_ipi(id, target));
+    }
+
+    fn allocate_msi(&self) -> Option<Msi> {
+        dispatch!(self, c => c.allocate_msi())
+    }
+
+    fn free_msi(&self, id: Id) {
+        dispatch!(self, c => c.free_msi(id));
+    }
+}
+
+/// Interpret an entry in an `interrupts` property using the 3-cell format shared by the ARM GIC device tree bindings.
+fn parse_interrupt_specifier(data: &[u8], index: usize) -> Option<(Id, TriggerMode)> {
+    if (index + 1) * 12 > data.len() {
+        return None;
+    }
+
+    let d = &data[index * 12..(index + 1) * 12];
+    let first_cell = BigEndian::read_u32(&d[0..4]);
+    let second_cell = BigEndian::read_u32(&d[4..8]);
+    let flags = d[11];
+
+    let id = match first_cell {
+        0 => {
+            // SPI interrupt
+            // defined in device tree as 0-987, mapped to interrupt ids 32-1019
+            32 + second_cell
+        }
+        1 => {
+            // PPI interrupt
+            // defined in device tree as 0-15, mapped to interrupt ids 16-31
+            16 + second_cell
+        }
+        _ => return None,
+    };
+
+    let trigger_mode = match flags {
+        0b0001 | 0b0010 => TriggerMode::Edge,
+        0b0100 | 0b1000 => TriggerMode::Level,
+        _ => return None,
+    };
 
-pub type PlatformController = gic2::GenericV2;
+    Some((id, trigger_mode))
+}
@@ -1,24 +1,61 @@
 //! Interrupts from hardware devices.
+use alloc::{format, sync::Arc, vec::Vec};
 use kernel_core::{
-    exceptions::{interrupt::Handler, InterruptController},
-    platform::device_tree::DeviceTree,
+    exceptions::{
+        deferred::DeferredQueue,
+        interrupt::{
+            Config, Handler, HandlerRegistry, Id, InterruptStatistics, IpiTarget, ThreadedInterrupt,
+        },
+        InterruptController,
+    },
+    memory::{page_table::TlbFlush, AddressSpaceId},
+    platform::{
+        cpu::{CoreInfo, Id as CpuId},
+        device_tree::DeviceTree,
+    },
+    smp::{IpiDispatcher, IpiMechanism, IpiMessage},
+    time::TimerQueue,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use spin::once::Once;
 
 use crate::{
-    thread::{PlatformScheduler, SCHEDULER},
-    timer::Timer,
+    driver::DeviceHandler,
+    thread::{PlatformScheduler, SystemCpuIdReader, SCHEDULER},
+    timer::{SystemCounter, Timer},
 };
 
 pub mod controller;
 use controller::PlatformController;
 
+/// Work deferred by interrupt handlers to the deferred work thread.
+pub static DEFERRED: DeferredQueue = DeferredQueue::new();
+
+/// The maximum number of deferred work items to run before the deferred work thread lets other
+/// threads run, since kernel threads are not preempted.
+const DEFERRED_WORK_BATCH: usize = 4;
+
+/// One-shot timers, run by the timer interrupt handler when they expire.
+pub static TIMER_QUEUE: TimerQueue = TimerQueue::new();
+
 /// The global interrupt handler policy.
 pub static HANDLER_POLICY: Once<
-    Handler<'static, 'static, 'static, Timer, PlatformController, PlatformScheduler>,
+    Handler<'static, 'static, 'static, Timer, PlatformController, PlatformScheduler, PlatformIpi>,
 > = Once::new();
 
+/// Counts of the interrupts that have been handled.
+static STATISTICS: Once<InterruptStatistics> = Once::new();
+
+/// An interrupt that occurs this many times within [`STORM_WINDOW_NANOS`] is reported as a storm.
+const STORM_THRESHOLD: u64 = 10_000;
+
+/// The length of the window in which interrupts are counted to detect storms, in nanoseconds.
+const STORM_WINDOW_NANOS: u64 = 100_000_000;
+
+/// The number of interrupt IDs counted in [`STATISTICS`]. The GIC's IDs from 1020 up are special,
+/// and message based interrupts use IDs below them.
+const NUM_COUNTED_INTERRUPTS: usize = 1020;
+
 /// The current interrupt controller device in the system.
 pub static CONTROLLER: Once<PlatformController> = Once::new();
 
@@ -28,61 +65,242 @@ pub static TIMER: Once<Timer> = Once::new();
 /// The length of the timer interval in `1/seconds`.
 pub const TIMER_INTERVAL: u32 = 10;
 
-/// Initialize the interrupt controller and interrupt handler.
-pub fn init(device_tree: &DeviceTree<'_>) {
-    debug!("Initializing interrupts…");
+/// The software-generated interrupt used to deliver inter-processor interrupts.
+pub const IPI_INTERRUPT_ID: Id = 0;
 
-    // TODO: we assume here that the interrupt controller is under `/intc@?`, which is definitely
-    // not true in general! We need to either use the `/interrupt-parent` property or the
-    // `interrupt-controller` marker property.
-    let intc_node = device_tree
-        .iter_nodes_named(b"/", b"intc")
-        .expect("root node")
-        .next()
-        .expect("have intc node");
-
-    let controller = CONTROLLER.call_once(|| {
-        controller::PlatformController::in_device_tree(intc_node.properties)
-            .expect("configure interrupt controller")
-    });
+/// The inter-processor interrupt dispatcher for this system.
+pub type PlatformIpi = IpiDispatcher<'static, SystemCpuIdReader, SystemIpiMechanism>;
+
+/// The global inter-processor interrupt dispatcher.
+pub static IPI: Once<PlatformIpi> = Once::new();
+
+/// Carries out inter-processor interrupt requests on the current core.
+pub struct SystemIpiMechanism;
+
+impl IpiMechanism for SystemIpiMechanism {
+    fn halt(&self) -> ! {
+        halt_current_core()
+    }
 
-    controller.global_initialize();
+    fn flush_tlb(&self, flush: &TlbFlush, asid: AddressSpaceId) {
+        /// Past this many pages it is cheaper to flush the entire TLB.
+        const MAX_PAGES_TO_FLUSH: usize = 64;
+        let pages = flush.length.div_ceil(0x1000);
+        unsafe {
+            core::arch::asm!("DSB ISHST");
+            if pages > MAX_PAGES_TO_FLUSH {
+                core::arch::asm!("TLBI VMALLE1");
+            } else {
+                // the operand holds VA[55:12] in the low bits and the ASID in bits 63:48
+                let asid_bits = u64::from(asid) << 48;
+                let page_number = (usize::from(flush.virtual_start) >> 12) & 0xfff_ffff_ffff;
+                for i in 0..pages {
+                    let va = (page_number + i) as u64;
+                    if flush.global {
+                        core::arch::asm!("TLBI VAAE1, {v}", v = in(reg) va);
+                    } else {
+                        core::arch::asm!("TLBI VAE1, {v}", v = in(reg) va | asid_bits);
+                    }
+                }
+            }
+            core::arch::asm!("DSB NSH", "ISB");
+        }
+    }
+
+    fn flush_all_tlb(&self) {
+        // this flushes the ASIDs freed by a rollover of the ASID pool too
+        crate::process::clear_pending_flush();
+        unsafe {
+            core::arch::asm!("DSB ISHST", "TLBI VMALLE1", "DSB NSH", "ISB");
+        }
+    }
 
-    let timer_node = device_tree
-        .iter_node_properties(b"/timer")
-        .expect("have timer node");
+    fn dump_state(&self) {
+        crate::lockup::dump_current_thread();
+    }
+}
+
+/// Initialize the interrupt handler, once the drivers for the interrupt controller and system timer
+/// have been bound (see [`crate::driver::probe_all`]).
+/// The `cores` are the cores in the system that interrupts can be routed to.
+pub fn init(device_tree: &DeviceTree<'_>, cores: &[CoreInfo]) {
+    debug!("Initializing interrupts…");
 
-    let timer = TIMER.call_once(|| {
-        Timer::in_device_tree(timer_node, controller, TIMER_INTERVAL)
-            .expect("configure system timer")
+    // interrupt threads run with IRQs unmasked, so the locks they share with handlers mask them
+    kernel_core::sync::interrupts::set_masking(super::mask_irqs, super::unmask_irqs);
+
+    let controller = CONTROLLER
+        .get()
+        .expect("interrupt controller bound by its driver");
+    let timer = TIMER.get().expect("system timer bound by its driver");
+
+    let stats = STATISTICS.call_once(|| {
+        InterruptStatistics::new::<SystemCpuIdReader, SystemCounter>(
+            cores.iter().map(|c| c.id),
+            NUM_COUNTED_INTERRUPTS,
+            STORM_THRESHOLD,
+            crate::timer::clock().nanos_to_ticks(STORM_WINDOW_NANOS),
+        )
     });
 
-    HANDLER_POLICY.call_once(|| {
+    let handler = HANDLER_POLICY.call_once(|| {
         Handler::new(
             controller,
             timer,
+            &TIMER_QUEUE,
             SCHEDULER
                 .get()
                 .expect("threads initialized before interrupts"),
+            IPI.call_once(|| {
+                let cores: Vec<_> = cores.iter().map(|c| c.id).collect();
+                IpiDispatcher::new(IPI_INTERRUPT_ID, &cores, &SystemIpiMechanism)
+            }),
         )
+        .with_statistics(stats)
     });
+    crate::lockup::create_detector(cores);
 
     init_for_core();
 
+    DEFERRED.set_worker(crate::kthread::spawn_interrupt_thread(
+        "deferred",
+        || loop {
+            if DEFERRED.run_pending(DEFERRED_WORK_BATCH) == 0 {
+                crate::kthread::park();
+            } else {
+                crate::kthread::yield_now();
+            }
+        },
+    ));
+
+    if let Some(uart) = crate::uart::UART.get() {
+        if let Some((id, mode)) = crate::uart::interrupt_in_device_tree(device_tree, controller) {
+            controller.configure(
+                id,
+                &Config {
+                    mode,
+                    ..Config::default()
+                },
+            );
+            handler
+                .devices()
+                .register(id, crate::uart::handle_interrupt)
+                .expect("UART interrupt is not shared");
+            controller.enable(id);
+            uart.enable_interrupts();
+            debug!("UART using interrupt {id}");
+        } else {
+            warn!("UART interrupt not found, output will remain synchronous");
+        }
+    }
+
+    for (id, mode, device_handler, registered) in crate::driver::take_interrupts(controller) {
+        controller.configure(
+            id,
+            &Config {
+                mode,
+                ..Config::default()
+            },
+        );
+        register_threaded(handler.devices(), controller, id, device_handler);
+        controller.enable(id);
+        debug!("device using interrupt {id}, threaded");
+        registered();
+    }
+
     info!("Interrupts initialized!");
 }
 
+/// Run `device_handler` for the interrupt `id` in a dedicated kernel thread, registering a hard
+/// handler in `devices` that masks the interrupt and wakes the thread.
+fn register_threaded(
+    devices: &HandlerRegistry,
+    controller: &'static PlatformController,
+    id: Id,
+    device_handler: DeviceHandler,
+) {
+    let irq = Arc::new(ThreadedInterrupt::new(id, device_handler));
+    let thread = {
+        let irq = irq.clone();
+        crate::kthread::spawn_interrupt_thread(&format!("irq/{id}"), move || loop {
+            if !irq.run_pending(controller) {
+                crate::kthread::park();
+            }
+        })
+    };
+    devices
+        .register(id, move || {
+            irq.raise(controller);
+            // this runs in the exception handler, so the thread is unparked without locking
+            thread.unpark();
+        })
+        .expect("device interrupts are not shared");
+}
+
+/// Counts of the interrupts that have been handled, by interrupt ID.
+pub fn statistics() -> Option<&'static InterruptStatistics> {
+    STATISTICS.get()
+}
+
 /// Perform initialization for interrupts that needs to happen for each core on the system.
 pub fn init_for_core() {
     let ctrl = CONTROLLER.get().unwrap();
     ctrl.initialize_for_core();
+    ctrl.configure(IPI_INTERRUPT_ID, &Config::default());
+    ctrl.enable(IPI_INTERRUPT_ID);
     TIMER.get().unwrap().start_for_core(ctrl);
 }
 
+/// Stop the current core permanently, masking all exceptions so that it spins safely.
+pub fn halt_current_core() -> ! {
+    unsafe {
+        core::arch::asm!("msr DAIFSet, #0b1111");
+    }
+    loop {
+        unsafe {
+            core::arch::asm!("wfi");
+        }
+    }
+}
+
+/// Halt every core other than the current one, if interrupts have been initialized.
+pub fn halt_other_cores() {
+    if let (Some(ipi), Some(ctrl)) = (IPI.get(), CONTROLLER.get()) {
+        ipi.send(ctrl, IpiTarget::AllButSelf, IpiMessage::Halt);
+    }
+}
+
+/// Tell every core other than the current one to flush its entire TLB, if interrupts have been
+/// initialized.
+pub fn flush_other_cores_tlb() {
+    if let (Some(ipi), Some(ctrl)) = (IPI.get(), CONTROLLER.get()) {
+        ipi.send(ctrl, IpiTarget::AllButSelf, IpiMessage::FlushTlb);
+    }
+}
+
+/// Ask the core with index `core` to log the state of the thread it is running, if interrupts
+/// have been initialized.
+pub fn request_state_dump(core: usize) {
+    if let (Some(ipi), Some(ctrl)) = (IPI.get(), CONTROLLER.get()) {
+        ipi.send(ctrl, IpiTarget::Core(core), IpiMessage::DumpState);
+    }
+}
+
+/// Interrupt the idle core `id` so that it runs the scheduler, if interrupts have been initialized.
+///
+/// Idle cores stop their timer tick, so this is how they find out that a thread was placed on them.
+pub fn wake_core(id: CpuId) {
+    if let (Some(ipi), Some(ctrl)) = (IPI.get(), CONTROLLER.get()) {
+        if let Some(index) = ipi.core_index(id) {
+            ipi.send(ctrl, IpiTarget::Core(index), IpiMessage::Reschedule);
+        }
+    }
+}
+
 /// Wait for an interrupt to occur, pausing execution.
 #[inline]
 pub fn wait_for_interrupt() {
-    unsafe {
-        core::arch::asm!("wfi");
-    }
+    crate::watchdog::idle();
+    crate::lockup::idle();
+    crate::idle::enter();
 }
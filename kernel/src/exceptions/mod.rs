@@ -1,13 +1,17 @@
 //! Mechanisms for exception handling
 
 mod handlers;
-pub use handlers::install_exception_vector;
+pub use handlers::{install_exception_vector, set_emergency_stack, ExceptionFrame};
 
 mod interrupt;
 
 pub use interrupt::init as init_interrupts;
 pub use interrupt::init_for_core as init_interrupts_for_core;
 pub use interrupt::wait_for_interrupt;
+pub use interrupt::{
+    controller, flush_other_cores_tlb, halt_current_core, halt_other_cores, request_state_dump,
+    statistics as interrupt_statistics, wake_core, CONTROLLER, TIMER, TIMER_INTERVAL, TIMER_QUEUE,
+};
 
 use bitfield::bitfield;
 
@@ -23,6 +27,26 @@ bitfield! {
     frq, set_frq: 6;
 }
 
+/// Mask IRQs on the current core, returning true if they were already masked.
+fn mask_irqs() -> bool {
+    let was_masked = CpuExceptionMask::read().irq();
+    unsafe {
+        core::arch::asm!("msr DAIFSet, #2");
+    }
+    was_masked
+}
+
+/// Unmask IRQs on the current core.
+///
+/// Only code that may be interrupted by any interrupt handler can unmask them, such as interrupt
+/// threads. Every lock that interrupt handlers take masks them again while it is held (see
+/// [`kernel_core::sync::interrupts`]).
+pub fn unmask_irqs() {
+    unsafe {
+        core::arch::asm!("msr DAIFClr, #2");
+    }
+}
+
 #[allow(unused)]
 impl CpuExceptionMask {
     /// A mask to enable all exceptions.
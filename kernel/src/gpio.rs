@@ -0,0 +1,242 @@
+//! PL061 GPIO controller driver.
+//!
+//! Documentation for the interface can be found [on ARM's website](https://developer.arm.com/documentation/ddi0190/latest/).
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+use kernel_core::{
+    driver::{Device, Driver, ProbeError},
+    memory::PhysicalAddress,
+    platform::{
+        device_tree::Value,
+        gpio::{panic_led_in_device_tree, Direction, Edge, Gpio, GpioMechanism, PinRef},
+    },
+    time::CounterReader as _,
+};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::{
+    driver::add_threaded_interrupt,
+    exceptions::CpuExceptionMask,
+    memory::map_device,
+    timer::{clock, SystemCounter},
+};
+
+/// Register offsets, in bytes.
+mod regs {
+    /// Data register. Bits 9:2 of the address mask which pins are read or written.
+    pub const DATA: usize = 0x000;
+    /// Direction register, 1 for outputs.
+    pub const DIR: usize = 0x400;
+    /// Interrupt sense register, 0 for edges.
+    pub const IS: usize = 0x404;
+    /// Interrupt both edges register.
+    pub const IBE: usize = 0x408;
+    /// Interrupt event register, 1 for rising edges.
+    pub const IEV: usize = 0x40c;
+    /// Interrupt mask register, 1 to raise interrupts.
+    pub const IE: usize = 0x410;
+    /// Masked interrupt status register.
+    pub const MIS: usize = 0x418;
+    /// Interrupt clear register.
+    pub const IC: usize = 0x41c;
+    /// Mode control select register, 1 for pins controlled by other hardware.
+    pub const AFSEL: usize = 0x420;
+}
+
+/// The number of pins on a PL061.
+const NUM_PINS: u32 = 8;
+
+/// How long the panic LED stays on or off while blinking.
+const PANIC_BLINK_NANOS: u64 = 250_000_000;
+
+/// The PL061 GPIO controller object.
+pub struct PL061 {
+    base_address: *mut u8,
+}
+
+// SAFETY: It's fine to move the pointer as long as it doesn't get duplicated!
+unsafe impl Send for PL061 {}
+// SAFETY: registers are only accessed with single volatile reads and writes, and the data register
+// only changes the pins selected by the address.
+unsafe impl Sync for PL061 {}
+
+impl PL061 {
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.read_volatile()
+        }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.write_volatile(value);
+        }
+    }
+
+    /// Set or clear the bit for `pin` in the register at `offset`.
+    fn set_bit(&self, offset: usize, pin: u32, set: bool) {
+        let value = self.read_reg(offset);
+        self.write_reg(
+            offset,
+            if set {
+                value | (1 << pin)
+            } else {
+                value & !(1 << pin)
+            },
+        );
+    }
+}
+
+impl GpioMechanism for PL061 {
+    fn num_pins(&self) -> u32 {
+        NUM_PINS
+    }
+
+    fn direction(&self, pin: u32) -> Direction {
+        if self.read_reg(regs::DIR) & (1 << pin) == 0 {
+            Direction::Input
+        } else {
+            Direction::Output
+        }
+    }
+
+    fn set_direction(&self, pin: u32, direction: Direction) {
+        self.set_bit(regs::DIR, pin, direction == Direction::Output);
+    }
+
+    fn read(&self, pin: u32) -> bool {
+        self.read_reg(regs::DATA + (4 << pin)) != 0
+    }
+
+    fn write(&self, pin: u32, high: bool) {
+        self.write_reg(regs::DATA + (4 << pin), if high { 0xff } else { 0 });
+    }
+
+    fn set_interrupt(&self, pin: u32, edge: Option<Edge>) {
+        let Some(edge) = edge else {
+            self.set_bit(regs::IE, pin, false);
+            return;
+        };
+        self.set_bit(regs::IS, pin, false);
+        self.set_bit(regs::IBE, pin, edge == Edge::Both);
+        self.set_bit(regs::IEV, pin, edge == Edge::Rising);
+        self.set_bit(regs::IE, pin, true);
+    }
+
+    fn pending_interrupts(&self) -> u32 {
+        self.read_reg(regs::MIS)
+    }
+
+    fn clear_interrupts(&self, pins: u32) {
+        self.write_reg(regs::IC, pins & 0xff);
+    }
+}
+
+/// The driver for the PL061.
+pub const DRIVER: Driver = Driver {
+    name: "pl061",
+    compatible: &[b"arm,pl061"],
+    probe,
+};
+
+/// A GPIO controller that has been set up.
+pub type Controller = Arc<Gpio<PL061>>;
+
+/// The GPIO controllers that other device tree nodes can refer to, by the phandle of each one's
+/// node.
+static CONTROLLERS: Mutex<BTreeMap<u32, Controller>> = Mutex::new(BTreeMap::new());
+
+/// The controller whose device tree node has `phandle`, if it has been set up.
+///
+/// This is how pins that other nodes refer to are found, like the panic LED, and will be how user
+/// space drivers ask for pin interrupts.
+pub fn controller(phandle: u32) -> Option<Controller> {
+    CONTROLLERS.lock().get(&phandle).cloned()
+}
+
+/// The LED that is blinked after a panic, and its controller.
+static PANIC_LED: Once<(Controller, PinRef)> = Once::new();
+
+/// Set up a PL061, with every pin under software control.
+fn probe(device: &Device) -> Result<(), ProbeError> {
+    let mut region = None;
+    let mut interrupts = None;
+    let mut phandle = None;
+    for (name, value) in device.properties().ok_or(ProbeError::Declined)? {
+        match (name, value) {
+            (b"reg", Value::Reg(r)) => region = r.iter().next(),
+            (b"interrupts", value) => interrupts = value.into_bytes(),
+            (b"phandle", value) => phandle = value.into_phandle(),
+            _ => {}
+        }
+    }
+    let (base, len) = region.ok_or(ProbeError::Failed {
+        reason: "no registers",
+    })?;
+    let mech = PL061 {
+        base_address: map_device(PhysicalAddress::from(base), len),
+    };
+    mech.write_reg(regs::AFSEL, 0);
+    let gpio = Arc::new(Gpio::new(mech));
+    info!("PL061 GPIO controller at {base:#x}");
+
+    if let Some(interrupts) = interrupts {
+        let gpio = gpio.clone();
+        add_threaded_interrupt(
+            interrupts,
+            Box::new(move || {
+                gpio.handle_interrupt();
+            }),
+        );
+    }
+
+    if let Some(phandle) = phandle {
+        CONTROLLERS.lock().insert(phandle, gpio);
+    }
+
+    if let Some((gpio, led)) = panic_led_in_device_tree(device.device_tree)
+        .filter(|_| !PANIC_LED.is_completed())
+        .and_then(|led| Some((controller(led.controller)?, led)))
+    {
+        match gpio
+            .set_direction(led.pin, Direction::Output)
+            .and_then(|()| gpio.write(led.pin, led.level(false)))
+        {
+            Ok(()) => {
+                info!(
+                    "panic LED on GPIO pin {} of controller {:#x}",
+                    led.pin, led.controller
+                );
+                PANIC_LED.call_once(|| (gpio, led));
+            }
+            Err(e) => warn!("failed to set up panic LED: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Blink the panic LED forever with exceptions masked, if the device tree has one. Returns
+/// immediately otherwise.
+pub fn blink_panic_led() {
+    let Some((gpio, led)) = PANIC_LED.get() else {
+        return;
+    };
+    unsafe {
+        CpuExceptionMask::all_disabled().write();
+    }
+    let period = clock().nanos_to_ticks(PANIC_BLINK_NANOS);
+    let mut on = true;
+    loop {
+        // the pin was checked when the LED was found
+        let _ = gpio.write(led.pin, led.level(on));
+        let until = SystemCounter::read() + period;
+        while SystemCounter::read() < until {
+            core::hint::spin_loop();
+        }
+        on = !on;
+    }
+}
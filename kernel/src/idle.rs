@@ -0,0 +1,81 @@
+//! Putting idle cores to sleep, in the deepest PSCI idle state that is worth entering before the
+//! next timer deadline. See [`kernel_core::platform::idle`].
+use kernel_core::{
+    memory::PhysicalAddress,
+    platform::{
+        cpu::{CoreInfo, CpuIdReader as _},
+        device_tree::DeviceTree,
+        idle::{idle_states_in_device_tree, IdleGovernor},
+        power::PowerManager as _,
+    },
+    time::Ticks,
+};
+use log::{info, warn};
+use spin::Once;
+
+use crate::thread::SystemCpuIdReader;
+
+/// The idle governor, once the idle states have been found.
+static GOVERNOR: Once<IdleGovernor> = Once::new();
+
+/// Find the idle states that cores can enter. Until this is called, idle cores only use `wfi`.
+///
+/// This must be called after PSCI is initialized.
+pub fn init(device_tree: &DeviceTree, cores: &[CoreInfo]) {
+    let states = idle_states_in_device_tree(device_tree).unwrap_or_else(|e| {
+        warn!("ignoring invalid idle states in device tree: {e}");
+        alloc::vec::Vec::new()
+    });
+    let ids: alloc::vec::Vec<_> = cores.iter().map(|c| c.id).collect();
+    let governor = GOVERNOR.call_once(|| IdleGovernor::new(&states, &ids));
+    info!(
+        "{} of {} idle states usable",
+        governor.states().len(),
+        states.len()
+    );
+}
+
+/// Statistics about how long each core has spent in each idle state.
+pub fn statistics() -> Option<&'static IdleGovernor> {
+    GOVERNOR.get()
+}
+
+fn wait_for_interrupt() {
+    unsafe {
+        core::arch::asm!("wfi");
+    }
+}
+
+/// Put the current core to sleep until the next interrupt.
+pub fn enter() {
+    let (Some(governor), Some(power)) = (GOVERNOR.get(), crate::psci::POWER.get()) else {
+        wait_for_interrupt();
+        return;
+    };
+    let clock = crate::timer::clock();
+    let start = clock.now();
+    let deadline = crate::timer::next_deadline();
+    let predicted =
+        (deadline != Ticks::MAX).then(|| clock.ticks_to_nanos(deadline.saturating_sub(start)));
+    let mut state = governor.select(predicted);
+    if let Some(s) = state {
+        // SAFETY: only retention states are selected, which resume here rather than at an entry
+        // point.
+        let result = unsafe {
+            power.suspend_core(governor.states()[s].power_state, PhysicalAddress::null(), 0)
+        };
+        if let Err(e) = result {
+            warn!("firmware rejected idle state {s}, disabling it: {e}");
+            governor.disable(s);
+            state = None;
+        }
+    }
+    if state.is_none() {
+        wait_for_interrupt();
+    }
+    governor.record(
+        SystemCpuIdReader::current_cpu(),
+        state,
+        clock.ticks_to_nanos(clock.now().saturating_sub(start)),
+    );
+}
@@ -0,0 +1,84 @@
+//! Kernel page table isolation (see [`kernel_core::memory::kpti`]).
+//!
+//! The exception vector (`exceptions/exception_vector.S`) loads the tables recorded here into
+//! `TTBR1_EL1` on entry from and return to user space, and switches between the ASIDs of the
+//! current address space's pair. Until [`install_tables`] records them, both are zero and the full
+//! kernel page tables stay loaded.
+use core::ptr::addr_of_mut;
+
+use kernel_core::{
+    memory::{
+        kpti::{self, Mode, Vulnerability},
+        AddressSpaceId,
+    },
+    platform::boot_args::BootArgs,
+};
+use log::{info, warn};
+use spin::Once;
+
+use crate::{cpu, memory};
+
+extern "C" {
+    /// Root of the full kernel page tables, loaded on entry from user space.
+    static mut _kpti_kernel_ttbr1: u64;
+    /// Root of the reduced kernel page tables, loaded on return to user space.
+    static mut _kpti_user_ttbr1: u64;
+}
+
+/// True if the kernel page tables are isolated from user space.
+static ENABLED: Once<bool> = Once::new();
+
+/// Decide whether to isolate the kernel page tables from user space, as the `kpti` boot argument
+/// asks, or by default if the boot core could be vulnerable to Meltdown.
+///
+/// Every core is assumed to be the same kind as the boot core. This must be called before the
+/// kernel page tables are set up, since their mappings are not global if the kernel is isolated.
+pub fn init(boot_args: &BootArgs) {
+    let mode = Mode::from_boot_args(boot_args);
+    let registers = cpu::features().id_registers();
+    let (midr, pfr0) = (registers.midr, registers.pfr0);
+    if mode == Mode::Auto && kpti::vulnerability(midr, pfr0) == Vulnerability::Unknown {
+        warn!("Core (MIDR={midr:#x}) is not known to be safe from Meltdown, assuming it is vulnerable");
+    }
+    let enabled = *ENABLED.call_once(|| mode.is_enabled(midr, pfr0));
+    if enabled {
+        info!("Kernel page table isolation enabled ({mode:?})");
+    } else {
+        info!("Kernel page table isolation disabled ({mode:?}, MIDR={midr:#x})");
+    }
+}
+
+/// True if the kernel page tables are isolated from user space.
+pub fn is_enabled() -> bool {
+    ENABLED.get().copied().unwrap_or_default()
+}
+
+/// The ASIDs that TLB entries of the address space with `asid` are tagged with: the kernel's first,
+/// then user space's if it has a different one.
+pub fn hardware_asids(asid: AddressSpaceId) -> (AddressSpaceId, Option<AddressSpaceId>) {
+    if is_enabled() {
+        let (kernel, user) = kpti::asid_pair(asid);
+        (kernel, Some(user))
+    } else {
+        (asid, None)
+    }
+}
+
+/// Build the reduced kernel page tables and start using them when returning to user space, if
+/// kernel page table isolation is enabled.
+///
+/// This must be called once every core has started, so that all of their stacks are mapped into the
+/// reduced tables, and before any user thread runs.
+pub fn install_tables() {
+    if !is_enabled() {
+        return;
+    }
+    let (kernel_root, user_root) = memory::build_user_kernel_tables();
+    unsafe {
+        // the kernel tables must be recorded first, so that any return to user space that sees the
+        // reduced tables can also find the way back
+        addr_of_mut!(_kpti_kernel_ttbr1).write_volatile(usize::from(kernel_root) as u64);
+        core::arch::asm!("DMB ISH");
+        addr_of_mut!(_kpti_user_ttbr1).write_volatile(usize::from(user_root) as u64);
+    }
+}
@@ -0,0 +1,173 @@
+//! Kernel threads, which run a Rust function at EL1 on their own stack.
+//!
+//! Kernel threads are used for work that should not be done in an exception handler, such as
+//! deferred work that may take a long time, flushing logs and helping drivers.
+//! See [`kernel_core::process::thread::kernel_thread`] for how they are scheduled.
+use alloc::{boxed::Box, sync::Arc};
+use core::time::Duration;
+use kernel_core::{
+    ipc::Notification,
+    memory::{kernel_vm::KernelStack, VirtualAddress},
+    process::{
+        thread::{
+            kernel_thread::{
+                KernelThreadCall, KernelThreads, SVC_EXIT, SVC_JOIN, SVC_PARK, SVC_SLEEP, SVC_WAIT,
+                SVC_YIELD,
+            },
+            Id, Thread,
+        },
+        Name,
+    },
+    time::Ticks,
+};
+use spin::once::Once;
+
+use crate::{
+    exceptions::TIMER_QUEUE,
+    thread::{SCHEDULER, THREADS},
+    timer::clock,
+};
+
+/// Number of pages in the stack of each kernel thread.
+const KERNEL_THREAD_STACK_PAGES: usize = 16;
+
+/// The function run by a kernel thread.
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Every kernel thread that has been spawned and not reaped yet.
+static KERNEL_THREADS: Once<KernelThreads<KernelStack>> = Once::new();
+
+fn kernel_threads() -> &'static KernelThreads<KernelStack> {
+    KERNEL_THREADS.call_once(KernelThreads::new)
+}
+
+/// The first code run by a new kernel thread, which runs the thread's task and then exits.
+extern "C" fn kernel_thread_entry(task: *mut Task) -> ! {
+    // SAFETY: `spawn` passes a pointer created by `Box::into_raw`, which is only used here.
+    let task = unsafe { Box::from_raw(task) };
+    task();
+    exit()
+}
+
+/// Free the stacks of kernel threads that have exited.
+fn reap() {
+    kernel_threads().reap(THREADS.wait(), |stack| {
+        crate::memory::free_kernel_stack(&stack);
+    });
+}
+
+/// Spawn a new kernel thread called `name` that runs `task`, returning the id of the new thread.
+///
+/// # Panics
+/// Panics if threads are not initialized or the thread's stack could not be allocated.
+pub fn spawn(name: &str, task: impl FnOnce() + Send + 'static) -> Id {
+    spawn_thread(name, task).id
+}
+
+/// Spawn a new kernel thread called `name` that runs `task` at the interrupt priority, which no
+/// other thread can be given, returning the new thread so that interrupt handlers can unpark it
+/// directly with [`Thread::unpark`].
+///
+/// Unlike other kernel threads, the thread runs with IRQs unmasked so that a slow handler doesn't
+/// hold up the timer tick or more urgent interrupts. It can be preempted at any point where it
+/// doesn't hold a lock.
+///
+/// # Panics
+/// Panics if threads are not initialized or the thread's stack could not be allocated.
+pub fn spawn_interrupt_thread(name: &str, task: impl FnOnce() + Send + 'static) -> Arc<Thread> {
+    let thread = spawn_thread(name, move || {
+        crate::exceptions::unmask_irqs();
+        task();
+    });
+    thread.set_interrupt_priority();
+    thread
+}
+
+fn spawn_thread(name: &str, task: impl FnOnce() + Send + 'static) -> Arc<Thread> {
+    reap();
+    let stack = crate::memory::allocate_kernel_stack(KERNEL_THREAD_STACK_PAGES);
+    let top = stack.top;
+    let task: Box<Task> = Box::new(Box::new(task));
+    kernel_threads().spawn(
+        THREADS.wait(),
+        SCHEDULER.wait(),
+        Name::new(name),
+        stack,
+        top,
+        VirtualAddress::from(kernel_thread_entry as *mut ()),
+        Box::into_raw(task) as usize,
+    )
+}
+
+/// Exit the current kernel thread. Its stack is freed later.
+///
+/// This must only be called from a kernel thread.
+pub fn exit() -> ! {
+    unsafe {
+        core::arch::asm!("svc #{call}", call = const SVC_EXIT, options(noreturn));
+    }
+}
+
+/// Block the current kernel thread until the kernel thread `id` exits. Returns immediately if it
+/// has already exited.
+///
+/// This must only be called from a kernel thread, since other threads can't block.
+pub fn join(id: Id) {
+    unsafe {
+        core::arch::asm!("svc #{call}", call = const SVC_JOIN, in("x0") id as usize);
+    }
+    reap();
+}
+
+/// Let other threads run for the rest of the current kernel thread's time slice.
+///
+/// This must only be called from a kernel thread.
+pub fn yield_now() {
+    unsafe {
+        core::arch::asm!("svc #{call}", call = const SVC_YIELD);
+    }
+}
+
+/// Block the current kernel thread for at least `duration`.
+///
+/// This must only be called from a kernel thread, since other threads can't block.
+pub fn sleep(duration: Duration) {
+    let deadline =
+        clock().now() + clock().nanos_to_ticks(duration.as_nanos().try_into().unwrap_or(u64::MAX));
+    unsafe {
+        core::arch::asm!("svc #{call}", call = const SVC_SLEEP, in("x0") deadline);
+    }
+}
+
+/// Block the current kernel thread until it is unparked with [`Thread::unpark`]. Returns
+/// immediately if it has been unparked since it last parked.
+///
+/// This must only be called from a kernel thread, since other threads can't block.
+pub fn park() {
+    unsafe {
+        core::arch::asm!("svc #{call}", call = const SVC_PARK);
+    }
+}
+
+/// Block the current kernel thread until one of the flags in `mask` is raised on `notification`,
+/// or the time reaches `deadline`. Raised flags are only consumed if the thread doesn't block, so
+/// the caller must check for whatever it is waiting for again.
+///
+/// This must only be called from a kernel thread, since other threads can't block.
+pub fn wait(notification: &Notification, mask: u64, deadline: Ticks) {
+    unsafe {
+        core::arch::asm!(
+            "svc #{call}",
+            call = const SVC_WAIT,
+            in("x0") core::ptr::from_ref(notification),
+            in("x1") mask,
+            in("x2") deadline,
+        );
+    }
+}
+
+/// Handle a kernel thread `call` made by the current thread. Must be called by the exception
+/// handler, inside [`crate::thread::switch_threads_around`].
+pub fn handle_call(call: KernelThreadCall) {
+    kernel_threads().handle_call(SCHEDULER.wait(), &TIMER_QUEUE, call);
+}
@@ -0,0 +1,135 @@
+//! Soft lockup detection.
+//!
+//! A kernel thread periodically checks that every busy core is still switching threads or running
+//! user space (see [`kernel_core::debug::lockup`]). When a core stops, the checking core logs the
+//! registers and backtrace last saved for the thread the stuck core is running, since a core with
+//! interrupts masked can't be asked for them. The stuck core is also sent an IPI asking it to log
+//! its live state, which it answers if it still takes interrupts. If it doesn't, the hardware
+//! watchdog resets the system.
+use core::time::Duration;
+
+use kernel_core::{
+    debug::{backtrace::Backtrace, lockup::LockupDetector},
+    platform::cpu::{CoreInfo, CpuIdReader, Id as CpuId},
+    process::thread::{ProcessorState, Scheduler, Thread},
+};
+use log::{info, warn};
+use spin::Once;
+
+use crate::{
+    debug::KernelFrameReader,
+    exceptions::request_state_dump,
+    thread::{SystemCpuIdReader, SCHEDULER},
+    timer::clock,
+};
+
+/// How long a busy core can go without a heartbeat before it is locked up.
+const THRESHOLD: Duration = Duration::from_secs(5);
+
+/// How often the cores are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The soft lockup detector, created when interrupts are initialized.
+static DETECTOR: Once<LockupDetector> = Once::new();
+
+/// Create the soft lockup detector for `cores`, which counts the heartbeats of each core.
+pub fn create_detector(cores: &[CoreInfo]) {
+    DETECTOR.call_once(|| {
+        let cores: alloc::vec::Vec<_> = cores.iter().map(|c| c.id).collect();
+        let threshold = clock().nanos_to_ticks(THRESHOLD.as_nanos().try_into().unwrap_or(u64::MAX));
+        LockupDetector::new::<SystemCpuIdReader>(&cores, threshold, clock().now())
+    });
+}
+
+/// Start the kernel thread that checks for soft lockups, once interrupts are initialized.
+pub fn init() {
+    let Some(detector) = DETECTOR.get() else {
+        return;
+    };
+    crate::kthread::spawn("lockup", move || loop {
+        crate::kthread::sleep(CHECK_INTERVAL);
+        for core in detector.check(clock().now()) {
+            if let Some(id) = detector.core(core) {
+                dump_stuck_core(id);
+            }
+            request_state_dump(core);
+        }
+    });
+    info!("soft lockup detector started with a threshold of {THRESHOLD:?}");
+}
+
+/// Record that the current core made progress, by switching threads or taking an exception from
+/// user space.
+pub fn heartbeat() {
+    if let Some(detector) = DETECTOR.get() {
+        detector.heartbeat();
+    }
+}
+
+/// Record that the current core is idle and waiting for an interrupt.
+pub fn idle() {
+    if let Some(detector) = DETECTOR.get() {
+        detector.idle(SystemCpuIdReader::current_cpu());
+    }
+}
+
+/// Record that the current core was woken by an interrupt.
+pub fn wake() {
+    if let Some(detector) = DETECTOR.get() {
+        detector.wake();
+    }
+}
+
+/// Stop expecting heartbeats from the core `id`, because it is offline.
+pub fn core_offline(id: CpuId) {
+    if let Some(detector) = DETECTOR.get() {
+        detector.idle(id);
+    }
+}
+
+/// Log the registers and backtrace of the thread that was running on the current core when it was
+/// interrupted. Must be called by the interrupt handler, which has saved the thread's registers.
+pub fn dump_current_thread() {
+    let Some(scheduler) = SCHEDULER.get() else {
+        return;
+    };
+    let thread = scheduler.current_thread();
+    let state = thread.processor_state.lock();
+    log_thread_state(&thread, SystemCpuIdReader::current_cpu(), &state);
+}
+
+/// Log the registers and backtrace that were saved for the thread running on the stuck core `cpu`
+/// when it last entered the kernel or was switched to, from another core.
+fn dump_stuck_core(cpu: CpuId) {
+    let Some(thread) = SCHEDULER.get().and_then(|s| s.current_thread_on(cpu)) else {
+        return;
+    };
+    warn!("last saved state of the thread on core {cpu}:");
+    // the stuck core may be holding the lock, if it is stuck saving or restoring the state
+    let state = thread.processor_state.try_lock();
+    if let Some(state) = &state {
+        log_thread_state(&thread, cpu, state);
+    } else {
+        warn!("thread {thread} on core {cpu} has its state locked");
+    }
+}
+
+/// Log the registers and backtrace of `thread`, running on core `cpu`, from `state`.
+fn log_thread_state(thread: &Thread, cpu: CpuId, state: &ProcessorState) {
+    warn!(
+        "thread {thread} on core {cpu} at pc={:?} sp={:?} el={}, registers = {:x?}",
+        state.program_counter,
+        state.stack_pointer,
+        state.spsr.el(),
+        state.registers
+    );
+    // user stacks can't be walked from the kernel
+    if state.spsr.el() == 1 {
+        let backtrace = Backtrace::new(KernelFrameReader, state.registers.x[29]);
+        if let Some(symbols) = crate::debug::symbol_table() {
+            warn!("{}", backtrace.with_symbolizer(&symbols));
+        } else {
+            warn!("{backtrace}");
+        }
+    }
+}
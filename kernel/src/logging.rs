@@ -1,25 +1,32 @@
 //! Kernel logging mechanism.
-use log::{debug, info};
-use spin::once::Once;
+use log::{debug, info, warn};
 
 use kernel_core::{
-    logger::{GlobalValueReader, Logger},
-    platform::device_tree::{DeviceTree, Value},
+    logger::{history::LogHistory, sinks::SinkSet, GlobalValueReader, LogSink, Logger},
+    platform::{
+        boot_args::BootArgs,
+        cpu::CpuIdReader as _,
+        device_tree::{DeviceTree, Value},
+        semihosting::SemihostingConsole,
+        uart::Uart,
+        virtio::console::VirtioConsole,
+    },
 };
 
-use crate::uart;
+use crate::{semihosting::HltSemihosting, thread::SystemCpuIdReader, uart, virtio::MmioTransport};
 
 /// Implementation of [`GlobalValueReader`] that reads the real system registers.
 struct SystemGlobalValueReader;
 
 impl GlobalValueReader for SystemGlobalValueReader {
     fn read() -> kernel_core::logger::GlobalValues {
-        let mut r = kernel_core::logger::GlobalValues::default();
+        let mut r = kernel_core::logger::GlobalValues {
+            timestamp_nanos: crate::timer::clock().monotonic_nanos(),
+            ..Default::default()
+        };
         unsafe {
             core::arch::asm!(
-                "mrs {counter}, CNTPCT_EL0",
                 "mrs {core_id}, MPIDR_EL1",
-                counter = out(reg) r.timer_counter,
                 core_id = out(reg) r.core_id
             );
         }
@@ -29,22 +36,104 @@ impl GlobalValueReader for SystemGlobalValueReader {
     }
 }
 
-/// The global kernel logger instance.
-static LOGGER: Once<Logger<uart::PL011, SystemGlobalValueReader>> = Once::new();
+/// The history of recent kernel log records, which can be read back by user space.
+pub static LOG_HISTORY: LogHistory = LogHistory::new();
 
-/// Initialize the kernel global logger.
-pub fn init_logging(device_tree: &DeviceTree) {
-    let stdout_device_path = device_tree
-        .find_property(b"/chosen/stdout-path")
-        .and_then(Value::into_bytes)
-        // the string is null terminated in the device tree
-        // TODO: default to QEMU virt board UART for now, should be platform default
-        .map_or(b"/pl011@9000000" as &[u8], |p| &p[0..p.len() - 1]);
+/// The destinations that kernel log output can be sent to.
+enum KernelLogSink {
+    /// The UART chosen as the standard output device.
+    Uart(&'static Uart<uart::PL011>),
+    /// The in-memory history of recent log records.
+    History(&'static LogHistory),
+    /// The console of the semihosting host, if the boot arguments asked for it.
+    Semihosting(SemihostingConsole<'static, HltSemihosting>),
+    /// The virtio console.
+    Virtio(&'static VirtioConsole<MmioTransport>),
+    /// A device that isn't present, which never receives output.
+    Absent,
+}
 
-    let uart = uart::PL011::from_device_tree(device_tree, stdout_device_path).expect("init UART");
+impl LogSink for KernelLogSink {
+    fn accept(&mut self, chunk: &[u8]) {
+        match self {
+            Self::Uart(uart) => uart.accept(chunk),
+            Self::History(history) => history.accept(chunk),
+            Self::Semihosting(console) => console.accept(chunk),
+            Self::Virtio(console) => console.accept(chunk),
+            Self::Absent => {}
+        }
+    }
 
+    fn is_ready(&mut self) -> bool {
+        match self {
+            Self::Uart(uart) => uart.is_ready(),
+            Self::History(history) => history.is_ready(),
+            Self::Semihosting(console) => console.is_ready(),
+            Self::Virtio(console) => console.is_ready(),
+            Self::Absent => false,
+        }
+    }
+}
+
+/// The global kernel logger instance.
+///
+/// Until the output devices are attached, records are kept in the logger's buffer.
+static LOGGER: Logger<SinkSet<KernelLogSink, 4>, SystemGlobalValueReader> =
+    Logger::new_without_sink(log::LevelFilter::Trace);
+
+/// Report a panic and a backtrace directly on the UART, after any log messages that are still
+/// buffered.
+///
+/// Does nothing if the output devices have not been attached yet.
+pub fn log_panic(info: &core::panic::PanicInfo) {
+    // interrupts will no longer drain the UART's queue
+    if let Some(uart) = uart::UART.get() {
+        uart.disable_interrupts();
+    }
+    let symbols = crate::debug::symbol_table();
+    let backtrace = crate::debug::current_backtrace();
+    let backtrace = match &symbols {
+        Some(symbols) => backtrace.with_symbolizer(symbols),
+        None => backtrace,
+    };
+    // name the thread that panicked once there are threads, since there can be many of them.
+    // The panic may have happened with any lock held, so the thread is found without locking.
+    let thread = crate::thread::SCHEDULER
+        .get()
+        .and_then(|s| s.current_thread_on(SystemCpuIdReader::current_cpu()));
+    match thread {
+        Some(thread) => {
+            let thread = thread.display_without_waiting();
+            LOGGER.write_panic_message(format_args!("{info}\nin thread {thread}\n{backtrace}"));
+        }
+        None => LOGGER.write_panic_message(format_args!("{info}\n{backtrace}")),
+    }
+}
+
+/// Install the kernel global logger so that records can be logged as early as possible during
+/// boot. They will be output once [`init_logging`] is called.
+pub fn init_early_logging() {
     log::set_max_level(log::LevelFilter::max());
-    log::set_logger(LOGGER.call_once(|| Logger::new(uart, log::LevelFilter::max())) as _).unwrap();
+    log::set_logger(&LOGGER).unwrap();
+}
+
+/// Attach the output devices to the kernel global logger, flushing any records logged so far.
+///
+/// If there is no UART, the devices are attached later by [`init_late_logging`] instead, so that
+/// the records are kept until the virtio console is available.
+pub fn init_logging(device_tree: &DeviceTree, boot_args: &BootArgs) {
+    let stdout_device_path = uart::stdout_path(device_tree);
+
+    // the console is bound before the heap is available, so that it can be used straight away
+    crate::driver::probe_node(device_tree, stdout_device_path);
+    if uart::UART.get().is_some() {
+        attach_sinks(boot_args);
+    } else {
+        warn!(
+            "No UART found at stdout path {:?}, log output waits for the virtio console",
+            core::str::from_utf8(stdout_device_path)
+        );
+    }
 
     info!(
         "\x1b[1mCavern 🕳️\x1b[0m v{} (git: {}@{})",
@@ -69,3 +158,36 @@ pub fn init_logging(device_tree: &DeviceTree) {
         crate::running_image::memory_region()
     },);
 }
+
+/// Attach the output devices to the kernel global logger again once the drivers have been probed,
+/// so that the virtio console receives output if it was found, or so that output isn't held back
+/// any longer if [`init_logging`] didn't find a UART.
+pub fn init_late_logging(boot_args: &BootArgs) {
+    let uart = uart::UART.get().is_some();
+    let console = crate::virtio::CONSOLE.get().is_some();
+    if !uart && !console {
+        warn!("No UART or virtio console found, log output is only kept in the history");
+    }
+    if !uart || console {
+        attach_sinks(boot_args);
+    }
+}
+
+/// Attach every output device that is present to the kernel global logger.
+///
+/// Output is also copied to the semihosting host's console if the `semihosting` boot argument is
+/// set.
+fn attach_sinks(boot_args: &BootArgs) {
+    // a missing device never receives output, rather than counting it as dropped
+    let present = |sink: Option<KernelLogSink>| {
+        sink.map_or((KernelLogSink::Absent, log::LevelFilter::Off), |sink| {
+            (sink, log::LevelFilter::max())
+        })
+    };
+    LOGGER.attach_sink(SinkSet::new([
+        present(uart::UART.get().map(KernelLogSink::Uart)),
+        (KernelLogSink::History(&LOG_HISTORY), log::LevelFilter::Info),
+        present(crate::semihosting::console(boot_args).map(KernelLogSink::Semihosting)),
+        present(crate::virtio::CONSOLE.get().map(KernelLogSink::Virtio)),
+    ]));
+}
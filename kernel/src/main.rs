@@ -10,24 +10,51 @@ extern crate alloc;
 
 core::arch::global_asm!(core::include_str!("./start.S"));
 
+mod bootfs;
+mod branch_protection;
+mod cpu;
+mod debug;
+mod driver;
 mod exceptions;
+mod gpio;
+mod idle;
+mod kpti;
+mod kthread;
+mod lockup;
 mod logging;
 mod memory;
+mod monitor;
+mod process;
 mod psci;
+mod rtc;
 mod running_image;
+mod selftest;
+mod semihosting;
+mod smmu;
 mod thread;
 mod timer;
 mod uart;
+mod virtio;
+mod watchdog;
 
+use core::ffi::CStr;
 use kernel_core::{
     memory::{PhysicalAddress, PhysicalPointer},
     platform::{
-        cpu::{boot_all_cores, list_cores, CoreInfo},
-        device_tree::DeviceTree,
+        boot_args::{BootArgs, Value as BootValue},
+        cpu::{boot_all_cores, CoreInfo, CpuIdReader as _},
+        device_tree::{DeviceTree, Value},
+        info::PlatformInfo as _,
     },
+    smp::{PanicEntry, PanicLatch},
 };
-use log::{debug, info};
-use memory::page_allocator;
+use log::{debug, info, warn};
+
+/// The number of tracepoint records kept for each core.
+const TRACE_CAPACITY: usize = 1024;
+
+/// How long to wait for the secondary cores to come online before carrying on without them.
+const CORE_BOOT_TIMEOUT_NANOS: u64 = 1_000_000_000;
 
 /// The main entry point for the kernel.
 ///
@@ -44,22 +71,78 @@ pub extern "C" fn kmain(device_tree_blob: PhysicalPointer<u8>) -> ! {
         running_image::zero_bss_section();
         exceptions::install_exception_vector();
     }
+    thread::init_debug_for_core();
+
+    logging::init_early_logging();
+
+    #[cfg(feature = "lockdep")]
+    kernel_core::sync::lockdep::init::<thread::SystemCpuIdReader>(
+        kernel_core::sync::lockdep::Policy::Panic,
+    );
 
     let device_tree = unsafe { DeviceTree::from_memory(device_tree_blob.into()) };
+    debug!("Device tree blob at {device_tree_blob:?}");
+
+    let boot_args = boot_args(&device_tree);
+    semihosting::init(&boot_args);
+
+    memory::randomize_physical_map(&device_tree);
+    // the device tree was found through the boot mapping, which goes away once the kernel image
+    // is protected
+    let device_tree = unsafe { DeviceTree::from_memory(device_tree_blob.into()) };
+    let boot_args = self::boot_args(&device_tree);
+    memory::randomize_stack_canary();
+    cpu::init();
+    branch_protection::enable_for_core();
+
+    logging::init_logging(&device_tree, &boot_args);
+    kpti::init(&boot_args);
 
-    logging::init_logging(&device_tree);
+    cpu::init_topology(&device_tree);
 
     memory::init(&device_tree);
+    uart::reserve_registers();
+    memory::protect_kernel_image();
+    memory::protect_boot_stack();
 
-    let cores = list_cores(&device_tree).expect("list cores in system");
+    // now that the heap is available, avoid rescanning the tree for every lookup
+    device_tree.build_index();
+
+    driver::probe_all(&device_tree);
+    logging::init_late_logging(&boot_args);
+
+    timer::init_time_page();
+
+    let cores = device_tree.cores().expect("list cores in system");
     debug!("System has {} cores", cores.len());
 
+    kernel_core::trace::init::<thread::SystemCpuIdReader, timer::SystemCounter>(
+        cores.iter().map(|info| info.id),
+        TRACE_CAPACITY,
+    );
+    enable_tracepoints(&boot_args);
+
     thread::init(&cores);
+    process::init(cores.len());
 
-    exceptions::init_interrupts(&device_tree);
+    exceptions::init_interrupts(&device_tree, &cores);
+
+    watchdog::init(&device_tree, &cores);
+
+    lockup::init();
 
     init_smp(&device_tree, &cores);
 
+    kpti::install_tables();
+
+    idle::init(&device_tree, &cores);
+
+    selftest::run_if_requested(&boot_args);
+
+    monitor::spawn_if_console();
+
+    bootfs::init(&device_tree, &boot_args);
+
     info!("Boot succesful!");
 
     unsafe {
@@ -71,6 +154,36 @@ pub extern "C" fn kmain(device_tree_blob: PhysicalPointer<u8>) -> ! {
     }
 }
 
+/// Read the kernel command line from the device tree. Invalid arguments are ignored, with a
+/// warning.
+fn boot_args<'dt>(device_tree: &'dt DeviceTree) -> BootArgs<'dt> {
+    let text = device_tree
+        .find_property(b"/chosen/bootargs")
+        .and_then(|value| match value {
+            Value::String(s) => Some(s.to_bytes()),
+            Value::Bytes(b) => Some(CStr::from_bytes_until_nul(b).map_or(b, CStr::to_bytes)),
+            _ => None,
+        })
+        .unwrap_or_default();
+    BootArgs::parse(text).unwrap_or_else(|e| {
+        warn!("ignoring boot arguments: {e}");
+        BootArgs::default()
+    })
+}
+
+/// Enable the tracepoints whose names start with the `trace` boot argument, if there is one.
+fn enable_tracepoints(boot_args: &BootArgs) {
+    let Some(BootValue::String(prefix)) = boot_args.get(b"trace") else {
+        return;
+    };
+    let Ok(prefix) = core::str::from_utf8(prefix) else {
+        warn!("ignoring trace boot argument that is not UTF-8");
+        return;
+    };
+    let count = kernel_core::trace::set_enabled(prefix, true);
+    info!("Enabled {count} tracepoints matching {prefix:?}");
+}
+
 extern "C" {
     /// The true entry point for non-boot cores. Defined in `start.S`.
     pub fn _secondary_core_start();
@@ -78,12 +191,25 @@ extern "C" {
 
 /// Initialize power control interface and boot the rest of the cores in the system.
 fn init_smp(device_tree: &DeviceTree, cores: &[CoreInfo]) {
-    let power = psci::Psci::in_device_tree(device_tree).expect("get PSCI info from device tree");
+    let power = psci::POWER.call_once(|| {
+        psci::Psci::in_device_tree(device_tree).expect("get PSCI info from device tree")
+    });
 
     let entry_point_address = PhysicalAddress::from(_secondary_core_start as *mut ());
 
-    boot_all_cores(cores, &power, entry_point_address, page_allocator())
-        .expect("boot all cores on board");
+    let timeout = timer::clock().nanos_to_ticks(CORE_BOOT_TIMEOUT_NANOS);
+    let failures = boot_all_cores::<_, timer::SystemCounter>(
+        cores,
+        thread::CORES.wait(),
+        power,
+        entry_point_address,
+        timeout,
+        |id| Ok(memory::allocate_core_stack(id)),
+        memory::free_core_stack,
+    );
+    for (id, _) in failures {
+        thread::remove_core(id);
+    }
 }
 
 /// The main entry point for secondary cores in an SMP system.
@@ -91,10 +217,21 @@ fn init_smp(device_tree: &DeviceTree, cores: &[CoreInfo]) {
 /// This function is called by `start.S:_secondary_core_start` after it sets up virtual memory, the stack, etc.
 #[no_mangle]
 pub extern "C" fn secondary_core_kmain() -> ! {
+    branch_protection::enable_for_core();
+    if !thread::CORES
+        .wait()
+        .report_online(thread::SystemCpuIdReader::current_cpu())
+    {
+        // the boot core gave up on us, so stop here (it keeps our stack, which we are running on)
+        exceptions::halt_current_core();
+    }
     unsafe {
         exceptions::install_exception_vector();
     }
 
+    thread::init_debug_for_core();
+    process::init_for_core();
+
     debug!("Secondary core init");
 
     exceptions::init_interrupts_for_core();
@@ -108,24 +245,23 @@ pub extern "C" fn secondary_core_kmain() -> ! {
     }
 }
 
+/// Records which core is reporting a panic.
+static PANIC_LATCH: PanicLatch = PanicLatch::new();
+
 /// The kernel-wide panic handler.
 ///
 /// Code here should not assume anything about the state of the kernel.
-/// Currently this only writes to the platform defined debug UART.
+/// The first core to panic halts all the other cores and then writes the panic message to the
+/// debug UART. Any other core that panics at the same time halts without reporting anything, so
+/// that the output is never interleaved.
 #[panic_handler]
 #[cfg(not(test))]
 pub fn panic_handler(info: &core::panic::PanicInfo) -> ! {
-    // TODO: somehow make sure that if one core panics, they all halt. Probably via SGI?
-
-    log::error!("{info}");
-
-    /*use core::fmt::Write;
-    unsafe {
-        let mut uart = uart::PL011::from_platform_debug_best_guess();
-
-        writeln!(&mut uart, "\x1b[31mpanic!\x1b[0m {info}").unwrap();
-    }*/
+    if PANIC_LATCH.enter(thread::SystemCpuIdReader::current_cpu()) == PanicEntry::First {
+        exceptions::halt_other_cores();
+        logging::log_panic(info);
+        gpio::blink_panic_led();
+    }
 
-    #[allow(clippy::empty_loop)]
-    loop {}
+    exceptions::halt_current_core()
 }
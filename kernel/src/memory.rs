@@ -4,17 +4,29 @@
 //! - the global physical page allocator
 //! - the MMU and the kernel page tables
 //! - the Rust heap
-use crate::running_image;
+use crate::{cpu, kpti, running_image};
+use alloc::vec::Vec;
 use core::ptr::addr_of_mut;
-use itertools::Itertools as _;
 use kernel_core::{
     memory::{
+        kernel_vm::KernelStack,
+        kpti::UserKernelTables,
+        map::{Region, RegionKind},
         page_table::{MapBlockSize, MemoryKind, MemoryProperties},
-        BuddyPageAllocator, HeapAllocator, PageAllocator, PageSize, PageTables, PhysicalAddress,
+        physical_map_base, physmap, set_kernel_image, set_physical_map_base,
+        stack_check::{self, CheckedStack},
+        AddressSpaceId, BuddyPageAllocator, DmaAllocator, HeapAllocator, HeapStatistics,
+        KernelVmAllocator, MemoryManagmentUnit, MemoryMap, MemoryStatistics, OomPolicy,
+        PageAllocator, PageFrameDatabase, PageSize, PageTables, PhysicalAddress,
+        ReclaimingPageAllocator, VirtualAddress, ZoneId, ZonedPageAllocator,
     },
-    platform::device_tree::DeviceTree,
+    platform::{
+        cpu::{CpuIdReader as _, Id as CpuId},
+        device_tree::DeviceTree,
+    },
+    process::mmio::MmioRegistry,
 };
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use spin::{once::Once, Mutex};
 
 extern "C" {
@@ -22,9 +34,20 @@ extern "C" {
     static mut _kernel_page_table_root: u8;
 }
 
-type ChosenPageAllocator = BuddyPageAllocator;
+/// The page allocator used for all physical memory.
+pub type ChosenPageAllocator =
+    ReclaimingPageAllocator<'static, ZonedPageAllocator<BuddyPageAllocator>>;
+
+/// The physical page allocator for each range of RAM.
+static ZONES: Once<ZonedPageAllocator<BuddyPageAllocator>> = Once::new();
+
+/// Every range of physical memory and what it is used for, recorded while booting.
+static MEMORY_MAP: Once<MemoryMap> = Once::new();
+
+/// Decides how memory is reclaimed when the kernel runs out.
+static OOM_POLICY: OomPolicy = OomPolicy::new();
 
-/// The global physical page allocator.
+/// The global physical page allocator, which reclaims memory when it runs out.
 static PAGE_ALLOCATOR: Once<ChosenPageAllocator> = Once::new();
 
 #[global_allocator]
@@ -36,6 +59,44 @@ static ALLOCATOR: HeapAllocator<'static, ChosenPageAllocator> = HeapAllocator::n
 /// Map addresses in TTBR1, matching `0xffff_????_????_????`.
 static KERNEL_PAGE_TABLES: Once<Mutex<PageTables<'static, ChosenPageAllocator>>> = Once::new();
 
+/// The reduced kernel page tables used while user threads run, if kernel page table isolation is
+/// enabled.
+static USER_KERNEL_TABLES: Once<Mutex<UserKernelTables<'static, ChosenPageAllocator>>> =
+    Once::new();
+
+/// Reference counts and flags for every page of physical memory.
+static PAGE_FRAMES: Once<PageFrameDatabase> = Once::new();
+
+/// Claims on device MMIO regions by driver processes.
+static MMIO_REGISTRY: Once<MmioRegistry> = Once::new();
+
+/// Start of the region of kernel virtual addresses that device MMIO regions are mapped into.
+const DEVICE_REGION_START: usize = 0xffff_8000_0000_0000;
+/// Length in bytes of the device MMIO region.
+const DEVICE_REGION_LENGTH: usize = 0x10_0000_0000;
+
+/// Number of pages in the kernel stack of each secondary core.
+const CORE_STACK_PAGES: usize = 1024;
+
+/// Number of pages in the emergency stack of each core, used to report an overflow of the core's
+/// own stack.
+const EMERGENCY_STACK_PAGES: usize = 16;
+
+/// Space left at the top of the boot core's stack when it is watermarked, for the frames in use
+/// while the watermark is written.
+const BOOT_STACK_WATERMARK_MARGIN: usize = 0x1000;
+
+/// The kernel stack of each core, with a canary that is checked on every context switch, and the
+/// allocation backing it for every core but the boot core.
+static CORE_STACKS: Mutex<Vec<(CpuId, CheckedStack, Option<KernelStack>)>> = Mutex::new(Vec::new());
+
+/// The emergency stack of each core, which the exception vector switches to if the core's own
+/// stack overflows.
+static EMERGENCY_STACKS: Mutex<Vec<(CpuId, KernelStack)>> = Mutex::new(Vec::new());
+
+/// Allocator for kernel virtual addresses used to map devices.
+static KERNEL_VM_ALLOCATOR: Once<KernelVmAllocator> = Once::new();
+
 /// Flush the TLB for everything in EL1.
 ///
 /// # Safety
@@ -52,6 +113,127 @@ pub unsafe fn flush_tlb_total_el1() {
     );
 }
 
+/// The MMU of every core in the system.
+///
+/// All of the cores are in the same inner shareable domain, so TLB maintenance is broadcast to
+/// every core by the hardware and has completed on all of them when each operation returns.
+pub struct SystemMmu;
+
+impl SystemMmu {
+    /// Past this many pages it is cheaper to invalidate every entry than each page.
+    const MAX_PAGES_TO_INVALIDATE: usize = 64;
+
+    /// Make the user space page tables with the root table at `root` current on this core, with
+    /// lookups tagged with `asid`.
+    ///
+    /// # Safety
+    /// The tables must stay valid until other tables are activated.
+    pub unsafe fn activate_user_tables(root: PhysicalAddress, asid: AddressSpaceId) {
+        // the exception vector switches to the user ASID of the pair on the way to user space
+        let (asid, _) = kpti::hardware_asids(asid);
+        // `TCR_EL1.A1` is clear, so the ASID is taken from bits 63:48 of `TTBR0_EL1`
+        core::arch::asm!(
+            "msr TTBR0_EL1, {root}",
+            "isb",
+            root = in(reg) usize::from(root) as u64 | u64::from(asid) << 48
+        );
+    }
+}
+
+impl MemoryManagmentUnit for SystemMmu {
+    unsafe fn activate_page_tables<PA: PageAllocator>(
+        &self,
+        tables: &PageTables<'_, PA>,
+        asid: AddressSpaceId,
+    ) {
+        if tables.high_tag() {
+            let root = usize::from(tables.physical_address()) as u64;
+            core::arch::asm!("msr TTBR1_EL1, {root}", "isb", root = in(reg) root);
+        } else {
+            Self::activate_user_tables(tables.physical_address(), asid);
+        }
+    }
+
+    fn synchronize_tables(&self) {
+        unsafe {
+            core::arch::asm!("DSB ISHST");
+        }
+    }
+
+    fn invalidate_asid(&self, asid: AddressSpaceId) {
+        let (kernel, user) = kpti::hardware_asids(asid);
+        unsafe {
+            core::arch::asm!("DSB ISHST");
+            for asid in core::iter::once(kernel).chain(user) {
+                core::arch::asm!("TLBI ASIDE1IS, {v}", v = in(reg) u64::from(asid) << 48);
+            }
+            core::arch::asm!("DSB ISH", "ISB");
+        }
+    }
+
+    fn invalidate_va_range(
+        &self,
+        asid: Option<AddressSpaceId>,
+        start: VirtualAddress,
+        length: usize,
+    ) {
+        let pages = length.div_ceil(0x1000);
+        if pages > Self::MAX_PAGES_TO_INVALIDATE {
+            match asid {
+                Some(asid) => self.invalidate_asid(asid),
+                None => self.invalidate_all(),
+            }
+            return;
+        }
+        // the operand holds VA[55:12] in the low bits and the ASID in bits 63:48
+        let page_number = (usize::from(start) >> 12) & 0xfff_ffff_ffff;
+        unsafe {
+            core::arch::asm!("DSB ISHST");
+            for i in 0..pages {
+                let va = (page_number + i) as u64;
+                match asid.map(kpti::hardware_asids) {
+                    Some((kernel, user)) => {
+                        for asid in core::iter::once(kernel).chain(user) {
+                            core::arch::asm!(
+                                "TLBI VAE1IS, {v}",
+                                v = in(reg) va | u64::from(asid) << 48
+                            );
+                        }
+                    }
+                    None => core::arch::asm!("TLBI VAAE1IS, {v}", v = in(reg) va),
+                }
+            }
+            core::arch::asm!("DSB ISH", "ISB");
+        }
+    }
+
+    fn invalidate_all(&self) {
+        unsafe {
+            core::arch::asm!("DSB ISHST", "TLBI VMALLE1IS", "DSB ISH", "ISB");
+        }
+    }
+
+    fn synchronize_instruction_cache(&self, start: PhysicalAddress, length: usize) {
+        let ctr: u64;
+        unsafe {
+            core::arch::asm!("mrs {v}, CTR_EL0", v = out(reg) ctr);
+        }
+        // the smallest data cache line, in words
+        let line = 4 << ((ctr >> 16) & 0xf);
+        let first: *mut u8 = start.cast().into();
+        let start = first as usize & !(line - 1);
+        let end = first as usize + length;
+        unsafe {
+            for address in (start..end).step_by(line) {
+                core::arch::asm!("DC CVAU, {a}", a = in(reg) address);
+            }
+            // the instruction cache may be indexed by virtual address, and user space reaches the
+            // code through a different mapping, so every line is invalidated
+            core::arch::asm!("DSB ISH", "IC IALLUIS", "DSB ISH", "ISB");
+        }
+    }
+}
+
 /// Write the `MAIR_EL1` register.
 pub unsafe fn write_mair(value: u64) {
     core::arch::asm!(
@@ -60,79 +242,117 @@ pub unsafe fn write_mair(value: u64) {
     );
 }
 
-/// Initialize the memory subsystem.
-pub fn init(dt: &DeviceTree<'_>) {
-    debug!("Initializing memory…");
-    // create page allocator
-    let page_size = PageSize::FourKiB;
-    let memory_node = dt
-        .iter_nodes_named(b"/", b"memory")
-        .expect("root")
-        .exactly_one()
-        .expect("device tree has memory node");
-    let memory_range = memory_node
-        .properties
-        .clone()
-        .find(|(name, _)| name == b"reg")
-        .and_then(|(_, v)| v.into_reg())
-        .expect("memory node has reg property")
-        .iter()
-        .exactly_one()
-        .expect("memory has exactly one reg range");
-    let reserved_regions = [
-        unsafe { running_image::memory_region() },
-        dt.memory_region(),
-    ];
-    let memory_start = PhysicalAddress::from(memory_range.0);
-    let mut memory_regions = kernel_core::memory::subtract_ranges(
-        (memory_start.cast().into(), memory_range.1),
-        reserved_regions.into_iter(),
-    );
-    trace!(
-        "memory range = {memory_start:?}{memory_range:x?}, reserved = {reserved_regions:x?}, page size = {page_size:?}"
-    );
+/// Call `f` with the start address and length of each range of RAM described by the `/memory`
+/// nodes of the device tree.
+fn for_each_memory_range(dt: &DeviceTree<'_>, mut f: impl FnMut(usize, usize)) {
+    for node in dt.iter_nodes_named(b"/", b"memory").expect("root") {
+        let regs = node
+            .properties
+            .clone()
+            .find(|(name, _)| name == b"reg")
+            .and_then(|(_, v)| v.into_reg())
+            .expect("memory node has reg property");
+        for (start, length) in &regs {
+            f(start, length);
+        }
+    }
+}
+
+/// Record every range of RAM and everything in it that the page allocator must not hand out: the
+/// kernel image, the device tree blob, the initrd and the device tree's memory reservations. The
+/// addresses below the lowest range of RAM are recorded as MMIO.
+///
+/// # Panics
+/// Panics if there are too many regions to record, since memory in a region that was left out
+/// could be allocated while it is still in use.
+fn build_memory_map(dt: &DeviceTree<'_>) -> MemoryMap {
+    let mut map = MemoryMap::new();
+    let mut add = |start: PhysicalAddress, length: usize, kind: RegionKind| {
+        if let Err(e) = map.add(Region::new(start, length, kind)) {
+            panic!("{e}");
+        }
+    };
 
-    let pa = PAGE_ALLOCATOR.call_once(|| unsafe {
-        BuddyPageAllocator::new(page_size, memory_start.cast().into(), memory_range.1)
+    let mut lowest_memory_start = usize::MAX;
+    for_each_memory_range(dt, |start, length| {
+        lowest_memory_start = lowest_memory_start.min(start);
+        add(start.into(), length, RegionKind::Usable);
     });
+    if lowest_memory_start != usize::MAX {
+        add(0.into(), lowest_memory_start, RegionKind::Mmio);
+    }
 
-    let first_region = memory_regions.next().expect("at least one memory region");
-    trace!(
-        "adding first memory region to physical page allocator ({:x?}, {:x})",
-        first_region.0,
-        first_region.1
+    let (image_start, image_length) = unsafe { running_image::memory_region() };
+    add(
+        image_start.cast::<()>().into(),
+        image_length,
+        RegionKind::Kernel,
     );
-    unsafe {
-        assert!(pa.add_memory_region(first_region.0, first_region.1));
+
+    let (dt_start, dt_length) = dt.memory_region();
+    add(
+        dt_start.cast::<()>().into(),
+        dt_length,
+        RegionKind::DeviceTree,
+    );
+
+    match kernel_core::init::initrd_region(dt) {
+        Ok(Some((start, length))) => add(start, length, RegionKind::Initrd),
+        Ok(None) => {}
+        Err(e) => warn!("failed to find initrd, its memory may be reused: {e}"),
+    }
+
+    for (start, length) in dt.iter_reserved_memory_regions() {
+        let (Ok(start), Ok(length)) = (usize::try_from(start), usize::try_from(length)) else {
+            warn!("ignoring reserved memory region {start:#x}+{length:#x} outside address space");
+            continue;
+        };
+        add(start.into(), length, RegionKind::Reserved);
     }
 
-    // setup page tables
+    map
+}
+
+/// Set up the kernel's page tables, mapping every zone of RAM and the MMIO addresses below them.
+fn init_kernel_page_tables(pa: &'static ChosenPageAllocator) {
+    let zones = pa.inner();
     KERNEL_PAGE_TABLES.call_once(|| unsafe {
         let root_table_address = addr_of_mut!(_kernel_page_table_root);
         let mut pt =
             PageTables::from_existing(pa, PhysicalAddress::from(root_table_address.cast()), true);
+        if kpti::is_enabled() {
+            pt.make_not_global();
+        }
         let block_size = MapBlockSize::largest_supported_block_size(pa.page_size());
         let block_size_in_bytes = block_size.length_in_bytes(pa.page_size()).unwrap();
-        let memory_size_in_blocks = memory_range.1.div_ceil(block_size_in_bytes);
-        trace!("mapping RAM {memory_start:?}, {memory_size_in_blocks} {block_size:?}");
-        pt.map(
-            memory_start.into(),
-            memory_start,
-            memory_size_in_blocks,
-            block_size,
-            &MemoryProperties {
-                writable: true,
-                executable: true,
-                ..MemoryProperties::default()
-            },
-        )
-        .expect("identity map RAM into kernel");
+        let mut lowest_memory_start = usize::MAX;
+        for id in (0..zones.zone_count()).map(ZoneId) {
+            let (memory_start, memory_length) = zones.zone_range(id).unwrap();
+            lowest_memory_start = lowest_memory_start.min(memory_start.into());
+            let memory_size_in_blocks = memory_length.div_ceil(block_size_in_bytes);
+            trace!("mapping RAM {memory_start:?}, {memory_size_in_blocks} {block_size:?}");
+            pt.map(
+                memory_start.into(),
+                memory_start,
+                memory_size_in_blocks,
+                block_size,
+                // executable only until `protect_kernel_image` has swapped out the boot mapping
+                // while running from here
+                &MemoryProperties {
+                    writable: true,
+                    executable: true,
+                    shareability: cpu::topology().shareability(),
+                    ..MemoryProperties::default()
+                },
+            )
+            .expect("identity map RAM into kernel");
+        }
 
         trace!("mapping low addresses as MMIO");
         pt.map(
-            0xffff_0000_0000_0000.into(),
+            physical_map_base().into(),
             0.into(),
-            usize::from(memory_start) / block_size_in_bytes,
+            lowest_memory_start / block_size_in_bytes,
             block_size,
             &MemoryProperties {
                 writable: true,
@@ -146,33 +366,554 @@ pub fn init(dt: &DeviceTree<'_>) {
 
         Mutex::new(pt)
     });
+}
 
+/// Move the mapping of physical memory to a random location in the kernel's address space.
+///
+/// The kernel's entropy pool is seeded here, from the bootloader's KASLR seed and the jitter of the
+/// system counter, since this is the first thing that needs randomness.
+///
+/// This must be called before anything else converts physical addresses into kernel pointers, so
+/// that the rest of the kernel agrees on where physical memory is. Pointers made before then must be
+/// made again. The boot mapping made by `start.S` is left in place, since the kernel image still
+/// runs from it, until [`protect_kernel_image`] replaces it.
+pub fn randomize_physical_map(dt: &DeviceTree<'_>) {
+    if let Some(seed) = physmap::seed_from_device_tree(dt) {
+        kernel_core::rand::add_entropy(&seed.to_le_bytes(), size_of::<u64>());
+    } else {
+        warn!("bootloader provided no KASLR seed, falling back to system counter jitter");
+    }
+    kernel_core::rand::add_jitter(crate::timer::read_virtual_counter);
+    let slot = physmap::choose_slot(kernel_core::rand::next_u64());
     unsafe {
-        // TODO: mess with TCR to make sure that page sizes and address sizes are as expected.
-        // install MAIR value that corresponds to the [`MemoryKind`] enum encoding.
-        write_mair(kernel_core::memory::page_table::MAIR_VALUE);
-
-        // Flush the TLB to ensure that the new page table mapping takes effect.
+        // the boot mapping is entirely contained in the first entry of the (page aligned) root table
+        #[allow(clippy::cast_ptr_alignment)]
+        let root = addr_of_mut!(_kernel_page_table_root).cast::<u64>();
+        root.add(slot).write_volatile(root.read_volatile());
         flush_tlb_total_el1();
+        set_physical_map_base(physmap::slot_base(slot));
+    }
+    debug!("Physical memory mapped at {:#x}", physical_map_base());
+}
+
+/// Choose a random canary for the kernel stacks prepared from now on.
+pub fn randomize_stack_canary() {
+    stack_check::set_canary(kernel_core::rand::next_u64());
+}
+
+/// Initialize the memory subsystem.
+pub fn init(dt: &DeviceTree<'_>) {
+    debug!("Initializing memory…");
+    // create page allocator, with a zone for each range of RAM
+    let page_size = PageSize::FourKiB;
+    assert!(
+        cpu::features().supports_page_size(page_size),
+        "core does not support {page_size:?} pages"
+    );
+    let map = MEMORY_MAP.call_once(|| build_memory_map(dt));
+    for region in map.regions() {
+        debug!("memory region {region}");
     }
+    let zone_allocator = ZONES.call_once(|| {
+        let mut pa = ZonedPageAllocator::new(page_size);
+        for ram in map.regions().filter(|r| r.kind == RegionKind::Usable) {
+            trace!("memory range = {ram}, page size = {page_size:?}");
+            let zone = unsafe {
+                BuddyPageAllocator::new(page_size, ram.start().cast().into(), ram.length)
+            };
+            if pa.add_zone(ram.start(), ram.length, zone).is_none() {
+                warn!("too many memory ranges, ignoring {ram}");
+            }
+        }
+        pa
+    });
+    let pa = PAGE_ALLOCATOR.call_once(|| ReclaimingPageAllocator::new(zone_allocator, &OOM_POLICY));
 
-    for (region_start, region_length) in memory_regions {
+    // only give the allocator whole pages, so that nothing next to a reserved region is touched
+    let page_len = usize::from(page_size);
+    let mut usable_regions = map.usable_regions().filter_map(|(start, length)| {
+        let aligned_start = usize::from(start).next_multiple_of(page_len);
+        let end = (usize::from(start) + length) / page_len * page_len;
+        (end > aligned_start).then(|| (PhysicalAddress::from(aligned_start), end - aligned_start))
+    });
+    let add_region = |(start, length): (PhysicalAddress, usize)| {
+        let Some(zone) = zone_allocator.zone_containing(start) else {
+            warn!("usable memory {start:?}+{length:#x} is not in any zone");
+            return;
+        };
         trace!(
-            "adding additional memory region to physical page allocator ({region_start:x?}, {region_length:x})",
+            "adding memory region to physical page allocator zone {} ({start:?}, {length:#x})",
+            zone.0
         );
         unsafe {
-            assert!(pa.add_memory_region(region_start, region_length));
+            zone_allocator
+                .zone(zone)
+                .unwrap()
+                .add_memory_region(start.cast().into(), length);
         }
+    };
+
+    // the page tables need some memory before all of RAM is mapped
+    add_region(
+        usable_regions
+            .next()
+            .expect("at least one usable memory region"),
+    );
+
+    init_kernel_page_tables(pa);
+
+    unsafe {
+        // TODO: mess with TCR to make sure that page sizes and address sizes are as expected.
+        // install MAIR value that corresponds to the [`MemoryKind`] enum encoding.
+        write_mair(kernel_core::memory::page_table::MAIR_VALUE);
+
+        // Flush the TLB to ensure that the new page table mapping takes effect.
+        flush_tlb_total_el1();
     }
 
+    usable_regions.for_each(add_region);
+
     // initialize kernel heap
     ALLOCATOR.init(pa);
 
-    info!("Memory initialized!");
+    OOM_POLICY.register("heap", |_| ALLOCATOR.trim());
+    OOM_POLICY.register("logs", |_| {
+        // this frees nothing, but gets everything logged so far out in case things get worse
+        log::logger().flush();
+        0
+    });
+
+    PAGE_FRAMES.call_once(|| {
+        PageFrameDatabase::new(
+            page_size,
+            (0..zone_allocator.zone_count()).filter_map(|id| zone_allocator.zone_range(ZoneId(id))),
+        )
+    });
+
+    MMIO_REGISTRY.call_once(|| {
+        MmioRegistry::new(
+            page_size,
+            (0..zone_allocator.zone_count()).filter_map(|id| zone_allocator.zone_range(ZoneId(id))),
+        )
+    });
+
+    KERNEL_VM_ALLOCATOR.call_once(|| {
+        KernelVmAllocator::new(page_size, DEVICE_REGION_START.into(), DEVICE_REGION_LENGTH)
+    });
+
+    let stats = pa.statistics();
+    info!(
+        "Memory initialized! {} of {} pages free, largest free block {} pages",
+        stats.free_pages, stats.total_pages, stats.largest_free_block
+    );
+}
+
+/// Replace the boot mapping with a mapping of only the kernel image, in which no part of the image
+/// is both writable and executable.
+///
+/// The boot mapping covers the start of physical memory with large blocks at the image's link
+/// address. Once physical memory is mapped at its randomized location, the rest of it would only
+/// be an alias that gives away where physical memory is, so the new mapping maps the image alone,
+/// with pages. It starts out with the boot mapping's permissions, then each section is protected:
+/// code is made read-only, read-only data is made non-executable, and the writable data (including
+/// `bss` and the boot stack) is made non-executable. The image runs from the boot mapping, so the
+/// new mapping is built off to the side and swapped in with break-before-make by
+/// [`replace_boot_mapping`], running from the randomized mapping of physical memory. After that,
+/// nothing runs from the mapping of physical memory, so it is made non-executable too.
+///
+/// This must be called after [`randomize_physical_map`] and before the secondary cores start.
+pub fn protect_kernel_image() {
+    let pa = PAGE_ALLOCATOR.wait();
+    let page_size = pa.page_size();
+    // nothing else may change the kernel page tables while the boot mapping is replaced
+    let mut kernel_tables = KERNEL_PAGE_TABLES.wait().lock();
+    let root: *mut u64 = PhysicalAddress::from(addr_of_mut!(_kernel_page_table_root).cast::<()>())
+        .cast()
+        .into();
+
+    // map the image in the first entry of a new root, so that the mapping can be built with the
+    // page table operations without touching the live tables
+    let side_root = pa
+        .allocate_zeroed(1)
+        .expect("allocate kernel image page table");
+    let mut side = unsafe { PageTables::from_existing(pa, side_root, true) };
+    if kpti::is_enabled() {
+        side.make_not_global();
+    }
+    let (image_start, image_length) = unsafe { running_image::memory_region() };
+    side.map(
+        VirtualAddress::from(image_start.cast::<()>()),
+        PhysicalAddress::from(image_start.cast::<()>()),
+        image_length.div_ceil(page_size.into()),
+        MapBlockSize::Page,
+        &MemoryProperties {
+            writable: true,
+            executable: true,
+            shareability: cpu::topology().shareability(),
+            ..MemoryProperties::default()
+        },
+    )
+    .expect("map kernel image");
+    let regions = unsafe {
+        [
+            ("text", running_image::text_region(), false, true),
+            ("rodata", running_image::read_only_region(), false, false),
+            ("data", running_image::writable_region(), true, false),
+        ]
+    };
+    for (name, (start, length), writable, executable) in regions {
+        trace!("protecting kernel {name} at {start:?}+{length:#x}");
+        // the side tables aren't live, so nothing needs to be flushed
+        side.protect(
+            VirtualAddress::from(start.cast::<()>()),
+            length.div_ceil(page_size.into()),
+            MapBlockSize::Page,
+            &MemoryProperties {
+                writable,
+                executable,
+                shareability: cpu::topology().shareability(),
+                ..MemoryProperties::default()
+            },
+        )
+        .expect("protect kernel image section");
+    }
+
+    // detach the new tables from the side root, so that only the root is freed with it
+    let new_entry = unsafe {
+        let side_root: *mut u64 = side_root.cast().into();
+        let entry = side_root.read();
+        side_root.write(0);
+        entry
+    };
+    drop(side);
+
+    unsafe {
+        replace_boot_mapping(root, new_entry);
+        set_kernel_image(image_start as usize, image_length);
+    }
+
+    let zones = pa.inner();
+    let block_size = MapBlockSize::largest_supported_block_size(page_size);
+    let block_size_in_bytes = block_size.length_in_bytes(page_size).unwrap();
+    for id in (0..zones.zone_count()).map(ZoneId) {
+        let (memory_start, memory_length) = zones.zone_range(id).unwrap();
+        // only permissions change, so the live entries are updated in place and then flushed
+        kernel_tables
+            .protect_live(
+                &SystemMmu,
+                0,
+                memory_start.into(),
+                memory_length.div_ceil(block_size_in_bytes),
+                block_size,
+                &MemoryProperties {
+                    writable: true,
+                    shareability: cpu::topology().shareability(),
+                    ..MemoryProperties::default()
+                },
+            )
+            .expect("make physical memory mapping non-executable");
+    }
+    debug!("Kernel image mapped W^X, boot mapping removed, physical memory mapping non-executable");
+}
+
+/// Replace the root table entry for the boot mapping (at `entry`) with `value`, using
+/// break-before-make.
+///
+/// The boot mapping holds the running kernel image, so the entry is replaced while running from
+/// the randomized mapping of physical memory. The sequence touches neither the stack nor any other
+/// memory in the boot mapping, and runs with exceptions masked since the exception vector is in
+/// the boot mapping too.
+///
+/// # Safety
+/// `entry` must point to the first entry of the live kernel root table through the randomized
+/// mapping of physical memory, which must map the kernel image executable. `value` must map the
+/// kernel image at the same addresses. No other core may be running.
+unsafe fn replace_boot_mapping(entry: *mut u64, value: u64) {
+    let alias_offset = physical_map_base() - kernel_core::memory::KERNEL_SPACE_START;
+    assert_ne!(alias_offset, 0, "physical memory is not randomized");
+    core::arch::asm!(
+        "mrs {daif}, DAIF",
+        "msr DAIFSet, #0xf",
+        // continue in the randomized mapping
+        "adr {tmp}, 2f",
+        "add {tmp}, {tmp}, {offset}",
+        "br {tmp}",
+        "2:",
+        // break
+        "str xzr, [{entry}]",
+        "dsb ishst",
+        "tlbi vmalle1",
+        "dsb ish",
+        "isb",
+        // make
+        "str {value}, [{entry}]",
+        "dsb ishst",
+        "isb",
+        // return to the boot mapping
+        "adr {tmp}, 3f",
+        "sub {tmp}, {tmp}, {offset}",
+        "br {tmp}",
+        "3:",
+        "msr DAIF, {daif}",
+        daif = out(reg) _,
+        tmp = out(reg) _,
+        offset = in(reg) alias_offset,
+        entry = in(reg) entry,
+        value = in(reg) value,
+    );
+}
+
+/// Build the reduced kernel page tables used while user threads run, returning the physical
+/// addresses of the root tables of the full and reduced kernel page tables.
+///
+/// The reduced tables map the exception vector, the data it reads and each core's kernel stack.
+/// This must be called once every core's stack has been allocated.
+///
+/// # Panics
+/// Panics if the memory subsystem is not initialized or the tables could not be built.
+pub fn build_user_kernel_tables() -> (PhysicalAddress, PhysicalAddress) {
+    let page_size = usize::from(PAGE_ALLOCATOR.wait().page_size());
+    let pt = KERNEL_PAGE_TABLES.wait().lock();
+    let mut user = UserKernelTables::new(PAGE_ALLOCATOR.wait()).expect("allocate root table");
+    let mut mirror = |start: VirtualAddress, length: usize, writable, executable| {
+        trace!("mapping {start:?}+{length:#x} into the user kernel page tables");
+        user.mirror(
+            &pt,
+            start,
+            length.div_ceil(page_size),
+            &MemoryProperties {
+                writable,
+                executable,
+                ..MemoryProperties::default()
+            },
+        )
+        .expect("map region into user kernel page tables");
+    };
+    let (trampoline, trampoline_length) = unsafe { running_image::trampoline_region() };
+    mirror(
+        VirtualAddress::from(trampoline.cast::<()>()),
+        trampoline_length,
+        false,
+        true,
+    );
+    let (data, data_length) = unsafe { running_image::trampoline_data_region() };
+    mirror(
+        VirtualAddress::from(data.cast::<()>()),
+        data_length,
+        false,
+        false,
+    );
+    for (_, stack, _) in CORE_STACKS.lock().iter() {
+        let length = usize::from(stack.top) - usize::from(stack.bottom);
+        mirror(stack.bottom, length, true, false);
+    }
+    let roots = (pt.physical_address(), user.physical_address());
+    USER_KERNEL_TABLES.call_once(|| Mutex::new(user));
+    roots
+}
+
+/// Get a snapshot of the usage of physical memory.
+pub fn statistics() -> MemoryStatistics {
+    PAGE_ALLOCATOR.wait().statistics()
+}
+
+/// Get a snapshot of the usage of the kernel heap.
+pub fn heap_statistics() -> HeapStatistics {
+    ALLOCATOR.statistics()
+}
+
+/// Returns the map of physical memory recorded while booting.
+pub fn memory_map() -> &'static MemoryMap {
+    MEMORY_MAP.wait()
+}
+
+/// Returns the policy used to reclaim memory when the kernel runs out.
+pub fn oom_policy() -> &'static OomPolicy {
+    &OOM_POLICY
 }
 
 /// Returns a reference to the current global physical page allocator.
-#[allow(unused)]
-pub fn page_allocator() -> &'static impl PageAllocator {
+pub fn page_allocator() -> &'static ChosenPageAllocator {
     PAGE_ALLOCATOR.wait()
 }
+
+/// Returns the database of reference counts and flags for every page of physical memory.
+pub fn page_frames() -> &'static PageFrameDatabase {
+    PAGE_FRAMES.wait()
+}
+
+/// Returns the registry of device MMIO regions claimed by driver processes.
+pub fn mmio_registry() -> &'static MmioRegistry {
+    MMIO_REGISTRY.wait()
+}
+
+/// Returns an allocator for buffers that devices access directly.
+pub fn dma_allocator() -> DmaAllocator<'static, impl PageAllocator> {
+    DmaAllocator::new(PAGE_ALLOCATOR.wait())
+}
+
+/// Allocate a kernel stack of `num_pages` pages, with unmapped guard pages below it.
+///
+/// # Panics
+/// Panics if the memory subsystem is not initialized or the stack could not be allocated.
+pub fn allocate_kernel_stack(num_pages: usize) -> KernelStack {
+    let mut pt = KERNEL_PAGE_TABLES.wait().lock();
+    let stack = KERNEL_VM_ALLOCATOR
+        .wait()
+        .map_stack(&mut pt, PAGE_ALLOCATOR.wait(), num_pages)
+        .expect("map kernel stack");
+    trace!("mapped {num_pages} page kernel stack at {:?}", stack.top);
+    stack
+}
+
+/// Unmap a kernel stack allocated by [`allocate_kernel_stack`] and free its pages.
+///
+/// The stack must not be in use by any core.
+///
+/// # Panics
+/// Panics if the memory subsystem is not initialized or the stack could not be unmapped.
+pub fn free_kernel_stack(stack: &KernelStack) {
+    KERNEL_VM_ALLOCATOR
+        .wait()
+        .unmap_stack(&mut KERNEL_PAGE_TABLES.wait().lock(), stack)
+        .expect("unmap kernel stack")
+        .apply(&SystemMmu, 0);
+    PAGE_ALLOCATOR
+        .wait()
+        .free(stack.pages, stack.num_pages)
+        .expect("free kernel stack pages");
+    trace!("freed kernel stack at {:?}", stack.top);
+}
+
+/// Allocate the kernel stack for the secondary core `id`, returning its initial stack pointer.
+///
+/// # Panics
+/// Panics if the memory subsystem is not initialized or the stack could not be allocated.
+pub fn allocate_core_stack(id: CpuId) -> VirtualAddress {
+    let stack = allocate_kernel_stack(CORE_STACK_PAGES);
+    let page_size = usize::from(PAGE_ALLOCATOR.wait().page_size());
+    let bottom = VirtualAddress::from(usize::from(stack.top) - stack.num_pages * page_size);
+    let checked = unsafe {
+        CheckedStack::prepare(
+            bottom,
+            stack.top,
+            cfg!(feature = "stack-watermark").then_some(stack.top),
+        )
+    };
+    let top = stack.top;
+    CORE_STACKS.lock().push((id, checked, Some(stack)));
+    allocate_emergency_stack(id);
+    top
+}
+
+/// Allocate the emergency stack of the core `id`, which must not be running yet unless it is the
+/// current core.
+fn allocate_emergency_stack(id: CpuId) {
+    let stack = allocate_kernel_stack(EMERGENCY_STACK_PAGES);
+    if unsafe { crate::exceptions::set_emergency_stack(id, Some(stack.top)) } {
+        EMERGENCY_STACKS.lock().push((id, stack));
+    } else {
+        warn!(
+            "core {id} can't have an emergency stack, so its stack overflows will not be reported"
+        );
+        free_kernel_stack(&stack);
+    }
+}
+
+/// Free the kernel stack allocated for the secondary core `id` by [`allocate_core_stack`], because
+/// the core failed to start. It must never be called for a core that may be running.
+///
+/// # Panics
+/// Panics if the memory subsystem is not initialized or the stack could not be unmapped.
+pub fn free_core_stack(id: CpuId) {
+    let stack = {
+        let mut stacks = CORE_STACKS.lock();
+        let Some(i) = stacks.iter().position(|(core, _, _)| *core == id) else {
+            return;
+        };
+        stacks.remove(i).2
+    };
+    if let Some(stack) = stack {
+        free_kernel_stack(&stack);
+    }
+    let emergency = {
+        let mut stacks = EMERGENCY_STACKS.lock();
+        stacks
+            .iter()
+            .position(|(core, _)| *core == id)
+            .map(|i| stacks.remove(i).1)
+    };
+    if let Some(stack) = emergency {
+        unsafe {
+            crate::exceptions::set_emergency_stack(id, None);
+        }
+        free_kernel_stack(&stack);
+    }
+}
+
+/// Write a canary to the bottom of the boot core's stack, which is currently in use, and allocate
+/// the boot core's emergency stack.
+pub fn protect_boot_stack() {
+    let (bottom, length) = unsafe { running_image::boot_stack_region() };
+    let bottom = VirtualAddress::from(bottom.cast::<()>());
+    let watermark_until = cfg!(feature = "stack-watermark").then(|| {
+        let sp: usize;
+        unsafe {
+            core::arch::asm!("mov {sp}, sp", sp = out(reg) sp);
+        }
+        VirtualAddress::from(sp - BOOT_STACK_WATERMARK_MARGIN)
+    });
+    let checked =
+        unsafe { CheckedStack::prepare(bottom, bottom.byte_add(length), watermark_until) };
+    let id = crate::thread::SystemCpuIdReader::current_cpu();
+    CORE_STACKS.lock().push((id, checked, None));
+    allocate_emergency_stack(id);
+}
+
+/// Check the canary of the current core's kernel stack.
+///
+/// # Panics
+/// Panics if the canary has been overwritten.
+pub fn check_core_stack() {
+    let id = crate::thread::SystemCpuIdReader::current_cpu();
+    if let Some((_, stack, _)) = CORE_STACKS.lock().iter().find(|(core, _, _)| *core == id) {
+        unsafe {
+            stack.check_canary();
+        }
+    }
+}
+
+/// The largest number of bytes each core has used of its kernel stack, if the stacks are
+/// watermarked.
+pub fn core_stack_usage() -> Vec<(CpuId, Option<usize>)> {
+    CORE_STACKS
+        .lock()
+        .iter()
+        .map(|(id, stack, _)| (*id, unsafe { stack.max_usage() }))
+        .collect()
+}
+
+/// Returns true if `address` is in the guard pages below a kernel stack.
+pub fn is_kernel_stack_guard(address: VirtualAddress) -> bool {
+    address.is_in_kernel_space()
+        && KERNEL_VM_ALLOCATOR
+            .get()
+            .is_some_and(|vm| vm.is_stack_guard(address))
+}
+
+/// Map the `length` bytes of device MMIO registers at physical address `base` into the kernel
+/// address space, returning a pointer to them.
+///
+/// # Panics
+/// Panics if the memory subsystem is not initialized or the region could not be mapped.
+pub fn map_device(base: PhysicalAddress, length: usize) -> *mut u8 {
+    let mut pt = KERNEL_PAGE_TABLES.wait().lock();
+    let va = KERNEL_VM_ALLOCATOR
+        .wait()
+        .map_device(&mut pt, base, length)
+        .expect("map device into kernel address space");
+    trace!("mapped device {base:?} ({length} bytes) at {va:?}");
+    // the kernel drives this device now, so no driver process may claim it too
+    MMIO_REGISTRY.wait().reserve(base, length);
+    usize::from(va) as *mut u8
+}
@@ -0,0 +1,122 @@
+//! The debug monitor, which reads commands from the kernel console and logs reports on the state of
+//! the kernel.
+//!
+//! Input comes from the UART if there is one, and otherwise from the virtio console (see
+//! [`kernel_core::debug::monitor`] for the commands).
+use core::time::Duration;
+
+use kernel_core::debug::monitor::{Command, LineBuffer, COMMANDS};
+use log::{info, warn};
+
+use crate::kthread;
+
+/// How long the monitor waits before checking for more input when there is none.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The longest command line the monitor accepts.
+const MAX_LINE: usize = 64;
+
+/// Read input from the kernel console into `buf`, returning the number of bytes read.
+fn read_input(buf: &mut [u8]) -> usize {
+    match crate::uart::UART.get() {
+        Some(uart) => uart.read(buf),
+        None => crate::virtio::read_console(buf),
+    }
+}
+
+/// Spawn a kernel thread to run the debug monitor, if there is a console to read commands from.
+pub fn spawn_if_console() {
+    if crate::uart::UART.get().is_none() && crate::virtio::CONSOLE.get().is_none() {
+        warn!("No console for the debug monitor to read input from");
+        return;
+    }
+    kthread::spawn("monitor", || {
+        let mut line = LineBuffer::<MAX_LINE>::new();
+        let mut buf = [0; MAX_LINE];
+        loop {
+            let n = read_input(&mut buf);
+            if n == 0 {
+                kthread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            for byte in &buf[..n] {
+                match line.accept(*byte).map(Command::parse) {
+                    Some(Some(command)) => run(command),
+                    Some(None) => info!("unknown command, type `help` for a list"),
+                    None => {}
+                }
+            }
+        }
+    });
+}
+
+/// Log the report for `command`.
+fn run(command: Command) {
+    match command {
+        Command::Help => {
+            for (name, _, description) in COMMANDS {
+                info!("{name}: {description}");
+            }
+        }
+        Command::Memory => {
+            for region in crate::memory::memory_map().regions() {
+                info!("region {region}");
+            }
+            let memory = crate::memory::statistics();
+            info!(
+                "pages: {} free of {}, largest free block {}",
+                memory.free_pages, memory.total_pages, memory.largest_free_block
+            );
+            let heap = crate::memory::heap_statistics();
+            info!(
+                "heap: {} bytes in {} allocations, {} free of {}",
+                heap.allocated_bytes,
+                heap.allocation_count,
+                heap.free_bytes(),
+                heap.heap_size
+            );
+        }
+        Command::Interrupts => {
+            let Some(statistics) = crate::exceptions::interrupt_statistics() else {
+                info!("interrupts are not initialized yet");
+                return;
+            };
+            for (id, counters) in statistics.snapshot() {
+                info!(
+                    "interrupt {id}: {} handled {:?}, longest {} ticks, {} spurious, {} storms",
+                    counters.handled,
+                    counters.per_core,
+                    counters.max_duration,
+                    counters.spurious,
+                    counters.storms
+                );
+            }
+        }
+        Command::Stacks => {
+            for (id, usage) in crate::memory::core_stack_usage() {
+                if let Some(bytes) = usage {
+                    info!("core {id}: {bytes} bytes of stack used");
+                } else {
+                    info!("core {id}: stack is not watermarked");
+                }
+            }
+        }
+        Command::Idle => {
+            let Some(governor) = crate::idle::statistics() else {
+                info!("idle states are not initialized yet");
+                return;
+            };
+            for core in governor.cores() {
+                let wfi = governor.residency(*core, None);
+                info!("core {core}: wfi {} times, {} ns", wfi.entries, wfi.nanos);
+                for (i, state) in governor.states().iter().enumerate() {
+                    let residency = governor.residency(*core, Some(i));
+                    info!(
+                        "core {core}: state {:#x} {} times, {} ns",
+                        state.power_state, residency.entries, residency.nanos
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! User space processes.
+//!
+//! A process' address space is made current on a core whenever one of its threads is switched to
+//! (see [`activate`]). Kernel threads run with page tables that map nothing in user space, so that
+//! no address space is walked, even speculatively, after it has been freed.
+use alloc::sync::Arc;
+use kernel_core::{
+    collections::HandleMap,
+    memory::{AddressSpaceIdPool, PageAllocator as _, PhysicalAddress},
+    platform::cpu::CpuIdReader as _,
+    process::{
+        self as core_process, loader, startup,
+        system_call::{self, SystemCall},
+        thread::{Scheduler as _, State, Thread},
+        ExitCode, Id, Name, Privilege, Process,
+    },
+};
+use log::{debug, info, warn};
+use snafu::{ensure, ResultExt as _, Snafu};
+use spin::once::Once;
+
+use crate::{
+    exceptions::TIMER_QUEUE,
+    kpti,
+    memory::{self, ChosenPageAllocator, SystemMmu},
+    thread::{SystemCpuIdReader, CORES, SCHEDULER, THREADS},
+};
+
+/// A user space process in this system.
+pub type PlatformProcess = Process<'static, ChosenPageAllocator>;
+
+/// The maximum number of processes in the system.
+const MAX_PROCESS_ID: u32 = 0xffff;
+
+/// The number of bits in an ASID. `TCR_EL1.AS` is clear, so only 8 bits are used even on cores
+/// that support 16.
+const ASID_BITS: u32 = 8;
+
+/// The number of pages in the stack of a new process' main thread.
+const STACK_PAGES: usize = 16;
+
+/// The code a process exits with when one of its threads causes an exception that the kernel
+/// doesn't handle.
+pub const UNHANDLED_EXCEPTION_EXIT_CODE: ExitCode = ExitCode::MAX - 1;
+
+/// Errors that can occur spawning a process.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The executable could not be loaded.
+    #[snafu(display("failed to load executable"))]
+    Load {
+        /// Underlying error.
+        source: loader::Error,
+    },
+    /// The executable requests an interpreter, which can't be loaded yet.
+    #[snafu(display("executable requests an interpreter, which is not supported"))]
+    InterpreterUnsupported,
+    /// The startup information for the process could not be encoded.
+    #[snafu(display("failed to encode startup information"))]
+    StartupInfo {
+        /// Underlying error.
+        source: startup::Error,
+    },
+    /// The process could not be created.
+    #[snafu(display("failed to create process"))]
+    Create {
+        /// Underlying error.
+        source: core_process::Error,
+    },
+}
+
+/// Every process in the system, by ID.
+pub static PROCESSES: Once<HandleMap<PlatformProcess>> = Once::new();
+
+/// The ASIDs handed out to processes as their threads are switched to.
+static ASIDS: Once<AddressSpaceIdPool> = Once::new();
+
+/// The physical address of the root of page tables that map nothing, which are current while
+/// kernel threads run.
+static EMPTY_ROOT: Once<usize> = Once::new();
+
+/// Initialize the process table for a system with `num_cores` cores, and stop using the boot
+/// mapping for user space addresses on the current core.
+pub fn init(num_cores: usize) {
+    debug!("Initializing processes…");
+    PROCESSES.call_once(|| HandleMap::new(MAX_PROCESS_ID));
+    // with kernel page table isolation each address space runs with a pair of ASIDs
+    let asid_bits = if kpti::is_enabled() {
+        ASID_BITS - 1
+    } else {
+        ASID_BITS
+    };
+    // the entropy pool was seeded while memory was initialized
+    ASIDS.call_once(|| {
+        AddressSpaceIdPool::new(asid_bits, num_cores).with_random_start(kernel_core::rand::next_u64)
+    });
+    EMPTY_ROOT.call_once(|| {
+        memory::page_allocator()
+            .allocate_zeroed(1)
+            .expect("allocate empty page table")
+            .into()
+    });
+    memory::oom_policy().set_last_resort(|_| {
+        core_process::oom_kill(
+            PROCESSES.wait(),
+            THREADS.wait(),
+            SCHEDULER.wait(),
+            memory::page_frames(),
+            memory::mmio_registry(),
+            &SystemMmu,
+        )
+    });
+    init_for_core();
+}
+
+/// Stop using the boot mapping for user space addresses on the current core.
+///
+/// The boot mapping's entries are global, so they are flushed too, or they would be visible to
+/// every process.
+pub fn init_for_core() {
+    unsafe {
+        deactivate();
+        memory::flush_tlb_total_el1();
+    }
+}
+
+/// Spawn a new process called `name` running `executable`, with `path` as its only argument.
+///
+/// # Errors
+/// Returns an error if the executable could not be loaded, or needs an interpreter.
+pub fn spawn(
+    name: &str,
+    path: &[u8],
+    executable: &loader::Executable,
+    supervisor: Option<Id>,
+    privilege: Privilege,
+) -> Result<Arc<PlatformProcess>, Error> {
+    ensure!(
+        executable.interpreter.is_none(),
+        InterpreterUnsupportedSnafu
+    );
+    let image = loader::ElfImage::parse(&executable.image).context(LoadSnafu)?;
+    let mut loaded = loader::load_image(
+        memory::page_allocator(),
+        &image,
+        STACK_PAGES,
+        crate::timer::time_page(),
+    )
+    .context(LoadSnafu)?;
+    let info = startup::encode(&[path], &[], &[]).context(StartupInfoSnafu)?;
+    loaded.map_startup_info(&info).context(LoadSnafu)?;
+    let (process, mut state) = Process::from_image(
+        PROCESSES.wait(),
+        Name::new(name),
+        supervisor,
+        privilege,
+        memory::page_frames(),
+        loaded,
+    )
+    .context(CreateSnafu)?;
+    state.pointer_auth_keys = process.pointer_auth_keys;
+    let thread = Thread::new(THREADS.wait(), State::Running, state);
+    thread.set_name(Name::new(name));
+    process.add_thread(thread.clone());
+    SCHEDULER.wait().add_thread(thread);
+    info!("spawned process {process}");
+    Ok(process)
+}
+
+/// Handle the system `call` made by `thread`, the current thread, which belongs to `process`,
+/// returning the result to it in `x0`. Must be called by the exception handler, inside
+/// [`crate::thread::switch_threads_around`].
+pub fn handle_system_call(thread: &Thread, process: &PlatformProcess, call: SystemCall) {
+    let result = system_call::handle(
+        process,
+        SCHEDULER.wait(),
+        &TIMER_QUEUE,
+        crate::rtc::set_time,
+        call,
+    );
+    // the thread may have blocked, but its saved registers are only restored once it resumes, and
+    // then `complete_wait` replaces the result with how the wait ended
+    thread.processor_state.lock().registers.x[0] = result;
+}
+
+/// Make the process that `thread` belongs to exit with `code`.
+pub fn exit(thread: &Thread, code: ExitCode) {
+    let Some(id) = thread.process() else {
+        return;
+    };
+    if let Err(e) = core_process::exit(
+        PROCESSES.wait(),
+        THREADS.wait(),
+        SCHEDULER.wait(),
+        memory::page_frames(),
+        memory::mmio_registry(),
+        &SystemMmu,
+        id,
+        code,
+    ) {
+        warn!("process #{id} did not exit cleanly: {e}");
+    }
+}
+
+/// Make the empty page tables current on this core.
+unsafe fn deactivate() {
+    SystemMmu::activate_user_tables(PhysicalAddress::from(*EMPTY_ROOT.wait()), 0);
+}
+
+/// The index of the current core, which the ASID pool tracks cores by.
+fn current_core_index() -> usize {
+    CORES
+        .wait()
+        .index(SystemCpuIdReader::current_cpu())
+        .expect("current core is known")
+}
+
+/// The process that `thread` belongs to, or `None` if it is a kernel thread or the process no
+/// longer exists.
+pub fn process_of(thread: &Thread) -> Option<Arc<PlatformProcess>> {
+    PROCESSES.get()?.get(thread.process()?)
+}
+
+/// Make the address space of the process `thread` belongs to current on this core, or the empty
+/// page tables if it is a kernel thread. If the ASID pool rolled over, the other cores are told to
+/// flush their TLBs.
+///
+/// # Safety
+/// This must only be called while handling an exception, once `thread` has been chosen to run when
+/// the exception returns.
+pub unsafe fn activate(thread: &Thread) {
+    let activation = process_of(thread)
+        .and_then(|process| process.activate(ASIDS.wait(), current_core_index(), &SystemMmu));
+    match activation {
+        Some(activation) if activation.rolled_over => crate::exceptions::flush_other_cores_tlb(),
+        Some(_) => {}
+        None => deactivate(),
+    }
+}
+
+/// Record that the current core's TLB is being flushed entirely, so it doesn't need to flush it
+/// again for a rollover of the ASID pool.
+pub fn clear_pending_flush() {
+    if let Some(pool) = ASIDS.get() {
+        pool.flush_if_pending(current_core_index(), || {});
+    }
+}
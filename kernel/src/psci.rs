@@ -5,21 +5,31 @@
 //! Device Tree Reference: <https://www.kernel.org/doc/Documentation/devicetree/bindings/arm/psci.txt>
 
 use byteorder::{BigEndian, ByteOrder};
+use core::convert::Infallible;
 use kernel_core::memory::PhysicalAddress;
-use kernel_core::platform::cpu::{Id as CpuId, PowerManager, PowerManagerError};
+use kernel_core::platform::cpu::Id as CpuId;
 use kernel_core::platform::device_tree::{
     DeviceTree, NodeNotFoundSnafu, ParseError, PropertyNotFoundSnafu,
 };
+use kernel_core::platform::power::{PowerManager, PowerManagerError};
 use log::{error, trace, warn};
 use snafu::OptionExt;
+use spin::Once;
+
+/// The system power manager, available once secondary cores have been booted.
+pub static POWER: Once<Psci> = Once::new();
 
 /// Convert a PSCI return value to a Rust [`Result`].
 fn psci_error_code_to_result(result: i32) -> Result<(), PowerManagerError> {
     // Error codes as defined by §5.2.2.
     match result {
         0 => Ok(()),
+        // NotSupported
+        -1 => Err(PowerManagerError::NotSupported),
         // InvalidParameters
         -2 => Err(PowerManagerError::InvalidCoreId),
+        // Denied
+        -3 => Err(PowerManagerError::Denied),
         // AlreadyOn
         -4 => Err(PowerManagerError::AlreadyOn),
         // OnPending
@@ -42,8 +52,14 @@ enum CallingMethod {
     Hvc,
 }
 
+/// Function ID for `CPU_SUSPEND` PSCI function.
+const FUNC_ID_CPU_SUSPEND: u32 = 0xC400_0001;
 /// Function ID for `CPU_ON` PSCI function.
 const FUNC_ID_CPU_ON: u32 = 0xC400_0003;
+/// Function ID for `SYSTEM_OFF` PSCI function.
+const FUNC_ID_SYSTEM_OFF: u32 = 0x8400_0008;
+/// Function ID for `SYSTEM_RESET` PSCI function.
+const FUNC_ID_SYSTEM_RESET: u32 = 0x8400_0009;
 
 /// The PSCI driver.
 #[derive(Debug)]
@@ -52,6 +68,8 @@ pub struct Psci {
     calling_method: CallingMethod,
     /// The current function ID for `CPU_ON` PSCI function reported by the firmware.
     func_id_cpu_on: u32,
+    /// The current function ID for `CPU_SUSPEND` PSCI function reported by the firmware.
+    func_id_cpu_suspend: u32,
 }
 
 impl Psci {
@@ -59,6 +77,7 @@ impl Psci {
     pub fn in_device_tree<'a>(dt: &'a DeviceTree) -> Result<Self, ParseError<'a>> {
         let mut calling_method = None;
         let mut func_id_cpu_on = None;
+        let mut func_id_cpu_suspend = None;
 
         for (name, value) in dt
             .iter_node_properties(b"/psci")
@@ -75,6 +94,9 @@ impl Psci {
                 b"cpu_on" => {
                     func_id_cpu_on = Some(BigEndian::read_u32(value.as_bytes(name)?));
                 }
+                b"cpu_suspend" => {
+                    func_id_cpu_suspend = Some(BigEndian::read_u32(value.as_bytes(name)?));
+                }
                 _ => {}
             }
         }
@@ -88,8 +110,41 @@ impl Psci {
         Ok(Self {
             calling_method,
             func_id_cpu_on: func_id_cpu_on.unwrap_or(FUNC_ID_CPU_ON),
+            func_id_cpu_suspend: func_id_cpu_suspend.unwrap_or(FUNC_ID_CPU_SUSPEND),
         })
     }
+
+    /// Invoke the PSCI function `func_id` with up to three arguments, returning the result code.
+    ///
+    /// # Safety
+    /// The arguments must be valid for the function, which may power off or reset cores.
+    unsafe fn call(&self, func_id: u32, arg0: usize, arg1: usize, arg2: usize) -> i32 {
+        let result: usize;
+
+        match self.calling_method {
+            CallingMethod::Smc => core::arch::asm!(
+                "smc #0",
+                inout("x0") func_id as usize => result,
+                in("x1") arg0,
+                in("x2") arg1,
+                in("x3") arg2,
+                clobber_abi("C"),
+            ),
+            CallingMethod::Hvc => core::arch::asm!(
+                "hvc #0",
+                inout("x0") func_id as usize => result,
+                in("x1") arg0,
+                in("x2") arg1,
+                in("x3") arg2,
+                clobber_abi("C"),
+            ),
+        }
+
+        // the result is a signed 32-bit value in `w0`
+        #[allow(clippy::cast_possible_truncation)]
+        let result = result as u32;
+        result.cast_signed()
+    }
 }
 
 impl PowerManager for Psci {
@@ -103,40 +158,43 @@ impl PowerManager for Psci {
             "turning CPU #{target_cpu} on! entry point = {entry_point_address:?}, arg  = 0x{arg:x}"
         );
 
-        let entry_point: usize = entry_point_address.into();
+        psci_error_code_to_result(self.call(
+            self.func_id_cpu_on,
+            target_cpu,
+            entry_point_address.into(),
+            arg,
+        ))
+    }
 
-        let result: i32;
+    unsafe fn suspend_core(
+        &self,
+        power_state: u32,
+        entry_point_address: PhysicalAddress,
+        arg: usize,
+    ) -> Result<(), PowerManagerError> {
+        trace!("suspending core, power state = 0x{power_state:x}");
+        psci_error_code_to_result(self.call(
+            self.func_id_cpu_suspend,
+            power_state as usize,
+            entry_point_address.into(),
+            arg,
+        ))
+    }
 
-        match self.calling_method {
-            CallingMethod::Smc => core::arch::asm!(
-                "mov w0, {func_id:w}",
-                "mov x1, {target_cpu}",
-                "mov x2, {entry_point_address}",
-                "mov x3, {context_id}",
-                "smc #0",
-                "mov {result:w}, w0",
-                func_id = in(reg) self.func_id_cpu_on,
-                entry_point_address = in(reg) entry_point,
-                target_cpu = in(reg) target_cpu,
-                context_id = in(reg) arg,
-                result = out(reg) result
-            ),
-            CallingMethod::Hvc => core::arch::asm!(
-                "mov w0, {func_id:w}",
-                "mov x1, {target_cpu}",
-                "mov x2, {entry_point_address}",
-                "mov x3, {context_id}",
-                "hvc #0",
-                "mov {result:w}, w0",
-                func_id = in(reg) self.func_id_cpu_on,
-                entry_point_address = in(reg) entry_point,
-                target_cpu = in(reg) target_cpu,
-                context_id = in(reg) arg,
-                result = out(reg) result
-            ),
-        }
+    fn system_off(&self) -> Result<Infallible, PowerManagerError> {
+        trace!("powering off system");
+        let result = unsafe { self.call(FUNC_ID_SYSTEM_OFF, 0, 0, 0) };
+        psci_error_code_to_result(result)?;
+        // the call should never return successfully
+        Err(PowerManagerError::Internal)
+    }
 
-        psci_error_code_to_result(result)
+    fn system_reset(&self) -> Result<Infallible, PowerManagerError> {
+        trace!("resetting system");
+        let result = unsafe { self.call(FUNC_ID_SYSTEM_RESET, 0, 0, 0) };
+        psci_error_code_to_result(result)?;
+        // the call should never return successfully
+        Err(PowerManagerError::Internal)
     }
 
     fn enable_method_name() -> &'static [u8] {
@@ -0,0 +1,110 @@
+//! PL031 real time clock driver.
+//!
+//! Documentation for the interface can be found [on ARM's website](https://developer.arm.com/documentation/ddi0224/latest/).
+
+use kernel_core::{
+    driver::{Device, Driver, ProbeError},
+    memory::PhysicalAddress,
+    platform::{
+        device_tree::Value,
+        rtc::{self, DateTime, RtcMechanism},
+    },
+};
+use log::info;
+use spin::Once;
+
+use crate::{memory::map_device, timer::clock};
+
+/// Register offsets, in bytes.
+mod regs {
+    /// Data register, the current time in seconds.
+    pub const DR: usize = 0x00;
+    /// Load register, which sets the current time in seconds.
+    pub const LR: usize = 0x08;
+    /// Control register. Bit 0 starts the clock, and can't be cleared once set.
+    pub const CR: usize = 0x0c;
+}
+
+/// The PL031 real time clock object.
+pub struct PL031 {
+    base_address: *mut u8,
+}
+
+// SAFETY: It's fine to move the pointer as long as it doesn't get duplicated!
+unsafe impl Send for PL031 {}
+// SAFETY: registers are only accessed with single volatile reads and writes.
+unsafe impl Sync for PL031 {}
+
+impl PL031 {
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.read_volatile()
+        }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.write_volatile(value);
+        }
+    }
+}
+
+impl RtcMechanism for PL031 {
+    fn read_seconds(&self) -> u64 {
+        u64::from(self.read_reg(regs::DR))
+    }
+
+    fn write_seconds(&self, seconds: u64) {
+        // the counter is only 32 bits wide, so times after 2106 saturate
+        self.write_reg(regs::LR, u32::try_from(seconds).unwrap_or(u32::MAX));
+    }
+}
+
+/// The driver for the PL031.
+pub const DRIVER: Driver = Driver {
+    name: "pl031",
+    compatible: &[b"arm,pl031"],
+    probe,
+};
+
+/// The real time clock, if the device tree has one.
+static RTC: Once<PL031> = Once::new();
+
+/// Set up the first PL031 and set the wall clock time from it.
+fn probe(device: &Device) -> Result<(), ProbeError> {
+    if RTC.is_completed() {
+        return Err(ProbeError::Declined);
+    }
+    let (base, len) = device
+        .properties()
+        .ok_or(ProbeError::Declined)?
+        .find_map(|(name, value)| match (name, value) {
+            (b"reg", Value::Reg(r)) => r.iter().next(),
+            _ => None,
+        })
+        .ok_or(ProbeError::Failed {
+            reason: "no registers",
+        })?;
+    let mech = PL031 {
+        base_address: map_device(PhysicalAddress::from(base), len),
+    };
+    mech.write_reg(regs::CR, 1);
+    let rtc = RTC.call_once(|| mech);
+    let seconds = rtc::set_clock_from_rtc(rtc, clock());
+    info!(
+        "PL031 real time clock at {base:#x}, wall clock is {}",
+        DateTime::from_unix_seconds(seconds)
+    );
+    Ok(())
+}
+
+/// Set the wall clock time, in nanoseconds since the Unix epoch, saving it in the real time clock
+/// if there is one.
+pub fn set_time(nanos: u64) {
+    match RTC.get() {
+        Some(rtc) => rtc::set_time(rtc, clock(), nanos),
+        None => clock().set_realtime(nanos),
+    }
+}
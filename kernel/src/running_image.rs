@@ -14,6 +14,30 @@ mod markers {
         pub static mut __kernel_start: u8;
         /// End of the entire kernel image.
         pub static mut __kernel_end: u8;
+        /// Beginning of the executable code.
+        pub static mut __text_start: u8;
+        /// End of the executable code (page aligned).
+        pub static mut __text_end: u8;
+        /// Beginning of the exception vector page.
+        pub static mut __trampoline_start: u8;
+        /// End of the exception vector page (page aligned).
+        pub static mut __trampoline_end: u8;
+        /// Beginning of the data read by the exception vector.
+        pub static mut __trampoline_data_start: u8;
+        /// End of the data read by the exception vector (page aligned).
+        pub static mut __trampoline_data_end: u8;
+        /// Beginning of the read-only data, including the symbol table.
+        pub static mut __rodata_start: u8;
+        /// End of the read-only data (page aligned).
+        pub static mut __rodata_end: u8;
+        /// Beginning of the writable data, including the `bss` section and boot stack.
+        pub static mut __data_start: u8;
+        /// End of the writable data (page aligned).
+        pub static mut __data_end: u8;
+        /// Lowest address of the boot core's stack.
+        pub static mut __stack_bottom: u8;
+        /// The boot core's initial stack pointer, just past the highest address of its stack.
+        pub static mut __stack_start: u8;
     }
 }
 
@@ -35,7 +59,81 @@ pub unsafe fn zero_bss_section() {
 /// The validity of the returned region depends entirely on the correctness of the linker, linker
 /// script and loader to make sure the marker symbols are defined in the correct places.
 pub unsafe fn memory_region() -> (*mut u8, usize) {
-    let start = addr_of_mut!(markers::__kernel_start);
-    let end = addr_of!(markers::__kernel_end);
+    region_between(
+        addr_of_mut!(markers::__kernel_start),
+        addr_of!(markers::__kernel_end),
+    )
+}
+
+/// Find the region between two marker symbols.
+unsafe fn region_between(start: *mut u8, end: *const u8) -> (*mut u8, usize) {
     (start, end.offset_from(start) as usize)
 }
+
+/// Find the region of the kernel image that contains executable code.
+///
+/// # Safety
+/// See [`memory_region`].
+pub unsafe fn text_region() -> (*mut u8, usize) {
+    region_between(
+        addr_of_mut!(markers::__text_start),
+        addr_of!(markers::__text_end),
+    )
+}
+
+/// Find the region of the kernel image that contains the exception vector and the code that
+/// returns from exceptions, which must stay mapped while user threads run.
+///
+/// # Safety
+/// See [`memory_region`].
+pub unsafe fn trampoline_region() -> (*mut u8, usize) {
+    region_between(
+        addr_of_mut!(markers::__trampoline_start),
+        addr_of!(markers::__trampoline_end),
+    )
+}
+
+/// Find the region of the kernel image that contains the data read by the code in
+/// [`trampoline_region`].
+///
+/// # Safety
+/// See [`memory_region`].
+pub unsafe fn trampoline_data_region() -> (*mut u8, usize) {
+    region_between(
+        addr_of_mut!(markers::__trampoline_data_start),
+        addr_of!(markers::__trampoline_data_end),
+    )
+}
+
+/// Find the region of the kernel image that contains read-only data.
+///
+/// # Safety
+/// See [`memory_region`].
+pub unsafe fn read_only_region() -> (*mut u8, usize) {
+    region_between(
+        addr_of_mut!(markers::__rodata_start),
+        addr_of!(markers::__rodata_end),
+    )
+}
+
+/// Find the region of the kernel image that contains writable data, including the boot stack.
+///
+/// # Safety
+/// See [`memory_region`].
+pub unsafe fn writable_region() -> (*mut u8, usize) {
+    region_between(
+        addr_of_mut!(markers::__data_start),
+        addr_of!(markers::__data_end),
+    )
+}
+
+/// Find the region of the kernel image used as the boot core's stack.
+///
+/// # Safety
+/// See [`memory_region`].
+pub unsafe fn boot_stack_region() -> (*mut u8, usize) {
+    region_between(
+        addr_of_mut!(markers::__stack_bottom),
+        addr_of!(markers::__stack_start),
+    )
+}
@@ -0,0 +1,372 @@
+//! On-target self tests, run at boot when the `self_test` boot argument is set.
+//!
+//! These exercise the page allocator, page tables, IPC, the timer and the IOMMU on the real hardware
+//! path, and write a machine-readable report to the UART (see [`kernel_core::selftest`]) so that a
+//! runner such as QEMU in CI can gate on the results. Fixtures can be loaded from a semihosting
+//! host, selected with the `self_test_fixture` boot argument.
+use alloc::{ffi::CString, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use kernel_core::{
+    init::archive::Archive,
+    ipc::{MessageBlock, MessageQueue, ReceiveFlags},
+    memory::{
+        page_table::{MapBlockSize, MemoryProperties},
+        PageAllocator, PageTables, PhysicalAddress, PhysicalPointer, VirtualAddress,
+    },
+    platform::boot_args::{BootArgs, Value},
+    self_test_ensure,
+    selftest::{self, Failure, SelfTest},
+    time::{clock::NANOS_PER_SECOND, Ticks},
+};
+use log::{info, warn};
+use spin::Once;
+
+use crate::{kthread, thread::SCHEDULER};
+
+/// Every self test, in the order they are run.
+const TESTS: &[SelfTest] = &[
+    SelfTest {
+        name: "page_allocator_stress",
+        run: page_allocator_stress,
+    },
+    SelfTest {
+        name: "page_table_map_unmap",
+        run: page_table_map_unmap,
+    },
+    SelfTest {
+        name: "ipc_round_trip",
+        run: ipc_round_trip,
+    },
+    SelfTest {
+        name: "timer_accuracy",
+        run: timer_accuracy,
+    },
+    SelfTest {
+        name: "iommu_grant_revoke",
+        run: iommu_grant_revoke,
+    },
+    SelfTest {
+        name: "semihosting_fixture",
+        run: semihosting_fixture,
+    },
+];
+
+/// The path on the semihosting host of the archive read by [`semihosting_fixture`], if the
+/// `self_test_fixture` boot argument was given.
+static FIXTURE_PATH: Once<CString> = Once::new();
+
+/// Writes the report to the UART, waiting for space in its queue rather than dropping output.
+struct UartWriter;
+
+impl core::fmt::Write for UartWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let uart = crate::uart::UART.get().ok_or(core::fmt::Error)?;
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let written = uart.write(bytes);
+            bytes = &bytes[written..];
+            if !bytes.is_empty() {
+                kthread::yield_now();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Spawn a kernel thread to run the self tests if they were requested in the boot arguments.
+///
+/// If the `qemu_exit` boot argument is also set, QEMU is terminated once the tests finish, with
+/// exit status 0 if every test passed and 1 otherwise.
+pub fn run_if_requested(boot_args: &BootArgs) {
+    if !boot_args.flag(b"self_test") {
+        return;
+    }
+    info!("Running self tests");
+    match boot_args.get(b"self_test_fixture") {
+        Some(Value::String(path)) => {
+            if let Ok(path) = CString::new(path) {
+                FIXTURE_PATH.call_once(|| path);
+            } else {
+                warn!("ignoring self test fixture path containing a NUL byte");
+            }
+        }
+        Some(_) => warn!("ignoring self test fixture path that is not a string"),
+        None => {}
+    }
+    let exit_qemu = boot_args.flag(b"qemu_exit");
+    kthread::spawn("selftest", move || {
+        let summary = selftest::run(TESTS, &mut UartWriter);
+        info!(
+            "Self tests finished: {} passed, {} failed",
+            summary.passed, summary.failed
+        );
+        if exit_qemu {
+            crate::semihosting::exit(u32::from(!summary.succeeded()));
+        }
+    });
+}
+
+/// Fill `num_pages` pages at `address` with a pattern derived from `seed`.
+fn fill_pattern(address: PhysicalAddress, num_pages: usize, page_size: usize, seed: u64) {
+    let words: *mut u64 = PhysicalPointer::from(usize::from(address)).into();
+    for i in 0..num_pages * page_size / 8 {
+        unsafe { words.add(i).write_volatile(seed ^ i as u64) };
+    }
+}
+
+/// Check that `num_pages` pages at `address` still hold the pattern written by [`fill_pattern`].
+fn check_pattern(address: PhysicalAddress, num_pages: usize, page_size: usize, seed: u64) -> bool {
+    let words: *const u64 = PhysicalPointer::from(usize::from(address)).into();
+    (0..num_pages * page_size / 8)
+        .all(|i| unsafe { words.add(i).read_volatile() } == seed ^ i as u64)
+}
+
+/// Allocate many regions of varying sizes, check that they don't overlap by filling each with a
+/// distinct pattern, then check that freed memory is zeroed when allocated again.
+fn page_allocator_stress() -> Result<(), Failure> {
+    let pa = crate::memory::page_allocator();
+    let page_size = pa.page_size().into();
+    let mut regions = Vec::new();
+    // every region allocated so far is freed below, even if an allocation or a check fails
+    let result = (|| {
+        for (seed, num_pages) in (1..=64u64).zip([1, 2, 3, 5, 8, 13].into_iter().cycle()) {
+            let address = pa.allocate(num_pages)?;
+            fill_pattern(address, num_pages, page_size, seed << 32);
+            regions.push((address, num_pages, seed << 32));
+        }
+        for &(address, num_pages, seed) in &regions {
+            self_test_ensure!(
+                check_pattern(address, num_pages, page_size, seed),
+                "region at {address:?} was overwritten"
+            );
+        }
+        Ok(())
+    })();
+    // free every other region first so that the allocator has to merge them back together
+    for &(address, num_pages, _) in regions
+        .iter()
+        .step_by(2)
+        .chain(regions.iter().skip(1).step_by(2))
+    {
+        pa.free(address, num_pages)?;
+    }
+    result?;
+    let address = pa.allocate_zeroed(8)?;
+    let zeroed = check_pattern(address, 8, page_size, 0);
+    pa.free(address, 8)?;
+    self_test_ensure!(zeroed, "zeroed allocation at {address:?} was not zero");
+    Ok(())
+}
+
+/// Map pages into a fresh set of page tables, check the translations, then unmap them again.
+fn page_table_map_unmap() -> Result<(), Failure> {
+    let pa = crate::memory::page_allocator();
+    let page_size: usize = pa.page_size().into();
+    let mut tables = PageTables::empty(pa)?;
+    let pages = pa.allocate(4)?;
+    let base = VirtualAddress::from(0x10_0000_0000usize);
+    let result = (|| {
+        tables.map(
+            base,
+            pages,
+            4,
+            MapBlockSize::Page,
+            &MemoryProperties {
+                writable: true,
+                ..MemoryProperties::default()
+            },
+        )?;
+        for i in 0..4 {
+            let found = tables.physical_address_of(base.byte_add(i * page_size + 8));
+            self_test_ensure!(
+                found == Some(pages.byte_add(i * page_size + 8)),
+                "page {i} translated to {found:?}"
+            );
+        }
+        // the tables were never live, so there is nothing to flush from the TLB
+        let _ = tables.unmap(base, 4, MapBlockSize::Page)?;
+        let found = tables.physical_address_of(base);
+        self_test_ensure!(found.is_none(), "unmapped page translated to {found:?}");
+        Ok(())
+    })();
+    drop(tables);
+    pa.free(pages, 4)?;
+    result
+}
+
+/// The number of messages sent back and forth in [`ipc_round_trip`].
+const ROUND_TRIPS: u64 = 64;
+
+/// How long [`ipc_round_trip`] may take in total before it gives up, in nanoseconds.
+const IPC_TIMEOUT_NANOS: u64 = NANOS_PER_SECOND;
+
+/// Receive a message from `queue` without blocking, yielding until one arrives or the clock passes
+/// `give_up`.
+fn receive_counter(
+    queue: &MessageQueue<'_, impl PageAllocator>,
+    give_up: Ticks,
+) -> Result<u64, Failure> {
+    let mut flags = ReceiveFlags::default();
+    flags.set_nonblocking(true);
+    loop {
+        match queue.receive(SCHEDULER.wait(), flags, None) {
+            Ok(message) => {
+                let block = unsafe { message.as_slice() }[0];
+                queue.free_message(message.data)?;
+                return Ok(u64::from_le_bytes(block.0[..8].try_into().unwrap()));
+            }
+            Err(kernel_core::ipc::Error::WouldBlock) => {
+                if crate::timer::clock().now() > give_up {
+                    return Err("timed out waiting for a message".into());
+                }
+                kthread::yield_now();
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Send `value` as a single block message to `queue`.
+fn send_counter(queue: &MessageQueue<'_, impl PageAllocator>, value: u64) -> Result<(), Failure> {
+    let mut block = MessageBlock::default();
+    block.0[..8].copy_from_slice(&value.to_le_bytes());
+    queue.send(&[block])?;
+    Ok(())
+}
+
+/// Bounce a counter between this thread and another kernel thread through a pair of message
+/// queues, each side incrementing it.
+fn ipc_round_trip() -> Result<(), Failure> {
+    let pa = crate::memory::page_allocator();
+    let requests = Arc::new(MessageQueue::new(pa, 1)?);
+    let replies = Arc::new(MessageQueue::new(pa, 1)?);
+    let clock = crate::timer::clock();
+    // both threads give up at the same time, so a lost message can't leave either blocked forever
+    let give_up = clock.now() + clock.nanos_to_ticks(IPC_TIMEOUT_NANOS);
+    let echo = {
+        let (requests, replies) = (requests.clone(), replies.clone());
+        kthread::spawn("selftest-echo", move || {
+            for _ in 0..ROUND_TRIPS {
+                let Ok(value) = receive_counter(&requests, give_up) else {
+                    return;
+                };
+                if send_counter(&replies, value + 1).is_err() {
+                    return;
+                }
+            }
+        })
+    };
+    let result = (|| {
+        let mut value = 0;
+        for _ in 0..ROUND_TRIPS {
+            send_counter(&requests, value)?;
+            let reply = receive_counter(&replies, give_up)?;
+            self_test_ensure!(reply == value + 1, "sent {value}, got {reply} back");
+            value = reply + 1;
+        }
+        Ok(())
+    })();
+    kthread::join(echo);
+    result
+}
+
+/// How far ahead the timer in [`timer_accuracy`] is armed, in nanoseconds.
+const TIMER_DELAY_NANOS: u64 = 20_000_000;
+
+/// How late the timer in [`timer_accuracy`] may fire, in nanoseconds. Timers are checked when the
+/// timer interrupt fires, so they can be up to a time slice late.
+const TIMER_TOLERANCE_NANOS: u64 = NANOS_PER_SECOND / crate::exceptions::TIMER_INTERVAL as u64;
+
+/// Arm a timer and check that it fires neither early nor too late.
+fn timer_accuracy() -> Result<(), Failure> {
+    let clock = crate::timer::clock();
+    let fired_at = Arc::new(AtomicU64::new(0));
+    let deadline = clock.now() + clock.nanos_to_ticks(TIMER_DELAY_NANOS);
+    {
+        let fired_at = fired_at.clone();
+        crate::exceptions::TIMER_QUEUE.arm(
+            deadline,
+            alloc::boxed::Box::new(move || {
+                fired_at.store(crate::timer::clock().now(), Ordering::Release);
+            }),
+        );
+    }
+    let give_up = deadline + clock.nanos_to_ticks(10 * TIMER_TOLERANCE_NANOS);
+    let fired_at = loop {
+        match fired_at.load(Ordering::Acquire) {
+            0 if clock.now() > give_up => return Err("timer never fired".into()),
+            // kernel threads run with interrupts masked, so the timer can only fire while this
+            // thread is blocked
+            0 => kthread::sleep(Duration::from_nanos(TIMER_TOLERANCE_NANOS)),
+            t => break t,
+        }
+    };
+    self_test_ensure!(
+        fired_at >= deadline,
+        "fired {} ns early",
+        clock.ticks_to_nanos(deadline - fired_at)
+    );
+    let late = clock.ticks_to_nanos(fired_at - deadline);
+    self_test_ensure!(late <= TIMER_TOLERANCE_NANOS, "fired {late} ns late");
+    Ok(())
+}
+
+/// Attach an unused stream of the SMMU to an I/O address space, grant it pages and check their
+/// translation, then revoke and release them. Passes without testing anything if there is no
+/// SMMU.
+fn iommu_grant_revoke() -> Result<(), Failure> {
+    let Some(stream) = crate::smmu::unused_stream() else {
+        return Ok(());
+    };
+    let pa = crate::memory::page_allocator();
+    let frames = crate::memory::page_frames();
+    let page_size: usize = pa.page_size().into();
+    let pages = pa.allocate(2)?;
+    // hold a reference to the pages, like the mapping of a driver, so that revoking the grant
+    // doesn't free them
+    for i in 0..2 {
+        frames.get(pages.byte_add(i * page_size))?;
+    }
+    let io = VirtualAddress::from(0x10_0000usize);
+    let mut space = crate::smmu::attach(stream)?;
+    let result = (|| {
+        space.grant(frames, io, pages, 2, true)?;
+        let found = space.translate(io.byte_add(page_size + 8));
+        self_test_ensure!(
+            found == Some(pages.byte_add(page_size + 8)),
+            "granted page translated to {found:?}"
+        );
+        crate::smmu::revoke(&mut space, io)?;
+        let found = space.translate(io);
+        self_test_ensure!(found.is_none(), "revoked page translated to {found:?}");
+        Ok(())
+    })();
+    crate::smmu::release(space)?;
+    for i in 0..2 {
+        frames.put(pages.byte_add(i * page_size))?;
+    }
+    pa.free(pages, 2)?;
+    result
+}
+
+/// Load the archive named by the `self_test_fixture` boot argument from the semihosting host, and
+/// check that every entry in it can be read. Passes without testing anything if no fixture was
+/// given.
+fn semihosting_fixture() -> Result<(), Failure> {
+    let Some(path) = FIXTURE_PATH.get() else {
+        return Ok(());
+    };
+    let data = crate::semihosting::read_file(path)
+        .ok_or_else(|| alloc::format!("could not read fixture {path:?}"))?;
+    let archive = Archive::new(&data)?;
+    let mut count = 0;
+    for entry in archive.entries() {
+        entry?;
+        count += 1;
+    }
+    self_test_ensure!(count > 0, "fixture {path:?} is an empty archive");
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! Semihosting calls to a host such as QEMU or a JTAG debugger, used to copy log output to the
+//! host's console, load test fixtures from the host's files, and terminate QEMU at the end of
+//! automated test runs.
+//!
+//! Semihosting is only used when the boot arguments say that a host is attached. See
+//! [`kernel_core::platform::semihosting`].
+use alloc::vec::Vec;
+use core::{arch::asm, ffi::CStr};
+use kernel_core::platform::{
+    boot_args::BootArgs,
+    qemu,
+    semihosting::{Host, Semihosting, SemihostingConsole},
+};
+use log::{error, warn};
+use spin::Once;
+
+/// Makes semihosting calls with the `HLT #0xF000` instruction, as specified for 64-bit ARM.
+pub struct HltSemihosting;
+
+impl Semihosting for HltSemihosting {
+    unsafe fn call(&self, operation: u32, parameter: usize) -> usize {
+        let mut result = operation as usize;
+        asm!(
+            "hlt #0xf000",
+            inout("x0") result,
+            in("x1") parameter,
+            options(nostack)
+        );
+        result
+    }
+}
+
+/// The semihosting host, if the boot arguments say one is attached.
+static HOST: Once<Host<HltSemihosting>> = Once::new();
+
+/// Returns true if the boot arguments say that a semihosting host is attached.
+fn host_attached(boot_args: &BootArgs) -> bool {
+    boot_args.flag(b"semihosting") || boot_args.flag(b"qemu_exit")
+}
+
+/// Start using semihosting if the boot arguments say that a host is attached.
+pub fn init(boot_args: &BootArgs) {
+    if host_attached(boot_args) {
+        // SAFETY: the boot arguments promise that a host is attached.
+        HOST.call_once(|| unsafe { Host::new(HltSemihosting) });
+    }
+}
+
+/// Open the host's console to copy log output to, if the `semihosting` boot argument is set.
+pub fn console(boot_args: &BootArgs) -> Option<SemihostingConsole<'static, HltSemihosting>> {
+    if !boot_args.flag(b"semihosting") {
+        return None;
+    }
+    SemihostingConsole::open(HOST.get()?)
+        .inspect_err(|e| warn!("could not open semihosting console: {e}"))
+        .ok()
+}
+
+/// Read the file at `path` on the host, for instance to load a test fixture.
+///
+/// Returns `None` if no host is attached or the file could not be read.
+pub fn read_file(path: &CStr) -> Option<Vec<u8>> {
+    HOST.get()?
+        .read_file(path)
+        .inspect_err(|e| error!("could not read {path:?} from semihosting host: {e}"))
+        .ok()
+}
+
+/// Terminate QEMU with exit status `code`, halting the core instead if no host is attached or the
+/// host ignores the request.
+pub fn exit(code: u32) -> ! {
+    // send any queued output before the emulator goes away
+    if let Some(uart) = crate::uart::UART.get() {
+        uart.disable_interrupts();
+    }
+    if let Some(host) = HOST.get() {
+        qemu::exit(host, code);
+        warn!("semihosting host ignored exit request");
+    } else {
+        warn!("can't exit without a semihosting host");
+    }
+    crate::exceptions::halt_current_core()
+}
@@ -0,0 +1,229 @@
+//! The system's IOMMU, an SMMU version 2 or 3 found in the device tree.
+//!
+//! DMA from the devices that the kernel drives itself bypasses the SMMU. DMA from every other
+//! device is blocked until the device is attached to an address space (see [`attach`]), which only
+//! maps the buffers its driver process has granted it.
+use alloc::vec::Vec;
+use kernel_core::{
+    driver::{Device, Driver, ProbeError},
+    memory::{
+        iommu::{self, IoAddressSpace, IoMmu, StreamId},
+        AddressSpaceId, PageAllocator as _, PhysicalAddress, VirtualAddress,
+    },
+    platform::{
+        device_tree::Value,
+        smmu::{v2, v3, Registers},
+    },
+};
+use log::{info, warn};
+use spin::Once;
+
+use crate::memory::{dma_allocator, map_device, page_allocator, page_frames, ChosenPageAllocator};
+
+/// The registers of an SMMU, mapped into the kernel address space.
+pub struct MmioRegisters {
+    base_address: *mut u8,
+}
+
+// SAFETY: It's fine to move the pointer as long as it doesn't get duplicated!
+unsafe impl Send for MmioRegisters {}
+// SAFETY: registers are only accessed with single volatile reads and writes.
+unsafe impl Sync for MmioRegisters {}
+
+impl Registers for MmioRegisters {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.read_volatile()
+        }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.write_volatile(value);
+        }
+    }
+}
+
+/// The SMMU of the system, whichever version it is.
+pub enum SystemSmmu {
+    /// An SMMU version 2, such as the MMU-500.
+    V2(v2::Smmu<MmioRegisters>),
+    /// An SMMU version 3.
+    V3(v3::Smmu<MmioRegisters>),
+}
+
+impl SystemSmmu {
+    /// The number of streams the SMMU can translate.
+    pub fn num_streams(&self) -> usize {
+        match self {
+            SystemSmmu::V2(smmu) => smmu.num_streams(),
+            SystemSmmu::V3(smmu) => smmu.num_streams(),
+        }
+    }
+}
+
+impl IoMmu for SystemSmmu {
+    fn attach(
+        &self,
+        stream: StreamId,
+        asid: AddressSpaceId,
+        root: PhysicalAddress,
+    ) -> Result<(), iommu::Error> {
+        match self {
+            SystemSmmu::V2(smmu) => smmu.attach(stream, asid, root),
+            SystemSmmu::V3(smmu) => smmu.attach(stream, asid, root),
+        }
+    }
+
+    fn detach(&self, stream: StreamId) -> Result<(), iommu::Error> {
+        match self {
+            SystemSmmu::V2(smmu) => smmu.detach(stream),
+            SystemSmmu::V3(smmu) => smmu.detach(stream),
+        }
+    }
+
+    fn invalidate_range(
+        &self,
+        asid: AddressSpaceId,
+        start: VirtualAddress,
+        length: usize,
+    ) -> Result<(), iommu::Error> {
+        match self {
+            SystemSmmu::V2(smmu) => smmu.invalidate_range(asid, start, length),
+            SystemSmmu::V3(smmu) => smmu.invalidate_range(asid, start, length),
+        }
+    }
+
+    fn invalidate_asid(&self, asid: AddressSpaceId) -> Result<(), iommu::Error> {
+        match self {
+            SystemSmmu::V2(smmu) => smmu.invalidate_asid(asid),
+            SystemSmmu::V3(smmu) => smmu.invalidate_asid(asid),
+        }
+    }
+}
+
+/// The SMMU, if the system has one.
+pub static IOMMU: Once<SystemSmmu> = Once::new();
+
+/// The streams of the devices the kernel drives, which bypass the SMMU.
+static KERNEL_STREAMS: Once<Vec<StreamId>> = Once::new();
+
+/// The addresses a device given to a driver process can access with DMA.
+pub type DeviceAddressSpace = IoAddressSpace<'static, ChosenPageAllocator>;
+
+/// Attach the device `stream` to a new I/O address space that maps nothing, so that it can only
+/// reach the buffers its driver grants it (see
+/// [`Process::grant_dma`](kernel_core::process::Process::grant_dma)). The address space's ASID is
+/// the stream ID, so each device has its own.
+///
+/// # Errors
+/// - [`iommu::Error::UnknownStream`] if there is no SMMU, the SMMU can't translate DMA from
+///   `stream`, or `stream` belongs to a device that the kernel drives.
+/// - Any error attaching the device.
+pub fn attach(stream: StreamId) -> Result<DeviceAddressSpace, iommu::Error> {
+    let unknown = iommu::Error::UnknownStream { stream };
+    let Some(iommu) = IOMMU.get() else {
+        return Err(unknown);
+    };
+    if KERNEL_STREAMS.get().is_some_and(|s| s.contains(&stream)) {
+        return Err(unknown);
+    }
+    let asid = AddressSpaceId::try_from(stream).map_err(|_| unknown)?;
+    IoAddressSpace::new(page_allocator(), iommu, stream, asid)
+}
+
+/// Take away the device's access to the region granted at `io_start` in `space`.
+///
+/// # Errors
+/// Returns any error revoking the grant (see [`IoAddressSpace::revoke`]).
+pub fn revoke(
+    space: &mut DeviceAddressSpace,
+    io_start: VirtualAddress,
+) -> Result<(), iommu::Error> {
+    space.revoke(page_frames(), IOMMU.wait(), io_start)
+}
+
+/// Block all DMA from the device of `space` and release every region it was granted.
+///
+/// # Errors
+/// Returns any error releasing the address space (see [`IoAddressSpace::release`]).
+pub fn release(space: DeviceAddressSpace) -> Result<(), iommu::Error> {
+    space.release(page_frames(), IOMMU.wait())
+}
+
+/// A stream that the SMMU can translate but that no device the kernel drives uses, or `None` if
+/// there is no SMMU or no such stream.
+pub fn unused_stream() -> Option<StreamId> {
+    let iommu = IOMMU.get()?;
+    let kernel = KERNEL_STREAMS.get()?;
+    (0..iommu.num_streams())
+        .rev()
+        .filter_map(|s| StreamId::try_from(s).ok())
+        .find(|s| !kernel.contains(s))
+}
+
+/// The driver for the SMMU. Only the first SMMU in the device tree is used.
+///
+/// The memory subsystem must be initialized first, since the tables of an SMMU version 3 are
+/// allocated with the DMA allocator.
+pub const DRIVER: Driver = Driver {
+    name: "smmu",
+    compatible: &[
+        b"arm,smmu-v3",
+        b"arm,mmu-500",
+        b"arm,smmu-v2",
+        b"arm,mmu-401",
+    ],
+    probe,
+};
+
+/// Set up and enable the SMMU, letting the devices the kernel drives bypass it.
+fn probe(device: &Device) -> Result<(), ProbeError> {
+    if IOMMU.get().is_some() {
+        return Err(ProbeError::Declined);
+    }
+    let mut registers = None;
+    let mut phandle = None;
+    for (name, value) in device.properties().ok_or(ProbeError::Declined)? {
+        match (name, value) {
+            (b"reg", Value::Reg(r)) => registers = r.iter().next(),
+            (b"phandle", Value::Phandle(p)) => phandle = Some(p),
+            _ => {}
+        }
+    }
+    let (base, len) = registers.ok_or(ProbeError::Failed {
+        reason: "no registers",
+    })?;
+    let bypass = phandle
+        .map(|p| crate::driver::iommu_streams(device.device_tree, p))
+        .unwrap_or_default();
+    let regs = MmioRegisters {
+        base_address: map_device(PhysicalAddress::from(base), len),
+    };
+    let page_size = page_allocator().page_size();
+    let smmu = if device.compatible == b"arm,smmu-v3" {
+        v3::Smmu::new(regs, &dma_allocator(), page_size, &bypass).map(SystemSmmu::V3)
+    } else {
+        v2::Smmu::new(regs, page_size, &bypass).map(SystemSmmu::V2)
+    };
+    match smmu {
+        Ok(smmu) => {
+            info!(
+                "SMMU at {base:#x} translating {} streams, {} bypassed for the kernel",
+                smmu.num_streams(),
+                bypass.len()
+            );
+            KERNEL_STREAMS.call_once(|| bypass);
+            IOMMU.call_once(|| smmu);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("failed to set up SMMU at {base:#x}: {e}");
+            Err(ProbeError::Failed {
+                reason: "SMMU setup failed",
+            })
+        }
+    }
+}
@@ -4,15 +4,26 @@ use alloc::vec::Vec;
 use kernel_core::{
     collections::HandleMap,
     memory::VirtualAddress,
-    platform::cpu::{CoreInfo, CpuIdReader, Id as CpuId},
-    process::thread::{
-        scheduler::RoundRobinScheduler, ProcessorState, Registers, SavedProgramStatus, Scheduler,
-        State, Thread, MAX_THREAD_ID,
+    platform::cpu::{CoreInfo, CoreSet, CpuIdReader, Id as CpuId},
+    process::{
+        system_call,
+        thread::{
+            scheduler::{events, RoundRobinScheduler},
+            switch::{switch_threads, ExceptionContext},
+            ProcessorState, SavedProgramStatus, Scheduler, State, Thread, MAX_THREAD_ID,
+        },
+        Name,
     },
+    sync::rcu,
 };
 use log::{debug, info, trace};
 use spin::once::Once;
 
+use crate::{branch_protection, exceptions::ExceptionFrame, timer::SystemCounter};
+
+/// The number of scheduling events kept for each core.
+const SCHEDULING_TRACE_CAPACITY: usize = 512;
+
 /// Implementation of [`CpuIdReader`] that reads the real system registers.
 pub struct SystemCpuIdReader;
 
@@ -35,9 +46,19 @@ pub type PlatformScheduler = RoundRobinScheduler<SystemCpuIdReader>;
 pub static SCHEDULER: Once<PlatformScheduler> = Once::new();
 pub static THREADS: Once<HandleMap<Thread>> = Once::new();
 
+/// The boot status of every core, which decides the cores threads can run on.
+pub static CORES: Once<CoreSet> = Once::new();
+
 pub fn init(cores: &[CoreInfo]) {
     debug!("Initalizing threads...");
 
+    CORES.call_once(|| CoreSet::new(cores.iter().map(|info| info.id)));
+    rcu::init(cores.iter().map(|info| info.id));
+    events::init::<SystemCpuIdReader, SystemCounter>(
+        cores.iter().map(|info| info.id),
+        SCHEDULING_TRACE_CAPACITY,
+    );
+
     let threads = THREADS.call_once(|| HandleMap::new(MAX_THREAD_ID));
 
     trace!("Creating thread scheduler...");
@@ -48,15 +69,30 @@ pub fn init(cores: &[CoreInfo]) {
             let idle_thread = Thread::new(threads, State::Running, unsafe {
                 ProcessorState::new_for_idle_thread()
             });
+            idle_thread.set_name(Name::new("idle"));
             (info.id, idle_thread)
         })
         .collect();
 
-    SCHEDULER.call_once(|| PlatformScheduler::new(&init_threads));
+    SCHEDULER.call_once(|| {
+        PlatformScheduler::new(&init_threads)
+            .with_topology(crate::cpu::topology().clone())
+            .with_wake(crate::exceptions::wake_core)
+    });
 
     info!("Threads initialized!");
 }
 
+/// Stop using the core `id`, which failed to boot, so that nothing waits for it.
+pub fn remove_core(id: CpuId) {
+    SCHEDULER.wait().remove_core(id);
+    if let Some(rcu) = rcu::global() {
+        rcu.enter_idle(id);
+    }
+    crate::watchdog::core_offline(id);
+    crate::lockup::core_offline(id);
+}
+
 /// Read the current value of the `SPSR_EL1` register.
 pub fn read_saved_program_status() -> SavedProgramStatus {
     let mut v: u64;
@@ -123,42 +159,123 @@ pub unsafe fn write_stack_pointer(el: u8, sp: VirtualAddress) {
     }
 }
 
-pub unsafe fn save_current_thread_state(registers: &Registers) {
-    let current_thread = SCHEDULER
-        .get()
-        .expect("scheduler init before thread switch")
-        .current_thread();
-    let mut s = current_thread
-        .processor_state
-        .try_lock()
-        .expect("no locks on current thread's execution state");
-    s.spsr = read_saved_program_status();
-    s.program_counter = read_exception_link_reg();
-    s.stack_pointer = read_stack_pointer(0);
-    s.registers = *registers;
-    trace!(
-        "saving processor state to thread#{}, pc={:?}",
-        current_thread.id,
-        s.program_counter
-    );
+/// Reads the EL0 thread pointer register (`TPIDR_EL0`).
+pub fn read_thread_pointer() -> VirtualAddress {
+    let mut v: usize;
+    unsafe {
+        core::arch::asm!("mrs {v}, TPIDR_EL0", v = out(reg) v);
+    }
+    v.into()
+}
+
+/// Writes the EL0 thread pointer register (`TPIDR_EL0`).
+///
+/// # Safety
+/// The kernel does not use this register, but it is up to the caller to ensure that the value is
+/// what the thread running at EL0 expects.
+pub unsafe fn write_thread_pointer(tp: VirtualAddress) {
+    core::arch::asm!("msr TPIDR_EL0, {v}", v = in(reg) usize::from(tp));
+}
+
+/// Enable or disable software stepping of user space on the current core (`MDSCR_EL1.SS`).
+///
+/// # Safety
+/// Stepping only takes effect when an exception returns to user space, with `SPSR_EL1.SS`
+/// deciding whether an instruction runs before the step exception is taken.
+pub unsafe fn write_single_step(enabled: bool) {
+    let mut mdscr: u64;
+    core::arch::asm!("mrs {v}, MDSCR_EL1", v = out(reg) mdscr);
+    mdscr = mdscr & !1 | u64::from(enabled);
+    core::arch::asm!("msr MDSCR_EL1, {v}", v = in(reg) mdscr);
 }
 
-pub unsafe fn restore_current_thread_state(registers: &mut Registers) {
-    let current_thread = SCHEDULER
+/// Allow debug exceptions, such as software steps, to be taken on the current core by clearing
+/// the OS lock, which is set when the core resets.
+pub fn init_debug_for_core() {
+    unsafe {
+        core::arch::asm!("msr OSLAR_EL1, xzr", "isb");
+    }
+}
+
+/// Accesses the system registers of the current core that hold the interrupted thread's state.
+pub struct SystemExceptionContext;
+
+impl ExceptionContext for SystemExceptionContext {
+    fn save(&self, state: &mut ProcessorState) {
+        state.spsr = read_saved_program_status();
+        state.program_counter = read_exception_link_reg();
+        state.stack_pointer = read_stack_pointer(0);
+        state.thread_pointer = read_thread_pointer();
+    }
+
+    fn restore(&self, state: &ProcessorState) {
+        // SAFETY: this is only called just before returning from an exception, and the state
+        // was either saved from a thread that was running or created for a new thread.
+        unsafe {
+            write_stack_pointer(0, state.stack_pointer);
+            write_thread_pointer(state.thread_pointer);
+            write_exception_link_reg(state.program_counter);
+            write_saved_program_status(&state.spsr);
+            write_single_step(state.single_step);
+            // the kernel doesn't use these keys, so they can be loaded now; the instruction A key
+            // is swapped by the exception vector
+            branch_protection::write_user_keys(&state.pointer_auth_keys);
+        }
+    }
+}
+
+/// Handle an exception by calling `handle`, switching to the thread chosen by the scheduler
+/// afterwards.
+///
+/// # Safety
+/// This must only be called by an exception handler, with `frame` being the exception frame that
+/// will be restored when the exception returns.
+pub unsafe fn switch_threads_around(frame: &mut ExceptionFrame, handle: impl FnOnce()) {
+    let scheduler = SCHEDULER
         .get()
-        .expect("scheduler init before thread switch")
-        .current_thread();
-    let s = current_thread
-        .processor_state
-        .try_lock()
-        .expect("no locks on current thread's execution state");
-    *registers = s.registers;
-    write_stack_pointer(0, s.stack_pointer);
-    write_exception_link_reg(s.program_counter);
-    write_saved_program_status(&s.spsr);
-    trace!(
-        "restoring processor state to thread#{}, pc={:?}",
-        current_thread.id,
-        s.program_counter
+        .expect("scheduler init before thread switch");
+    let rcu = rcu::global();
+    let cpu = SystemCpuIdReader::current_cpu();
+    let was_idle = scheduler.is_idle();
+    if let Some(rcu) = rcu.filter(|_| was_idle) {
+        rcu.exit_idle(cpu);
+    }
+    // an exception taken from the kernel may have interrupted a read of a lock-free structure (a
+    // synchronous one can happen even while reads mask interrupts), but user threads and idle cores
+    // can't be reading, so interrupting them is a quiescent point
+    let from_user = read_saved_program_status().el() == 0;
+    let quiescent = was_idle || from_user;
+    let switched = switch_threads(
+        scheduler,
+        crate::timer::clock(),
+        &SystemExceptionContext,
+        &mut frame.registers,
+        &mut frame.fp,
+        handle,
     );
+    // user threads can always be preempted, so only a core stuck in the kernel stops making
+    // progress
+    if switched || from_user {
+        crate::lockup::heartbeat();
+    }
+    if switched {
+        let next = scheduler.current_thread();
+        trace!("switched to thread#{}", next.id);
+        // the exception vector loads the instruction key from the frame on return to user space
+        frame.user_instruction_key = next.processor_state.lock().pointer_auth_keys.instruction_a;
+        crate::process::activate(&next);
+    }
+    let current = scheduler.current_thread();
+    if current.process().is_some() {
+        // a system call that blocked the thread only learns how its wait ended now
+        system_call::complete_wait(&current, &mut frame.registers);
+    }
+    if let Some(rcu) = rcu {
+        if quiescent {
+            rcu.quiescent(cpu);
+        }
+        if scheduler.is_idle() {
+            rcu.enter_idle(cpu);
+        }
+    }
 }
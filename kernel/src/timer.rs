@@ -3,24 +3,105 @@
 use bitfield::bitfield;
 use core::arch::asm;
 use kernel_core::{
+    driver::{Device, Driver, ProbeError},
     exceptions::{interrupt, InterruptController, InterruptId},
+    memory::{PageAllocator as _, PhysicalAddress, PhysicalPointer},
     platform::{
         device_tree::{
             iter::NodePropertyIter, ParseError, PropertyNotFoundSnafu, UnexpectedValueSnafu,
         },
         timer::SystemTimer,
     },
+    time::{Clock, CounterReader, Ticks, TimePage},
 };
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use snafu::{ensure, OptionExt};
+use spin::Once;
 
-/// Write timer value register (`CNTP_TVAL_EL0`).
+use crate::exceptions::{CONTROLLER, TIMER, TIMER_INTERVAL};
+
+/// Write timer compare value register (`CNTP_CVAL_EL0`).
 ///
 /// # Safety
-/// This will change the system register that stores the current timer value.
-/// This may cause the timer interrupt to be triggered if it is enabled, and is globally visible.
-unsafe fn write_timer_value(timer_value: u32) {
-    asm!("msr CNTP_TVAL_EL0, {cv:x}", cv = in(reg) timer_value);
+/// This will change the system register that stores the counter value the timer fires at.
+/// This may cause the timer interrupt to be triggered if it is enabled.
+unsafe fn write_compare_value(compare_value: u64) {
+    asm!("msr CNTP_CVAL_EL0, {cv}", cv = in(reg) compare_value);
+}
+
+/// Read timer compare value register (`CNTP_CVAL_EL0`).
+fn read_compare_value() -> u64 {
+    let mut compare_value: u64;
+    unsafe {
+        asm!("mrs {cv}, CNTP_CVAL_EL0", cv = out(reg) compare_value);
+    }
+    compare_value
+}
+
+/// The counter value that the current core's timer fires at next, or [`Ticks::MAX`] if it has
+/// been stopped.
+pub fn next_deadline() -> Ticks {
+    read_compare_value()
+}
+
+/// Reads the physical counter register (`CNTPCT_EL0`).
+pub struct SystemCounter;
+
+impl CounterReader for SystemCounter {
+    fn read() -> Ticks {
+        let mut count: u64;
+        unsafe {
+            asm!("isb", "mrs {val}, CNTPCT_EL0", val = out(reg) count);
+        }
+        count
+    }
+}
+
+/// The system-wide clock.
+static CLOCK: Once<Clock<SystemCounter>> = Once::new();
+
+/// The system-wide clock, shared by everything in the kernel that needs to know the time.
+pub fn clock() -> &'static Clock<SystemCounter> {
+    CLOCK.call_once(|| Clock::new(u64::from(frequency())))
+}
+
+/// The physical address of the time page, which is mapped read only into every process.
+static TIME_PAGE: Once<usize> = Once::new();
+
+/// Allocate the time page and publish the system clock's parameters to it.
+///
+/// # Panics
+/// Panics if the page could not be allocated.
+pub fn init_time_page() {
+    let page = crate::memory::page_allocator()
+        .allocate_zeroed(1)
+        .expect("allocate time page");
+    let ptr: *mut TimePage = PhysicalPointer::<TimePage>::from(usize::from(page)).into();
+    // SAFETY: the page was just allocated, and is never freed.
+    let time_page = unsafe {
+        ptr.write(TimePage::new());
+        &*ptr
+    };
+    // the virtual counter is offset from the physical counter by a constant amount, so the
+    // difference between two nearly simultaneous reads is a close enough estimate
+    let counter_offset = SystemCounter::read().wrapping_sub(read_virtual_counter());
+    clock().attach_time_page(time_page, counter_offset);
+    TIME_PAGE.call_once(|| usize::from(page));
+    debug!("time page at {page:?}, counter offset {counter_offset}");
+}
+
+/// The physical address of the time page, to map into new processes.
+pub fn time_page() -> PhysicalAddress {
+    PhysicalAddress::from(*TIME_PAGE.get().expect("time page initialized"))
+}
+
+/// Read the virtual counter register (`CNTVCT_EL0`).
+pub fn read_virtual_counter() -> u64 {
+    let mut count: u64;
+    unsafe {
+        asm!("isb", "mrs {val}, CNTVCT_EL0", val = out(reg) count);
+    }
+    count
 }
 
 /// Read timer counter frequency register (`CNTFRQ_EL0`).
@@ -32,6 +113,19 @@ fn frequency() -> u32 {
     freq
 }
 
+/// Allow user space to read the virtual counter (`CNTVCT_EL0`), by setting `EL0VCTEN` in
+/// `CNTKCTL_EL1`, so that it can compute the time from the time page.
+fn enable_user_counter_access() {
+    unsafe {
+        asm!(
+            "mrs {tmp}, CNTKCTL_EL1",
+            "orr {tmp}, {tmp}, #0b10",
+            "msr CNTKCTL_EL1, {tmp}",
+            tmp = out(reg) _,
+        );
+    }
+}
+
 bitfield! {
     struct TimerControlRegister(u64);
     impl Debug;
@@ -64,6 +158,32 @@ impl TimerControlRegister {
 /// A list of device tree `compatible` strings (see section 2.3.1 of the spec) that this driver is compatible with.
 const COMPATIBLE: &[&[u8]] = &[b"arm,armv7-timer", b"arm,armv8-timer"];
 
+/// The driver for the system timer, which is bound once the interrupt controller is.
+pub const DRIVER: Driver = Driver {
+    name: "timer",
+    compatible: COMPATIBLE,
+    probe,
+};
+
+/// Set up the system timer, if the interrupt controller has been bound.
+fn probe(device: &Device) -> Result<(), ProbeError> {
+    let Some(controller) = CONTROLLER.get() else {
+        return Err(ProbeError::Deferred);
+    };
+    if TIMER.get().is_some() {
+        return Err(ProbeError::Declined);
+    }
+    let node = device.properties().ok_or(ProbeError::Declined)?;
+    let timer = Timer::in_device_tree(node, controller, TIMER_INTERVAL).map_err(|e| {
+        warn!("failed to configure system timer: {e}");
+        ProbeError::Failed {
+            reason: "invalid device tree node",
+        }
+    })?;
+    TIMER.call_once(|| timer);
+    Ok(())
+}
+
 /// The system timer interface.
 ///
 /// This interface is implicitly per-CPU, so it does not need to be synchronized.
@@ -71,7 +191,7 @@ const COMPATIBLE: &[&[u8]] = &[b"arm,armv7-timer", b"arm,armv8-timer"];
 pub struct Timer {
     int_id: InterruptId,
     int_config: interrupt::Config,
-    reset_value: u32,
+    time_slice: Ticks,
 }
 
 impl Timer {
@@ -122,8 +242,9 @@ impl Timer {
             int_config: interrupt::Config {
                 priority: 0,
                 mode: trigger_mode,
+                ..interrupt::Config::default()
             },
-            reset_value: frequency() / interval,
+            time_slice: u64::from(frequency() / interval),
         };
 
         debug!("configured system timer: {s:?}");
@@ -136,13 +257,15 @@ impl Timer {
     // NOTE: you've gotta call this for every CPU because the timer itself is per-CPU
     // this is kinda strange, b/c it should really be in the mech trait
     pub fn start_for_core(&self, intc: &impl InterruptController) {
+        enable_user_counter_access();
         let mut ctl = TimerControlRegister::read();
         ctl.set_enable(true);
         ctl.set_imask(false);
         unsafe {
             ctl.write();
-            write_timer_value(0);
         }
+        // fire immediately to start the first time slice
+        self.set_deadline(SystemCounter::read());
         intc.enable(self.int_id);
         trace!("system timer started");
     }
@@ -153,9 +276,21 @@ impl SystemTimer for Timer {
         self.int_id
     }
 
-    fn reset(&self) {
+    fn now(&self) -> Ticks {
+        SystemCounter::read()
+    }
+
+    fn time_slice(&self) -> Ticks {
+        self.time_slice
+    }
+
+    fn deadline(&self) -> Ticks {
+        read_compare_value()
+    }
+
+    fn set_deadline(&self, deadline: Ticks) {
         unsafe {
-            write_timer_value(self.reset_value);
+            write_compare_value(deadline);
         }
     }
 }
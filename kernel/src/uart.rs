@@ -2,13 +2,43 @@
 //!
 //! Documentation for the interface can be found [on ARM's website](https://developer.arm.com/documentation/ddi0183/latest/).
 
-use core::fmt::Write;
-
 use kernel_core::{
-    logger::LogSink,
-    memory::PhysicalPointer,
-    platform::device_tree::{DeviceTree, Value},
+    driver::{Device, Driver, ProbeError},
+    exceptions::{interrupt::TriggerMode, InterruptController, InterruptId},
+    memory::{PhysicalAddress, PhysicalPointer},
+    platform::{
+        device_tree::{DeviceTree, Value},
+        uart::{Uart, UartMechanism},
+    },
 };
+use spin::Once;
+
+/// Register offsets, in bytes.
+mod regs {
+    /// Data register.
+    pub const DR: usize = 0x00;
+    /// Flag register.
+    pub const FR: usize = 0x18;
+    /// Interrupt mask set/clear register.
+    pub const IMSC: usize = 0x38;
+    /// Interrupt clear register.
+    pub const ICR: usize = 0x44;
+
+    /// Receive FIFO empty bit in `FR`.
+    pub const FR_RXFE: u32 = 1 << 4;
+    /// Transmit FIFO full bit in `FR`.
+    pub const FR_TXFF: u32 = 1 << 5;
+
+    /// Receive interrupt bit in `IMSC`.
+    pub const INT_RX: u32 = 1 << 4;
+    /// Transmit interrupt bit in `IMSC`.
+    pub const INT_TX: u32 = 1 << 5;
+    /// Receive timeout interrupt bit in `IMSC`, raised when bytes are left in the FIFO below the
+    /// receive interrupt threshold.
+    pub const INT_RT: u32 = 1 << 6;
+    /// Every interrupt bit in `ICR`.
+    pub const INT_ALL: u32 = 0x7ff;
+}
 
 /// The PL011 UART object.
 pub struct PL011 {
@@ -17,40 +47,143 @@ pub struct PL011 {
 
 // SAFETY: It's fine to move the pointer as long as it doesn't get duplicated!
 unsafe impl Send for PL011 {}
+// SAFETY: registers are only accessed with single volatile reads and writes.
+unsafe impl Sync for PL011 {}
 
 impl PL011 {
-    /// Configure the driver using information from a device tree node.
+    /// Configure the driver using information from a device tree node, returning it along with
+    /// the physical address and length of its registers.
     /// The node must follow the spec at [].
-    pub fn from_device_tree(dt: &DeviceTree, path: &[u8]) -> Option<Self> {
-        let mut base_address = None;
+    pub fn from_device_tree(dt: &DeviceTree, path: &[u8]) -> Option<(Self, (usize, usize))> {
+        let mut registers = None;
         for (name, value) in dt.iter_node_properties(path)? {
             if let (b"reg", Value::Reg(r)) = (name, value) {
-                base_address = r.iter().next().map(|(r, _)| PhysicalPointer::from(r));
+                registers = r.iter().next();
             }
         }
-        base_address.map(|r| PL011 {
-            base_address: r.into(),
+        registers.map(|(base, length)| {
+            (
+                PL011 {
+                    base_address: PhysicalPointer::from(base).into(),
+                },
+                (base, length),
+            )
         })
     }
-}
 
-impl Write for PL011 {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for ch in s.bytes() {
-            unsafe {
-                self.base_address.write_volatile(ch);
-            }
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.read_volatile()
+        }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.write_volatile(value);
         }
-        Ok(())
     }
 }
 
-impl LogSink for PL011 {
-    fn accept(&mut self, chunk: &[u8]) {
-        for byte in chunk {
-            unsafe {
-                self.base_address.write_volatile(*byte);
-            }
+impl UartMechanism for PL011 {
+    fn can_transmit(&self) -> bool {
+        self.read_reg(regs::FR) & regs::FR_TXFF == 0
+    }
+
+    fn transmit(&self, byte: u8) {
+        self.write_reg(regs::DR, u32::from(byte));
+    }
+
+    fn can_receive(&self) -> bool {
+        self.read_reg(regs::FR) & regs::FR_RXFE == 0
+    }
+
+    fn receive(&self) -> u8 {
+        // the upper bits hold error flags
+        self.read_reg(regs::DR).to_le_bytes()[0]
+    }
+
+    fn set_interrupts(&self, transmit: bool, receive: bool) {
+        let mut mask = 0;
+        if transmit {
+            mask |= regs::INT_TX;
+        }
+        if receive {
+            mask |= regs::INT_RX | regs::INT_RT;
         }
+        self.write_reg(regs::IMSC, mask);
+    }
+
+    fn clear_interrupts(&self) {
+        self.write_reg(regs::ICR, regs::INT_ALL);
+    }
+}
+
+/// The UART used for the kernel console and log output.
+pub static UART: Once<Uart<PL011>> = Once::new();
+
+/// The physical address and length of the registers of [`UART`].
+static REGISTERS: Once<(usize, usize)> = Once::new();
+
+/// The driver for the PL011, bound only to the UART used for the kernel console.
+pub const DRIVER: Driver = Driver {
+    name: "pl011",
+    compatible: &[b"arm,pl011"],
+    probe,
+};
+
+/// Set up the console UART. Other UARTs are declined, since they are left for driver processes.
+fn probe(device: &Device) -> Result<(), ProbeError> {
+    if device.path != stdout_path(device.device_tree) {
+        return Err(ProbeError::Declined);
+    }
+    if UART.get().is_none() {
+        let (mech, registers) =
+            PL011::from_device_tree(device.device_tree, device.path).ok_or(ProbeError::Failed {
+                reason: "no registers",
+            })?;
+        REGISTERS.call_once(|| registers);
+        UART.call_once(|| Uart::new(mech));
+    }
+    Ok(())
+}
+
+/// Reserve the registers of the console UART, so that no driver process can claim them.
+///
+/// The UART is set up before memory is, so it is reached through the mapping of physical memory
+/// rather than [`crate::memory::map_device`], which would reserve them itself.
+pub fn reserve_registers() {
+    if let Some(&(base, length)) = REGISTERS.get() {
+        crate::memory::mmio_registry().reserve(PhysicalAddress::from(base), length);
+    }
+}
+
+/// The device tree path of the UART used for the kernel console.
+pub fn stdout_path<'a>(device_tree: &'a DeviceTree) -> &'a [u8] {
+    device_tree
+        .find_property(b"/chosen/stdout-path")
+        .and_then(Value::into_bytes)
+        // the string is null terminated in the device tree
+        // TODO: default to QEMU virt board UART for now, should be platform default
+        .map_or(b"/pl011@9000000" as &[u8], |p| &p[0..p.len() - 1])
+}
+
+/// Find the interrupt raised by the console UART.
+pub fn interrupt_in_device_tree(
+    device_tree: &DeviceTree,
+    intc: &impl InterruptController,
+) -> Option<(InterruptId, TriggerMode)> {
+    device_tree
+        .iter_node_properties(stdout_path(device_tree))?
+        .find(|(name, _)| *name == b"interrupts")
+        .and_then(|(_, value)| value.into_bytes())
+        .and_then(|blob| intc.interrupt_in_device_tree(blob, 0))
+}
+
+/// Handle an interrupt from the console UART.
+pub fn handle_interrupt() {
+    if let Some(uart) = UART.get() {
+        uart.handle_interrupt();
     }
 }
@@ -0,0 +1,220 @@
+//! Virtio devices on the MMIO transport, found in the device tree.
+//!
+//! The console carries log output and console input, which makes logs available on QEMU
+//! configurations without a PL011. Block devices give the kernel access to disk images, and the
+//! entropy device seeds the kernel's random number generator.
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::time::Duration;
+use kernel_core::{
+    driver::{self, Driver, ProbeError},
+    io::{self, BlockDevice},
+    memory::PhysicalAddress,
+    platform::{
+        device_tree::Value,
+        virtio::{
+            block::{self, VirtioBlock},
+            console::{self, VirtioConsole},
+            rng::{self, VirtioRng},
+            Device, Transport,
+        },
+    },
+};
+use log::{debug, info, warn};
+use spin::{Mutex, Once};
+
+use crate::{driver::DeviceHandler, memory::map_device};
+
+/// The registers of a virtio MMIO transport, mapped into the kernel address space.
+pub struct MmioTransport {
+    base_address: *mut u8,
+}
+
+// SAFETY: It's fine to move the pointer as long as it doesn't get duplicated!
+unsafe impl Send for MmioTransport {}
+// SAFETY: registers are only accessed with single volatile reads and writes.
+unsafe impl Sync for MmioTransport {}
+
+impl Transport for MmioTransport {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.read_volatile()
+        }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe {
+            let reg: *mut u32 = self.base_address.add(offset).cast();
+            reg.write_volatile(value);
+        }
+    }
+}
+
+/// The virtio console, if there is one.
+pub static CONSOLE: Once<VirtioConsole<MmioTransport>> = Once::new();
+
+/// The virtio entropy device, if there is one.
+pub static RNG: Once<VirtioRng<MmioTransport>> = Once::new();
+
+/// The virtio block devices whose interrupts have been registered, in the order they appear in the
+/// device tree.
+pub static DISKS: Mutex<Vec<Arc<VirtioBlock<MmioTransport>>>> = Mutex::new(Vec::new());
+
+/// How long to wait for a disk to finish a request before giving up on it.
+const DISK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The driver for virtio MMIO transports. The first console, the first entropy device, and every
+/// block device are set up.
+///
+/// The memory subsystem must be initialized first, since the devices are given buffers allocated
+/// with the DMA allocator.
+pub const DRIVER: Driver = Driver {
+    name: "virtio-mmio",
+    compatible: &[b"virtio,mmio"],
+    probe,
+};
+
+/// Set up the device behind a virtio MMIO transport, declining empty transports and devices that
+/// aren't supported.
+fn probe(node: &driver::Device) -> Result<(), ProbeError> {
+    let mut region = None;
+    let mut interrupts = None;
+    for (name, value) in node.properties().ok_or(ProbeError::Declined)? {
+        match (name, value) {
+            (b"reg", Value::Reg(r)) => region = r.iter().next(),
+            (b"interrupts", value) => interrupts = value.into_bytes(),
+            _ => {}
+        }
+    }
+    let (base, len) = region.ok_or(ProbeError::Failed {
+        reason: "no registers",
+    })?;
+    let transport = MmioTransport {
+        base_address: map_device(PhysicalAddress::from(base), len),
+    };
+    let device = match Device::probe(transport) {
+        Ok(Some(device)) => device,
+        Ok(None) => return Err(ProbeError::Declined),
+        Err(e) => {
+            debug!("skipping virtio transport at {base:#x}: {e}");
+            return Err(ProbeError::Declined);
+        }
+    };
+    let handler: DeviceHandler = match device.device_id() {
+        console::DEVICE_ID if CONSOLE.get().is_none() => {
+            match VirtioConsole::new(device, &crate::memory::dma_allocator()) {
+                Ok(console) => {
+                    CONSOLE.call_once(|| console);
+                    info!("virtio console at {base:#x}");
+                    Box::new(|| {
+                        if let Some(console) = CONSOLE.get() {
+                            console.handle_interrupt();
+                        }
+                    })
+                }
+                Err(e) => {
+                    warn!("failed to set up virtio console at {base:#x}: {e}");
+                    return Err(ProbeError::Failed {
+                        reason: "console setup failed",
+                    });
+                }
+            }
+        }
+        rng::DEVICE_ID if RNG.get().is_none() => {
+            match VirtioRng::new(device, &crate::memory::dma_allocator()) {
+                Ok(rng) => {
+                    RNG.call_once(|| rng);
+                    info!("virtio entropy device at {base:#x}");
+                    Box::new(|| {
+                        if let Some(rng) = RNG.get() {
+                            rng.handle_interrupt();
+                            // keep asking until the pool is seeded
+                            if !kernel_core::rand::is_seeded() {
+                                rng.request();
+                            }
+                        }
+                    })
+                }
+                Err(e) => {
+                    warn!("failed to set up virtio entropy device at {base:#x}: {e}");
+                    return Err(ProbeError::Failed {
+                        reason: "entropy device setup failed",
+                    });
+                }
+            }
+        }
+        block::DEVICE_ID => return probe_block(device, base, interrupts),
+        _ => return Err(ProbeError::Declined),
+    };
+    if let Some(interrupts) = interrupts {
+        crate::driver::add_threaded_interrupt(interrupts, handler);
+    }
+    Ok(())
+}
+
+/// Set up a block device at `base`, which is made available in [`DISKS`] once its interrupt is
+/// registered, since its requests are only ever finished by the interrupt handler.
+fn probe_block(
+    device: Device<MmioTransport>,
+    base: usize,
+    interrupts: Option<&[u8]>,
+) -> Result<(), ProbeError> {
+    let interrupts = interrupts.ok_or(ProbeError::Failed {
+        reason: "block device has no interrupt",
+    })?;
+    let disk = match VirtioBlock::new(device, &crate::memory::dma_allocator()) {
+        Ok(disk) => Arc::new(disk),
+        Err(e) => {
+            warn!("failed to set up virtio block device at {base:#x}: {e}");
+            return Err(ProbeError::Failed {
+                reason: "block device setup failed",
+            });
+        }
+    };
+    info!(
+        "virtio block device at {base:#x}: {} sectors{}",
+        disk.num_sectors(),
+        if disk.read_only() { ", read only" } else { "" }
+    );
+    let handler = {
+        let disk = disk.clone();
+        Box::new(move || disk.handle_interrupt())
+    };
+    crate::driver::add_threaded_interrupt_then(
+        interrupts,
+        handler,
+        Box::new(move || DISKS.lock().push(disk)),
+    );
+    Ok(())
+}
+
+/// Read input received by the virtio console into `buf`, returning the number of bytes read.
+pub fn read_console(buf: &mut [u8]) -> usize {
+    CONSOLE.get().map_or(0, |console| console.read(buf))
+}
+
+/// Read the sectors starting at `sector` from `disk` into `buf`, whose length must be a whole
+/// number of sectors, blocking the current kernel thread until the device is finished or
+/// [`DISK_TIMEOUT`] passes.
+///
+/// # Errors
+/// Returns an error if the request is invalid, the device could not carry it out, or it took too
+/// long.
+pub fn read_disk(disk: &dyn BlockDevice, sector: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+    let clock = crate::timer::clock();
+    let deadline =
+        clock.now() + clock.nanos_to_ticks(DISK_TIMEOUT.as_nanos().try_into().unwrap_or(u64::MAX));
+    io::read_blocking(
+        disk,
+        sector,
+        buf,
+        &crate::memory::dma_allocator(),
+        |notification, flags| {
+            if clock.now() >= deadline {
+                return false;
+            }
+            crate::kthread::wait(notification, flags, deadline);
+            true
+        },
+    )
+}
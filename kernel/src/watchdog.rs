@@ -0,0 +1,263 @@
+//! Hardware watchdog drivers.
+//!
+//! Supports the SBSA generic watchdog and the ARM SP805 watchdog.
+use alloc::boxed::Box;
+use byteorder::{BigEndian, ByteOrder};
+use core::time::Duration;
+use kernel_core::{
+    memory::PhysicalAddress,
+    platform::{
+        cpu::{CoreInfo, CpuIdReader, Id as CpuId},
+        device_tree::{iter::NodePropertyIter, DeviceTree, ParseError, PropertyNotFoundSnafu},
+        watchdog::{Watchdog, WatchdogPolicy},
+    },
+};
+use log::{debug, info, warn};
+use snafu::OptionExt as _;
+use spin::Once;
+
+use crate::{exceptions::TIMER_QUEUE, memory::map_device, thread::SystemCpuIdReader, timer::clock};
+
+/// How long the system can go without petting the watchdog before it resets.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Register offsets for the SBSA generic watchdog, in bytes.
+mod sbsa_regs {
+    /// Control and status register, in the control frame.
+    pub const WCS: usize = 0x000;
+    /// Offset register, in the control frame.
+    pub const WOR: usize = 0x008;
+    /// Refresh register, in the refresh frame.
+    pub const WRR: usize = 0x000;
+
+    /// Watchdog enable bit in `WCS`.
+    pub const WCS_EN: u32 = 1;
+}
+
+/// Register offsets for the SP805 watchdog, in bytes.
+mod sp805_regs {
+    /// Load register.
+    pub const LOAD: usize = 0x000;
+    /// Control register.
+    pub const CONTROL: usize = 0x008;
+    /// Interrupt clear register, which also reloads the counter.
+    pub const INTCLR: usize = 0x00c;
+    /// Lock register.
+    pub const LOCK: usize = 0xc00;
+
+    /// Enable the counter and interrupt in `CONTROL`.
+    pub const CONTROL_INTEN: u32 = 1 << 0;
+    /// Enable the reset output in `CONTROL`.
+    pub const CONTROL_RESEN: u32 = 1 << 1;
+    /// Value to write to `LOCK` to allow writes to the other registers.
+    pub const UNLOCK: u32 = 0x1acc_e551;
+}
+
+/// A watchdog present in the system.
+pub enum PlatformWatchdog {
+    /// An SBSA generic watchdog, which counts using the system counter.
+    Sbsa {
+        /// Base of the control frame.
+        control: *mut u8,
+        /// Base of the refresh frame.
+        refresh: *mut u8,
+    },
+    /// An ARM SP805 watchdog.
+    Sp805 {
+        /// Base of the registers.
+        base: *mut u8,
+        /// Frequency of the watchdog clock in Hz.
+        frequency: u32,
+    },
+}
+
+// SAFETY: the registers are only written with single volatile writes.
+unsafe impl Send for PlatformWatchdog {}
+unsafe impl Sync for PlatformWatchdog {}
+
+/// Write the 32-bit register at byte offset `offset` from `base`.
+unsafe fn write_reg(base: *mut u8, offset: usize, value: u32) {
+    let reg: *mut u32 = base.add(offset).cast();
+    reg.write_volatile(value);
+}
+
+/// Find the frequency of the fixed clock with the given `phandle`.
+fn clock_frequency(dt: &DeviceTree, phandle: u32) -> Option<u32> {
+    dt.iter_node_properties_by_phandle(phandle)?
+        .find(|(name, _)| *name == b"clock-frequency")
+        .and_then(|(_, value)| value.into_bytes())
+        .filter(|data| data.len() >= 4)
+        .map(BigEndian::read_u32)
+}
+
+impl PlatformWatchdog {
+    /// Create a driver for the watchdog described by a device tree node, if it is supported.
+    pub fn in_device_tree<'dt>(
+        dt: &'dt DeviceTree,
+        node: NodePropertyIter<'dt>,
+    ) -> Result<Option<Self>, ParseError<'dt>> {
+        let mut kind = None;
+        let mut regions = None;
+        let mut clock = None;
+        for (name, value) in node {
+            match name {
+                b"compatible" => {
+                    let strings = value.as_strings(name)?;
+                    if strings.contains(b"arm,sbsa-gwdt") {
+                        kind = Some(true);
+                    } else if strings.contains(b"arm,sp805") {
+                        kind = Some(false);
+                    }
+                }
+                b"reg" => regions = Some(value.as_reg(name)?.clone()),
+                b"clocks" => clock = Some(BigEndian::read_u32(value.as_bytes(name)?)),
+                _ => {}
+            }
+        }
+        let Some(is_sbsa) = kind else {
+            return Ok(None);
+        };
+        let regions = regions.context(PropertyNotFoundSnafu { name: "reg" })?;
+        let mut regions = regions.iter();
+        let mut next_region = || {
+            regions
+                .next()
+                .map(|(base, len)| map_device(PhysicalAddress::from(base), len))
+                .context(PropertyNotFoundSnafu { name: "reg" })
+        };
+        if is_sbsa {
+            let control = next_region()?;
+            let refresh = next_region()?;
+            Ok(Some(Self::Sbsa { control, refresh }))
+        } else {
+            let base = next_region()?;
+            let frequency = clock
+                .and_then(|phandle| clock_frequency(dt, phandle))
+                .context(PropertyNotFoundSnafu { name: "clocks" })?;
+            Ok(Some(Self::Sp805 { base, frequency }))
+        }
+    }
+}
+
+impl Watchdog for PlatformWatchdog {
+    fn start(&self, timeout: Duration) {
+        // both watchdogs signal once the count expires, and then reset when it expires again
+        let half = timeout / 2;
+        match self {
+            Self::Sbsa { control, .. } => {
+                let ticks = clock().nanos_to_ticks(half.as_nanos().try_into().unwrap_or(u64::MAX));
+                unsafe {
+                    write_reg(
+                        *control,
+                        sbsa_regs::WOR,
+                        ticks.try_into().unwrap_or(u32::MAX),
+                    );
+                    write_reg(*control, sbsa_regs::WCS, sbsa_regs::WCS_EN);
+                }
+            }
+            Self::Sp805 { base, frequency } => {
+                let count = u64::from(*frequency) * half.as_secs()
+                    + u64::from(*frequency) * u64::from(half.subsec_millis()) / 1000;
+                unsafe {
+                    write_reg(*base, sp805_regs::LOCK, sp805_regs::UNLOCK);
+                    write_reg(
+                        *base,
+                        sp805_regs::LOAD,
+                        count.try_into().unwrap_or(u32::MAX),
+                    );
+                    write_reg(
+                        *base,
+                        sp805_regs::CONTROL,
+                        sp805_regs::CONTROL_INTEN | sp805_regs::CONTROL_RESEN,
+                    );
+                    write_reg(*base, sp805_regs::LOCK, 0);
+                }
+            }
+        }
+    }
+
+    fn pet(&self) {
+        match self {
+            Self::Sbsa { refresh, .. } => unsafe {
+                write_reg(*refresh, sbsa_regs::WRR, 0);
+            },
+            Self::Sp805 { base, .. } => unsafe {
+                write_reg(*base, sp805_regs::LOCK, sp805_regs::UNLOCK);
+                write_reg(*base, sp805_regs::INTCLR, 0);
+                write_reg(*base, sp805_regs::LOCK, 0);
+            },
+        }
+    }
+}
+
+/// The watchdog present in the system, if any.
+static WATCHDOG: Once<PlatformWatchdog> = Once::new();
+
+/// The policy for petting the watchdog.
+static POLICY: Once<WatchdogPolicy> = Once::new();
+
+/// Discover and start the system watchdog, if there is one.
+pub fn init(dt: &DeviceTree, cores: &[CoreInfo]) {
+    let Some(watchdog) = dt
+        .iter_nodes_named(b"/", b"watchdog")
+        .into_iter()
+        .flatten()
+        .find_map(
+            |node| match PlatformWatchdog::in_device_tree(dt, node.properties) {
+                Ok(w) => w,
+                Err(e) => {
+                    warn!("failed to configure watchdog: {e}");
+                    None
+                }
+            },
+        )
+    else {
+        debug!("no supported watchdog found");
+        return;
+    };
+
+    let cores: alloc::vec::Vec<_> = cores.iter().map(|c| c.id).collect();
+    POLICY.call_once(|| WatchdogPolicy::new(&cores, clock().now()));
+    let watchdog = WATCHDOG.call_once(|| watchdog);
+    watchdog.start(TIMEOUT);
+    schedule_check();
+    info!("watchdog started with a timeout of {TIMEOUT:?}");
+}
+
+/// Record that the current core is still responsive.
+pub fn heartbeat() {
+    if let Some(policy) = POLICY.get() {
+        policy.heartbeat(SystemCpuIdReader::current_cpu(), clock().now());
+    }
+}
+
+/// Record that the current core is idle and waiting for an interrupt.
+pub fn idle() {
+    if let Some(policy) = POLICY.get() {
+        policy.idle(SystemCpuIdReader::current_cpu());
+    }
+}
+
+/// Stop expecting heartbeats from the core `id`, because it is offline.
+pub fn core_offline(id: CpuId) {
+    if let Some(policy) = POLICY.get() {
+        policy.idle(id);
+    }
+}
+
+/// Arm a timer to check the health of the cores and pet the watchdog.
+///
+/// Checks happen several times per timeout so that a healthy system always pets the watchdog in
+/// time, while busy cores take several time slice interrupts between checks.
+fn schedule_check() {
+    let interval = clock().nanos_to_ticks((TIMEOUT / 4).as_nanos().try_into().unwrap_or(u64::MAX));
+    TIMER_QUEUE.arm(
+        clock().now() + interval,
+        Box::new(|| {
+            if let (Some(policy), Some(watchdog)) = (POLICY.get(), WATCHDOG.get()) {
+                policy.check(watchdog, clock().now());
+            }
+            schedule_check();
+        }),
+    );
+}
@@ -1,29 +1,110 @@
 //
 
 use core::{
+    fmt,
+    hash::{Hash, Hasher},
     marker::PhantomData,
+    mem::ManuallyDrop,
     ptr::NonNull,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
 };
 
-use alloc::{boxed::Box, sync::Arc};
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 
 use super::HandleAllocator;
+use crate::sync::rcu;
 
+/// A raw handle to a value in a [`HandleMap`].
 pub type Handle = u32;
 
-struct Table<T>([AtomicUsize; 256], PhantomData<Arc<T>>);
+/// A handle to a value of type `T` in a [`HandleMap<T>`], so that handles to different kinds of
+/// objects can't be mixed up.
+pub struct TypedHandle<T> {
+    raw: Handle,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedHandle<T> {
+    /// Assume that the raw handle `raw` refers to a value of type `T`.
+    #[must_use]
+    pub const fn from_raw(raw: Handle) -> Self {
+        Self {
+            raw,
+            _type: PhantomData,
+        }
+    }
+
+    /// The raw handle, for instance to give to user space.
+    #[must_use]
+    pub const fn raw(self) -> Handle {
+        self.raw
+    }
+}
+
+// these are implemented by hand so that `T` doesn't need to implement them too
+impl<T> Clone for TypedHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for TypedHandle<T> {}
 
-impl<T> Default for Table<T> {
-    fn default() -> Self {
+impl<T> PartialEq for TypedHandle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl<T> Eq for TypedHandle<T> {}
+
+impl<T> Hash for TypedHandle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.raw.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for TypedHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}", core::any::type_name::<T>(), self.raw)
+    }
+}
+
+impl<T> From<TypedHandle<T>> for Handle {
+    fn from(value: TypedHandle<T>) -> Self {
+        value.raw
+    }
+}
+
+/// A level of the map's tree of tables, holding either pointers to the next level or values. Leaf
+/// tables, which hold values, also hold the current generation of each value slot.
+struct Table<T>(
+    [AtomicUsize; 256],
+    Option<Box<[AtomicU32; 256]>>,
+    PhantomData<Arc<T>>,
+);
+
+impl<T> Table<T> {
+    /// Make an empty table, which holds values if it is a `leaf` and tables otherwise.
+    fn new(leaf: bool) -> Self {
         Self(
             core::array::from_fn(|_| AtomicUsize::default()),
+            leaf.then(|| Box::new(core::array::from_fn(|_| AtomicU32::default()))),
             PhantomData,
         )
     }
-}
 
-impl<T> Table<T> {
+    /// The generations of the value slots of a leaf table.
+    fn generations(&self) -> &[AtomicU32; 256] {
+        self.1
+            .as_deref()
+            .expect("only leaf tables have generations")
+    }
+
     /// Get the `Arc<T>` stored at `index`, or `None` if there is no value at that index.
     ///
     /// # Safety
@@ -33,8 +114,10 @@ impl<T> Table<T> {
         if v == 0 {
             None
         } else {
-            Arc::increment_strong_count(v as *mut T);
-            Some(Arc::from_raw(v as _))
+            // the value may have been removed and dropped since it was loaded, but its allocation
+            // is kept until a grace period has elapsed (see `HandleMap::remove`), so it is safe to
+            // try to upgrade it, which fails once the value has been dropped
+            ManuallyDrop::new(Weak::from_raw(v as *const T)).upgrade()
         }
     }
 
@@ -68,6 +151,27 @@ impl<T> Table<T> {
         }
     }
 
+    /// The current generation of the value slot at `index`.
+    fn generation(&self, index: usize) -> Handle {
+        self.generations()[index].load(Ordering::Acquire)
+    }
+
+    /// Advance the generation of the value slot at `index` from `generation` to the next one,
+    /// wrapping around within `mask`.
+    ///
+    /// Returns false if the slot is no longer in `generation`, because someone else advanced it
+    /// first. Only one of several concurrent callers with the same generation succeeds.
+    fn advance_generation(&self, index: usize, generation: Handle, mask: Handle) -> bool {
+        self.generations()[index]
+            .compare_exchange(
+                generation,
+                generation.wrapping_add(1) & mask,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
     /// Get the `Table<T>` stored at `index`, or `None` if there is no table at that index.
     ///
     /// # Safety
@@ -81,13 +185,14 @@ impl<T> Table<T> {
         }
     }
 
-    /// Attempt to store a new next-level table at `index`, assuming that the slot is empty.
+    /// Attempt to store a new next-level table at `index`, assuming that the slot is empty. The new
+    /// table holds values if it is a `leaf`.
     /// If it is not empty, then the table that is stored there is returned instead.
     ///
     /// # Safety
     /// Assumes that if there is an existing non-zero value at this index, then it is a table.
-    unsafe fn new_next_level_table(&self, index: usize) -> NonNull<Table<T>> {
-        let new_table = NonNull::new_unchecked(Box::into_raw(Box::new(Table::default())));
+    unsafe fn new_next_level_table(&self, index: usize, leaf: bool) -> NonNull<Table<T>> {
+        let new_table = NonNull::new_unchecked(Box::into_raw(Box::new(Table::new(leaf))));
         match self.0[index].compare_exchange(
             0,
             new_table.as_ptr() as _,
@@ -103,6 +208,30 @@ impl<T> Table<T> {
         }
     }
 
+    /// Call `f` with the slot index, generation and value of every value in this table and its
+    /// children, where `prefix` is the part of the slot index that selects this table.
+    ///
+    /// # Safety
+    /// Assumes that `depth` is the number of levels of tables below and including this one.
+    unsafe fn for_each_value(
+        &self,
+        depth: usize,
+        prefix: Handle,
+        f: &mut impl FnMut(Handle, Handle, Arc<T>),
+    ) {
+        for index in 0..self.0.len() {
+            #[allow(clippy::cast_possible_truncation)]
+            let handle = (prefix << 8) | index as Handle;
+            if depth == 1 {
+                if let Some(value) = self.get_value(index) {
+                    f(handle, self.generation(index), value);
+                }
+            } else if let Some(table) = self.get_table(index) {
+                table.as_ref().for_each_value(depth - 1, handle, f);
+            }
+        }
+    }
+
     fn drop_children(&mut self, depth: usize) {
         // because we have an exclusive reference to the table, we know there are no other threads accessing the table.
         // Therefore, we can safely use `Relaxed` operations.
@@ -140,11 +269,24 @@ impl<T> Drop for Table<T> {
 }
 
 /// An internally synchronized concurrent map from handles to atomically ref-counted values of type `T`.
+///
+/// The low bits of each handle select a slot in the map, and the rest hold the generation of the
+/// slot, which advances whenever its value is removed. A handle kept after its value was removed
+/// then won't refer to a new value stored in the same slot, at least until the generation wraps.
+/// The more handles the map can hold, the fewer bits are left for the generation.
+///
+/// Lookups don't take any locks, so a value may be read just as it is removed. The allocation of a
+/// removed value is kept until an [RCU](crate::sync::rcu) grace period has elapsed, so that a
+/// reader can still safely find out whether the value has been dropped before it takes its own
+/// reference. Each lookup is an RCU read-side critical section. The value itself is dropped as soon
+/// as its last reference is.
 pub struct HandleMap<T> {
     allocator: HandleAllocator,
     table: Table<T>,
     handle_zeros_prefix_bit_length: u32,
     depth: usize,
+    /// The number of low bits of each handle that hold the index of its slot.
+    index_bits: u32,
 }
 
 impl<T> HandleMap<T> {
@@ -152,24 +294,49 @@ impl<T> HandleMap<T> {
     #[must_use]
     pub fn new(max_handle: Handle) -> Self {
         let extra_bits = max_handle.leading_zeros() & !7;
+        let depth = (32 - extra_bits).div_ceil(8) as usize;
         Self {
             allocator: HandleAllocator::new(max_handle),
-            table: Table::default(),
+            table: Table::new(depth == 1),
             // compute the length of the zero prefix for all handles so we can skip some tables.
             handle_zeros_prefix_bit_length: extra_bits,
-            depth: (32 - extra_bits).div_ceil(8) as usize,
+            depth,
+            index_bits: Handle::BITS - max_handle.leading_zeros(),
         }
     }
 
-    fn leaf_table_for_handle(&self, handle: Handle) -> Option<(&Table<T>, usize)> {
-        let mut handle = (handle << self.handle_zeros_prefix_bit_length).rotate_left(8);
+    /// Split `handle` into its slot index and generation.
+    fn split(&self, handle: Handle) -> (Handle, Handle) {
+        let generation = handle.checked_shr(self.index_bits).unwrap_or(0);
+        (handle & !self.join(0, Handle::MAX), generation)
+    }
+
+    /// Make a handle from a slot index and generation.
+    fn join(&self, index: Handle, generation: Handle) -> Handle {
+        index | generation.checked_shl(self.index_bits).unwrap_or(0)
+    }
+
+    /// The largest generation that fits in a handle.
+    fn generation_mask(&self) -> Handle {
+        Handle::MAX.checked_shr(self.index_bits).unwrap_or(0)
+    }
+
+    /// Find the table holding the slot for `handle`, returning it with the index of the slot in
+    /// the table, if the table exists and the slot is in the same generation as `handle`.
+    ///
+    /// The generation must be checked again after reading the slot, since it may be removed and
+    /// reused concurrently.
+    fn leaf_table_for_handle(&self, handle: Handle) -> Option<(&Table<T>, usize, Handle)> {
+        let (index, generation) = self.split(handle);
+        let mut handle = (index << self.handle_zeros_prefix_bit_length).rotate_left(8);
         let mut table = &self.table;
         for _ in 0..(self.depth - 1) {
             let index = handle & 0xff;
             table = unsafe { table.get_table(index as usize)?.as_ref() };
             handle = handle.rotate_left(8);
         }
-        Some((table, (handle & 0xff) as usize))
+        let leaf_index = (handle & 0xff) as usize;
+        (table.generation(leaf_index) == generation).then_some((table, leaf_index, generation))
     }
 
     /// Get a new handle that refers to `value`.
@@ -199,17 +366,20 @@ impl<T> HandleMap<T> {
         let handle = self.allocator.next_handle()?;
         let mut handle = (handle << self.handle_zeros_prefix_bit_length).rotate_left(8);
         let mut table = &self.table;
-        for _ in 0..(self.depth - 1) {
+        for level in 1..self.depth {
             let index = handle & 0xff;
             table = unsafe {
                 match table.get_table(index as usize) {
                     Some(t) => t.as_ref(),
-                    None => table.new_next_level_table(index as usize).as_ref(),
+                    None => table
+                        .new_next_level_table(index as usize, level + 1 == self.depth)
+                        .as_ref(),
                 }
             };
             handle = handle.rotate_left(8);
         }
         let index = (handle & 0xff) as usize;
+        let handle = self.join(handle, table.generation(index));
         let val = make_value(handle);
         unsafe {
             let res = table.put_value(index, val.clone());
@@ -219,20 +389,96 @@ impl<T> HandleMap<T> {
     }
 
     /// Returns a reference to the value associated with `handle`.
-    /// If the handle is unknown, then `None` is returned.
+    /// If the handle is unknown or its value has been removed, then `None` is returned.
     pub fn get(&self, handle: Handle) -> Option<Arc<T>> {
-        let (table, leaf_index) = self.leaf_table_for_handle(handle)?;
-        unsafe { table.get_value(leaf_index) }
+        let _read = rcu::read();
+        let (table, leaf_index, generation) = self.leaf_table_for_handle(handle)?;
+        let val = unsafe { table.get_value(leaf_index) };
+        // the value may have been removed and replaced after the generation was checked
+        val.filter(|_| table.generation(leaf_index) == generation)
+    }
+
+    /// Get a snapshot of every handle in the map along with its value, in order of handle.
+    ///
+    /// Values inserted or removed while the snapshot is taken may or may not be included.
+    #[must_use]
+    pub fn iter(&self) -> alloc::vec::IntoIter<(Handle, Arc<T>)> {
+        let mut entries = Vec::new();
+        let _read = rcu::read();
+        unsafe {
+            self.table.for_each_value(self.depth, 0, &mut |i, g, v| {
+                entries.push((self.join(i, g), v));
+            });
+        }
+        entries.into_iter()
+    }
+
+    /// Get a new typed handle that refers to `value`. See [`HandleMap::insert`].
+    ///
+    /// # Errors
+    /// If there are no handles left, then the value is returned in `Err`.
+    pub fn insert_typed(&self, value: Arc<T>) -> Result<TypedHandle<T>, Arc<T>> {
+        self.insert(value).map(TypedHandle::from_raw)
+    }
+
+    /// Returns a reference to the value associated with the typed `handle`. See [`HandleMap::get`].
+    pub fn get_typed(&self, handle: TypedHandle<T>) -> Option<Arc<T>> {
+        self.get(handle.raw)
+    }
+
+    /// Removes a value from the map by its typed `handle`. See [`HandleMap::remove`].
+    pub fn remove_typed(&self, handle: TypedHandle<T>) -> Option<Arc<T>> {
+        self.remove(handle.raw)
     }
 
     /// Removes a value from the map by its handle.
     /// Returns a reference to the value associated with `handle`.
-    /// If the handle is unknown, then `None` is returned.
+    /// If the handle is unknown or its value has been removed, then `None` is returned.
+    ///
+    /// If the slot of the handle can't be freed, which would mean the map is corrupted, the value is
+    /// still removed and returned, and the slot is leaked and logged.
     pub fn remove(&self, handle: Handle) -> Option<Arc<T>> {
-        let (table, leaf_index) = self.leaf_table_for_handle(handle)?;
-        let val = unsafe { table.take_value(leaf_index) };
-        self.allocator.free_handle(handle).ok()?;
-        val
+        let (table, leaf_index, generation) = self.leaf_table_for_handle(handle)?;
+        // claim the slot by moving it on to the next generation before taking the value, so that
+        // a concurrent remove of the same handle can't take a value stored in the slot later
+        if !table.advance_generation(leaf_index, generation, self.generation_mask()) {
+            return None;
+        }
+        let val = unsafe { table.take_value(leaf_index) }?;
+        if let Err(e) = self.allocator.free_handle(self.split(handle).0) {
+            // the value has still been removed, but its slot can never be used again
+            log::error!("leaking slot of handle {handle:#x}: {e}");
+        }
+        // until RCU is initialized only one core is running, so nothing else can be reading
+        if let Some(rcu) = rcu::global() {
+            let retired = Weak::into_raw(Arc::downgrade(&val)) as usize;
+            let free: unsafe fn(usize) = free_retired::<T>;
+            // SAFETY: `retired` came from `Weak::into_raw` for a `Weak<T>`
+            rcu.defer(move || unsafe { free(retired) });
+        }
+        Some(val)
+    }
+}
+
+/// Free the allocation of a value removed from a [`HandleMap`], once readers can no longer be
+/// loading it. The value itself has already been dropped, or will be by its last reference.
+///
+/// This is passed to [`Rcu::defer`](rcu::Rcu::defer) as a plain function pointer, which doesn't
+/// capture the lifetimes in `T`. Dropping a `Weak<T>` never touches the value, only the reference
+/// counts and the allocation, so it is fine to run after those lifetimes have ended.
+///
+/// # Safety
+/// `ptr` must have come from [`Weak::into_raw`] for a `Weak<T>`.
+unsafe fn free_retired<T>(ptr: usize) {
+    drop(Weak::from_raw(ptr as *const T));
+}
+
+impl<T> IntoIterator for &HandleMap<T> {
+    type Item = (Handle, Arc<T>);
+    type IntoIter = alloc::vec::IntoIter<(Handle, Arc<T>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 
@@ -254,6 +500,39 @@ mod tests {
     };
     use test_case::{test_case, test_matrix};
 
+    /// Test that a reader that loaded a value just before it was removed and dropped doesn't
+    /// revive it, as long as the allocation has been kept.
+    #[test]
+    fn removed_value_is_not_revived() {
+        let table = Table::<usize>::new(true);
+        unsafe {
+            table.put_value(0, Arc::new(5));
+        }
+        let val = unsafe { table.take_value(0) }.unwrap();
+        let raw = Arc::as_ptr(&val) as usize;
+        let retired = Arc::downgrade(&val);
+        drop(val);
+        table.0[0].store(raw, Ordering::Release);
+        assert!(unsafe { table.get_value(0) }.is_none());
+        table.0[0].store(0, Ordering::Release);
+        drop(retired);
+    }
+
+    /// Test that only the tables that hold values have generations.
+    #[test]
+    fn only_leaf_tables_have_generations() {
+        let map = HandleMap::new(0xffff);
+        let handle = map.insert(Arc::new(1)).unwrap();
+        assert_eq!(map.depth, 2);
+        assert!(map.table.1.is_none());
+        let leaf = unsafe { map.table.get_table(0).unwrap().as_ref() };
+        assert!(leaf.1.is_some());
+        assert_eq!(map.remove(handle).as_deref(), Some(&1));
+
+        let small = HandleMap::<usize>::new(0xff);
+        assert!(small.table.1.is_some());
+    }
+
     /// Test that inserting a value into the map works and that it can be retrieved.
     #[test]
     fn test_insert_and_get() {
@@ -508,6 +787,100 @@ mod tests {
         });
     }
 
+    #[test_case(10)]
+    #[test_case(1000)]
+    #[test_case(100_000)]
+    fn iter_lists_live_entries_in_order(max_handle: Handle) {
+        let map = HandleMap::new(max_handle);
+        let handles: Vec<_> = (0..max_handle.min(300))
+            .map(|i| map.insert(Arc::new(i)).unwrap())
+            .collect();
+        for h in handles.iter().step_by(3) {
+            map.remove(*h);
+        }
+        let expected: Vec<_> = handles
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 0)
+            .map(|(i, h)| (*h, i as u32))
+            .collect();
+        let entries: Vec<_> = map.iter().map(|(h, v)| (h, *v)).collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn iter_while_inserting_and_removing() {
+        let map = HandleMap::new(1024);
+        let stable: HashSet<_> = (0..64).map(|i| map.insert(Arc::new(i)).unwrap()).collect();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..1000 {
+                    let h = map.insert(Arc::new(i)).unwrap();
+                    map.remove(h);
+                }
+            });
+            for _ in 0..100 {
+                let seen: HashSet<_> = map.iter().map(|(h, _)| h).collect();
+                assert!(seen.is_superset(&stable));
+            }
+        });
+    }
+
+    #[test]
+    fn concurrent_removes_take_value_once() {
+        let map = HandleMap::new(16);
+        for round in 0..100 {
+            let h = map.insert(Arc::new(round)).unwrap();
+            let removed: usize = thread::scope(|s| {
+                let removers: Vec<_> = (0..4)
+                    .map(|_| s.spawn(|| usize::from(map.remove(h).is_some())))
+                    .collect();
+                removers.into_iter().map(|r| r.join().unwrap()).sum()
+            });
+            assert_eq!(removed, 1);
+        }
+    }
+
+    #[test]
+    fn typed_handles() {
+        let map = HandleMap::new(16);
+        let h: TypedHandle<u32> = map.insert_typed(Arc::new(7)).unwrap();
+        assert_eq!(map.get_typed(h).as_deref(), Some(&7));
+        assert_eq!(map.get(h.raw()).as_deref(), Some(&7));
+        assert_eq!(TypedHandle::from_raw(Handle::from(h)), h);
+        assert_eq!(std::format!("{h:?}"), std::format!("u32#{}", h.raw()));
+        assert_eq!(map.remove_typed(h).as_deref(), Some(&7));
+        assert!(map.get_typed(h).is_none());
+    }
+
+    #[test]
+    fn stale_handle_does_not_refer_to_reused_slot() {
+        let map = HandleMap::new(0xffff);
+        let old = map.insert(Arc::new(1)).unwrap();
+        assert_eq!(map.remove(old).as_deref(), Some(&1));
+        let new = map.insert(Arc::new(2)).unwrap();
+        // the slot is reused, but with a new generation in the upper bits
+        assert_eq!(new & 0xffff, old & 0xffff);
+        assert_ne!(new, old);
+        assert!(map.get(old).is_none());
+        assert!(map.remove(old).is_none());
+        assert_eq!(map.get(new).as_deref(), Some(&2));
+        assert_eq!(map.iter().map(|(h, _)| h).collect::<Vec<_>>(), [new]);
+    }
+
+    #[test]
+    fn generation_wraps_within_handle() {
+        // 28 bits of slot index leave 4 bits of generation
+        let map = HandleMap::new(0x0fff_ffff);
+        let first = map.insert(Arc::new(0)).unwrap();
+        let mut h = first;
+        for _ in 0..16 {
+            map.remove(h).unwrap();
+            h = map.insert(Arc::new(0)).unwrap();
+        }
+        assert_eq!(h, first);
+    }
+
     /// Test that handles are unique across different inserts.
     #[test]
     fn test_handle_uniqueness() {
@@ -4,7 +4,7 @@ mod handle_allocator;
 pub use handle_allocator::HandleAllocator;
 
 mod handle_map;
-pub use handle_map::HandleMap;
+pub use handle_map::{Handle, HandleMap, TypedHandle};
 
 mod arc_swap;
 pub use arc_swap::ArcSwap;
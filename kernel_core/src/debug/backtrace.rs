@@ -0,0 +1,165 @@
+//! Walking the chain of frame records on the stack to produce a backtrace.
+//!
+//! When frame pointers are enabled, the prologue of every function that calls another pushes a
+//! frame record onto the stack: the caller's frame pointer (`x29`) followed by the return address
+//! in the link register (`x30`), and then points `x29` at the record. Following the chain of
+//! records from the current frame pointer gives the return address of every active call.
+use core::fmt;
+
+/// The maximum number of frames walked, in case the chain of frame records is corrupt.
+pub const MAX_FRAMES: usize = 64;
+
+/// Mechanism for reading frame records from memory.
+pub trait FrameRecordReader {
+    /// Read the frame record at `fp`, returning the saved frame pointer and return address.
+    ///
+    /// Returns `None` if `fp` does not point to memory that can be safely read.
+    fn read_frame_record(&self, fp: usize) -> Option<(usize, usize)>;
+}
+
+/// Resolves code addresses to the name of the function that contains them.
+pub trait Symbolizer {
+    /// Find the function containing `address`, returning its name and the offset of `address`
+    /// from the start of the function.
+    fn symbolize(&self, address: usize) -> Option<(&str, usize)>;
+}
+
+/// An iterator over the return addresses on the stack, starting with the innermost call.
+pub struct StackWalker<'r, R> {
+    reader: &'r R,
+    fp: usize,
+    remaining: usize,
+}
+
+impl<'r, R: FrameRecordReader> StackWalker<'r, R> {
+    /// Walk the stack starting from the frame record at `fp`.
+    pub fn new(reader: &'r R, fp: usize) -> Self {
+        Self {
+            reader,
+            fp,
+            remaining: MAX_FRAMES,
+        }
+    }
+}
+
+impl<R: FrameRecordReader> Iterator for StackWalker<'_, R> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 || self.fp == 0 || !self.fp.is_multiple_of(16) {
+            return None;
+        }
+        self.remaining -= 1;
+        let (next_fp, return_address) = self.reader.read_frame_record(self.fp)?;
+        // callers' frames are always further up the stack, otherwise the chain is corrupt
+        self.fp = if next_fp > self.fp { next_fp } else { 0 };
+        (return_address != 0).then_some(return_address)
+    }
+}
+
+/// A backtrace of the stack starting at a frame pointer, which is walked each time it is displayed.
+pub struct Backtrace<'s, R> {
+    reader: R,
+    fp: usize,
+    symbolizer: Option<&'s dyn Symbolizer>,
+}
+
+impl<'s, R: FrameRecordReader> Backtrace<'s, R> {
+    /// Create a backtrace of the stack starting at the frame record at `fp`.
+    pub fn new(reader: R, fp: usize) -> Self {
+        Self {
+            reader,
+            fp,
+            symbolizer: None,
+        }
+    }
+
+    /// Use `symbolizer` to display the function that each return address is in.
+    #[must_use]
+    pub fn with_symbolizer(self, symbolizer: &'s dyn Symbolizer) -> Self {
+        Self {
+            symbolizer: Some(symbolizer),
+            ..self
+        }
+    }
+
+    /// Iterate over the return addresses in the backtrace.
+    pub fn frames(&self) -> StackWalker<'_, R> {
+        StackWalker::new(&self.reader, self.fp)
+    }
+}
+
+impl<R: FrameRecordReader> fmt::Display for Backtrace<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        for (i, address) in self.frames().enumerate() {
+            write!(f, "{i:>4}: {address:#018x}")?;
+            if let Some((name, offset)) = self.symbolizer.and_then(|s| s.symbolize(address)) {
+                write!(f, " {name}+{offset:#x}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, string::ToString, vec::Vec};
+
+    use super::*;
+
+    /// A stack made up of frame records, by address.
+    struct FakeStack(HashMap<usize, (usize, usize)>);
+
+    impl FrameRecordReader for FakeStack {
+        fn read_frame_record(&self, fp: usize) -> Option<(usize, usize)> {
+            self.0.get(&fp).copied()
+        }
+    }
+
+    struct FakeSymbols;
+
+    impl Symbolizer for FakeSymbols {
+        fn symbolize(&self, address: usize) -> Option<(&str, usize)> {
+            (address >= 0x1000).then(|| ("kmain", address - 0x1000))
+        }
+    }
+
+    #[test]
+    fn walk_frames() {
+        let stack = FakeStack(HashMap::from([
+            (0x100, (0x120, 0x1004)),
+            (0x120, (0x200, 0x0f00)),
+            (0x200, (0, 0x1010)),
+        ]));
+        let frames: Vec<_> = StackWalker::new(&stack, 0x100).collect();
+        assert_eq!(frames, [0x1004, 0x0f00, 0x1010]);
+    }
+
+    #[test]
+    fn stop_at_corrupt_chain() {
+        // the frame pointer goes back down the stack
+        let stack = FakeStack(HashMap::from([
+            (0x100, (0x200, 0x1004)),
+            (0x200, (0x100, 0x1008)),
+        ]));
+        assert_eq!(StackWalker::new(&stack, 0x100).count(), 2);
+        // misaligned or unreadable frame pointers end the walk
+        assert_eq!(StackWalker::new(&stack, 0x104).count(), 0);
+        assert_eq!(StackWalker::new(&stack, 0x300).count(), 0);
+    }
+
+    #[test]
+    fn display_with_symbols() {
+        let stack = FakeStack(HashMap::from([
+            (0x100, (0x120, 0x1004)),
+            (0x120, (0, 0x20)),
+        ]));
+        let bt = Backtrace::new(stack, 0x100).with_symbolizer(&FakeSymbols);
+        assert_eq!(
+            bt.to_string(),
+            "backtrace:\n   0: 0x0000000000001004 kmain+0x4\n   1: 0x0000000000000020\n"
+        );
+    }
+}
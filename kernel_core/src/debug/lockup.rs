@@ -0,0 +1,199 @@
+//! Detection of soft lockups, where a core stops making scheduler progress.
+//!
+//! Every core counts its heartbeats: the times it switches to another thread, and the exceptions
+//! taken from user space, which can always be preempted. A checker, run periodically by a kernel
+//! thread, compares the counts with the ones it saw last time. A busy core whose count has not
+//! changed for longer than a threshold is stuck in the kernel: its timer has stopped, it has been
+//! running with interrupts masked, or it keeps taking timer ticks but never leaves the thread it is
+//! running. The checker reports each lockup once, so the kernel can dump the state of the thread
+//! the core is running.
+//!
+//! Idle cores stop their tick while they wait for an interrupt, so they are exempt until the next
+//! interrupt wakes them.
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::vec::Vec;
+use log::warn;
+
+use crate::{
+    platform::cpu::{CpuIdReader, Id as CpuId},
+    sync::Mutex,
+    time::Ticks,
+};
+
+/// The progress of a single core, as seen by the checker.
+#[derive(Clone, Copy)]
+struct Progress {
+    /// The heartbeat count at the previous check.
+    heartbeats: u64,
+    /// The time the heartbeat count was last seen to change.
+    since: Ticks,
+    /// True if the core has already been reported as locked up.
+    reported: bool,
+}
+
+/// Detects cores that have stopped making scheduler progress.
+pub struct LockupDetector {
+    /// The id of each core, in the order of core indices.
+    cores: Vec<CpuId>,
+    /// The number of heartbeats of each core, by core index.
+    heartbeats: Vec<AtomicU64>,
+    /// Whether each core is idle and waiting for an interrupt, by core index.
+    idle: Vec<AtomicBool>,
+    /// The progress of each core at the previous check, by core index.
+    progress: Mutex<Vec<Progress>>,
+    /// How long a core can go without a heartbeat before it is locked up.
+    threshold: Ticks,
+    current_cpu: fn() -> CpuId,
+}
+
+impl LockupDetector {
+    /// Create a new detector for the cores with ids `cores`, as if every core had a heartbeat at
+    /// `now`. A core is locked up once it goes `threshold` ticks without a heartbeat. The current
+    /// core is read with `C`.
+    #[must_use]
+    pub fn new<C: CpuIdReader>(cores: &[CpuId], threshold: Ticks, now: Ticks) -> Self {
+        Self {
+            cores: cores.to_vec(),
+            heartbeats: cores.iter().map(|_| AtomicU64::new(0)).collect(),
+            idle: cores.iter().map(|_| AtomicBool::new(false)).collect(),
+            progress: Mutex::new(
+                cores
+                    .iter()
+                    .map(|_| Progress {
+                        heartbeats: 0,
+                        since: now,
+                        reported: false,
+                    })
+                    .collect(),
+            ),
+            threshold,
+            current_cpu: C::current_cpu,
+        }
+    }
+
+    fn index(&self, core: CpuId) -> Option<usize> {
+        self.cores.iter().position(|c| *c == core)
+    }
+
+    /// The id of the core with index `index`.
+    #[must_use]
+    pub fn core(&self, index: usize) -> Option<CpuId> {
+        self.cores.get(index).copied()
+    }
+
+    /// Record that the current core made progress: it switched to another thread, or took an
+    /// exception from user space.
+    pub fn heartbeat(&self) {
+        if let Some(i) = self.index((self.current_cpu)()) {
+            self.heartbeats[i].fetch_add(1, Ordering::Release);
+            self.idle[i].store(false, Ordering::Release);
+        }
+    }
+
+    /// Record that the current core was woken by an interrupt, so it is expected to make progress
+    /// again.
+    pub fn wake(&self) {
+        if let Some(i) = self.index((self.current_cpu)()) {
+            self.idle[i].store(false, Ordering::Release);
+        }
+    }
+
+    /// Record that core `core` is idle and waiting for an interrupt, or offline, so it does not
+    /// need to make progress until it is woken.
+    pub fn idle(&self, core: CpuId) {
+        if let Some(i) = self.index(core) {
+            self.idle[i].store(true, Ordering::Release);
+        }
+    }
+
+    /// Check every core for progress at time `now`, returning the indices of the cores that have
+    /// just been found to be locked up. Each lockup is only reported once, until the core makes
+    /// progress again.
+    pub fn check(&self, now: Ticks) -> Vec<usize> {
+        let mut progress = self.progress.lock();
+        let mut locked = Vec::new();
+        for (i, p) in progress.iter_mut().enumerate() {
+            let heartbeats = self.heartbeats[i].load(Ordering::Acquire);
+            if heartbeats != p.heartbeats || self.idle[i].load(Ordering::Acquire) {
+                *p = Progress {
+                    heartbeats,
+                    since: now,
+                    reported: false,
+                };
+                continue;
+            }
+            let stalled = now.saturating_sub(p.since);
+            if stalled >= self.threshold && !p.reported {
+                warn!(
+                    "soft lockup: core {} has made no scheduler progress for {stalled} ticks",
+                    self.cores[i]
+                );
+                p.reported = true;
+                locked.push(i);
+            }
+        }
+        locked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::platform::cpu::tests::TestCpu;
+
+    fn beat_on(d: &LockupDetector, core: CpuId) {
+        TestCpu::set(core);
+        d.heartbeat();
+    }
+
+    #[test]
+    fn stalled_core_reported_once() {
+        let d = LockupDetector::new::<TestCpu>(&[0, 3], 100, 0);
+        assert!(d.check(50).is_empty());
+
+        beat_on(&d, 0);
+        beat_on(&d, 3);
+        assert!(d.check(120).is_empty());
+
+        // core 3 stops making progress
+        beat_on(&d, 0);
+        assert!(d.check(180).is_empty());
+        beat_on(&d, 0);
+        assert_eq!(d.check(220), [1]);
+        beat_on(&d, 0);
+        assert!(d.check(300).is_empty());
+
+        // once it recovers it can be reported again
+        beat_on(&d, 3);
+        beat_on(&d, 0);
+        assert!(d.check(310).is_empty());
+        beat_on(&d, 0);
+        assert_eq!(d.check(410), [1]);
+    }
+
+    #[test]
+    fn idle_cores_are_exempt() {
+        let d = LockupDetector::new::<TestCpu>(&[0, 1], 100, 0);
+        d.idle(1);
+        beat_on(&d, 0);
+        assert!(d.check(150).is_empty());
+        beat_on(&d, 0);
+        assert!(d.check(300).is_empty());
+
+        // once woken the core must make progress again
+        TestCpu::set(1);
+        d.wake();
+        beat_on(&d, 0);
+        assert!(d.check(350).is_empty());
+        beat_on(&d, 0);
+        assert_eq!(d.check(450), [1]);
+
+        // unknown cores are ignored
+        d.idle(7);
+        beat_on(&d, 7);
+        assert_eq!(d.core(1), Some(1));
+        assert_eq!(d.core(2), None);
+    }
+}
@@ -0,0 +1,6 @@
+//! Facilities for diagnosing kernel crashes.
+
+pub mod backtrace;
+pub mod lockup;
+pub mod monitor;
+pub mod symbols;
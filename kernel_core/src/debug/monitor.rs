@@ -0,0 +1,123 @@
+//! Line editing and command parsing for the kernel's debug monitor, which reads commands from the
+//! kernel console and reports on the state of the kernel.
+
+/// A command that the debug monitor understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// List the commands.
+    Help,
+    /// Report the physical memory map and the usage of physical memory and the kernel heap.
+    Memory,
+    /// Report the interrupts that have been handled.
+    Interrupts,
+    /// Report how much of each core's kernel stack has been used.
+    Stacks,
+    /// Report how long each core has spent in each idle state.
+    Idle,
+}
+
+/// The name of each command with a short description, in the order they are listed by
+/// [`Command::Help`].
+pub const COMMANDS: &[(&str, Command, &str)] = &[
+    ("help", Command::Help, "list the commands"),
+    ("mem", Command::Memory, "memory map, page and heap usage"),
+    ("irq", Command::Interrupts, "interrupt counts"),
+    ("stacks", Command::Stacks, "kernel stack usage of each core"),
+    ("idle", Command::Idle, "time each core has spent idle"),
+];
+
+impl Command {
+    /// Parse a line of input, ignoring surrounding whitespace. Returns `None` if the line is not a
+    /// known command.
+    #[must_use]
+    pub fn parse(line: &[u8]) -> Option<Self> {
+        let line = line.trim_ascii();
+        COMMANDS
+            .iter()
+            .find(|(name, _, _)| name.as_bytes() == line)
+            .map(|(_, command, _)| *command)
+    }
+}
+
+/// Collects bytes of console input into lines.
+///
+/// Backspace deletes the last byte. A line longer than the buffer is discarded once it ends.
+pub struct LineBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LineBuffer<N> {
+    /// Create an empty line buffer.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Accept a byte of input, returning the line if the byte ended it.
+    pub fn accept(&mut self, byte: u8) -> Option<&[u8]> {
+        match byte {
+            b'\r' | b'\n' => {
+                let len = core::mem::take(&mut self.len);
+                if core::mem::take(&mut self.overflowed) {
+                    None
+                } else {
+                    Some(&self.buf[..len])
+                }
+            }
+            // backspace and delete
+            0x08 | 0x7f => {
+                self.len = self.len.saturating_sub(1);
+                None
+            }
+            _ if self.len == N => {
+                self.overflowed = true;
+                None
+            }
+            _ => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse(b"mem"), Some(Command::Memory));
+        assert_eq!(Command::parse(b"  irq \t"), Some(Command::Interrupts));
+        assert_eq!(Command::parse(b"memory"), None);
+        assert_eq!(Command::parse(b""), None);
+    }
+
+    #[test]
+    fn edit_lines() {
+        let mut lines = LineBuffer::<4>::new();
+        let mut feed = |input: &[u8]| {
+            input
+                .iter()
+                .filter_map(|b| lines.accept(*b).map(<[u8]>::to_vec))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(feed(b"hx\x7fep\r\n"), [b"hep".to_vec(), Vec::new()]);
+        // an overlong line is dropped whole, and the next line starts fresh
+        assert_eq!(feed(b"stacks\rmem\n"), [b"mem".to_vec()]);
+    }
+}
@@ -0,0 +1,203 @@
+//! Kernel symbol tables, for mapping code addresses back to function names.
+//!
+//! The symbol table is generated from the linked kernel image and embedded in it after the fact,
+//! so it uses a compact binary format that can be read in place. All integers are little endian.
+//!
+//! | Offset | Size       | Field                                               |
+//! |--------|------------|-----------------------------------------------------|
+//! | 0      | 4          | Magic, [`MAGIC`]                                    |
+//! | 4      | 4          | Number of symbols                                   |
+//! | 8      | 4          | Offset of the string table from the start           |
+//! | 12     | 4          | Reserved, zero                                      |
+//! | 16     | 16 × count | Symbols, sorted by address                          |
+//! | *      | *          | String table of null terminated symbol names        |
+//!
+//! Each symbol is 16 bytes: the address (8 bytes), the size in bytes (4 bytes, or zero if
+//! unknown), and the offset of its name in the string table (4 bytes).
+use byteorder::{ByteOrder, LittleEndian};
+use snafu::{ensure, Snafu};
+
+use super::backtrace::Symbolizer;
+
+/// The magic number at the start of a symbol table.
+pub const MAGIC: &[u8; 4] = b"KSYM";
+
+/// The size of the symbol table header in bytes.
+pub const HEADER_LEN: usize = 16;
+
+/// The size of a symbol entry in bytes.
+pub const ENTRY_LEN: usize = 16;
+
+/// Errors that can occur when parsing a symbol table.
+#[derive(Debug, Snafu)]
+pub enum ParseError {
+    /// The blob does not start with [`MAGIC`], so there is no symbol table.
+    #[snafu(display("symbol table magic not found"))]
+    BadMagic,
+    /// The blob is too short to hold the symbols and strings that the header describes.
+    #[snafu(display("symbol table is truncated"))]
+    Truncated,
+}
+
+/// A symbol table that can be searched by address.
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Parse the symbol table in `blob`.
+    ///
+    /// # Errors
+    /// Returns an error if the blob does not contain a valid symbol table header, or is too short
+    /// for the table it describes.
+    pub fn parse(blob: &'a [u8]) -> Result<Self, ParseError> {
+        ensure!(
+            blob.len() >= HEADER_LEN && &blob[0..4] == MAGIC,
+            BadMagicSnafu
+        );
+        let count = LittleEndian::read_u32(&blob[4..8]) as usize;
+        let strings_offset = LittleEndian::read_u32(&blob[8..12]) as usize;
+        let entries_end = count
+            .checked_mul(ENTRY_LEN)
+            .and_then(|n| n.checked_add(HEADER_LEN))
+            .filter(|end| *end <= strings_offset && strings_offset <= blob.len());
+        let Some(entries_end) = entries_end else {
+            return TruncatedSnafu.fail();
+        };
+        Ok(Self {
+            entries: &blob[HEADER_LEN..entries_end],
+            strings: &blob[strings_offset..],
+        })
+    }
+
+    /// The number of symbols in the table.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_LEN
+    }
+
+    /// True if there are no symbols in the table.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The address, size and name offset of symbol `i`.
+    fn entry(&self, i: usize) -> (usize, usize, usize) {
+        let e = &self.entries[i * ENTRY_LEN..(i + 1) * ENTRY_LEN];
+        #[allow(clippy::cast_possible_truncation)]
+        let address = LittleEndian::read_u64(&e[0..8]) as usize;
+        (
+            address,
+            LittleEndian::read_u32(&e[8..12]) as usize,
+            LittleEndian::read_u32(&e[12..16]) as usize,
+        )
+    }
+
+    /// The name at `offset` in the string table.
+    fn name(&self, offset: usize) -> Option<&'a str> {
+        let s = self.strings.get(offset..)?;
+        let end = s.iter().position(|b| *b == 0).unwrap_or(s.len());
+        core::str::from_utf8(&s[..end]).ok()
+    }
+
+    /// Find the symbol containing `address`, returning its name and the offset of `address` from
+    /// the start of the symbol.
+    ///
+    /// Symbols with an unknown size are assumed to extend up to the next symbol.
+    #[must_use]
+    pub fn lookup(&self, address: usize) -> Option<(&'a str, usize)> {
+        // binary search for the last symbol starting at or before the address
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.entry(mid).0 <= address {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let (start, size, name_offset) = self.entry(lo.checked_sub(1)?);
+        let offset = address - start;
+        if size != 0 && offset >= size {
+            return None;
+        }
+        Some((self.name(name_offset)?, offset))
+    }
+}
+
+impl Symbolizer for SymbolTable<'_> {
+    fn symbolize(&self, address: usize) -> Option<(&str, usize)> {
+        self.lookup(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Encode a symbol table containing `symbols`, which must be sorted by address.
+    fn encode(symbols: &[(u64, u32, &str)]) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut entries = Vec::new();
+        for (address, size, name) in symbols {
+            entries.extend_from_slice(&address.to_le_bytes());
+            entries.extend_from_slice(&size.to_le_bytes());
+            entries.extend_from_slice(&u32::try_from(strings.len()).unwrap().to_le_bytes());
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+        let mut blob = Vec::from(*MAGIC);
+        blob.extend_from_slice(&u32::try_from(symbols.len()).unwrap().to_le_bytes());
+        blob.extend_from_slice(
+            &u32::try_from(HEADER_LEN + entries.len())
+                .unwrap()
+                .to_le_bytes(),
+        );
+        blob.extend_from_slice(&[0; 4]);
+        blob.extend(entries);
+        blob.extend(strings);
+        blob
+    }
+
+    #[test]
+    fn lookup() {
+        let blob = encode(&[
+            (0x1000, 0x40, "kmain"),
+            (0x1040, 0, "start_thread"),
+            (0x2000, 0x10, "halt"),
+        ]);
+        let table = SymbolTable::parse(&blob).unwrap();
+        assert_eq!(table.len(), 3);
+
+        assert_eq!(table.lookup(0xfff), None);
+        assert_eq!(table.lookup(0x1000), Some(("kmain", 0)));
+        assert_eq!(table.lookup(0x103c), Some(("kmain", 0x3c)));
+        // unknown size extends to the next symbol
+        assert_eq!(table.lookup(0x1fff), Some(("start_thread", 0xfbf)));
+        assert_eq!(table.lookup(0x200f), Some(("halt", 0xf)));
+        assert_eq!(table.lookup(0x2010), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(
+            SymbolTable::parse(&[0; 64]),
+            Err(ParseError::BadMagic)
+        ));
+        let mut blob = encode(&[(0x1000, 0x40, "kmain")]);
+        blob[4] = 100;
+        assert!(matches!(
+            SymbolTable::parse(&blob),
+            Err(ParseError::Truncated)
+        ));
+        let empty = encode(&[]);
+        let table = SymbolTable::parse(&empty).unwrap();
+        assert!(table.is_empty());
+        assert_eq!(table.lookup(0x1000), None);
+    }
+}
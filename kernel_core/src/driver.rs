@@ -0,0 +1,675 @@
+//! A registry of the drivers built into the kernel, and a walker that binds them to the devices
+//! described by the device tree.
+//!
+//! Each [`Driver`] lists the `compatible` strings (see section 2.3.1 of the spec) of the devices it
+//! supports. A node lists its `compatible` strings from most to least specific, so the node is
+//! bound to the driver that matches the earliest one. Nodes whose `status` is not `"okay"` are
+//! skipped.
+//!
+//! A device is only probed once the devices it depends on have been bound: its interrupt
+//! controllers, and the providers named by its `clocks`, `resets` and `power-domains` properties.
+//! Dependencies that have no driver are assumed to be ready, since the kernel doesn't drive them.
+//! A driver's probe function can also decline a device it is compatible with, or defer it until the
+//! other devices have been probed. Devices that are never bound, because of a deferral, a failed
+//! dependency or a cycle of dependencies, are reported along with the dependency they were waiting
+//! for.
+//!
+//! The IOMMU is not a dependency, since devices work without it. Instead the IOMMU's driver asks
+//! the registry for the streams of the devices behind it that the kernel drives (see
+//! [`Registry::iommu_streams`]).
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::ffi::CStr;
+
+use byteorder::{BigEndian, ByteOrder as _};
+use snafu::Snafu;
+
+use crate::platform::device_tree::{
+    fdt::Token, interrupts::InterruptTree, iter::NodePropertyIter, DeviceTree, StringList, Value,
+};
+
+/// The properties that refer to other devices that a device depends on, as lists of phandles
+/// followed by arguments, with the property in the referenced node that gives the number of
+/// argument cells.
+const DEPENDENCY_PROPERTIES: [(&[u8], &[u8]); 3] = [
+    (b"clocks", b"#clock-cells"),
+    (b"resets", b"#reset-cells"),
+    (b"power-domains", b"#power-domain-cells"),
+];
+
+/// Reasons a driver did not bind to a device.
+#[derive(Debug, Snafu, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The driver depends on a device that has not been bound yet, so it should be probed again
+    /// once the other devices have been.
+    #[snafu(display("Deferred by the driver until after the other devices"))]
+    Deferred,
+    /// A device that this device depends on was not bound.
+    #[snafu(display("Waiting for a device that was not bound"))]
+    DependencyNotBound,
+    /// The device depends on itself through the devices it depends on.
+    #[snafu(display("Dependency cycle"))]
+    DependencyCycle,
+    /// The driver does not handle this device, even though it is compatible with it.
+    #[snafu(display("Declined by the driver"))]
+    Declined,
+    /// The device could not be set up. The driver logs the details.
+    #[snafu(display("Failed to set up device: {reason}"))]
+    Failed {
+        /// What went wrong.
+        reason: &'static str,
+    },
+}
+
+/// A device tree node that a driver is being probed with.
+pub struct Device<'a> {
+    /// The device tree the node is in.
+    pub device_tree: &'a DeviceTree<'a>,
+    /// The path to the node.
+    pub path: &'a [u8],
+    /// The entry of the driver's match table that the node matched.
+    pub compatible: &'static [u8],
+}
+
+impl<'a> Device<'a> {
+    /// The properties of the device's node.
+    #[must_use]
+    pub fn properties(&self) -> Option<NodePropertyIter<'a>> {
+        self.device_tree.iter_node_properties(self.path)
+    }
+}
+
+/// A driver built into the kernel.
+pub struct Driver {
+    /// The name of the driver, for log messages.
+    pub name: &'static str,
+    /// The `compatible` strings of the devices that the driver supports.
+    pub compatible: &'static [&'static [u8]],
+    /// Set up the driver for a device.
+    ///
+    /// Drivers keep the devices they set up themselves, so this only reports whether it worked.
+    pub probe: fn(&Device) -> Result<(), ProbeError>,
+}
+
+/// The outcome of probing a device with the driver it matched.
+#[derive(Debug)]
+pub struct Probed {
+    /// The path to the device's node.
+    pub path: Vec<u8>,
+    /// The name of the driver.
+    pub driver: &'static str,
+    /// The result of the probe. Devices that were declined are not reported.
+    pub result: Result<(), ProbeError>,
+    /// The path to the dependency the device was waiting for, if it was never probed because of
+    /// it.
+    pub waiting_for: Option<Vec<u8>>,
+}
+
+/// A device that matched a driver, found while walking the tree.
+struct Candidate<'dt> {
+    path: Vec<u8>,
+    driver: &'static Driver,
+    compatible: &'static [u8],
+    phandle: Option<u32>,
+    /// The value of each of the [`DEPENDENCY_PROPERTIES`], if the node has it.
+    references: [Option<&'dt [u8]>; DEPENDENCY_PROPERTIES.len()],
+    /// The value of the `iommus` property, if the node has it.
+    iommus: Option<&'dt [u8]>,
+}
+
+/// The progress of a device through probing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Bound,
+    Declined,
+    Failed,
+}
+
+impl State {
+    /// True if the devices that depend on this one can be probed.
+    fn is_ready(self) -> bool {
+        matches!(self, State::Bound | State::Declined)
+    }
+}
+
+/// The set of drivers that devices are matched against.
+pub struct Registry {
+    drivers: &'static [Driver],
+}
+
+impl Registry {
+    /// Create a registry of `drivers`. If more than one driver supports the same `compatible`
+    /// string, the first one is used.
+    #[must_use]
+    pub const fn new(drivers: &'static [Driver]) -> Self {
+        Self { drivers }
+    }
+
+    /// The drivers in the registry.
+    #[must_use]
+    pub fn drivers(&self) -> &'static [Driver] {
+        self.drivers
+    }
+
+    /// Find the driver for a node with the `compatible` strings `compatible`, and the entry of its
+    /// match table that was matched.
+    #[must_use]
+    pub fn find(&self, compatible: &StringList) -> Option<(&'static Driver, &'static [u8])> {
+        compatible.iter().find_map(|model| {
+            self.drivers.iter().find_map(|driver| {
+                driver
+                    .compatible
+                    .iter()
+                    .find(|c| **c == model.to_bytes())
+                    .map(|c| (driver, *c))
+            })
+        })
+    }
+
+    /// Probe the node at `path` with its driver, returning the name of the driver and the result,
+    /// or `None` if the node is disabled or has no driver. This does not need the kernel heap.
+    ///
+    /// A deferred probe is not retried.
+    #[must_use]
+    pub fn probe_node(
+        &self,
+        device_tree: &DeviceTree,
+        path: &[u8],
+    ) -> Option<(&'static str, Result<(), ProbeError>)> {
+        let mut compatible = None;
+        let mut enabled = true;
+        for (name, value) in device_tree.iter_node_properties(path)? {
+            match name {
+                b"compatible" => compatible = value.into_strings(),
+                b"status" => {
+                    enabled = value.into_string().is_some_and(|s| is_okay(s.to_bytes()));
+                }
+                _ => {}
+            }
+        }
+        if !enabled {
+            return None;
+        }
+        let (driver, compatible) = self.find(&compatible?)?;
+        let device = Device {
+            device_tree,
+            path,
+            compatible,
+        };
+        Some((driver.name, (driver.probe)(&device)))
+    }
+
+    /// Probe every enabled node in the device tree that has a driver, in tree order, once the
+    /// devices it depends on are bound.
+    ///
+    /// Devices deferred by their driver are probed again after the others, until a pass over the
+    /// remaining devices binds nothing new. The devices that are left are reported with the reason
+    /// they were never bound.
+    pub fn probe_all(&self, device_tree: &DeviceTree) -> Vec<Probed> {
+        let candidates = self.candidates(device_tree);
+        let dependencies = dependencies(device_tree, &candidates);
+        let mut states = vec![State::Pending; candidates.len()];
+        let mut results = Vec::new();
+        loop {
+            let mut progress = false;
+            for (i, candidate) in candidates.iter().enumerate() {
+                if states[i] != State::Pending
+                    || !dependencies[i].iter().all(|d| states[*d].is_ready())
+                {
+                    continue;
+                }
+                let device = Device {
+                    device_tree,
+                    path: &candidate.path,
+                    compatible: candidate.compatible,
+                };
+                let result = (candidate.driver.probe)(&device);
+                states[i] = match result {
+                    Err(ProbeError::Deferred) => continue,
+                    Err(ProbeError::Declined) => State::Declined,
+                    Ok(()) => State::Bound,
+                    Err(_) => State::Failed,
+                };
+                progress = true;
+                if states[i] != State::Declined {
+                    results.push(Probed {
+                        path: candidate.path.clone(),
+                        driver: candidate.driver.name,
+                        result,
+                        waiting_for: None,
+                    });
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        for (i, candidate) in candidates.iter().enumerate() {
+            if states[i] != State::Pending {
+                continue;
+            }
+            let (result, waiting_for) = match blocking_dependency(i, &dependencies, &states) {
+                None => (ProbeError::Deferred, None),
+                Some((d, true)) => (ProbeError::DependencyCycle, Some(d)),
+                Some((d, false)) => (ProbeError::DependencyNotBound, Some(d)),
+            };
+            results.push(Probed {
+                path: candidate.path.clone(),
+                driver: candidate.driver.name,
+                result: Err(result),
+                waiting_for: waiting_for.map(|d| candidates[d].path.clone()),
+            });
+        }
+        results
+    }
+
+    /// The stream IDs that the devices with a driver use for DMA through the IOMMU with the
+    /// phandle `iommu`, from the first argument cell of the entries of their `iommus` properties
+    /// that refer to it.
+    #[must_use]
+    pub fn iommu_streams(&self, device_tree: &DeviceTree, iommu: u32) -> Vec<u32> {
+        self.candidates(device_tree)
+            .iter()
+            .filter_map(|c| c.iommus)
+            .flat_map(|data| phandle_arguments(device_tree, data, b"#iommu-cells"))
+            .filter(|(phandle, arguments)| *phandle == iommu && arguments.len() >= 4)
+            .map(|(_, arguments)| BigEndian::read_u32(arguments))
+            .collect()
+    }
+
+    /// Find every enabled node that has a driver, in tree order.
+    fn candidates<'dt>(&self, device_tree: &'dt DeviceTree) -> Vec<Candidate<'dt>> {
+        /// A node on the current path, whose properties are read until its first child starts.
+        struct Node<'dt> {
+            parent_len: usize,
+            compatible: Option<StringList<'dt>>,
+            enabled: bool,
+            phandle: Option<u32>,
+            references: [Option<&'dt [u8]>; DEPENDENCY_PROPERTIES.len()],
+            iommus: Option<&'dt [u8]>,
+            done: bool,
+        }
+
+        let mut candidates = Vec::new();
+        let mut stack: Vec<Node> = Vec::new();
+        let mut path = Vec::new();
+        let mut finish = |node: &mut Node<'dt>, path: &[u8]| {
+            if node.done {
+                return;
+            }
+            node.done = true;
+            if let (true, Some(compatible)) = (node.enabled, &node.compatible) {
+                if let Some((driver, matched)) = self.find(compatible) {
+                    candidates.push(Candidate {
+                        path: path.to_vec(),
+                        driver,
+                        compatible: matched,
+                        phandle: node.phandle,
+                        references: node.references,
+                        iommus: node.iommus,
+                    });
+                }
+            }
+        };
+        for token in device_tree.iter_structure() {
+            match token {
+                Token::StartNode(name) => {
+                    if let Some(parent) = stack.last_mut() {
+                        finish(parent, &path);
+                    }
+                    let parent_len = path.len();
+                    if path.last() != Some(&b'/') {
+                        path.push(b'/');
+                    }
+                    path.extend_from_slice(name);
+                    stack.push(Node {
+                        parent_len,
+                        compatible: None,
+                        enabled: true,
+                        phandle: None,
+                        references: [None; DEPENDENCY_PROPERTIES.len()],
+                        iommus: None,
+                        done: false,
+                    });
+                }
+                Token::EndNode => {
+                    if let Some(mut node) = stack.pop() {
+                        finish(&mut node, &path);
+                        path.truncate(node.parent_len);
+                    }
+                }
+                Token::Property { name, data } => {
+                    let Some(node) = stack.last_mut() else {
+                        continue;
+                    };
+                    match name {
+                        b"compatible" => node.compatible = Some(StringList { data }),
+                        b"status" => {
+                            node.enabled = CStr::from_bytes_until_nul(data)
+                                .is_ok_and(|s| is_okay(s.to_bytes()));
+                        }
+                        b"phandle" if data.len() >= 4 => {
+                            node.phandle = Some(BigEndian::read_u32(data));
+                        }
+                        b"iommus" => node.iommus = Some(data),
+                        _ => {
+                            if let Some(i) =
+                                DEPENDENCY_PROPERTIES.iter().position(|(p, _)| *p == name)
+                            {
+                                node.references[i] = Some(data);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        candidates
+    }
+}
+
+/// Find the candidates that each candidate depends on, by index. A device never depends on itself,
+/// which an interrupt controller with its own maintenance interrupt would otherwise do.
+fn dependencies(device_tree: &DeviceTree, candidates: &[Candidate]) -> Vec<Vec<usize>> {
+    let by_path = |path: &[u8]| candidates.iter().position(|c| c.path == path);
+    let by_phandle: BTreeMap<u32, usize> = candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| c.phandle.map(|p| (p, i)))
+        .collect();
+    let interrupt_tree = InterruptTree::new(device_tree);
+    candidates
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| {
+            // devices with invalid interrupts are left for their driver to report
+            let mut dependencies: Vec<usize> = interrupt_tree
+                .interrupts_of(&candidate.path)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|int| by_path(interrupt_tree.path(int.controller)))
+                .collect();
+            for ((_, cells_name), data) in DEPENDENCY_PROPERTIES.iter().zip(candidate.references) {
+                let Some(data) = data else {
+                    continue;
+                };
+                dependencies.extend(
+                    phandle_arguments(device_tree, data, cells_name)
+                        .filter_map(|(phandle, _)| by_phandle.get(&phandle).copied()),
+                );
+            }
+            dependencies.retain(|d| *d != i);
+            dependencies.sort_unstable();
+            dependencies.dedup();
+            dependencies
+        })
+        .collect()
+}
+
+/// Iterate over the phandles in a property that lists phandles followed by arguments, with the
+/// bytes of the arguments of each, where the number of argument cells is given by the `cells_name`
+/// property of the referenced node. The list ends early at a phandle that doesn't exist.
+pub fn phandle_arguments<'a>(
+    device_tree: &'a DeviceTree,
+    data: &'a [u8],
+    cells_name: &'a [u8],
+) -> impl Iterator<Item = (u32, &'a [u8])> + 'a {
+    let mut rest = data;
+    core::iter::from_fn(move || {
+        if rest.len() < 4 {
+            return None;
+        }
+        let phandle = BigEndian::read_u32(rest);
+        let arguments = device_tree
+            .iter_node_properties_by_phandle(phandle)?
+            .find(|(name, _)| *name == cells_name)
+            .map_or(Some(0), |(_, value)| match value {
+                Value::U32(cells) => Some(cells),
+                Value::Bytes(b) if b.len() >= 4 => Some(BigEndian::read_u32(b)),
+                _ => None,
+            })?;
+        let end = (1 + arguments as usize) * 4;
+        let arguments = rest.get(4..end).unwrap_or_default();
+        rest = rest.get(end..).unwrap_or_default();
+        Some((phandle, arguments))
+    })
+}
+
+/// Find the dependency that kept the pending device at `index` from being probed, and whether the
+/// device depends on itself through it. Returns `None` if every dependency is ready, in which case
+/// the device's driver deferred it.
+fn blocking_dependency(
+    index: usize,
+    dependencies: &[Vec<usize>],
+    states: &[State],
+) -> Option<(usize, bool)> {
+    let blocking = *dependencies[index]
+        .iter()
+        .find(|d| !states[**d].is_ready())?;
+    // search the pending devices that the blocking dependency is waiting for, for this one
+    let mut visited = vec![false; dependencies.len()];
+    let mut stack = vec![blocking];
+    while let Some(i) = stack.pop() {
+        if i == index {
+            return Some((blocking, true));
+        }
+        if visited[i] || states[i] != State::Pending {
+            continue;
+        }
+        visited[i] = true;
+        stack.extend(
+            dependencies[i]
+                .iter()
+                .copied()
+                .filter(|d| !states[*d].is_ready()),
+        );
+    }
+    Some((blocking, false))
+}
+
+/// True if a `status` property value means the device is enabled.
+fn is_okay(status: &[u8]) -> bool {
+    matches!(status, b"okay" | b"ok")
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+
+    const TEST_TREE: &[u8] = include_bytes!("platform/device_tree/test-tree.fdt");
+
+    fn ok(_: &Device) -> Result<(), ProbeError> {
+        Ok(())
+    }
+
+    fn driver_for<'r>(results: &'r [Probed], path: &[u8]) -> Option<&'r Probed> {
+        results.iter().find(|p| p.path.starts_with(path))
+    }
+
+    #[test]
+    fn most_specific_compatible_wins() {
+        static DRIVERS: &[Driver] = &[
+            Driver {
+                name: "primecell",
+                compatible: &[b"arm,primecell"],
+                probe: ok,
+            },
+            Driver {
+                name: "pl011",
+                compatible: &[b"arm,pl011"],
+                probe: ok,
+            },
+        ];
+        let dt = DeviceTree::from_bytes(TEST_TREE);
+        let results = Registry::new(DRIVERS).probe_all(&dt);
+        assert_eq!(results.len(), 3);
+        assert_eq!(driver_for(&results, b"/pl011@").unwrap().driver, "pl011");
+        assert_eq!(
+            driver_for(&results, b"/pl031@").unwrap().driver,
+            "primecell"
+        );
+        assert_eq!(
+            driver_for(&results, b"/pl061@").unwrap().driver,
+            "primecell"
+        );
+        assert!(results.iter().all(|p| p.result.is_ok()));
+    }
+
+    #[test]
+    fn probed_after_dependencies() {
+        static INTC_BOUND: AtomicBool = AtomicBool::new(false);
+        static VIRTIO_PROBES: AtomicUsize = AtomicUsize::new(0);
+        fn intc(_: &Device) -> Result<(), ProbeError> {
+            INTC_BOUND.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        fn virtio(device: &Device) -> Result<(), ProbeError> {
+            assert_eq!(device.compatible, b"virtio,mmio");
+            assert!(INTC_BOUND.load(Ordering::Relaxed));
+            VIRTIO_PROBES.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        static DRIVERS: &[Driver] = &[
+            Driver {
+                name: "virtio",
+                compatible: &[b"virtio,mmio"],
+                probe: virtio,
+            },
+            Driver {
+                name: "intc",
+                compatible: &[b"arm,cortex-a15-gic", b"arm,gic-400"],
+                probe: intc,
+            },
+        ];
+        let dt = DeviceTree::from_bytes(TEST_TREE);
+        let results = Registry::new(DRIVERS).probe_all(&dt);
+        assert_eq!(results.len(), 33);
+        assert!(results.iter().all(|p| p.result.is_ok()));
+        // the virtio devices come before their interrupt controller in the tree
+        assert_eq!(results[0].driver, "intc");
+        assert_eq!(VIRTIO_PROBES.load(Ordering::Relaxed), 32);
+    }
+
+    #[test]
+    fn failed_dependency_reported() {
+        fn fail(_: &Device) -> Result<(), ProbeError> {
+            Err(ProbeError::Failed { reason: "test" })
+        }
+        static DRIVERS: &[Driver] = &[
+            Driver {
+                name: "clock",
+                compatible: &[b"fixed-clock"],
+                probe: fail,
+            },
+            Driver {
+                name: "uart",
+                compatible: &[b"arm,pl011"],
+                probe: ok,
+            },
+        ];
+        let dt = DeviceTree::from_bytes(TEST_TREE);
+        let results = Registry::new(DRIVERS).probe_all(&dt);
+        assert_eq!(results.len(), 2);
+        let clock = driver_for(&results, b"/apb-pclk").unwrap();
+        assert_eq!(clock.result, Err(ProbeError::Failed { reason: "test" }));
+        let uart = driver_for(&results, b"/pl011@").unwrap();
+        assert_eq!(uart.result, Err(ProbeError::DependencyNotBound));
+        assert_eq!(uart.waiting_for.as_deref(), Some(b"/apb-pclk" as &[u8]));
+    }
+
+    #[test]
+    fn blocking_dependencies() {
+        // 0 -> 1 -> 2 -> 0 is a cycle, 3 waits on the cycle, 4 waits on 5 which failed, and 6 was
+        // deferred by its driver
+        let dependencies = [
+            vec![1],
+            vec![2],
+            vec![0],
+            vec![0, 6],
+            vec![6, 5],
+            vec![],
+            vec![],
+        ];
+        let mut states = [State::Pending; 7];
+        states[5] = State::Failed;
+        states[6] = State::Bound;
+        assert_eq!(
+            blocking_dependency(0, &dependencies, &states),
+            Some((1, true))
+        );
+        assert_eq!(
+            blocking_dependency(2, &dependencies, &states),
+            Some((0, true))
+        );
+        assert_eq!(
+            blocking_dependency(3, &dependencies, &states),
+            Some((0, false))
+        );
+        assert_eq!(
+            blocking_dependency(4, &dependencies, &states),
+            Some((5, false))
+        );
+        states[6] = State::Pending;
+        assert_eq!(blocking_dependency(6, &dependencies, &states), None);
+    }
+
+    #[test]
+    fn unresolved_and_declined() {
+        fn decline(_: &Device) -> Result<(), ProbeError> {
+            Err(ProbeError::Declined)
+        }
+        fn defer(_: &Device) -> Result<(), ProbeError> {
+            Err(ProbeError::Deferred)
+        }
+        static DRIVERS: &[Driver] = &[
+            Driver {
+                name: "virtio",
+                compatible: &[b"virtio,mmio"],
+                probe: decline,
+            },
+            Driver {
+                name: "rtc",
+                compatible: &[b"arm,pl031"],
+                probe: defer,
+            },
+            Driver {
+                name: "uart",
+                compatible: &[b"arm,pl011"],
+                probe: ok,
+            },
+        ];
+        let dt = DeviceTree::from_bytes(TEST_TREE);
+        let registry = Registry::new(DRIVERS);
+        let results = registry.probe_all(&dt);
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            driver_for(&results, b"/pl031@").unwrap().result,
+            Err(ProbeError::Deferred)
+        );
+        assert_eq!(
+            registry.probe_node(&dt, b"/pl011@9000000"),
+            Some(("uart", Ok(())))
+        );
+        assert_eq!(registry.probe_node(&dt, b"/intc@8000000"), None);
+        assert_eq!(registry.probe_node(&dt, b"/nonexistent"), None);
+    }
+
+    #[test]
+    fn arguments_of_phandles() {
+        let dt = DeviceTree::from_bytes(TEST_TREE);
+        // the GPIO controller takes two cells, and the clock, which has no `#gpio-cells`, none
+        let data: Vec<u8> = [0x8004, 3, 0, 0x8000, 0x8004, 5, 1, 0x9999, 7]
+            .iter()
+            .flat_map(|cell: &u32| cell.to_be_bytes())
+            .collect();
+        let found: Vec<_> = phandle_arguments(&dt, &data, b"#gpio-cells")
+            .map(|(phandle, arguments)| (phandle, arguments.len()))
+            .collect();
+        assert_eq!(found, [(0x8004, 8), (0x8000, 0), (0x8004, 8)]);
+        assert_eq!(
+            Registry::new(&[]).iommu_streams(&dt, 0x8004),
+            Vec::<u32>::new()
+        );
+    }
+}
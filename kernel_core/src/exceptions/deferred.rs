@@ -0,0 +1,125 @@
+//! Deferred interrupt handling.
+//!
+//! Interrupt handlers run with interrupts masked, so they should do as little as possible.
+//! Any longer running work can instead be pushed onto a [`DeferredQueue`] (the "bottom half"),
+//! which is drained later by a dedicated worker thread. Pushing work unparks the worker, which
+//! neither locks nor allocates beyond the work item itself.
+use alloc::{boxed::Box, sync::Arc};
+use crossbeam::queue::SegQueue;
+use log::trace;
+use spin::Once;
+
+use crate::process::thread::Thread;
+
+/// A unit of deferred work.
+pub type WorkItem = Box<dyn FnOnce() + Send>;
+
+/// A queue of work deferred from interrupt handlers.
+pub struct DeferredQueue {
+    items: SegQueue<WorkItem>,
+    /// The thread that runs the work, which parks while the queue is empty.
+    worker: Once<Arc<Thread>>,
+}
+
+impl DeferredQueue {
+    /// Create a new, empty queue.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            items: SegQueue::new(),
+            worker: Once::new(),
+        }
+    }
+
+    /// Set the thread that runs the work in the queue, which is unparked whenever work is pushed.
+    /// The worker should park whenever [`DeferredQueue::run_pending`] finds no work to run.
+    ///
+    /// Only the first worker is kept.
+    pub fn set_worker(&self, worker: Arc<Thread>) {
+        self.worker.call_once(|| worker);
+    }
+
+    /// Queue `work` to run later, and wake the worker thread.
+    pub fn push(&self, work: impl FnOnce() + Send + 'static) {
+        self.items.push(Box::new(work));
+        if let Some(worker) = self.worker.get() {
+            worker.unpark();
+        }
+    }
+
+    /// True if there is no pending work.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Run up to `limit` pending work items in the order they were queued.
+    ///
+    /// Items pushed while running are also eligible to run, as long as the limit has not been
+    /// reached. Returns the number of items that were run.
+    pub fn run_pending(&self, limit: usize) -> usize {
+        let mut count = 0;
+        while count < limit {
+            let Some(work) = self.items.pop() else {
+                break;
+            };
+            work();
+            count += 1;
+        }
+        if count > 0 {
+            trace!("ran {count} deferred work items");
+        }
+        count
+    }
+}
+
+impl Default for DeferredQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        process::thread::{ProcessorState, State, WaitReason, MAX_THREAD_ID},
+    };
+
+    #[test]
+    fn runs_in_order_up_to_limit() {
+        let q = DeferredQueue::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..3 {
+            let log = log.clone();
+            q.push(move || log.lock().unwrap().push(i));
+        }
+        assert_eq!(q.run_pending(2), 2);
+        assert_eq!(*log.lock().unwrap(), [0, 1]);
+        assert!(!q.is_empty());
+        assert_eq!(q.run_pending(usize::MAX), 1);
+        assert_eq!(*log.lock().unwrap(), [0, 1, 2]);
+        assert!(q.is_empty());
+        assert_eq!(q.run_pending(usize::MAX), 0);
+    }
+
+    #[test]
+    fn push_unparks_worker() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let worker = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let q = DeferredQueue::new();
+        q.set_worker(worker.clone());
+
+        let token = worker.block_for(WaitReason::Park).unwrap();
+        assert!(worker.park(token));
+        q.push(|| {});
+        assert_eq!(worker.state(), State::Running);
+        assert_eq!(q.run_pending(usize::MAX), 1);
+    }
+}
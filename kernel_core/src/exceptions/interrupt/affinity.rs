@@ -0,0 +1,155 @@
+//! Routing of interrupts to cores.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A set of cores, identified by their index in the list of cores in the system (i.e. the order
+/// given by [`crate::platform::cpu::list_cores`]). At most 64 cores can be represented.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CoreSet(u64);
+
+impl CoreSet {
+    /// The set containing no cores.
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The set containing every core.
+    #[must_use]
+    pub const fn all() -> Self {
+        Self(u64::MAX)
+    }
+
+    /// The set containing only the core with index `core`.
+    ///
+    /// # Panics
+    /// Panics if `core` is not less than 64.
+    #[must_use]
+    pub const fn single(core: usize) -> Self {
+        assert!(core < 64);
+        Self(1 << core)
+    }
+
+    /// Returns this set with the core with index `core` added.
+    ///
+    /// # Panics
+    /// Panics if `core` is not less than 64.
+    #[must_use]
+    pub const fn with(self, core: usize) -> Self {
+        Self(self.0 | Self::single(core).0)
+    }
+
+    /// Returns true if the set contains the core with index `core`.
+    #[must_use]
+    pub const fn contains(self, core: usize) -> bool {
+        core < 64 && self.0 & (1 << core) != 0
+    }
+
+    /// Returns true if the set contains every one of the first `num_cores` cores.
+    #[must_use]
+    pub fn contains_all(self, num_cores: usize) -> bool {
+        (0..num_cores).all(|i| self.contains(i))
+    }
+
+    /// Iterate over the indices of the cores in the set, in increasing order.
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..64).filter(move |i| self.contains(*i))
+    }
+
+    /// The raw bitmask of the set, where bit `i` is set if core `i` is in the set.
+    #[must_use]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for CoreSet {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl core::fmt::Debug for CoreSet {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.0 == u64::MAX {
+            write!(f, "CoreSet(all)")
+        } else {
+            f.debug_set().entries(self.iter()).finish()
+        }
+    }
+}
+
+/// Policy for choosing which core each device interrupt is routed to.
+///
+/// Interrupts are spread round-robin across the cores so that no one core handles all of them.
+/// The boot core (index 0) already handles the bulk of early system work, so it is only used if it
+/// is the only core in the system.
+pub struct AffinityPolicy {
+    num_cores: usize,
+    next: AtomicUsize,
+}
+
+impl AffinityPolicy {
+    /// Create a new policy for a system with `num_cores` cores.
+    ///
+    /// # Panics
+    /// Panics if `num_cores` is zero or greater than 64.
+    #[must_use]
+    pub fn new(num_cores: usize) -> Self {
+        assert!(num_cores > 0 && num_cores <= 64);
+        Self {
+            num_cores,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Choose the set of cores that the next device interrupt should be routed to.
+    pub fn next_target(&self) -> CoreSet {
+        if self.num_cores == 1 {
+            return CoreSet::single(0);
+        }
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        CoreSet::single(1 + n % (self.num_cores - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn core_set() {
+        let s = CoreSet::empty().with(1).with(3);
+        assert!(!s.contains(0));
+        assert!(s.contains(1));
+        assert!(s.contains(3));
+        assert!(!s.contains(64));
+        assert_eq!(s.iter().collect::<Vec<_>>(), [1, 3]);
+        assert!(!s.contains_all(2));
+        assert!(CoreSet::all().contains_all(64));
+        assert_eq!(CoreSet::default(), CoreSet::all());
+    }
+
+    #[test]
+    fn policy_avoids_boot_core() {
+        let p = AffinityPolicy::new(3);
+        let targets: Vec<_> = (0..4).map(|_| p.next_target()).collect();
+        assert_eq!(
+            targets,
+            [
+                CoreSet::single(1),
+                CoreSet::single(2),
+                CoreSet::single(1),
+                CoreSet::single(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn policy_single_core() {
+        let p = AffinityPolicy::new(1);
+        assert_eq!(p.next_target(), CoreSet::single(0));
+        assert_eq!(p.next_target(), CoreSet::single(0));
+    }
+}
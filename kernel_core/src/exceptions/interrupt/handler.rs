@@ -1,85 +1,219 @@
-use crate::{platform::timer::SystemTimer, process::thread::Scheduler};
-use log::{debug, trace};
+use crate::{
+    platform::timer::SystemTimer,
+    process::thread::Scheduler,
+    smp::IpiReceiver,
+    time::{Ticks, TimerQueue},
+};
+use log::{debug, trace, warn};
 
-use super::Id as InterruptId;
+use super::{
+    HandlerRegistry, Id as InterruptId, InterruptStatistics, INTERRUPT_ENTRY, INTERRUPT_EXIT,
+};
 
 /// Interrupt handler policy.
-pub struct Handler<'ic, 'sc, 't, T: SystemTimer, IC: super::Controller, Sched: Scheduler> {
+pub struct Handler<
+    'ic,
+    'sc,
+    't,
+    T: SystemTimer,
+    IC: super::Controller,
+    Sched: Scheduler,
+    Ipi: IpiReceiver,
+> {
     controller: &'ic IC,
     timer: &'t T,
+    timers: &'t TimerQueue,
     scheduler: &'sc Sched,
+    ipi: &'ic Ipi,
+    /// Handlers for interrupts raised by devices, by interrupt id.
+    devices: HandlerRegistry,
+    stats: Option<&'ic InterruptStatistics>,
 }
 
 /// An error that could occur during handling an interrupt.
 #[derive(Debug)]
 pub enum Error {
-    /// An interrupt occurred that was unexpected.
+    /// An interrupt occurred that was unexpected. It has been counted as spurious and finished.
+    /// If there were several, this is the first.
     UnknownInterrupt(InterruptId),
 }
 
-impl<'ic, 'sc, 't, T: SystemTimer, IC: super::Controller, Sched: Scheduler>
-    Handler<'ic, 'sc, 't, T, IC, Sched>
+impl<'ic, 'sc, 't, T: SystemTimer, IC: super::Controller, Sched: Scheduler, Ipi: IpiReceiver>
+    Handler<'ic, 'sc, 't, T, IC, Sched, Ipi>
 {
     /// Create a new interrupt handler policy.
-    pub fn new(controller: &'ic IC, timer: &'t T, scheduler: &'sc Sched) -> Self {
+    ///
+    /// Callbacks armed in `timers` are run by the timer interrupt handler once their deadline passes.
+    pub fn new(
+        controller: &'ic IC,
+        timer: &'t T,
+        timers: &'t TimerQueue,
+        scheduler: &'sc Sched,
+        ipi: &'ic Ipi,
+    ) -> Self {
         Self {
             controller,
             timer,
+            timers,
             scheduler,
+            ipi,
+            devices: HandlerRegistry::new(),
+            stats: None,
         }
     }
 
+    /// Count every interrupt that is handled in `stats`.
+    #[must_use]
+    pub fn with_statistics(mut self, stats: &'ic InterruptStatistics) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// The handlers for interrupts raised by devices, which can be registered and unregistered
+    /// at any time.
+    pub fn devices(&self) -> &HandlerRegistry {
+        &self.devices
+    }
+
     /// Acknowledge any interrupts that have occurred, and handle the ones that are known.
     ///
-    /// # Errors
-    /// If an error occurs, the thread state is not be restored.
+    /// Unknown interrupts are finished without being handled, and processing continues. An unknown
+    /// interrupt that storms (see [`InterruptStatistics`]) is disabled.
     ///
+    /// # Errors
     /// - [`Error::UnknownInterrupt`]: If an interrupt happens that is unknown to the handler.
     pub fn process_interrupts(&self) -> Result<(), Error> {
-        while let Some(int_id) = self.controller.ack_interrupt() {
+        let mut handled_other = false;
+        let mut unknown = None;
+        while let Some(ack) = self.controller.ack_interrupt() {
+            let int_id = ack.id;
             trace!("handling interrupt {int_id}");
+            INTERRUPT_ENTRY.hit(u64::from(int_id), 0);
+            let started = self.stats.map(InterruptStatistics::now);
 
             if int_id == self.timer.interrupt_id() {
                 debug!("timer interrupt");
+                let now = self.timer.now();
+                while let Some((id, callback)) = self.timers.pop_expired(now) {
+                    trace!("timer {id:?} expired");
+                    callback();
+                }
                 self.scheduler.next_time_slice();
-                self.timer.reset();
+                self.program_timer(now);
+            } else if int_id == self.ipi.interrupt_id() {
+                debug!("inter-processor interrupt");
+                handled_other = true;
+                if self.ipi.handle_pending() {
+                    self.scheduler.next_time_slice();
+                }
+            } else if self.devices.handle(int_id) {
+                trace!("device interrupt");
+                handled_other = true;
             } else {
-                return Err(Error::UnknownInterrupt(int_id));
+                debug!("spurious interrupt {int_id}");
+                let storm = self.stats.is_some_and(|s| s.record_spurious(int_id));
+                unknown.get_or_insert(int_id);
+                self.controller.finish_interrupt(ack);
+                if storm {
+                    // nothing will ever handle it, so keep it from starving the core
+                    warn!("disabling interrupt {int_id}, which has no handler");
+                    self.controller.disable(int_id);
+                }
+                continue;
             }
 
             trace!("finished interrupt {int_id}");
-            self.controller.finish_interrupt(int_id);
+            INTERRUPT_EXIT.hit(u64::from(int_id), 0);
+            if let Some((stats, started)) = self.stats.zip(started) {
+                stats.record_handled(int_id, started);
+            }
+            self.controller.finish_interrupt(ack);
+        }
+
+        if handled_other {
+            // the core may have stopped its tick while idle but now have work to do, or an
+            // earlier timer may have been armed
+            let deadline = self.next_deadline(self.timer.now());
+            if deadline < self.timer.deadline() {
+                self.timer.set_deadline(deadline);
+            }
         }
-        Ok(())
+
+        unknown.map_or(Ok(()), |id| Err(Error::UnknownInterrupt(id)))
+    }
+
+    /// The time the timer for the current core should next expire: the end of the next time
+    /// slice, or the earliest armed deadline if that is sooner.
+    /// If the core is idle, there is no need for a time slice, so only armed deadlines count.
+    fn next_deadline(&self, now: Ticks) -> Ticks {
+        let end_of_slice = if self.scheduler.is_idle() {
+            Ticks::MAX
+        } else {
+            now + self.timer.time_slice()
+        };
+        self.timers
+            .next_deadline()
+            .map_or(end_of_slice, |d| d.min(end_of_slice))
+    }
+
+    /// Program the timer for the current core for the next time slice or armed deadline,
+    /// stopping it entirely if the core is idle and there are no armed deadlines.
+    fn program_timer(&self, now: Ticks) {
+        self.timer.set_deadline(self.next_deadline(now));
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::{boxed::Box, sync::Arc};
+
     use mockall::predicate::eq;
 
     use crate::{
-        exceptions::{interrupt::MockController, InterruptId},
+        exceptions::{
+            interrupt::{Acknowledged, MockController},
+            InterruptId,
+        },
         platform::timer::MockSystemTimer,
         process::thread::MockScheduler,
+        smp::MockIpiReceiver,
+        time::TimerQueue,
     };
 
-    use super::{Error, Handler};
+    use super::{super::stats::tests::statistics, Error, Handler};
 
     #[test]
     fn unknown_interrupt() {
         let unknown_id = 1000;
         let mut controller = MockController::new();
         let mut timer = MockSystemTimer::new();
+        let timers = TimerQueue::new();
         let sched = MockScheduler::new();
         controller
             .expect_ack_interrupt()
+            .times(2)
+            .return_const(Some(Acknowledged::from(unknown_id)));
+        controller
+            .expect_finish_interrupt()
+            .times(2)
+            .with(eq(Acknowledged::from(unknown_id)))
+            .return_const(());
+        // the second time makes a storm, so the interrupt is disabled
+        controller
+            .expect_disable()
             .once()
-            .return_const(Some(unknown_id));
-        timer.expect_interrupt_id().once().return_const(30u32);
-        let h = Handler::new(&controller, &timer, &sched);
+            .with(eq(unknown_id))
+            .return_const(());
+        controller.expect_ack_interrupt().once().return_const(None);
+        timer.expect_interrupt_id().times(2).return_const(30u32);
+        let mut ipi = MockIpiReceiver::new();
+        ipi.expect_interrupt_id().times(2).return_const(0u32);
+        let stats = statistics(2, 100);
+        let h = Handler::new(&controller, &timer, &timers, &sched, &ipi).with_statistics(&stats);
         let res = h.process_interrupts();
         assert!(matches!(res, Err(Error::UnknownInterrupt(id)) if id == unknown_id));
+        assert_eq!(stats.get(unknown_id).unwrap().spurious, 2);
     }
 
     #[test]
@@ -87,21 +221,170 @@ mod tests {
         let timer_id: InterruptId = 30;
         let mut controller = MockController::new();
         let mut timer = MockSystemTimer::new();
+        let timers = TimerQueue::new();
         let mut sched = MockScheduler::new();
         sched.expect_next_time_slice().once().return_const(());
+        sched.expect_is_idle().return_const(false);
         controller
             .expect_ack_interrupt()
             .once()
-            .return_const(Some(timer_id));
+            .return_const(Some(Acknowledged::from(timer_id)));
         controller
             .expect_finish_interrupt()
             .once()
-            .with(eq(timer_id))
+            .with(eq(Acknowledged::from(timer_id)))
             .return_const(());
         controller.expect_ack_interrupt().once().return_const(None);
         timer.expect_interrupt_id().once().return_const(timer_id);
-        timer.expect_reset().once().return_const(());
-        let h = Handler::new(&controller, &timer, &sched);
+        timer.expect_now().once().return_const(1000u64);
+        timer.expect_time_slice().once().return_const(100u64);
+        timer
+            .expect_set_deadline()
+            .once()
+            .with(eq(1100))
+            .return_const(());
+        let ipi = MockIpiReceiver::new();
+        let h = Handler::new(&controller, &timer, &timers, &sched, &ipi);
+        h.process_interrupts().expect("handle interrupt");
+    }
+
+    #[test]
+    fn handle_ipi() {
+        let ipi_id: InterruptId = 0;
+        let mut controller = MockController::new();
+        let mut timer = MockSystemTimer::new();
+        let timers = TimerQueue::new();
+        let mut sched = MockScheduler::new();
+        let mut ipi = MockIpiReceiver::new();
+        // GICv2 reports the core that sent an SGI alongside its ID, which must be written back
+        let ack = Acknowledged {
+            id: ipi_id,
+            raw: (2 << 10) | ipi_id,
+        };
+        controller
+            .expect_ack_interrupt()
+            .once()
+            .return_const(Some(ack));
+        controller
+            .expect_finish_interrupt()
+            .once()
+            .with(eq(ack))
+            .return_const(());
+        controller.expect_ack_interrupt().once().return_const(None);
+        timer.expect_interrupt_id().once().return_const(30u32);
+        ipi.expect_interrupt_id().once().return_const(ipi_id);
+        ipi.expect_handle_pending().once().return_const(true);
+        sched.expect_next_time_slice().once().return_const(());
+        // the core was idle with its tick stopped, but now has a thread to run
+        sched.expect_is_idle().once().return_const(false);
+        timer.expect_now().once().return_const(1000u64);
+        timer.expect_time_slice().once().return_const(100u64);
+        timer.expect_deadline().once().return_const(u64::MAX);
+        timer
+            .expect_set_deadline()
+            .once()
+            .with(eq(1100))
+            .return_const(());
+        let stats = statistics(10, 100);
+        let h = Handler::new(&controller, &timer, &timers, &sched, &ipi).with_statistics(&stats);
+        h.process_interrupts().expect("handle interrupt");
+        let counters = stats.get(ipi_id).unwrap();
+        assert_eq!((counters.handled, counters.spurious), (1, 0));
+    }
+
+    #[test]
+    fn run_expired_timers() {
+        let timer_id: InterruptId = 30;
+        let mut controller = MockController::new();
+        let mut timer = MockSystemTimer::new();
+        let timers: TimerQueue = TimerQueue::new();
+        let mut sched = MockScheduler::new();
+        sched.expect_next_time_slice().once().return_const(());
+        sched.expect_is_idle().return_const(false);
+        controller
+            .expect_ack_interrupt()
+            .once()
+            .return_const(Some(Acknowledged::from(timer_id)));
+        controller.expect_finish_interrupt().return_const(());
+        controller.expect_ack_interrupt().once().return_const(None);
+        timer.expect_interrupt_id().return_const(timer_id);
+        timer.expect_now().return_const(1000u64);
+        timer.expect_time_slice().return_const(100u64);
+        // the next deadline is sooner than the end of the time slice
+        timer
+            .expect_set_deadline()
+            .once()
+            .with(eq(1050))
+            .return_const(());
+        let fired = Arc::new(AtomicBool::new(false));
+        let f = fired.clone();
+        timers.arm(900, Box::new(move || f.store(true, Ordering::Relaxed)));
+        timers.arm(1050, Box::new(|| panic!("not expired yet")));
+        let ipi = MockIpiReceiver::new();
+        let h = Handler::new(&controller, &timer, &timers, &sched, &ipi);
+        h.process_interrupts().expect("handle interrupt");
+        assert!(fired.load(Ordering::Relaxed));
+        assert_eq!(timers.next_deadline(), Some(1050));
+    }
+
+    #[test]
+    fn idle_core_stops_tick() {
+        let timer_id: InterruptId = 30;
+        let mut controller = MockController::new();
+        let mut timer = MockSystemTimer::new();
+        let timers = TimerQueue::new();
+        let mut sched = MockScheduler::new();
+        sched.expect_next_time_slice().once().return_const(());
+        sched.expect_is_idle().return_const(true);
+        controller
+            .expect_ack_interrupt()
+            .once()
+            .return_const(Some(Acknowledged::from(timer_id)));
+        controller.expect_finish_interrupt().return_const(());
+        controller.expect_ack_interrupt().once().return_const(None);
+        timer.expect_interrupt_id().return_const(timer_id);
+        timer.expect_now().return_const(1000u64);
+        timer.expect_time_slice().return_const(100u64);
+        timer
+            .expect_set_deadline()
+            .once()
+            .with(eq(u64::MAX))
+            .return_const(());
+        let ipi = MockIpiReceiver::new();
+        let h = Handler::new(&controller, &timer, &timers, &sched, &ipi);
+        h.process_interrupts().expect("handle interrupt");
+    }
+
+    #[test]
+    fn handle_device_interrupt() {
+        let device_id: InterruptId = 40;
+        let mut controller = MockController::new();
+        let mut timer = MockSystemTimer::new();
+        let timers = TimerQueue::new();
+        let mut sched = MockScheduler::new();
+        let mut ipi = MockIpiReceiver::new();
+        controller
+            .expect_ack_interrupt()
+            .once()
+            .return_const(Some(Acknowledged::from(device_id)));
+        controller
+            .expect_finish_interrupt()
+            .once()
+            .with(eq(Acknowledged::from(device_id)))
+            .return_const(());
+        controller.expect_ack_interrupt().once().return_const(None);
+        timer.expect_interrupt_id().once().return_const(30u32);
+        ipi.expect_interrupt_id().once().return_const(0u32);
+        sched.expect_is_idle().once().return_const(true);
+        timer.expect_now().once().return_const(1000u64);
+        timer.expect_deadline().once().return_const(u64::MAX);
+        let handled = Arc::new(AtomicBool::new(false));
+        let device = handled.clone();
+        let h = Handler::new(&controller, &timer, &timers, &sched, &ipi);
+        h.devices()
+            .register(device_id, move || device.store(true, Ordering::SeqCst))
+            .unwrap();
         h.process_interrupts().expect("handle interrupt");
+        assert!(handled.load(Ordering::SeqCst));
     }
 }
@@ -3,11 +3,50 @@
 mod handler;
 pub use handler::{Error as HandlerError, Handler};
 
+pub mod msi;
+pub use msi::Msi;
+
+mod affinity;
+pub use affinity::{AffinityPolicy, CoreSet};
+
+pub mod registry;
+pub use registry::HandlerRegistry;
+
+pub mod stats;
+pub use stats::InterruptStatistics;
+
+pub mod threaded;
+pub use threaded::ThreadedInterrupt;
+
+crate::tracepoints! {
+    /// An interrupt was acknowledged and is about to be handled. Arguments: interrupt ID, unused.
+    INTERRUPT_ENTRY;
+    /// An interrupt has been handled. Arguments: interrupt ID, unused.
+    INTERRUPT_EXIT;
+}
+
 /// The identifier of an interrupt.
 pub type Id = u32;
 
+/// An interrupt that has been acknowledged with the controller, but not yet finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Acknowledged {
+    /// The ID of the interrupt.
+    pub id: Id,
+    /// The value read from the controller when the interrupt was acknowledged, which must be
+    /// written back when it is finished. On `GICv2` this also holds the core that sent an SGI.
+    pub raw: u32,
+}
+
+impl From<Id> for Acknowledged {
+    /// An acknowledgement for a controller that reports nothing beyond the interrupt ID.
+    fn from(id: Id) -> Self {
+        Self { id, raw: id }
+    }
+}
+
 /// Trigger mode for an interrupt.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum TriggerMode {
     /// Use level triggering.
     #[default]
@@ -16,6 +55,15 @@ pub enum TriggerMode {
     Edge,
 }
 
+/// The cores that a software-generated interrupt is sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiTarget {
+    /// The core with this index (see [`CoreSet`] for how cores are indexed).
+    Core(usize),
+    /// Every core except the one sending the interrupt.
+    AllButSelf,
+}
+
 /// The configuration of an interrupt with the interrupt controller.
 #[derive(Debug, Default)]
 pub struct Config {
@@ -23,6 +71,9 @@ pub struct Config {
     pub priority: u8,
     /// Triggering mode for the interrupt.
     pub mode: TriggerMode,
+    /// The cores that the interrupt can be delivered to.
+    /// This is ignored for interrupts that are private to a core.
+    pub targets: CoreSet,
 }
 
 /// An interrupt controller manages and collates interrupts for the processor.
@@ -44,6 +95,13 @@ pub trait Controller {
     /// Set the configuration of an interrupt.
     fn configure(&self, id: Id, config: &Config);
 
+    /// Change the set of cores that an interrupt can be delivered to.
+    ///
+    /// Controllers that can only deliver an interrupt to one particular core or to any core will
+    /// use the first core in the set, unless the set contains every core.
+    /// This has no effect for interrupts that are private to a core.
+    fn set_affinity(&self, id: Id, cores: CoreSet);
+
     /// Enable an interrupt to raise an exception.
     fn enable(&self, id: Id);
     /// Disable an interrupt from raising an exception.
@@ -53,9 +111,23 @@ pub trait Controller {
     fn clear_pending(&self, id: Id);
 
     /// Acknowledge that an interrupt exception has been handled.
-    /// Returns the ID of the interrupt that was triggered.
-    fn ack_interrupt(&self) -> Option<Id>;
+    /// Returns the interrupt that was triggered.
+    fn ack_interrupt(&self) -> Option<Acknowledged>;
 
     /// Inform the interrupt controller that the system has finished processing an interrupt.
-    fn finish_interrupt(&self, id: Id);
+    fn finish_interrupt(&self, interrupt: Acknowledged);
+
+    /// Send the software-generated interrupt `id` to the `target` cores.
+    fn send_ipi(&self, id: Id, target: IpiTarget);
+
+    /// Allocate a message-signaled interrupt that a device can use to raise an interrupt.
+    /// The interrupt must still be configured and enabled before it will be delivered.
+    ///
+    /// Returns `None` if the controller does not support MSIs or they have all been allocated.
+    fn allocate_msi(&self) -> Option<Msi> {
+        None
+    }
+
+    /// Free a message-signaled interrupt previously returned by [`Controller::allocate_msi`].
+    fn free_msi(&self, _id: Id) {}
 }
@@ -0,0 +1,103 @@
+//! Message-signaled interrupts (MSIs).
+//!
+//! Instead of asserting a dedicated interrupt line, a device signals an MSI by writing a value to a
+//! special "doorbell" address provided by the interrupt controller.
+use alloc::vec::Vec;
+
+use super::Id;
+use crate::{memory::PhysicalAddress, sync::Mutex};
+
+/// A message-signaled interrupt allocated by an interrupt controller.
+///
+/// A device raises the interrupt by writing `data` (as a 32-bit value) to the physical address `doorbell`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    /// The interrupt that will be raised.
+    pub id: Id,
+    /// Physical address that the device must write to.
+    pub doorbell: PhysicalAddress,
+    /// Value the device must write.
+    pub data: u32,
+}
+
+/// A contiguous range of interrupts that are reserved for MSIs and share a single doorbell,
+/// where the value written selects the interrupt. For example, a `GICv2m` frame.
+pub struct MsiFrame {
+    doorbell: PhysicalAddress,
+    base: Id,
+    /// Whether each interrupt in the frame is currently allocated.
+    in_use: Mutex<Vec<bool>>,
+}
+
+impl MsiFrame {
+    /// Create a new frame for the `count` interrupts starting at `base`, all of which are initially free.
+    #[must_use]
+    pub fn new(doorbell: PhysicalAddress, base: Id, count: usize) -> Self {
+        Self {
+            doorbell,
+            base,
+            in_use: Mutex::new(alloc::vec![false; count]),
+        }
+    }
+
+    /// Allocate a free interrupt from the frame, if there are any left.
+    pub fn allocate(&self) -> Option<Msi> {
+        let mut in_use = self.in_use.lock();
+        let index = in_use.iter().position(|used| !used)?;
+        in_use[index] = true;
+        let id = self.base + Id::try_from(index).ok()?;
+        Some(Msi {
+            id,
+            doorbell: self.doorbell,
+            data: id,
+        })
+    }
+
+    /// Free an interrupt previously returned by [`Self::allocate`].
+    ///
+    /// Returns false if the interrupt is not part of this frame or was not allocated.
+    pub fn free(&self, id: Id) -> bool {
+        let Some(index) = id
+            .checked_sub(self.base)
+            .and_then(|i| usize::try_from(i).ok())
+        else {
+            return false;
+        };
+        let mut in_use = self.in_use.lock();
+        match in_use.get_mut(index) {
+            Some(used) if *used => {
+                *used = false;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_and_free() {
+        let frame = MsiFrame::new(0x0802_0040.into(), 80, 2);
+        let a = frame.allocate().unwrap();
+        let b = frame.allocate().unwrap();
+        assert_eq!(
+            a,
+            Msi {
+                id: 80,
+                doorbell: 0x0802_0040.into(),
+                data: 80
+            }
+        );
+        assert_eq!(b.id, 81);
+        assert!(frame.allocate().is_none());
+
+        assert!(frame.free(a.id));
+        assert!(!frame.free(a.id));
+        assert!(!frame.free(79));
+        assert!(!frame.free(82));
+        assert_eq!(frame.allocate().unwrap().id, 80);
+    }
+}
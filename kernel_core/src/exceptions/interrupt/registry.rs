@@ -0,0 +1,154 @@
+//! A registry of the handlers for interrupts raised by devices, which can change while the kernel
+//! is running.
+//!
+//! Kernel components (and eventually user space drivers) register a callback for an interrupt ID
+//! when they start using a device, and unregister it when they stop. Each interrupt has at most
+//! one handler.
+use alloc::{sync::Arc, vec::Vec};
+use log::trace;
+use snafu::{ensure, OptionExt, Snafu};
+
+use super::Id as InterruptId;
+use crate::sync::Mutex;
+
+/// A function called to handle an interrupt raised by a device.
+pub type DeviceHandler = dyn Fn() + Send + Sync;
+
+/// Errors that arise when registering interrupt handlers.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The interrupt already has a handler.
+    #[snafu(display("interrupt {id} already has a handler"))]
+    AlreadyRegistered {
+        /// The interrupt ID.
+        id: InterruptId,
+    },
+    /// The interrupt has no handler to unregister.
+    #[snafu(display("interrupt {id} has no handler"))]
+    NotRegistered {
+        /// The interrupt ID.
+        id: InterruptId,
+    },
+}
+
+/// The handlers for device interrupts, by interrupt ID.
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: Mutex<Vec<(InterruptId, Arc<DeviceHandler>)>>,
+}
+
+impl HandlerRegistry {
+    /// Create an empty registry.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            handlers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Call `handler` whenever the device interrupt `id` occurs.
+    /// The interrupt must also be configured and enabled in the interrupt controller.
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyRegistered`]: If the interrupt already has a handler.
+    pub fn register(
+        &self,
+        id: InterruptId,
+        handler: impl Fn() + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        let mut handlers = self.handlers.lock();
+        ensure!(
+            handlers.iter().all(|(i, _)| *i != id),
+            AlreadyRegisteredSnafu { id }
+        );
+        trace!("registered handler for interrupt {id}");
+        handlers.push((id, Arc::new(handler)));
+        Ok(())
+    }
+
+    /// Stop calling the handler for the device interrupt `id`.
+    ///
+    /// The handler may still be running on another core when this returns, but it will not be
+    /// called again.
+    ///
+    /// # Errors
+    /// - [`Error::NotRegistered`]: If the interrupt has no handler.
+    pub fn unregister(&self, id: InterruptId) -> Result<(), Error> {
+        let mut handlers = self.handlers.lock();
+        let index = handlers
+            .iter()
+            .position(|(i, _)| *i == id)
+            .context(NotRegisteredSnafu { id })?;
+        handlers.swap_remove(index);
+        trace!("unregistered handler for interrupt {id}");
+        Ok(())
+    }
+
+    /// Returns true if the interrupt `id` has a handler.
+    #[must_use]
+    pub fn is_registered(&self, id: InterruptId) -> bool {
+        self.handlers.lock().iter().any(|(i, _)| *i == id)
+    }
+
+    /// Call the handler for the interrupt `id`, returning false if it has none.
+    ///
+    /// The registry is not locked while the handler runs, so handlers can register and unregister
+    /// handlers, including themselves.
+    pub fn handle(&self, id: InterruptId) -> bool {
+        let handler = self
+            .handlers
+            .lock()
+            .iter()
+            .find_map(|(i, h)| (*i == id).then(|| h.clone()));
+        handler.is_some_and(|handler| {
+            handler();
+            true
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::OnceLock;
+
+    #[test]
+    fn register_and_unregister() {
+        let registry = HandlerRegistry::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        registry
+            .register(40, move || {
+                c.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        assert!(matches!(
+            registry.register(40, || {}),
+            Err(Error::AlreadyRegistered { id: 40 })
+        ));
+        assert!(registry.is_registered(40));
+        assert!(registry.handle(40));
+        assert!(!registry.handle(41));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        registry.unregister(40).unwrap();
+        assert!(!registry.handle(40));
+        assert!(matches!(
+            registry.unregister(40),
+            Err(Error::NotRegistered { id: 40 })
+        ));
+        registry.register(40, || {}).unwrap();
+    }
+
+    #[test]
+    fn handler_can_unregister_itself() {
+        static REGISTRY: OnceLock<HandlerRegistry> = OnceLock::new();
+        let registry = REGISTRY.get_or_init(HandlerRegistry::new);
+        registry
+            .register(7, || REGISTRY.get().unwrap().unregister(7).unwrap())
+            .unwrap();
+        assert!(registry.handle(7));
+        assert!(!registry.is_registered(7));
+    }
+}
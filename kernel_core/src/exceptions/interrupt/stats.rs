@@ -0,0 +1,261 @@
+//! Statistics about the interrupts that have been handled, for diagnosing misbehaving devices.
+//!
+//! For each interrupt ID, the number of times it was handled on each core and the longest time
+//! spent handling it are counted. Interrupts that are acknowledged but have no handler are counted
+//! as spurious. If an interrupt occurs more than a threshold number of times within a window of
+//! time, a storm is reported.
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use hashbrown::HashMap;
+use log::warn;
+
+use super::Id as InterruptId;
+use crate::{
+    platform::cpu::{CpuIdReader, Id as CpuId},
+    time::{CounterReader, Ticks},
+};
+
+/// The counts for a single interrupt ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterruptCounters {
+    /// The number of times the interrupt was handled.
+    pub handled: u64,
+    /// The number of times the interrupt was handled on each core.
+    pub per_core: BTreeMap<CpuId, u64>,
+    /// The longest time spent handling the interrupt, in counter ticks.
+    pub max_duration: Ticks,
+    /// The number of times the interrupt was acknowledged without a handler to handle it.
+    pub spurious: u64,
+    /// The number of storms of the interrupt that have been detected.
+    pub storms: u64,
+}
+
+/// The counts for a single interrupt ID on one core.
+///
+/// Only the core itself changes its entries, with interrupts disabled, so the counters are updated
+/// with plain loads and stores. Other cores only read them.
+#[derive(Default)]
+struct Entry {
+    handled: AtomicU64,
+    max_duration: AtomicU64,
+    spurious: AtomicU64,
+    storms: AtomicU64,
+    /// The start of the current storm detection window.
+    window_start: AtomicU64,
+    /// The number of times the interrupt has occurred in the current window.
+    window_count: AtomicU64,
+}
+
+impl Entry {
+    fn increment(counter: &AtomicU64) {
+        counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+}
+
+/// Counts interrupts as they are handled, and detects interrupt storms on each core.
+pub struct InterruptStatistics {
+    /// An entry for each interrupt ID on each core, allocated up front so that nothing is
+    /// allocated or locked while handling an interrupt.
+    tables: HashMap<CpuId, Vec<Entry>>,
+    num_ids: usize,
+    storm_threshold: u64,
+    storm_window: Ticks,
+    current_cpu: fn() -> CpuId,
+    now: fn() -> Ticks,
+}
+
+impl InterruptStatistics {
+    /// Create empty statistics for the interrupt IDs below `num_ids` on each of the cores `cpus`,
+    /// that report a storm when an interrupt occurs `storm_threshold` times within
+    /// `storm_window` counter ticks on one core. The current core is read with `C` and the time
+    /// with `R`.
+    ///
+    /// Interrupts with larger IDs, or that occur on other cores, are not counted.
+    ///
+    /// # Panics
+    /// Panics if `storm_threshold` is zero.
+    #[must_use]
+    pub fn new<C: CpuIdReader, R: CounterReader>(
+        cpus: impl IntoIterator<Item = CpuId>,
+        num_ids: usize,
+        storm_threshold: u64,
+        storm_window: Ticks,
+    ) -> Self {
+        assert!(storm_threshold > 0);
+        Self {
+            tables: cpus
+                .into_iter()
+                .map(|id| (id, (0..num_ids).map(|_| Entry::default()).collect()))
+                .collect(),
+            num_ids,
+            storm_threshold,
+            storm_window,
+            current_cpu: C::current_cpu,
+            now: R::read,
+        }
+    }
+
+    /// The current time, to pass to [`Self::record_handled`] once the interrupt has been handled.
+    #[must_use]
+    pub fn now(&self) -> Ticks {
+        (self.now)()
+    }
+
+    /// Count an occurrence of the interrupt `id` in `entry`, returning true if it starts a storm.
+    fn occurred(&self, id: InterruptId, entry: &Entry, now: Ticks) -> bool {
+        let window_start = entry.window_start.load(Ordering::Relaxed);
+        let count = if now.saturating_sub(window_start) >= self.storm_window {
+            entry.window_start.store(now, Ordering::Relaxed);
+            1
+        } else {
+            entry.window_count.load(Ordering::Relaxed) + 1
+        };
+        entry.window_count.store(count, Ordering::Relaxed);
+        if count != self.storm_threshold {
+            return false;
+        }
+        Entry::increment(&entry.storms);
+        warn!(
+            "interrupt storm: interrupt {id} occurred {count} times in {} ticks",
+            now - entry.window_start.load(Ordering::Relaxed)
+        );
+        true
+    }
+
+    fn update(&self, id: InterruptId, f: impl FnOnce(&Entry, Ticks)) -> bool {
+        let Some(entry) = self
+            .tables
+            .get(&(self.current_cpu)())
+            .and_then(|table| table.get(id as usize))
+        else {
+            return false;
+        };
+        let now = self.now();
+        f(entry, now);
+        self.occurred(id, entry, now)
+    }
+
+    /// Record that the interrupt `id` was handled on the current core, having started at
+    /// `started` (from [`Self::now`]).
+    ///
+    /// Returns true if the interrupt has just started a storm.
+    #[allow(clippy::must_use_candidate)]
+    pub fn record_handled(&self, id: InterruptId, started: Ticks) -> bool {
+        self.update(id, |entry, now| {
+            Entry::increment(&entry.handled);
+            let duration = now.saturating_sub(started);
+            if duration > entry.max_duration.load(Ordering::Relaxed) {
+                entry.max_duration.store(duration, Ordering::Relaxed);
+            }
+        })
+    }
+
+    /// Record that the interrupt `id` was acknowledged on the current core, but had no handler.
+    ///
+    /// Returns true if the interrupt has just started a storm.
+    #[allow(clippy::must_use_candidate)]
+    pub fn record_spurious(&self, id: InterruptId) -> bool {
+        self.update(id, |entry, _| Entry::increment(&entry.spurious))
+    }
+
+    /// The counts for the interrupt `id` across every core, if it has occurred.
+    #[must_use]
+    pub fn get(&self, id: InterruptId) -> Option<InterruptCounters> {
+        let mut counters = InterruptCounters::default();
+        for (cpu, table) in &self.tables {
+            let entry = table.get(id as usize)?;
+            let handled = entry.handled.load(Ordering::Relaxed);
+            if handled > 0 {
+                counters.per_core.insert(*cpu, handled);
+            }
+            counters.handled += handled;
+            counters.max_duration = counters
+                .max_duration
+                .max(entry.max_duration.load(Ordering::Relaxed));
+            counters.spurious += entry.spurious.load(Ordering::Relaxed);
+            counters.storms += entry.storms.load(Ordering::Relaxed);
+        }
+        (counters.handled + counters.spurious > 0).then_some(counters)
+    }
+
+    /// The counts for every interrupt that has occurred, in order of interrupt ID.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn snapshot(&self) -> Vec<(InterruptId, InterruptCounters)> {
+        (0..self.num_ids as InterruptId)
+            .filter_map(|id| Some((id, self.get(id)?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::platform::cpu::tests::TestCpu;
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static COUNTER: Cell<Ticks> = const { Cell::new(0) };
+    }
+
+    struct TestCounter;
+
+    impl CounterReader for TestCounter {
+        fn read() -> Ticks {
+            COUNTER.get()
+        }
+    }
+
+    /// Statistics for the interrupt IDs below 1020 on cores 0 and 1, which read the core and the
+    /// time from the thread locals of these tests.
+    pub(crate) fn statistics(storm_threshold: u64, storm_window: Ticks) -> InterruptStatistics {
+        InterruptStatistics::new::<TestCpu, TestCounter>(
+            [0, 1],
+            1020,
+            storm_threshold,
+            storm_window,
+        )
+    }
+
+    #[test]
+    fn counts_per_core_and_max_duration() {
+        let stats = statistics(100, 1000);
+        COUNTER.set(10);
+        assert!(!stats.record_handled(40, 5));
+        TestCpu::set(1);
+        COUNTER.set(50);
+        assert!(!stats.record_handled(40, 48));
+        assert!(!stats.record_handled(30, 50));
+        assert!(!stats.record_spurious(1000));
+        // interrupts past the table, or on unknown cores, are not counted
+        assert!(!stats.record_spurious(5000));
+        TestCpu::set(7);
+        assert!(!stats.record_handled(40, 50));
+
+        let counters = stats.get(40).unwrap();
+        assert_eq!(counters.handled, 2);
+        assert_eq!(counters.per_core, BTreeMap::from([(0, 1), (1, 1)]));
+        assert_eq!(counters.max_duration, 5);
+        assert_eq!(stats.get(1000).unwrap().spurious, 1);
+        assert!(stats.get(7).is_none());
+        assert!(stats.get(5000).is_none());
+        let ids: Vec<_> = stats.snapshot().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, [30, 40, 1000]);
+    }
+
+    #[test]
+    fn storms_are_detected_within_window() {
+        let stats = statistics(3, 100);
+        COUNTER.set(0);
+        assert!(!stats.record_handled(40, 0));
+        assert!(!stats.record_handled(40, 0));
+        // the window has passed, so counting starts again
+        COUNTER.set(100);
+        assert!(!stats.record_handled(40, 100));
+        assert!(!stats.record_spurious(40));
+        assert!(stats.record_spurious(40));
+        // a storm is only reported once per window
+        assert!(!stats.record_handled(40, 100));
+        assert_eq!(stats.get(40).unwrap().storms, 1);
+    }
+}
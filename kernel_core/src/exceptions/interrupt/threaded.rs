@@ -0,0 +1,108 @@
+//! Threaded interrupts, whose handlers run in a kernel thread instead of in the exception handler.
+//!
+//! Handlers for devices that are not critical to the system can take a long time or need to block,
+//! which they can't do in the exception handler. A threaded interrupt splits handling in two. The
+//! hard half, run by the exception handler, only masks the interrupt and marks it pending, so that
+//! a level-triggered interrupt doesn't fire again before the device has been serviced. The thread
+//! half, run by a dedicated kernel thread at
+//! [`INTERRUPT_PRIORITY`](crate::process::thread::INTERRUPT_PRIORITY), calls the device's handler
+//! and then unmasks the interrupt again.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::boxed::Box;
+use log::trace;
+
+use super::{registry::DeviceHandler, Controller, Id as InterruptId};
+
+/// An interrupt whose handler runs in a kernel thread.
+pub struct ThreadedInterrupt {
+    id: InterruptId,
+    /// True if the interrupt has occurred since the handler last ran.
+    pending: AtomicBool,
+    handler: Box<DeviceHandler>,
+}
+
+impl ThreadedInterrupt {
+    /// Create a threaded interrupt that runs `handler` when interrupt `id` occurs.
+    #[must_use]
+    pub fn new(id: InterruptId, handler: Box<DeviceHandler>) -> Self {
+        Self {
+            id,
+            pending: AtomicBool::new(false),
+            handler,
+        }
+    }
+
+    /// The ID of the interrupt.
+    #[must_use]
+    pub fn id(&self) -> InterruptId {
+        self.id
+    }
+
+    /// The hard half of handling the interrupt: mask it in `controller` and mark it pending. The
+    /// caller must then wake the interrupt's thread.
+    pub fn raise(&self, controller: &impl Controller) {
+        controller.disable(self.id);
+        self.pending.store(true, Ordering::Release);
+    }
+
+    /// The thread half of handling the interrupt: if it is pending, call the handler and then
+    /// unmask the interrupt in `controller`.
+    ///
+    /// Returns false if the interrupt was not pending, so the thread should wait to be woken.
+    pub fn run_pending(&self, controller: &impl Controller) -> bool {
+        if !self.pending.swap(false, Ordering::AcqRel) {
+            return false;
+        }
+        trace!("handling threaded interrupt {}", self.id);
+        (self.handler)();
+        controller.enable(self.id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use mockall::{predicate::eq, Sequence};
+
+    use super::*;
+    use crate::exceptions::interrupt::MockController;
+
+    #[test]
+    fn masked_until_handler_runs() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let irq = ThreadedInterrupt::new(
+            33,
+            Box::new(move || {
+                c.fetch_add(1, Ordering::Relaxed);
+            }),
+        );
+        let mut controller = MockController::new();
+        let mut seq = Sequence::new();
+        controller
+            .expect_disable()
+            .with(eq(33))
+            .times(2)
+            .in_sequence(&mut seq)
+            .return_const(());
+        controller
+            .expect_enable()
+            .with(eq(33))
+            .once()
+            .in_sequence(&mut seq)
+            .return_const(());
+
+        assert!(!irq.run_pending(&controller));
+        irq.raise(&controller);
+        // raised again before the thread ran, which only runs the handler once
+        irq.raise(&controller);
+        assert!(irq.run_pending(&controller));
+        assert!(!irq.run_pending(&controller));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(irq.id(), 33);
+    }
+}
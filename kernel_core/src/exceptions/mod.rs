@@ -1,10 +1,13 @@
 //! Policies and definitions for processing hardware exceptions.
 //! This includes interrupts, synchronous exceptions, etc.
 
+pub mod deferred;
 pub mod interrupt;
 pub use interrupt::Controller as InterruptController;
 pub use interrupt::Id as InterruptId;
 
+use crate::{memory::VirtualAddress, platform::cpu::Id as CpuId};
+
 bitfield::bitfield! {
     /// A value in the ESR (Exception Syndrome Register), which indicates the cause of an
     /// exception.
@@ -51,6 +54,28 @@ impl ExceptionClass {
     fn is_user_space_code_page_fault(&self) -> bool {
         self.0 == 0b10_0000
     }
+
+    #[inline]
+    fn is_software_step(&self) -> bool {
+        self.0 == 0b11_0010
+    }
+
+    #[inline]
+    fn is_breakpoint(&self) -> bool {
+        self.0 == 0b11_1100
+    }
+
+    #[inline]
+    fn is_illegal_instruction(&self) -> bool {
+        // unknown instructions, illegal execution state, and branch target exceptions
+        matches!(self.0, 0b00_0000 | 0b00_1110 | 0b00_1101)
+    }
+
+    #[inline]
+    fn is_alignment_fault(&self) -> bool {
+        // PC and SP alignment faults
+        matches!(self.0, 0b10_0010 | 0b10_0110)
+    }
 }
 
 impl core::fmt::Debug for ExceptionClass {
@@ -71,6 +96,8 @@ impl core::fmt::Debug for ExceptionClass {
             0b10_0100 => write!(f, "[Data Abort exception from a lower Exception level]"),
             0b10_0101 => write!(f, "[Data Abort exception taken without a change in Exception level]"),
             0b10_0110 => write!(f, "[SP alignment fault exception]"),
+            0b11_0010 => write!(f, "[Software Step exception from a lower Exception level]"),
+            0b11_1100 => write!(f, "[BRK instruction execution in AArch64 state]"),
             _ => write!(f, "[Unknown]")
         }
     }
@@ -147,3 +174,185 @@ impl core::fmt::Display for ExceptionSyndromeRegister {
             .finish()
     }
 }
+
+/// What caused a data abort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataAbortCause {
+    /// An access to the unmapped guard pages below a stack, meaning that the stack overflowed.
+    StackOverflow,
+    /// Any other fault accessing memory.
+    PageFault,
+}
+
+/// The number of slots in the table of emergency stacks used by the exception vector, see
+/// [`emergency_stack_slot`].
+pub const EMERGENCY_STACK_SLOTS: usize = 256;
+
+/// The slot in the table of emergency stacks that the exception vector switches to when the core
+/// `id` overflows its own stack, or `None` if the core can't have an emergency stack.
+///
+/// The vector finds the slot from `MPIDR_EL1` with a single free register, so only cores with
+/// affinity levels 0 and 1 both below 16, and the higher levels zero, are supported.
+#[must_use]
+pub fn emergency_stack_slot(id: CpuId) -> Option<usize> {
+    let aff0 = id & 0xff;
+    let aff1 = (id >> 8) & 0xff;
+    let higher = id & 0xff_00ff_0000;
+    (aff0 < 16 && aff1 < 16 && higher == 0).then_some(aff1 * 16 + aff0)
+}
+
+/// The kind of fault a user space thread caused, which the thread's process may be able to
+/// recover from.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserFault {
+    /// An instruction fetch or data access to an address that is not mapped, or that the access
+    /// is not permitted to, like `SIGSEGV`.
+    MemoryAccess = 1,
+    /// An instruction that is undefined or can't be executed in the current state, like `SIGILL`.
+    IllegalInstruction = 2,
+    /// A misaligned program counter or stack pointer, like `SIGBUS`.
+    Alignment = 3,
+}
+
+impl ExceptionSyndromeRegister {
+    /// The immediate value of the `svc` instruction that caused this exception, or `None` if the
+    /// exception was not caused by a system call.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn system_call_immediate(&self) -> Option<u16> {
+        self.ec().is_system_call().then(|| self.iss() as u16)
+    }
+
+    /// The immediate value of the `brk` instruction that caused this exception, or `None` if the
+    /// exception was not caused by a breakpoint instruction.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn breakpoint_immediate(&self) -> Option<u16> {
+        self.ec().is_breakpoint().then(|| self.iss() as u16)
+    }
+
+    /// Returns true if the exception was caused by a user space thread completing a single step.
+    #[must_use]
+    pub fn is_software_step(&self) -> bool {
+        self.ec().is_software_step()
+    }
+
+    /// Classify an exception taken from user space as a fault the thread's process may be able to
+    /// recover from, or `None` if the exception is not a fault (for instance a system call).
+    #[must_use]
+    pub fn classify_user_fault(&self) -> Option<UserFault> {
+        let ec = self.ec();
+        if ec.is_user_space_data_page_fault() || ec.is_user_space_code_page_fault() {
+            Some(UserFault::MemoryAccess)
+        } else if ec.is_illegal_instruction() {
+            Some(UserFault::IllegalInstruction)
+        } else if ec.is_alignment_fault() {
+            Some(UserFault::Alignment)
+        } else {
+            None
+        }
+    }
+
+    /// Classify a data abort that occurred accessing `fault_address` (the value of `FAR_EL1`).
+    ///
+    /// The `in_stack_guard` function returns true if an address is in the guard pages below a stack.
+    /// Returns `None` if the exception was not a data abort.
+    pub fn classify_data_abort(
+        &self,
+        fault_address: VirtualAddress,
+        in_stack_guard: impl FnOnce(VirtualAddress) -> bool,
+    ) -> Option<DataAbortCause> {
+        if !self.ec().is_data_abort() {
+            return None;
+        }
+        // guard pages are unmapped, so accessing them always causes a translation fault
+        let translation_fault = self.iss() & 0b11_1100 == 0b00_0100;
+        Some(if translation_fault && in_stack_guard(fault_address) {
+            DataAbortCause::StackOverflow
+        } else {
+            DataAbortCause::PageFault
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_data_aborts() {
+        let guard = |a: VirtualAddress| usize::from(a) & !0xfff == 0xffff_8000_0000_0000;
+        // translation fault, level 3 from EL1
+        let translation = ExceptionSyndromeRegister(0b10_0101 << 26 | 0b00_0111);
+        assert_eq!(
+            translation.classify_data_abort(0xffff_8000_0000_0ff0.into(), guard),
+            Some(DataAbortCause::StackOverflow)
+        );
+        assert_eq!(
+            translation.classify_data_abort(0xffff_8000_0000_1000.into(), guard),
+            Some(DataAbortCause::PageFault)
+        );
+        // permission fault, level 3 from EL0
+        let permission = ExceptionSyndromeRegister(0b10_0100 << 26 | 0b00_1111);
+        assert_eq!(
+            permission.classify_data_abort(0xffff_8000_0000_0ff0.into(), guard),
+            Some(DataAbortCause::PageFault)
+        );
+        // SVC instruction
+        let syscall = ExceptionSyndromeRegister(0b01_0101 << 26);
+        assert_eq!(syscall.classify_data_abort(0.into(), guard), None);
+    }
+
+    #[test]
+    fn emergency_stack_slots() {
+        assert_eq!(emergency_stack_slot(0), Some(0));
+        assert_eq!(emergency_stack_slot(3), Some(3));
+        assert_eq!(emergency_stack_slot(0x0102), Some(18));
+        assert_eq!(
+            emergency_stack_slot(0x0f0f),
+            Some(EMERGENCY_STACK_SLOTS - 1)
+        );
+        // the multithreading flag is ignored
+        assert_eq!(emergency_stack_slot(0x0100_0001), Some(1));
+        assert_eq!(emergency_stack_slot(0x10), None);
+        assert_eq!(emergency_stack_slot(0x1000), None);
+        assert_eq!(emergency_stack_slot(0x01_0000), None);
+        assert_eq!(emergency_stack_slot(0x01_0000_0000), None);
+    }
+
+    #[test]
+    fn system_call_immediates() {
+        let svc = ExceptionSyndromeRegister(0b01_0101 << 26 | 1 << 25 | 0x1234);
+        assert_eq!(svc.system_call_immediate(), Some(0x1234));
+        let abort = ExceptionSyndromeRegister(0b10_0101 << 26 | 0b00_0111);
+        assert_eq!(abort.system_call_immediate(), None);
+        let brk = ExceptionSyndromeRegister(0b11_1100 << 26 | 1 << 25 | 0xf000);
+        assert_eq!(brk.breakpoint_immediate(), Some(0xf000));
+        assert_eq!(brk.system_call_immediate(), None);
+        assert_eq!(svc.breakpoint_immediate(), None);
+        assert!(ExceptionSyndromeRegister(0b11_0010 << 26 | 1 << 25).is_software_step());
+        assert!(!brk.is_software_step());
+    }
+
+    #[test]
+    fn classify_user_faults() {
+        let cases = [
+            (0b10_0100, Some(UserFault::MemoryAccess)),
+            (0b10_0000, Some(UserFault::MemoryAccess)),
+            (0b00_0000, Some(UserFault::IllegalInstruction)),
+            (0b00_1110, Some(UserFault::IllegalInstruction)),
+            (0b10_0010, Some(UserFault::Alignment)),
+            (0b10_0110, Some(UserFault::Alignment)),
+            (0b01_0101, None),
+            (0b11_1100, None),
+            (0b10_0101, None),
+        ];
+        for (ec, fault) in cases {
+            assert_eq!(
+                ExceptionSyndromeRegister(ec << 26 | 1 << 25).classify_user_fault(),
+                fault
+            );
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Filesystem over an archive in memory, such as the initrd.
+use snafu::{ensure, ResultExt};
+
+use super::{copy_from, has_path, ArchiveSnafu, Error, File, FileSystem, NotAFileSnafu};
+use crate::init::archive::{Archive, EntryKind};
+
+/// A filesystem whose files are the entries of an [`Archive`] in memory.
+///
+/// Entries are found by scanning the archive, which is fine for the handful of lookups made while
+/// booting.
+#[derive(Debug, Clone)]
+pub struct ArchiveFs<'a> {
+    archive: Archive<'a>,
+}
+
+impl<'a> ArchiveFs<'a> {
+    /// Create a filesystem containing the entries of `archive`.
+    #[must_use]
+    pub fn new(archive: Archive<'a>) -> Self {
+        Self { archive }
+    }
+}
+
+impl FileSystem for ArchiveFs<'_> {
+    fn lookup(&self, path: &[u8]) -> Result<File, Error> {
+        let base = self.archive.bytes().as_ptr() as usize;
+        let mut found = None;
+        // later entries replace earlier ones with the same path
+        for entry in self.archive.entries() {
+            let entry = entry.context(ArchiveSnafu)?;
+            if has_path(entry.prefix, entry.name, path) {
+                found = Some(File {
+                    kind: entry.kind,
+                    size: entry.data.len(),
+                    location: entry.data.as_ptr() as usize - base,
+                });
+            }
+        }
+        found.ok_or(Error::NotFound)
+    }
+
+    fn read(&self, file: &File, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        ensure!(file.kind == EntryKind::File, NotAFileSnafu);
+        let data = self
+            .archive
+            .bytes()
+            .get(file.location..file.location + file.size)
+            .unwrap_or_default();
+        Ok(copy_from(data, offset, buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::init::archive::tests::{build_cpio, build_tar};
+
+    use super::*;
+
+    #[test]
+    fn read_files() {
+        for data in [build_tar(), build_cpio()] {
+            let fs = ArchiveFs::new(Archive::new(&data).unwrap());
+            assert_eq!(fs.read_file(b"/bin/init").unwrap(), b"init program");
+            let config = fs.lookup(b"etc/config").unwrap();
+            assert_eq!(config.kind, EntryKind::File);
+            let mut buf = [0; 16];
+            assert_eq!(fs.read(&config, config.size - 4, &mut buf).unwrap(), 4);
+            assert_eq!(&buf[..4], &[7; 4]);
+            assert_eq!(fs.lookup(b"./bin/").unwrap().kind, EntryKind::Directory);
+            assert!(matches!(fs.read_file(b"/bin"), Err(Error::NotAFile)));
+            assert!(matches!(fs.lookup(b"/bin/sh"), Err(Error::NotFound)));
+        }
+    }
+}
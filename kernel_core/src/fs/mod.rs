@@ -0,0 +1,139 @@
+//! Read-only filesystems holding the files needed to boot, like the `init` executable and its
+//! dependencies.
+//!
+//! A boot filesystem is either an archive already in memory, like the initrd ([`ArchiveFs`]), or a
+//! tar archive written directly to a block device ([`TarFs`]). Paths are byte strings separated by
+//! `/`, and any leading `/` or `./` is ignored, so `/bin/init`, `./bin/init` and `bin/init` all
+//! name the same file.
+use alloc::{vec, vec::Vec};
+use snafu::{ensure, Snafu};
+
+use crate::{init::archive, io};
+
+mod archive_fs;
+mod tar_fs;
+
+pub use archive_fs::ArchiveFs;
+pub use tar_fs::{SectorReader, TarFs};
+
+/// Errors that arise while reading from a filesystem.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// There is no entry with the requested path.
+    NotFound,
+    /// The entry is a directory or some other kind of entry that has no contents to read.
+    NotAFile,
+    /// The archive holding the filesystem is malformed.
+    #[snafu(display("read archive"))]
+    Archive {
+        /// Underlying archive error.
+        source: archive::Error,
+    },
+    /// The device holding the filesystem could not be read.
+    #[snafu(display("read device"))]
+    Io {
+        /// Underlying device error.
+        source: io::Error,
+    },
+}
+
+/// An entry in a filesystem, found by [`FileSystem::lookup`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct File {
+    /// The kind of entry.
+    pub kind: archive::EntryKind,
+    /// The size of the entry's contents, in bytes.
+    pub size: usize,
+    /// Where the filesystem keeps the contents of the entry. The meaning is up to the filesystem.
+    pub location: usize,
+}
+
+/// A read-only filesystem.
+pub trait FileSystem {
+    /// Find the entry at `path`.
+    ///
+    /// # Errors
+    /// - [`Error::NotFound`] if there is no entry at `path`.
+    /// - Any error reading the filesystem.
+    fn lookup(&self, path: &[u8]) -> Result<File, Error>;
+
+    /// Read the contents of `file` starting at `offset` into `buf`, returning the number of bytes
+    /// read. Fewer bytes than the length of `buf` are read at the end of the file.
+    ///
+    /// # Errors
+    /// - [`Error::NotAFile`] if `file` is not a regular file.
+    /// - Any error reading the filesystem.
+    fn read(&self, file: &File, offset: usize, buf: &mut [u8]) -> Result<usize, Error>;
+
+    /// Read the entire contents of `file`.
+    ///
+    /// # Errors
+    /// See [`Self::read`].
+    fn read_all(&self, file: &File) -> Result<Vec<u8>, Error> {
+        ensure!(file.kind == archive::EntryKind::File, NotAFileSnafu);
+        let mut data = vec![0; file.size];
+        let mut read = 0;
+        while read < data.len() {
+            let n = self.read(file, read, &mut data[read..])?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        data.truncate(read);
+        Ok(data)
+    }
+
+    /// Read the entire contents of the file at `path`.
+    ///
+    /// # Errors
+    /// See [`Self::lookup`] and [`Self::read`].
+    fn read_file(&self, path: &[u8]) -> Result<Vec<u8>, Error> {
+        let file = self.lookup(path)?;
+        self.read_all(&file)
+    }
+}
+
+/// Check if the entry with the tar-style `prefix` and `name` has the path `path`, ignoring any
+/// leading, trailing or repeated `/` and any `./`.
+fn has_path(prefix: &[u8], name: &[u8], path: &[u8]) -> bool {
+    archive::components(prefix)
+        .chain(archive::components(name))
+        .eq(archive::components(path))
+}
+
+/// Copy the part of `data` starting at `offset` into `buf`, returning the number of bytes copied.
+fn copy_from(data: &[u8], offset: usize, buf: &mut [u8]) -> usize {
+    let data = data.get(offset..).unwrap_or_default();
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_paths() {
+        assert!(has_path(b"", b"bin/init", b"/bin/init"));
+        assert!(has_path(b"", b"./bin/", b"bin"));
+        assert!(has_path(b"", b"bin//", b"./bin"));
+        assert!(has_path(b"", b"bin/init", b"bin//./init"));
+        assert!(has_path(b"", b"", b"/"));
+        assert!(has_path(b"", b"bin/init", b"bin/init"));
+        assert!(has_path(b"usr/lib", b"libc.so", b"usr/lib/libc.so"));
+        assert!(!has_path(b"usr/lib", b"libc.so", b"usr/libc.so"));
+        assert!(!has_path(b"", b"bin/init", b"bin"));
+    }
+
+    #[test]
+    fn copy_past_end() {
+        let mut buf = [0; 4];
+        assert_eq!(copy_from(b"hello", 0, &mut buf), 4);
+        assert_eq!(&buf, b"hell");
+        assert_eq!(copy_from(b"hello", 3, &mut buf), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(copy_from(b"hello", 9, &mut buf), 0);
+    }
+}
@@ -0,0 +1,214 @@
+//! Filesystem over a tar archive written directly to a block device, for example with
+//! `tar -cf disk.img -C root .`.
+//!
+//! Mounting reads every header on the device once to build an index of the entries, so lookups
+//! don't touch the device and reads go straight to the data of the file.
+use alloc::{vec, vec::Vec};
+use snafu::{ensure, ResultExt};
+
+use super::{copy_from, has_path, ArchiveSnafu, Error, File, FileSystem, IoSnafu};
+use crate::{
+    init::archive::{self, parse_tar_header, EntryKind, TAR_BLOCK_SIZE, TAR_MAGIC},
+    io::{self, SECTOR_SIZE},
+};
+
+/// The largest number of sectors read from the device at once.
+const MAX_READ_SECTORS: usize = 16;
+
+/// A device that can be read a sector at a time.
+pub trait SectorReader {
+    /// The number of sectors on the device.
+    fn num_sectors(&self) -> u64;
+
+    /// Read the sectors starting at `sector` into `buf`, whose length is a whole number of
+    /// sectors, waiting until the data is ready.
+    ///
+    /// # Errors
+    /// Returns an error if the request is invalid or the device could not carry it out.
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), io::Error>;
+}
+
+/// An entry found while mounting the filesystem.
+struct Entry {
+    prefix: Vec<u8>,
+    name: Vec<u8>,
+    file: File,
+}
+
+/// A filesystem whose files are the entries of a tar archive on a block device.
+pub struct TarFs<R> {
+    reader: R,
+    entries: Vec<Entry>,
+}
+
+impl<R: SectorReader> TarFs<R> {
+    /// Mount the archive on `reader`, reading the header of every entry.
+    ///
+    /// # Errors
+    /// - [`Error::Archive`] if the device does not hold a valid tar archive.
+    /// - [`Error::Io`] if the device could not be read.
+    pub fn mount(reader: R) -> Result<Self, Error> {
+        let num_sectors = reader.num_sectors();
+        let mut entries = Vec::new();
+        let mut header = [0; SECTOR_SIZE];
+        let mut sector = 0;
+        while sector < num_sectors {
+            reader.read_sectors(sector, &mut header).context(IoSnafu)?;
+            let offset = sector_offset(sector);
+            // an empty archive still starts with a header, so anything else isn't an archive
+            if sector == 0 && &header[257..262] != TAR_MAGIC {
+                return Err(archive::Error::UnknownFormat).context(ArchiveSnafu);
+            }
+            let Some(entry) = parse_tar_header(&header, offset).context(ArchiveSnafu)? else {
+                break;
+            };
+            let data_sectors = entry.size.div_ceil(TAR_BLOCK_SIZE) as u64;
+            if sector + 1 + data_sectors > num_sectors {
+                return Err(archive::Error::Malformed {
+                    offset,
+                    reason: "truncated data",
+                })
+                .context(ArchiveSnafu);
+            }
+            entries.push(Entry {
+                prefix: entry.prefix.to_vec(),
+                name: entry.name.to_vec(),
+                file: File {
+                    kind: entry.kind,
+                    size: entry.size,
+                    location: offset + TAR_BLOCK_SIZE,
+                },
+            });
+            sector += 1 + data_sectors;
+        }
+        Ok(Self { reader, entries })
+    }
+}
+
+/// The byte offset of `sector` on the device.
+// devices with more bytes than fit in a `usize` can't be addressed by the kernel anyway
+#[allow(clippy::cast_possible_truncation)]
+fn sector_offset(sector: u64) -> usize {
+    sector as usize * SECTOR_SIZE
+}
+
+impl<R: SectorReader> FileSystem for TarFs<R> {
+    fn lookup(&self, path: &[u8]) -> Result<File, Error> {
+        // later entries replace earlier ones with the same path
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| has_path(&entry.prefix, &entry.name, path))
+            .map(|entry| entry.file.clone())
+            .ok_or(Error::NotFound)
+    }
+
+    fn read(&self, file: &File, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        ensure!(file.kind == EntryKind::File, super::NotAFileSnafu);
+        let len = file.size.saturating_sub(offset).min(buf.len());
+        let mut scratch = vec![0; MAX_READ_SECTORS * SECTOR_SIZE];
+        let mut read = 0;
+        while read < len {
+            let start = file.location + offset + read;
+            let skip = start % SECTOR_SIZE;
+            let sectors = (skip + len - read)
+                .div_ceil(SECTOR_SIZE)
+                .min(MAX_READ_SECTORS);
+            let chunk = &mut scratch[..sectors * SECTOR_SIZE];
+            self.reader
+                .read_sectors((start / SECTOR_SIZE) as u64, chunk)
+                .context(IoSnafu)?;
+            read += copy_from(chunk, skip, &mut buf[read..len]);
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::init::archive::tests::{build_tar, tar_entry};
+
+    use super::*;
+
+    /// A device holding `data`, which counts the sectors read from it.
+    struct FakeDevice {
+        data: Vec<u8>,
+        sectors_read: Mutex<u64>,
+    }
+
+    impl FakeDevice {
+        fn new(mut data: Vec<u8>) -> Self {
+            data.resize(data.len().next_multiple_of(SECTOR_SIZE), 0);
+            Self {
+                data,
+                sectors_read: Mutex::new(0),
+            }
+        }
+    }
+
+    impl SectorReader for &FakeDevice {
+        fn num_sectors(&self) -> u64 {
+            (self.data.len() / SECTOR_SIZE) as u64
+        }
+
+        fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+            let count = io::check_request(self.num_sectors(), sector, buf.len())?;
+            let start = sector as usize * SECTOR_SIZE;
+            buf.copy_from_slice(&self.data[start..start + buf.len()]);
+            *self.sectors_read.lock().unwrap() += count;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mount_and_read() {
+        let mut data = build_tar();
+        // the archive only ends at the end of the device
+        data.truncate(data.len() - 2 * TAR_BLOCK_SIZE);
+        let big: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        tar_entry(&mut data, "lib/big", b'0', &big);
+        tar_entry(&mut data, "./bin/init", b'0', b"new init");
+        let device = FakeDevice::new(data);
+        let fs = TarFs::mount(&device).unwrap();
+        assert_eq!(fs.entries.len(), 5);
+
+        // lookups don't read the device
+        let headers = *device.sectors_read.lock().unwrap();
+        assert_eq!(fs.lookup(b"/bin").unwrap().kind, EntryKind::Directory);
+        assert!(matches!(fs.lookup(b"/bin/sh"), Err(Error::NotFound)));
+        assert_eq!(*device.sectors_read.lock().unwrap(), headers);
+
+        assert_eq!(fs.read_file(b"/bin/init").unwrap(), b"new init");
+        assert_eq!(fs.read_file(b"etc/config").unwrap(), [7; 600]);
+        assert_eq!(fs.read_file(b"lib/big").unwrap(), big);
+        let file = fs.lookup(b"lib/big").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(fs.read(&file, 19_996, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &big[19_996..]);
+        assert!(matches!(fs.read_file(b"bin"), Err(Error::NotAFile)));
+    }
+
+    #[test]
+    fn reject_bad_archives() {
+        let device = FakeDevice::new(vec![0; 4 * SECTOR_SIZE]);
+        assert!(matches!(
+            TarFs::mount(&device),
+            Err(Error::Archive {
+                source: archive::Error::UnknownFormat
+            })
+        ));
+
+        let mut data = Vec::new();
+        tar_entry(&mut data, "file", b'0', &[1; 2000]);
+        data.truncate(3 * SECTOR_SIZE);
+        let device = FakeDevice::new(data);
+        assert!(matches!(
+            TarFs::mount(&device),
+            Err(Error::Archive {
+                source: archive::Error::Malformed { offset: 0, .. }
+            })
+        ));
+    }
+}
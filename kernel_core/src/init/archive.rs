@@ -0,0 +1,477 @@
+//! Parser for initrd archives.
+//!
+//! Supports the POSIX `ustar` tar format and the SVR4 `newc` cpio format (as produced by
+//! `cpio -H newc`). Entries are borrowed directly from the archive bytes without copying.
+use snafu::{ensure, OptionExt as _, Snafu};
+
+use crate::memory::PhysicalAddress;
+
+pub(crate) const TAR_BLOCK_SIZE: usize = 512;
+pub(crate) const TAR_MAGIC: &[u8] = b"ustar";
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+
+/// Errors that arise while parsing an archive.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The archive is not in a known format.
+    UnknownFormat,
+    /// The archive contains an invalid entry.
+    #[snafu(display("malformed archive entry at offset {offset}: {reason}"))]
+    Malformed {
+        /// Offset of the entry header in the archive.
+        offset: usize,
+        /// Description of what is wrong with the entry.
+        reason: &'static str,
+    },
+}
+
+/// The format of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// POSIX `ustar` tar archive.
+    Tar,
+    /// SVR4 `newc` cpio archive.
+    Cpio,
+}
+
+/// The type of an entry in an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// Some other kind of entry, like a link or device node.
+    Other,
+}
+
+/// A single entry in an archive.
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    /// The path prefix of the entry, used by tar archives for long paths. Empty if unused.
+    pub prefix: &'a [u8],
+    /// The name of the entry.
+    pub name: &'a [u8],
+    /// The kind of entry.
+    pub kind: EntryKind,
+    /// The contents of the entry.
+    pub data: &'a [u8],
+}
+
+/// The components of `path`, skipping the empty and `.` components made by leading, trailing or
+/// repeated `/` and by `./`.
+pub(crate) fn components(path: &[u8]) -> impl Iterator<Item = &[u8]> + Clone {
+    path.split(|b| *b == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+}
+
+impl Entry<'_> {
+    /// Check if this entry has the path `path`, ignoring any leading, trailing or repeated `/` and
+    /// any `./`.
+    #[must_use]
+    pub fn has_path(&self, path: &[u8]) -> bool {
+        components(self.prefix)
+            .chain(components(self.name))
+            .eq(components(path))
+    }
+}
+
+/// An archive in memory.
+#[derive(Debug, Clone)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+    format: Format,
+}
+
+impl<'a> Archive<'a> {
+    /// Create an archive from its bytes, detecting the format.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownFormat`] if the bytes are not an archive in a supported format.
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let format = if data.starts_with(CPIO_MAGIC) {
+            Format::Cpio
+        } else if data.get(257..262).is_some_and(|m| m == TAR_MAGIC) {
+            Format::Tar
+        } else {
+            return Err(Error::UnknownFormat);
+        };
+        Ok(Self { data, format })
+    }
+
+    /// Create an archive from a region of physical memory.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownFormat`] if the region does not contain an archive in a supported format.
+    ///
+    /// # Safety
+    /// The region must be valid memory that is not modified for the lifetime `'a`.
+    pub unsafe fn from_physical(start: PhysicalAddress, len: usize) -> Result<Self, Error> {
+        let ptr: *mut u8 = start.cast().into();
+        Self::new(core::slice::from_raw_parts(ptr, len))
+    }
+
+    /// The bytes of the archive.
+    #[must_use]
+    pub fn bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The format of the archive.
+    #[must_use]
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Iterate over the entries in the archive.
+    #[must_use]
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            data: self.data,
+            format: self.format,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Find the entry with the given path, ignoring any leading `/` or `./`.
+    ///
+    /// # Errors
+    /// - [`Error::Malformed`] if a malformed entry is encountered before the entry is found.
+    pub fn find(&self, path: &[u8]) -> Result<Option<Entry<'a>>, Error> {
+        for entry in self.entries() {
+            let entry = entry?;
+            if entry.has_path(path) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Iterator over the entries in an [`Archive`].
+///
+/// If a malformed entry is encountered, an error is yielded and iteration stops.
+#[derive(Debug, Clone)]
+pub struct Entries<'a> {
+    data: &'a [u8],
+    format: Format,
+    offset: usize,
+    done: bool,
+}
+
+/// Parse a NUL-terminated (or full-width) field.
+fn c_field(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+/// Parse an integer field with the given radix, ignoring surrounding spaces and NUL bytes.
+fn parse_number(field: &[u8], radix: u32) -> Option<usize> {
+    let s = core::str::from_utf8(c_field(field)).ok()?.trim();
+    if s.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(s, radix).ok()
+}
+
+/// The fields of a tar header that describe an entry.
+pub(crate) struct TarHeader<'a> {
+    /// The path prefix of the entry, empty if unused.
+    pub prefix: &'a [u8],
+    /// The name of the entry.
+    pub name: &'a [u8],
+    /// The kind of entry.
+    pub kind: EntryKind,
+    /// The size of the entry's data, which follows the header padded to a whole block.
+    pub size: usize,
+}
+
+/// Parse the tar header block in `header`, found at `offset` in the archive.
+///
+/// Returns `None` at the zeroed blocks that mark the end of the archive.
+pub(crate) fn parse_tar_header(
+    header: &[u8],
+    offset: usize,
+) -> Result<Option<TarHeader<'_>>, Error> {
+    ensure!(
+        header.len() >= TAR_BLOCK_SIZE,
+        MalformedSnafu {
+            offset,
+            reason: "truncated header"
+        }
+    );
+    // the end of the archive is marked by zeroed blocks
+    if header[0] == 0 {
+        return Ok(None);
+    }
+    ensure!(
+        &header[257..262] == TAR_MAGIC,
+        MalformedSnafu {
+            offset,
+            reason: "bad magic"
+        }
+    );
+    let size = parse_number(&header[124..136], 8).context(MalformedSnafu {
+        offset,
+        reason: "invalid size",
+    })?;
+    let kind = match header[156] {
+        b'0' | 0 => EntryKind::File,
+        b'5' => EntryKind::Directory,
+        _ => EntryKind::Other,
+    };
+    Ok(Some(TarHeader {
+        prefix: c_field(&header[345..500]),
+        name: c_field(&header[0..100]),
+        kind,
+        size,
+    }))
+}
+
+impl<'a> Entries<'a> {
+    fn next_tar(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        let header = self
+            .data
+            .get(self.offset..self.offset + TAR_BLOCK_SIZE)
+            .context(MalformedSnafu {
+                offset: self.offset,
+                reason: "truncated header",
+            })?;
+        let Some(header) = parse_tar_header(header, self.offset)? else {
+            return Ok(None);
+        };
+        let data_start = self.offset + TAR_BLOCK_SIZE;
+        let data = data_start
+            .checked_add(header.size)
+            .and_then(|end| self.data.get(data_start..end))
+            .context(MalformedSnafu {
+                offset: self.offset,
+                reason: "truncated data",
+            })?;
+        let entry = Entry {
+            prefix: header.prefix,
+            name: header.name,
+            kind: header.kind,
+            data,
+        };
+        self.offset = data_start + header.size.next_multiple_of(TAR_BLOCK_SIZE);
+        Ok(Some(entry))
+    }
+
+    fn next_cpio(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        let header = self
+            .data
+            .get(self.offset..self.offset + CPIO_HEADER_SIZE)
+            .context(MalformedSnafu {
+                offset: self.offset,
+                reason: "truncated header",
+            })?;
+        ensure!(
+            header.starts_with(CPIO_MAGIC),
+            MalformedSnafu {
+                offset: self.offset,
+                reason: "bad magic"
+            }
+        );
+        // header fields are 8 hex digits each, starting after the magic
+        let field = |i: usize| {
+            let start = CPIO_MAGIC.len() + i * 8;
+            parse_number(&header[start..start + 8], 16).context(MalformedSnafu {
+                offset: self.offset,
+                reason: "invalid header field",
+            })
+        };
+        let mode = field(1)?;
+        let file_size = field(6)?;
+        let name_size = field(11)?;
+
+        let name_start = self.offset + CPIO_HEADER_SIZE;
+        let name = name_start
+            .checked_add(name_size)
+            .and_then(|end| self.data.get(name_start..end))
+            .context(MalformedSnafu {
+                offset: self.offset,
+                reason: "truncated name",
+            })?;
+        let name = c_field(name);
+        if name == CPIO_TRAILER {
+            return Ok(None);
+        }
+
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = data_start
+            .checked_add(file_size)
+            .and_then(|end| self.data.get(data_start..end))
+            .context(MalformedSnafu {
+                offset: self.offset,
+                reason: "truncated data",
+            })?;
+        let kind = match mode & 0o170_000 {
+            0o100_000 => EntryKind::File,
+            0o040_000 => EntryKind::Directory,
+            _ => EntryKind::Other,
+        };
+        self.offset = (data_start + file_size).next_multiple_of(4);
+        Ok(Some(Entry {
+            prefix: &[],
+            name,
+            kind,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = match self.format {
+            Format::Tar => self.next_tar(),
+            Format::Cpio => self.next_cpio(),
+        };
+        match result {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    pub fn tar_entry(out: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+        let mut header = [0u8; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}", data.len());
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        out.extend_from_slice(&header);
+        out.extend_from_slice(data);
+        out.resize(out.len().next_multiple_of(TAR_BLOCK_SIZE), 0);
+    }
+
+    pub fn build_tar() -> Vec<u8> {
+        let mut out = Vec::new();
+        tar_entry(&mut out, "./bin/", b'5', &[]);
+        tar_entry(&mut out, "./bin/init", b'0', b"init program");
+        tar_entry(&mut out, "./etc/config", b'0', &[7; 600]);
+        out.extend_from_slice(&[0; TAR_BLOCK_SIZE * 2]);
+        out
+    }
+
+    pub fn cpio_entry(out: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let header = format!(
+            "070701{:08x}{mode:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            0,
+            0,
+            0,
+            1,
+            0,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0
+        );
+        out.extend_from_slice(header.as_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize(out.len().next_multiple_of(4), 0);
+        out.extend_from_slice(data);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    pub fn build_cpio() -> Vec<u8> {
+        let mut out = Vec::new();
+        cpio_entry(&mut out, "bin", 0o040_755, &[]);
+        cpio_entry(&mut out, "bin/init", 0o100_755, b"init program");
+        cpio_entry(&mut out, "etc/config", 0o100_644, &[7; 601]);
+        cpio_entry(&mut out, "TRAILER!!!", 0, &[]);
+        out
+    }
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(Archive::new(&build_tar()).unwrap().format(), Format::Tar);
+        assert_eq!(Archive::new(&build_cpio()).unwrap().format(), Format::Cpio);
+        assert!(matches!(
+            Archive::new(&[0; 1024]),
+            Err(Error::UnknownFormat)
+        ));
+    }
+
+    #[test]
+    fn iterate_tar() {
+        let data = build_tar();
+        let archive = Archive::new(&data).unwrap();
+        let entries: Vec<_> = archive.entries().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].kind, EntryKind::Directory);
+        assert_eq!(entries[1].name, b"./bin/init");
+        assert_eq!(entries[1].data, b"init program");
+        assert_eq!(entries[2].data, &[7; 600]);
+    }
+
+    #[test]
+    fn iterate_cpio() {
+        let data = build_cpio();
+        let archive = Archive::new(&data).unwrap();
+        let entries: Vec<_> = archive.entries().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].kind, EntryKind::Directory);
+        assert_eq!(entries[1].name, b"bin/init");
+        assert_eq!(entries[1].kind, EntryKind::File);
+        assert_eq!(entries[1].data, b"init program");
+        assert_eq!(entries[2].data, &[7; 601]);
+    }
+
+    #[test]
+    fn find_init() {
+        for data in [build_tar(), build_cpio()] {
+            let archive = Archive::new(&data).unwrap();
+            let init = archive.find(b"/bin/init").unwrap().expect("find init");
+            assert_eq!(init.data, b"init program");
+            assert!(archive.find(b"/bin/missing").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn tar_prefix_path() {
+        let mut data = Vec::new();
+        tar_entry(&mut data, "init", b'0', b"x");
+        data[345..348].copy_from_slice(b"bin");
+        data.extend_from_slice(&[0; TAR_BLOCK_SIZE * 2]);
+        let archive = Archive::new(&data).unwrap();
+        assert!(archive.find(b"/bin/init").unwrap().is_some());
+    }
+
+    #[test]
+    fn truncated_archive() {
+        let mut data = build_cpio();
+        data.truncate(200);
+        let archive = Archive::new(&data).unwrap();
+        let mut entries = archive.entries();
+        assert!(entries.next().unwrap().is_ok());
+        assert!(matches!(entries.next(), Some(Err(Error::Malformed { .. }))));
+        assert!(entries.next().is_none());
+    }
+}
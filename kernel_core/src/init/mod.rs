@@ -0,0 +1,88 @@
+//! Locating and loading the `init` process.
+//!
+//! The bootloader provides an initial RAM disk (initrd) archive in memory, whose location is given by
+//! the `/chosen/linux,initrd-start` and `/chosen/linux,initrd-end` properties in the device tree.
+//! The `init` executable is loaded from this archive.
+use crate::{
+    memory::PhysicalAddress,
+    platform::device_tree::{DeviceTree, ParseError, Value},
+};
+
+pub mod archive;
+
+fn parse_initrd_address<'dt>(
+    name: &'dt [u8],
+    value: &Value<'dt>,
+) -> Result<usize, ParseError<'dt>> {
+    match value {
+        Value::U32(v) => Ok(*v as usize),
+        Value::U64(v) => usize::try_from(*v).map_err(|_| ParseError::UnexpectedValue {
+            name,
+            value: value.clone(),
+            reason: "address too large",
+        }),
+        _ => Err(ParseError::UnexpectedType {
+            name,
+            value: value.clone(),
+            expected_type: "u32 or u64",
+        }),
+    }
+}
+
+/// Find the physical memory region (start, length in bytes) that contains the initrd archive.
+///
+/// Returns `None` if the device tree does not specify an initrd.
+///
+/// # Errors
+/// - [`ParseError::PropertyNotFound`] if only one of the start/end properties is present.
+/// - [`ParseError::UnexpectedType`] if the properties are not integers.
+/// - [`ParseError::UnexpectedValue`] if the end of the region is before the start.
+pub fn initrd_region<'dt>(
+    dt: &'dt DeviceTree<'dt>,
+) -> Result<Option<(PhysicalAddress, usize)>, ParseError<'dt>> {
+    let Some(props) = dt.iter_node_properties(b"/chosen") else {
+        return Ok(None);
+    };
+
+    let mut start = None;
+    let mut end = None;
+    for (name, value) in props {
+        match name {
+            b"linux,initrd-start" => start = Some(parse_initrd_address(name, &value)?),
+            b"linux,initrd-end" => end = Some((name, value)),
+            _ => {}
+        }
+    }
+
+    match (start, end) {
+        (None, None) => Ok(None),
+        (Some(_), None) => Err(ParseError::PropertyNotFound {
+            name: "linux,initrd-end",
+        }),
+        (None, Some(_)) => Err(ParseError::PropertyNotFound {
+            name: "linux,initrd-start",
+        }),
+        (Some(start), Some((end_name, end_value))) => {
+            let end = parse_initrd_address(end_name, &end_value)?;
+            if end < start {
+                return Err(ParseError::UnexpectedValue {
+                    name: end_name,
+                    value: end_value,
+                    reason: "initrd end is before start",
+                });
+            }
+            Ok(Some((PhysicalAddress::from(start), end - start)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_initrd_in_tree() {
+        let dt = DeviceTree::from_bytes(include_bytes!("../platform/device_tree/test-tree.fdt"));
+        assert!(initrd_region(&dt).unwrap().is_none());
+    }
+}
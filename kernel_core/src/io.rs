@@ -0,0 +1,386 @@
+//! Kernel interfaces to storage devices.
+//!
+//! Transfers are asynchronous: a request is submitted to the device and the caller is told when it
+//! finishes through a [`Notification`], so that a thread can block until the data is ready
+//! instead of the device being polled.
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use snafu::{ResultExt, Snafu};
+
+use crate::{
+    ipc::Notification,
+    memory::{AllocationConstraints, DmaAllocator, PageAllocator, PhysicalAddress},
+};
+
+/// The size of a sector of a block device, in bytes.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Errors submitting a request to a block device.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The request extends past the end of the device.
+    #[snafu(display("{count} sectors starting at {sector} are out of range"))]
+    OutOfRange {
+        /// The first sector of the request.
+        sector: u64,
+        /// The number of sectors in the request.
+        count: u64,
+    },
+    /// The length of the buffer is not a non-zero multiple of [`SECTOR_SIZE`].
+    #[snafu(display("buffer length {len} is not a whole number of sectors"))]
+    Unaligned {
+        /// The length of the buffer in bytes.
+        len: usize,
+    },
+    /// The device can't be written to.
+    ReadOnly,
+    /// The device has too many requests in flight to accept another.
+    Busy,
+    /// The device did not finish the request in time.
+    Timeout,
+    /// The device could not carry out the request.
+    #[snafu(display("device finished request with status {status:?}"))]
+    Device {
+        /// The status the request finished with.
+        status: Status,
+    },
+    /// A buffer for the request could not be allocated.
+    #[snafu(display("allocate buffer for request"))]
+    Memory {
+        /// Underlying memory error.
+        source: crate::memory::Error,
+    },
+}
+
+/// The state of a block device request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    /// The request has not finished yet.
+    Pending = 0,
+    /// The request finished successfully.
+    Done,
+    /// The device reported an I/O error.
+    Failed,
+    /// The device does not support the request.
+    Unsupported,
+}
+
+/// Reports the outcome of a block device request by raising flags on a notification.
+pub struct Completion {
+    status: AtomicU8,
+    notification: Arc<Notification>,
+    flags: u64,
+}
+
+impl Completion {
+    /// Create a pending completion that raises `flags` on `notification` once the request finishes.
+    #[must_use]
+    pub fn new(notification: Arc<Notification>, flags: u64) -> Self {
+        Self {
+            status: AtomicU8::new(Status::Pending as u8),
+            notification,
+            flags,
+        }
+    }
+
+    /// The current state of the request.
+    #[must_use]
+    pub fn status(&self) -> Status {
+        match self.status.load(Ordering::Acquire) {
+            0 => Status::Pending,
+            1 => Status::Done,
+            2 => Status::Failed,
+            _ => Status::Unsupported,
+        }
+    }
+
+    /// Record that the request finished with `status`, and signal the notification.
+    ///
+    /// This is called by the device driver.
+    pub fn complete(&self, status: Status) {
+        self.status.store(status as u8, Ordering::Release);
+        self.notification.signal(self.flags);
+    }
+}
+
+/// A storage device that is read and written in sectors of [`SECTOR_SIZE`] bytes.
+pub trait BlockDevice: Send + Sync {
+    /// The number of sectors on the device.
+    fn num_sectors(&self) -> u64;
+
+    /// Returns true if the device can't be written to.
+    fn read_only(&self) -> bool;
+
+    /// Start reading the sectors starting at `sector` into the `len` bytes of physical memory at
+    /// `buffer`. `completion` is completed once the data is in the buffer.
+    ///
+    /// # Safety
+    /// The buffer must remain valid, and must not be otherwise accessed, until the request
+    /// finishes.
+    ///
+    /// # Errors
+    /// - [`Error::Unaligned`] if `len` is not a whole number of sectors.
+    /// - [`Error::OutOfRange`] if the sectors extend past the end of the device.
+    /// - [`Error::Busy`] if the device can't accept another request right now.
+    unsafe fn read(
+        &self,
+        sector: u64,
+        buffer: PhysicalAddress,
+        len: usize,
+        completion: Arc<Completion>,
+    ) -> Result<(), Error>;
+
+    /// Start writing the `len` bytes of physical memory at `buffer` to the sectors starting at
+    /// `sector`. `completion` is completed once the data has been written.
+    ///
+    /// # Safety
+    /// The buffer must remain valid, and must not be modified, until the request finishes.
+    ///
+    /// # Errors
+    /// - [`Error::ReadOnly`] if the device can't be written to.
+    /// - Any of the errors of [`Self::read`].
+    unsafe fn write(
+        &self,
+        sector: u64,
+        buffer: PhysicalAddress,
+        len: usize,
+        completion: Arc<Completion>,
+    ) -> Result<(), Error>;
+}
+
+/// Check that a request for the `len` bytes starting at `sector` fits on a device with
+/// `num_sectors` sectors, returning the number of sectors in the request.
+///
+/// # Errors
+/// - [`Error::Unaligned`] if `len` is not a whole number of sectors.
+/// - [`Error::OutOfRange`] if the sectors extend past the end of the device.
+pub fn check_request(num_sectors: u64, sector: u64, len: usize) -> Result<u64, Error> {
+    snafu::ensure!(
+        len > 0 && len.is_multiple_of(SECTOR_SIZE),
+        UnalignedSnafu { len }
+    );
+    let count = (len / SECTOR_SIZE) as u64;
+    snafu::ensure!(
+        sector
+            .checked_add(count)
+            .is_some_and(|end| end <= num_sectors),
+        OutOfRangeSnafu { sector, count }
+    );
+    Ok(count)
+}
+
+/// The flag raised on the notification of a request made by [`read_blocking`].
+const REQUEST_FINISHED: u64 = 1;
+
+/// Read the sectors starting at `sector` from `device` into `buf`, blocking until the request
+/// finishes.
+///
+/// `wait(notification, flags)` is called while the request is pending, and must block until one of
+/// `flags` is raised on `notification`. It returns false to give up on the request.
+///
+/// The data is read through a buffer allocated from `dma`, so `buf` can be anywhere in memory. If
+/// the request is given up on, the device may still write to that buffer, so it is leaked.
+///
+/// # Errors
+/// - [`Error::Memory`] if the buffer for the device could not be allocated.
+/// - [`Error::Device`] if the request did not finish successfully.
+/// - [`Error::Timeout`] if `wait` gave up on the request.
+/// - Any error submitting the request (see [`BlockDevice::read`]).
+pub fn read_blocking(
+    device: &dyn BlockDevice,
+    sector: u64,
+    buf: &mut [u8],
+    dma: &DmaAllocator<impl PageAllocator>,
+    mut wait: impl FnMut(&Notification, u64) -> bool,
+) -> Result<(), Error> {
+    check_request(device.num_sectors(), sector, buf.len())?;
+    let bounce = dma
+        .allocate(buf.len(), &AllocationConstraints::default())
+        .context(MemorySnafu)?;
+    let notification = Arc::new(Notification::new());
+    let completion = Arc::new(Completion::new(notification.clone(), REQUEST_FINISHED));
+    // SAFETY: the buffer is only freed once the request has finished
+    let result = unsafe {
+        device.read(
+            sector,
+            bounce.physical_address(),
+            buf.len(),
+            completion.clone(),
+        )
+    }
+    .and_then(|()| loop {
+        match completion.status() {
+            Status::Pending => {
+                if !wait(&notification, REQUEST_FINISHED) {
+                    return Err(Error::Timeout);
+                }
+            }
+            Status::Done => {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(bounce.as_ptr(), buf.as_mut_ptr(), buf.len());
+                }
+                return Ok(());
+            }
+            status => return DeviceSnafu { status }.fail(),
+        }
+    });
+    // the device may still write to the buffer of a request that was given up on, so it is leaked
+    if !matches!(result, Err(Error::Timeout)) {
+        // the buffer was allocated by `dma`, so this can't fail
+        let _ = dma.free(bounce);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{tests::MockPageAllocator, PageSize};
+
+    use super::*;
+
+    /// The sector, buffer address, length and completion of a request.
+    type Request = (u64, usize, usize, Arc<Completion>);
+
+    /// A device whose sectors are each filled with their sector number, and that completes every
+    /// request after the caller waits once.
+    struct FakeDisk {
+        pending: crate::sync::Mutex<Option<Request>>,
+        status: Status,
+    }
+
+    impl FakeDisk {
+        fn finish(&self) {
+            let (sector, buffer, len, completion) = self.pending.lock().take().unwrap();
+            let data: *mut u8 = PhysicalAddress::from(buffer).cast().into();
+            for i in 0..len {
+                unsafe { data.add(i).write(sector as u8 + (i / SECTOR_SIZE) as u8) };
+            }
+            completion.complete(self.status);
+        }
+    }
+
+    impl BlockDevice for FakeDisk {
+        fn num_sectors(&self) -> u64 {
+            4
+        }
+
+        fn read_only(&self) -> bool {
+            true
+        }
+
+        unsafe fn read(
+            &self,
+            sector: u64,
+            buffer: PhysicalAddress,
+            len: usize,
+            completion: Arc<Completion>,
+        ) -> Result<(), Error> {
+            *self.pending.lock() = Some((sector, usize::from(buffer), len, completion));
+            Ok(())
+        }
+
+        unsafe fn write(
+            &self,
+            _sector: u64,
+            _buffer: PhysicalAddress,
+            _len: usize,
+            _completion: Arc<Completion>,
+        ) -> Result<(), Error> {
+            Err(Error::ReadOnly)
+        }
+    }
+
+    #[test]
+    fn blocking_read() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 4);
+        let dma = DmaAllocator::new(&pa);
+        let disk = FakeDisk {
+            pending: crate::sync::Mutex::new(None),
+            status: Status::Done,
+        };
+        let mut buf = alloc::vec![0; 2 * SECTOR_SIZE];
+        read_blocking(&disk, 2, &mut buf, &dma, |notification, flags| {
+            disk.finish();
+            assert_eq!(notification.pending(), flags);
+            true
+        })
+        .unwrap();
+        assert!(buf[..SECTOR_SIZE].iter().all(|b| *b == 2));
+        assert!(buf[SECTOR_SIZE..].iter().all(|b| *b == 3));
+
+        let disk = FakeDisk {
+            pending: crate::sync::Mutex::new(None),
+            status: Status::Failed,
+        };
+        assert!(matches!(
+            read_blocking(&disk, 0, &mut buf, &dma, |_, _| {
+                disk.finish();
+                true
+            }),
+            Err(Error::Device {
+                status: Status::Failed
+            })
+        ));
+        assert!(matches!(
+            read_blocking(&disk, 3, &mut buf, &dma, |_, _| true),
+            Err(Error::OutOfRange { .. })
+        ));
+        pa.end_check();
+    }
+
+    #[test]
+    fn blocking_read_times_out() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 4);
+        let dma = DmaAllocator::new(&pa);
+        let disk = FakeDisk {
+            pending: crate::sync::Mutex::new(None),
+            status: Status::Done,
+        };
+        let mut buf = alloc::vec![0; SECTOR_SIZE];
+        assert!(matches!(
+            read_blocking(&disk, 0, &mut buf, &dma, |_, _| false),
+            Err(Error::Timeout)
+        ));
+        // the device still has the buffer, and can finish late without harm
+        disk.finish();
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn check_requests() {
+        assert_eq!(check_request(8, 0, SECTOR_SIZE * 8).unwrap(), 8);
+        assert_eq!(check_request(8, 7, SECTOR_SIZE).unwrap(), 1);
+        assert!(matches!(
+            check_request(8, 0, 0),
+            Err(Error::Unaligned { len: 0 })
+        ));
+        assert!(matches!(
+            check_request(8, 0, 100),
+            Err(Error::Unaligned { len: 100 })
+        ));
+        assert!(matches!(
+            check_request(8, 7, SECTOR_SIZE * 2),
+            Err(Error::OutOfRange {
+                sector: 7,
+                count: 2
+            })
+        ));
+        assert!(matches!(
+            check_request(8, u64::MAX, SECTOR_SIZE),
+            Err(Error::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn completion_signals_notification() {
+        let notification = Arc::new(Notification::new());
+        let completion = Completion::new(notification.clone(), 0b100);
+        assert_eq!(completion.status(), Status::Pending);
+        assert_eq!(notification.pending(), 0);
+        completion.complete(Status::Failed);
+        assert_eq!(completion.status(), Status::Failed);
+        assert_eq!(notification.pending(), 0b100);
+    }
+}
@@ -0,0 +1,281 @@
+//! Synchronous calls, which send a request and block the caller until the receiver replies.
+//!
+//! [`call`] sends a message together with a [`Reply`], the single-use capability to answer it,
+//! which the receiver gets with the message in [`ReceivedMessage::reply`](super::ReceivedMessage).
+//! Replying copies the answer straight to the caller and makes it runnable, so a round trip only
+//! needs the receiver to be scheduled once. Each call gets exactly one answer: either its reply, or
+//! [`Error::NoReply`] if every reference to the [`Reply`] is dropped without replying, for instance
+//! because the receiver exited.
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+use log::trace;
+use snafu::{ensure, OptionExt as _};
+
+use super::{
+    AlreadyRepliedSnafu, CancelledSnafu, Error, InvalidLengthSnafu, MessageBlock, MessageQueue,
+    NoReplySnafu, WouldBlockSnafu, MAX_MESSAGE_BLOCKS,
+};
+use crate::{
+    memory::PageAllocator,
+    process::thread::{
+        wait::{block_current, Timeout, Waiter},
+        Scheduler, WaitOutcome, WaitReason,
+    },
+    sync::Mutex,
+};
+
+enum CallState {
+    /// The call has not been answered, and the caller is blocked on it if there is a waiter.
+    Waiting(Option<Waiter>),
+    /// The receiver replied with a message that the caller has not taken yet.
+    Replied(Vec<MessageBlock>),
+    /// The reply was taken by the caller.
+    Taken,
+    /// The receiver dropped the reply capability without replying.
+    Abandoned,
+}
+
+impl CallState {
+    /// Answer the call with `state`, waking the caller if it is blocked.
+    fn answer(&mut self, state: CallState) {
+        if let CallState::Waiting(Some((waiter, token))) = core::mem::replace(self, state) {
+            if waiter.end_wait(token, WaitOutcome::Signaled) {
+                trace!("waking thread {} for reply", waiter.id);
+            }
+        }
+    }
+}
+
+/// The caller's end of a call, through which it receives the reply.
+pub struct Call {
+    state: Arc<Mutex<CallState>>,
+}
+
+/// The single-use capability to reply to a call, sent to the receiver with the call's message.
+pub struct Reply {
+    state: Arc<Mutex<CallState>>,
+}
+
+/// Send `message` to `queue` as a call, and block the current thread (given by `scheduler`) until
+/// the receiver replies or the `timeout` passes, advancing the scheduler to the next time slice.
+///
+/// Once the thread resumes, [`Call::take_reply`] returns the reply, and
+/// [`Thread::take_wait_outcome`](crate::process::thread::Thread::take_wait_outcome) tells how the
+/// wait ended.
+///
+/// # Errors
+/// - Any error from [`Call::send`].
+/// - [`Error::Cancelled`] if the current thread's waits have been cancelled. The message has still
+///   been sent.
+pub fn call<PA: PageAllocator>(
+    scheduler: &impl Scheduler,
+    queue: &MessageQueue<'_, PA>,
+    message: &[MessageBlock],
+    timeout: Option<Timeout>,
+) -> Result<Call, Error> {
+    let call = Call::send(queue, message)?;
+    match call.wait(scheduler, timeout) {
+        Ok(()) | Err(Error::Blocked) => Ok(call),
+        Err(e) => Err(e),
+    }
+}
+
+impl Call {
+    /// Send `message` to `queue` with a new [`Reply`], without blocking.
+    ///
+    /// # Errors
+    /// The same as [`MessageQueue::send`].
+    pub fn send<PA: PageAllocator>(
+        queue: &MessageQueue<'_, PA>,
+        message: &[MessageBlock],
+    ) -> Result<Self, Error> {
+        let state = Arc::new(Mutex::new(CallState::Waiting(None)));
+        queue.send_call(
+            message,
+            Arc::new(Reply {
+                state: state.clone(),
+            }),
+        )?;
+        Ok(Self { state })
+    }
+
+    /// Block the current thread (given by `scheduler`) until the call is answered or the `timeout`
+    /// passes, advancing the scheduler to the next time slice. Returns immediately if the call has
+    /// already been answered.
+    ///
+    /// # Errors
+    /// - [`Error::Blocked`] if the current thread was blocked. The reply should be taken once the
+    ///   thread is resumed.
+    /// - [`Error::Cancelled`] if the current thread's waits have been cancelled.
+    pub fn wait(&self, scheduler: &impl Scheduler, timeout: Option<Timeout>) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        let CallState::Waiting(waiter) = &mut *state else {
+            return Ok(());
+        };
+        let (thread, token) =
+            block_current(scheduler, WaitReason::Message, timeout).context(CancelledSnafu)?;
+        trace!("blocking thread {} for reply", thread.id);
+        *waiter = Some((thread, token));
+        drop(state);
+        scheduler.next_time_slice();
+        Err(Error::Blocked)
+    }
+
+    /// Take the reply to the call.
+    ///
+    /// # Errors
+    /// - [`Error::WouldBlock`] if the call has not been answered yet.
+    /// - [`Error::NoReply`] if the receiver dropped the reply capability without replying, or the
+    ///   reply has already been taken.
+    pub fn take_reply(&self) -> Result<Vec<MessageBlock>, Error> {
+        let mut state = self.state.lock();
+        match core::mem::replace(&mut *state, CallState::Taken) {
+            CallState::Replied(message) => Ok(message),
+            CallState::Waiting(waiter) => {
+                *state = CallState::Waiting(waiter);
+                WouldBlockSnafu.fail()
+            }
+            CallState::Taken | CallState::Abandoned => NoReplySnafu.fail(),
+        }
+    }
+}
+
+impl Reply {
+    /// Answer the call with `message`, waking the caller if it is blocked.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidLength`] if the message is empty or longer than [`MAX_MESSAGE_BLOCKS`].
+    /// - [`Error::AlreadyReplied`] if the call has already been answered.
+    pub fn reply(&self, message: &[MessageBlock]) -> Result<(), Error> {
+        ensure!(
+            (1..=MAX_MESSAGE_BLOCKS).contains(&message.len()),
+            InvalidLengthSnafu
+        );
+        let mut state = self.state.lock();
+        ensure!(matches!(*state, CallState::Waiting(_)), AlreadyRepliedSnafu);
+        state.answer(CallState::Replied(message.to_vec()));
+        Ok(())
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if matches!(*state, CallState::Waiting(_)) {
+            state.answer(CallState::Abandoned);
+        }
+    }
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reply")
+            .field("state", &Arc::as_ptr(&self.state))
+            .finish()
+    }
+}
+
+impl PartialEq for Reply {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl Eq for Reply {}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        ipc::{ReceiveFlags, MESSAGE_BLOCK_SIZE},
+        memory::{tests::MockPageAllocator, PageSize},
+        process::thread::{MockScheduler, ProcessorState, State, Thread, MAX_THREAD_ID},
+    };
+
+    fn message(len: usize, fill: u8) -> Vec<MessageBlock> {
+        vec![MessageBlock([fill; MESSAGE_BLOCK_SIZE]); len]
+    }
+
+    fn blocking_scheduler(thread: &Arc<Thread>) -> MockScheduler {
+        let mut sched = MockScheduler::new();
+        let t = thread.clone();
+        sched
+            .expect_current_thread()
+            .once()
+            .returning(move || t.clone());
+        sched.expect_next_time_slice().once().return_const(());
+        sched
+    }
+
+    #[test]
+    fn reply_wakes_caller() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let caller = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let sched = blocking_scheduler(&caller);
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            let c = call(&sched, &q, &message(2, 1), None).unwrap();
+            assert_eq!(caller.state(), State::Blocked);
+            assert!(matches!(c.take_reply(), Err(Error::WouldBlock)));
+
+            let m = q
+                .receive(&MockScheduler::new(), ReceiveFlags::default(), None)
+                .unwrap();
+            assert_eq!(unsafe { m.as_slice() }, message(2, 1).as_slice());
+            let reply = m.reply.as_ref().unwrap();
+            assert!(matches!(reply.reply(&[]), Err(Error::InvalidLength)));
+            reply.reply(&message(1, 2)).unwrap();
+            assert_eq!(caller.state(), State::Running);
+            assert_eq!(caller.take_wait_outcome(), Some(WaitOutcome::Signaled));
+            // a call only gets one answer
+            assert!(matches!(
+                reply.reply(&message(1, 3)),
+                Err(Error::AlreadyReplied)
+            ));
+            drop(m);
+            assert_eq!(c.take_reply().unwrap(), message(1, 2));
+            assert!(matches!(c.take_reply(), Err(Error::NoReply)));
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn dropped_reply_wakes_caller() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let caller = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let sched = blocking_scheduler(&caller);
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            let c = call(&sched, &q, &message(1, 1), None).unwrap();
+            // plain messages don't carry a reply
+            q.send(&message(1, 2)).unwrap();
+            let m = q
+                .receive(&MockScheduler::new(), ReceiveFlags::default(), None)
+                .unwrap();
+            let reply = m.reply.unwrap();
+            let m2 = q
+                .receive(&MockScheduler::new(), ReceiveFlags::default(), None)
+                .unwrap();
+            assert_eq!(m2.reply, None);
+
+            drop(reply);
+            assert_eq!(caller.state(), State::Running);
+            assert!(matches!(c.take_reply(), Err(Error::NoReply)));
+
+            // calls still in the queue are answered when the queue is destroyed
+            let c = Call::send(&q, &message(1, 3)).unwrap();
+            drop(q);
+            assert!(matches!(c.take_reply(), Err(Error::NoReply)));
+        }
+        pa.end_check();
+    }
+}
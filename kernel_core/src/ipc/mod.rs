@@ -0,0 +1,104 @@
+//! Inter-process communication via message passing.
+//!
+//! Messages consist of 64-byte blocks and can be a maximum of 16 blocks long.
+//! The kernel stores messages that are in transit in a per-thread [`MessageQueue`], which holds
+//! the message data in pages allocated for the receiver until the receiver marks them as read.
+//! Threads can also signal events to each other cheaply with a [`Notification`], or make a
+//! synchronous [`call`] that blocks until the receiver replies. A [`WaitSet`] lets a single thread
+//! block on several queues and notifications at once.
+//! See `spec/kernel.md` for the full description.
+use snafu::Snafu;
+
+use crate::memory::PhysicalAddress;
+
+mod queue;
+pub use queue::{MessageQueue, ReceivedMessage};
+
+mod notification;
+pub use notification::Notification;
+
+mod call;
+pub use call::{call, Call, Reply};
+
+mod wait_set;
+pub use wait_set::{Observer, WaitSet, Waitable};
+
+crate::tracepoints! {
+    /// A message was sent to a queue. Arguments: number of blocks, number of pages moved with it.
+    MESSAGE_SENT;
+    /// A message was received from a queue. Arguments: number of blocks, number of pages moved
+    /// with it.
+    MESSAGE_RECEIVED;
+    /// A notification was signaled. Arguments: flags raised, flags pending afterwards.
+    NOTIFICATION_SIGNALED;
+}
+
+/// The size of a single message block in bytes.
+pub const MESSAGE_BLOCK_SIZE: usize = 64;
+
+/// The maximum number of blocks a single message can contain.
+pub const MAX_MESSAGE_BLOCKS: usize = 16;
+
+/// A single 64-byte block of a message.
+///
+/// The first block in a message contains the message header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C, align(8))]
+pub struct MessageBlock(pub [u8; MESSAGE_BLOCK_SIZE]);
+
+impl Default for MessageBlock {
+    fn default() -> Self {
+        Self([0; MESSAGE_BLOCK_SIZE])
+    }
+}
+
+/// Physical pages attached to a message, which are moved from the sender's address space to the
+/// receiver's instead of being copied.
+///
+/// The transfer owns the sender's references to the pages in the page frame database. Pages are
+/// detached from the sender with [`Process::detach_pages`](crate::process::Process::detach_pages)
+/// and attached to the receiver with [`Process::attach_pages`](crate::process::Process::attach_pages).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageTransfer {
+    /// The start of the pages.
+    pub physical_start: PhysicalAddress,
+    /// The number of pages.
+    pub num_pages: usize,
+}
+
+bitfield::bitfield! {
+    /// Flags for receiving a message.
+    #[derive(Copy, Clone, Default, PartialEq, Eq)]
+    pub struct ReceiveFlags(u32);
+    impl Debug;
+    /// Return [`Error::WouldBlock`] if there are no messages instead of blocking the thread.
+    pub nonblocking, set_nonblocking: 0;
+}
+
+/// Errors that arise during message passing.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The length of the message is invalid, i.e. not in `1..=MAX_MESSAGE_BLOCKS`.
+    InvalidLength,
+    /// The receiving queue does not have enough free space to hold the message.
+    InboxFull,
+    /// There are no messages (or notification flags) to receive and the receive was non-blocking.
+    WouldBlock,
+    /// There are no messages (or notification flags) to receive, so the current thread was blocked.
+    /// The receive should be retried once the thread is resumed.
+    Blocked,
+    /// The current thread's waits have been cancelled, so it can't block.
+    Cancelled,
+    /// A message was referenced that is not known to the queue.
+    UnknownMessage,
+    /// The receiver of a call dropped its reply capability without replying, or the reply has
+    /// already been taken.
+    NoReply,
+    /// The call has already been replied to.
+    AlreadyReplied,
+    /// Error occurred allocating memory for the queue.
+    Memory {
+        /// Cause of the error.
+        source: crate::memory::Error,
+    },
+}
@@ -0,0 +1,273 @@
+//! Notifications, a lightweight way to signal events without sending messages.
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use log::trace;
+use snafu::{ensure, OptionExt as _};
+
+use super::{
+    wait_set::{add_observer, remove_observer, wake_observers},
+    CancelledSnafu, Error, Observer, ReceiveFlags, Waitable, WouldBlockSnafu,
+};
+use crate::{
+    process::thread::{
+        wait::{block_current, Timeout},
+        Scheduler, Thread, WaitOutcome, WaitReason, WaitToken,
+    },
+    sync::Mutex,
+};
+
+struct NotificationState {
+    /// Event flags that have been raised but not yet consumed by a waiter.
+    pending: u64,
+    /// Threads blocked waiting for any of the flags in their mask to be raised.
+    waiters: Vec<(Arc<Thread>, WaitToken, u64)>,
+    /// Wait sets that this notification is a member of.
+    observers: Vec<Weak<Observer>>,
+}
+
+/// A set of 64 event flags that can be raised by a sender (or an interrupt handler) and waited on
+/// by threads.
+///
+/// Raising a flag that is already raised has no additional effect, so notifications only tell a
+/// waiter that an event has happened since it last checked, not how many times.
+pub struct Notification {
+    state: Mutex<NotificationState>,
+}
+
+impl Notification {
+    /// Create a new notification with no flags raised.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(NotificationState {
+                pending: 0,
+                waiters: Vec::new(),
+                observers: Vec::new(),
+            }),
+        }
+    }
+
+    /// The flags that are currently raised.
+    pub fn pending(&self) -> u64 {
+        self.state.lock().pending
+    }
+
+    /// Raise the event flags in `flags`.
+    /// Every thread blocked waiting for one of the flags is resumed.
+    pub fn signal(&self, flags: u64) {
+        let mut state = self.state.lock();
+        state.pending |= flags;
+        let pending = state.pending;
+        super::NOTIFICATION_SIGNALED.hit(flags, pending);
+        state.waiters.retain(|(waiter, token, mask)| {
+            if pending & mask == 0 {
+                // forget threads whose waits ended another way
+                return waiter.is_waiting(*token);
+            }
+            if waiter.end_wait(*token, WaitOutcome::Signaled) {
+                trace!("waking thread {} for notification {pending:#x}", waiter.id);
+            }
+            false
+        });
+        wake_observers(&mut state.observers);
+    }
+
+    /// Consume the raised flags that are in `mask`, returning them.
+    ///
+    /// If none of the flags in `mask` are raised, then by default the current thread (given by
+    /// `scheduler`) is blocked until one of them is or the `timeout` passes, and the scheduler is
+    /// advanced to the next time slice. Once the thread resumes, [`Thread::take_wait_outcome`]
+    /// tells how the wait ended.
+    ///
+    /// # Errors
+    /// - [`Error::WouldBlock`] if no flags are raised and the `nonblocking` flag is set.
+    /// - [`Error::Blocked`] if no flags are raised and the current thread was blocked.
+    ///   The wait should be retried once the thread is resumed.
+    /// - [`Error::Cancelled`] if no flags are raised and the current thread's waits have been
+    ///   cancelled.
+    pub fn wait(
+        &self,
+        scheduler: &impl Scheduler,
+        mask: u64,
+        flags: ReceiveFlags,
+        timeout: Option<Timeout>,
+    ) -> Result<u64, Error> {
+        let mut state = self.state.lock();
+        let raised = state.pending & mask;
+        if raised != 0 {
+            state.pending &= !raised;
+            return Ok(raised);
+        }
+
+        ensure!(!flags.nonblocking(), WouldBlockSnafu);
+
+        let (thread, token) =
+            block_current(scheduler, WaitReason::Notification, timeout).context(CancelledSnafu)?;
+        trace!(
+            "blocking thread {} for notification mask {mask:#x}",
+            thread.id
+        );
+        state.waiters.push((thread, token, mask));
+        drop(state);
+        scheduler.next_time_slice();
+        Err(Error::Blocked)
+    }
+}
+
+impl Waitable for Notification {
+    fn is_ready(&self, mask: u64) -> bool {
+        self.pending() & mask != 0
+    }
+
+    fn observe(&self, observer: Weak<Observer>) {
+        add_observer(&mut self.state.lock().observers, observer);
+    }
+
+    fn unobserve(&self, observer: &Weak<Observer>) {
+        remove_observer(&mut self.state.lock().observers, observer);
+    }
+}
+
+impl Default for Notification {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Notification {
+    fn drop(&mut self) {
+        for (waiter, token, _) in self.state.get_mut().waiters.drain(..) {
+            waiter.end_wait(token, WaitOutcome::Cancelled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        process::thread::{MockScheduler, ProcessorState, State, MAX_THREAD_ID},
+        time::TimerQueue,
+    };
+
+    fn nonblocking() -> ReceiveFlags {
+        let mut f = ReceiveFlags::default();
+        f.set_nonblocking(true);
+        f
+    }
+
+    #[test]
+    fn signal_then_wait() {
+        let sched = MockScheduler::new();
+        let n = Notification::new();
+        n.signal(0b0101);
+        n.signal(0b0001);
+        assert_eq!(n.pending(), 0b0101);
+        assert_eq!(n.wait(&sched, 0b0001, nonblocking(), None).unwrap(), 0b0001);
+        assert_eq!(n.pending(), 0b0100);
+        assert!(matches!(
+            n.wait(&sched, 0b0010, nonblocking(), None),
+            Err(Error::WouldBlock)
+        ));
+        assert_eq!(
+            n.wait(&sched, u64::MAX, nonblocking(), None).unwrap(),
+            0b0100
+        );
+        assert_eq!(n.pending(), 0);
+    }
+
+    #[test]
+    fn blocking_wait_wakes_on_matching_signal() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let mut sched = MockScheduler::new();
+        let t = thread.clone();
+        sched
+            .expect_current_thread()
+            .once()
+            .returning(move || t.clone());
+        sched.expect_next_time_slice().once().return_const(());
+
+        let n = Notification::new();
+        assert!(matches!(
+            n.wait(&sched, 0b10, ReceiveFlags::default(), None),
+            Err(Error::Blocked)
+        ));
+        assert_eq!(thread.state(), State::Blocked);
+        // flags the thread isn't waiting for don't wake it
+        n.signal(0b01);
+        assert_eq!(thread.state(), State::Blocked);
+        n.signal(0b10);
+        assert_eq!(thread.state(), State::Running);
+        assert_eq!(
+            n.wait(&sched, 0b10, ReceiveFlags::default(), None).unwrap(),
+            0b10
+        );
+        assert_eq!(n.pending(), 0b01);
+    }
+
+    #[test]
+    fn wait_times_out_and_signal_stays_pending() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let mut sched = MockScheduler::new();
+        let t = thread.clone();
+        sched
+            .expect_current_thread()
+            .once()
+            .returning(move || t.clone());
+        sched.expect_next_time_slice().once().return_const(());
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+
+        let n = Notification::new();
+        assert!(matches!(
+            n.wait(
+                &sched,
+                0b1,
+                ReceiveFlags::default(),
+                Some(Timeout::new(timers, 30))
+            ),
+            Err(Error::Blocked)
+        ));
+        let (_, expire) = timers.pop_expired(30).unwrap();
+        expire();
+        assert_eq!(thread.state(), State::Running);
+        assert_eq!(thread.take_wait_outcome(), Some(WaitOutcome::TimedOut));
+
+        // nobody is waiting any more, so the flag is kept for the next wait
+        n.signal(0b1);
+        assert_eq!(thread.take_wait_outcome(), None);
+        assert_eq!(n.pending(), 0b1);
+    }
+
+    #[test]
+    fn cancelled_thread_does_not_block() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let mut sched = MockScheduler::new();
+        let t = thread.clone();
+        sched
+            .expect_current_thread()
+            .once()
+            .returning(move || t.clone());
+        thread.cancel_waits();
+
+        let n = Notification::new();
+        assert!(matches!(
+            n.wait(&sched, 0b1, ReceiveFlags::default(), None),
+            Err(Error::Cancelled)
+        ));
+        assert_eq!(thread.state(), State::Running);
+    }
+}
@@ -0,0 +1,501 @@
+//! Message queues that hold messages in transit.
+use alloc::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use log::trace;
+use snafu::{ensure, OptionExt, ResultExt};
+
+use super::{
+    wait_set::{add_observer, remove_observer, wake_observers},
+    CancelledSnafu, Error, InboxFullSnafu, InvalidLengthSnafu, MemorySnafu, MessageBlock, Observer,
+    PageTransfer, ReceiveFlags, Reply, UnknownMessageSnafu, Waitable, WouldBlockSnafu,
+    MAX_MESSAGE_BLOCKS, MESSAGE_BLOCK_SIZE,
+};
+use crate::{
+    memory::{PageAllocator, PhysicalPointer},
+    process::thread::{
+        wait::{block_current, Timeout, Waiter},
+        Scheduler, WaitOutcome, WaitReason,
+    },
+    sync::Mutex,
+};
+
+/// A message that has been received from a [`MessageQueue`].
+///
+/// The message data remains valid until it is freed with [`MessageQueue::free_message`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    /// Pointer to the first block of the message in the queue's buffer.
+    pub data: PhysicalPointer<MessageBlock>,
+    /// The number of blocks in the message.
+    pub num_blocks: usize,
+    /// Pages that were moved with the message, which must be attached to the receiver's address space.
+    pub pages: Option<PageTransfer>,
+    /// The capability to reply to the message, if it was sent with [`call`](super::call).
+    pub reply: Option<Arc<Reply>>,
+}
+
+impl ReceivedMessage {
+    /// Get the blocks of the message as a slice.
+    ///
+    /// # Safety
+    /// The message must not have been freed yet, and the queue it was received from must still be alive.
+    #[must_use]
+    pub unsafe fn as_slice(&self) -> &[MessageBlock] {
+        let data: *mut MessageBlock = self.data.into();
+        core::slice::from_raw_parts(data, self.num_blocks)
+    }
+}
+
+/// A message that has been sent but not yet received.
+struct PendingMessage {
+    /// The first block of the message in the buffer.
+    start: usize,
+    /// Pages moved with the message.
+    pages: Option<PageTransfer>,
+    /// The reply capability sent with the message, if it is a call.
+    reply: Option<Arc<Reply>>,
+}
+
+struct QueueState {
+    /// For each block in the buffer, the length of the message that starts at that block, or zero
+    /// if no message starts there.
+    message_lengths: Vec<u8>,
+    /// For each block in the buffer, true if the block is currently holding message data.
+    occupied: Vec<bool>,
+    /// Messages that have been sent but not yet received, in order.
+    pending: VecDeque<PendingMessage>,
+    /// The thread that is blocked waiting for a message, if any.
+    waiter: Option<Waiter>,
+    /// Wait sets that this queue is a member of.
+    observers: Vec<Weak<Observer>>,
+}
+
+impl QueueState {
+    /// Find the first run of `len` free blocks in the buffer.
+    fn find_free_run(&self, len: usize) -> Option<usize> {
+        let mut run_start = 0;
+        for (i, occupied) in self.occupied.iter().enumerate() {
+            if *occupied {
+                run_start = i + 1;
+            } else if i + 1 - run_start == len {
+                return Some(run_start);
+            }
+        }
+        None
+    }
+
+    fn set_occupied(&mut self, start: usize, len: usize, occupied: bool) {
+        self.occupied[start..start + len].fill(occupied);
+    }
+}
+
+/// A queue of messages that have been sent to a thread but not yet freed by the receiver.
+///
+/// Message data is stored in a buffer of pages allocated from a [`PageAllocator`] when the queue is created.
+pub struct MessageQueue<'pa, PA: PageAllocator> {
+    page_allocator: &'pa PA,
+    buffer: PhysicalPointer<MessageBlock>,
+    buffer_num_pages: usize,
+    state: Mutex<QueueState>,
+}
+
+impl<'pa, PA: PageAllocator> MessageQueue<'pa, PA> {
+    /// Create a new empty message queue with a buffer of `num_pages` allocated from `page_allocator`.
+    ///
+    /// # Errors
+    /// - [`Error::Memory`] if the buffer could not be allocated.
+    pub fn new(page_allocator: &'pa PA, num_pages: usize) -> Result<Self, Error> {
+        let buffer = page_allocator
+            .allocate(num_pages)
+            .context(MemorySnafu)?
+            .cast();
+        let num_blocks = num_pages * page_allocator.page_size() / MESSAGE_BLOCK_SIZE;
+        trace!("creating message queue at {buffer:?} with {num_blocks} blocks");
+        Ok(Self {
+            page_allocator,
+            buffer,
+            buffer_num_pages: num_pages,
+            state: Mutex::new(QueueState {
+                message_lengths: vec![0; num_blocks],
+                occupied: vec![false; num_blocks],
+                pending: VecDeque::new(),
+                waiter: None,
+                observers: Vec::new(),
+            }),
+        })
+    }
+
+    /// The physical address of the start of the message buffer.
+    #[must_use]
+    pub fn buffer(&self) -> PhysicalPointer<MessageBlock> {
+        self.buffer
+    }
+
+    /// The number of messages that have been sent but not yet received.
+    pub fn pending_count(&self) -> usize {
+        self.state.lock().pending.len()
+    }
+
+    /// Send a message to this queue by copying it into the buffer.
+    /// If a thread is blocked waiting for a message on this queue, it will be resumed.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidLength`] if the message is empty or longer than [`MAX_MESSAGE_BLOCKS`].
+    /// - [`Error::InboxFull`] if there is not enough contiguous space in the buffer for the message.
+    pub fn send(&self, message: &[MessageBlock]) -> Result<(), Error> {
+        self.enqueue(message, None, None)
+    }
+
+    /// Send a message to this queue like [`Self::send`], moving `pages` to the receiver with it.
+    ///
+    /// # Errors
+    /// The same as [`Self::send`]. If an error occurs, the pages are returned in the error.
+    pub fn send_with_pages(
+        &self,
+        message: &[MessageBlock],
+        pages: PageTransfer,
+    ) -> Result<(), (Error, PageTransfer)> {
+        let transfer = pages.clone();
+        self.enqueue(message, Some(pages), None)
+            .map_err(|e| (e, transfer))
+    }
+
+    /// Send a message to this queue like [`Self::send`], with the capability to reply to it.
+    ///
+    /// # Errors
+    /// The same as [`Self::send`]. If an error occurs, the reply is dropped.
+    pub(super) fn send_call(
+        &self,
+        message: &[MessageBlock],
+        reply: Arc<Reply>,
+    ) -> Result<(), Error> {
+        self.enqueue(message, None, Some(reply))
+    }
+
+    fn enqueue(
+        &self,
+        message: &[MessageBlock],
+        pages: Option<PageTransfer>,
+        reply: Option<Arc<Reply>>,
+    ) -> Result<(), Error> {
+        ensure!(
+            (1..=MAX_MESSAGE_BLOCKS).contains(&message.len()),
+            InvalidLengthSnafu
+        );
+
+        let mut state = self.state.lock();
+        let start = state.find_free_run(message.len()).context(InboxFullSnafu)?;
+        unsafe {
+            let dst: *mut MessageBlock = self.buffer.add(start).into();
+            core::ptr::copy_nonoverlapping(message.as_ptr(), dst, message.len());
+        }
+        state.set_occupied(start, message.len(), true);
+        #[allow(clippy::cast_possible_truncation)] // length is at most MAX_MESSAGE_BLOCKS
+        {
+            state.message_lengths[start] = message.len() as u8;
+        }
+        trace!(
+            "sent message of {} blocks at block {start} with pages {pages:?}",
+            message.len()
+        );
+        super::MESSAGE_SENT.hit(
+            message.len() as u64,
+            pages.as_ref().map_or(0, |p| p.num_pages as u64),
+        );
+        state.pending.push_back(PendingMessage {
+            start,
+            pages,
+            reply,
+        });
+
+        if let Some((waiter, token)) = state.waiter.take() {
+            if waiter.end_wait(token, WaitOutcome::Signaled) {
+                trace!("waking thread {}", waiter.id);
+            }
+        }
+        wake_observers(&mut state.observers);
+
+        Ok(())
+    }
+
+    /// Receive the next message in the queue.
+    ///
+    /// If there are no messages, then by default the current thread (given by `scheduler`) is
+    /// blocked until a message is sent or the `timeout` passes, and the scheduler is advanced to the
+    /// next time slice. Once the thread resumes,
+    /// [`Thread::take_wait_outcome`](crate::process::thread::Thread::take_wait_outcome) tells how
+    /// the wait ended.
+    ///
+    /// # Errors
+    /// - [`Error::WouldBlock`] if there are no messages and the `nonblocking` flag is set.
+    /// - [`Error::Blocked`] if there are no messages and the current thread was blocked.
+    /// - [`Error::Cancelled`] if there are no messages and the current thread's waits have been
+    ///   cancelled.
+    pub fn receive(
+        &self,
+        scheduler: &impl Scheduler,
+        flags: ReceiveFlags,
+        timeout: Option<Timeout>,
+    ) -> Result<ReceivedMessage, Error> {
+        let mut state = self.state.lock();
+        if let Some(PendingMessage {
+            start,
+            pages,
+            reply,
+        }) = state.pending.pop_front()
+        {
+            super::MESSAGE_RECEIVED.hit(
+                u64::from(state.message_lengths[start]),
+                pages.as_ref().map_or(0, |p| p.num_pages as u64),
+            );
+            return Ok(ReceivedMessage {
+                data: self.buffer.add(start),
+                num_blocks: state.message_lengths[start] as usize,
+                pages,
+                reply,
+            });
+        }
+
+        ensure!(!flags.nonblocking(), WouldBlockSnafu);
+
+        let waiter =
+            block_current(scheduler, WaitReason::Message, timeout).context(CancelledSnafu)?;
+        trace!("blocking thread {} for message", waiter.0.id);
+        state.waiter = Some(waiter);
+        drop(state);
+        scheduler.next_time_slice();
+        Err(Error::Blocked)
+    }
+
+    /// Free a message that was previously received so that the space can be reused.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownMessage`] if `data` does not point to a received message in this queue.
+    pub fn free_message(&self, data: PhysicalPointer<MessageBlock>) -> Result<(), Error> {
+        let offset = usize::from(data)
+            .checked_sub(usize::from(self.buffer))
+            .context(UnknownMessageSnafu)?;
+        ensure!(offset % MESSAGE_BLOCK_SIZE == 0, UnknownMessageSnafu);
+        let start = offset / MESSAGE_BLOCK_SIZE;
+
+        let mut state = self.state.lock();
+        let len = state
+            .message_lengths
+            .get(start)
+            .copied()
+            .context(UnknownMessageSnafu)? as usize;
+        ensure!(
+            len > 0 && !state.pending.iter().any(|m| m.start == start),
+            UnknownMessageSnafu
+        );
+        state.message_lengths[start] = 0;
+        state.set_occupied(start, len, false);
+        Ok(())
+    }
+}
+
+impl<PA: PageAllocator + Sync> Waitable for MessageQueue<'_, PA> {
+    fn is_ready(&self, _mask: u64) -> bool {
+        self.pending_count() > 0
+    }
+
+    fn observe(&self, observer: Weak<Observer>) {
+        add_observer(&mut self.state.lock().observers, observer);
+    }
+
+    fn unobserve(&self, observer: &Weak<Observer>) {
+        remove_observer(&mut self.state.lock().observers, observer);
+    }
+}
+
+impl<PA: PageAllocator> Drop for MessageQueue<'_, PA> {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        if let Some((waiter, token)) = state.waiter.take() {
+            waiter.end_wait(token, WaitOutcome::Cancelled);
+        }
+        // dropping the reply capabilities of pending calls answers them
+        for pages in state.pending.drain(..).filter_map(|m| m.pages) {
+            log::warn!("leaking pages {pages:?} attached to a message that was never received");
+        }
+        self.page_allocator
+            .free(self.buffer.cast(), self.buffer_num_pages)
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        memory::{tests::MockPageAllocator, PageSize, PhysicalAddress},
+        process::thread::{MockScheduler, ProcessorState, State, Thread, MAX_THREAD_ID},
+    };
+
+    fn message(len: usize, fill: u8) -> Vec<MessageBlock> {
+        vec![MessageBlock([fill; MESSAGE_BLOCK_SIZE]); len]
+    }
+
+    fn nonblocking() -> ReceiveFlags {
+        let mut f = ReceiveFlags::default();
+        f.set_nonblocking(true);
+        f
+    }
+
+    #[test]
+    fn queue_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MessageQueue<'static, MockPageAllocator>>();
+    }
+
+    #[test]
+    fn send_receive_free() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let sched = MockScheduler::new();
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            q.send(&message(3, 0xab)).unwrap();
+            q.send(&message(1, 0xcd)).unwrap();
+            assert_eq!(q.pending_count(), 2);
+
+            let m = q.receive(&sched, ReceiveFlags::default(), None).unwrap();
+            assert_eq!(m.num_blocks, 3);
+            assert_eq!(unsafe { m.as_slice() }, message(3, 0xab).as_slice());
+            let m2 = q.receive(&sched, ReceiveFlags::default(), None).unwrap();
+            assert_eq!(unsafe { m2.as_slice() }, message(1, 0xcd).as_slice());
+
+            q.free_message(m.data).unwrap();
+            q.free_message(m2.data).unwrap();
+            assert!(matches!(q.free_message(m.data), Err(Error::UnknownMessage)));
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn send_pages_with_message() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let sched = MockScheduler::new();
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            let pages = PageTransfer {
+                physical_start: PhysicalAddress::from(0x4000_0000),
+                num_pages: 3,
+            };
+            q.send(&message(1, 1)).unwrap();
+            q.send_with_pages(&message(2, 2), pages.clone()).unwrap();
+            assert!(matches!(
+                q.send_with_pages(&[], pages.clone()),
+                Err((Error::InvalidLength, p)) if p == pages
+            ));
+
+            let m = q.receive(&sched, nonblocking(), None).unwrap();
+            assert_eq!(m.pages, None);
+            let m2 = q.receive(&sched, nonblocking(), None).unwrap();
+            assert_eq!(m2.num_blocks, 2);
+            assert_eq!(m2.pages, Some(pages));
+            q.free_message(m.data).unwrap();
+            q.free_message(m2.data).unwrap();
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn invalid_length() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            assert!(matches!(q.send(&[]), Err(Error::InvalidLength)));
+            assert!(matches!(
+                q.send(&message(MAX_MESSAGE_BLOCKS + 1, 0)),
+                Err(Error::InvalidLength)
+            ));
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn inbox_full_until_freed() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let sched = MockScheduler::new();
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            // 4096 / 64 = 64 blocks = 4 max-size messages
+            for i in 0..4 {
+                q.send(&message(MAX_MESSAGE_BLOCKS, i)).unwrap();
+            }
+            assert!(matches!(q.send(&message(1, 0)), Err(Error::InboxFull)));
+            let m = q.receive(&sched, nonblocking(), None).unwrap();
+            // receiving alone does not free the space
+            assert!(matches!(q.send(&message(1, 0)), Err(Error::InboxFull)));
+            q.free_message(m.data).unwrap();
+            q.send(&message(MAX_MESSAGE_BLOCKS, 9)).unwrap();
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn cannot_free_pending_message() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            q.send(&message(2, 0)).unwrap();
+            assert!(matches!(
+                q.free_message(q.buffer()),
+                Err(Error::UnknownMessage)
+            ));
+            assert!(matches!(
+                q.free_message(q.buffer().add(1)),
+                Err(Error::UnknownMessage)
+            ));
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn nonblocking_receive_on_empty() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let sched = MockScheduler::new();
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            assert!(matches!(
+                q.receive(&sched, nonblocking(), None),
+                Err(Error::WouldBlock)
+            ));
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn blocking_receive_wakes_on_send() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let mut sched = MockScheduler::new();
+        let t = thread.clone();
+        sched
+            .expect_current_thread()
+            .once()
+            .returning(move || t.clone());
+        sched.expect_next_time_slice().once().return_const(());
+        {
+            let q = MessageQueue::new(&pa, 1).unwrap();
+            assert!(matches!(
+                q.receive(&sched, ReceiveFlags::default(), None),
+                Err(Error::Blocked)
+            ));
+            assert_eq!(thread.state(), State::Blocked);
+            q.send(&message(1, 7)).unwrap();
+            assert_eq!(thread.state(), State::Running);
+            let m = q.receive(&sched, ReceiveFlags::default(), None).unwrap();
+            assert_eq!(unsafe { m.as_slice() }, message(1, 7).as_slice());
+        }
+        pa.end_check();
+    }
+}
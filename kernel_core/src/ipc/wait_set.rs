@@ -0,0 +1,334 @@
+//! Wait sets, which let a thread block on several message queues and notifications at once.
+//!
+//! A [`WaitSet`] holds a set of [`Waitable`] objects, each under a key chosen by its owner.
+//! [`WaitSet::wait`] returns the keys of every member that is ready, or blocks until one becomes
+//! ready. Readiness is level-triggered: a member stays ready until its message is received or its
+//! flags are consumed through the member itself, so an event loop handles whatever is ready and
+//! then waits again.
+//!
+//! Each member wakes the set through an [`Observer`] that it holds weakly, so the set does not keep
+//! itself alive through its members.
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::trace;
+use snafu::{ensure, OptionExt as _};
+
+use super::{CancelledSnafu, Error, ReceiveFlags, WouldBlockSnafu};
+use crate::{
+    process::thread::{
+        wait::{block_current, Timeout, Waiter},
+        Scheduler, WaitOutcome, WaitReason,
+    },
+    sync::Mutex,
+};
+
+/// An object that can be a member of a [`WaitSet`].
+pub trait Waitable: Send + Sync {
+    /// Returns true if the object has an event that a thread could take without blocking.
+    ///
+    /// For notifications, only the flags in `mask` count. Other objects ignore it.
+    fn is_ready(&self, mask: u64) -> bool;
+
+    /// Wake `observer` whenever the object might have become ready, until the observer is dropped
+    /// or [`unobserve`](Self::unobserve)d. Observing with the same observer again has no effect.
+    fn observe(&self, observer: Weak<Observer>);
+
+    /// Stop waking `observer`.
+    fn unobserve(&self, observer: &Weak<Observer>);
+}
+
+/// Wakes the thread blocked on a [`WaitSet`] when one of its members might have become ready.
+pub struct Observer {
+    /// Counts wake ups, so that a wait can tell that it raced with one.
+    events: AtomicU64,
+    waiter: Mutex<Option<Waiter>>,
+}
+
+impl Observer {
+    /// Wake the thread waiting on the set, if any.
+    pub fn wake(&self) {
+        self.events.fetch_add(1, Ordering::SeqCst);
+        if let Some((waiter, token)) = self.waiter.lock().take() {
+            if waiter.end_wait(token, WaitOutcome::Signaled) {
+                trace!("waking thread {} for wait set", waiter.id);
+            }
+        }
+    }
+}
+
+/// Wake every observer in `observers`, forgetting the ones whose wait set has been dropped.
+pub(super) fn wake_observers(observers: &mut Vec<Weak<Observer>>) {
+    observers.retain(|observer| {
+        observer.upgrade().is_some_and(|observer| {
+            observer.wake();
+            true
+        })
+    });
+}
+
+/// Add `observer` to `observers` unless it is already there, forgetting the ones whose wait set has
+/// been dropped.
+pub(super) fn add_observer(observers: &mut Vec<Weak<Observer>>, observer: Weak<Observer>) {
+    observers.retain(|o| o.strong_count() > 0);
+    if !observers.iter().any(|o| o.ptr_eq(&observer)) {
+        observers.push(observer);
+    }
+}
+
+/// Remove `observer` from `observers`.
+pub(super) fn remove_observer(observers: &mut Vec<Weak<Observer>>, observer: &Weak<Observer>) {
+    observers.retain(|o| !o.ptr_eq(observer));
+}
+
+/// A member of a wait set.
+struct Member<'w> {
+    key: u64,
+    object: Arc<dyn Waitable + 'w>,
+    mask: u64,
+}
+
+/// A set of objects that a thread can wait on together.
+pub struct WaitSet<'w> {
+    members: Mutex<Vec<Member<'w>>>,
+    observer: Arc<Observer>,
+}
+
+impl<'w> WaitSet<'w> {
+    /// Create a new, empty wait set.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            members: Mutex::new(Vec::new()),
+            observer: Arc::new(Observer {
+                events: AtomicU64::new(0),
+                waiter: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Add `object` to the set under `key`, which [`Self::wait`] returns when the object is ready.
+    /// For notifications, only the flags in `mask` make the member ready.
+    ///
+    /// Keys don't have to be unique, but every member with a key is removed together.
+    pub fn add(&self, key: u64, object: Arc<dyn Waitable + 'w>, mask: u64) {
+        object.observe(Arc::downgrade(&self.observer));
+        self.members.lock().push(Member { key, object, mask });
+    }
+
+    /// Remove every member with `key` from the set, returning true if there were any.
+    ///
+    /// Removed objects stop waking the set, unless they are still members under another key.
+    pub fn remove(&self, key: u64) -> bool {
+        let mut members = self.members.lock();
+        let (removed, kept) = core::mem::take(&mut *members)
+            .into_iter()
+            .partition::<Vec<_>, _>(|m| m.key == key);
+        *members = kept;
+        let observer = Arc::downgrade(&self.observer);
+        for member in &removed {
+            let object = Arc::as_ptr(&member.object).cast::<()>();
+            if !members
+                .iter()
+                .any(|m| Arc::as_ptr(&m.object).cast::<()>() == object)
+            {
+                member.object.unobserve(&observer);
+            }
+        }
+        !removed.is_empty()
+    }
+
+    /// The keys of the members that are ready, in the order they were added.
+    pub fn ready(&self) -> Vec<u64> {
+        self.members
+            .lock()
+            .iter()
+            .filter(|m| m.object.is_ready(m.mask))
+            .map(|m| m.key)
+            .collect()
+    }
+
+    /// Return the keys of the members that are ready, in the order they were added.
+    ///
+    /// If none are ready, then by default the current thread (given by `scheduler`) is blocked until
+    /// one might be or the `timeout` passes, and the scheduler is advanced to the next time slice.
+    /// Once the thread resumes, [`Thread::take_wait_outcome`](crate::process::thread::Thread::take_wait_outcome)
+    /// tells how the wait ended.
+    ///
+    /// # Errors
+    /// - [`Error::WouldBlock`] if no members are ready and the `nonblocking` flag is set.
+    /// - [`Error::Blocked`] if no members are ready and the current thread was blocked.
+    ///   The wait should be retried once the thread is resumed.
+    /// - [`Error::Cancelled`] if no members are ready and the current thread's waits have been
+    ///   cancelled.
+    pub fn wait(
+        &self,
+        scheduler: &impl Scheduler,
+        flags: ReceiveFlags,
+        timeout: Option<Timeout>,
+    ) -> Result<Vec<u64>, Error> {
+        loop {
+            let events = self.observer.events.load(Ordering::SeqCst);
+            let ready = self.ready();
+            if !ready.is_empty() {
+                return Ok(ready);
+            }
+
+            ensure!(!flags.nonblocking(), WouldBlockSnafu);
+
+            let mut waiter = self.observer.waiter.lock();
+            // a member woke the set after it was checked, so check again instead of blocking
+            if self.observer.events.load(Ordering::SeqCst) != events {
+                continue;
+            }
+            let (thread, token) =
+                block_current(scheduler, WaitReason::Message, timeout).context(CancelledSnafu)?;
+            trace!("blocking thread {} for wait set", thread.id);
+            *waiter = Some((thread, token));
+            drop(waiter);
+            scheduler.next_time_slice();
+            return Err(Error::Blocked);
+        }
+    }
+}
+
+impl Default for WaitSet<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for WaitSet<'_> {
+    fn drop(&mut self) {
+        if let Some((waiter, token)) = self.observer.waiter.lock().take() {
+            waiter.end_wait(token, WaitOutcome::Cancelled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        ipc::{MessageBlock, MessageQueue, Notification},
+        memory::{tests::MockPageAllocator, PageSize},
+        process::thread::{MockScheduler, ProcessorState, State, Thread, MAX_THREAD_ID},
+    };
+
+    fn nonblocking() -> ReceiveFlags {
+        let mut f = ReceiveFlags::default();
+        f.set_nonblocking(true);
+        f
+    }
+
+    #[test]
+    fn ready_members_are_reported() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let sched = MockScheduler::new();
+        {
+            let queue = Arc::new(MessageQueue::new(&pa, 1).unwrap());
+            let notification = Arc::new(Notification::new());
+            let set = WaitSet::new();
+            set.add(1, queue.clone(), 0);
+            set.add(2, notification.clone(), 0b10);
+            assert!(matches!(
+                set.wait(&sched, nonblocking(), None),
+                Err(Error::WouldBlock)
+            ));
+
+            // flags outside the mask don't count
+            notification.signal(0b01);
+            assert!(set.ready().is_empty());
+            notification.signal(0b10);
+            queue.send(&[MessageBlock::default()]).unwrap();
+            assert_eq!(set.wait(&sched, nonblocking(), None).unwrap(), vec![1, 2]);
+            // members stay ready until their events are taken
+            assert_eq!(set.ready(), vec![1, 2]);
+            notification
+                .wait(&sched, 0b10, ReceiveFlags::default(), None)
+                .unwrap();
+            assert_eq!(set.ready(), vec![1]);
+
+            assert!(set.remove(1));
+            assert!(!set.remove(1));
+            assert!(set.ready().is_empty());
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn removed_members_forget_the_set() {
+        let notification = Arc::new(Notification::new());
+        let set = WaitSet::new();
+        for key in 0..16 {
+            set.add(key, notification.clone(), u64::MAX);
+            // the notification holds the set's observer only once
+            set.add(key + 100, notification.clone(), u64::MAX);
+            assert_eq!(Arc::weak_count(&set.observer), 1);
+            assert!(set.remove(key));
+            assert_eq!(Arc::weak_count(&set.observer), 1);
+            assert!(set.remove(key + 100));
+            assert_eq!(Arc::weak_count(&set.observer), 0);
+        }
+    }
+
+    #[test]
+    fn blocked_thread_wakes_on_any_member() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let mut sched = MockScheduler::new();
+        let t = thread.clone();
+        sched
+            .expect_current_thread()
+            .times(2)
+            .returning(move || t.clone());
+        sched.expect_next_time_slice().times(2).return_const(());
+        {
+            let queue = Arc::new(MessageQueue::new(&pa, 1).unwrap());
+            let notification = Arc::new(Notification::new());
+            let set = WaitSet::new();
+            set.add(7, queue.clone(), 0);
+            set.add(9, notification.clone(), u64::MAX);
+
+            assert!(matches!(
+                set.wait(&sched, ReceiveFlags::default(), None),
+                Err(Error::Blocked)
+            ));
+            assert_eq!(thread.state(), State::Blocked);
+            notification.signal(1);
+            assert_eq!(thread.state(), State::Running);
+            assert_eq!(thread.take_wait_outcome(), Some(WaitOutcome::Signaled));
+            assert_eq!(
+                set.wait(&sched, ReceiveFlags::default(), None).unwrap(),
+                vec![9]
+            );
+            notification
+                .wait(&sched, u64::MAX, ReceiveFlags::default(), None)
+                .unwrap();
+
+            assert!(matches!(
+                set.wait(&sched, ReceiveFlags::default(), None),
+                Err(Error::Blocked)
+            ));
+            queue.send(&[MessageBlock::default()]).unwrap();
+            assert_eq!(thread.state(), State::Running);
+            assert_eq!(
+                set.wait(&sched, ReceiveFlags::default(), None).unwrap(),
+                vec![7]
+            );
+
+            // the members forget the set once it is dropped
+            drop(set);
+            notification.signal(1);
+        }
+        pa.end_check();
+    }
+}
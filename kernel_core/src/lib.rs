@@ -16,11 +16,23 @@ extern crate std;
 extern crate alloc;
 
 pub mod collections;
+pub mod debug;
+pub mod driver;
 pub mod exceptions;
+pub mod fs;
+pub mod init;
+pub mod io;
+pub mod ipc;
 pub mod logger;
 pub mod memory;
 pub mod platform;
 pub mod process;
+pub mod rand;
+pub mod selftest;
+pub mod smp;
+pub mod sync;
+pub mod time;
+pub mod trace;
 
 #[cfg(test)]
 mod tests {
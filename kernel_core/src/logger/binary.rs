@@ -0,0 +1,261 @@
+//! A compact binary log record format, for decoding by a tool on the host instead of displaying
+//! directly on a terminal.
+//!
+//! Each record is encoded as a fixed size header followed by the message text. All fields are
+//! little endian:
+//!
+//! | Offset | Size | Field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 1    | [`RECORD_MAGIC`]                                       |
+//! | 1      | 1    | Level: 1 (error) through 5 (trace), or 0 for a panic   |
+//! | 2      | 4    | Core ID                                                |
+//! | 6      | 8    | Timestamp in nanoseconds since boot                    |
+//! | 14     | 4    | Module ID (see [`module_id`])                          |
+//! | 18     | 4    | Line number                                            |
+//! | 22     | 2    | Length of the message in bytes                         |
+//! | 24     | *    | Message, as UTF-8                                      |
+//!
+//! Module paths are replaced by a hash to keep records small; the decoder is expected to know the
+//! set of module paths in the kernel. Messages longer than [`MAX_MESSAGE_LEN`] are truncated.
+use core::fmt::Write;
+
+use log::Record;
+
+use super::{GlobalValues, LogSink, RecordOutput};
+
+/// The first byte of every record, so that a decoder can find the start of the next record if
+/// the stream is corrupted.
+pub const RECORD_MAGIC: u8 = 0xa5;
+
+/// The size of the record header in bytes.
+pub const HEADER_LEN: usize = 24;
+
+/// The maximum length of a message in bytes.
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+/// The level recorded for panic messages.
+pub const PANIC_LEVEL: u8 = 0;
+
+/// Compute the module ID for a module path, which is the 32-bit FNV-1a hash of the path.
+#[must_use]
+pub const fn module_id(module_path: &str) -> u32 {
+    // a loop instead of an iterator, so that tracepoint IDs can be computed at compile time
+    let bytes = module_path.as_bytes();
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u32).wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+/// A fixed size buffer that formats a message, discarding anything past [`MAX_MESSAGE_LEN`].
+struct MessageBuffer {
+    data: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(MAX_MESSAGE_LEN - self.len);
+        self.data[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Encode a single record into `out`.
+fn encode(
+    out: &mut impl RecordOutput,
+    level: u8,
+    global_values: &GlobalValues,
+    module_path: &str,
+    line: u32,
+    message: core::fmt::Arguments,
+) {
+    let mut msg = MessageBuffer {
+        data: [0; MAX_MESSAGE_LEN],
+        len: 0,
+    };
+    let _ = msg.write_fmt(message);
+
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = RECORD_MAGIC;
+    header[1] = level;
+    header[2..6].copy_from_slice(
+        &u32::try_from(global_values.core_id)
+            .unwrap_or(u32::MAX)
+            .to_le_bytes(),
+    );
+    header[6..14].copy_from_slice(&global_values.timestamp_nanos.to_le_bytes());
+    header[14..18].copy_from_slice(&module_id(module_path).to_le_bytes());
+    header[18..22].copy_from_slice(&line.to_le_bytes());
+    header[22..24].copy_from_slice(&u16::try_from(msg.len).unwrap_or(u16::MAX).to_le_bytes());
+    out.write_bytes(&header);
+    out.write_bytes(&msg.data[..msg.len]);
+}
+
+/// A [`LogSink`] that encodes records in the binary format before passing them to another sink.
+pub struct BinarySink<S>(S);
+
+impl<S: LogSink> BinarySink<S> {
+    /// Create a sink that writes binary records to `inner`.
+    pub fn new(inner: S) -> Self {
+        Self(inner)
+    }
+}
+
+impl<S: LogSink> LogSink for BinarySink<S> {
+    fn accept(&mut self, chunk: &[u8]) {
+        self.0.accept(chunk);
+    }
+
+    fn write_record(out: &mut impl RecordOutput, record: &Record, global_values: &GlobalValues) {
+        #[allow(clippy::cast_possible_truncation)]
+        let level = record.level() as u8;
+        encode(
+            out,
+            level,
+            global_values,
+            record.module_path().unwrap_or("unknown module"),
+            record.line().unwrap_or(0),
+            *record.args(),
+        );
+    }
+
+    fn write_panic(out: &mut impl RecordOutput, message: core::fmt::Arguments) {
+        encode(
+            out,
+            PANIC_LEVEL,
+            &GlobalValues::default(),
+            "panic",
+            0,
+            message,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{GlobalValueReader, Logger};
+    use log::{Level, LevelFilter, Log};
+    use std::{string::String, sync::Arc, vec::Vec};
+
+    /// A record decoded from the binary format.
+    #[derive(Debug, PartialEq, Eq)]
+    struct DecodedRecord {
+        level: u8,
+        core_id: u32,
+        timestamp_nanos: u64,
+        module_id: u32,
+        line: u32,
+        message: String,
+    }
+
+    /// Decode every record in `bytes`, skipping over any bytes that are not part of a record.
+    fn decode(mut bytes: &[u8]) -> Vec<DecodedRecord> {
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            if bytes[0] != RECORD_MAGIC || bytes.len() < HEADER_LEN || bytes[1] > 5 {
+                bytes = &bytes[1..];
+                continue;
+            }
+            let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+            let len = usize::from(u16::from_le_bytes(bytes[22..24].try_into().unwrap()));
+            if bytes.len() < HEADER_LEN + len {
+                break;
+            }
+            records.push(DecodedRecord {
+                level: bytes[1],
+                core_id: u32_at(2),
+                timestamp_nanos: u64::from_le_bytes(bytes[6..14].try_into().unwrap()),
+                module_id: u32_at(14),
+                line: u32_at(18),
+                message: String::from_utf8_lossy(&bytes[HEADER_LEN..HEADER_LEN + len]).into_owned(),
+            });
+            bytes = &bytes[HEADER_LEN + len..];
+        }
+        records
+    }
+
+    #[derive(Clone, Default)]
+    struct TestSink(Arc<spin::Mutex<Vec<u8>>>);
+
+    impl LogSink for TestSink {
+        fn accept(&mut self, chunk: &[u8]) {
+            self.0.lock().extend_from_slice(chunk);
+        }
+    }
+
+    struct TestGlobalValueReader;
+
+    impl GlobalValueReader for TestGlobalValueReader {
+        fn read() -> GlobalValues {
+            GlobalValues {
+                core_id: 3,
+                timestamp_nanos: 1_234_567,
+            }
+        }
+    }
+
+    #[test]
+    fn module_ids() {
+        assert_eq!(module_id(""), 0x811c_9dc5);
+        assert_eq!(module_id("a"), 0xe40c_292c);
+        assert_ne!(module_id("kernel::memory"), module_id("kernel::thread"));
+    }
+
+    #[test]
+    fn logger_round_trip() {
+        let output = TestSink::default();
+        let logger = Logger::<_, TestGlobalValueReader, 16>::new(
+            BinarySink::new(output.clone()),
+            LevelFilter::Info,
+        );
+        let long = "x".repeat(MAX_MESSAGE_LEN + 10);
+        for (level, message) in [(Level::Warn, "hello"), (Level::Info, long.as_str())] {
+            logger.log(
+                &log::Record::builder()
+                    .args(format_args!("{message}"))
+                    .level(level)
+                    .module_path(Some("test_module"))
+                    .line(Some(42))
+                    .build(),
+            );
+        }
+        logger.write_panic_message(format_args!("oh no"));
+
+        let records = decode(&output.0.lock());
+        assert_eq!(
+            records[0],
+            DecodedRecord {
+                level: 2,
+                core_id: 3,
+                timestamp_nanos: 1_234_567,
+                module_id: module_id("test_module"),
+                line: 42,
+                message: "hello".into(),
+            }
+        );
+        assert_eq!(records[1].level, 3);
+        assert_eq!(records[1].message, long[..MAX_MESSAGE_LEN]);
+        assert_eq!(records[2].level, PANIC_LEVEL);
+        assert_eq!(records[2].message, "oh no");
+        assert_eq!(records.len(), 3);
+    }
+
+    #[test]
+    fn decoder_skips_garbage() {
+        let mut output = TestSink::default();
+        output.accept(b"log overflow!");
+        BinarySink::<TestSink>::write_panic(
+            &mut crate::logger::SinkWriter(&mut output),
+            format_args!("still here"),
+        );
+        let records = decode(&output.0.lock());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].message, "still here");
+    }
+}
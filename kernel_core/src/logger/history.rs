@@ -0,0 +1,242 @@
+//! A persistent history of recent log records, so that they can be read back later (like `dmesg`).
+//!
+//! Every line written to the log is a record, and is assigned a sequence number. The history keeps
+//! as many of the most recent records as fit in its fixed size buffer, discarding the oldest
+//! records first. Readers ask for records starting at a sequence number, and can tell from the
+//! returned sequence numbers whether any records were lost in between reads.
+use spin::Mutex;
+
+use super::LogSink;
+use crate::sync::interrupts;
+
+/// The result of reading from a [`LogHistory`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryRead {
+    /// The sequence number of the first record that was copied.
+    ///
+    /// This is greater than the requested sequence number if older records have been discarded.
+    pub first_seq: u64,
+    /// The sequence number to read from next to continue after the records that were copied.
+    pub next_seq: u64,
+    /// The number of bytes copied into the buffer.
+    pub len: usize,
+}
+
+struct HistoryState<const N: usize> {
+    data: [u8; N],
+    /// Index of the oldest byte in `data`.
+    start: usize,
+    /// Number of bytes stored in `data`.
+    len: usize,
+    /// Sequence number of the oldest record in `data`.
+    first_seq: u64,
+    /// Sequence number of the record currently being written.
+    next_seq: u64,
+}
+
+impl<const N: usize> HistoryState<N> {
+    fn byte(&self, i: usize) -> u8 {
+        self.data[(self.start + i) % N]
+    }
+
+    /// Discard the oldest record, including the partial record being written if it is the only one.
+    fn discard_oldest(&mut self) {
+        while self.len > 0 {
+            let b = self.byte(0);
+            self.start = (self.start + 1) % N;
+            self.len -= 1;
+            if b == b'\n' {
+                self.first_seq += 1;
+                return;
+            }
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if self.len == N {
+                self.discard_oldest();
+            }
+            self.data[(self.start + self.len) % N] = b;
+            self.len += 1;
+            if b == b'\n' {
+                self.next_seq += 1;
+            }
+        }
+    }
+
+    /// Find the length of the record beginning at `offset`, including the newline.
+    /// Returns `None` if the record is not complete.
+    fn record_len(&self, offset: usize) -> Option<usize> {
+        (offset..self.len)
+            .find(|i| self.byte(*i) == b'\n')
+            .map(|i| i + 1 - offset)
+    }
+}
+
+/// A fixed size history of the most recent log records.
+///
+/// By default the history is 16KiB.
+pub struct LogHistory<const N: usize = 16384> {
+    state: Mutex<HistoryState<N>>,
+}
+
+impl<const N: usize> Default for LogHistory<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LogHistory<N> {
+    /// Create a new empty history.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(HistoryState {
+                data: [0; N],
+                start: 0,
+                len: 0,
+                first_seq: 0,
+                next_seq: 0,
+            }),
+        }
+    }
+
+    /// Append log output to the history.
+    pub fn append(&self, bytes: &[u8]) {
+        let _interrupts = interrupts::mask();
+        self.state.lock().append(bytes);
+    }
+
+    /// The sequence number of the oldest record still in the history.
+    pub fn first_seq(&self) -> u64 {
+        let _interrupts = interrupts::mask();
+        self.state.lock().first_seq
+    }
+
+    /// The sequence number that the next complete record will have.
+    pub fn next_seq(&self) -> u64 {
+        let _interrupts = interrupts::mask();
+        self.state.lock().next_seq
+    }
+
+    /// Copy as many complete records as fit into `buf`, starting with record `seq`, or the oldest
+    /// record still in the history if `seq` has already been discarded.
+    ///
+    /// Records are only copied whole, except that if the first record is larger than `buf` it is
+    /// truncated so that the reader can always make progress.
+    pub fn read_from(&self, seq: u64, buf: &mut [u8]) -> HistoryRead {
+        let _interrupts = interrupts::mask();
+        let state = self.state.lock();
+        let first_seq = seq.max(state.first_seq);
+        let mut current = state.first_seq;
+        let mut offset = 0;
+        // skip records older than the requested sequence number
+        while current < first_seq {
+            let Some(len) = state.record_len(offset) else {
+                break;
+            };
+            offset += len;
+            current += 1;
+        }
+        let mut copied = 0;
+        while let Some(len) = state.record_len(offset) {
+            let n = if len <= buf.len() - copied {
+                len
+            } else if copied == 0 && !buf.is_empty() {
+                buf.len()
+            } else {
+                break;
+            };
+            for (i, dest) in buf[copied..copied + n].iter_mut().enumerate() {
+                *dest = state.byte(offset + i);
+            }
+            copied += n;
+            offset += len;
+            current += 1;
+            if n < len {
+                break;
+            }
+        }
+        HistoryRead {
+            first_seq: first_seq.min(current),
+            next_seq: current,
+            len: copied,
+        }
+    }
+}
+
+/// Log output can be sent directly to a history, for instance as one of the sinks in a
+/// [`super::sinks::SinkSet`].
+impl<const N: usize> LogSink for &LogHistory<N> {
+    fn accept(&mut self, chunk: &[u8]) {
+        self.append(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(history: &LogHistory<32>, seq: u64, len: usize) -> (HistoryRead, std::string::String) {
+        let mut buf = std::vec![0; len];
+        let r = history.read_from(seq, &mut buf);
+        (
+            r,
+            std::string::String::from_utf8_lossy(&buf[..r.len]).into_owned(),
+        )
+    }
+
+    #[test]
+    fn read_complete_records() {
+        let history = LogHistory::<32>::new();
+        history.append(b"one\ntw");
+        history.append(b"o\nthr");
+
+        let (r, s) = read(&history, 0, 64);
+        assert_eq!(s, "one\ntwo\n");
+        assert_eq!((r.first_seq, r.next_seq), (0, 2));
+
+        let (r, s) = read(&history, 1, 64);
+        assert_eq!(s, "two\n");
+        assert_eq!((r.first_seq, r.next_seq), (1, 2));
+
+        // only whole records are copied unless the first one doesn't fit
+        let (r, s) = read(&history, 0, 6);
+        assert_eq!(s, "one\n");
+        assert_eq!(r.next_seq, 1);
+        let (r, s) = read(&history, 0, 2);
+        assert_eq!(s, "on");
+        assert_eq!(r.next_seq, 1);
+
+        // nothing new to read
+        history.append(b"ee\n");
+        let (r, _) = read(&history, 3, 64);
+        assert_eq!((r.len, r.next_seq), (0, 3));
+    }
+
+    #[test]
+    fn oldest_records_are_discarded() {
+        let history = LogHistory::<32>::new();
+        for i in 0..10 {
+            history.append(std::format!("record {i}\n").as_bytes());
+        }
+        assert_eq!(history.next_seq(), 10);
+        let first = history.first_seq();
+        assert!(first > 0);
+
+        let (r, s) = read(&history, 0, 64);
+        assert_eq!(r.first_seq, first);
+        assert_eq!(r.next_seq, 10);
+        assert!(s.starts_with(&std::format!("record {first}\n")));
+        assert!(s.ends_with("record 9\n"));
+    }
+
+    #[test]
+    fn sink_records_history() {
+        let history = LogHistory::<32>::new();
+        let mut sink = &history;
+        sink.accept(b"hello\n");
+        assert_eq!(read(&history, 0, 64).1, "hello\n");
+    }
+}
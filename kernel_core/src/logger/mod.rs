@@ -6,6 +6,13 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use log::{Level, LevelFilter, Log, Metadata, Record};
 use spin::Mutex;
 
+use crate::sync::interrupts;
+use crate::time::clock::NANOS_PER_SECOND;
+
+pub mod binary;
+pub mod history;
+pub mod sinks;
+
 /// Returns the ANSI color code for a given log level.
 fn color_for_level(lvl: Level) -> &'static str {
     match lvl {
@@ -28,14 +35,64 @@ pub trait GlobalValueReader: Send + Sync {
 pub struct GlobalValues {
     /// The ID of the current CPU core.
     pub core_id: usize,
-    /// The current value of the system timer.
-    pub timer_counter: u64,
+    /// Monotonic time since boot, in nanoseconds.
+    pub timestamp_nanos: u64,
+}
+
+/// Output that log records are encoded into before being sent to a [`LogSink`].
+pub trait RecordOutput: Write {
+    /// Write raw bytes to the output.
+    fn write_bytes(&mut self, bytes: &[u8]);
 }
 
 /// Trait representing a sink that accepts log chunks.
 pub trait LogSink {
     /// Accepts a log chunk.
     fn accept(&mut self, chunk: &[u8]);
+
+    /// Accepts a log chunk that is part of a record logged at `level`.
+    ///
+    /// By default the level is ignored.
+    fn accept_record_chunk(&mut self, level: Level, chunk: &[u8]) {
+        let _ = level;
+        self.accept(chunk);
+    }
+
+    /// Returns true if the sink is able to accept more output.
+    ///
+    /// Sinks that are full or broken can return false so that output is not sent to them.
+    fn is_ready(&mut self) -> bool {
+        true
+    }
+
+    /// Encode `record` into `out` in the format this sink expects.
+    ///
+    /// By default records are formatted as colored text, one line per record.
+    fn write_record(out: &mut impl RecordOutput, record: &Record, global_values: &GlobalValues)
+    where
+        Self: Sized,
+    {
+        let _ = writeln!(
+            out,
+            "\x1b[{}m{:<5} \x1b[90m{}.{:06} C{:x}\x1b[0m {}@{}| {}",
+            color_for_level(record.level()),
+            record.level(),
+            global_values.timestamp_nanos / NANOS_PER_SECOND,
+            global_values.timestamp_nanos % NANOS_PER_SECOND / 1000,
+            global_values.core_id,
+            record.module_path().unwrap_or("unknown module"),
+            record.line().unwrap_or(0),
+            record.args()
+        );
+    }
+
+    /// Encode a panic `message` into `out` in the format this sink expects.
+    fn write_panic(out: &mut impl RecordOutput, message: core::fmt::Arguments)
+    where
+        Self: Sized,
+    {
+        let _ = writeln!(out, "\x1b[31mpanic!\x1b[0m {message}");
+    }
 }
 
 const MAX_LOG_CHUNK_SIZE: usize = 120;
@@ -47,10 +104,12 @@ pub struct ChunkWriteGuard<'a> {
 }
 
 impl ChunkWriteGuard<'_> {
-    /// Marks the chunk as full with the given size and consumes the guard
-    pub fn finish(self, actual_size: usize) {
+    /// Marks the chunk as full with the given size and the level of the record it belongs to, and
+    /// consumes the guard
+    pub fn finish(self, actual_size: usize, level: Level) {
         // Ensure that data writes are visible before updating the status
-        let status_and_size = STATUS_FULL | (actual_size << SIZE_SHIFT);
+        let status_and_size =
+            STATUS_FULL | ((level as usize) << LEVEL_SHIFT) | (actual_size << SIZE_SHIFT);
         self.chunk
             .status_and_size
             .store(status_and_size, Ordering::Release);
@@ -115,11 +174,12 @@ impl LogChunk {
     /// Attempts to read data from the chunk and processes it using the provided closure.
     fn try_read<F>(&self, f: F) -> bool
     where
-        F: FnOnce(&[u8]),
+        F: FnOnce(Level, &[u8]),
     {
         let status_and_size = self.status_and_size.load(Ordering::Acquire);
         if (status_and_size & STATUS_MASK) == STATUS_FULL {
             let size = (status_and_size & SIZE_MASK) >> SIZE_SHIFT;
+            let level = level_from_bits((status_and_size & LEVEL_MASK) >> LEVEL_SHIFT);
             let data = unsafe {
                 // SAFETY: we know no one is mutating because the chunk is [`STATUS_FULL`].
                 // It is possible two reads could happen concurrently, but this is probably fine.
@@ -127,7 +187,7 @@ impl LogChunk {
             };
             let data = &data[..size];
             // Process the data while the chunk is still marked as full.
-            f(data);
+            f(level, data);
             // Mark the chunk as empty after processing.
             self.status_and_size.store(STATUS_EMPTY, Ordering::Release);
             true
@@ -141,8 +201,21 @@ const STATUS_EMPTY: usize = 0;
 const STATUS_WRITING: usize = 1;
 const STATUS_FULL: usize = 2;
 const STATUS_MASK: usize = 0b11; // Lower 2 bits.
-const SIZE_SHIFT: usize = 2;
-const SIZE_MASK: usize = !STATUS_MASK;
+const LEVEL_SHIFT: usize = 2;
+const LEVEL_MASK: usize = 0b111 << LEVEL_SHIFT; // Next 3 bits.
+const SIZE_SHIFT: usize = 5;
+const SIZE_MASK: usize = !(STATUS_MASK | LEVEL_MASK);
+
+/// Convert the level stored in a chunk's status back into a [`Level`].
+fn level_from_bits(bits: usize) -> Level {
+    match bits {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
 
 /// A lock-free concurrent logger with a ring buffer.
 ///
@@ -153,7 +226,8 @@ pub struct Logger<S, G: GlobalValueReader, const NUM_CHUNKS_IN_BUFFER: usize = 1
     write_index: AtomicUsize,
     read_index: AtomicUsize,
     overflow_count: AtomicUsize,
-    sink: Mutex<S>,
+    /// The sink that output is sent to, or `None` during early boot before it has been attached.
+    sink: Mutex<Option<S>>,
     level_filter: LevelFilter,
 }
 
@@ -162,41 +236,43 @@ impl<S: LogSink, G: GlobalValueReader, const NUM_CHUNKS_IN_BUFFER: usize>
 {
     /// Creates a new `Logger` with the specified sink and log level filter.
     pub fn new(sink: S, level_filter: LevelFilter) -> Self {
+        let logger = Self::new_without_sink(level_filter);
+        *logger.sink.lock() = Some(sink);
+        logger
+    }
+
+    /// Creates a new `Logger` without a sink, for use during early boot before any output device
+    /// is ready.
+    ///
+    /// Records accumulate in the buffer until a sink is attached with [`Self::attach_sink`]. If
+    /// the buffer fills up, further records are lost and an overflow is reported once the sink is
+    /// attached.
+    pub const fn new_without_sink(level_filter: LevelFilter) -> Self {
         Self {
             buffer: [const { LogChunk::new() }; NUM_CHUNKS_IN_BUFFER],
             write_index: AtomicUsize::new(0),
             read_index: AtomicUsize::new(0),
             overflow_count: AtomicUsize::new(0),
-            sink: Mutex::new(sink),
+            sink: Mutex::new(None),
             level_filter,
             global_value_reader: PhantomData,
         }
     }
 
+    /// Attach `sink` to the logger, replacing the current sink if there is one, and flush every
+    /// buffered record to it.
+    pub fn attach_sink(&self, sink: S) {
+        let _interrupts = interrupts::mask();
+        let mut guard = self.sink.lock();
+        let sink = guard.insert(sink);
+        self.flush_internal(sink, NUM_CHUNKS_IN_BUFFER);
+    }
+
     /// Write a record into the buffer.
     fn write_record(&self, record: &Record) {
-        // Create a RingBufferWriter.
-        let mut writer = RingBufferWriter::new(self);
-
-        let module_path = record.module_path().unwrap_or("unknown module");
-        let line = record.line().unwrap_or(0);
-
-        // Read global values.
-        let global_values = G::read();
-
-        // Write formatted data directly into the ring buffer.
-        writeln!(
-            &mut writer,
-            "\x1b[{}m{:<5} \x1b[90m{} C{:x}\x1b[0m {}@{}| {}",
-            color_for_level(record.level()),
-            record.level(),
-            global_values.timer_counter,
-            global_values.core_id,
-            module_path,
-            line,
-            record.args()
-        )
-        .unwrap();
+        // Write the encoded record directly into the ring buffer.
+        let mut writer = RingBufferWriter::new(self, record.level());
+        S::write_record(&mut writer, record, &G::read());
     }
 
     /// Flush up to `limit` log chunks to the sink, given that we could acquire it.
@@ -220,8 +296,8 @@ impl<S: LogSink, G: GlobalValueReader, const NUM_CHUNKS_IN_BUFFER: usize>
             let chunk = &self.buffer[wrapped_index];
 
             // Safely read and process the chunk.
-            let read_success = chunk.try_read(|data| {
-                sink.accept(data);
+            let read_success = chunk.try_read(|level, data| {
+                sink.accept_record_chunk(level, data);
             });
 
             if read_success {
@@ -232,6 +308,49 @@ impl<S: LogSink, G: GlobalValueReader, const NUM_CHUNKS_IN_BUFFER: usize>
             }
         }
     }
+
+    /// Write `message` directly to the sink after everything already in the buffer, for reporting a panic.
+    ///
+    /// Unlike normal logging, this waits for the sink to become available so that the message
+    /// is never interleaved with other output. If the sink stays locked for too long, the core
+    /// holding it is assumed to have been halted and the lock is forcibly released.
+    pub fn write_panic_message(&self, message: core::fmt::Arguments) {
+        const MAX_ATTEMPTS: usize = 1_000_000;
+        let _interrupts = interrupts::mask();
+        let mut sink = (0..MAX_ATTEMPTS)
+            .find_map(|_| {
+                let guard = self.sink.try_lock();
+                if guard.is_none() {
+                    core::hint::spin_loop();
+                }
+                guard
+            })
+            .unwrap_or_else(|| unsafe {
+                // SAFETY: every other core has been halted, so the previous holder will never release the lock.
+                self.sink.force_unlock();
+                self.sink.lock()
+            });
+        if let Some(sink) = sink.as_mut() {
+            self.flush_internal(sink, NUM_CHUNKS_IN_BUFFER);
+            S::write_panic(&mut SinkWriter(sink), message);
+        }
+    }
+}
+
+/// Adapter to write formatted text directly to a sink.
+struct SinkWriter<'s, S: LogSink>(&'s mut S);
+
+impl<S: LogSink> core::fmt::Write for SinkWriter<'_, S> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl<S: LogSink> RecordOutput for SinkWriter<'_, S> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.0.accept(bytes);
+    }
 }
 
 impl<S: LogSink + Send, G: GlobalValueReader, const NUM_CHUNKS_IN_BUFFER: usize> Log
@@ -246,32 +365,38 @@ impl<S: LogSink + Send, G: GlobalValueReader, const NUM_CHUNKS_IN_BUFFER: usize>
             return;
         }
 
+        // interrupt handlers log too, so they must not interrupt a record or the sink
+        let _interrupts = interrupts::mask();
         self.write_record(record);
 
         // Attempt to flush the buffer if possible.
-        if let Some(mut sink_guard) = self.sink.try_lock() {
-            self.flush_internal(&mut *sink_guard, NUM_CHUNKS_IN_BUFFER / 3);
+        if let Some(sink) = self.sink.try_lock().as_deref_mut().and_then(Option::as_mut) {
+            self.flush_internal(sink, NUM_CHUNKS_IN_BUFFER / 3);
         }
     }
 
     fn flush(&self) {
-        let mut sink_guard = self.sink.lock();
-        self.flush_internal(&mut *sink_guard, NUM_CHUNKS_IN_BUFFER);
+        let _interrupts = interrupts::mask();
+        if let Some(sink) = self.sink.lock().as_mut() {
+            self.flush_internal(sink, NUM_CHUNKS_IN_BUFFER);
+        }
     }
 }
 
 /// A writer that writes directly into the ring buffer.
 struct RingBufferWriter<'a, S: LogSink, G: GlobalValueReader, const N: usize> {
     logger: &'a Logger<S, G, N>,
+    level: Level,
     current_chunk: Option<ChunkWriteGuard<'a>>,
     current_chunk_offset: usize,
 }
 
 impl<'a, S: LogSink, G: GlobalValueReader, const N: usize> RingBufferWriter<'a, S, G, N> {
     /// Creates a new `RingBufferWriter`.
-    fn new(logger: &'a Logger<S, G, N>) -> Self {
+    fn new(logger: &'a Logger<S, G, N>, level: Level) -> Self {
         Self {
             logger,
+            level,
             current_chunk: None,
             current_chunk_offset: 0,
         }
@@ -308,7 +433,7 @@ impl<'a, S: LogSink, G: GlobalValueReader, const N: usize> RingBufferWriter<'a,
     /// Finish the chunk we're currently writing in.
     fn finish_chunk(&mut self) {
         if let Some(chunk) = self.current_chunk.take() {
-            chunk.finish(self.current_chunk_offset);
+            chunk.finish(self.current_chunk_offset, self.level);
         }
     }
 }
@@ -317,7 +442,16 @@ impl<S: LogSink, G: GlobalValueReader, const N: usize> core::fmt::Write
     for RingBufferWriter<'_, S, G, N>
 {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let mut s = s.as_bytes();
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl<S: LogSink, G: GlobalValueReader, const N: usize> RecordOutput
+    for RingBufferWriter<'_, S, G, N>
+{
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let mut s = bytes;
         while !s.is_empty() {
             // If no current chunk or current chunk is full, acquire a new one.
             if self.current_chunk.is_none() || self.current_chunk_offset >= MAX_LOG_CHUNK_SIZE {
@@ -328,7 +462,7 @@ impl<S: LogSink, G: GlobalValueReader, const N: usize> core::fmt::Write
                 if self.acquire_new_chunk().is_err() {
                     // Increment overflow count and discard the remaining data.
                     self.logger.overflow_count.fetch_add(1, Ordering::Relaxed);
-                    return Ok(());
+                    return;
                 }
             }
 
@@ -344,8 +478,6 @@ impl<S: LogSink, G: GlobalValueReader, const N: usize> core::fmt::Write
             self.current_chunk_offset += bytes_to_copy;
             s = &s[bytes_to_copy..];
         }
-
-        Ok(())
     }
 }
 
@@ -387,7 +519,7 @@ mod tests {
         fn read() -> GlobalValues {
             GlobalValues {
                 core_id: 0,
-                timer_counter: 0,
+                timestamp_nanos: 0,
             }
         }
     }
@@ -410,7 +542,12 @@ mod tests {
 
         logger.log(&record);
 
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
         assert_eq!(messages.len(), 1);
         assert!(messages[0].contains("test message"));
         assert!(messages[0].contains("test_module@42"));
@@ -441,7 +578,12 @@ mod tests {
         logger.log(&info_record);
         logger.flush();
 
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
         assert_eq!(messages.len(), 1);
         assert!(messages[0].contains("warning"));
     }
@@ -474,7 +616,12 @@ mod tests {
         drop(sink);
         logger.flush();
 
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
 
         // Check for overflow message
         assert!(messages.iter().any(|msg| msg.contains("overflow")));
@@ -514,7 +661,12 @@ mod tests {
         });
 
         logger.flush();
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
 
         // Count actual messages (excluding overflow messages)
         let actual_messages: Vec<_> = messages
@@ -550,7 +702,12 @@ mod tests {
         );
         logger.flush();
 
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
 
         // Message should be split into chunks
         assert!(messages.len() > 1);
@@ -580,7 +737,12 @@ mod tests {
         logger.log(&record);
         logger.flush();
 
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
         assert!(!messages.is_empty()); // Should still log the metadata
     }
 
@@ -628,7 +790,12 @@ mod tests {
         // Final flush to ensure all messages are processed
         logger.flush();
 
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
         assert!(!messages.is_empty());
     }
 
@@ -654,7 +821,12 @@ mod tests {
         }
 
         logger.flush();
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
 
         // Should have some messages and potentially an overflow message
         assert!(!messages.is_empty());
@@ -685,7 +857,12 @@ mod tests {
         }
 
         logger.flush();
-        let messages = logger.sink.lock().get_messages_as_string();
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
 
         assert_eq!(messages.len(), 5);
         assert!(messages.iter().any(|msg| msg.contains("ERROR")));
@@ -694,4 +871,60 @@ mod tests {
         assert!(messages.iter().any(|msg| msg.contains("DEBUG")));
         assert!(messages.iter().any(|msg| msg.contains("TRACE")));
     }
+
+    #[test]
+    fn test_panic_message_after_buffered_logs() {
+        let logger = Logger::<TestSink, TestGlobalValueReader, 16>::new(
+            TestSink::default(),
+            LevelFilter::Info,
+        );
+
+        // hold the sink so that the record stays buffered, as if another core was halted mid-write
+        core::mem::forget(logger.sink.lock());
+        logger.log(
+            &Record::builder()
+                .args(format_args!("before"))
+                .level(Level::Info)
+                .target("test")
+                .build(),
+        );
+
+        logger.write_panic_message(format_args!("oh no"));
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string()
+            .concat();
+        let before = messages.find("before").unwrap();
+        let panic = messages.find("panic!\x1b[0m oh no").unwrap();
+        assert!(before < panic);
+    }
+
+    #[test]
+    fn test_early_records_flushed_when_sink_attached() {
+        let logger =
+            Logger::<TestSink, TestGlobalValueReader, 16>::new_without_sink(LevelFilter::Info);
+
+        logger.log(
+            &Record::builder()
+                .args(format_args!("early message"))
+                .level(Level::Info)
+                .target("test")
+                .build(),
+        );
+        logger.flush();
+        assert!(logger.sink.lock().is_none());
+
+        logger.attach_sink(TestSink::default());
+        let messages = logger
+            .sink
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get_messages_as_string();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("early message"));
+    }
 }
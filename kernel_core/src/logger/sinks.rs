@@ -0,0 +1,177 @@
+//! Sending log output to several sinks at once.
+use core::fmt::Write as _;
+
+use log::{Level, LevelFilter, Record};
+
+use super::{GlobalValues, LogSink, RecordOutput, SinkWriter};
+
+/// A sink in a [`SinkSet`], along with its level filter.
+struct Slot<S> {
+    sink: S,
+    level_filter: LevelFilter,
+    /// Number of chunks that were dropped because the sink was not ready.
+    dropped: usize,
+}
+
+/// A [`LogSink`] that sends output to a fixed set of sinks, each with its own level filter.
+///
+/// A sink that is not ready (see [`LogSink::is_ready`]) is skipped so that it does not hold up
+/// the others. Once it becomes ready again it is told how much output it missed.
+///
+/// Records are encoded in the format of the sink type `S`.
+pub struct SinkSet<S, const M: usize> {
+    slots: [Slot<S>; M],
+}
+
+impl<S: LogSink, const M: usize> SinkSet<S, M> {
+    /// Create a set of sinks, where each sink only receives records that pass its level filter.
+    pub fn new(sinks: [(S, LevelFilter); M]) -> Self {
+        Self {
+            slots: sinks.map(|(sink, level_filter)| Slot {
+                sink,
+                level_filter,
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// Send `chunk` to every sink for which `filter` returns true.
+    fn send(&mut self, filter: impl Fn(LevelFilter) -> bool, chunk: &[u8]) {
+        for slot in self.slots.iter_mut().filter(|s| filter(s.level_filter)) {
+            if !slot.sink.is_ready() {
+                slot.dropped += 1;
+                continue;
+            }
+            if slot.dropped > 0 {
+                let _ = writeln!(
+                    SinkWriter(&mut slot.sink),
+                    "\x1b[31m{} log chunks dropped!\x1b[0m",
+                    slot.dropped
+                );
+                slot.dropped = 0;
+            }
+            slot.sink.accept(chunk);
+        }
+    }
+}
+
+impl<S: LogSink, const M: usize> LogSink for SinkSet<S, M> {
+    fn accept(&mut self, chunk: &[u8]) {
+        self.send(|f| f != LevelFilter::Off, chunk);
+    }
+
+    fn accept_record_chunk(&mut self, level: Level, chunk: &[u8]) {
+        self.send(|f| level <= f, chunk);
+    }
+
+    fn write_record(out: &mut impl RecordOutput, record: &Record, global_values: &GlobalValues) {
+        S::write_record(out, record, global_values);
+    }
+
+    fn write_panic(out: &mut impl RecordOutput, message: core::fmt::Arguments) {
+        S::write_panic(out, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{GlobalValueReader, Logger};
+    use log::Log;
+    use std::{string::String, sync::Arc, vec::Vec};
+
+    #[derive(Clone, Default)]
+    struct TestSink {
+        output: Arc<spin::Mutex<Vec<u8>>>,
+        ready: Arc<core::sync::atomic::AtomicBool>,
+    }
+
+    impl TestSink {
+        fn new() -> Self {
+            let s = Self::default();
+            s.set_ready(true);
+            s
+        }
+
+        fn set_ready(&self, ready: bool) {
+            self.ready
+                .store(ready, core::sync::atomic::Ordering::Relaxed);
+        }
+
+        fn output(&self) -> String {
+            String::from_utf8_lossy(&self.output.lock()).into_owned()
+        }
+    }
+
+    impl LogSink for TestSink {
+        fn accept(&mut self, chunk: &[u8]) {
+            self.output.lock().extend_from_slice(chunk);
+        }
+
+        fn is_ready(&mut self) -> bool {
+            self.ready.load(core::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    struct TestGlobalValueReader;
+
+    impl GlobalValueReader for TestGlobalValueReader {
+        fn read() -> GlobalValues {
+            GlobalValues::default()
+        }
+    }
+
+    fn log(logger: &impl Log, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .level(level)
+                .target("test")
+                .build(),
+        );
+    }
+
+    #[test]
+    fn per_sink_level() {
+        let (a, b) = (TestSink::new(), TestSink::new());
+        let logger = Logger::<_, TestGlobalValueReader, 16>::new(
+            SinkSet::new([
+                (a.clone(), LevelFilter::Trace),
+                (b.clone(), LevelFilter::Warn),
+            ]),
+            LevelFilter::Trace,
+        );
+        log(&logger, Level::Debug, "debug message");
+        log(&logger, Level::Error, "error message");
+        logger.write_panic_message(format_args!("oh no"));
+
+        assert!(a.output().contains("debug message"));
+        assert!(a.output().contains("error message"));
+        assert!(!b.output().contains("debug message"));
+        assert!(b.output().contains("error message"));
+        assert!(b.output().contains("oh no"));
+    }
+
+    #[test]
+    fn broken_sink_does_not_block_others() {
+        let (a, b) = (TestSink::new(), TestSink::new());
+        let logger = Logger::<_, TestGlobalValueReader, 16>::new(
+            SinkSet::new([
+                (a.clone(), LevelFilter::Info),
+                (b.clone(), LevelFilter::Info),
+            ]),
+            LevelFilter::Info,
+        );
+        b.set_ready(false);
+        log(&logger, Level::Info, "first");
+        assert!(a.output().contains("first"));
+        assert!(b.output().is_empty());
+
+        b.set_ready(true);
+        log(&logger, Level::Info, "second");
+        assert!(a.output().contains("second"));
+        assert!(b.output().contains("1 log chunks dropped!"));
+        assert!(b.output().contains("second"));
+        assert!(!b.output().contains("first"));
+    }
+}
@@ -0,0 +1,404 @@
+//! Allocation of address space IDs (ASIDs), which tag TLB entries so that switching between
+//! processes doesn't require flushing the TLB.
+//!
+//! There are far fewer ASIDs than processes, so ASIDs are handed out in generations. A process
+//! keeps its ASID for as long as its generation is current. When every ASID of the current
+//! generation is in use the pool rolls over: the ASIDs active on each core are reserved so those
+//! processes can keep running, every other ASID is freed, and the generation is bumped so that other
+//! processes allocate a new ASID the next time they are activated. TLB entries for the freed ASIDs
+//! are still cached, so every core must flush its TLB before it activates an address space after
+//! the rollover. The core that rolled the pool over can send [`IpiMessage::FlushTlb`] to the others
+//! so that they do so promptly.
+//!
+//! This is the algorithm Linux uses on arm64 (`arch/arm64/mm/context.c`), except that the search
+//! for a free ASID can start at a random position in each generation (see
+//! [`AddressSpaceIdPool::with_random_start`]), so that the ASID a process gets is harder to predict.
+//!
+//! [`IpiMessage::FlushTlb`]: crate::smp::IpiMessage::FlushTlb
+use alloc::{vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::AddressSpaceId;
+use crate::sync::Mutex;
+
+/// The position of the generation in a context ID, above the largest possible ASID.
+const GENERATION_SHIFT: u32 = 16;
+
+/// The ASID allocated to an address space, along with the generation it was allocated in.
+///
+/// Each address space (i.e. process) has one of these, starting with no ASID.
+#[derive(Debug, Default)]
+pub struct AsidContext {
+    /// The generation from bit [`GENERATION_SHIFT`] up and the ASID in the low bits, or zero if
+    /// none has been allocated.
+    id: AtomicU64,
+}
+
+impl AsidContext {
+    /// A context that has not been allocated an ASID yet.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            id: AtomicU64::new(0),
+        }
+    }
+
+    /// The ASID last allocated to the address space, or `None` if it has never been activated.
+    ///
+    /// The address space's TLB entries are tagged with this ASID. If it was allocated in an older
+    /// generation it may have been handed out again since, but then the entries were flushed when
+    /// the pool rolled over, so invalidating it is at worst unnecessary.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn asid(&self) -> Option<AddressSpaceId> {
+        let id = self.id.load(Ordering::Relaxed);
+        (id != 0).then_some((id & ((1 << GENERATION_SHIFT) - 1)) as AddressSpaceId)
+    }
+}
+
+/// The result of activating an address space on a core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Activation {
+    /// The ASID to run the address space with.
+    pub asid: AddressSpaceId,
+    /// The core must invalidate its entire TLB before using the ASID.
+    pub flush_local: bool,
+    /// The pool rolled over, so the other cores should be told to flush their TLBs.
+    pub rolled_over: bool,
+}
+
+struct State {
+    /// A bit for each ASID, set if it is in use in the current generation.
+    used: Vec<u64>,
+    /// Where to start searching for a free ASID.
+    next: usize,
+    /// The context ID each core was running when the pool last rolled over, by core index.
+    reserved: Vec<u64>,
+}
+
+impl State {
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / 64] & (1 << (asid % 64)) != 0
+    }
+
+    fn set_used(&mut self, asid: usize) {
+        self.used[asid / 64] |= 1 << (asid % 64);
+    }
+}
+
+/// Hands out ASIDs to address spaces as they are activated on each core.
+pub struct AddressSpaceIdPool {
+    /// The number of bits in an ASID.
+    bits: u32,
+    /// The current generation, in the bits from [`GENERATION_SHIFT`] up.
+    generation: AtomicU64,
+    /// The context ID active on each core, by core index, or zero if the core must take the slow
+    /// path to activate an address space because the pool rolled over.
+    active: Vec<AtomicU64>,
+    /// Set for each core that must flush its TLB because the pool rolled over.
+    flush_pending: Vec<AtomicBool>,
+    /// Source of the random position each generation's search for a free ASID starts at, if any.
+    random: Option<fn() -> u64>,
+    state: Mutex<State>,
+}
+
+impl AddressSpaceIdPool {
+    /// Create a pool of the ASIDs that fit in `bits` bits (8 or 16 on Arm, see `ID_AA64MMFR0_EL1`),
+    /// for `num_cores` cores.
+    ///
+    /// ASID 0 is never handed out, so it can be used for address spaces that are never activated,
+    /// like the kernel's.
+    ///
+    /// # Panics
+    /// If `bits` is not between 1 and 16.
+    #[must_use]
+    pub fn new(bits: u32, num_cores: usize) -> Self {
+        assert!((1..=16).contains(&bits), "invalid ASID size {bits}");
+        let mut state = State {
+            used: vec![0; (1usize << bits).div_ceil(64)],
+            next: 1,
+            reserved: vec![0; num_cores],
+        };
+        state.set_used(0);
+        Self {
+            bits,
+            generation: AtomicU64::new(1 << GENERATION_SHIFT),
+            active: (0..num_cores).map(|_| AtomicU64::new(0)).collect(),
+            flush_pending: (0..num_cores).map(|_| AtomicBool::new(false)).collect(),
+            random: None,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Start the search for a free ASID at a position drawn from `random` in each generation,
+    /// instead of at ASID 1.
+    #[must_use]
+    pub fn with_random_start(mut self, random: fn() -> u64) -> Self {
+        self.random = Some(random);
+        self.state.get_mut().next = self.start();
+        self
+    }
+
+    /// Where the search for a free ASID starts in a new generation.
+    // the capacity is at most 16 bits
+    #[allow(clippy::cast_possible_truncation)]
+    fn start(&self) -> usize {
+        self.random
+            .map_or(1, |random| 1 + (random() % self.capacity() as u64) as usize)
+    }
+
+    /// The number of ASIDs that can be handed out in each generation.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        (1 << self.bits) - 1
+    }
+
+    /// The ASID part of a context ID.
+    // the ASID is at most 16 bits
+    #[allow(clippy::cast_possible_truncation)]
+    fn asid_of(&self, id: u64) -> usize {
+        (id & ((1 << self.bits) - 1)) as usize
+    }
+
+    /// True if the context ID `id` was allocated in the current generation.
+    fn is_current(&self, id: u64) -> bool {
+        (id ^ self.generation.load(Ordering::Relaxed)) >> GENERATION_SHIFT == 0
+    }
+
+    /// Activate the address space of `context` on the core with index `core`, allocating it an
+    /// ASID if it doesn't have one in the current generation.
+    ///
+    /// This must be called with the core's interrupts disabled (so that it isn't preempted) every
+    /// time an address space is switched to, and the core must use the returned ASID until the
+    /// next call.
+    ///
+    /// # Panics
+    /// If `core` is out of range.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn activate(&self, core: usize, context: &AsidContext) -> Activation {
+        let id = context.id.load(Ordering::Relaxed);
+        // if the pool rolls over concurrently it zeroes this core's active ID, so the exchange
+        // fails and the slow path is taken
+        let old_active = self.active[core].load(Ordering::Relaxed);
+        if old_active != 0
+            && self.is_current(id)
+            && self.active[core]
+                .compare_exchange(old_active, id, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            return Activation {
+                asid: self.asid_of(id) as AddressSpaceId,
+                flush_local: false,
+                rolled_over: false,
+            };
+        }
+
+        let mut state = self.state.lock();
+        let mut id = context.id.load(Ordering::Relaxed);
+        let mut rolled_over = false;
+        if !self.is_current(id) {
+            (id, rolled_over) = self.new_context(&mut state, id);
+            context.id.store(id, Ordering::Relaxed);
+        }
+        let flush_local = self.flush_pending[core].swap(false, Ordering::AcqRel);
+        self.active[core].store(id, Ordering::Relaxed);
+        Activation {
+            asid: self.asid_of(id) as AddressSpaceId,
+            flush_local,
+            rolled_over,
+        }
+    }
+
+    /// Run `flush` to invalidate the TLB of the core with index `core` if it must be flushed
+    /// because the pool rolled over, for instance when the core is told about the rollover with an
+    /// IPI. Returns true if the TLB was flushed.
+    ///
+    /// # Panics
+    /// If `core` is out of range.
+    pub fn flush_if_pending(&self, core: usize, flush: impl FnOnce()) -> bool {
+        // clear the flag first so that a rollover after this point is not missed
+        let pending = self.flush_pending[core].swap(false, Ordering::AcqRel);
+        if pending {
+            flush();
+        }
+        pending
+    }
+
+    /// Allocate a context ID in the current generation for an address space whose previous ID was
+    /// `old`, rolling the pool over if every ASID is in use. Returns the new ID and whether the
+    /// pool rolled over.
+    fn new_context(&self, state: &mut State, old: u64) -> (u64, bool) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        if old != 0 {
+            let asid = self.asid_of(old);
+            let new = generation | asid as u64;
+            // a core was running this address space when the pool rolled over, so it kept its ASID
+            let mut reserved = false;
+            for id in &mut state.reserved {
+                if *id == old {
+                    *id = new;
+                    reserved = true;
+                }
+            }
+            if reserved {
+                return (new, false);
+            }
+            // otherwise keep the same ASID if nothing else has taken it in this generation
+            if !state.is_used(asid) {
+                state.set_used(asid);
+                return (new, false);
+            }
+        }
+
+        let limit = 1 << self.bits;
+        let find_free = |state: &State| {
+            (state.next..limit)
+                .chain(1..state.next)
+                .find(|a| !state.is_used(*a))
+        };
+        let mut rolled_over = false;
+        let asid = if let Some(asid) = find_free(state) {
+            asid
+        } else {
+            self.roll_over(state);
+            rolled_over = true;
+            find_free(state).expect("rollover frees an ASID, since there are more ASIDs than cores")
+        };
+        state.set_used(asid);
+        state.next = asid;
+        (
+            self.generation.load(Ordering::Relaxed) | asid as u64,
+            rolled_over,
+        )
+    }
+
+    /// Start a new generation, keeping only the ASIDs active on each core.
+    fn roll_over(&self, state: &mut State) {
+        let generation = self
+            .generation
+            .fetch_add(1 << GENERATION_SHIFT, Ordering::Relaxed)
+            + (1 << GENERATION_SHIFT);
+        log::trace!(
+            "ASID pool rolled over to generation {}",
+            generation >> GENERATION_SHIFT
+        );
+        state.used.fill(0);
+        state.set_used(0);
+        for (core, active) in self.active.iter().enumerate() {
+            let mut id = active.swap(0, Ordering::Relaxed);
+            // a core that hasn't activated an address space since the last rollover is still
+            // running the one it had reserved then
+            if id == 0 {
+                id = state.reserved[core];
+            }
+            let asid = self.asid_of(id);
+            state.set_used(asid);
+            state.reserved[core] = id;
+        }
+        for pending in &self.flush_pending {
+            pending.store(true, Ordering::Release);
+        }
+        state.next = self.start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_asid_without_flushing() {
+        let pool = AddressSpaceIdPool::new(8, 2);
+        assert_eq!(pool.capacity(), 255);
+        let (a, b) = (AsidContext::new(), AsidContext::new());
+        assert_eq!(a.asid(), None);
+        let first = pool.activate(0, &a);
+        assert_eq!(a.asid(), Some(1));
+        assert_eq!(
+            first,
+            Activation {
+                asid: 1,
+                flush_local: false,
+                rolled_over: false
+            }
+        );
+        assert_eq!(pool.activate(1, &b).asid, 2);
+        // activating again on any core takes the fast path and keeps the ASID
+        assert_eq!(pool.activate(1, &a), first);
+        assert_eq!(pool.activate(0, &a), first);
+    }
+
+    #[test]
+    fn rollover_reserves_active_asids() {
+        // ASIDs 1 to 3
+        let pool = AddressSpaceIdPool::new(2, 2);
+        let contexts: Vec<_> = (0..5).map(|_| AsidContext::new()).collect();
+        assert_eq!(pool.activate(0, &contexts[0]).asid, 1);
+        assert_eq!(pool.activate(1, &contexts[1]).asid, 2);
+        assert_eq!(pool.activate(0, &contexts[2]).asid, 3);
+
+        // core 0 runs out of ASIDs, so it rolls over and flushes its TLB
+        let rollover = pool.activate(0, &contexts[3]);
+        assert_eq!(
+            rollover,
+            Activation {
+                asid: 1,
+                flush_local: true,
+                rolled_over: true
+            }
+        );
+        assert_eq!(contexts[3].asid(), Some(1));
+        // core 1 is still running with ASID 2, which it keeps, but it must flush before switching
+        let mut flushed = false;
+        assert!(pool.flush_if_pending(1, || flushed = true));
+        assert!(flushed);
+        assert!(!pool.flush_if_pending(1, || panic!("flushed twice")));
+        assert_eq!(
+            pool.activate(1, &contexts[1]),
+            Activation {
+                asid: 2,
+                flush_local: false,
+                rolled_over: false
+            }
+        );
+        // the address space core 0 was running before the rollover kept its ASID too
+        assert_eq!(pool.activate(0, &contexts[2]).asid, 3);
+
+        // the first address space lost its ASID, and there are none left, so the pool rolls over
+        // again
+        let again = pool.activate(0, &contexts[0]);
+        assert!(again.rolled_over && again.flush_local);
+        assert_ne!(again.asid, 2);
+        assert_eq!(pool.activate(1, &contexts[1]).asid, 2);
+    }
+
+    #[test]
+    fn random_start_wraps_around() {
+        // ASIDs 1 to 3, searched from 3
+        let pool = AddressSpaceIdPool::new(2, 1).with_random_start(|| 5);
+        let contexts: Vec<_> = (0..4).map(|_| AsidContext::new()).collect();
+        let asids: Vec<_> = contexts[..3].iter().map(|c| pool.activate(0, c)).collect();
+        assert_eq!(asids.iter().map(|a| a.asid).collect::<Vec<_>>(), [3, 1, 2]);
+        assert!(asids.iter().all(|a| !a.rolled_over));
+        assert!(pool.activate(0, &contexts[3]).rolled_over);
+    }
+
+    #[test]
+    fn slow_path_after_rollover_on_other_core() {
+        let pool = AddressSpaceIdPool::new(2, 2);
+        let contexts: Vec<_> = (0..4).map(|_| AsidContext::new()).collect();
+        let on_core_1 = pool.activate(1, &contexts[0]);
+        pool.activate(0, &contexts[1]);
+        pool.activate(0, &contexts[2]);
+        assert!(pool.activate(0, &contexts[3]).rolled_over);
+        // the rollover cleared core 1's active ID, so it notices the pending flush even though its
+        // address space is still current
+        assert_eq!(
+            pool.activate(1, &contexts[0]),
+            Activation {
+                flush_local: true,
+                ..on_core_1
+            }
+        );
+    }
+}
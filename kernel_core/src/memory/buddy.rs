@@ -2,14 +2,17 @@
 
 use core::{
     ptr::{null_mut, NonNull},
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
 };
 
 use snafu::{ensure, OptionExt as _};
 
 use crate::memory::{InvalidSizeSnafu, OutOfMemorySnafu, UnknownPtrSnafu};
 
-use super::{Error, PageAllocator, PageSize, PhysicalAddress};
+use super::{
+    AllocationConstraints, Error, MemoryStatistics, PageAllocator, PageSize, PhysicalAddress,
+    PAGES_ALLOCATED, PAGES_FREED,
+};
 
 #[repr(C)]
 struct FreeHeader {
@@ -20,12 +23,17 @@ struct FreeHeader {
 /// memory.
 ///
 /// `MAX_ORDER` is the largest power of two block of pages that will be managed by the allocator.
+///
+/// Allocations are rounded up to a power of two pages, unless they are made with
+/// [`Self::allocate_exact`].
 #[allow(clippy::module_name_repetitions)]
 pub struct BuddyPageAllocator<const MAX_ORDER: usize = 16> {
     base_addr: *mut u8,
     end_addr: *mut u8,
     page_size: PageSize,
     free_blocks: [AtomicPtr<FreeHeader>; MAX_ORDER],
+    total_pages: AtomicUsize,
+    free_pages: AtomicUsize,
 }
 
 unsafe impl Send for BuddyPageAllocator {}
@@ -54,6 +62,8 @@ impl<const MAX_ORDER: usize> BuddyPageAllocator<MAX_ORDER> {
             end_addr: unsafe { memory_start.add(memory_length) },
             page_size,
             free_blocks: [const { AtomicPtr::new(null_mut()) }; MAX_ORDER],
+            total_pages: AtomicUsize::new(0),
+            free_pages: AtomicUsize::new(0),
         }
     }
 
@@ -96,6 +106,8 @@ impl<const MAX_ORDER: usize> BuddyPageAllocator<MAX_ORDER> {
                     });
                 }
                 self.push_free(order, block);
+                self.total_pages.fetch_add(1 << order, Ordering::Relaxed);
+                self.free_pages.fetch_add(1 << order, Ordering::Relaxed);
                 remaining_bytes -= block_len;
                 block_start = block_start.add(block_len);
             } else {
@@ -216,15 +228,37 @@ impl<const MAX_ORDER: usize> BuddyPageAllocator<MAX_ORDER> {
         }
     }
 
-    fn block_in_free_list(&self, order: usize, block: NonNull<FreeHeader>) -> bool {
+    /// Returns true if any page of `block` of order `order` is free, either in a free block of its
+    /// own or as part of a larger free block.
+    ///
+    /// This walks every free list, so it is only used to catch double frees in debug builds.
+    fn is_free(&self, block: NonNull<FreeHeader>, order: usize) -> bool {
+        let start = block.as_ptr() as usize;
+        let end = start + self.page_size * (1 << order);
+        (0..MAX_ORDER).any(|o| {
+            let length = self.page_size * (1 << o);
+            self.find_free(o, |free| {
+                let free_start = free.as_ptr() as usize;
+                free_start < end && start < free_start + length
+            })
+            .is_some()
+        })
+    }
+
+    /// Find a block in the free list of order `order` for which `pred` is true.
+    fn find_free(
+        &self,
+        order: usize,
+        pred: impl Fn(NonNull<FreeHeader>) -> bool,
+    ) -> Option<NonNull<FreeHeader>> {
         let mut cur = NonNull::new(self.free_blocks[order].load(Ordering::Acquire));
         while let Some(n) = cur {
-            if n == block {
-                return true;
+            if pred(n) {
+                return Some(n);
             }
             cur = unsafe { NonNull::new(n.as_ref().next_block.load(Ordering::Relaxed)) };
         }
-        false
+        None
     }
 
     #[cfg(test)]
@@ -262,29 +296,8 @@ impl<const MAX_ORDER: usize> BuddyPageAllocator<MAX_ORDER> {
         block
     }
 
-    unsafe fn buddy_of(&self, block: NonNull<FreeHeader>, order: usize) -> NonNull<FreeHeader> {
-        let offset: usize = unsafe { block.cast::<u8>().as_ptr().offset_from(self.base_addr) }
-            .try_into()
-            .unwrap();
-        let buddy_offset = offset ^ (self.page_size * (1 << order));
-        let ptr = unsafe { self.base_addr.add(buddy_offset) };
-        NonNull::new(ptr).unwrap().cast()
-    }
-}
-
-impl<const MAX_ORDER: usize> PageAllocator for BuddyPageAllocator<MAX_ORDER> {
-    fn page_size(&self) -> PageSize {
-        self.page_size
-    }
-
-    fn allocate(&self, num_pages: usize) -> Result<PhysicalAddress, Error> {
-        ensure!(num_pages > 0, InvalidSizeSnafu);
-
-        let block_size = num_pages
-            .checked_next_power_of_two()
-            .context(OutOfMemorySnafu)?;
-        let order = block_size.ilog2() as usize;
-
+    /// Remove a block of order `order` from the free lists, splitting a larger block if necessary.
+    fn take_block(&self, order: usize) -> Result<NonNull<FreeHeader>, Error> {
         let mut actual_order = order;
         let free_block = loop {
             ensure!(actual_order < MAX_ORDER, OutOfMemorySnafu);
@@ -294,12 +307,15 @@ impl<const MAX_ORDER: usize> PageAllocator for BuddyPageAllocator<MAX_ORDER> {
             actual_order += 1;
         };
 
-        let block = self.split_block_to_size(free_block, actual_order, order);
-
-        Ok(PhysicalAddress::from(block.as_ptr().cast()))
+        Ok(self.split_block_to_size(free_block, actual_order, order))
     }
 
-    fn free(&self, pages: PhysicalAddress, num_pages: usize) -> Result<(), Error> {
+    /// Check that `pages` could have been allocated by this allocator, returning the block it points to.
+    fn check_freed_pointer(
+        &self,
+        pages: PhysicalAddress,
+        num_pages: usize,
+    ) -> Result<NonNull<FreeHeader>, Error> {
         let pages_ptr: *mut () = pages.into();
         let block = NonNull::new(pages_ptr.cast()).context(UnknownPtrSnafu)?;
         ensure!(num_pages > 0, InvalidSizeSnafu);
@@ -307,38 +323,234 @@ impl<const MAX_ORDER: usize> PageAllocator for BuddyPageAllocator<MAX_ORDER> {
             pages_ptr.cast() >= self.base_addr && pages_ptr.cast() < self.end_addr,
             UnknownPtrSnafu
         );
+        Ok(block)
+    }
 
-        let block_size = num_pages
-            .checked_next_power_of_two()
-            .context(InvalidSizeSnafu)?;
-        let order = block_size.ilog2() as usize;
+    /// The index of the page `block` starts at, counting from the start of the allocator's memory.
+    fn page_index(&self, block: NonNull<FreeHeader>) -> usize {
+        let offset: usize = unsafe { block.cast::<u8>().as_ptr().offset_from(self.base_addr) }
+            .try_into()
+            .unwrap();
+        offset / self.page_size
+    }
+
+    /// Check that `block` of order `order` could be an allocated block of this allocator, so that
+    /// it can be freed.
+    fn check_freed_block(&self, block: NonNull<FreeHeader>, order: usize) -> Result<(), Error> {
         ensure!(order < MAX_ORDER, InvalidSizeSnafu);
+        ensure!(
+            block.as_ptr() as usize + self.page_size * (1 << order) <= self.end_addr as usize,
+            UnknownPtrSnafu
+        );
+        // prevent double frees
+        if cfg!(debug_assertions) {
+            ensure!(!self.is_free(block, order), UnknownPtrSnafu);
+        }
+        Ok(())
+    }
 
+    /// Return the allocated block `block` of order `order` to the free lists, merging it with its
+    /// buddies for as long as they are free. The block must have been checked by
+    /// [`Self::check_freed_block`].
+    fn free_block(&self, block: NonNull<FreeHeader>, order: usize) {
         let buddy = unsafe { self.buddy_of(block, order) };
 
         #[cfg(test)]
         std::println!("free block={block:x?} order={order} buddy={buddy:x?}");
 
-        if self.try_remove_buddy(order, buddy) {
+        let (mut merged, mut merged_order) = (block, order);
+        if order + 1 < MAX_ORDER && self.try_remove_buddy(order, buddy) {
             #[cfg(test)]
             std::println!("removed buddy");
-            unsafe {
-                self.push_free(order + 1, block);
+            // pages freed a few at a time are only whole again once every part is merged
+            merged = merged.min(buddy);
+            merged_order += 1;
+            while merged_order + 1 < MAX_ORDER {
+                let buddy = unsafe { self.buddy_of(merged, merged_order) };
+                if !self.try_remove_buddy(merged_order, buddy) {
+                    break;
+                }
+                merged = merged.min(buddy);
+                merged_order += 1;
             }
         } else {
-            // prevent double frees
-            ensure!(!self.block_in_free_list(order, block), UnknownPtrSnafu);
-            ensure!(!self.block_in_free_list(order + 1, block), UnknownPtrSnafu);
-            ensure!(!self.block_in_free_list(order + 1, buddy), UnknownPtrSnafu);
             #[cfg(test)]
             std::println!("buddy is allocated");
+        }
+        unsafe {
+            self.push_free(merged_order, merged);
+        }
+        self.free_pages.fetch_add(1 << order, Ordering::Relaxed);
+    }
+
+    /// Return the pages past the first `num_pages` of the allocated block `block` of `block_size`
+    /// pages to the free lists, so that exactly `num_pages` stay allocated.
+    fn trim(&self, block: NonNull<FreeHeader>, num_pages: usize, block_size: usize) {
+        // the tail is split into the largest blocks that are aligned within the block, none of
+        // which can be merged with their buddies since those are at least partially allocated
+        let mut offset = num_pages;
+        while offset < block_size {
+            let order = offset.trailing_zeros() as usize;
             unsafe {
-                self.push_free(order, block);
+                let tail = block.cast::<u8>().add(offset * self.page_size).cast();
+                self.push_free(order, tail);
             }
+            offset += 1 << order;
+        }
+        self.free_pages.fetch_sub(num_pages, Ordering::Relaxed);
+    }
+
+    /// The largest naturally aligned blocks that the `num_pages` pages at `block` are made of, with
+    /// the order of each.
+    fn aligned_blocks(
+        &self,
+        block: NonNull<FreeHeader>,
+        num_pages: usize,
+    ) -> impl Iterator<Item = (NonNull<FreeHeader>, usize)> + '_ {
+        let first_page = self.page_index(block);
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            if offset >= num_pages {
+                return None;
+            }
+            let order = ((first_page + offset).trailing_zeros() as usize)
+                .min((num_pages - offset).ilog2() as usize);
+            let part = unsafe { block.cast::<u8>().add(offset * self.page_size).cast() };
+            offset += 1 << order;
+            Some((part, order))
+        })
+    }
+
+    /// Allocate exactly `num_pages` of contiguous memory, returning a pointer to the beginning.
+    ///
+    /// Unlike [`PageAllocator::allocate`], the size is not rounded up to a power of two. A block
+    /// large enough is allocated as usual, and then the pages past the end of the allocation are
+    /// immediately returned to the free lists.
+    /// The pages must be freed with [`Self::free_exact`].
+    ///
+    /// # Errors
+    /// - [`Error::OutOfMemory`] if there is not enough memory to allocate `num_pages`.
+    /// - [`Error::InvalidSize`] if `num_pages` is zero.
+    pub fn allocate_exact(&self, num_pages: usize) -> Result<PhysicalAddress, Error> {
+        ensure!(num_pages > 0, InvalidSizeSnafu);
+
+        let block_size = num_pages
+            .checked_next_power_of_two()
+            .context(OutOfMemorySnafu)?;
+        let block = self.take_block(block_size.ilog2() as usize)?;
+        self.trim(block, num_pages, block_size);
+
+        let pages = PhysicalAddress::from(block.as_ptr().cast());
+        PAGES_ALLOCATED.hit(usize::from(pages) as u64, num_pages as u64);
+        Ok(pages)
+    }
+
+    /// Free the `num_pages` pages pointed to by `pages`, which must be part of an allocation made
+    /// by [`Self::allocate_exact`]. The pages of an allocation can be freed all at once, or a few
+    /// at a time as each of them stops being used.
+    ///
+    /// Nothing is freed unless all of the pages can be.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownPtr`] if `pages` is null or was not allocated by this allocator.
+    /// - [`Error::InvalidSize`] if `num_pages` is zero or larger than any block.
+    pub fn free_exact(&self, pages: PhysicalAddress, num_pages: usize) -> Result<(), Error> {
+        let block = self.check_freed_pointer(pages, num_pages)?;
+        ensure!(num_pages < 1 << MAX_ORDER, InvalidSizeSnafu);
+        for (part, order) in self.aligned_blocks(block, num_pages) {
+            self.check_freed_block(part, order)?;
+        }
+        PAGES_FREED.hit(usize::from(pages) as u64, num_pages as u64);
+        for (part, order) in self.aligned_blocks(block, num_pages) {
+            self.free_block(part, order);
         }
+        Ok(())
+    }
 
+    unsafe fn buddy_of(&self, block: NonNull<FreeHeader>, order: usize) -> NonNull<FreeHeader> {
+        let offset: usize = unsafe { block.cast::<u8>().as_ptr().offset_from(self.base_addr) }
+            .try_into()
+            .unwrap();
+        let buddy_offset = offset ^ (self.page_size * (1 << order));
+        let ptr = unsafe { self.base_addr.add(buddy_offset) };
+        NonNull::new(ptr).unwrap().cast()
+    }
+}
+
+impl<const MAX_ORDER: usize> PageAllocator for BuddyPageAllocator<MAX_ORDER> {
+    fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    fn allocate(&self, num_pages: usize) -> Result<PhysicalAddress, Error> {
+        ensure!(num_pages > 0, InvalidSizeSnafu);
+
+        let block_size = num_pages
+            .checked_next_power_of_two()
+            .context(OutOfMemorySnafu)?;
+        let block = self.take_block(block_size.ilog2() as usize)?;
+        self.free_pages.fetch_sub(block_size, Ordering::Relaxed);
+
+        let pages = PhysicalAddress::from(block.as_ptr().cast());
+        PAGES_ALLOCATED.hit(usize::from(pages) as u64, num_pages as u64);
+        Ok(pages)
+    }
+
+    fn free(&self, pages: PhysicalAddress, num_pages: usize) -> Result<(), Error> {
+        let block = self.check_freed_pointer(pages, num_pages)?;
+        let block_size = num_pages
+            .checked_next_power_of_two()
+            .context(InvalidSizeSnafu)?;
+        let order = block_size.ilog2() as usize;
+        self.check_freed_block(block, order)?;
+        PAGES_FREED.hit(usize::from(pages) as u64, num_pages as u64);
+        self.free_block(block, order);
         Ok(())
     }
+
+    fn allocate_constrained(
+        &self,
+        num_pages: usize,
+        constraints: &AllocationConstraints,
+    ) -> Result<PhysicalAddress, Error> {
+        ensure!(num_pages > 0, InvalidSizeSnafu);
+        ensure!(constraints.alignment.is_power_of_two(), InvalidSizeSnafu);
+
+        let block_size = num_pages
+            .checked_next_power_of_two()
+            .context(OutOfMemorySnafu)?;
+        let order = block_size.ilog2() as usize;
+        let length = block_size * self.page_size;
+
+        // splitting a block keeps its first half, so a larger block is suitable if its start is
+        let suitable = |block: NonNull<FreeHeader>| {
+            constraints.allow(PhysicalAddress::from(block.as_ptr().cast()), length)
+        };
+        for actual_order in order..MAX_ORDER {
+            while let Some(free_block) = self.find_free(actual_order, suitable) {
+                // the block may have been taken by someone else in the meantime
+                if self.try_remove_buddy(actual_order, free_block) {
+                    let block = self.split_block_to_size(free_block, actual_order, order);
+                    self.free_pages.fetch_sub(block_size, Ordering::Relaxed);
+                    let pages = PhysicalAddress::from(block.as_ptr().cast());
+                    PAGES_ALLOCATED.hit(usize::from(pages) as u64, num_pages as u64);
+                    return Ok(pages);
+                }
+            }
+        }
+        OutOfMemorySnafu.fail()
+    }
+
+    fn statistics(&self) -> MemoryStatistics {
+        let largest_free_order = (0..MAX_ORDER)
+            .rev()
+            .find(|order| !self.free_blocks[*order].load(Ordering::Acquire).is_null());
+        MemoryStatistics {
+            total_pages: self.total_pages.load(Ordering::Relaxed),
+            free_pages: self.free_pages.load(Ordering::Relaxed),
+            largest_free_block: largest_free_order.map_or(0, |order| 1 << order),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -358,7 +570,8 @@ mod tests {
         let page_size = PageSize::FourKiB;
         let total_pages = 512;
         let total_size = total_pages * page_size;
-        let layout = Layout::from_size_align(total_size, usize::from(page_size)).unwrap();
+        // align the memory to its size, like a real memory region, so address constraints are predictable
+        let layout = Layout::from_size_align(total_size, total_size).unwrap();
         let memory = unsafe { std::alloc::alloc(layout) };
         assert!(!memory.is_null());
 
@@ -407,6 +620,10 @@ mod tests {
     fn cleanup_allocator(cx: TestContext, allocator: BuddyPageAllocator) {
         // every page should be free at the end
         assert_eq!(allocator.total_pages_free(), cx.num_pages_free_at_end);
+        assert_eq!(
+            allocator.statistics().free_pages,
+            allocator.total_pages_free()
+        );
         unsafe {
             std::alloc::dealloc(cx.memory, cx.layout);
         }
@@ -419,6 +636,126 @@ mod tests {
         cleanup_allocator
     );
 
+    #[test]
+    fn statistics() {
+        let (cx, allocator) = setup_allocator_with_gap();
+        assert_eq!(
+            allocator.statistics(),
+            MemoryStatistics {
+                total_pages: 512,
+                free_pages: 512,
+                largest_free_block: 256,
+            }
+        );
+
+        let a = allocator.allocate(3).unwrap();
+        let b = allocator.allocate(128).unwrap();
+        let stats = allocator.statistics();
+        assert_eq!(stats.free_pages, 512 - 4 - 128);
+        assert_eq!(stats.largest_free_block, 256);
+
+        allocator.free(a, 3).unwrap();
+        allocator.free(b, 128).unwrap();
+        assert_eq!(allocator.statistics().free_pages, 512);
+        cleanup_allocator(cx, allocator);
+    }
+
+    #[test]
+    fn allocate_constrained() {
+        let (cx, allocator) = setup_allocator();
+        let base = usize::from(PhysicalAddress::from(cx.memory.cast::<()>()));
+        let a = allocator.allocate(1).unwrap();
+
+        // only the first 8 pages are low enough
+        let low = AllocationConstraints {
+            alignment: 1,
+            max_address: base + 8 * PageSize::FourKiB - 1,
+        };
+        let b = allocator.allocate_constrained(3, &low).unwrap();
+        assert!(low.allow(b, 4 * PageSize::FourKiB));
+        assert!(matches!(
+            allocator.allocate_constrained(4, &low),
+            Err(Error::OutOfMemory)
+        ));
+        assert!(matches!(
+            allocator.allocate_constrained(
+                1,
+                &AllocationConstraints {
+                    alignment: 3,
+                    ..Default::default()
+                }
+            ),
+            Err(Error::InvalidSize)
+        ));
+
+        let aligned = AllocationConstraints {
+            alignment: 64 * PageSize::FourKiB,
+            max_address: usize::MAX,
+        };
+        let c = allocator.allocate_constrained(2, &aligned).unwrap();
+        assert_eq!(usize::from(c), base + 64 * PageSize::FourKiB);
+        assert_eq!(allocator.statistics().free_pages, 512 - 1 - 4 - 2);
+
+        allocator.free(a, 1).unwrap();
+        allocator.free(b, 3).unwrap();
+        allocator.free(c, 2).unwrap();
+        cleanup_allocator(cx, allocator);
+    }
+
+    #[test]
+    fn allocate_exact() {
+        let (cx, allocator) = setup_allocator();
+        let base = usize::from(PhysicalAddress::from(cx.memory.cast::<()>()));
+
+        let a = allocator.allocate_exact(5).unwrap();
+        assert_eq!(allocator.statistics().free_pages, 512 - 5);
+        // the three pages after the allocation are free again
+        let b = allocator.allocate(1).unwrap();
+        let c = allocator.allocate(2).unwrap();
+        assert_eq!(usize::from(b), usize::from(a) + 5 * PageSize::FourKiB);
+        assert_eq!(usize::from(c), usize::from(a) + 6 * PageSize::FourKiB);
+
+        let d = allocator.allocate_exact(200).unwrap();
+        assert_eq!(usize::from(d), base + 256 * PageSize::FourKiB);
+        assert_eq!(allocator.statistics().free_pages, 512 - 5 - 1 - 2 - 200);
+
+        assert!(matches!(
+            allocator.allocate_exact(0),
+            Err(Error::InvalidSize)
+        ));
+        assert!(matches!(
+            allocator.free_exact(d, 1 << 16),
+            Err(Error::InvalidSize)
+        ));
+
+        // parts of an allocation that are not aligned to their size can be freed too
+        let e = allocator.allocate_exact(8).unwrap();
+        allocator
+            .free_exact(e.byte_add(usize::from(PageSize::FourKiB)), 2)
+            .unwrap();
+        assert!(matches!(
+            allocator.free_exact(e.byte_add(2 * PageSize::FourKiB), 2),
+            Err(Error::UnknownPtr)
+        ));
+        // nothing is freed if any part is already free
+        assert!(matches!(allocator.free_exact(e, 3), Err(Error::UnknownPtr)));
+        allocator.free_exact(e, 1).unwrap();
+        allocator
+            .free_exact(e.byte_add(3 * PageSize::FourKiB), 5)
+            .unwrap();
+
+        // the pages of an allocation can be freed separately
+        allocator
+            .free_exact(a.byte_add(4 * PageSize::FourKiB), 1)
+            .unwrap();
+        allocator.free_exact(a, 4).unwrap();
+        allocator.free(b, 1).unwrap();
+        allocator.free(c, 2).unwrap();
+        allocator.free_exact(d, 200).unwrap();
+        assert_eq!(allocator.statistics().free_pages, 512);
+        cleanup_allocator(cx, allocator);
+    }
+
     #[test]
     fn real_world_4gib() {
         let page_size = PageSize::FourKiB;
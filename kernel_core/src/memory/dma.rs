@@ -0,0 +1,128 @@
+//! Allocation of physically contiguous buffers that devices can access directly (DMA).
+
+use super::{AllocationConstraints, Error, PageAllocator, PhysicalAddress};
+
+/// A physically contiguous buffer allocated for a device to access directly.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    physical_address: PhysicalAddress,
+    num_pages: usize,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// The physical address of the buffer, to give to the device.
+    #[must_use]
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.physical_address
+    }
+
+    /// A pointer to the buffer in the kernel address space, for the kernel to access it.
+    #[must_use]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.physical_address.cast().into()
+    }
+
+    /// The length of the buffer in bytes.
+    ///
+    /// This is the requested size rounded up to a whole number of pages.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// True if the buffer has a length of zero, which never happens.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Allocates zeroed buffers for devices that are physically contiguous and meet the device's
+/// addressing constraints.
+pub struct DmaAllocator<'pa, PA> {
+    page_allocator: &'pa PA,
+}
+
+impl<'pa, PA: PageAllocator> DmaAllocator<'pa, PA> {
+    /// Create a new allocator that allocates buffers from `page_allocator`.
+    pub fn new(page_allocator: &'pa PA) -> Self {
+        Self { page_allocator }
+    }
+
+    /// Allocate a zeroed buffer of at least `size` bytes that satisfies `constraints`.
+    ///
+    /// # Errors
+    /// - [`Error::OutOfMemory`] if there is not enough memory that satisfies the constraints.
+    /// - [`Error::InvalidSize`] if `size` is zero or the alignment is not a power of two.
+    pub fn allocate(
+        &self,
+        size: usize,
+        constraints: &AllocationConstraints,
+    ) -> Result<DmaBuffer, Error> {
+        let page_size = self.page_allocator.page_size();
+        let num_pages = size.div_ceil(page_size.into());
+        let physical_address = self
+            .page_allocator
+            .allocate_constrained(num_pages, constraints)?;
+        let buffer = DmaBuffer {
+            physical_address,
+            num_pages,
+            len: num_pages * page_size,
+        };
+        unsafe {
+            core::ptr::write_bytes(buffer.as_ptr(), 0, buffer.len);
+        }
+        Ok(buffer)
+    }
+
+    /// Free a buffer allocated by [`Self::allocate`].
+    ///
+    /// # Errors
+    /// - [`Error::UnknownPtr`] if the buffer was not allocated by this allocator.
+    // the buffer is taken by value so that it can't be used after it is freed
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn free(&self, buffer: DmaBuffer) -> Result<(), Error> {
+        self.page_allocator
+            .free(buffer.physical_address, buffer.num_pages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{tests::MockPageAllocator, PageSize};
+
+    use super::*;
+
+    #[test]
+    fn allocate_buffer() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let dma = DmaAllocator::new(&pa);
+
+        let buffer = dma
+            .allocate(5000, &AllocationConstraints::default())
+            .unwrap();
+        assert_eq!(buffer.len(), 2 * PageSize::FourKiB);
+        let contents = unsafe { core::slice::from_raw_parts(buffer.as_ptr(), buffer.len()) };
+        assert!(contents.iter().all(|b| *b == 0));
+
+        assert!(matches!(
+            dma.allocate(0, &AllocationConstraints::default()),
+            Err(Error::InvalidSize)
+        ));
+        // no memory is this low, so the pages are returned
+        assert!(matches!(
+            dma.allocate(
+                1,
+                &AllocationConstraints {
+                    alignment: 1,
+                    max_address: 0,
+                }
+            ),
+            Err(Error::OutOfMemory)
+        ));
+
+        dma.free(buffer).unwrap();
+        pa.end_check();
+    }
+}
@@ -0,0 +1,221 @@
+//! Per-page metadata for physical memory, used to share pages between address spaces.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use snafu::OptionExt as _;
+
+use super::{Error, PageSize, PhysicalAddress, UnknownPtrSnafu};
+
+/// Flags describing how a physical page frame is being used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameFlags(u32);
+
+impl FrameFlags {
+    /// No flags.
+    pub const NONE: Self = Self(0);
+    /// The frame is shared by several mappings that must copy it before writing to it.
+    pub const COPY_ON_WRITE: Self = Self(1 << 0);
+    /// The frame must stay at this physical address, for instance because a device is accessing it.
+    pub const PINNED: Self = Self(1 << 1);
+
+    /// Returns true if every flag in `other` is also set in `self`.
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for FrameFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Metadata for a single physical page frame.
+#[derive(Debug, Default)]
+pub struct PageFrame {
+    ref_count: AtomicU32,
+    flags: AtomicU32,
+}
+
+impl PageFrame {
+    /// The number of mappings that refer to this frame.
+    pub fn ref_count(&self) -> u32 {
+        self.ref_count.load(Ordering::Acquire)
+    }
+
+    /// The current flags for this frame.
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags(self.flags.load(Ordering::Acquire))
+    }
+
+    /// Set the flags in `flags` for this frame.
+    pub fn set_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.0, Ordering::AcqRel);
+    }
+
+    /// Clear the flags in `flags` for this frame.
+    pub fn clear_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.0, Ordering::AcqRel);
+    }
+}
+
+/// The frames for a contiguous region of physical memory.
+struct FrameRegion {
+    start: usize,
+    frames: Box<[PageFrame]>,
+}
+
+/// Tracks the reference count and flags of every physical page frame in the system.
+///
+/// A frame's reference count is the number of mappings to it. Mapping a frame takes a reference
+/// with [`Self::get`], and unmapping it releases the reference with [`Self::put`]. When the last
+/// reference is released, the frame can be returned to the page allocator once it is no longer
+/// in any TLB.
+pub struct PageFrameDatabase {
+    page_size: PageSize,
+    regions: Vec<FrameRegion>,
+}
+
+impl PageFrameDatabase {
+    /// Create a database for the frames of size `page_size` in each of the (start, length in bytes)
+    /// regions of physical memory. Every frame starts with no references and no flags.
+    pub fn new(
+        page_size: PageSize,
+        regions: impl Iterator<Item = (PhysicalAddress, usize)>,
+    ) -> Self {
+        Self {
+            page_size,
+            regions: regions
+                .map(|(start, length)| FrameRegion {
+                    start: start.into(),
+                    frames: (0..length / page_size)
+                        .map(|_| PageFrame::default())
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The total number of frames tracked by the database.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.regions.iter().map(|r| r.frames.len()).sum()
+    }
+
+    /// True if the database does not track any frames.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The metadata for the frame containing `address`, if it is tracked.
+    #[must_use]
+    pub fn frame(&self, address: PhysicalAddress) -> Option<&PageFrame> {
+        let address = usize::from(address);
+        self.regions.iter().find_map(|r| {
+            r.frames
+                .get(address.checked_sub(r.start)? / usize::from(self.page_size))
+        })
+    }
+
+    /// Take a reference to the frame containing `address`, returning the new reference count.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownPtr`] if the frame is not tracked by the database.
+    pub fn get(&self, address: PhysicalAddress) -> Result<u32, Error> {
+        let frame = self.frame(address).context(UnknownPtrSnafu)?;
+        Ok(frame.ref_count.fetch_add(1, Ordering::AcqRel) + 1)
+    }
+
+    /// Release a reference to the frame containing `address`, returning the new reference count.
+    ///
+    /// When the count reaches zero the frame's flags are cleared, and the frame should be freed.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownPtr`] if the frame is not tracked by the database.
+    ///
+    /// # Panics
+    /// If the frame has no references.
+    pub fn put(&self, address: PhysicalAddress) -> Result<u32, Error> {
+        let frame = self.frame(address).context(UnknownPtrSnafu)?;
+        let old = frame.ref_count.fetch_sub(1, Ordering::AcqRel);
+        assert!(old > 0, "released unreferenced page frame {address:?}");
+        if old == 1 {
+            frame.flags.store(0, Ordering::Release);
+        }
+        Ok(old - 1)
+    }
+
+    /// Share the frame containing `address` with another mapping, taking a reference and marking it
+    /// copy-on-write. Returns the new reference count.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownPtr`] if the frame is not tracked by the database.
+    pub fn share(&self, address: PhysicalAddress) -> Result<u32, Error> {
+        let frame = self.frame(address).context(UnknownPtrSnafu)?;
+        frame.set_flags(FrameFlags::COPY_ON_WRITE);
+        Ok(frame.ref_count.fetch_add(1, Ordering::AcqRel) + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> PageFrameDatabase {
+        PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [
+                (PhysicalAddress::from(0x4000_0000), 0x10_0000),
+                (PhysicalAddress::from(0x8000_0000), 0x4000),
+            ]
+            .into_iter(),
+        )
+    }
+
+    #[test]
+    fn reference_counts() {
+        let db = database();
+        assert_eq!(db.len(), 256 + 4);
+        let page = PhysicalAddress::from(0x8000_3000);
+        assert_eq!(db.get(page).unwrap(), 1);
+        assert_eq!(db.share(page.byte_add(0x10)).unwrap(), 2);
+        assert!(db
+            .frame(page)
+            .unwrap()
+            .flags()
+            .contains(FrameFlags::COPY_ON_WRITE));
+
+        assert_eq!(db.put(page).unwrap(), 1);
+        assert_eq!(db.put(page).unwrap(), 0);
+        assert_eq!(db.frame(page).unwrap().flags(), FrameFlags::NONE);
+        assert_eq!(
+            db.frame(PhysicalAddress::from(0x4000_0000))
+                .unwrap()
+                .ref_count(),
+            0
+        );
+    }
+
+    #[test]
+    fn untracked_frames() {
+        let db = database();
+        assert!(db.frame(PhysicalAddress::from(0x3fff_f000)).is_none());
+        assert!(db.frame(PhysicalAddress::from(0x8000_4000)).is_none());
+        assert!(matches!(
+            db.get(PhysicalAddress::from(0x9000_0000)),
+            Err(Error::UnknownPtr)
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn release_unreferenced_frame() {
+        let db = database();
+        let _ = db.put(PhysicalAddress::from(0x4000_0000));
+    }
+}
@@ -1,34 +1,350 @@
 //! Rust heap allocator [`GlobalAlloc`] implementation.
+//!
+//! The heap is made up of chunks of pages obtained from a [`PageAllocator`]. Each chunk is divided
+//! into blocks, each of which starts with a tag that records its size and whether it and the block
+//! before it are in use. Free blocks also end with a copy of their size (a boundary tag), so that
+//! a block being freed can find and merge with both of its neighbours in constant time. Free blocks
+//! are kept in bins by size so that a fit can be found without scanning every free block.
+//!
+//! With the `heap-debug` feature, the data of each allocation is surrounded by redzones that are
+//! checked when it is freed, and the inside of free blocks is filled with a poison pattern that is
+//! checked when they are reused, so that buffer overruns and writes after free cause a panic.
 
 use core::{
     alloc::{GlobalAlloc, Layout},
-    ptr::{null_mut, NonNull},
-    sync::atomic::{AtomicPtr, Ordering},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use spin::once::Once;
 
-use super::PageAllocator;
-
+use super::{PageAllocator, PhysicalAddress};
+use crate::sync::interrupts::{self, MaskGuard};
+
+/// Set in a block's tag if the block is allocated.
+const IN_USE: usize = 0b01;
+/// Set in a block's tag if the block right before it in memory is allocated.
+const PREV_IN_USE: usize = 0b10;
+const TAG_FLAGS: usize = IN_USE | PREV_IN_USE;
+
+/// The size of a block tag, which comes at the start of every block.
+const TAG_SIZE: usize = size_of::<usize>();
+/// Block sizes are a multiple of this, and blocks start `TAG_SIZE` bytes before a multiple of it,
+/// so that data is always aligned to it.
+const GRANULE: usize = 2 * TAG_SIZE;
+
+/// True if allocations have redzones and free blocks are poisoned.
+const CHECKED: bool = cfg!(feature = "heap-debug");
+/// The minimum number of bytes of redzone on either side of the data of an allocation.
+const REDZONE_SIZE: usize = if CHECKED { GRANULE } else { 0 };
+/// The offset of the data in an allocated block.
+const DATA_OFFSET: usize = TAG_SIZE + REDZONE_SIZE;
+const REDZONE_BYTE: u8 = 0xfd;
+const POISON_BYTE: u8 = 0xdd;
+
+/// The beginning of a block. Only free blocks have the list links.
 #[repr(C)]
 struct FreeHeader {
-    size: usize,
-    next: AtomicPtr<FreeHeader>,
+    /// The size of the block in bytes, or'd with [`IN_USE`] and [`PREV_IN_USE`].
+    tag: usize,
+    next: Option<NonNull<FreeHeader>>,
+    prev: Option<NonNull<FreeHeader>>,
 }
 
-#[repr(C)]
-struct AllocatedHeader {
+/// The smallest block, which must have room for the free header and the size at the end.
+const MIN_BLOCK_SIZE: usize = (size_of::<FreeHeader>() + TAG_SIZE).next_multiple_of(GRANULE);
+
+/// Free blocks smaller than this are binned by their exact size, larger ones by powers of two.
+const SMALL_BLOCK_LIMIT: usize = 512;
+const SMALL_BIN_COUNT: usize = (SMALL_BLOCK_LIMIT - MIN_BLOCK_SIZE) / GRANULE;
+const BIN_COUNT: usize = u64::BITS as usize;
+
+/// The index of the bin that free blocks of `size` bytes are kept in.
+fn bin_index(size: usize) -> usize {
+    if size < SMALL_BLOCK_LIMIT {
+        (size - MIN_BLOCK_SIZE) / GRANULE
+    } else {
+        (SMALL_BIN_COUNT + (size.ilog2() - SMALL_BLOCK_LIMIT.ilog2()) as usize).min(BIN_COUNT - 1)
+    }
+}
+
+unsafe fn size_of_block(block: NonNull<FreeHeader>) -> usize {
+    block.as_ref().tag & !TAG_FLAGS
+}
+
+unsafe fn next_block(block: NonNull<FreeHeader>) -> NonNull<FreeHeader> {
+    block.byte_add(size_of_block(block))
+}
+
+/// Write the tags of a free block of `size` bytes at `block`, whose previous block is in use.
+unsafe fn write_free_tags(block: NonNull<FreeHeader>, size: usize) {
+    block.cast::<usize>().write(size | PREV_IN_USE);
+    block.byte_add(size - TAG_SIZE).cast::<usize>().write(size);
+}
+
+/// The bytes of the free block `block` of `size` bytes that aren't used by its tags or links.
+unsafe fn free_interior(block: NonNull<FreeHeader>, size: usize) -> &'static mut [u8] {
+    let start = size_of::<FreeHeader>();
+    core::slice::from_raw_parts_mut(
+        block.cast::<u8>().add(start).as_ptr(),
+        size - start - TAG_SIZE,
+    )
+}
+
+/// Fill the inside of the free block `block` with poison.
+unsafe fn poison(block: NonNull<FreeHeader>) {
+    if CHECKED {
+        free_interior(block, size_of_block(block)).fill(POISON_BYTE);
+    }
+}
+
+/// Check that the inside of the free block `block` still contains only poison.
+///
+/// # Panics
+///
+/// If anything was written to the block after it was freed.
+unsafe fn check_poison(block: NonNull<FreeHeader>) {
+    if CHECKED {
+        let interior = free_interior(block, size_of_block(block));
+        if let Some(i) = interior.iter().position(|b| *b != POISON_BYTE) {
+            panic!(
+                "use after free detected: {:x?} in freed heap block {block:x?} was overwritten",
+                interior.as_ptr().add(i)
+            );
+        }
+    }
+}
+
+/// The redzones of the allocated block `block` of `size` bytes, holding `data_size` bytes of data.
+unsafe fn redzones(
+    block: NonNull<FreeHeader>,
     size: usize,
-    block: NonNull<u8>,
+    data_size: usize,
+) -> [&'static mut [u8]; 2] {
+    let block = block.cast::<u8>();
+    let data_end = DATA_OFFSET + data_size;
+    [
+        core::slice::from_raw_parts_mut(block.add(TAG_SIZE).as_ptr(), REDZONE_SIZE),
+        core::slice::from_raw_parts_mut(block.add(data_end).as_ptr(), size - data_end),
+    ]
+}
+
+/// Fill the redzones around the data of an allocated block.
+unsafe fn fill_redzones(block: NonNull<FreeHeader>, size: usize, data_size: usize) {
+    if CHECKED {
+        for zone in redzones(block, size, data_size) {
+            zone.fill(REDZONE_BYTE);
+        }
+    }
+}
+
+/// Check that the redzones around the data of an allocated block are intact.
+///
+/// # Panics
+///
+/// If anything was written to the redzones since they were filled.
+unsafe fn check_redzones(block: NonNull<FreeHeader>, size: usize, data_size: usize) {
+    if CHECKED {
+        for zone in redzones(block, size, data_size) {
+            if let Some(i) = zone.iter().position(|b| *b != REDZONE_BYTE) {
+                panic!(
+                    "heap buffer overrun detected: {:x?} is outside of the {data_size} bytes at {:x?}",
+                    zone.as_ptr().add(i),
+                    block.byte_add(DATA_OFFSET)
+                );
+            }
+        }
+    }
+}
+
+/// Free blocks of the heap, binned by size.
+struct Bins {
+    heads: [Option<NonNull<FreeHeader>>; BIN_COUNT],
+    /// Bit `i` is set if bin `i` is not empty.
+    occupied: u64,
+    /// The most recently added chunk. Each chunk starts with a pointer to the one added before it.
+    chunks: Option<NonNull<Option<NonNull<u8>>>>,
+}
+
+// SAFETY: the blocks are only accessed with the bins locked.
+unsafe impl Send for Bins {}
+
+impl Bins {
+    const fn new() -> Self {
+        Self {
+            heads: [None; BIN_COUNT],
+            occupied: 0,
+            chunks: None,
+        }
+    }
+
+    /// Record the new chunk that `block` spans, returning `block`.
+    unsafe fn add_chunk(&mut self, block: NonNull<FreeHeader>) -> NonNull<FreeHeader> {
+        let chunk = block.byte_sub(TAG_SIZE).cast();
+        chunk.write(self.chunks.map(NonNull::cast));
+        self.chunks = Some(chunk);
+        block
+    }
+
+    /// Add the free block `block`, whose tags must already be written, to its bin.
+    unsafe fn insert(&mut self, mut block: NonNull<FreeHeader>) {
+        let index = bin_index(size_of_block(block));
+        let head = self.heads[index];
+        block.as_mut().next = head;
+        block.as_mut().prev = None;
+        if let Some(mut head) = head {
+            head.as_mut().prev = Some(block);
+        }
+        self.heads[index] = Some(block);
+        self.occupied |= 1 << index;
+    }
+
+    /// Remove the free block `block` from its bin, checking that it hasn't been written to.
+    unsafe fn remove(&mut self, block: NonNull<FreeHeader>) {
+        check_poison(block);
+        let next = block.as_ref().next;
+        let prev = block.as_ref().prev;
+        if let Some(mut next) = next {
+            next.as_mut().prev = prev;
+        }
+        if let Some(mut prev) = prev {
+            prev.as_mut().next = next;
+        } else {
+            let index = bin_index(size_of_block(block));
+            self.heads[index] = next;
+            if next.is_none() {
+                self.occupied &= !(1 << index);
+            }
+        }
+    }
+
+    /// Remove and return a free block of at least `size` bytes, if there is one.
+    unsafe fn take_fit(&mut self, size: usize) -> Option<NonNull<FreeHeader>> {
+        let first = bin_index(size);
+        if first >= SMALL_BIN_COUNT {
+            // blocks in the large bins vary in size, so the first one may not fit
+            let mut cursor = self.heads[first];
+            while let Some(block) = cursor {
+                if size_of_block(block) >= size {
+                    self.remove(block);
+                    return Some(block);
+                }
+                cursor = block.as_ref().next;
+            }
+        } else if let Some(block) = self.heads[first] {
+            self.remove(block);
+            return Some(block);
+        }
+        // any block in a larger bin fits
+        let larger = self.occupied & u64::MAX << first << 1;
+        if larger == 0 {
+            return None;
+        }
+        let block = self.heads[larger.trailing_zeros() as usize]?;
+        self.remove(block);
+        Some(block)
+    }
+
+    /// Shrink the allocated block `block` of `size` bytes to `new_size` bytes, returning the rest to
+    /// a bin, merged with the next block if that is free.
+    ///
+    /// Returns the new size of the block, which is still `size` if the rest is too small to be a
+    /// block of its own.
+    unsafe fn shrink(
+        &mut self,
+        mut block: NonNull<FreeHeader>,
+        size: usize,
+        new_size: usize,
+    ) -> usize {
+        if new_size == size {
+            return size;
+        }
+        let mut next = block.byte_add(size);
+        let mut rest_size = size - new_size;
+        if next.as_ref().tag & IN_USE == 0 {
+            self.remove(next);
+            rest_size += size_of_block(next);
+            next.as_mut().tag = 0;
+        } else if rest_size < MIN_BLOCK_SIZE {
+            return size;
+        }
+        let rest = block.byte_add(new_size);
+        write_free_tags(rest, rest_size);
+        poison(rest);
+        next_block(rest).as_mut().tag &= !PREV_IN_USE;
+        self.insert(rest);
+        block.as_mut().tag = new_size | (block.as_ref().tag & TAG_FLAGS);
+        new_size
+    }
+}
+
+/// A snapshot of how much memory a [`HeapAllocator`] is using.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct HeapStatistics {
+    /// The number of bytes of pages that the heap has obtained from the page allocator.
+    pub heap_size: usize,
+    /// The number of bytes in allocated blocks, including their headers and padding.
+    pub allocated_bytes: usize,
+    /// The number of live allocations.
+    pub allocation_count: usize,
+}
+
+impl HeapStatistics {
+    /// The number of bytes in the heap that are not allocated.
+    #[must_use]
+    pub fn free_bytes(&self) -> usize {
+        self.heap_size.saturating_sub(self.allocated_bytes)
+    }
 }
 
 /// A heap allocator for arbitrary sized allocations that is usable as a Rust heap ([`GlobalAlloc`]).
 ///
-/// The allocator uses a basic free list algorithm.
+/// The allocator keeps free blocks in size-class bins, and merges adjacent free blocks when they
+/// are freed using boundary tags.
 #[allow(clippy::module_name_repetitions)]
 pub struct HeapAllocator<'pa, PA> {
     page_allocator: Once<&'pa PA>,
-    free_list: AtomicPtr<FreeHeader>,
+    /// This is a plain spin lock rather than a [`crate::sync::Mutex`], since reporting a lock
+    /// order violation may itself need to allocate. Use [`Self::lock_bins`] to take it.
+    bins: spin::Mutex<Bins>,
+    heap_size: AtomicUsize,
+    allocated_bytes: AtomicUsize,
+    allocation_count: AtomicUsize,
+}
+
+/// The locked bins of a [`HeapAllocator`], with interrupts masked until they are unlocked.
+struct BinsGuard<'h> {
+    bins: spin::MutexGuard<'h, Bins>,
+    /// Dropped after `bins`, so that interrupts stay masked until the lock is released.
+    _interrupts: MaskGuard,
+}
+
+impl Deref for BinsGuard<'_> {
+    type Target = Bins;
+
+    fn deref(&self) -> &Bins {
+        &self.bins
+    }
+}
+
+impl DerefMut for BinsGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Bins {
+        &mut self.bins
+    }
+}
+
+impl<PA> HeapAllocator<'_, PA> {
+    /// Lock the bins. Interrupt handlers allocate too, so interrupts are
+    /// [masked](crate::sync::interrupts) while they are locked.
+    fn lock_bins(&self) -> BinsGuard<'_> {
+        let interrupts = interrupts::mask();
+        BinsGuard {
+            bins: self.bins.lock(),
+            _interrupts: interrupts,
+        }
+    }
 }
 
 impl<'pa, PA: PageAllocator> HeapAllocator<'pa, PA> {
@@ -36,7 +352,7 @@ impl<'pa, PA: PageAllocator> HeapAllocator<'pa, PA> {
     pub fn new(page_allocator: &'pa PA) -> Self {
         Self {
             page_allocator: Once::initialized(page_allocator),
-            free_list: AtomicPtr::default(),
+            ..Self::new_uninit()
         }
     }
 
@@ -48,7 +364,10 @@ impl<'pa, PA: PageAllocator> HeapAllocator<'pa, PA> {
     pub const fn new_uninit() -> Self {
         Self {
             page_allocator: Once::new(),
-            free_list: AtomicPtr::new(null_mut()),
+            bins: spin::Mutex::new(Bins::new()),
+            heap_size: AtomicUsize::new(0),
+            allocated_bytes: AtomicUsize::new(0),
+            allocation_count: AtomicUsize::new(0),
         }
     }
 
@@ -57,105 +376,72 @@ impl<'pa, PA: PageAllocator> HeapAllocator<'pa, PA> {
         self.page_allocator.call_once(|| page_allocator);
     }
 
-    unsafe fn try_remove_fit(&self, desired_size: usize) -> Option<NonNull<FreeHeader>> {
-        // keep trying until successful or not found
-        'retry: loop {
-            let mut prev_ptr: Option<NonNull<FreeHeader>> = None;
-            let mut current_ptr = NonNull::new(self.free_list.load(Ordering::Acquire));
-
-            // step through the list
-            while let Some(current) = current_ptr {
-                let next_ptr = current.as_ref().next.load(Ordering::Relaxed);
-
-                let current_h = current.as_ref();
-                if current_h.size >= desired_size {
-                    // Attempt to remove the block from the free list.
-                    let success = if let Some(prev_ptr) = prev_ptr {
-                        unsafe {
-                            prev_ptr
-                                .as_ref()
-                                .next
-                                .compare_exchange(
-                                    current.as_ptr(),
-                                    next_ptr,
-                                    Ordering::AcqRel,
-                                    Ordering::Acquire,
-                                )
-                                .is_ok()
-                        }
-                    } else {
-                        // Removing from head
-                        self.free_list
-                            .compare_exchange(
-                                current.as_ptr(),
-                                next_ptr,
-                                Ordering::AcqRel,
-                                Ordering::Acquire,
-                            )
-                            .is_ok()
-                    };
-
-                    if success {
-                        return Some(current);
-                    }
-
-                    // Failed to remove; retry from the beginning of the list.
-                    continue 'retry;
-                }
-
-                prev_ptr = current_ptr;
-                current_ptr = NonNull::new(next_ptr);
-            }
-
-            return None;
-        }
-    }
-
-    unsafe fn push_free_block(&self, mut block: NonNull<FreeHeader>) {
-        let mut head = self.free_list.load(Ordering::Acquire);
-        loop {
-            block.as_mut().next.store(head, Ordering::Relaxed);
-
-            match self.free_list.compare_exchange_weak(
-                head,
-                block.as_ptr(),
-                Ordering::AcqRel,
-                Ordering::Acquire,
-            ) {
-                Ok(_) => break,
-                Err(h) => head = h,
-            }
+    /// Get a snapshot of the heap's memory usage.
+    pub fn statistics(&self) -> HeapStatistics {
+        HeapStatistics {
+            heap_size: self.heap_size.load(Ordering::Relaxed),
+            allocated_bytes: self.allocated_bytes.load(Ordering::Relaxed),
+            allocation_count: self.allocation_count.load(Ordering::Relaxed),
         }
     }
 
-    fn is_free(&self, ptr: NonNull<FreeHeader>) -> bool {
-        let mut cursor = self.free_list.load(Ordering::Acquire);
+    /// Return every chunk of the heap that is entirely free to the page allocator, returning the
+    /// number of pages released.
+    pub fn trim(&self) -> usize {
+        let Some(pa) = self.page_allocator.poll() else {
+            return 0;
+        };
+        let page_size = usize::from(pa.page_size());
+        let mut released = 0;
+        let mut bins = self.lock_bins();
         unsafe {
-            while let Some(block) = cursor.as_ref() {
-                if ptr.as_ptr() >= cursor && ptr.as_ptr() < cursor.byte_add(block.size) {
-                    #[cfg(test)]
-                    println!("{ptr:x?} in {cursor:x?}+{}", block.size);
-                    return true;
+            let mut link: *mut Option<NonNull<Option<NonNull<u8>>>> = &raw mut bins.chunks;
+            while let Some(chunk) = *link {
+                let block: NonNull<FreeHeader> = chunk.byte_add(TAG_SIZE).cast();
+                let next_chunk = chunk.read().map(NonNull::cast);
+                // the chunk is free if its first block is free and reaches the end marker
+                if block.as_ref().tag & IN_USE == 0 && size_of_block(next_block(block)) == 0 {
+                    let chunk_size = size_of_block(block) + GRANULE;
+                    bins.remove(block);
+                    *link = next_chunk;
+                    let pages = PhysicalAddress::from(chunk.cast::<()>().as_ptr());
+                    if pa.free(pages, chunk_size / page_size).is_ok() {
+                        released += chunk_size / page_size;
+                        self.heap_size.fetch_sub(chunk_size, Ordering::Relaxed);
+                    }
+                } else {
+                    link = chunk.cast().as_ptr();
                 }
-                cursor = block.next.load(Ordering::Acquire);
             }
-            false
         }
+        released
     }
-}
 
-fn align_up(offset: usize, align: usize) -> usize {
-    (offset + align - 1) & !(align - 1)
-}
-
-fn header_padding_max(layout: Layout) -> usize {
-    let alloc_header_layout = Layout::new::<AllocatedHeader>();
-    if layout.align() <= alloc_header_layout.align() {
-        0
-    } else if layout.align() == alloc_header_layout.size() {
-        alloc_header_layout.align()
-    } else {
-        layout.align() - alloc_header_layout.size()
+    /// Allocate a new chunk of pages with room for a block of at least `size` bytes, returning a
+    /// free block that spans the whole chunk. The block is not added to a bin.
+    unsafe fn grow(&self, size: usize, align: usize) -> Option<NonNull<FreeHeader>> {
+        let pa = self.page_allocator.poll()?;
+        let page_size = usize::from(pa.page_size());
+        assert!(
+            align <= page_size,
+            "layout alignments greater than a page are unsupported, align={align}"
+        );
+        // the chunk starts with padding so that the first block is aligned and ends with a tag
+        // marked in use, so that blocks are never merged past either end
+        let page_count = size
+            .checked_add(GRANULE)?
+            .div_ceil(page_size)
+            .max(MIN_PAGE_ALLOCATION);
+        let chunk_size = page_count.checked_mul(page_size)?;
+        let chunk: NonNull<u8> = NonNull::new(pa.allocate(page_count).ok()?.cast().into())?;
+        self.heap_size.fetch_add(chunk_size, Ordering::Relaxed);
+
+        let block = chunk.byte_add(TAG_SIZE).cast();
+        let block_size = chunk_size - GRANULE;
+        write_free_tags(block, block_size);
+        poison(block);
+        block.byte_add(block_size).cast::<usize>().write(IN_USE);
+        Some(block)
     }
 }
 
@@ -163,92 +449,169 @@ fn header_padding_max(layout: Layout) -> usize {
 /// Large allocations may request more pages than this.
 const MIN_PAGE_ALLOCATION: usize = 4;
 
+/// The size of the block needed to hold an allocation of `layout`, if it is aligned.
+fn block_size_for(layout: Layout) -> Option<usize> {
+    Some(
+        layout
+            .size()
+            .checked_add(DATA_OFFSET + REDZONE_SIZE + GRANULE - 1)?
+            .max(MIN_BLOCK_SIZE)
+            & !(GRANULE - 1),
+    )
+}
+
 unsafe impl<PA: PageAllocator> GlobalAlloc for HeapAllocator<'_, PA> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let alloc_header_layout = Layout::new::<AllocatedHeader>();
-        let between_header_and_data_padding_max = header_padding_max(layout);
-        let required_block_size =
-            alloc_header_layout.size() + between_header_and_data_padding_max + layout.size();
-
-        let block = self.try_remove_fit(required_block_size);
-
-        let (total_block_size, block) = if let Some(block) = block {
-            let block_size = block.as_ref().size;
-            assert!(block_size >= required_block_size);
+        let Some(size) = block_size_for(layout) else {
+            return core::ptr::null_mut();
+        };
+        // make room to move the data forward to the alignment, leaving enough room before it for a
+        // free block
+        let search_size = if layout.align() <= GRANULE {
+            size
+        } else if let Some(search_size) = size.checked_add(layout.align() + MIN_BLOCK_SIZE) {
+            search_size
+        } else {
+            return core::ptr::null_mut();
+        };
 
-            (block_size, block)
+        let mut bins = self.lock_bins();
+        let mut block = if let Some(block) = bins.take_fit(search_size) {
+            block
         } else {
-            let Some(pa) = self.page_allocator.poll() else {
-                return null_mut();
+            // growing may run out of memory and reclaim it by trimming the heap, which needs the lock
+            drop(bins);
+            let Some(block) = self.grow(search_size, layout.align()) else {
+                return core::ptr::null_mut();
             };
-            let page_count = required_block_size
-                .div_ceil(pa.page_size().into())
-                .max(MIN_PAGE_ALLOCATION);
-            assert!(
-                layout.align() <= usize::from(pa.page_size()),
-                "layout alignments greater than a page are unsupported, layout={layout:?}"
-            );
-            if let Ok(pages) = pa.allocate(page_count) {
-                (
-                    page_count * pa.page_size(),
-                    NonNull::new(pages.cast().into()).unwrap(),
-                )
-            } else {
-                return null_mut();
-            }
+            bins = self.lock_bins();
+            bins.add_chunk(block)
         };
+        let mut block_size = size_of_block(block);
 
-        let padding_required = block
-            .cast::<u8>()
-            .add(alloc_header_layout.size())
-            .align_offset(layout.align());
-        assert!(padding_required <= between_header_and_data_padding_max, "padding required {padding_required} <= max padding {between_header_and_data_padding_max}");
+        let data = block.byte_add(DATA_OFFSET).cast::<u8>().as_ptr();
+        let mut padding = data.align_offset(layout.align());
+        if padding > 0 && padding < MIN_BLOCK_SIZE {
+            padding = data.add(MIN_BLOCK_SIZE).align_offset(layout.align()) + MIN_BLOCK_SIZE;
+        }
+        let mut prev_in_use = PREV_IN_USE;
+        if padding > 0 {
+            // the padding becomes a free block of its own
+            write_free_tags(block, padding);
+            bins.insert(block);
+            block = block.byte_add(padding);
+            block_size -= padding;
+            prev_in_use = 0;
+        }
 
-        let actual_block_size = alloc_header_layout.size() + padding_required + layout.size();
+        if block_size - size >= MIN_BLOCK_SIZE {
+            // put back the rest, whose next block already knows a free block comes before it
+            let rest = block.byte_add(size);
+            write_free_tags(rest, block_size - size);
+            bins.insert(rest);
+            block_size = size;
+        } else {
+            block.byte_add(block_size).as_mut().tag |= PREV_IN_USE;
+        }
+        block.as_mut().tag = block_size | IN_USE | prev_in_use;
+        drop(bins);
+        fill_redzones(block, block_size, layout.size());
 
-        // #[cfg(test)]
-        // println!("alloc {total_block_size} ({actual_block_size} / {required_block_size}) {block:x?} padding_max={between_header_and_data_padding_max} padding_req={padding_required}");
+        self.allocated_bytes
+            .fetch_add(block_size, Ordering::Relaxed);
+        self.allocation_count.fetch_add(1, Ordering::Relaxed);
 
-        if total_block_size.saturating_sub(actual_block_size) > size_of::<FreeHeader>() {
-            // put back the rest
-            let rest_offset = align_up(actual_block_size, align_of::<FreeHeader>());
-            let mut rest_block = block.byte_add(rest_offset);
-            *rest_block.as_mut() = FreeHeader {
-                size: total_block_size - rest_offset,
-                next: AtomicPtr::default(),
-            };
-            self.push_free_block(rest_block);
-        }
+        block.byte_add(DATA_OFFSET).cast().as_ptr()
+    }
 
-        // place the padding first, then the header right before the data so we can find it in `dealloc`.
-        let mut header: NonNull<AllocatedHeader> = block.byte_add(padding_required).cast();
-        *header.as_mut() = AllocatedHeader {
-            size: actual_block_size,
-            block: block.cast(),
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(ptr) = NonNull::new(ptr) else {
+            return;
         };
-        header.add(1).cast().as_ptr()
+        let mut block: NonNull<FreeHeader> = ptr.byte_sub(DATA_OFFSET).cast();
+        // neighbouring blocks update this block's `PREV_IN_USE` bit while holding the lock
+        let mut bins = self.lock_bins();
+        let tag = block.as_ref().tag;
+        assert!(tag & IN_USE != 0, "double free detected");
+
+        let mut size = tag & !TAG_FLAGS;
+        let min_size = block_size_for(layout).unwrap();
+        let max_size = min_size + MIN_BLOCK_SIZE;
+        assert!(min_size <= size && size < max_size,
+            "min_size<=block_size<max_size! block_size={size}, min_size={min_size}, max_size={max_size}, layout={layout:?}");
+        check_redzones(block, size, layout.size());
+        self.allocated_bytes.fetch_sub(size, Ordering::Relaxed);
+        self.allocation_count.fetch_sub(1, Ordering::Relaxed);
+
+        // clear the tags of merged blocks so that freeing them again is caught
+        let mut next = block.byte_add(size);
+        if next.as_ref().tag & IN_USE == 0 {
+            bins.remove(next);
+            size += size_of_block(next);
+            next.as_mut().tag = 0;
+        }
+        if tag & PREV_IN_USE == 0 {
+            let prev_size = block.byte_sub(TAG_SIZE).cast::<usize>().read();
+            let prev = block.byte_sub(prev_size);
+            bins.remove(prev);
+            block.as_mut().tag = 0;
+            block = prev;
+            size += prev_size;
+        }
+        write_free_tags(block, size);
+        poison(block);
+        next_block(block).as_mut().tag &= !PREV_IN_USE;
+        bins.insert(block);
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if let Some(ptr) = NonNull::new(ptr) {
-            let alloc_header_layout = Layout::new::<AllocatedHeader>();
-
-            let header: NonNull<AllocatedHeader> = ptr.cast().offset(-1);
-            let block_claimed_size = header.as_ref().size;
-            let min_size = alloc_header_layout.size() + layout.size();
-            let max_size = header_padding_max(layout) + min_size;
-            assert!(min_size <= block_claimed_size && block_claimed_size <= max_size,
-                "min_size<=block_claimed_size<=max_size! block_claimed_size={block_claimed_size}, min_size={min_size}, max_size={max_size}, layout={layout:?}");
-            let mut free_block: NonNull<FreeHeader> = header.as_ref().block.cast();
-
-            assert!(!self.is_free(free_block), "double free detected");
-
-            *free_block.as_mut() = FreeHeader {
-                size: block_claimed_size,
-                next: AtomicPtr::default(),
-            };
-            self.push_free_block(free_block);
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let Some(new_block_size) = block_size_for(new_layout) else {
+            return core::ptr::null_mut();
+        };
+        let mut block: NonNull<FreeHeader> =
+            NonNull::new_unchecked(ptr).byte_sub(DATA_OFFSET).cast();
+
+        // neighbouring blocks update this block's `PREV_IN_USE` bit while holding the lock
+        let mut bins = self.lock_bins();
+        let tag = block.as_ref().tag;
+        assert!(tag & IN_USE != 0, "reallocation of freed block");
+        let size = tag & !TAG_FLAGS;
+        check_redzones(block, size, layout.size());
+
+        // try to resize the block where it is first, taking space from the next block if it is free
+        let mut next = block.byte_add(size);
+        let resized = if new_block_size <= size {
+            Some(bins.shrink(block, size, new_block_size))
+        } else if next.as_ref().tag & IN_USE == 0 && size + size_of_block(next) >= new_block_size {
+            bins.remove(next);
+            let merged_size = size + size_of_block(next);
+            next.as_mut().tag = 0;
+            block.as_mut().tag = merged_size | (tag & TAG_FLAGS);
+            next_block(block).as_mut().tag |= PREV_IN_USE;
+            Some(bins.shrink(block, merged_size, new_block_size))
+        } else {
+            None
+        };
+        drop(bins);
+        if let Some(resized) = resized {
+            fill_redzones(block, resized, new_size);
+            if resized >= size {
+                self.allocated_bytes
+                    .fetch_add(resized - size, Ordering::Relaxed);
+            } else {
+                self.allocated_bytes
+                    .fetch_sub(size - resized, Ordering::Relaxed);
+            }
+            return ptr;
         }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
@@ -369,6 +732,23 @@ mod tests {
         });
     }
 
+    #[test]
+    fn concurrent_adjacent_blocks() {
+        let pa = create_page_allocator();
+        let a = HeapAllocator::new(&pa);
+        let layout = Layout::from_size_align(40, 8).expect("create layout");
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..2000 {
+                        let blocks = allocate_batch(&a, layout, 8);
+                        free_batch_interleave(&a, layout, blocks);
+                    }
+                });
+            }
+        });
+    }
+
     #[test_matrix(
         [free_batch, free_batch_rev, free_batch_interleave],
         [8, 27, 64],
@@ -436,4 +816,173 @@ mod tests {
             assert!(p.is_null());
         }
     }
+
+    #[test]
+    fn freed_neighbours_are_merged() {
+        let pa = create_page_allocator();
+        let a = HeapAllocator::new(&pa);
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let batch = allocate_batch(&a, small, 64);
+        let heap_size = a.statistics().heap_size;
+        free_batch_interleave(&a, small, batch);
+
+        // the whole heap is one free block again, so a large allocation doesn't need more pages
+        let large = Layout::from_size_align(heap_size - 2 * GRANULE - 2 * REDZONE_SIZE, 8).unwrap();
+        let p = unsafe { a.alloc(large) };
+        assert!(!p.is_null());
+        assert_eq!(a.statistics().heap_size, heap_size);
+        unsafe { a.dealloc(p, large) };
+    }
+
+    #[test]
+    fn realloc_in_place() {
+        let pa = create_page_allocator();
+        let a = HeapAllocator::new(&pa);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let p = a.alloc(layout);
+            p.write_bytes(0xab, layout.size());
+
+            // the rest of the chunk is free, so the block can grow into it
+            let q = a.realloc(p, layout, 1000);
+            assert_eq!(q, p);
+            let grown = Layout::from_size_align(1000, 8).unwrap();
+            let allocated = a.statistics().allocated_bytes;
+            assert!(allocated >= grown.size());
+
+            // shrinking gives the space back
+            let r = a.realloc(q, grown, 32);
+            assert_eq!(r, p);
+            let shrunk = Layout::from_size_align(32, 8).unwrap();
+            assert!(a.statistics().allocated_bytes < allocated);
+            assert!(core::slice::from_raw_parts(r, 32)
+                .iter()
+                .all(|b| *b == 0xab));
+
+            a.dealloc(r, shrunk);
+        }
+        assert_eq!(
+            a.statistics(),
+            HeapStatistics {
+                allocated_bytes: 0,
+                allocation_count: 0,
+                ..a.statistics()
+            }
+        );
+    }
+
+    #[test]
+    fn realloc_moves_when_blocked() {
+        let pa = create_page_allocator();
+        let a = HeapAllocator::new(&pa);
+        let layout = Layout::from_size_align(64, 16).unwrap();
+        unsafe {
+            let p = a.alloc(layout);
+            let blocker = a.alloc(layout);
+            for i in 0..layout.size() {
+                p.add(i).write(i as u8);
+            }
+
+            let grown = Layout::from_size_align(256, 16).unwrap();
+            let q = a.realloc(p, layout, grown.size());
+            assert_ne!(q, p);
+            assert!(q.is_aligned_to(grown.align()));
+            for i in 0..layout.size() {
+                assert_eq!(q.add(i).read(), i as u8);
+            }
+            assert_eq!(a.statistics().allocation_count, 2);
+
+            a.dealloc(q, grown);
+            a.dealloc(blocker, layout);
+        }
+        assert_eq!(a.statistics().allocated_bytes, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap buffer overrun detected")]
+    fn overrun_is_detected() {
+        let pa = create_page_allocator();
+        let a = HeapAllocator::new(&pa);
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        unsafe {
+            let p = a.alloc(layout);
+            p.add(layout.size()).write(0);
+            a.dealloc(p, layout);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "use after free detected")]
+    fn use_after_free_is_detected() {
+        let pa = create_page_allocator();
+        let a = HeapAllocator::new(&pa);
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        unsafe {
+            let p = a.alloc(layout);
+            let blocker = a.alloc(layout);
+            a.dealloc(p, layout);
+            p.add(100).write(0);
+            // reusing the freed block finds the write
+            a.alloc(layout);
+            a.dealloc(blocker, layout);
+        }
+    }
+
+    #[test]
+    fn trim_releases_free_chunks() {
+        let pa = create_page_allocator();
+        let a = HeapAllocator::new(&pa);
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let large = Layout::from_size_align(8 * 4096, 8).unwrap();
+        let kept = allocate_batch(&a, small, 4);
+        let batch = allocate_batch(&a, large, 2);
+        let heap_size = a.statistics().heap_size;
+        assert!(heap_size > MIN_PAGE_ALLOCATION * 4096);
+        free_batch(&a, large, batch);
+
+        // only the chunk with the small allocations is still in use
+        assert_eq!(a.trim() * 4096, heap_size - MIN_PAGE_ALLOCATION * 4096);
+        assert_eq!(a.statistics().heap_size, MIN_PAGE_ALLOCATION * 4096);
+        assert_eq!(a.trim(), 0);
+
+        free_batch(&a, small, kept);
+        assert_eq!(a.trim(), MIN_PAGE_ALLOCATION);
+        assert_eq!(a.statistics().heap_size, 0);
+        drop(a);
+        pa.end_check();
+    }
+
+    #[test]
+    fn bins_by_size_class() {
+        assert_eq!(bin_index(MIN_BLOCK_SIZE), 0);
+        assert_eq!(bin_index(MIN_BLOCK_SIZE + GRANULE), 1);
+        assert_eq!(bin_index(SMALL_BLOCK_LIMIT - GRANULE), SMALL_BIN_COUNT - 1);
+        assert_eq!(bin_index(SMALL_BLOCK_LIMIT), SMALL_BIN_COUNT);
+        assert_eq!(bin_index(2 * SMALL_BLOCK_LIMIT - GRANULE), SMALL_BIN_COUNT);
+        assert_eq!(bin_index(2 * SMALL_BLOCK_LIMIT), SMALL_BIN_COUNT + 1);
+        assert_eq!(bin_index(usize::MAX & !(GRANULE - 1)), BIN_COUNT - 1);
+    }
+
+    #[test]
+    fn statistics() {
+        let pa = create_page_allocator();
+        let a = HeapAllocator::new(&pa);
+        assert_eq!(a.statistics(), HeapStatistics::default());
+
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let batch = allocate_batch(&a, layout, 4);
+        let stats = a.statistics();
+        assert_eq!(stats.heap_size, MIN_PAGE_ALLOCATION * 4096);
+        assert_eq!(stats.allocation_count, 4);
+        assert!(stats.allocated_bytes >= 4 * layout.size());
+        assert_eq!(stats.free_bytes(), stats.heap_size - stats.allocated_bytes);
+
+        free_batch(&a, layout, batch);
+        let stats = a.statistics();
+        assert_eq!(stats.allocation_count, 0);
+        assert_eq!(stats.allocated_bytes, 0);
+        assert_eq!(stats.free_bytes(), stats.heap_size);
+    }
 }
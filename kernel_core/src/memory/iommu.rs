@@ -0,0 +1,467 @@
+//! Isolating the DMA of devices with an IOMMU.
+//!
+//! Without an IOMMU a device can read and write any physical memory, so a driver process that
+//! controls a device could use it to overwrite the kernel or other processes. An IOMMU translates
+//! the addresses that devices use (I/O virtual addresses) through page tables, like the MMU does
+//! for the CPU. Each device, identified by its stream ID, is given an [`IoAddressSpace`] that maps
+//! only the buffers its driver has been granted.
+//!
+//! The translation tables have the same format as the CPU's, so they are built with
+//! [`PageTables`].
+use alloc::vec::Vec;
+#[cfg(test)]
+use mockall::automock;
+use snafu::{ensure, OptionExt as _, ResultExt as _, Snafu};
+
+use super::{
+    page_table::{self, MapBlockSize, MemoryProperties},
+    AddressSpaceId, PageAllocator, PageFrameDatabase, PageTables, PhysicalAddress, VirtualAddress,
+};
+
+/// Identifies the device that a DMA transaction came from to the IOMMU.
+pub type StreamId = u32;
+
+/// Errors that arise managing the I/O address space of a device.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The IOMMU can't translate DMA from the stream.
+    #[snafu(display("unknown stream {stream}"))]
+    UnknownStream {
+        /// The stream ID of the device.
+        stream: StreamId,
+    },
+    /// Part of the region has already been granted to the device.
+    #[snafu(display("I/O address {address:?} is already granted"))]
+    AlreadyGranted {
+        /// The start of the region.
+        address: VirtualAddress,
+    },
+    /// No region was granted to the device at the address.
+    #[snafu(display("no region granted at I/O address {address:?}"))]
+    NotGranted {
+        /// The start of the region.
+        address: VirtualAddress,
+    },
+    /// The IOMMU did not finish applying a change in time.
+    #[snafu(display("IOMMU did not complete a command"))]
+    Timeout,
+    /// An error occurred mapping the region into the device's page tables.
+    PageTables {
+        /// Cause of the error.
+        source: page_table::Error,
+    },
+    /// An error occurred allocating the page tables or taking references to the granted pages.
+    Memory {
+        /// Cause of the error.
+        source: super::Error,
+    },
+}
+
+/// Hardware that translates the addresses devices use for DMA.
+#[cfg_attr(test, automock)]
+pub trait IoMmu {
+    /// Translate DMA from the device `stream` through the page tables with the root table at
+    /// `root`, tagging cached translations with `asid`.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownStream`] if the IOMMU can't translate DMA from `stream`.
+    /// - [`Error::Memory`] if the IOMMU's structures for the stream could not be allocated.
+    /// - [`Error::Timeout`] if the IOMMU did not apply the change.
+    fn attach(
+        &self,
+        stream: StreamId,
+        asid: AddressSpaceId,
+        root: PhysicalAddress,
+    ) -> Result<(), Error>;
+
+    /// Block all DMA from the device `stream`.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownStream`] if the IOMMU can't translate DMA from `stream`.
+    /// - [`Error::Timeout`] if the IOMMU did not apply the change, in which case the device may
+    ///   still be able to reach the memory it was attached to.
+    fn detach(&self, stream: StreamId) -> Result<(), Error>;
+
+    /// Invalidate the cached translations for the `length` bytes of I/O addresses starting at
+    /// `start` in the address space `asid`.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the IOMMU did not complete the invalidation, in which case
+    /// the old translations may still be used.
+    fn invalidate_range(
+        &self,
+        asid: AddressSpaceId,
+        start: VirtualAddress,
+        length: usize,
+    ) -> Result<(), Error>;
+
+    /// Invalidate every cached translation in the address space `asid`.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the IOMMU did not complete the invalidation, in which case
+    /// the old translations may still be used.
+    fn invalidate_asid(&self, asid: AddressSpaceId) -> Result<(), Error>;
+}
+
+/// A region of physical memory that a device has been granted access to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    /// The I/O address the device uses for the start of the region.
+    pub io_start: VirtualAddress,
+    /// The start of the region in physical memory.
+    pub physical_start: PhysicalAddress,
+    /// The length of the region in pages.
+    pub num_pages: usize,
+    /// True if the device can write to the region.
+    pub writable: bool,
+}
+
+/// The addresses one device can access with DMA.
+///
+/// The space holds a reference to each granted page in the page frame database, so that pages
+/// aren't reused while the device can still access them. It must be given back with
+/// [`Self::release`] when the device is no longer in use.
+pub struct IoAddressSpace<'pa, PA: PageAllocator> {
+    stream: StreamId,
+    asid: AddressSpaceId,
+    page_allocator: &'pa PA,
+    page_tables: PageTables<'pa, PA>,
+    grants: Vec<Grant>,
+}
+
+impl<'pa, PA: PageAllocator> IoAddressSpace<'pa, PA> {
+    /// Create an empty address space for the device `stream`, and make `iommu` translate the
+    /// device's DMA through it, so that it can't access any memory until regions are granted.
+    ///
+    /// # Errors
+    /// - [`Error::Memory`] if the page tables could not be allocated.
+    /// - Any error invalidating `asid` or attaching the device to the address space.
+    pub fn new(
+        page_allocator: &'pa PA,
+        iommu: &impl IoMmu,
+        stream: StreamId,
+        asid: AddressSpaceId,
+    ) -> Result<Self, Error> {
+        let page_tables = PageTables::empty(page_allocator).context(MemorySnafu)?;
+        // stale translations may remain from a previous user of the ASID
+        iommu.invalidate_asid(asid)?;
+        iommu.attach(stream, asid, page_tables.physical_address())?;
+        Ok(Self {
+            stream,
+            asid,
+            page_allocator,
+            page_tables,
+            grants: Vec::new(),
+        })
+    }
+
+    /// The stream ID of the device.
+    #[must_use]
+    pub fn stream(&self) -> StreamId {
+        self.stream
+    }
+
+    /// The regions the device has been granted access to.
+    #[must_use]
+    pub fn grants(&self) -> &[Grant] {
+        &self.grants
+    }
+
+    /// The physical address the device accesses at `io_address`, or `None` if it can't access it.
+    #[must_use]
+    pub fn translate(&self, io_address: VirtualAddress) -> Option<PhysicalAddress> {
+        self.page_tables.physical_address_of(io_address)
+    }
+
+    /// Give the device access to the `num_pages` pages of RAM starting at `physical_start`, at the
+    /// I/O address `io_start`, taking a reference to each page in `frames`.
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyGranted`] if part of the region overlaps a region already granted.
+    /// - [`Error::Memory`] if the pages are not tracked by `frames`.
+    /// - [`Error::PageTables`] if the region could not be mapped.
+    pub fn grant(
+        &mut self,
+        frames: &PageFrameDatabase,
+        io_start: VirtualAddress,
+        physical_start: PhysicalAddress,
+        num_pages: usize,
+        writable: bool,
+    ) -> Result<(), Error> {
+        let page_size = usize::from(self.page_allocator.page_size());
+        let start = usize::from(io_start);
+        let end = start.saturating_add(num_pages.saturating_mul(page_size));
+        ensure!(
+            self.grants.iter().all(|g| {
+                let granted = usize::from(g.io_start);
+                end <= granted || start >= granted + g.num_pages * page_size
+            }),
+            AlreadyGrantedSnafu { address: io_start }
+        );
+        let mut taken = 0;
+        let result = (0..num_pages).try_for_each(|i| {
+            frames
+                .get(physical_start.byte_add(i * page_size))
+                .context(MemorySnafu)?;
+            taken += 1;
+            Ok(())
+        });
+        let result = result.and_then(|()| {
+            self.page_tables
+                .map(
+                    io_start,
+                    physical_start,
+                    num_pages,
+                    MapBlockSize::Page,
+                    &MemoryProperties {
+                        // devices are given access through the unprivileged permissions
+                        user_space_access: true,
+                        writable,
+                        executable: false,
+                        ..MemoryProperties::default()
+                    },
+                )
+                .context(PageTablesSnafu)
+        });
+        if result.is_err() {
+            for i in 0..taken {
+                // the reference was just taken, so it can't be the last one
+                let _ = frames.put(physical_start.byte_add(i * page_size));
+            }
+            return result;
+        }
+        self.grants.push(Grant {
+            io_start,
+            physical_start,
+            num_pages,
+            writable,
+        });
+        Ok(())
+    }
+
+    /// Take away the device's access to the region granted at `io_start`.
+    ///
+    /// The region is flushed from the IOMMU's caches before the references to its pages are
+    /// released, and pages that are no longer referenced are freed. If the region could not be
+    /// unmapped or flushed, the device may still reach it, so it stays granted.
+    ///
+    /// # Errors
+    /// - [`Error::NotGranted`] if no region was granted at `io_start`.
+    /// - [`Error::PageTables`] if the region could not be unmapped.
+    /// - [`Error::Timeout`] if the IOMMU did not flush the region.
+    /// - [`Error::Memory`] if the pages could not be released.
+    pub fn revoke(
+        &mut self,
+        frames: &PageFrameDatabase,
+        iommu: &impl IoMmu,
+        io_start: VirtualAddress,
+    ) -> Result<(), Error> {
+        let index = self
+            .grants
+            .iter()
+            .position(|g| g.io_start == io_start)
+            .context(NotGrantedSnafu { address: io_start })?;
+        let grant = &self.grants[index];
+        let flush = self
+            .page_tables
+            .unmap(grant.io_start, grant.num_pages, MapBlockSize::Page)
+            .context(PageTablesSnafu)?;
+        iommu.invalidate_range(self.asid, flush.virtual_start, flush.length)?;
+        let grant = self.grants.remove(index);
+        self.release_pages(frames, &grant)
+    }
+
+    /// Block all DMA from the device and release every region it was granted.
+    ///
+    /// If the device could not be blocked, it may still reach its page tables and the granted
+    /// pages, so they are leaked rather than freed.
+    ///
+    /// # Errors
+    /// - Any error detaching the device or invalidating its address space.
+    /// - [`Error::Memory`] if the pages could not be released.
+    pub fn release(mut self, frames: &PageFrameDatabase, iommu: &impl IoMmu) -> Result<(), Error> {
+        if let Err(e) = iommu
+            .detach(self.stream)
+            .and_then(|()| iommu.invalidate_asid(self.asid))
+        {
+            core::mem::forget(self);
+            return Err(e);
+        }
+        let mut result = Ok(());
+        for grant in core::mem::take(&mut self.grants) {
+            if let Err(e) = self.release_pages(frames, &grant) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Release the references to the pages of `grant`, freeing the pages nothing else refers to.
+    fn release_pages(&self, frames: &PageFrameDatabase, grant: &Grant) -> Result<(), Error> {
+        let page_size = usize::from(self.page_allocator.page_size());
+        let mut released = Vec::new();
+        for i in 0..grant.num_pages {
+            let page = grant.physical_start.byte_add(i * page_size);
+            if frames.put(page).context(MemorySnafu)? == 0 {
+                released.push(page);
+            }
+        }
+        // free the whole region at once if nothing else refers to it
+        if released.len() == grant.num_pages {
+            self.page_allocator
+                .free(grant.physical_start, grant.num_pages)
+                .context(MemorySnafu)
+        } else {
+            released
+                .into_iter()
+                .try_for_each(|page| self.page_allocator.free(page, 1))
+                .context(MemorySnafu)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::memory::{tests::MockPageAllocator, PageSize};
+
+    fn frames(
+        pa: &MockPageAllocator,
+        pages: PhysicalAddress,
+        num_pages: usize,
+    ) -> PageFrameDatabase {
+        PageFrameDatabase::new(
+            pa.page_size(),
+            [(pages, num_pages * usize::from(pa.page_size()))].into_iter(),
+        )
+    }
+
+    #[test]
+    fn grant_and_revoke() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 32);
+        let pages = pa.allocate(4).unwrap();
+        let frames = frames(&pa, pages, 4);
+        // the driver's own mapping holds a reference to the pages
+        for i in 0..4 {
+            frames.get(pages.byte_add(i * 0x1000)).unwrap();
+        }
+
+        let mut iommu = MockIoMmu::new();
+        iommu
+            .expect_attach()
+            .with(eq(7), eq(3), mockall::predicate::always())
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        iommu
+            .expect_invalidate_asid()
+            .with(eq(3))
+            .returning(|_| Ok(()));
+        iommu
+            .expect_invalidate_range()
+            .with(eq(3), eq(VirtualAddress::from(0x1_0000)), eq(0x2000))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        iommu
+            .expect_detach()
+            .with(eq(7))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut space = IoAddressSpace::new(&pa, &iommu, 7, 3).unwrap();
+        assert_eq!(space.stream(), 7);
+        let io = VirtualAddress::from(0x1_0000);
+        space.grant(&frames, io, pages, 2, true).unwrap();
+        space
+            .grant(
+                &frames,
+                io.byte_add(0x4000),
+                pages.byte_add(0x2000),
+                2,
+                false,
+            )
+            .unwrap();
+        assert_eq!(
+            space.translate(io.byte_add(0x1000)),
+            Some(pages.byte_add(0x1000))
+        );
+        assert_eq!(space.translate(io.byte_add(0x2000)), None);
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 2);
+        assert!(matches!(
+            space.grant(&frames, io.byte_add(0x1000), pages, 1, true),
+            Err(Error::AlreadyGranted { .. })
+        ));
+
+        space.revoke(&frames, &iommu, io).unwrap();
+        assert_eq!(space.translate(io), None);
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 1);
+        assert!(matches!(
+            space.revoke(&frames, &iommu, io),
+            Err(Error::NotGranted { .. })
+        ));
+
+        space.release(&frames, &iommu).unwrap();
+        assert_eq!(frames.frame(pages.byte_add(0x2000)).unwrap().ref_count(), 1);
+        pa.free(pages, 4).unwrap();
+        pa.end_check();
+    }
+
+    #[test]
+    fn released_pages_are_freed() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 32);
+        let pages = pa.allocate(2).unwrap();
+        let frames = frames(&pa, pages, 2);
+        let mut iommu = MockIoMmu::new();
+        iommu.expect_attach().returning(|_, _, _| Ok(()));
+        iommu.expect_invalidate_asid().returning(|_| Ok(()));
+        iommu.expect_detach().returning(|_| Ok(()));
+
+        // the driver has given up its mapping, so the device holds the only references
+        let mut space = IoAddressSpace::new(&pa, &iommu, 1, 1).unwrap();
+        space
+            .grant(&frames, VirtualAddress::from(0x1000), pages, 2, true)
+            .unwrap();
+        assert!(matches!(
+            space.grant(
+                &frames,
+                VirtualAddress::from(0x10_0000),
+                PhysicalAddress::from(0x10),
+                1,
+                true
+            ),
+            Err(Error::Memory { .. })
+        ));
+        space.release(&frames, &iommu).unwrap();
+        pa.end_check();
+    }
+
+    #[test]
+    fn unflushed_regions_stay_granted() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 32);
+        let pages = pa.allocate(1).unwrap();
+        let frames = frames(&pa, pages, 1);
+        let mut iommu = MockIoMmu::new();
+        iommu.expect_attach().returning(|_, _, _| Ok(()));
+        iommu.expect_invalidate_asid().returning(|_| Ok(()));
+        iommu
+            .expect_invalidate_range()
+            .returning(|_, _, _| Err(Error::Timeout));
+        iommu.expect_detach().returning(|_| Err(Error::Timeout));
+
+        let mut space = IoAddressSpace::new(&pa, &iommu, 1, 1).unwrap();
+        let io = VirtualAddress::from(0x1000);
+        space.grant(&frames, io, pages, 1, true).unwrap();
+        assert!(matches!(
+            space.revoke(&frames, &iommu, io),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(space.grants().len(), 1);
+        // the device may still reach the page, so it is never freed
+        assert!(matches!(
+            space.release(&frames, &iommu),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 1);
+    }
+}
@@ -0,0 +1,398 @@
+//! Allocation of kernel virtual address space, i.e. for mapping device MMIO regions and kernel
+//! stacks.
+use alloc::vec::Vec;
+use snafu::{ensure, OptionExt as _, ResultExt as _, Snafu};
+
+use super::{
+    page_table::{self, MapBlockSize, MemoryKind, MemoryProperties, TlbFlush},
+    PageAllocator, PageSize, PageTables, PhysicalAddress, VirtualAddress, STACK_GUARD_PAGES,
+};
+use crate::sync::Mutex;
+
+/// Errors that could arise allocating kernel virtual addresses.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// There was no free region of virtual addresses large enough.
+    #[snafu(display("No free kernel virtual region of {length} bytes"))]
+    OutOfSpace {
+        /// Length of the requested region in bytes.
+        length: usize,
+    },
+    /// A region of zero length was requested.
+    InvalidLength,
+    /// A region being freed was not allocated by this allocator.
+    #[snafu(display("Region {address:?} ({length} bytes) is not allocated"))]
+    NotAllocated {
+        /// Start of the region.
+        address: VirtualAddress,
+        /// Length of the region in bytes.
+        length: usize,
+    },
+    /// Error occurred allocating memory.
+    Memory {
+        /// Cause of the error.
+        source: super::Error,
+    },
+    /// Error occurred updating the page tables.
+    PageTables {
+        /// Cause of the error.
+        source: page_table::Error,
+    },
+}
+
+/// A kernel stack mapped by [`KernelVmAllocator::map_stack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    /// The lowest address of the unmapped guard pages below the stack.
+    pub guard: VirtualAddress,
+    /// The initial stack pointer, just past the highest address of the stack.
+    pub top: VirtualAddress,
+    /// The physical pages that back the stack.
+    pub pages: PhysicalAddress,
+    /// The number of pages in the stack, not including the guard pages.
+    pub num_pages: usize,
+}
+
+// The stack is only a description of memory owned by the kernel, which can be freed on any core.
+unsafe impl Send for KernelStack {}
+
+/// Hands out non-overlapping regions of the kernel virtual address space.
+///
+/// Regions are always a whole number of pages. Free regions are kept in a list sorted by address
+/// and allocated first-fit.
+#[allow(clippy::module_name_repetitions)]
+pub struct KernelVmAllocator {
+    page_size: PageSize,
+    /// Free regions as (start address, length in bytes), sorted by start address.
+    free: Mutex<Vec<(usize, usize)>>,
+    /// Guard regions below mapped stacks as (start address, length in bytes).
+    stack_guards: Mutex<Vec<(usize, usize)>>,
+}
+
+impl KernelVmAllocator {
+    /// Create a new allocator that allocates virtual addresses in the region starting at `start` that is `length` bytes long.
+    ///
+    /// # Panics
+    /// Panics if the region is not in the kernel address space or is not page aligned.
+    #[must_use]
+    pub fn new(page_size: PageSize, start: VirtualAddress, length: usize) -> Self {
+        assert!(start.is_in_kernel_space());
+        assert!(
+            start.is_aligned_to(page_size.into()) && length.is_multiple_of(usize::from(page_size))
+        );
+        Self {
+            page_size,
+            free: Mutex::new(if length > 0 {
+                alloc::vec![(usize::from(start), length)]
+            } else {
+                Vec::new()
+            }),
+            stack_guards: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn round_to_pages(&self, length: usize) -> usize {
+        length.div_ceil(self.page_size.into()) * self.page_size
+    }
+
+    /// Allocate a region of virtual addresses that is at least `length` bytes long, rounded up to a whole number of pages.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidLength`] if `length` is zero.
+    /// - [`Error::OutOfSpace`] if there is no free region large enough.
+    pub fn allocate(&self, length: usize) -> Result<VirtualAddress, Error> {
+        ensure!(length > 0, InvalidLengthSnafu);
+        let length = self.round_to_pages(length);
+        let mut free = self.free.lock();
+        let index = free
+            .iter()
+            .position(|(_, l)| *l >= length)
+            .context(OutOfSpaceSnafu { length })?;
+        let (start, region_length) = free[index];
+        if region_length == length {
+            free.remove(index);
+        } else {
+            free[index] = (start + length, region_length - length);
+        }
+        Ok(start.into())
+    }
+
+    /// Free a region previously returned by [`Self::allocate`] so that it can be reused.
+    ///
+    /// # Errors
+    /// - [`Error::NotAllocated`] if any part of the region is already free.
+    pub fn free(&self, address: VirtualAddress, length: usize) -> Result<(), Error> {
+        let start = usize::from(address);
+        let length = self.round_to_pages(length);
+        let mut free = self.free.lock();
+        let index = free.partition_point(|(s, _)| *s < start);
+        let overlaps_prev = index > 0 && free[index - 1].0 + free[index - 1].1 > start;
+        let overlaps_next = index < free.len() && start + length > free[index].0;
+        ensure!(
+            length > 0 && !overlaps_prev && !overlaps_next,
+            NotAllocatedSnafu { address, length }
+        );
+        free.insert(index, (start, length));
+        // merge with adjacent free regions
+        if index + 1 < free.len() && start + length == free[index + 1].0 {
+            free[index].1 += free.remove(index + 1).1;
+        }
+        if index > 0 && free[index - 1].0 + free[index - 1].1 == start {
+            free[index - 1].1 += free.remove(index).1;
+        }
+        Ok(())
+    }
+
+    /// Map the `length` bytes of device MMIO registers at `base` into a newly allocated region of
+    /// kernel virtual addresses in `page_tables`, returning the virtual address of `base`.
+    ///
+    /// The region is mapped as device memory, so accesses are uncached and not reordered.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidLength`] if `length` is zero.
+    /// - [`Error::OutOfSpace`] if there is no free region large enough.
+    /// - [`Error::PageTables`] if the region could not be mapped. The region may be partially
+    ///   mapped, so its virtual addresses are not reused.
+    pub fn map_device<PA: PageAllocator>(
+        &self,
+        page_tables: &mut PageTables<'_, PA>,
+        base: PhysicalAddress,
+        length: usize,
+    ) -> Result<VirtualAddress, Error> {
+        ensure!(length > 0, InvalidLengthSnafu);
+        let offset = usize::from(base) % usize::from(self.page_size);
+        let length = self.round_to_pages(offset + length);
+        let virtual_start = self.allocate(length)?;
+        page_tables
+            .map(
+                virtual_start,
+                PhysicalAddress::from(usize::from(base) - offset),
+                length / self.page_size,
+                MapBlockSize::Page,
+                &MemoryProperties {
+                    writable: true,
+                    kind: MemoryKind::Device,
+                    ..MemoryProperties::default()
+                },
+            )
+            .context(PageTablesSnafu)?;
+        Ok(virtual_start.byte_add(offset))
+    }
+
+    /// Allocate and map a kernel stack of `num_pages` pages from `page_allocator` into `page_tables`,
+    /// leaving [`STACK_GUARD_PAGES`] unmapped below it so that an overflow causes a fault.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidLength`] if `num_pages` is zero.
+    /// - [`Error::OutOfSpace`] if there is no free region large enough.
+    /// - [`Error::Memory`] if the pages for the stack could not be allocated.
+    /// - [`Error::PageTables`] if the stack could not be mapped. The stack may be partially mapped,
+    ///   so its pages and virtual addresses are not reused.
+    pub fn map_stack<PA: PageAllocator>(
+        &self,
+        page_tables: &mut PageTables<'_, PA>,
+        page_allocator: &impl PageAllocator,
+        num_pages: usize,
+    ) -> Result<KernelStack, Error> {
+        ensure!(num_pages > 0, InvalidLengthSnafu);
+        let guard_length = STACK_GUARD_PAGES * self.page_size;
+        let pages = page_allocator
+            .allocate_zeroed(num_pages)
+            .context(MemorySnafu)?;
+        let guard = match self.allocate(guard_length + num_pages * self.page_size) {
+            Ok(guard) => guard,
+            Err(e) => {
+                page_allocator.free(pages, num_pages).context(MemorySnafu)?;
+                return Err(e);
+            }
+        };
+        let bottom = guard.byte_add(guard_length);
+        page_tables
+            .map(
+                bottom,
+                pages,
+                num_pages,
+                MapBlockSize::Page,
+                &MemoryProperties {
+                    writable: true,
+                    ..MemoryProperties::default()
+                },
+            )
+            .context(PageTablesSnafu)?;
+        self.stack_guards
+            .lock()
+            .push((usize::from(guard), guard_length));
+        Ok(KernelStack {
+            guard,
+            top: bottom.byte_add(num_pages * self.page_size),
+            pages,
+            num_pages,
+        })
+    }
+
+    /// Unmap a stack mapped by [`Self::map_stack`] and free its virtual addresses.
+    ///
+    /// Returns the region that must be flushed from the TLB. The stack's physical pages must not be
+    /// freed until the flush is complete.
+    ///
+    /// # Errors
+    /// - [`Error::PageTables`] if the stack could not be unmapped.
+    /// - [`Error::NotAllocated`] if the stack was not allocated by this allocator.
+    pub fn unmap_stack<PA: PageAllocator>(
+        &self,
+        page_tables: &mut PageTables<'_, PA>,
+        stack: &KernelStack,
+    ) -> Result<TlbFlush, Error> {
+        let guard_length = STACK_GUARD_PAGES * self.page_size;
+        let flush = page_tables
+            .unmap(
+                stack.guard.byte_add(guard_length),
+                stack.num_pages,
+                MapBlockSize::Page,
+            )
+            .context(PageTablesSnafu)?;
+        self.stack_guards
+            .lock()
+            .retain(|(start, _)| *start != usize::from(stack.guard));
+        self.free(stack.guard, guard_length + stack.num_pages * self.page_size)?;
+        Ok(flush)
+    }
+
+    /// Returns true if `address` is in the guard pages below a stack mapped by [`Self::map_stack`],
+    /// which means that the stack has overflowed.
+    pub fn is_stack_guard(&self, address: VirtualAddress) -> bool {
+        let address = usize::from(address);
+        self.stack_guards
+            .lock()
+            .iter()
+            .any(|(start, length)| (*start..start + length).contains(&address))
+    }
+
+    /// Unmap a region mapped by [`Self::map_device`] and free its virtual addresses.
+    ///
+    /// The `address` and `length` must be the same as the returned address and length passed to [`Self::map_device`].
+    /// Returns the region that must be flushed from the TLB.
+    ///
+    /// # Errors
+    /// - [`Error::PageTables`] if the region could not be unmapped.
+    /// - [`Error::NotAllocated`] if the region was not allocated by this allocator.
+    pub fn unmap_device<PA: PageAllocator>(
+        &self,
+        page_tables: &mut PageTables<'_, PA>,
+        address: VirtualAddress,
+        length: usize,
+    ) -> Result<TlbFlush, Error> {
+        let offset = usize::from(address) % usize::from(self.page_size);
+        let virtual_start = VirtualAddress::from(usize::from(address) - offset);
+        let length = self.round_to_pages(offset + length);
+        let flush = page_tables
+            .unmap(virtual_start, length / self.page_size, MapBlockSize::Page)
+            .context(PageTablesSnafu)?;
+        self.free(virtual_start, length)?;
+        Ok(flush)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::MockPageAllocator;
+
+    const BASE: usize = 0xffff_8000_0000_0000;
+
+    #[test]
+    fn allocations_do_not_overlap() {
+        let vm = KernelVmAllocator::new(PageSize::FourKiB, BASE.into(), 0x10000);
+        let a = vm.allocate(1).unwrap();
+        let b = vm.allocate(0x2000).unwrap();
+        let c = vm.allocate(0x1001).unwrap();
+        assert_eq!(usize::from(a), BASE);
+        assert_eq!(usize::from(b), BASE + 0x1000);
+        assert_eq!(usize::from(c), BASE + 0x3000);
+        assert!(matches!(vm.allocate(0), Err(Error::InvalidLength)));
+        assert!(matches!(
+            vm.allocate(0x10000),
+            Err(Error::OutOfSpace { length: 0x10000 })
+        ));
+    }
+
+    #[test]
+    fn free_coalesces() {
+        let vm = KernelVmAllocator::new(PageSize::FourKiB, BASE.into(), 0x4000);
+        let a = vm.allocate(0x1000).unwrap();
+        let b = vm.allocate(0x1000).unwrap();
+        let c = vm.allocate(0x2000).unwrap();
+        vm.free(a, 0x1000).unwrap();
+        vm.free(c, 0x2000).unwrap();
+        assert!(matches!(vm.allocate(0x3000), Err(Error::OutOfSpace { .. })));
+        vm.free(b, 0x1000).unwrap();
+        assert_eq!(usize::from(vm.allocate(0x4000).unwrap()), BASE);
+    }
+
+    #[test]
+    fn double_free() {
+        let vm = KernelVmAllocator::new(PageSize::FourKiB, BASE.into(), 0x4000);
+        let a = vm.allocate(0x2000).unwrap();
+        vm.free(a, 0x2000).unwrap();
+        assert!(matches!(
+            vm.free(a.byte_add(0x1000), 0x1000),
+            Err(Error::NotAllocated { .. })
+        ));
+    }
+
+    #[test]
+    fn map_unmap_stack() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        {
+            let root = pa.allocate_zeroed(1).unwrap();
+            let mut pt = unsafe { PageTables::from_existing(&pa, root, true) };
+            let vm = KernelVmAllocator::new(PageSize::FourKiB, BASE.into(), 0x10000);
+            let stack = vm.map_stack(&mut pt, &pa, 2).unwrap();
+            assert_eq!(usize::from(stack.guard), BASE);
+            assert_eq!(usize::from(stack.top), BASE + 0x3000);
+            assert!(pt.physical_address_of(stack.guard).is_none());
+            assert_eq!(
+                pt.physical_address_of(VirtualAddress::from(BASE + 0x2fff)),
+                Some(stack.pages.byte_add(0x1fff))
+            );
+
+            assert!(vm.is_stack_guard(VirtualAddress::from(BASE + 0xff8)));
+            assert!(!vm.is_stack_guard(VirtualAddress::from(BASE + 0x1000)));
+
+            let flush = vm.unmap_stack(&mut pt, &stack).unwrap();
+            assert_eq!(usize::from(flush.virtual_start), BASE + 0x1000);
+            assert_eq!(flush.length, 0x2000);
+            assert!(!vm.is_stack_guard(VirtualAddress::from(BASE + 0xff8)));
+            pa.free(stack.pages, stack.num_pages).unwrap();
+            drop(pt);
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn map_unmap_device() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        {
+            let root = pa.allocate_zeroed(1).unwrap();
+            let mut pt = unsafe { PageTables::from_existing(&pa, root, true) };
+            let vm = KernelVmAllocator::new(PageSize::FourKiB, BASE.into(), 0x10000);
+            let va = vm.map_device(&mut pt, 0x0900_0010.into(), 0x1000).unwrap();
+            assert_eq!(usize::from(va), BASE + 0x10);
+            assert_eq!(
+                pt.physical_address_of(va),
+                Some(PhysicalAddress::from(0x0900_0010))
+            );
+            assert_eq!(
+                pt.physical_address_of(va.byte_add(0x1000)),
+                Some(PhysicalAddress::from(0x0900_1010))
+            );
+            let flush = vm.unmap_device(&mut pt, va, 0x1000).unwrap();
+            assert_eq!(usize::from(flush.virtual_start), BASE);
+            assert_eq!(flush.length, 0x2000);
+            assert!(flush.global);
+            assert!(pt.physical_address_of(va).is_none());
+            drop(pt);
+        }
+        pa.end_check();
+    }
+}
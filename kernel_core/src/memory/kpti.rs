@@ -0,0 +1,245 @@
+//! Kernel page table isolation (KPTI), which protects against Meltdown-class attacks (CVE-2017-5754)
+//! on cores that speculatively read kernel memory from user space.
+//!
+//! While a user thread runs, `TTBR1_EL1` points at a separate set of kernel page tables that map
+//! only what is needed to take an exception: the trampoline page holding the exception vector, the
+//! data it reads, and the stack each core takes exceptions on. The vector switches to the full
+//! kernel page tables on entry from user space and back to the reduced ones on the way out.
+//!
+//! The kernel's mappings are not global while the kernel is isolated, so that the TLB doesn't keep
+//! them around for user space. Instead each address space runs with a pair of ASIDs (see
+//! [`asid_pair`]): the even one while the kernel runs, and the odd one while user space runs, so
+//! nothing has to be flushed when switching between them.
+use super::{
+    page_table::{Error, MapBlockSize, MemoryProperties},
+    AddressSpaceId, PageAllocator, PageTables, PhysicalAddress, VirtualAddress,
+};
+use crate::platform::boot_args::{BootArgs, Value};
+
+/// Whether kernel page table isolation is used, as chosen by the `kpti` boot argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Never isolate the kernel page tables (`"kpti": false`).
+    Off,
+    /// Always isolate the kernel page tables (`"kpti": true`).
+    On,
+    /// Isolate the kernel page tables only if the boot core is vulnerable (the default).
+    #[default]
+    Auto,
+}
+
+impl Mode {
+    /// Read the mode from the `kpti` boot argument.
+    #[must_use]
+    pub fn from_boot_args(args: &BootArgs) -> Self {
+        match args.get(b"kpti") {
+            Some(Value::Bool(true)) => Mode::On,
+            Some(Value::Bool(false)) => Mode::Off,
+            _ => Mode::Auto,
+        }
+    }
+
+    /// True if the kernel page tables should be isolated on a core with the given `MIDR_EL1` and
+    /// `ID_AA64PFR0_EL1` register values.
+    #[must_use]
+    pub fn is_enabled(self, midr: u64, pfr0: u64) -> bool {
+        match self {
+            Mode::Off => false,
+            Mode::On => true,
+            Mode::Auto => vulnerability(midr, pfr0) != Vulnerability::Safe,
+        }
+    }
+}
+
+/// Whether a core could be attacked with Meltdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vulnerability {
+    /// The core reports `ID_AA64PFR0_EL1.CSV3` or is known to be safe.
+    Safe,
+    /// The core is known to be vulnerable.
+    Vulnerable,
+    /// The core is not known either way, so it must be assumed to be vulnerable.
+    Unknown,
+}
+
+/// Cores known not to speculate past the permission check on kernel memory, as
+/// (implementer, part number) pairs from `MIDR_EL1`.
+const UNAFFECTED_CORES: &[(u64, u64)] = &[
+    (0x41, 0xd03), // Cortex-A53
+    (0x41, 0xd04), // Cortex-A35
+    (0x41, 0xd05), // Cortex-A55
+    (0x41, 0xd07), // Cortex-A57
+    (0x41, 0xd08), // Cortex-A72
+    (0x41, 0xd09), // Cortex-A73
+];
+
+/// Cores known to be vulnerable, as (implementer, part number) pairs from `MIDR_EL1`.
+const AFFECTED_CORES: &[(u64, u64)] = &[
+    (0x41, 0xd0a), // Cortex-A75
+];
+
+/// Find out whether a core with the given `MIDR_EL1` and `ID_AA64PFR0_EL1` register values could
+/// be vulnerable to Meltdown.
+#[must_use]
+pub fn vulnerability(midr: u64, pfr0: u64) -> Vulnerability {
+    let csv3 = (pfr0 >> 60) & 0xf;
+    let core = ((midr >> 24) & 0xff, (midr >> 4) & 0xfff);
+    if csv3 != 0 || UNAFFECTED_CORES.contains(&core) {
+        Vulnerability::Safe
+    } else if AFFECTED_CORES.contains(&core) {
+        Vulnerability::Vulnerable
+    } else {
+        Vulnerability::Unknown
+    }
+}
+
+/// The ASIDs that the kernel and user space run the address space given `asid` with, while the
+/// kernel is isolated. This halves the number of ASIDs available, so `asid` must fit in one less
+/// bit than the hardware supports.
+#[must_use]
+pub const fn asid_pair(asid: AddressSpaceId) -> (AddressSpaceId, AddressSpaceId) {
+    (asid << 1, (asid << 1) | 1)
+}
+
+/// The reduced kernel page tables used while user threads run.
+pub struct UserKernelTables<'pa, PA: PageAllocator> {
+    tables: PageTables<'pa, PA>,
+    page_size: usize,
+}
+
+impl<'pa, PA: PageAllocator> UserKernelTables<'pa, PA> {
+    /// Create reduced kernel page tables that don't map anything yet.
+    ///
+    /// # Errors
+    /// Returns an error if the root table could not be allocated.
+    pub fn new(page_allocator: &'pa PA) -> Result<Self, super::Error> {
+        let root = page_allocator.allocate_zeroed(1)?;
+        // SAFETY: the root table was just allocated from `page_allocator` and is empty.
+        let mut tables = unsafe { PageTables::from_existing(page_allocator, root, true) };
+        tables.make_not_global();
+        Ok(Self {
+            tables,
+            page_size: page_allocator.page_size().into(),
+        })
+    }
+
+    /// Map `num_pages` pages starting at `start` the same way the full `kernel` page tables do,
+    /// with `properties`.
+    ///
+    /// The pages are mapped one at a time, even if `kernel` maps them with blocks, so that nothing
+    /// around them is exposed.
+    ///
+    /// # Errors
+    /// - [`Error::NotMapped`] if one of the pages is not mapped in `kernel`.
+    /// - Any error from mapping the pages, in which case some of them may already be mapped.
+    pub fn mirror(
+        &mut self,
+        kernel: &PageTables<'_, impl PageAllocator>,
+        start: VirtualAddress,
+        num_pages: usize,
+        properties: &MemoryProperties,
+    ) -> Result<(), Error> {
+        for i in 0..num_pages {
+            let address = start.byte_add(i * self.page_size);
+            let physical = kernel
+                .physical_address_of(address)
+                .ok_or(Error::NotMapped { address })?;
+            self.tables
+                .map(address, physical, 1, MapBlockSize::Page, properties)?;
+        }
+        Ok(())
+    }
+
+    /// The physical address of the root table, to be loaded into `TTBR1_EL1`.
+    #[must_use]
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.tables.physical_address()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{tests::MockPageAllocator, PageSize};
+
+    #[test]
+    fn mode_from_boot_args() {
+        let parse = |text: &[u8]| Mode::from_boot_args(&BootArgs::parse(text).unwrap());
+        assert_eq!(parse(b"{}"), Mode::Auto);
+        assert_eq!(parse(br#"{"kpti": true}"#), Mode::On);
+        assert_eq!(parse(br#"{"kpti": false}"#), Mode::Off);
+
+        let cortex_a72 = 0x410f_d083;
+        let cortex_a75 = 0x413f_d0a0;
+        let csv3 = 1 << 60;
+        assert!(!Mode::Auto.is_enabled(cortex_a72, 0));
+        assert!(Mode::Auto.is_enabled(cortex_a75, 0));
+        assert!(!Mode::Auto.is_enabled(cortex_a75, csv3));
+        assert!(Mode::On.is_enabled(cortex_a72, csv3));
+        assert!(!Mode::Off.is_enabled(cortex_a75, 0));
+
+        let unknown = 0x4100_0010;
+        assert_eq!(vulnerability(cortex_a72, 0), Vulnerability::Safe);
+        assert_eq!(vulnerability(cortex_a75, 0), Vulnerability::Vulnerable);
+        assert_eq!(vulnerability(unknown, 0), Vulnerability::Unknown);
+        assert_eq!(vulnerability(unknown, csv3), Vulnerability::Safe);
+        assert!(Mode::Auto.is_enabled(unknown, 0));
+        assert_eq!(asid_pair(0x7f), (0xfe, 0xff));
+    }
+
+    #[test]
+    fn mirror_only_given_pages() {
+        const BASE: usize = 0xffff_0000_4000_0000;
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 32);
+        {
+            let root = pa.allocate_zeroed(1).unwrap();
+            let mut kernel = unsafe { PageTables::from_existing(&pa, root, true) };
+            let physical = pa.allocate(4).unwrap();
+            kernel
+                .map(
+                    BASE.into(),
+                    physical,
+                    4,
+                    MapBlockSize::Page,
+                    &MemoryProperties::default(),
+                )
+                .unwrap();
+
+            let mut user = UserKernelTables::new(&pa).unwrap();
+            user.mirror(
+                &kernel,
+                VirtualAddress::from(BASE + 0x1000),
+                2,
+                &MemoryProperties::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                user.tables
+                    .physical_address_of(VirtualAddress::from(BASE + 0x2008)),
+                Some(physical.byte_add(0x2008))
+            );
+            assert!(user
+                .tables
+                .physical_address_of(VirtualAddress::from(BASE))
+                .is_none());
+            assert!(user
+                .tables
+                .physical_address_of(VirtualAddress::from(BASE + 0x3000))
+                .is_none());
+            assert!(matches!(
+                user.mirror(
+                    &kernel,
+                    VirtualAddress::from(BASE + 0x4000),
+                    1,
+                    &MemoryProperties::default()
+                ),
+                Err(Error::NotMapped { .. })
+            ));
+
+            drop(user);
+            drop(kernel);
+            pa.free(physical, 4).unwrap();
+        }
+        pa.end_check();
+    }
+}
@@ -0,0 +1,274 @@
+//! A map of what each range of physical memory is used for, built while booting.
+//!
+//! The map records the ranges of RAM along with everything that must not be given to the page
+//! allocator: the kernel image, the device tree blob, the initrd and any regions the firmware
+//! reserved. Reserved regions take precedence over RAM wherever they overlap.
+use core::fmt;
+use snafu::{ensure, Snafu};
+
+use super::PhysicalAddress;
+
+/// Errors that can occur building a [`MemoryMap`].
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The map has no room for another region.
+    #[snafu(display("memory map is full, can't record {region}"))]
+    Full {
+        /// The region that could not be recorded.
+        region: Region,
+    },
+}
+
+/// What a region of physical memory is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// RAM that is free to be allocated.
+    Usable,
+    /// The kernel image.
+    Kernel,
+    /// The device tree blob provided by the bootloader.
+    DeviceTree,
+    /// The initial RAM disk provided by the bootloader.
+    Initrd,
+    /// Memory reserved by the firmware or bootloader.
+    Reserved,
+    /// Device registers.
+    Mmio,
+}
+
+/// A range of physical memory with a particular use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    start: usize,
+    /// The length of the region in bytes.
+    pub length: usize,
+    /// What the region is used for.
+    pub kind: RegionKind,
+}
+
+impl Region {
+    /// Create a region of `length` bytes starting at `start`.
+    #[must_use]
+    pub fn new(start: PhysicalAddress, length: usize, kind: RegionKind) -> Self {
+        Self {
+            start: start.into(),
+            length,
+            kind,
+        }
+    }
+
+    /// The first address in the region.
+    #[must_use]
+    pub fn start(&self) -> PhysicalAddress {
+        PhysicalAddress::from(self.start)
+    }
+
+    /// The address right after the end of the region.
+    #[must_use]
+    pub fn end(&self) -> usize {
+        self.start.saturating_add(self.length)
+    }
+
+    /// Returns true if `address` is inside the region.
+    #[must_use]
+    pub fn contains(&self, address: PhysicalAddress) -> bool {
+        (self.start..self.end()).contains(&usize::from(address))
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#014x}..{:#014x} {:?} ({} KiB)",
+            self.start,
+            self.end(),
+            self.kind,
+            self.length / 1024
+        )
+    }
+}
+
+/// The regions of physical memory, in order of their start address.
+///
+/// The map has room for `MAX_REGIONS` regions, so that it can be built before the heap exists.
+pub struct MemoryMap<const MAX_REGIONS: usize = 64> {
+    regions: [Option<Region>; MAX_REGIONS],
+    len: usize,
+}
+
+impl<const MAX_REGIONS: usize> Default for MemoryMap<MAX_REGIONS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_REGIONS: usize> MemoryMap<MAX_REGIONS> {
+    /// Create an empty map.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            regions: [None; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Record `region` in the map. Empty regions are ignored.
+    ///
+    /// # Errors
+    /// Returns [`Error::Full`] if the map has no room for the region.
+    pub fn add(&mut self, region: Region) -> Result<(), Error> {
+        if region.length == 0 {
+            return Ok(());
+        }
+        ensure!(self.len < MAX_REGIONS, FullSnafu { region });
+        let index = self
+            .regions()
+            .position(|r| r.start > region.start)
+            .unwrap_or(self.len);
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = Some(region);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Iterate over every region recorded in the map, in order of start address.
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions[..self.len].iter().flatten().copied()
+    }
+
+    /// What the memory at `address` is used for, or `None` if it isn't in the map.
+    ///
+    /// Where regions overlap, reserved regions take precedence over RAM.
+    #[must_use]
+    pub fn kind_of(&self, address: PhysicalAddress) -> Option<RegionKind> {
+        let mut containing = self.regions().filter(|r| r.contains(address));
+        let first = containing.next()?;
+        Some(
+            containing
+                .chain(core::iter::once(first))
+                .find(|r| r.kind != RegionKind::Usable)
+                .map_or(RegionKind::Usable, |r| r.kind),
+        )
+    }
+
+    /// The total number of bytes in regions of `kind`, not accounting for overlap.
+    #[must_use]
+    pub fn total(&self, kind: RegionKind) -> usize {
+        self.regions()
+            .filter(|r| r.kind == kind)
+            .map(|r| r.length)
+            .sum()
+    }
+
+    /// Iterate over the (start, length in bytes) ranges of RAM that aren't covered by any
+    /// other region, in order of start address. These can be given to the page allocator.
+    pub fn usable_regions(&self) -> impl Iterator<Item = (PhysicalAddress, usize)> + '_ {
+        self.regions()
+            .filter(|r| r.kind == RegionKind::Usable)
+            .flat_map(move |ram| {
+                let mut reserved = self
+                    .regions()
+                    .filter(|r| r.kind != RegionKind::Usable)
+                    .skip_while(move |r| r.end() <= ram.start)
+                    .take_while(move |r| r.start < ram.end());
+                let mut position = ram.start;
+                core::iter::from_fn(move || {
+                    while position < ram.end() {
+                        let (gap_end, next_position) = match reserved.next() {
+                            Some(r) => (r.start, r.end()),
+                            None => (ram.end(), ram.end()),
+                        };
+                        let start = position;
+                        position = position.max(next_position);
+                        if gap_end > start {
+                            return Some((PhysicalAddress::from(start), gap_end - start));
+                        }
+                    }
+                    None
+                })
+            })
+    }
+}
+
+impl<const MAX_REGIONS: usize> fmt::Debug for MemoryMap<MAX_REGIONS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.regions()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn region(start: usize, length: usize, kind: RegionKind) -> Region {
+        Region::new(PhysicalAddress::from(start), length, kind)
+    }
+
+    fn test_map() -> MemoryMap<8> {
+        let mut map = MemoryMap::new();
+        map.add(region(0x8000_0000, 0x1000_0000, RegionKind::Usable))
+            .unwrap();
+        map.add(region(0x4000_0000, 0x1000_0000, RegionKind::Usable))
+            .unwrap();
+        map.add(region(0x4020_0000, 0x10_0000, RegionKind::Kernel))
+            .unwrap();
+        map.add(region(0x4800_0000, 0x1_0000, RegionKind::Initrd))
+            .unwrap();
+        // the device tree overlaps a firmware reservation
+        map.add(region(0x4ff0_0000, 0x2_0000, RegionKind::DeviceTree))
+            .unwrap();
+        map.add(region(0x4ff1_0000, 0xf_0000, RegionKind::Reserved))
+            .unwrap();
+        map.add(region(0, 0x4000_0000, RegionKind::Mmio)).unwrap();
+        map
+    }
+
+    #[test]
+    fn regions_are_sorted() {
+        let mut map = test_map();
+        let starts: Vec<_> = map.regions().map(|r| r.start).collect();
+        assert!(starts.is_sorted());
+        assert_eq!(starts.len(), 7);
+        assert_eq!(map.total(RegionKind::Usable), 0x2000_0000);
+        map.add(region(0x9000_0000, 0, RegionKind::Reserved))
+            .unwrap();
+        map.add(region(0x1000, 0x1000, RegionKind::Reserved))
+            .unwrap();
+        assert!(matches!(
+            map.add(region(0x2000, 0x1000, RegionKind::Reserved)),
+            Err(Error::Full { .. })
+        ));
+    }
+
+    #[test]
+    fn reserved_regions_take_precedence() {
+        let map = test_map();
+        let kind_of = |a: usize| map.kind_of(PhysicalAddress::from(a));
+        assert_eq!(kind_of(0x4000_0000), Some(RegionKind::Usable));
+        assert_eq!(kind_of(0x4020_1000), Some(RegionKind::Kernel));
+        assert_eq!(kind_of(0x4ff0_0000), Some(RegionKind::DeviceTree));
+        assert_eq!(kind_of(0x4fff_ffff), Some(RegionKind::Reserved));
+        assert_eq!(kind_of(0x1000), Some(RegionKind::Mmio));
+        assert_eq!(kind_of(0x5000_0000), None);
+    }
+
+    #[test]
+    fn usable_regions_exclude_reservations() {
+        let map = test_map();
+        let usable: Vec<_> = map
+            .usable_regions()
+            .map(|(s, l)| (usize::from(s), l))
+            .collect();
+        assert_eq!(
+            usable,
+            [
+                (0x4000_0000, 0x20_0000),
+                (0x4030_0000, 0x7d0_0000),
+                (0x4801_0000, 0x7ef_0000),
+                (0x8000_0000, 0x1000_0000),
+            ]
+        );
+    }
+}
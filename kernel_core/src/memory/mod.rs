@@ -12,11 +12,15 @@
 //! | `*const T`, `*mut T`  | Unsafe | Raw pointer with fewer safety gurantees from Rust. Still should be a kernel-space address that is dereferenceable via the MMU while in EL1. |
 //! | [`VirtualPointer<T>`], [`VirtualPointerMut<T>`] | If kernel-space: unsafe but trivial. Otherwise requires a manual page table lookup. | A virtual address in some address space. If the address space is the kernel's, then this is trivially convertable into a raw pointer. Otherwise a [`PageTables`] instance must be consulted to lookup the actual physical address that it is mapped to. |
 //! | [`VirtualAddress`]    | Same as `VirtualPointer` but must assume type. | An address in a virtual memory address space that is not associated with a type, but indicates some location. Assumed mutable for convenience. |
-//! | [`PhysicalPointer<T>`]| With conversion to kernel-space, unsafe. | A pointer to something in physical memory, i.e. the untranslated address space. Because the kernel virtual address space is identity mapped, these are trivially convertable to a [`VirtualPointer<T>`] or `*mut T`. All physical addresses are assumed to be mutable from the kernel's perspective. |
+//! | [`PhysicalPointer<T>`]| With conversion to kernel-space, unsafe. | A pointer to something in physical memory, i.e. the untranslated address space. Because the kernel linearly maps all of physical memory, these are trivially convertable to a [`VirtualPointer<T>`] or `*mut T`. All physical addresses are assumed to be mutable from the kernel's perspective. |
 //! | [`PhysicalAddress`]   | Same as `PhysicalPointer` but must assume type. | An address in the physical memory address space that is not associated with a type, but indicates some location.
 
-use core::{marker::PhantomData, num::NonZeroUsize};
-use snafu::Snafu;
+use core::{
+    marker::PhantomData,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use snafu::{ensure, Snafu};
 
 #[cfg(test)]
 use mockall::automock;
@@ -25,7 +29,7 @@ mod buddy;
 pub use buddy::BuddyPageAllocator;
 
 mod heap;
-pub use heap::HeapAllocator;
+pub use heap::{HeapAllocator, HeapStatistics};
 
 mod subtract_ranges;
 pub use subtract_ranges::*;
@@ -33,14 +37,135 @@ pub use subtract_ranges::*;
 pub mod page_table;
 pub use page_table::PageTables;
 
+pub mod kernel_vm;
+pub use kernel_vm::KernelVmAllocator;
+
+mod zoned;
+pub use zoned::{ZoneId, ZonedPageAllocator};
+
+mod dma;
+pub use dma::{DmaAllocator, DmaBuffer};
+
+pub mod oom;
+pub use oom::{OomPolicy, ReclaimingPageAllocator};
+
+mod frames;
+pub use frames::{FrameFlags, PageFrame, PageFrameDatabase};
+
+pub mod kpti;
+
+pub mod map;
+pub use map::MemoryMap;
+
+pub mod physmap;
+
+pub mod stack_check;
+
+pub mod asid;
+pub use asid::AddressSpaceIdPool;
+
+pub mod iommu;
+pub use iommu::{IoAddressSpace, IoMmu};
+
+crate::tracepoints! {
+    /// Pages were allocated by a page allocator. Arguments: physical address, number of pages.
+    PAGES_ALLOCATED;
+    /// Pages were freed to a page allocator. Arguments: physical address, number of pages.
+    PAGES_FREED;
+}
+
+/// The lowest address of the kernel's half of the address space (selected by `TTBR1_EL1`).
+pub const KERNEL_SPACE_START: usize = 0xffff_0000_0000_0000;
+
+/// The kernel virtual address that physical address zero is mapped to.
+static PHYSICAL_MAP_BASE: AtomicUsize = AtomicUsize::new(KERNEL_SPACE_START);
+
+/// The kernel virtual address that physical address zero is mapped to.
+///
+/// Physical memory is mapped linearly starting at this address, so it is used to convert between
+/// [`PhysicalPointer`]s and kernel pointers. This is [`KERNEL_SPACE_START`] until the boot process
+/// picks a randomized base with [`set_physical_map_base`].
+#[inline]
+#[must_use]
+pub fn physical_map_base() -> usize {
+    PHYSICAL_MAP_BASE.load(Ordering::Relaxed)
+}
+
+/// Change the kernel virtual address that physical memory is mapped at.
+///
+/// # Safety
+/// Physical memory must already be mapped at `base` in the kernel page tables. Pointers produced
+/// with the previous base remain valid only as long as the old mapping does.
+///
+/// # Panics
+/// If `base` is not in the kernel's half of the address space.
+pub unsafe fn set_physical_map_base(base: usize) {
+    assert!(base >= KERNEL_SPACE_START);
+    PHYSICAL_MAP_BASE.store(base, Ordering::Relaxed);
+}
+
+/// The start of the kernel virtual addresses outside of the physical map that are known to be
+/// mapped, see [`set_kernel_image`].
+static KERNEL_IMAGE_START: AtomicUsize = AtomicUsize::new(0);
+
+/// The end of the kernel virtual addresses outside of the physical map that are known to be
+/// mapped, see [`set_kernel_image`].
+static KERNEL_IMAGE_END: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Record that the kernel image at `start` (of `length` bytes) is the only thing still mapped
+/// outside of the physical map.
+///
+/// Until this is called, addresses below the physical map are assumed to be in the mapping made at
+/// boot, which maps physical memory at [`KERNEL_SPACE_START`]. Afterwards, converting any other
+/// address below the physical map to a physical address fails.
+///
+/// # Safety
+/// The boot mapping must have been replaced with one of only the kernel image, which must be
+/// mapped at its physical address offset by [`KERNEL_SPACE_START`].
+pub unsafe fn set_kernel_image(start: usize, length: usize) {
+    KERNEL_IMAGE_START.store(start, Ordering::Relaxed);
+    KERNEL_IMAGE_END.store(start + length, Ordering::Relaxed);
+}
+
+/// Convert a kernel virtual address to a physical address, returning `None` if it is neither in
+/// the physical map nor the kernel image.
+#[inline]
+fn kernel_virtual_to_physical(address: usize) -> Option<usize> {
+    virtual_to_physical_in(
+        address,
+        physical_map_base(),
+        KERNEL_IMAGE_START.load(Ordering::Relaxed)..KERNEL_IMAGE_END.load(Ordering::Relaxed),
+    )
+}
+
+/// Convert `address` to a physical address given the `base` of the physical map and the addresses
+/// of the kernel `image`, which is mapped at its physical address offset by [`KERNEL_SPACE_START`].
+#[inline]
+fn virtual_to_physical_in(
+    address: usize,
+    base: usize,
+    image: core::ops::Range<usize>,
+) -> Option<usize> {
+    if address >= base {
+        Some(address - base)
+    } else if image.contains(&address) {
+        Some(address & !KERNEL_SPACE_START)
+    } else {
+        None
+    }
+}
+
 /// A 48-bit physical address pointer that is not part of a virtual address space.
 ///
-/// Although in the kernel the virtual addresses are identity mapped, the high bits of the address
-/// must be `0xffff` to select the kernel page tables, so a `*mut T` is not quite but very close to
+/// Although in the kernel physical memory is linearly mapped, it is mapped starting at
+/// [`physical_map_base`] in the kernel page tables, so a `*mut T` is not quite but very close to
 /// the physical address of the `T`.
+///
+/// The pointer is only an address and owns nothing, so it is [`Send`] and [`Sync`] whatever `T` is;
+/// dereferencing it still requires converting it to a raw pointer.
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
-pub struct PhysicalPointer<T>(usize, PhantomData<*mut T>);
+pub struct PhysicalPointer<T>(usize, PhantomData<fn() -> *mut T>);
 
 /// A physical 48-bit address that does not dereference to any particular type of value.
 pub type PhysicalAddress = PhysicalPointer<()>;
@@ -106,7 +231,7 @@ impl<T> core::fmt::Debug for PhysicalPointer<T> {
 
 impl<T> From<usize> for PhysicalPointer<T> {
     fn from(value: usize) -> Self {
-        assert!(value < 0xffff_0000_0000_0000);
+        assert!(value < KERNEL_SPACE_START);
         PhysicalPointer(value, PhantomData)
     }
 }
@@ -119,19 +244,21 @@ impl<T> From<PhysicalPointer<T>> for usize {
 
 impl<T> From<*const T> for PhysicalPointer<T> {
     fn from(value: *const T) -> Self {
-        PhysicalPointer(value as usize & 0x0000_ffff_ffff_ffff, PhantomData)
+        value.cast_mut().into()
     }
 }
 
 impl<T> From<PhysicalPointer<T>> for *const T {
     fn from(val: PhysicalPointer<T>) -> Self {
-        (val.0 | 0xffff_0000_0000_0000) as _
+        (val.0 + physical_map_base()) as _
     }
 }
 
 impl<T> From<*mut T> for PhysicalPointer<T> {
     fn from(value: *mut T) -> Self {
-        PhysicalPointer(value as usize & 0x0000_ffff_ffff_ffff, PhantomData)
+        let address = kernel_virtual_to_physical(value as usize)
+            .expect("pointer is neither in the physical map nor the kernel image");
+        PhysicalPointer(address, PhantomData)
     }
 }
 
@@ -139,7 +266,7 @@ impl<T> From<PhysicalPointer<T>> for *mut T {
     fn from(val: PhysicalPointer<T>) -> Self {
         #[cfg(not(test))]
         {
-            (val.0 | 0xffff_0000_0000_0000) as _
+            (val.0 + physical_map_base()) as _
         }
         #[cfg(test)]
         {
@@ -174,7 +301,7 @@ macro_rules! virtual_pointer_impl {
             #[inline]
             #[must_use]
             pub fn is_in_kernel_space(&self) -> bool {
-                self.0 & 0xffff_0000_0000_0000 == 0xffff_0000_0000_0000
+                self.0 & KERNEL_SPACE_START == KERNEL_SPACE_START
             }
 
             /// Offset this pointer forward by `count` number of `T`s.
@@ -250,7 +377,7 @@ macro_rules! virtual_pointer_impl {
 
         impl<T> From<PhysicalPointer<T>> for $vpt<T> {
             fn from(value: PhysicalPointer<T>) -> Self {
-                Self(value.0 | 0xffff_0000_0000_0000, PhantomData)
+                Self(value.0 + physical_map_base(), PhantomData)
             }
         }
 
@@ -260,7 +387,9 @@ macro_rules! virtual_pointer_impl {
             fn try_from(value: $vpt<T>) -> Result<Self, Self::Error> {
                 value
                     .is_in_kernel_space()
-                    .then_some(PhysicalPointer::from(value.0 & 0x0000_ffff_ffff_ffff))
+                    .then(|| kernel_virtual_to_physical(value.0))
+                    .flatten()
+                    .map(PhysicalPointer::from)
                     .ok_or(NotInKernelAddressSpaceError)
             }
         }
@@ -358,6 +487,59 @@ impl core::ops::Div<PageSize> for usize {
     }
 }
 
+/// The number of unmapped guard pages left below each stack, so that overflowing the stack causes
+/// a fault instead of silently corrupting whatever is mapped below it.
+pub const STACK_GUARD_PAGES: usize = 1;
+
+/// A snapshot of how much memory a [`PageAllocator`] is managing and how much of it is free.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStatistics {
+    /// The total number of pages managed by the allocator.
+    pub total_pages: usize,
+    /// The number of pages that are currently free.
+    pub free_pages: usize,
+    /// The number of pages in the largest contiguous run that could be allocated at once.
+    pub largest_free_block: usize,
+}
+
+/// Constraints on where in physical memory an allocation may be placed, for instance so that a
+/// device with limited addressing can reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationConstraints {
+    /// The physical address of the allocation must be a multiple of this power of two, in bytes.
+    pub alignment: usize,
+    /// The highest physical address that any byte of the allocation may have.
+    pub max_address: usize,
+}
+
+impl AllocationConstraints {
+    /// Constraints for a device that can only address the first 4GiB of physical memory.
+    pub const BELOW_4GIB: Self = Self {
+        alignment: 1,
+        max_address: 0xffff_ffff,
+    };
+
+    /// Returns true if the `length` bytes starting at `address` satisfy the constraints.
+    #[must_use]
+    pub fn allow(&self, address: PhysicalAddress, length: usize) -> bool {
+        let address = usize::from(address);
+        address.is_multiple_of(self.alignment)
+            && length
+                .checked_sub(1)
+                .and_then(|l| address.checked_add(l))
+                .is_some_and(|last| last <= self.max_address)
+    }
+}
+
+impl Default for AllocationConstraints {
+    fn default() -> Self {
+        Self {
+            alignment: 1,
+            max_address: usize::MAX,
+        }
+    }
+}
+
 /// A memory allocator that provides pages of physical memory.
 ///
 /// Implementers of this trait must provide internal synchronization and each associated function
@@ -396,8 +578,39 @@ pub trait PageAllocator {
     /// # Errors
     /// - [`Error::UnknownPtr`] if `pages` is null or was not allocated by this allocator.
     fn free(&self, pages: PhysicalAddress, num_pages: usize) -> Result<(), Error>;
+
+    /// Get a snapshot of the allocator's memory usage.
+    ///
+    /// The values may be momentarily inconsistent with each other if pages are being allocated or
+    /// freed concurrently.
+    fn statistics(&self) -> MemoryStatistics;
+
+    /// Allocate `num_pages` of memory that satisfy `constraints`, returning a pointer to the beginning.
+    ///
+    /// The default implementation only succeeds if the allocator happens to return suitable pages.
+    ///
+    /// # Errors
+    /// - [`Error::OutOfMemory`] if there is not enough memory that satisfies the constraints to allocate `num_pages`.
+    /// - [`Error::InvalidSize`] if `num_pages` is zero or the alignment is not a power of two.
+    fn allocate_constrained(
+        &self,
+        num_pages: usize,
+        constraints: &AllocationConstraints,
+    ) -> Result<PhysicalAddress, Error> {
+        ensure!(constraints.alignment.is_power_of_two(), InvalidSizeSnafu);
+        let pages = self.allocate(num_pages)?;
+        if constraints.allow(pages, num_pages * self.page_size()) {
+            Ok(pages)
+        } else {
+            self.free(pages, num_pages)?;
+            OutOfMemorySnafu.fail()
+        }
+    }
 }
 
+/// An address space identifier (ASID) used by the MMU to tag TLB entries for a particular user-space address space.
+pub type AddressSpaceId = u16;
+
 /// Abstract operations provided by the Memory Managment Unit (MMU).
 pub trait MemoryManagmentUnit {
     /// Make a page table data structure current in the MMU so it is used for lookups.
@@ -406,7 +619,42 @@ pub trait MemoryManagmentUnit {
     ///
     /// The page tables provided must be valid or else this function has undefined behavior.
     /// Valid page tables for the kernel must map the caller's return address correctly or else this has undefined behavior. Likewise with the stack, etc.
-    unsafe fn activate_page_tables<PA: PageAllocator>(&self, tables: &PageTables<'_, PA>);
+    ///
+    /// Lookups in user space tables are tagged with `asid`, which is ignored for the kernel's tables.
+    unsafe fn activate_page_tables<PA: PageAllocator>(
+        &self,
+        tables: &PageTables<'_, PA>,
+        asid: AddressSpaceId,
+    );
+
+    /// Make the preceding writes to page tables visible to table walks on all cores.
+    ///
+    /// Writes to live tables must be synchronized before the TLB entries they replace are
+    /// invalidated, or the old entries could be walked again.
+    fn synchronize_tables(&self);
+
+    /// Invalidate every TLB entry tagged with `asid`, on all cores.
+    ///
+    /// This must be done before an ASID is reused for a different address space.
+    fn invalidate_asid(&self, asid: AddressSpaceId);
+
+    /// Invalidate the TLB entries for the `length` bytes of virtual addresses starting at `start`, on all cores.
+    ///
+    /// If `asid` is `None`, entries for the region are invalidated for every address space, which is
+    /// required for global (kernel) mappings.
+    fn invalidate_va_range(
+        &self,
+        asid: Option<AddressSpaceId>,
+        start: VirtualAddress,
+        length: usize,
+    );
+
+    /// Invalidate the entire TLB, on all cores.
+    fn invalidate_all(&self);
+
+    /// Make the `length` bytes of instructions that the kernel wrote to physical memory at
+    /// `start` visible to instruction fetches, on all cores.
+    fn synchronize_instruction_cache(&self, start: PhysicalAddress, length: usize);
 }
 
 #[cfg(test)]
@@ -416,7 +664,10 @@ pub mod tests {
 
     use crate::memory::{InvalidSizeSnafu, OutOfMemorySnafu, PhysicalPointer, UnknownPtrSnafu};
 
-    use super::{Error, PageAllocator, PageSize, PhysicalAddress};
+    use super::{
+        virtual_to_physical_in, Error, MemoryStatistics, PageAllocator, PageSize, PhysicalAddress,
+        KERNEL_SPACE_START,
+    };
 
     /// Generate tests to ensure correct implementation of the [`PageAllocator`] trait.
     ///
@@ -821,6 +1072,15 @@ pub mod tests {
 
             Ok(())
         }
+
+        fn statistics(&self) -> MemoryStatistics {
+            let free_pages = self.max_pages - *self.total_allocated.lock().unwrap();
+            MemoryStatistics {
+                total_pages: self.max_pages,
+                free_pages,
+                largest_free_block: free_pages,
+            }
+        }
     }
 
     fn setup_allocator() -> ((), MockPageAllocator) {
@@ -832,4 +1092,27 @@ pub mod tests {
     }
 
     test_page_allocator!(MockPageAllocator, setup_allocator, cleanup_allocator);
+
+    #[test]
+    fn only_image_translated_outside_physical_map() {
+        let base = KERNEL_SPACE_START + (1 << 39);
+        let image = KERNEL_SPACE_START + 0x4100_0000..KERNEL_SPACE_START + 0x4200_0000;
+        assert_eq!(
+            virtual_to_physical_in(base + 0x4000_1000, base, image.clone()),
+            Some(0x4000_1000)
+        );
+        assert_eq!(
+            virtual_to_physical_in(KERNEL_SPACE_START + 0x4100_2000, base, image.clone()),
+            Some(0x4100_2000)
+        );
+        // the rest of the boot mapping is gone
+        assert_eq!(
+            virtual_to_physical_in(KERNEL_SPACE_START + 0x4000_1000, base, image.clone()),
+            None
+        );
+        assert_eq!(
+            virtual_to_physical_in(KERNEL_SPACE_START + 0x4200_0000, base, image),
+            None
+        );
+    }
 }
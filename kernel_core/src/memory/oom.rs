@@ -0,0 +1,233 @@
+//! Handling running out of physical memory.
+//!
+//! When an allocation fails, the rest of the kernel is first asked to give back memory it can do
+//! without, by calling the reclaimers registered with an [`OomPolicy`] in the order they were
+//! registered. If they don't free enough, the policy's last resort is used, which is normally to
+//! kill a process to take its memory (see [`crate::process::oom_kill`]).
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{debug, error, warn};
+use spin::Once;
+
+use super::{
+    AllocationConstraints, Error, MemoryStatistics, PageAllocator, PageSize, PhysicalAddress,
+};
+use crate::sync::Mutex;
+
+/// A function that tries to free at least the given number of pages, returning the number of
+/// pages it actually freed.
+pub type Reclaimer = dyn Fn(usize) -> usize + Send + Sync;
+
+/// The number of times an allocation is retried after reclaiming memory before it fails.
+const MAX_RECLAIM_ATTEMPTS: usize = 3;
+
+/// Decides how memory is reclaimed when the kernel runs out.
+pub struct OomPolicy {
+    reclaimers: Mutex<Vec<(&'static str, Box<Reclaimer>)>>,
+    last_resort: Once<Box<Reclaimer>>,
+    /// Set while reclaiming, so that allocations made by reclaimers fail instead of recursing.
+    reclaiming: AtomicBool,
+}
+
+impl Default for OomPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OomPolicy {
+    /// Create a policy with no reclaimers and no last resort.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            reclaimers: Mutex::new(Vec::new()),
+            last_resort: Once::new(),
+            reclaiming: AtomicBool::new(false),
+        }
+    }
+
+    /// Register `reclaimer`, which is identified by `name` in the log.
+    pub fn register(
+        &self,
+        name: &'static str,
+        reclaimer: impl Fn(usize) -> usize + Send + Sync + 'static,
+    ) {
+        self.reclaimers.lock().push((name, Box::new(reclaimer)));
+    }
+
+    /// Set the function used when the reclaimers can't free enough memory. Only the first call has
+    /// any effect.
+    pub fn set_last_resort(&self, last_resort: impl Fn(usize) -> usize + Send + Sync + 'static) {
+        self.last_resort.call_once(|| Box::new(last_resort));
+    }
+
+    /// Try to free at least `num_pages` pages, returning the number of pages actually freed.
+    ///
+    /// Only one core reclaims at a time. If memory is already being reclaimed, this returns zero
+    /// right away, so that a reclaimer that allocates can't recurse.
+    pub fn reclaim(&self, num_pages: usize) -> usize {
+        if self.reclaiming.swap(true, Ordering::Acquire) {
+            return 0;
+        }
+        warn!("out of memory allocating {num_pages} pages, reclaiming");
+        let mut freed = 0;
+        for (name, reclaimer) in self.reclaimers.lock().iter() {
+            let count = reclaimer(num_pages - freed);
+            debug!("reclaimer {name} freed {count} pages");
+            freed += count;
+            if freed >= num_pages {
+                break;
+            }
+        }
+        if freed < num_pages {
+            if let Some(last_resort) = self.last_resort.get() {
+                error!("reclaimed only {freed} of {num_pages} pages, using last resort");
+                freed += last_resort(num_pages - freed);
+            } else {
+                error!("reclaimed only {freed} of {num_pages} pages and there is no last resort");
+            }
+        }
+        self.reclaiming.store(false, Ordering::Release);
+        freed
+    }
+}
+
+/// A page allocator that reclaims memory with an [`OomPolicy`] and tries again when it runs out.
+pub struct ReclaimingPageAllocator<'pa, PA> {
+    inner: &'pa PA,
+    policy: &'pa OomPolicy,
+}
+
+impl<'pa, PA: PageAllocator> ReclaimingPageAllocator<'pa, PA> {
+    /// Wrap `inner` so that allocations reclaim memory with `policy` when it runs out.
+    #[must_use]
+    pub fn new(inner: &'pa PA, policy: &'pa OomPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    /// The underlying allocator.
+    #[must_use]
+    pub fn inner(&self) -> &'pa PA {
+        self.inner
+    }
+
+    /// Call `allocate`, reclaiming memory and trying again while it fails with
+    /// [`Error::OutOfMemory`] and reclaiming makes progress.
+    fn retry(
+        &self,
+        num_pages: usize,
+        allocate: impl Fn() -> Result<PhysicalAddress, Error>,
+    ) -> Result<PhysicalAddress, Error> {
+        let mut result = allocate();
+        for _ in 0..MAX_RECLAIM_ATTEMPTS {
+            if !matches!(result, Err(Error::OutOfMemory)) || self.policy.reclaim(num_pages) == 0 {
+                break;
+            }
+            result = allocate();
+        }
+        result
+    }
+}
+
+impl<PA: PageAllocator> PageAllocator for ReclaimingPageAllocator<'_, PA> {
+    fn page_size(&self) -> PageSize {
+        self.inner.page_size()
+    }
+
+    fn allocate(&self, num_pages: usize) -> Result<PhysicalAddress, Error> {
+        self.retry(num_pages, || self.inner.allocate(num_pages))
+    }
+
+    fn free(&self, pages: PhysicalAddress, num_pages: usize) -> Result<(), Error> {
+        self.inner.free(pages, num_pages)
+    }
+
+    fn statistics(&self) -> MemoryStatistics {
+        self.inner.statistics()
+    }
+
+    fn allocate_constrained(
+        &self,
+        num_pages: usize,
+        constraints: &AllocationConstraints,
+    ) -> Result<PhysicalAddress, Error> {
+        self.retry(num_pages, || {
+            self.inner.allocate_constrained(num_pages, constraints)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::MockPageAllocator;
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn reclaimers_run_in_order_until_enough_is_freed() {
+        let policy = OomPolicy::new();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        for (name, freed) in [("a", 1), ("b", 2), ("c", 4)] {
+            let calls = calls.clone();
+            policy.register(name, move |wanted| {
+                calls.lock().push((name, wanted));
+                freed
+            });
+        }
+        let last_resort_calls = Arc::new(AtomicUsize::new(0));
+        let lr = last_resort_calls.clone();
+        policy.set_last_resort(move |_| {
+            lr.fetch_add(1, Ordering::Relaxed);
+            8
+        });
+
+        assert_eq!(policy.reclaim(3), 3);
+        assert_eq!(*calls.lock(), [("a", 3), ("b", 2)]);
+        assert_eq!(last_resort_calls.load(Ordering::Relaxed), 0);
+
+        calls.lock().clear();
+        assert_eq!(policy.reclaim(10), 15);
+        assert_eq!(*calls.lock(), [("a", 10), ("b", 9), ("c", 7)]);
+        assert_eq!(last_resort_calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn reclaiming_does_not_recurse() {
+        let policy = Arc::new(OomPolicy::new());
+        let inner = Arc::downgrade(&policy);
+        policy.register("recursive", move |n| {
+            inner.upgrade().map_or(0, |p| p.reclaim(n)) + 1
+        });
+        assert_eq!(policy.reclaim(1), 1);
+    }
+
+    #[test]
+    fn allocation_is_retried_after_reclaiming() {
+        let pa: &'static MockPageAllocator =
+            Box::leak(Box::new(MockPageAllocator::new(PageSize::FourKiB, 8)));
+        let policy = OomPolicy::new();
+        let held = Arc::new(Mutex::new(Vec::new()));
+        let reclaim_held = held.clone();
+        policy.register("held", move |_| {
+            let mut held = reclaim_held.lock();
+            let count = held.len();
+            for pages in held.drain(..) {
+                pa.free(PhysicalAddress::from(pages), 1).unwrap();
+            }
+            count
+        });
+
+        let rpa = ReclaimingPageAllocator::new(pa, &policy);
+        for _ in 0..6 {
+            held.lock().push(usize::from(rpa.allocate(1).unwrap()));
+        }
+        // only two pages are free until the held pages are reclaimed
+        let pages = rpa.allocate(4).unwrap();
+        assert!(held.lock().is_empty());
+        assert!(matches!(rpa.allocate(5), Err(Error::OutOfMemory)));
+
+        rpa.free(pages, 4).unwrap();
+        assert_eq!(rpa.statistics().free_pages, 8);
+    }
+}
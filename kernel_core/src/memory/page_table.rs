@@ -3,7 +3,9 @@
 use bitfield::BitRange;
 use snafu::{ensure, ResultExt as _, Snafu};
 
-use super::{PageAllocator, PageSize, PhysicalAddress, VirtualAddress};
+use super::{
+    AddressSpaceId, MemoryManagmentUnit, PageAllocator, PageSize, PhysicalAddress, VirtualAddress,
+};
 use PageSize::{FourKiB, SixteenKiB};
 
 /// Defines required cache coherence for memory shared across different cores.
@@ -41,7 +43,7 @@ impl From<u64> for Shareability {
 }
 
 /// Types of caching available for memory operations.
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub enum MemoryKind {
     /// "Normal" cached memory.
     #[default]
@@ -49,6 +51,13 @@ pub enum MemoryKind {
     /// Device memory that is uncached with strict access order (no gathering, reordering or early
     /// write acknowledgement). Ideal for memory mapped I/O.
     Device,
+    /// Normal memory that is not cached, so accesses go straight to memory but may still be
+    /// merged, reordered and speculated. Ideal for buffers shared with devices that don't snoop
+    /// the caches, like DMA descriptor rings.
+    NormalNonCacheable,
+    /// Device memory that allows writes to be gathered and reordered, but is never speculatively
+    /// read. Ideal for framebuffers and other memory mapped I/O that is written in bulk.
+    WriteCombining,
 }
 
 /// The correct value that must be written to the Memory Attribute Indirection Register (`MAIR_EL1`) for [`MemoryProperties`] to correctly encode the meaning of [`MemoryKind`].
@@ -60,8 +69,10 @@ pub enum MemoryKind {
 /// |-------|----------------|------------|-------------|
 /// |  `0`  | [`MemoryKind::Device`] |  `0b0000_0000`   | Device-nGnRE memory |
 /// |  `1`  | [`MemoryKind::Normal`] |  `0b1111_1111`   | Normal memory, write back, read/write allocate, non-transient cache for inner and outer sharing. |
+/// |  `2`  | [`MemoryKind::NormalNonCacheable`] |  `0b0100_0100`   | Normal memory, inner and outer non-cacheable. |
+/// |  `3`  | [`MemoryKind::WriteCombining`] |  `0b0000_1100`   | Device-GRE memory |
 #[allow(clippy::unusual_byte_groupings)]
-pub const MAIR_VALUE: u64 = 0x00_00_00_00__00_00_ff_00;
+pub const MAIR_VALUE: u64 = 0x00_00_00_00__0c_44_ff_00;
 
 impl MemoryKind {
     #[inline]
@@ -69,6 +80,8 @@ impl MemoryKind {
         match self {
             MemoryKind::Device => 0b000,
             MemoryKind::Normal => 0b001,
+            MemoryKind::NormalNonCacheable => 0b010,
+            MemoryKind::WriteCombining => 0b011,
         }
     }
 }
@@ -78,6 +91,8 @@ impl From<u64> for MemoryKind {
         match value {
             0b000 => MemoryKind::Device,
             0b001 => MemoryKind::Normal,
+            0b010 => MemoryKind::NormalNonCacheable,
+            0b011 => MemoryKind::WriteCombining,
             _ => panic!("unknown memory kind: 0b{value:b}"),
         }
     }
@@ -85,6 +100,7 @@ impl From<u64> for MemoryKind {
 
 /// The properties given to a particular memory mapping.
 #[derive(Clone, Default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct MemoryProperties {
     /// Determines cachability and load/store requirements.
     pub kind: MemoryKind,
@@ -96,12 +112,16 @@ pub struct MemoryProperties {
     pub executable: bool,
     /// Required cache coherence across cores for this memory.
     pub shareability: Shareability,
+    /// Indirect branches into this memory must land on a `BTI` instruction (see
+    /// [`crate::platform::branch_protection`]). Only set this if the core supports BTI.
+    pub guarded: bool,
 }
 
 impl MemoryProperties {
     fn encode(&self) -> u64 {
         (u64::from(!self.executable) << 54)
             | (u64::from(!self.executable) << 53)
+            | (u64::from(self.guarded) << 50)
             | (self.shareability.encode() << 8)
             | (u64::from(!self.writable) << 7)
             | (u64::from(self.user_space_access) << 6)
@@ -111,6 +131,7 @@ impl MemoryProperties {
     fn decode(raw_entry: u64) -> Self {
         Self {
             executable: ((raw_entry >> 54) & 0x1) == 0,
+            guarded: ((raw_entry >> 50) & 0x1) == 1,
             shareability: Shareability::from((raw_entry >> 8) & 0b11),
             writable: ((raw_entry >> 7) & 0x1) == 0,
             user_space_access: ((raw_entry >> 6) & 0x1) == 1,
@@ -123,11 +144,12 @@ impl core::fmt::Debug for MemoryProperties {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "MemProps<{:?} {:?} {}{} {}>",
+            "MemProps<{:?} {:?} {}{}{} {}>",
             self.shareability,
             self.kind,
             if self.writable { "RW" } else { "R" },
             if self.executable { "X" } else { "" },
+            if self.guarded { "G" } else { "" },
             if self.user_space_access { "*" } else { "K" }
         )
     }
@@ -213,6 +235,59 @@ pub enum Error {
     },
 }
 
+/// A region of virtual addresses whose translations were changed in a set of page tables.
+///
+/// Any stale entries for the region must be flushed from the TLB before the change is guaranteed to be
+/// observed, in particular before physical memory that was unmapped is reused.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[must_use]
+pub struct TlbFlush {
+    /// Start of the affected region.
+    pub virtual_start: VirtualAddress,
+    /// Length of the affected region in bytes.
+    pub length: usize,
+    /// True if the region is in the kernel (high tag) tables, whose entries are not tagged with an ASID.
+    pub global: bool,
+}
+
+impl TlbFlush {
+    /// Invalidate the TLB entries for the affected region using `mmu`.
+    ///
+    /// The `asid` is the identifier of the address space the changed page tables belong to. It is
+    /// ignored for global mappings.
+    pub fn apply(&self, mmu: &impl MemoryManagmentUnit, asid: AddressSpaceId) {
+        mmu.invalidate_va_range(
+            (!self.global).then_some(asid),
+            self.virtual_start,
+            self.length,
+        );
+    }
+}
+
+/// The bits of a block or page entry (the memory kind and shareability) that can't be changed in a
+/// live mapping without break-before-make.
+const ATTRIBUTES_REQUIRING_BREAK: u64 = 0b111 << 2 | 0b11 << 8;
+
+/// Replace the live entry at `entry_ptr` with `new` using break-before-make: the entry is made
+/// invalid, the region it maps is flushed from the TLB, and only then is the new entry written.
+fn break_before_make(
+    mmu: &impl MemoryManagmentUnit,
+    entry_ptr: *mut Entry,
+    new: Entry,
+    flush: TlbFlush,
+    asid: AddressSpaceId,
+) {
+    unsafe {
+        entry_ptr.write(Entry::empty());
+    }
+    mmu.synchronize_tables();
+    flush.apply(mmu, asid);
+    unsafe {
+        entry_ptr.write(new);
+    }
+    mmu.synchronize_tables();
+}
+
 #[derive(Eq, PartialEq, Debug, Default, Clone, Copy)]
 #[repr(transparent)]
 struct Entry(u64);
@@ -251,8 +326,28 @@ impl Entry {
         Self(0b11 | (address as u64) | properties.encode() | (1 << 10/*access flag*/))
     }
 
+    /// Construct a copy of this block or page entry that is only used by the TLB for the address
+    /// space it was looked up in, instead of for every address space.
+    fn not_global(self) -> Self {
+        Self(self.0 | (1 << 11/*nG*/))
+    }
+
+    /// Construct a copy of this block or page entry with its memory properties replaced.
+    fn with_properties(self, properties: &MemoryProperties) -> Self {
+        Self(
+            (self.0 & (0x0000_ffff_ffff_f000 | (1 << 11) | 0b11))
+                | properties.encode()
+                | (1 << 10/*access flag*/),
+        )
+    }
+
+    /// The physical address of the block, page or table this entry points to.
+    fn address(self) -> PhysicalAddress {
+        PhysicalAddress::from((self.0 & 0x0000_ffff_ffff_f000) as usize)
+    }
+
     fn decode(self, occuring_at_level: u8) -> DecodedEntry {
-        let address = PhysicalAddress::from((self.0 & 0x0000_ffff_ffff_f000) as usize);
+        let address = self.address();
         match (occuring_at_level, self.0 & 0b11) {
             (_, 0b00) => DecodedEntry::Empty,
             (3, 0b11) => DecodedEntry::Page(address),
@@ -427,6 +522,9 @@ pub struct PageTables<'pa, PA: PageAllocator> {
     page_size: PageSize,
     /// true => pointers must have `0xffff` tag, false => must have `0x0000` tag.
     high_tag: bool,
+    /// true => new mappings are shared by every address space in the TLB, false => they are tagged
+    /// with the ASID they were looked up with.
+    global: bool,
 }
 
 // SAFETY: this is safe because each `PageTables` owns the memory it points to exclusively.
@@ -444,6 +542,7 @@ impl<'pa, PA: PageAllocator> PageTables<'pa, PA> {
 
     /// Convert existing page tables in memory into a [`PageTables`] instance.
     /// If `high_tag` is true, these tables will be for mapping addresses starting with `0xffff`, i.e. the TTBR1 table.
+    /// Mappings in TTBR1 tables are global, and mappings in TTBR0 tables are tagged with an ASID.
     ///
     /// # Safety
     /// - The `root_table_address` must point to a valid root page table in memory.
@@ -466,9 +565,17 @@ impl<'pa, PA: PageAllocator> PageTables<'pa, PA> {
             entries_per_page: usize::from(page_allocator.page_size()) / size_of::<Entry>(),
             root,
             high_tag,
+            global: high_tag,
         }
     }
 
+    /// Make the mappings added from now on tagged with an ASID in the TLB, even though these are
+    /// kernel tables, so that they can be hidden from some address spaces (see
+    /// [`super::kpti`]). Mappings that already exist are not changed.
+    pub fn make_not_global(&mut self) {
+        self.global = false;
+    }
+
     /// Get the physical address of the root table.
     #[must_use]
     pub fn physical_address(&self) -> PhysicalAddress {
@@ -570,10 +677,13 @@ impl<'pa, PA: PageAllocator> PageTables<'pa, PA> {
             size,
             true,
             |entry_ptr, addr| {
-                let entry = match size {
+                let mut entry = match size {
                     MapBlockSize::Page => Entry::for_page(addr, properties),
                     _ => Entry::for_block(addr, properties),
                 };
+                if !self.global {
+                    entry = entry.not_global();
+                }
                 unsafe {
                     entry_ptr.write(entry);
                 }
@@ -584,17 +694,39 @@ impl<'pa, PA: PageAllocator> PageTables<'pa, PA> {
 
     /// Unmap a region of virtual addresses to a region of physical addresses in these page tables.
     ///
+    /// Returns the region that must be flushed from the TLB if these tables are live.
+    ///
     /// # Errors
     /// - [`Error::InvalidTag`] if the virtual pointer has the wrong tag for this table.
     ///
-    /// If one of these errors occurs, the region may be partially unmapped in the table:
+    /// If one of these errors occurs, the region may be partially unmapped in the table, so the
+    /// entire address space should be flushed from the TLB:
     /// - [`Error::NotMapped`] if the region contains unmapped pages.
     pub fn unmap(
         &mut self,
         virtual_start: VirtualAddress,
         count: usize,
         size: MapBlockSize,
-    ) -> Result<(), Error> {
+    ) -> Result<TlbFlush, Error> {
+        self.unmap_each(virtual_start, count, size, |_| {})
+    }
+
+    /// Unmap a region of virtual addresses like [`Self::unmap`], calling `f` with the physical
+    /// address of each block that was mapped in the region.
+    ///
+    /// This is used to release references to the pages in a
+    /// [`PageFrameDatabase`](super::PageFrameDatabase). Pages whose last reference is released
+    /// must not be freed until the returned region has been flushed from the TLB.
+    ///
+    /// # Errors
+    /// The same as [`Self::unmap`].
+    pub fn unmap_each(
+        &mut self,
+        virtual_start: VirtualAddress,
+        count: usize,
+        size: MapBlockSize,
+        mut f: impl FnMut(PhysicalAddress),
+    ) -> Result<TlbFlush, Error> {
         ensure!(
             virtual_start.is_in_kernel_space() == self.high_tag,
             InvalidTagSnafu {
@@ -608,12 +740,179 @@ impl<'pa, PA: PageAllocator> PageTables<'pa, PA> {
             size,
             false,
             |entry_ptr, _| {
+                let old = unsafe { entry_ptr.read() };
+                if old != Entry::empty() {
+                    f(old.address());
+                }
                 unsafe {
                     entry_ptr.write(Entry::empty());
                 }
                 Ok(())
             },
-        )
+        )?;
+        Ok(TlbFlush {
+            virtual_start,
+            length: count * size.length_in_bytes(self.page_size).unwrap_or_default(),
+            global: self.high_tag,
+        })
+    }
+
+    /// Change the properties of an already mapped region of virtual addresses in place, without
+    /// changing the physical addresses they are mapped to.
+    ///
+    /// The region must have been mapped with the same block `size`.
+    /// Returns the smallest region containing every entry that was changed, which must be flushed
+    /// from the TLB if these tables are live, or `None` if no entries changed.
+    ///
+    /// Changing the memory kind or shareability of a live mapping this way is not allowed by the
+    /// architecture, use [`Self::protect_live`] instead.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidTag`] if the virtual pointer has the wrong tag for this table.
+    ///
+    /// If one of these errors occurs, the region may be partially updated in the table, so the
+    /// entire address space should be flushed from the TLB:
+    /// - [`Error::NotMapped`] if the region contains unmapped pages.
+    /// - [`Error::AlreadyMapped`] if the region was mapped with a different block size.
+    pub fn protect(
+        &mut self,
+        virtual_start: VirtualAddress,
+        count: usize,
+        size: MapBlockSize,
+        new_properties: &MemoryProperties,
+    ) -> Result<Option<TlbFlush>, Error> {
+        let mut changed = None;
+        self.update_properties(
+            virtual_start,
+            count,
+            size,
+            new_properties,
+            &mut changed,
+            |entry_ptr, _, new, _| {
+                unsafe {
+                    entry_ptr.write(new);
+                }
+                true
+            },
+        )?;
+        Ok(changed)
+    }
+
+    /// Change the properties of an already mapped region like [`Self::protect`], in tables that
+    /// may be live in the MMU, invalidating the TLB through `mmu` as the architecture requires.
+    ///
+    /// Entries whose permissions change are updated in place and flushed from the TLB together at
+    /// the end. Entries whose memory kind or shareability change must not be replaced while they
+    /// may be cached, so each of them is made invalid (break), flushed from the TLB, and only then
+    /// written with the new properties (make). The `asid` is the identifier of the address space
+    /// these tables belong to, and is ignored for kernel tables.
+    ///
+    /// # Errors
+    /// The same as [`Self::protect`]. Entries that were changed before the error are still flushed
+    /// from the TLB.
+    pub fn protect_live(
+        &mut self,
+        mmu: &impl MemoryManagmentUnit,
+        asid: AddressSpaceId,
+        virtual_start: VirtualAddress,
+        count: usize,
+        size: MapBlockSize,
+        new_properties: &MemoryProperties,
+    ) -> Result<(), Error> {
+        let global = self.high_tag;
+        let block_size_in_bytes = size.length_in_bytes(self.page_size).unwrap_or_default();
+        let mut changed = None;
+        let result = self.update_properties(
+            virtual_start,
+            count,
+            size,
+            new_properties,
+            &mut changed,
+            |entry_ptr, old, new, address| {
+                if old.0 & ATTRIBUTES_REQUIRING_BREAK == new.0 & ATTRIBUTES_REQUIRING_BREAK {
+                    unsafe {
+                        entry_ptr.write(new);
+                    }
+                    return true;
+                }
+                break_before_make(
+                    mmu,
+                    entry_ptr,
+                    new,
+                    TlbFlush {
+                        virtual_start: address,
+                        length: block_size_in_bytes,
+                        global,
+                    },
+                    asid,
+                );
+                false
+            },
+        );
+        if let Some(flush) = changed {
+            mmu.synchronize_tables();
+            flush.apply(mmu, asid);
+        }
+        result
+    }
+
+    /// Call `write` with each entry in the region whose properties would change, along with its old
+    /// and new value and its virtual address, recording the region to flush in `changed` for each
+    /// entry that `write` returns true for.
+    fn update_properties(
+        &self,
+        virtual_start: VirtualAddress,
+        count: usize,
+        size: MapBlockSize,
+        new_properties: &MemoryProperties,
+        changed: &mut Option<TlbFlush>,
+        mut write: impl FnMut(*mut Entry, Entry, Entry, VirtualAddress) -> bool,
+    ) -> Result<(), Error> {
+        ensure!(
+            virtual_start.is_in_kernel_space() == self.high_tag,
+            InvalidTagSnafu {
+                value: virtual_start
+            }
+        );
+        // the walker is given a physical start of zero, so the address passed to the callback is
+        // the offset of the entry from the start of the region
+        let mut range: Option<(usize, usize)> = None;
+        let result = self.for_each_entry_of_size(
+            virtual_start,
+            0.into(),
+            count,
+            size,
+            false,
+            |entry_ptr, offset| {
+                let offset = usize::from(offset);
+                let address = virtual_start.byte_add(offset);
+                let old = unsafe { entry_ptr.read() };
+                ensure!(old != Entry::empty(), NotMappedSnafu { address });
+                // page and table entries have the same type bits, so make sure this isn't a table
+                // of smaller blocks before treating it as a block
+                let expected_type = if size == MapBlockSize::Page {
+                    0b11
+                } else {
+                    0b01
+                };
+                ensure!(
+                    old.0 & 0b11 == expected_type,
+                    AlreadyMappedSnafu { address }
+                );
+                let new = old.with_properties(new_properties);
+                if new != old && write(entry_ptr, old, new, address) {
+                    range = Some(range.map_or((offset, offset), |(first, _)| (first, offset)));
+                }
+                Ok(())
+            },
+        );
+        let block_size_in_bytes = size.length_in_bytes(self.page_size).unwrap_or_default();
+        *changed = range.map(|(first, last)| TlbFlush {
+            virtual_start: virtual_start.byte_add(first),
+            length: last - first + block_size_in_bytes,
+            global: self.high_tag,
+        });
+        result
     }
 
     /// Compute the physical address that these page tables map the virtual address `p` to.
@@ -838,8 +1137,17 @@ mod tests {
             )
             .expect("map range");
             check_mapping(&pt, 0.into(), start_address, count, block_size, true);
-            pt.unmap(start_address, count, block_size)
+            let flush = pt
+                .unmap(start_address, count, block_size)
                 .expect("unmap range");
+            assert_eq!(
+                flush,
+                TlbFlush {
+                    virtual_start: start_address,
+                    length: count * block_size.length_in_bytes(page_size).unwrap(),
+                    global: false
+                }
+            );
             check_mapping(&pt, 0.into(), start_address, count, block_size, false);
             drop(pt);
         }
@@ -928,8 +1236,11 @@ mod tests {
                 &MemoryProperties::default(),
             )
             .expect("map range");
-            pt.unmap((0xeeee_0000_0000 + block_len).into(), 1, block_size)
+            let flush = pt
+                .unmap((0xeeee_0000_0000 + block_len).into(), 1, block_size)
                 .expect("unmap middle");
+            assert_eq!(flush.virtual_start, (0xeeee_0000_0000 + block_len).into());
+            assert_eq!(flush.length, block_len);
             check_mapping(
                 &pt,
                 0xaaaa_0000_0000.into(),
@@ -958,7 +1269,389 @@ mod tests {
         }
         pa.end_check();
     }
+
+    fn properties_at<PA: PageAllocator>(
+        pt: &PageTables<'_, PA>,
+        address: VirtualAddress,
+        size: MapBlockSize,
+    ) -> MemoryProperties {
+        let mut props = None;
+        pt.for_each_entry_of_size(address, 0.into(), 1, size, false, |entry_ptr, _| {
+            props = Some(MemoryProperties::decode(unsafe { entry_ptr.read() }.0));
+            Ok(())
+        })
+        .expect("entry exists");
+        props.unwrap()
+    }
+
+    #[test_matrix(FourKiB, [Page, SmallBlock, LargeBlock])]
+    #[test_matrix(SixteenKiB, [Page, SmallBlock])]
+    fn protect_changes_properties_in_place(page_size: PageSize, block_size: MapBlockSize) {
+        let pa = MockPageAllocator::new(page_size, 128);
+        {
+            let mut pt = PageTables::empty(&pa).unwrap();
+            let block_len = block_size.length_in_bytes(page_size).unwrap();
+            let rw = MemoryProperties {
+                writable: true,
+                ..MemoryProperties::default()
+            };
+            let rx = MemoryProperties {
+                executable: true,
+                ..MemoryProperties::default()
+            };
+            pt.map(
+                0xeeee_0000_0000.into(),
+                0xaaaa_0000_0000.into(),
+                4,
+                block_size,
+                &rw,
+            )
+            .expect("map range");
+
+            // changing the middle two blocks reports exactly those blocks
+            let flush = pt
+                .protect((0xeeee_0000_0000 + block_len).into(), 2, block_size, &rx)
+                .expect("protect")
+                .expect("entries changed");
+            assert_eq!(
+                flush,
+                TlbFlush {
+                    virtual_start: (0xeeee_0000_0000 + block_len).into(),
+                    length: 2 * block_len,
+                    global: false
+                }
+            );
+            check_mapping(
+                &pt,
+                0xaaaa_0000_0000.into(),
+                0xeeee_0000_0000.into(),
+                4,
+                block_size,
+                true,
+            );
+            let p = properties_at(&pt, 0xeeee_0000_0000.into(), block_size);
+            assert!(p.writable && !p.executable);
+            let p = properties_at(&pt, (0xeeee_0000_0000 + block_len).into(), block_size);
+            assert!(!p.writable && p.executable);
+
+            // protecting the whole range only changes the outer blocks, but the reported region covers them both
+            let flush = pt
+                .protect(0xeeee_0000_0000.into(), 4, block_size, &rx)
+                .expect("protect")
+                .expect("entries changed");
+            assert_eq!(flush.virtual_start, 0xeeee_0000_0000.into());
+            assert_eq!(flush.length, 4 * block_len);
+
+            // nothing changes the second time
+            assert!(pt
+                .protect(0xeeee_0000_0000.into(), 4, block_size, &rx)
+                .expect("protect")
+                .is_none());
+            drop(pt);
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn protect_unmapped_or_wrong_size() {
+        let pa = MockPageAllocator::new(FourKiB, 128);
+        {
+            let mut pt = PageTables::empty(&pa).unwrap();
+            pt.map(
+                0xeeee_0000_0000.into(),
+                0xaaaa_0000_0000.into(),
+                2,
+                Page,
+                &MemoryProperties::default(),
+            )
+            .expect("map range");
+            assert!(matches!(
+                pt.protect(0xeeee_0000_0000.into(), 3, Page, &MemoryProperties::default()),
+                Err(Error::NotMapped { address }) if address == VirtualAddress::from(0xeeee_0000_2000)
+            ));
+            assert!(matches!(
+                pt.protect(
+                    0xeeee_0000_0000.into(),
+                    1,
+                    SmallBlock,
+                    &MemoryProperties::default()
+                ),
+                Err(Error::AlreadyMapped { .. })
+            ));
+            drop(pt);
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn unmap_reports_physical_pages() {
+        let pa = MockPageAllocator::new(FourKiB, 128);
+        {
+            let mut pt = PageTables::empty(&pa).unwrap();
+            pt.map(
+                0xeeee_0000_0000.into(),
+                0xaaaa_0000_0000.into(),
+                3,
+                Page,
+                &MemoryProperties::default(),
+            )
+            .expect("map range");
+            let mut unmapped = std::vec::Vec::new();
+            let flush = pt
+                .unmap_each(0xeeee_0000_1000.into(), 2, Page, |p| unmapped.push(p))
+                .expect("unmap range");
+            assert_eq!(flush.length, 0x2000);
+            assert_eq!(
+                unmapped,
+                [
+                    PhysicalAddress::from(0xaaaa_0000_1000),
+                    PhysicalAddress::from(0xaaaa_0000_2000)
+                ]
+            );
+            drop(pt);
+        }
+        pa.end_check();
+    }
+
+    /// An operation requested from a [`RecordingMmu`].
+    #[derive(Debug, PartialEq, Eq)]
+    enum MmuOp {
+        Synchronize,
+        Invalidate(Option<AddressSpaceId>, VirtualAddress, usize),
+    }
+
+    #[derive(Default)]
+    struct RecordingMmu {
+        ops: std::cell::RefCell<std::vec::Vec<MmuOp>>,
+    }
+
+    impl RecordingMmu {
+        fn take(&self) -> std::vec::Vec<MmuOp> {
+            self.ops.take()
+        }
+    }
+
+    impl MemoryManagmentUnit for RecordingMmu {
+        unsafe fn activate_page_tables<PA: PageAllocator>(
+            &self,
+            _tables: &PageTables<'_, PA>,
+            _asid: AddressSpaceId,
+        ) {
+        }
+
+        fn synchronize_tables(&self) {
+            self.ops.borrow_mut().push(MmuOp::Synchronize);
+        }
+
+        fn invalidate_asid(&self, _asid: AddressSpaceId) {}
+
+        fn invalidate_va_range(
+            &self,
+            asid: Option<AddressSpaceId>,
+            start: VirtualAddress,
+            length: usize,
+        ) {
+            self.ops
+                .borrow_mut()
+                .push(MmuOp::Invalidate(asid, start, length));
+        }
+
+        fn invalidate_all(&self) {}
+
+        fn synchronize_instruction_cache(&self, _start: PhysicalAddress, _length: usize) {}
+    }
+
+    #[test]
+    fn flush_uses_asid_only_for_user_tables() {
+        let mmu = RecordingMmu::default();
+        let user = TlbFlush {
+            virtual_start: 0x1000.into(),
+            length: 0x2000,
+            global: false,
+        };
+        let kernel = TlbFlush {
+            virtual_start: 0xffff_0000_0000_1000.into(),
+            length: 0x1000,
+            global: true,
+        };
+        user.apply(&mmu, 7);
+        kernel.apply(&mmu, 7);
+        assert_eq!(
+            mmu.take(),
+            [
+                MmuOp::Invalidate(Some(7), user.virtual_start, 0x2000),
+                MmuOp::Invalidate(None, kernel.virtual_start, 0x1000)
+            ]
+        );
+    }
+
+    #[test]
+    fn protect_live_breaks_before_changing_kind() {
+        use MmuOp::{Invalidate, Synchronize};
+        let pa = MockPageAllocator::new(FourKiB, 128);
+        {
+            let mmu = RecordingMmu::default();
+            let mut pt = PageTables::empty(&pa).unwrap();
+            let va = VirtualAddress::from(0xeeee_0000_0000);
+            pt.map(
+                va,
+                0xaaaa_0000_0000.into(),
+                4,
+                Page,
+                &MemoryProperties::default(),
+            )
+            .expect("map range");
+
+            // permission changes are made in place and flushed together
+            let rw = MemoryProperties {
+                writable: true,
+                ..MemoryProperties::default()
+            };
+            pt.protect_live(&mmu, 3, va, 4, Page, &rw).unwrap();
+            assert_eq!(mmu.take(), [Synchronize, Invalidate(Some(3), va, 0x4000)]);
+            assert!(properties_at(&pt, va, Page).writable);
+
+            // changing the memory kind replaces each entry with break-before-make
+            let uncached = MemoryProperties {
+                kind: MemoryKind::NormalNonCacheable,
+                writable: true,
+                ..MemoryProperties::default()
+            };
+            pt.protect_live(&mmu, 3, va.byte_add(0x1000), 2, Page, &uncached)
+                .unwrap();
+            assert_eq!(
+                mmu.take(),
+                [
+                    Synchronize,
+                    Invalidate(Some(3), va.byte_add(0x1000), 0x1000),
+                    Synchronize,
+                    Synchronize,
+                    Invalidate(Some(3), va.byte_add(0x2000), 0x1000),
+                    Synchronize,
+                ]
+            );
+            check_mapping(&pt, 0xaaaa_0000_0000.into(), va, 4, Page, true);
+            assert_eq!(
+                properties_at(&pt, va.byte_add(0x2000), Page).kind,
+                MemoryKind::NormalNonCacheable
+            );
+            assert_eq!(properties_at(&pt, va, Page).kind, MemoryKind::Normal);
+
+            // changed entries are still flushed if an error occurs part way through
+            assert!(matches!(
+                pt.protect_live(&mmu, 3, va.byte_add(0x3000), 2, Page, &uncached),
+                Err(Error::NotMapped { .. })
+            ));
+            assert_eq!(
+                mmu.take(),
+                [
+                    Synchronize,
+                    Invalidate(Some(3), va.byte_add(0x3000), 0x1000),
+                    Synchronize,
+                ]
+            );
+            drop(pt);
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn only_kernel_mappings_are_global() {
+        let pa = MockPageAllocator::new(FourKiB, 128);
+        {
+            let is_global = |pt: &PageTables<'_, _>, address: usize| {
+                let mut raw = 0;
+                pt.for_each_entry_of_size(address.into(), 0.into(), 1, Page, false, |entry, _| {
+                    raw = unsafe { entry.read() }.0;
+                    Ok(())
+                })
+                .expect("entry exists");
+                raw & (1 << 11) == 0
+            };
+            let props = MemoryProperties::default();
+
+            let mut user = PageTables::empty(&pa).unwrap();
+            user.map(0x1000.into(), 0xa000.into(), 1, Page, &props)
+                .unwrap();
+            assert!(!is_global(&user, 0x1000));
+
+            let root = pa.allocate_zeroed(1).unwrap();
+            let mut kernel = unsafe { PageTables::from_existing(&pa, root, true) };
+            let base = 0xffff_0000_0000_0000;
+            kernel
+                .map(base.into(), 0xa000.into(), 1, Page, &props)
+                .unwrap();
+            assert!(is_global(&kernel, base));
+            kernel.make_not_global();
+            kernel
+                .map((base + 0x1000).into(), 0xb000.into(), 1, Page, &props)
+                .unwrap();
+            assert!(is_global(&kernel, base));
+            assert!(!is_global(&kernel, base + 0x1000));
+
+            // changing the properties keeps the mapping in its address space
+            kernel
+                .protect(
+                    (base + 0x1000).into(),
+                    1,
+                    Page,
+                    &MemoryProperties {
+                        writable: true,
+                        ..MemoryProperties::default()
+                    },
+                )
+                .unwrap();
+            assert!(!is_global(&kernel, base + 0x1000));
+            drop(user);
+            drop(kernel);
+        }
+        pa.end_check();
+    }
+
     //TODO: if you map a block and then try to unmap a page in the block or try to remap a page in
     //the block, what should happen? implementing this the obvious way is complex, but returning an
     //error seems leaky.
+
+    #[test]
+    fn memory_kinds_round_trip() {
+        for kind in [
+            MemoryKind::Normal,
+            MemoryKind::Device,
+            MemoryKind::NormalNonCacheable,
+            MemoryKind::WriteCombining,
+        ] {
+            let properties = MemoryProperties {
+                kind: kind.clone(),
+                writable: true,
+                ..MemoryProperties::default()
+            };
+            let decoded = MemoryProperties::decode(properties.encode());
+            assert_eq!(decoded.kind, kind);
+            // each kind selects its own attribute in the MAIR
+            let attribute = (MAIR_VALUE >> (8 * kind.encode())) & 0xff;
+            assert_eq!(
+                attribute,
+                match kind {
+                    MemoryKind::Normal => 0xff,
+                    MemoryKind::Device => 0x00,
+                    MemoryKind::NormalNonCacheable => 0x44,
+                    MemoryKind::WriteCombining => 0x0c,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn guarded_round_trip() {
+        let properties = MemoryProperties {
+            executable: true,
+            guarded: true,
+            ..MemoryProperties::default()
+        };
+        let encoded = properties.encode();
+        assert_eq!(encoded & (1 << 50), 1 << 50);
+        let decoded = MemoryProperties::decode(encoded);
+        assert!(decoded.guarded && decoded.executable);
+        assert!(!MemoryProperties::decode(MemoryProperties::default().encode()).guarded);
+    }
 }
@@ -0,0 +1,98 @@
+//! Randomization of where physical memory is mapped in the kernel's address space.
+//!
+//! At boot the kernel picks a random location in its half of the address space to map physical
+//! memory at, so that kernel pointers to physical memory can't be predicted from physical
+//! addresses. The location is a whole entry (a "slot") of the root page table, so that the boot
+//! mapping can be moved there by copying a single page table entry.
+//!
+//! Slot 0 holds the mapping made by the boot assembly, which contains the kernel image at its link
+//! address. Once the image is protected, the rest of that mapping is removed, so that physical
+//! memory is only reachable through the chosen slot.
+//!
+//! This is only part of kernel address space layout randomization (KASLR): the kernel image stays
+//! at its link address. Randomizing it is separate work, since it needs a position independent
+//! kernel that relocates itself at boot.
+use byteorder::{BigEndian, ByteOrder as _};
+
+use super::KERNEL_SPACE_START;
+use crate::platform::device_tree::DeviceTree;
+
+/// Number of bytes of virtual addresses covered by one entry of the root page table.
+pub const SLOT_SIZE: usize = 1 << 39;
+
+/// The first slot that can be chosen. Lower slots are used by the boot mapping.
+pub const FIRST_SLOT: usize = 1;
+
+/// The number of slots that can be chosen. The upper half of the kernel address space is left for
+/// device mappings and kernel stacks.
+pub const SLOT_COUNT: usize = 255;
+
+/// Read the seed provided by the bootloader in the `/chosen/kaslr-seed` property, if present.
+#[must_use]
+pub fn seed_from_device_tree(dt: &DeviceTree) -> Option<u64> {
+    let bytes = dt.find_property(b"/chosen/kaslr-seed")?.into_bytes()?;
+    match bytes.len() {
+        4 => Some(u64::from(BigEndian::read_u32(bytes))),
+        8 => Some(BigEndian::read_u64(bytes)),
+        _ => None,
+    }
+}
+
+/// Spread the entropy in `seed` across all of its bits (the `SplitMix64` finalizer).
+///
+/// Seeds from a counter only have entropy in their low bits, so they must be mixed before use.
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Choose the slot to map physical memory into using `seed`.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn choose_slot(seed: u64) -> usize {
+    FIRST_SLOT + (mix(seed) % SLOT_COUNT as u64) as usize
+}
+
+/// The kernel virtual address at the start of `slot`.
+///
+/// # Panics
+/// If `slot` is past the last slot that can be chosen.
+#[must_use]
+pub fn slot_base(slot: usize) -> usize {
+    assert!(slot < FIRST_SLOT + SLOT_COUNT);
+    KERNEL_SPACE_START + slot * SLOT_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::device_tree::chosen::ChosenBuilder;
+
+    #[test]
+    fn seed_from_chosen() {
+        let dt = DeviceTree::from_bytes(include_bytes!("../platform/device_tree/test-tree.fdt"));
+        assert_eq!(seed_from_device_tree(&dt), Some(0x67aa_6cf0_f3eb_6cd4));
+
+        let blob = ChosenBuilder::new(&dt)
+            .with_u64(b"kaslr-seed", 0x0123_4567_89ab_cdef)
+            .build();
+        let seeded = DeviceTree::from_bytes(&blob);
+        assert_eq!(seed_from_device_tree(&seeded), Some(0x0123_4567_89ab_cdef));
+    }
+
+    #[test]
+    fn slots_stay_in_range() {
+        let mut seen = [false; FIRST_SLOT + SLOT_COUNT];
+        for seed in 0..4096 {
+            let slot = choose_slot(seed);
+            assert!((FIRST_SLOT..FIRST_SLOT + SLOT_COUNT).contains(&slot));
+            seen[slot] = true;
+        }
+        // consecutive counter values should still spread over most of the slots
+        assert!(seen.iter().filter(|s| **s).count() > SLOT_COUNT * 9 / 10);
+        assert_eq!(slot_base(FIRST_SLOT), 0xffff_0080_0000_0000);
+        assert!(slot_base(FIRST_SLOT + SLOT_COUNT - 1) + SLOT_SIZE <= 0xffff_8000_0000_0000);
+    }
+}
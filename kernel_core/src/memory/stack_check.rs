@@ -0,0 +1,204 @@
+//! Canaries and usage watermarks for kernel stacks.
+//!
+//! Guard pages catch a stack that overflows with a push or store that lands in them, but a large
+//! frame can jump over the guard entirely. To catch those too, the lowest words of each kernel
+//! stack hold a canary value that is checked whenever the kernel switches threads. The value is
+//! chosen at random during boot (see [`set_canary`]), so that it can't be forged by an attacker
+//! who can write to the stack.
+//!
+//! Optionally, the rest of the stack can be filled with a watermark value, so that the deepest
+//! point the stack has ever reached can be found later by looking for the first overwritten word.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::VirtualAddress;
+
+/// Value written to the lowest words of a kernel stack, until [`set_canary`] is called.
+pub const CANARY: u64 = 0x57ac_ca9a_c0de_d00d;
+
+/// The canary written to newly prepared stacks.
+static CURRENT_CANARY: AtomicU64 = AtomicU64::new(CANARY);
+
+/// Use `value` as the canary for stacks that are prepared from now on.
+///
+/// Stacks that have already been prepared keep their canary.
+pub fn set_canary(value: u64) {
+    CURRENT_CANARY.store(value, Ordering::Relaxed);
+}
+
+/// Number of words at the bottom of a kernel stack that hold the canary.
+pub const CANARY_WORDS: usize = 2;
+
+/// Value written to the unused part of a watermarked stack.
+pub const WATERMARK: u64 = 0x5757_5757_5757_5757;
+
+/// A kernel stack with a canary at its lowest address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckedStack {
+    /// The lowest address of the stack.
+    pub bottom: VirtualAddress,
+    /// The initial stack pointer, just past the highest address of the stack.
+    pub top: VirtualAddress,
+    /// True if the stack was filled with [`WATERMARK`] when it was prepared.
+    watermarked: bool,
+    /// The canary written to the bottom of the stack.
+    canary: u64,
+}
+
+/// Write `canary` to the start of `words`, and fill the rest of them with the watermark if
+/// `watermark` is true.
+fn prepare_words(words: &mut [u64], canary: u64, watermark: bool) {
+    let (canary_words, rest) = words.split_at_mut(CANARY_WORDS);
+    canary_words.fill(canary);
+    if watermark {
+        rest.fill(WATERMARK);
+    }
+}
+
+/// The number of bytes used at the top of `words`, given that unused words still contain the
+/// watermark.
+fn used_bytes(words: &[u64]) -> usize {
+    let untouched = words[CANARY_WORDS..]
+        .iter()
+        .take_while(|w| **w == WATERMARK)
+        .count();
+    (words.len() - CANARY_WORDS - untouched) * size_of::<u64>()
+}
+
+impl CheckedStack {
+    /// Write the current canary to the bottom of the stack between `bottom` and `top`.
+    ///
+    /// If `watermark_until` is given, the stack from just above the canary up to that address is
+    /// also filled with [`WATERMARK`]. This must be below anything on the stack that is in use.
+    ///
+    /// # Safety
+    /// The stack must be mapped and writable, and the region being written must not be in use.
+    ///
+    /// # Panics
+    /// If the stack is too small to hold the canary, or `bottom` is not aligned to a word.
+    #[must_use]
+    pub unsafe fn prepare(
+        bottom: VirtualAddress,
+        top: VirtualAddress,
+        watermark_until: Option<VirtualAddress>,
+    ) -> Self {
+        let end = watermark_until.unwrap_or(bottom.byte_add(CANARY_WORDS * size_of::<u64>()));
+        let stack = Self {
+            bottom,
+            top,
+            watermarked: watermark_until.is_some(),
+            canary: CURRENT_CANARY.load(Ordering::Relaxed),
+        };
+        assert!(bottom.is_aligned_to(size_of::<u64>()));
+        assert!(usize::from(end) <= usize::from(top));
+        let length = stack.words_until(end);
+        prepare_words(
+            core::slice::from_raw_parts_mut(usize::from(bottom) as *mut u64, length),
+            stack.canary,
+            stack.watermarked,
+        );
+        stack
+    }
+
+    /// The number of words of the stack from the bottom up to `end`.
+    fn words_until(&self, end: VirtualAddress) -> usize {
+        let length = (usize::from(end) - usize::from(self.bottom)) / size_of::<u64>();
+        assert!(length >= CANARY_WORDS, "stack too small for canary");
+        length
+    }
+
+    /// Returns true if the canary at the bottom of the stack has not been overwritten.
+    ///
+    /// # Safety
+    /// The stack must still be mapped.
+    #[must_use]
+    pub unsafe fn canary_intact(&self) -> bool {
+        let canary = usize::from(self.bottom) as *const u64;
+        (0..CANARY_WORDS).all(|i| canary.add(i).read_volatile() == self.canary)
+    }
+
+    /// Check the canary at the bottom of the stack.
+    ///
+    /// # Safety
+    /// The stack must still be mapped.
+    ///
+    /// # Panics
+    /// If the canary has been overwritten, which means that the stack has overflowed.
+    pub unsafe fn check_canary(&self) {
+        assert!(
+            self.canary_intact(),
+            "kernel stack canary corrupted! stack {:?}..{:?} has overflowed",
+            self.bottom,
+            self.top
+        );
+    }
+
+    /// The largest number of bytes of the stack that have ever been used, or `None` if the stack
+    /// was not watermarked.
+    ///
+    /// # Safety
+    /// The stack must still be mapped.
+    #[must_use]
+    pub unsafe fn max_usage(&self) -> Option<usize> {
+        self.watermarked.then(|| {
+            used_bytes(core::slice::from_raw_parts(
+                usize::from(self.bottom) as *const u64,
+                self.words_until(self.top),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+
+    use super::*;
+
+    fn stack_in(words: &mut [u64]) -> (VirtualAddress, VirtualAddress) {
+        let range = words.as_mut_ptr_range();
+        (
+            VirtualAddress::from(range.start as usize),
+            VirtualAddress::from(range.end as usize),
+        )
+    }
+
+    #[test]
+    fn watermark_tracks_deepest_use() {
+        let mut memory = vec![0u64; 64];
+        let (bottom, top) = stack_in(&mut memory);
+        let stack = unsafe { CheckedStack::prepare(bottom, top, Some(top)) };
+        assert!(unsafe { stack.canary_intact() });
+        assert_eq!(unsafe { stack.max_usage() }, Some(0));
+
+        // the deepest write counts, even if the words above it were never written
+        memory[54..].fill(0);
+        assert_eq!(unsafe { stack.max_usage() }, Some(10 * 8));
+        memory[40] = 0;
+        assert_eq!(unsafe { stack.max_usage() }, Some(24 * 8));
+
+        memory[CANARY_WORDS..].fill(0);
+        assert_eq!(unsafe { stack.max_usage() }, Some((64 - CANARY_WORDS) * 8));
+        assert!(unsafe { stack.canary_intact() });
+    }
+
+    #[test]
+    fn unwatermarked_stack_only_writes_canary() {
+        let mut memory = vec![1u64; 16];
+        let (bottom, top) = stack_in(&mut memory);
+        let stack = unsafe { CheckedStack::prepare(bottom, top, None) };
+        assert_eq!(memory[..CANARY_WORDS], [stack.canary; CANARY_WORDS]);
+        assert!(memory[CANARY_WORDS..].iter().all(|w| *w == 1));
+        assert_eq!(unsafe { stack.max_usage() }, None);
+        unsafe { stack.check_canary() };
+    }
+
+    #[test]
+    #[should_panic(expected = "kernel stack canary corrupted")]
+    fn corrupted_canary_panics() {
+        let mut memory = vec![0u64; 16];
+        let (bottom, top) = stack_in(&mut memory);
+        let stack = unsafe { CheckedStack::prepare(bottom, top, None) };
+        memory[1] = 0;
+        unsafe { stack.check_canary() };
+    }
+}
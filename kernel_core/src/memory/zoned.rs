@@ -0,0 +1,299 @@
+//! Page allocator for systems with several discontiguous regions of physical memory.
+
+use snafu::OptionExt as _;
+
+use super::{
+    AllocationConstraints, Error, MemoryStatistics, OutOfMemorySnafu, PageAllocator, PageSize,
+    PhysicalAddress, UnknownPtrSnafu,
+};
+
+/// Identifies a zone in a [`ZonedPageAllocator`], in the order the zones were added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneId(pub usize);
+
+/// A region of physical memory managed by its own page allocator.
+struct Zone<PA> {
+    start: usize,
+    end: usize,
+    allocator: PA,
+}
+
+/// Page allocator that manages several discontiguous regions of physical memory (zones), each with
+/// its own page allocator.
+///
+/// Allocations are made from a preferred zone if possible, falling back to the other zones in
+/// order. Frees are returned to the zone that contains the pages.
+///
+/// `MAX_ZONES` is the largest number of zones that can be managed.
+#[allow(clippy::module_name_repetitions)]
+pub struct ZonedPageAllocator<PA, const MAX_ZONES: usize = 8> {
+    page_size: PageSize,
+    zones: [Option<Zone<PA>>; MAX_ZONES],
+}
+
+impl<PA: PageAllocator, const MAX_ZONES: usize> ZonedPageAllocator<PA, MAX_ZONES> {
+    /// Create a new allocator with no zones, that allocates pages of size `page_size`.
+    #[must_use]
+    pub const fn new(page_size: PageSize) -> Self {
+        Self {
+            page_size,
+            zones: [const { None }; MAX_ZONES],
+        }
+    }
+
+    /// Add a zone for the `length` bytes of physical memory starting at `start`, whose pages are
+    /// managed by `allocator`.
+    ///
+    /// Returns the new zone's ID, or `None` if there is no room for more zones.
+    ///
+    /// # Panics
+    /// - If the allocator's page size does not match this allocator's page size.
+    /// - If the zone overlaps an existing zone.
+    pub fn add_zone(
+        &mut self,
+        start: PhysicalAddress,
+        length: usize,
+        allocator: PA,
+    ) -> Option<ZoneId> {
+        assert_eq!(allocator.page_size(), self.page_size);
+        let start = usize::from(start);
+        let end = start + length;
+        assert!(
+            self.zones().all(|(_, z)| end <= z.start || start >= z.end),
+            "zone {start:#x}..{end:#x} overlaps an existing zone"
+        );
+        let (index, slot) = self
+            .zones
+            .iter_mut()
+            .enumerate()
+            .find(|(_, z)| z.is_none())?;
+        *slot = Some(Zone {
+            start,
+            end,
+            allocator,
+        });
+        Some(ZoneId(index))
+    }
+
+    fn zones(&self) -> impl Iterator<Item = (ZoneId, &Zone<PA>)> {
+        self.zones
+            .iter()
+            .enumerate()
+            .filter_map(|(i, z)| z.as_ref().map(|z| (ZoneId(i), z)))
+    }
+
+    /// The number of zones in the allocator.
+    pub fn zone_count(&self) -> usize {
+        self.zones().count()
+    }
+
+    /// The page allocator for zone `id`, if it exists.
+    pub fn zone(&self, id: ZoneId) -> Option<&PA> {
+        self.zones.get(id.0)?.as_ref().map(|z| &z.allocator)
+    }
+
+    /// The start address and length in bytes of the physical memory in zone `id`, if it exists.
+    pub fn zone_range(&self, id: ZoneId) -> Option<(PhysicalAddress, usize)> {
+        self.zones
+            .get(id.0)?
+            .as_ref()
+            .map(|z| (PhysicalAddress::from(z.start), z.end - z.start))
+    }
+
+    /// Find the zone that contains the physical address `address`.
+    pub fn zone_containing(&self, address: PhysicalAddress) -> Option<ZoneId> {
+        let address = usize::from(address);
+        self.zones()
+            .find(|(_, z)| (z.start..z.end).contains(&address))
+            .map(|(id, _)| id)
+    }
+
+    /// Allocate `num_pages` of memory, preferring to allocate them from zone `preferred`.
+    ///
+    /// If the preferred zone does not have enough free memory, the other zones are tried in order.
+    ///
+    /// # Errors
+    /// - [`Error::OutOfMemory`] if no zone has enough memory to allocate `num_pages`.
+    /// - [`Error::InvalidSize`] if `num_pages` is zero.
+    pub fn allocate_in(
+        &self,
+        preferred: ZoneId,
+        num_pages: usize,
+    ) -> Result<PhysicalAddress, Error> {
+        let preferred_zone = self.zone(preferred).into_iter();
+        let other_zones = self
+            .zones()
+            .filter(|(id, _)| *id != preferred)
+            .map(|(_, z)| &z.allocator);
+        let mut result = Err(Error::OutOfMemory);
+        for zone in preferred_zone.chain(other_zones) {
+            result = zone.allocate(num_pages);
+            if !matches!(result, Err(Error::OutOfMemory)) {
+                break;
+            }
+        }
+        result
+    }
+}
+
+impl<PA: PageAllocator, const MAX_ZONES: usize> PageAllocator
+    for ZonedPageAllocator<PA, MAX_ZONES>
+{
+    fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    fn allocate(&self, num_pages: usize) -> Result<PhysicalAddress, Error> {
+        self.allocate_in(ZoneId(0), num_pages)
+    }
+
+    fn free(&self, pages: PhysicalAddress, num_pages: usize) -> Result<(), Error> {
+        let zone = self.zone_containing(pages).context(UnknownPtrSnafu)?;
+        self.zone(zone).unwrap().free(pages, num_pages)
+    }
+
+    fn allocate_constrained(
+        &self,
+        num_pages: usize,
+        constraints: &AllocationConstraints,
+    ) -> Result<PhysicalAddress, Error> {
+        for (_, zone) in self
+            .zones()
+            .filter(|(_, z)| z.start <= constraints.max_address)
+        {
+            match zone.allocator.allocate_constrained(num_pages, constraints) {
+                Err(Error::OutOfMemory) => {}
+                result => return result,
+            }
+        }
+        OutOfMemorySnafu.fail()
+    }
+
+    fn statistics(&self) -> MemoryStatistics {
+        self.zones().map(|(_, z)| z.allocator.statistics()).fold(
+            MemoryStatistics::default(),
+            |total, zone| MemoryStatistics {
+                total_pages: total.total_pages + zone.total_pages,
+                free_pages: total.free_pages + zone.free_pages,
+                largest_free_block: total.largest_free_block.max(zone.largest_free_block),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::tests::MockPageAllocator;
+
+    use super::*;
+
+    /// Create a zoned allocator with a zone of mock pages for each entry of `pages`.
+    ///
+    /// The mock allocators return addresses outside of their zones, so pages must be freed
+    /// through the zones directly.
+    fn two_zones(pages: [usize; 2]) -> ZonedPageAllocator<MockPageAllocator> {
+        let mut za = ZonedPageAllocator::new(PageSize::FourKiB);
+        for p in pages {
+            za.add_zone(
+                PhysicalAddress::from((za.zone_count() + 1) << 32),
+                p * PageSize::FourKiB,
+                MockPageAllocator::new(PageSize::FourKiB, p),
+            );
+        }
+        za
+    }
+
+    #[test]
+    fn preferred_zone_with_fallback() {
+        let za = two_zones([4, 8]);
+        let z0 = za.zone(ZoneId(0)).unwrap();
+        let z1 = za.zone(ZoneId(1)).unwrap();
+
+        let a = za.allocate_in(ZoneId(1), 6).unwrap();
+        assert_eq!(z1.statistics().free_pages, 2);
+        // zone 1 is too full, so this comes from zone 0
+        let b = za.allocate_in(ZoneId(1), 4).unwrap();
+        assert_eq!(z0.statistics().free_pages, 0);
+        assert!(matches!(
+            za.allocate_in(ZoneId(0), 4),
+            Err(Error::OutOfMemory)
+        ));
+        assert!(matches!(
+            za.allocate_in(ZoneId(0), 0),
+            Err(Error::InvalidSize)
+        ));
+
+        assert_eq!(
+            za.statistics(),
+            MemoryStatistics {
+                total_pages: 12,
+                free_pages: 2,
+                largest_free_block: 2,
+            }
+        );
+
+        z1.free(a, 6).unwrap();
+        z0.free(b, 4).unwrap();
+    }
+
+    #[test]
+    fn zone_lookup() {
+        let mut za = ZonedPageAllocator::<MockPageAllocator, 2>::new(PageSize::FourKiB);
+        let low = za
+            .add_zone(
+                PhysicalAddress::from(0x4000_0000),
+                0x1000_0000,
+                MockPageAllocator::new(PageSize::FourKiB, 1),
+            )
+            .unwrap();
+        let high = za
+            .add_zone(
+                PhysicalAddress::from(0x1_0000_0000),
+                0x1000_0000,
+                MockPageAllocator::new(PageSize::FourKiB, 1),
+            )
+            .unwrap();
+        assert!(za
+            .add_zone(
+                PhysicalAddress::from(0x2_0000_0000),
+                0x1000,
+                MockPageAllocator::new(PageSize::FourKiB, 1),
+            )
+            .is_none());
+
+        assert_eq!(za.zone_count(), 2);
+        assert_eq!(
+            za.zone_range(high),
+            Some((PhysicalAddress::from(0x1_0000_0000), 0x1000_0000))
+        );
+        assert_eq!(
+            za.zone_containing(PhysicalAddress::from(0x4fff_ffff)),
+            Some(low)
+        );
+        assert_eq!(
+            za.zone_containing(PhysicalAddress::from(0x1_0000_0000)),
+            Some(high)
+        );
+        assert_eq!(za.zone_containing(PhysicalAddress::from(0x5000_0000)), None);
+        assert!(matches!(
+            za.free(PhysicalAddress::from(0x8000_0000), 1),
+            Err(Error::UnknownPtr)
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn overlapping_zones() {
+        let mut za = ZonedPageAllocator::<MockPageAllocator>::new(PageSize::FourKiB);
+        za.add_zone(
+            PhysicalAddress::from(0x4000_0000),
+            0x1000_0000,
+            MockPageAllocator::new(PageSize::FourKiB, 1),
+        );
+        za.add_zone(
+            PhysicalAddress::from(0x4fff_f000),
+            0x1000,
+            MockPageAllocator::new(PageSize::FourKiB, 1),
+        );
+    }
+}
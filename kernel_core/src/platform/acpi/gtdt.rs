@@ -0,0 +1,77 @@
+//! Generic Timer Description Table (GTDT), which describes the generic timer interrupts.
+use byteorder::{ByteOrder as _, LittleEndian};
+use snafu::ensure;
+
+use super::{AcpiError, TruncatedSnafu};
+
+/// Offset of the secure EL1 timer entry, the first of the four per-core timers.
+const TIMERS_OFFSET: usize = 48;
+/// Length of the table up to the end of the EL2 timer entry.
+const MIN_LENGTH: usize = TIMERS_OFFSET + 4 * 8;
+
+/// The interrupt used by one of the per-core timers.
+#[derive(Debug, Clone, Copy)]
+pub struct TimerEntry {
+    /// The interrupt ID (GSIV) of the timer, or zero if it isn't provided.
+    pub interrupt: u32,
+    /// The timer's flags.
+    pub flags: u32,
+}
+
+impl TimerEntry {
+    /// True if the interrupt is edge triggered, rather than level triggered.
+    #[must_use]
+    pub fn edge_triggered(&self) -> bool {
+        self.flags & 0b01 != 0
+    }
+
+    /// True if the interrupt is active low, rather than active high.
+    #[must_use]
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b10 != 0
+    }
+}
+
+/// The Generic Timer Description Table.
+#[derive(Debug, Clone)]
+pub struct Gtdt {
+    /// Physical address of the counter control registers, or all ones if not provided.
+    pub counter_control_base: u64,
+    /// The secure EL1 physical timer.
+    pub secure_el1: TimerEntry,
+    /// The non-secure EL1 physical timer.
+    pub non_secure_el1: TimerEntry,
+    /// The EL1 virtual timer.
+    pub virtual_el1: TimerEntry,
+    /// The EL2 physical timer.
+    pub el2: TimerEntry,
+}
+
+impl Gtdt {
+    /// Parse the GTDT in `table`.
+    ///
+    /// # Errors
+    /// - [`AcpiError::Truncated`] if the table is too short.
+    pub fn parse(table: &[u8]) -> Result<Self, AcpiError> {
+        ensure!(
+            table.len() >= MIN_LENGTH,
+            TruncatedSnafu {
+                signature: super::signature(table)
+            }
+        );
+        let timer = |index: usize| {
+            let offset = TIMERS_OFFSET + index * 8;
+            TimerEntry {
+                interrupt: LittleEndian::read_u32(&table[offset..]),
+                flags: LittleEndian::read_u32(&table[offset + 4..]),
+            }
+        };
+        Ok(Self {
+            counter_control_base: LittleEndian::read_u64(&table[36..]),
+            secure_el1: timer(0),
+            non_secure_el1: timer(1),
+            virtual_el1: timer(2),
+            el2: timer(3),
+        })
+    }
+}
@@ -0,0 +1,147 @@
+//! Multiple APIC Description Table (MADT), which describes the cores and interrupt controller.
+use byteorder::{ByteOrder as _, LittleEndian};
+use snafu::ensure;
+
+use super::{AcpiError, TruncatedSnafu, SDT_HEADER_SIZE};
+
+/// Offset of the interrupt controller structures in the MADT.
+const ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+
+/// A GIC CPU interface (GICC) structure, which describes one core.
+#[derive(Debug, Clone)]
+pub struct GicCpuInterface {
+    /// The GIC's number for the CPU interface.
+    pub cpu_interface_number: u32,
+    /// The ACPI processor UID of the core.
+    pub uid: u32,
+    /// True if the core can be used.
+    pub enabled: bool,
+    /// Physical address of the memory mapped CPU interface (GIC version 2), or zero.
+    pub base: u64,
+    /// Physical address of the core's redistributor (GIC version 3), or zero if the
+    /// redistributors are given by GICR structures instead.
+    pub redistributor_base: u64,
+    /// The core's `MPIDR_EL1` value.
+    pub mpidr: u64,
+}
+
+impl GicCpuInterface {
+    /// The affinity fields of the core's `MPIDR_EL1`, which identify the core.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn affinity(&self) -> usize {
+        (self.mpidr & 0xff_00ff_ffff) as usize
+    }
+}
+
+/// A GIC distributor (GICD) structure.
+#[derive(Debug, Clone)]
+pub struct GicDistributor {
+    /// Physical address of the distributor registers.
+    pub base: u64,
+    /// The GIC version, or zero if it must be discovered from the hardware.
+    pub version: u8,
+}
+
+/// A GIC redistributor (GICR) structure, which describes a region containing redistributors.
+#[derive(Debug, Clone)]
+pub struct GicRedistributor {
+    /// Physical address of the start of the region.
+    pub base: u64,
+    /// The length of the region in bytes.
+    pub length: u32,
+}
+
+/// A GIC MSI frame structure, which describes a `GICv2m` frame.
+#[derive(Debug, Clone)]
+pub struct GicMsiFrame {
+    /// Physical address of the frame's registers.
+    pub base: u64,
+}
+
+/// An interrupt controller structure in the MADT.
+#[derive(Debug, Clone)]
+pub enum MadtEntry<'a> {
+    /// A core's GIC CPU interface.
+    GicCpuInterface(GicCpuInterface),
+    /// The GIC distributor.
+    GicDistributor(GicDistributor),
+    /// A `GICv2m` MSI frame.
+    GicMsiFrame(GicMsiFrame),
+    /// A region of GIC redistributors.
+    GicRedistributor(GicRedistributor),
+    /// A structure that is unsupported or too short to parse.
+    Other {
+        /// The structure type.
+        kind: u8,
+        /// The raw bytes of the structure.
+        data: &'a [u8],
+    },
+}
+
+impl<'a> MadtEntry<'a> {
+    fn parse(data: &'a [u8]) -> Self {
+        let read_u32 = |offset| LittleEndian::read_u32(&data[offset..]);
+        let read_u64 = |offset| LittleEndian::read_u64(&data[offset..]);
+        match (data[0], data.len()) {
+            (0x0b, 76..) => Self::GicCpuInterface(GicCpuInterface {
+                cpu_interface_number: read_u32(4),
+                uid: read_u32(8),
+                // either enabled, or online capable
+                enabled: read_u32(12) & 0b1001 != 0,
+                base: read_u64(32),
+                redistributor_base: read_u64(60),
+                mpidr: read_u64(68),
+            }),
+            (0x0c, 24..) => Self::GicDistributor(GicDistributor {
+                base: read_u64(8),
+                version: data[20],
+            }),
+            (0x0d, 24..) => Self::GicMsiFrame(GicMsiFrame { base: read_u64(8) }),
+            (0x0e, 16..) => Self::GicRedistributor(GicRedistributor {
+                base: read_u64(4),
+                length: read_u32(12),
+            }),
+            (kind, _) => Self::Other { kind, data },
+        }
+    }
+}
+
+/// The Multiple APIC Description Table.
+#[derive(Debug, Clone)]
+pub struct Madt<'a> {
+    entries: &'a [u8],
+}
+
+impl<'a> Madt<'a> {
+    /// Parse the MADT in `table`.
+    ///
+    /// # Errors
+    /// - [`AcpiError::Truncated`] if the table is too short.
+    pub fn parse(table: &'a [u8]) -> Result<Self, AcpiError> {
+        ensure!(
+            table.len() >= ENTRIES_OFFSET,
+            TruncatedSnafu {
+                signature: super::signature(table)
+            }
+        );
+        Ok(Self {
+            entries: &table[ENTRIES_OFFSET..],
+        })
+    }
+
+    /// Iterate over the interrupt controller structures in the table.
+    /// Iteration stops early if a structure's length is invalid.
+    pub fn entries(&self) -> impl Iterator<Item = MadtEntry<'a>> {
+        let mut rest = self.entries;
+        core::iter::from_fn(move || {
+            let length = *rest.get(1)? as usize;
+            if length < 2 || length > rest.len() {
+                return None;
+            }
+            let (entry, next) = rest.split_at(length);
+            rest = next;
+            Some(MadtEntry::parse(entry))
+        })
+    }
+}
@@ -0,0 +1,516 @@
+//! ACPI table parser.
+//!
+//! Some ARM server platforms describe their hardware with ACPI tables instead of a device tree.
+//! This module finds the tables through the RSDP and XSDT, and parses the tables needed to boot:
+//! the MADT (cores and interrupt controller), the GTDT (generic timer) and the FADT (how cores are
+//! started). See the [ACPI specification](https://uefi.org/specifications) for the table formats.
+//!
+//! Only ACPI 2.0 and later is supported, since earlier versions have no XSDT.
+use alloc::vec::Vec;
+use byteorder::{ByteOrder as _, LittleEndian};
+use log::{debug, warn};
+use snafu::{ensure, OptionExt as _, Snafu};
+
+use crate::{
+    exceptions::interrupt::TriggerMode,
+    memory::PhysicalAddress,
+    platform::{
+        cpu::CoreInfo,
+        info::{InterruptControllerInfo, PlatformInfo, TimerInfo, TimerInterrupt},
+    },
+};
+
+pub mod gtdt;
+pub mod madt;
+
+pub use gtdt::Gtdt;
+pub use madt::{Madt, MadtEntry};
+
+/// Size of the header common to every system description table.
+pub const SDT_HEADER_SIZE: usize = 36;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+/// Offset of the ARM boot architecture flags in the FADT.
+const FADT_ARM_BOOT_ARCH: usize = 129;
+/// FADT ARM boot architecture flag set if the platform implements PSCI.
+const ARM_BOOT_ARCH_PSCI_COMPLIANT: u16 = 1 << 0;
+
+/// Errors that can occur reading ACPI tables.
+#[derive(Debug, Snafu)]
+pub enum AcpiError {
+    /// A table was not at the given physical address.
+    #[snafu(display("Could not map table at {address:?}"))]
+    Unmapped {
+        /// The address of the table.
+        address: PhysicalAddress,
+    },
+    /// A table had the wrong signature.
+    #[snafu(display("Expected table {expected:?}"))]
+    BadSignature {
+        /// The signature that was expected.
+        expected: &'static str,
+    },
+    /// The bytes of a table did not sum to zero.
+    #[snafu(display("Table {:?} has invalid checksum", core::str::from_utf8(signature)))]
+    BadChecksum {
+        /// The signature of the table.
+        signature: [u8; 4],
+    },
+    /// The RSDP is from before ACPI 2.0, so there is no XSDT.
+    UnsupportedRevision {
+        /// The revision of the RSDP.
+        revision: u8,
+    },
+    /// A table was shorter than its format requires.
+    #[snafu(display("Table {:?} is truncated", core::str::from_utf8(signature)))]
+    Truncated {
+        /// The signature of the table.
+        signature: [u8; 4],
+    },
+    /// A required table was not present.
+    #[snafu(display("Table {signature:?} not found"))]
+    TableNotFound {
+        /// The signature of the missing table.
+        signature: &'static str,
+    },
+    /// The MADT does not describe a GIC distributor.
+    NoDistributor,
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Addresses in ACPI tables are 64 bits, the same as pointers on this platform.
+#[allow(clippy::cast_possible_truncation)]
+fn physical_address(address: u64) -> PhysicalAddress {
+    PhysicalAddress::from(address as usize)
+}
+
+/// The signature of a system description table.
+fn signature(table: &[u8]) -> [u8; 4] {
+    let mut s = [0; 4];
+    s.copy_from_slice(&table[0..4]);
+    s
+}
+
+/// Read the table at `address` using `map`, checking its length and checksum.
+fn read_table<'a>(
+    address: PhysicalAddress,
+    map: &impl Fn(PhysicalAddress, usize) -> Option<&'a [u8]>,
+) -> Result<&'a [u8], AcpiError> {
+    let header = map(address, SDT_HEADER_SIZE).context(UnmappedSnafu { address })?;
+    ensure!(
+        header.len() >= SDT_HEADER_SIZE,
+        TruncatedSnafu {
+            signature: signature(header)
+        }
+    );
+    let length = LittleEndian::read_u32(&header[4..]) as usize;
+    ensure!(
+        length >= SDT_HEADER_SIZE,
+        TruncatedSnafu {
+            signature: signature(header)
+        }
+    );
+    let table = map(address, length).context(UnmappedSnafu { address })?;
+    ensure!(
+        table.len() >= length,
+        TruncatedSnafu {
+            signature: signature(header)
+        }
+    );
+    let table = &table[..length];
+    ensure!(
+        checksum_ok(table),
+        BadChecksumSnafu {
+            signature: signature(table)
+        }
+    );
+    Ok(table)
+}
+
+/// The system description tables provided by the firmware.
+pub struct AcpiTables<'a> {
+    tables: Vec<&'a [u8]>,
+}
+
+impl<'a> AcpiTables<'a> {
+    /// Find all the tables listed in the XSDT referenced by the RSDP at `rsdp`.
+    ///
+    /// The `map` function provides access to the `length` bytes of physical memory starting at an
+    /// address, or `None` if the memory can't be accessed.
+    ///
+    /// # Errors
+    /// - [`AcpiError::BadSignature`] if the RSDP or XSDT signatures are incorrect.
+    /// - [`AcpiError::UnsupportedRevision`] if the RSDP is from before ACPI 2.0.
+    /// - [`AcpiError::BadChecksum`], [`AcpiError::Truncated`] or [`AcpiError::Unmapped`] if a
+    ///   table can't be read. Tables listed in the XSDT that can't be read are skipped instead.
+    pub fn from_rsdp(
+        rsdp: PhysicalAddress,
+        map: impl Fn(PhysicalAddress, usize) -> Option<&'a [u8]>,
+    ) -> Result<Self, AcpiError> {
+        let header = map(rsdp, RSDP_V1_SIZE).context(UnmappedSnafu { address: rsdp })?;
+        ensure!(
+            header.len() >= RSDP_V1_SIZE && header[0..8] == *RSDP_SIGNATURE,
+            BadSignatureSnafu {
+                expected: "RSD PTR "
+            }
+        );
+        let revision = header[15];
+        ensure!(revision >= 2, UnsupportedRevisionSnafu { revision });
+        let rsdp_table = map(rsdp, RSDP_V2_SIZE).context(UnmappedSnafu { address: rsdp })?;
+        ensure!(
+            rsdp_table.len() >= RSDP_V2_SIZE
+                && checksum_ok(&rsdp_table[..RSDP_V1_SIZE])
+                && checksum_ok(&rsdp_table[..RSDP_V2_SIZE]),
+            BadChecksumSnafu {
+                signature: *b"RSD "
+            }
+        );
+
+        let xsdt_address = physical_address(LittleEndian::read_u64(&rsdp_table[24..]));
+        let xsdt = read_table(xsdt_address, &map)?;
+        ensure!(
+            xsdt[0..4] == *b"XSDT",
+            BadSignatureSnafu { expected: "XSDT" }
+        );
+
+        let mut tables = Vec::new();
+        for entry in xsdt[SDT_HEADER_SIZE..].chunks_exact(8) {
+            let address = physical_address(LittleEndian::read_u64(entry));
+            match read_table(address, &map) {
+                Ok(table) => {
+                    debug!(
+                        "found ACPI table {:?} at {address:?}",
+                        core::str::from_utf8(&table[0..4])
+                    );
+                    tables.push(table);
+                }
+                Err(e) => warn!("skipping ACPI table at {address:?}: {e}"),
+            }
+        }
+        Ok(Self { tables })
+    }
+
+    /// Find the table with `signature`, if present.
+    #[must_use]
+    pub fn find(&self, signature: &[u8; 4]) -> Option<&'a [u8]> {
+        self.tables.iter().find(|t| t[0..4] == *signature).copied()
+    }
+
+    /// Find and parse the MADT.
+    ///
+    /// # Errors
+    /// - [`AcpiError::TableNotFound`] if there is no MADT.
+    /// - [`AcpiError::Truncated`] if the table is too short.
+    pub fn madt(&self) -> Result<Madt<'a>, AcpiError> {
+        Madt::parse(
+            self.find(b"APIC")
+                .context(TableNotFoundSnafu { signature: "APIC" })?,
+        )
+    }
+
+    /// Find and parse the GTDT.
+    ///
+    /// # Errors
+    /// - [`AcpiError::TableNotFound`] if there is no GTDT.
+    /// - [`AcpiError::Truncated`] if the table is too short.
+    pub fn gtdt(&self) -> Result<Gtdt, AcpiError> {
+        Gtdt::parse(
+            self.find(b"GTDT")
+                .context(TableNotFoundSnafu { signature: "GTDT" })?,
+        )
+    }
+
+    /// True if the FADT says the platform implements PSCI to start cores.
+    #[must_use]
+    pub fn psci_compliant(&self) -> bool {
+        self.find(b"FACP")
+            .and_then(|fadt| fadt.get(FADT_ARM_BOOT_ARCH..FADT_ARM_BOOT_ARCH + 2))
+            .is_some_and(|flags| LittleEndian::read_u16(flags) & ARM_BOOT_ARCH_PSCI_COMPLIANT != 0)
+    }
+}
+
+impl PlatformInfo for AcpiTables<'_> {
+    type Error = AcpiError;
+
+    fn cores(&self) -> Result<Vec<CoreInfo<'_>>, Self::Error> {
+        // use the same names as the device tree `enable-method` property
+        let enable_method: &'static [u8] = if self.psci_compliant() {
+            b"psci\0"
+        } else {
+            b"acpi-parking-protocol\0"
+        };
+        Ok(self
+            .madt()?
+            .entries()
+            .filter_map(|entry| match entry {
+                MadtEntry::GicCpuInterface(gicc) if gicc.enabled => Some(CoreInfo {
+                    id: gicc.affinity(),
+                    enable_method,
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn interrupt_controller(&self) -> Result<InterruptControllerInfo, Self::Error> {
+        let madt = self.madt()?;
+        let mut distributor = None;
+        let mut cpu_interface = None;
+        let mut redistributors = Vec::new();
+        let mut cpu_redistributors = Vec::new();
+        let mut msi_frames = Vec::new();
+        for entry in madt.entries() {
+            match entry {
+                MadtEntry::GicDistributor(gicd) => distributor = Some(gicd),
+                MadtEntry::GicCpuInterface(gicc) if gicc.enabled => {
+                    if cpu_interface.is_none() && gicc.base != 0 {
+                        cpu_interface = Some(physical_address(gicc.base));
+                    }
+                    if gicc.redistributor_base != 0 {
+                        cpu_redistributors.push(physical_address(gicc.redistributor_base));
+                    }
+                }
+                MadtEntry::GicRedistributor(gicr) => {
+                    redistributors.push((physical_address(gicr.base), gicr.length as usize));
+                }
+                MadtEntry::GicMsiFrame(frame) => {
+                    msi_frames.push(physical_address(frame.base));
+                }
+                _ => {}
+            }
+        }
+        let distributor = distributor.context(NoDistributorSnafu)?;
+
+        // version 0 means the version must be discovered from the hardware, but redistributors only
+        // exist in GICv3 and later
+        let version = match distributor.version {
+            0 if redistributors.is_empty() && cpu_redistributors.is_empty() => 2,
+            0 => 3,
+            v => v,
+        };
+        if redistributors.is_empty() && version >= 3 {
+            // each core's redistributor has an RD_base and SGI_base frame (and VLPI frames on v4)
+            let frame_size = if version >= 4 { 0x4_0000 } else { 0x2_0000 };
+            redistributors = cpu_redistributors
+                .into_iter()
+                .map(|base| (base, frame_size))
+                .collect();
+        }
+
+        Ok(InterruptControllerInfo {
+            version,
+            distributor: physical_address(distributor.base),
+            cpu_interface,
+            redistributors,
+            msi_frames,
+        })
+    }
+
+    fn timer(&self) -> Result<TimerInfo, Self::Error> {
+        let gtdt = self.gtdt()?;
+        let timer = |t: gtdt::TimerEntry| TimerInterrupt {
+            id: t.interrupt,
+            trigger: if t.edge_triggered() {
+                TriggerMode::Edge
+            } else {
+                TriggerMode::Level
+            },
+        };
+        // an interrupt of zero means the timer isn't provided
+        let optional = |t: gtdt::TimerEntry| (t.interrupt != 0).then(|| timer(t));
+        Ok(TimerInfo {
+            secure: optional(gtdt.secure_el1),
+            non_secure: timer(gtdt.non_secure_el1),
+            virtual_timer: timer(gtdt.virtual_el1),
+            hypervisor: optional(gtdt.el2),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, vec::Vec};
+
+    use super::*;
+
+    /// Build a table with a valid header and checksum.
+    fn table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+        let mut t = Vec::new();
+        t.extend_from_slice(signature);
+        t.extend_from_slice(&((SDT_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        t.push(revision);
+        t.push(0);
+        t.extend_from_slice(b"CAVERN");
+        t.extend_from_slice(b"TESTTABL");
+        t.extend_from_slice(&[0; 12]);
+        t.extend_from_slice(body);
+        t[9] = 0u8.wrapping_sub(t.iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+        t
+    }
+
+    fn gicc(uid: u32, mpidr: u64, enabled: bool) -> Vec<u8> {
+        let mut e = std::vec![0; 80];
+        e[0] = 0x0b;
+        e[1] = 80;
+        LittleEndian::write_u32(&mut e[4..], uid);
+        LittleEndian::write_u32(&mut e[8..], uid);
+        LittleEndian::write_u32(&mut e[12..], u32::from(enabled));
+        LittleEndian::write_u64(&mut e[32..], 0x801_0000);
+        LittleEndian::write_u64(&mut e[68..], mpidr);
+        e
+    }
+
+    fn gicd(version: u8) -> Vec<u8> {
+        let mut e = std::vec![0; 24];
+        e[0] = 0x0c;
+        e[1] = 24;
+        LittleEndian::write_u64(&mut e[8..], 0x800_0000);
+        e[20] = version;
+        e
+    }
+
+    fn gicr(base: u64, length: u32) -> Vec<u8> {
+        let mut e = std::vec![0; 16];
+        e[0] = 0x0e;
+        e[1] = 16;
+        LittleEndian::write_u64(&mut e[4..], base);
+        LittleEndian::write_u32(&mut e[12..], length);
+        e
+    }
+
+    fn madt(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = std::vec![0; 8];
+        for e in entries {
+            body.extend_from_slice(e);
+        }
+        table(b"APIC", 5, &body)
+    }
+
+    fn gtdt() -> Vec<u8> {
+        let mut body = std::vec![0; 60];
+        // (interrupt, flags) for secure, non-secure, virtual and EL2 timers, from offset 48
+        for (i, (interrupt, flags)) in [(29, 0), (30, 0), (27, 1), (26, 0)].iter().enumerate() {
+            LittleEndian::write_u32(&mut body[12 + i * 8..], *interrupt);
+            LittleEndian::write_u32(&mut body[16 + i * 8..], *flags);
+        }
+        table(b"GTDT", 2, &body)
+    }
+
+    fn fadt(psci: bool) -> Vec<u8> {
+        let mut body = std::vec![0; 276 - SDT_HEADER_SIZE];
+        body[FADT_ARM_BOOT_ARCH - SDT_HEADER_SIZE] = u8::from(psci);
+        table(b"FACP", 6, &body)
+    }
+
+    /// Physical memory containing an RSDP at 0x1000 that refers to the `tables`.
+    fn memory(tables: Vec<Vec<u8>>) -> BTreeMap<usize, Vec<u8>> {
+        let mut memory = BTreeMap::new();
+        let mut xsdt_body = Vec::new();
+        for (i, t) in tables.into_iter().enumerate() {
+            let address = 0x10_0000 * (i + 2);
+            xsdt_body.extend_from_slice(&(address as u64).to_le_bytes());
+            memory.insert(address, t);
+        }
+        memory.insert(0x10_0000, table(b"XSDT", 1, &xsdt_body));
+
+        let mut rsdp = std::vec![0; RSDP_V2_SIZE];
+        rsdp[0..8].copy_from_slice(RSDP_SIGNATURE);
+        rsdp[15] = 2;
+        LittleEndian::write_u32(&mut rsdp[20..], RSDP_V2_SIZE as u32);
+        LittleEndian::write_u64(&mut rsdp[24..], 0x10_0000);
+        rsdp[8] = 0u8.wrapping_sub(rsdp[..20].iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+        rsdp[32] = 0u8.wrapping_sub(rsdp.iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+        memory.insert(0x1000, rsdp);
+        memory
+    }
+
+    fn tables(memory: &BTreeMap<usize, Vec<u8>>) -> Result<AcpiTables<'_>, AcpiError> {
+        AcpiTables::from_rsdp(PhysicalAddress::from(0x1000), |address, _| {
+            memory.get(&usize::from(address)).map(Vec::as_slice)
+        })
+    }
+
+    #[test]
+    fn gicv2_platform() {
+        let memory = memory(std::vec![
+            madt(&[
+                gicd(2),
+                gicc(0, 0, true),
+                gicc(1, 1, true),
+                gicc(2, 2, false)
+            ]),
+            gtdt(),
+            fadt(true),
+        ]);
+        let acpi = tables(&memory).unwrap();
+
+        let cores = acpi.cores().unwrap();
+        assert_eq!(cores.iter().map(|c| c.id).collect::<Vec<_>>(), [0, 1]);
+        assert!(cores.iter().all(|c| c.enable_method == b"psci\0"));
+
+        assert_eq!(
+            acpi.interrupt_controller().unwrap(),
+            InterruptControllerInfo {
+                version: 2,
+                distributor: PhysicalAddress::from(0x800_0000),
+                cpu_interface: Some(PhysicalAddress::from(0x801_0000)),
+                redistributors: Vec::new(),
+                msi_frames: Vec::new(),
+            }
+        );
+
+        let timer = acpi.timer().unwrap();
+        assert_eq!(timer.secure.map(|t| t.id), Some(29));
+        assert_eq!(timer.non_secure.id, 30);
+        assert_eq!(timer.virtual_timer.trigger, TriggerMode::Edge);
+        assert_eq!(timer.hypervisor.map(|t| t.id), Some(26));
+    }
+
+    #[test]
+    fn gicv3_platform() {
+        let memory = memory(std::vec![
+            madt(&[gicd(0), gicr(0x80a_0000, 0xf6_0000), gicc(0, 0x100, true)]),
+            fadt(false),
+        ]);
+        let acpi = tables(&memory).unwrap();
+        let cores = acpi.cores().unwrap();
+        assert_eq!(cores[0].id, 0x100);
+        assert_eq!(cores[0].enable_method, b"acpi-parking-protocol\0");
+        let gic = acpi.interrupt_controller().unwrap();
+        assert_eq!(gic.version, 3);
+        assert_eq!(
+            gic.redistributors,
+            [(PhysicalAddress::from(0x80a_0000), 0xf6_0000)]
+        );
+        assert!(matches!(
+            acpi.timer(),
+            Err(AcpiError::TableNotFound { signature: "GTDT" })
+        ));
+    }
+
+    #[test]
+    fn reject_bad_tables() {
+        let mut memory = memory(std::vec![madt(&[gicd(2)])]);
+        // corrupt the MADT, which is then skipped
+        memory.get_mut(&0x20_0000).unwrap()[40] ^= 0xff;
+        let acpi = tables(&memory).unwrap();
+        assert!(acpi.find(b"APIC").is_none());
+
+        memory.get_mut(&0x1000).unwrap()[24] ^= 0xff;
+        assert!(matches!(
+            tables(&memory),
+            Err(AcpiError::BadChecksum { .. })
+        ));
+        memory.get_mut(&0x1000).unwrap()[15] = 0;
+        assert!(matches!(
+            tables(&memory),
+            Err(AcpiError::UnsupportedRevision { revision: 0 })
+        ));
+    }
+}
@@ -0,0 +1,228 @@
+//! Parsing the kernel command line, given by the bootloader in the `/chosen/bootargs` property of
+//! the device tree.
+//!
+//! The command line is a JSON object (see `spec/kernel.md`). Only flat objects are supported: each
+//! value must be a string, a non-negative integer, a boolean or `null`. Escape sequences in
+//! strings are not decoded. Parsing does not allocate, so the arguments can be read before the
+//! kernel heap is initialized.
+use snafu::Snafu;
+
+/// A value in the kernel command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    /// `null`.
+    Null,
+    /// `true` or `false`.
+    Bool(bool),
+    /// A non-negative integer.
+    Number(u64),
+    /// The bytes between the quotes of a string, with any escape sequences left as they are.
+    String(&'a [u8]),
+}
+
+/// An error in the syntax of the command line.
+#[derive(Debug, Snafu)]
+#[snafu(display("invalid boot arguments at byte {position}: {reason}"))]
+pub struct Error {
+    /// The offset in the command line where the error was found.
+    position: usize,
+    /// Description of what was wrong.
+    reason: &'static str,
+}
+
+/// The parsed kernel command line.
+#[derive(Debug, Clone, Default)]
+pub struct BootArgs<'a> {
+    /// The command line, which is known to be valid.
+    text: &'a [u8],
+}
+
+/// A cursor over the command line being parsed.
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, reason: &'static str) -> Error {
+        Error {
+            position: self.position,
+            reason,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.position += 1;
+        }
+    }
+
+    /// Skip whitespace, then consume `expected` if it is next.
+    fn eat(&mut self, expected: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.text.get(self.position) == Some(&expected);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, expected: u8, reason: &'static str) -> Result<(), Error> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(self.error(reason))
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], Error> {
+        self.expect(b'"', "expected string")?;
+        let start = self.position;
+        let mut escaped = false;
+        loop {
+            let c = *self
+                .text
+                .get(self.position)
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.position += 1;
+            match c {
+                b'"' if !escaped => return Ok(&self.text[start..self.position - 1]),
+                b'\\' => escaped = !escaped,
+                _ => escaped = false,
+            }
+        }
+    }
+
+    fn keyword(&mut self, keyword: &[u8]) -> bool {
+        let found = self.text[self.position..].starts_with(keyword);
+        if found {
+            self.position += keyword.len();
+        }
+        found
+    }
+
+    fn value(&mut self) -> Result<Value<'a>, Error> {
+        self.skip_whitespace();
+        match self.text.get(self.position) {
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'0'..=b'9') => {
+                let mut n = 0u64;
+                while let Some(digit @ b'0'..=b'9') = self.text.get(self.position) {
+                    n = n
+                        .checked_mul(10)
+                        .and_then(|n| n.checked_add(u64::from(digit - b'0')))
+                        .ok_or_else(|| self.error("number too large"))?;
+                    self.position += 1;
+                }
+                Ok(Value::Number(n))
+            }
+            _ if self.keyword(b"true") => Ok(Value::Bool(true)),
+            _ if self.keyword(b"false") => Ok(Value::Bool(false)),
+            _ if self.keyword(b"null") => Ok(Value::Null),
+            _ => Err(self.error("expected string, number, boolean or null")),
+        }
+    }
+}
+
+/// Parse the command line in `text`, calling `f` with each key and value in order.
+fn for_each_entry<'a>(text: &'a [u8], mut f: impl FnMut(&'a [u8], Value<'a>)) -> Result<(), Error> {
+    let mut parser = Parser { text, position: 0 };
+    parser.skip_whitespace();
+    if parser.position == text.len() {
+        return Ok(());
+    }
+    parser.expect(b'{', "expected object")?;
+    if !parser.eat(b'}') {
+        loop {
+            let key = parser.string()?;
+            parser.expect(b':', "expected ':'")?;
+            f(key, parser.value()?);
+            if parser.eat(b'}') {
+                break;
+            }
+            parser.expect(b',', "expected ',' or '}'")?;
+        }
+    }
+    parser.skip_whitespace();
+    if parser.position != text.len() {
+        return Err(parser.error("unexpected data after object"));
+    }
+    Ok(())
+}
+
+impl<'a> BootArgs<'a> {
+    /// Parse the command line in `text`. An empty command line has no arguments.
+    ///
+    /// # Errors
+    /// Returns an error if the command line is not a flat JSON object.
+    pub fn parse(text: &'a [u8]) -> Result<Self, Error> {
+        for_each_entry(text, |_, _| {})?;
+        Ok(Self { text })
+    }
+
+    /// The value of the argument `key`, if it was given. If it was given more than once, the last
+    /// value is used.
+    #[must_use]
+    pub fn get(&self, key: &[u8]) -> Option<Value<'a>> {
+        let mut found = None;
+        // the text was validated by `parse`, so this can't fail
+        let _ = for_each_entry(self.text, |k, v| {
+            if k == key {
+                found = Some(v);
+            }
+        });
+        found
+    }
+
+    /// Returns true if the argument `key` was given as `true`.
+    #[must_use]
+    pub fn flag(&self, key: &[u8]) -> bool {
+        self.get(key) == Some(Value::Bool(true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let args = BootArgs::parse(
+            br#" { "init_exec_name": "init", "max_ihvm_cycles" : 1000,
+                "self_test": true, "quiet": false, "x": null, "esc": "a\"b\\" } "#,
+        )
+        .unwrap();
+        assert_eq!(args.get(b"init_exec_name"), Some(Value::String(b"init")));
+        assert_eq!(args.get(b"max_ihvm_cycles"), Some(Value::Number(1000)));
+        assert!(args.flag(b"self_test"));
+        assert!(!args.flag(b"quiet"));
+        assert!(!args.flag(b"missing"));
+        assert_eq!(args.get(b"x"), Some(Value::Null));
+        assert_eq!(args.get(b"esc"), Some(Value::String(br#"a\"b\\"#)));
+
+        assert!(BootArgs::parse(b"").unwrap().get(b"x").is_none());
+        assert!(BootArgs::parse(b"{}").unwrap().get(b"x").is_none());
+        let args = BootArgs::parse(br#"{"a": 1, "a": 2}"#).unwrap();
+        assert_eq!(args.get(b"a"), Some(Value::Number(2)));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        for (text, position) in [
+            (&b"console=ttyAMA0"[..], 0),
+            (br#"{"a" 1}"#, 5),
+            (br#"{"a": [1]}"#, 6),
+            (br#"{"a": 1"#, 7),
+            (br#"{"a": "b}"#, 9),
+            (br#"{"a": 99999999999999999999}"#, 25),
+            (br#"{} x"#, 3),
+        ] {
+            let error = BootArgs::parse(text).unwrap_err();
+            assert_eq!(error.position, position, "{text:?}: {error}");
+        }
+    }
+}
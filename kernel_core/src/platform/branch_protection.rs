@@ -0,0 +1,190 @@
+//! Pointer authentication (ARMv8.3 PAC) and branch target identification (ARMv8.5 BTI).
+//!
+//! Pointer authentication signs return addresses and other pointers with a key, so that a
+//! corrupted pointer faults when it is authenticated instead of being followed. There are five
+//! 128-bit keys, held in system registers: two for instruction addresses, two for data addresses and
+//! one for generic signatures. Each process has its own keys, which are loaded when one of its
+//! threads runs. The kernel signs its own return addresses with the instruction A key, so that key
+//! is swapped by the exception vector on entry from and return to user space.
+//!
+//! Branch target identification makes indirect branches into guarded pages fault unless they land
+//! on a `BTI` instruction. Pages are guarded with [`MemoryProperties::guarded`]. The kernel's own
+//! code is not guarded, since the prebuilt `core` and `alloc` libraries have no landing pads, so
+//! BTI is only enforced at EL0.
+//!
+//! Both features are optional. Their instructions are no-ops on cores that lack them, so code
+//! built to use them still runs; the features are only turned on in `SCTLR_EL1` if the core has
+//! them.
+//!
+//! [`MemoryProperties::guarded`]: crate::memory::page_table::MemoryProperties::guarded
+
+use crate::memory::KERNEL_SPACE_START;
+
+/// `SCTLR_EL1.EnIA`: enables authentication with the instruction A key.
+pub const SCTLR_ENABLE_IA: u64 = 1 << 31;
+/// `SCTLR_EL1.EnIB`: enables authentication with the instruction B key.
+pub const SCTLR_ENABLE_IB: u64 = 1 << 30;
+/// `SCTLR_EL1.EnDA`: enables authentication with the data A key.
+pub const SCTLR_ENABLE_DA: u64 = 1 << 27;
+/// `SCTLR_EL1.EnDB`: enables authentication with the data B key.
+pub const SCTLR_ENABLE_DB: u64 = 1 << 13;
+/// `SCTLR_EL1.BT0`: `PACIASP` and `PACIBSP` are not implicit branch targets at EL0.
+pub const SCTLR_BT0: u64 = 1 << 35;
+
+/// The branch protection features a core has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features {
+    /// Pointers can be signed and authenticated with the instruction and data keys.
+    pub pointer_auth: bool,
+    /// Generic signatures can be computed with the generic key (`PACGA`).
+    pub generic_auth: bool,
+    /// Indirect branches into guarded pages must land on a `BTI` instruction.
+    pub branch_targets: bool,
+}
+
+impl Features {
+    /// Decode the features from the `ID_AA64ISAR1_EL1`, `ID_AA64ISAR2_EL1` and `ID_AA64PFR1_EL1`
+    /// register values.
+    #[must_use]
+    pub fn from_id_registers(isar1: u64, isar2: u64, pfr1: u64) -> Self {
+        let field = |value: u64, shift: u32| (value >> shift) & 0xf != 0;
+        Self {
+            // APA, API or APA3
+            pointer_auth: field(isar1, 4) || field(isar1, 8) || field(isar2, 12),
+            // GPA, GPI or GPA3
+            generic_auth: field(isar1, 24) || field(isar1, 28) || field(isar2, 8),
+            branch_targets: field(pfr1, 0),
+        }
+    }
+
+    /// The bits that must be set in `SCTLR_EL1` to turn on the features.
+    #[must_use]
+    pub fn sctlr_bits(&self) -> u64 {
+        let mut bits = 0;
+        if self.pointer_auth {
+            bits |= SCTLR_ENABLE_IA | SCTLR_ENABLE_IB | SCTLR_ENABLE_DA | SCTLR_ENABLE_DB;
+        }
+        if self.branch_targets {
+            bits |= SCTLR_BT0;
+        }
+        bits
+    }
+}
+
+/// Remove the pointer authentication code from `address`, a signed pointer into the kernel's half
+/// of the address space such as a return address saved on a kernel stack.
+///
+/// The code is held in the bits above the virtual address size, which are all ones in an unsigned
+/// kernel address. This does the same as `XPACI`, but also works on cores without PAC, where the
+/// address was never signed. A null address is left as it is.
+#[must_use]
+pub fn strip_kernel_pointer(address: usize) -> usize {
+    if address == 0 {
+        0
+    } else {
+        address | KERNEL_SPACE_START
+    }
+}
+
+/// A 128-bit pointer authentication key, as held in a pair of `AP*Key{Lo,Hi}_EL1` registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct Key {
+    /// The low half of the key.
+    pub lo: u64,
+    /// The high half of the key.
+    pub hi: u64,
+}
+
+impl Key {
+    /// Generate a key from `next`, which must return random numbers.
+    pub fn random(mut next: impl FnMut() -> u64) -> Self {
+        Self {
+            lo: next(),
+            hi: next(),
+        }
+    }
+}
+
+/// The pointer authentication keys of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Keys {
+    /// The instruction A key, which compilers use to sign return addresses by default.
+    pub instruction_a: Key,
+    /// The instruction B key.
+    pub instruction_b: Key,
+    /// The data A key.
+    pub data_a: Key,
+    /// The data B key.
+    pub data_b: Key,
+    /// The generic key.
+    pub generic: Key,
+}
+
+impl Keys {
+    /// Generate a new set of keys from `next`, which must return random numbers.
+    pub fn random(mut next: impl FnMut() -> u64) -> Self {
+        Self {
+            instruction_a: Key::random(&mut next),
+            instruction_b: Key::random(&mut next),
+            data_a: Key::random(&mut next),
+            data_b: Key::random(&mut next),
+            generic: Key::random(&mut next),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_features() {
+        assert_eq!(Features::from_id_registers(0, 0, 0), Features::default());
+        assert_eq!(Features::from_id_registers(0, 0, 0).sctlr_bits(), 0);
+
+        // QARMA5 address and generic authentication, and BTI
+        let max = Features::from_id_registers(0x0000_0000_0110_0050, 0, 0x1);
+        assert_eq!(
+            max,
+            Features {
+                pointer_auth: true,
+                generic_auth: true,
+                branch_targets: true
+            }
+        );
+        assert_eq!(
+            max.sctlr_bits(),
+            SCTLR_ENABLE_IA | SCTLR_ENABLE_IB | SCTLR_ENABLE_DA | SCTLR_ENABLE_DB | SCTLR_BT0
+        );
+
+        // QARMA3 is reported in ID_AA64ISAR2_EL1
+        let qarma3 = Features::from_id_registers(0, 0x1100, 0);
+        assert!(qarma3.pointer_auth && qarma3.generic_auth && !qarma3.branch_targets);
+        assert_eq!(qarma3.sctlr_bits() & SCTLR_BT0, 0);
+    }
+
+    #[test]
+    fn strip_signed_return_address() {
+        assert_eq!(
+            strip_kernel_pointer(0x5aab_0000_0812_3454),
+            0xffff_0000_0812_3454
+        );
+        assert_eq!(
+            strip_kernel_pointer(0xffff_0000_0008_1000),
+            0xffff_0000_0008_1000
+        );
+        assert_eq!(strip_kernel_pointer(0), 0);
+    }
+
+    #[test]
+    fn keys_drawn_in_order() {
+        let mut n = 0;
+        let keys = Keys::random(|| {
+            n += 1;
+            n
+        });
+        assert_eq!(keys.instruction_a, Key { lo: 1, hi: 2 });
+        assert_eq!(keys.generic, Key { lo: 9, hi: 10 });
+    }
+}
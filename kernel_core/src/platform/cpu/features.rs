@@ -0,0 +1,341 @@
+//! Detection of the optional architecture features a core implements, from its ID registers.
+//!
+//! The kernel reads the ID registers of the boot core once, and every other core is assumed to
+//! match it. Optional subsystems ask [`Features`] whether they can be turned on.
+use core::fmt;
+
+use crate::{memory::PageSize, platform::branch_protection};
+
+/// The raw values of the ID registers of a core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IdRegisters {
+    /// `MIDR_EL1`, which identifies the implementer and part number of the core.
+    pub midr: u64,
+    /// `ID_AA64ISAR0_EL1`, the instruction set attributes for atomics, cryptography and CRC32.
+    pub isar0: u64,
+    /// `ID_AA64ISAR1_EL1`, the instruction set attributes for pointer authentication.
+    pub isar1: u64,
+    /// `ID_AA64ISAR2_EL1`, further instruction set attributes. Zero on cores that predate it.
+    pub isar2: u64,
+    /// `ID_AA64MMFR0_EL1`, the memory model: address size, ASID size and translation granules.
+    pub mmfr0: u64,
+    /// `ID_AA64MMFR1_EL1`, the memory model: access flag and dirty state management, PAN.
+    pub mmfr1: u64,
+    /// `ID_AA64MMFR2_EL1`, the memory model: virtual address size and common not private translations.
+    pub mmfr2: u64,
+    /// `ID_AA64PFR0_EL1`, the processor features: floating point, SVE and speculation safety.
+    pub pfr0: u64,
+    /// `ID_AA64PFR1_EL1`, the processor features: BTI, SSBS and memory tagging.
+    pub pfr1: u64,
+}
+
+/// Extract the 4-bit ID register field at `shift`.
+fn field(value: u64, shift: u32) -> u64 {
+    (value >> shift) & 0xf
+}
+
+/// The features a core implements, decoded from its [`IdRegisters`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features {
+    registers: IdRegisters,
+}
+
+/// The name of an optional feature, and a test for whether it is implemented.
+type Flag = (&'static str, fn(&Features) -> bool);
+
+/// The optional features listed in the summary.
+const FLAGS: &[Flag] = &[
+    ("fp", Features::floating_point),
+    ("lse", Features::large_system_extensions),
+    ("crc32", Features::crc32),
+    ("aes", Features::aes),
+    ("sha2", Features::sha2),
+    ("rng", Features::random_numbers),
+    ("pan", Features::privileged_access_never),
+    ("haf", Features::hardware_access_flag),
+    ("hdbs", Features::hardware_dirty_state),
+    ("cnp", Features::common_not_private),
+    ("sve", Features::scalable_vectors),
+    ("mte", Features::memory_tagging),
+    ("ssbs", Features::speculative_store_bypass_safe),
+    ("csv2", Features::spectre_v2_safe),
+    ("csv3", Features::meltdown_safe),
+    ("pac", Features::pointer_auth),
+    ("bti", Features::branch_targets),
+];
+
+impl Features {
+    /// Decode the features from the ID registers of a core.
+    #[must_use]
+    pub fn from_id_registers(registers: IdRegisters) -> Self {
+        Self { registers }
+    }
+
+    /// The ID registers the features were decoded from.
+    #[must_use]
+    pub fn id_registers(&self) -> &IdRegisters {
+        &self.registers
+    }
+
+    /// The branch protection features, which gate pointer authentication and BTI.
+    #[must_use]
+    pub fn branch_protection(&self) -> branch_protection::Features {
+        branch_protection::Features::from_id_registers(
+            self.registers.isar1,
+            self.registers.isar2,
+            self.registers.pfr1,
+        )
+    }
+
+    /// Pointers can be signed and authenticated.
+    #[must_use]
+    pub fn pointer_auth(&self) -> bool {
+        self.branch_protection().pointer_auth
+    }
+
+    /// Indirect branches into guarded pages must land on a `BTI` instruction.
+    #[must_use]
+    pub fn branch_targets(&self) -> bool {
+        self.branch_protection().branch_targets
+    }
+
+    /// The number of bits in a physical address.
+    #[must_use]
+    pub fn physical_address_bits(&self) -> u8 {
+        match field(self.registers.mmfr0, 0) {
+            0 => 32,
+            1 => 36,
+            2 => 40,
+            3 => 42,
+            4 => 44,
+            5 => 48,
+            _ => 52,
+        }
+    }
+
+    /// The largest number of bits in a virtual address (with the 64KiB granule).
+    #[must_use]
+    pub fn virtual_address_bits(&self) -> u8 {
+        if field(self.registers.mmfr2, 16) == 0 {
+            48
+        } else {
+            52
+        }
+    }
+
+    /// The number of bits in an address space ID.
+    #[must_use]
+    pub fn asid_bits(&self) -> u32 {
+        if field(self.registers.mmfr0, 4) == 2 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// True if page tables can use pages of size `page_size`.
+    #[must_use]
+    pub fn supports_page_size(&self, page_size: PageSize) -> bool {
+        match page_size {
+            PageSize::FourKiB => field(self.registers.mmfr0, 28) != 0xf,
+            PageSize::SixteenKiB => field(self.registers.mmfr0, 20) != 0,
+        }
+    }
+
+    /// True if page tables can use 64KiB pages, which the kernel does not support itself.
+    #[must_use]
+    pub fn supports_64kib_pages(&self) -> bool {
+        field(self.registers.mmfr0, 24) != 0xf
+    }
+
+    /// Floating point and Advanced SIMD instructions are implemented.
+    #[must_use]
+    pub fn floating_point(&self) -> bool {
+        field(self.registers.pfr0, 16) != 0xf && field(self.registers.pfr0, 20) != 0xf
+    }
+
+    /// The large system extensions' atomic instructions (`CAS`, `LDADD`, ...) are implemented.
+    #[must_use]
+    pub fn large_system_extensions(&self) -> bool {
+        field(self.registers.isar0, 20) >= 2
+    }
+
+    /// The `CRC32` instructions are implemented.
+    #[must_use]
+    pub fn crc32(&self) -> bool {
+        field(self.registers.isar0, 16) != 0
+    }
+
+    /// The AES instructions are implemented.
+    #[must_use]
+    pub fn aes(&self) -> bool {
+        field(self.registers.isar0, 4) != 0
+    }
+
+    /// The SHA-256 instructions are implemented.
+    #[must_use]
+    pub fn sha2(&self) -> bool {
+        field(self.registers.isar0, 12) != 0
+    }
+
+    /// The `RNDR` and `RNDRRS` random number registers are implemented.
+    #[must_use]
+    pub fn random_numbers(&self) -> bool {
+        field(self.registers.isar0, 60) != 0
+    }
+
+    /// Privileged access never (`PSTATE.PAN`) is implemented.
+    #[must_use]
+    pub fn privileged_access_never(&self) -> bool {
+        field(self.registers.mmfr1, 20) != 0
+    }
+
+    /// The hardware sets the access flag in page table entries.
+    #[must_use]
+    pub fn hardware_access_flag(&self) -> bool {
+        field(self.registers.mmfr1, 0) != 0
+    }
+
+    /// The hardware manages the dirty state of page table entries.
+    #[must_use]
+    pub fn hardware_dirty_state(&self) -> bool {
+        field(self.registers.mmfr1, 0) >= 2
+    }
+
+    /// Translation table entries can be shared between cores (`TTBRn_EL1.CnP`).
+    #[must_use]
+    pub fn common_not_private(&self) -> bool {
+        field(self.registers.mmfr2, 0) != 0
+    }
+
+    /// The scalable vector extension is implemented.
+    #[must_use]
+    pub fn scalable_vectors(&self) -> bool {
+        field(self.registers.pfr0, 32) != 0
+    }
+
+    /// Memory tagging is implemented.
+    #[must_use]
+    pub fn memory_tagging(&self) -> bool {
+        field(self.registers.pfr1, 8) != 0
+    }
+
+    /// Speculative store bypass can be prevented with `PSTATE.SSBS`.
+    #[must_use]
+    pub fn speculative_store_bypass_safe(&self) -> bool {
+        field(self.registers.pfr1, 4) != 0
+    }
+
+    /// Branch targets trained in one context can't be used speculatively in another (Spectre v2).
+    #[must_use]
+    pub fn spectre_v2_safe(&self) -> bool {
+        field(self.registers.pfr0, 56) != 0
+    }
+
+    /// Loads that fail a permission check never pass data to speculative execution (Meltdown).
+    #[must_use]
+    pub fn meltdown_safe(&self) -> bool {
+        field(self.registers.pfr0, 60) != 0
+    }
+
+    /// The names of the optional features that are implemented.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        FLAGS
+            .iter()
+            .filter(|(_, implemented)| implemented(self))
+            .map(|(name, _)| *name)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let midr = self.registers.midr;
+        write!(
+            f,
+            "implementer {:#x} part {:#x} r{}p{}, {}-bit PA, {}-bit VA, {}-bit ASID, pages:",
+            (midr >> 24) & 0xff,
+            (midr >> 4) & 0xfff,
+            field(midr, 20),
+            field(midr, 0),
+            self.physical_address_bits(),
+            self.virtual_address_bits(),
+            self.asid_bits(),
+        )?;
+        for (size, name) in [(PageSize::FourKiB, "4K"), (PageSize::SixteenKiB, "16K")] {
+            if self.supports_page_size(size) {
+                write!(f, " {name}")?;
+            }
+        }
+        if self.supports_64kib_pages() {
+            write!(f, " 64K")?;
+        }
+        write!(f, ", features:")?;
+        for name in self.names() {
+            write!(f, " {name}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    /// ID register values reported by a Cortex-A72 (r0p3).
+    const CORTEX_A72: IdRegisters = IdRegisters {
+        midr: 0x410f_d083,
+        isar0: 0x0001_1120,
+        isar1: 0,
+        isar2: 0,
+        mmfr0: 0x0000_1124,
+        mmfr1: 0,
+        mmfr2: 0,
+        pfr0: 0x0000_0000_0000_2222,
+        pfr1: 0,
+    };
+
+    #[test]
+    fn decode_cortex_a72() {
+        let features = Features::from_id_registers(CORTEX_A72);
+        assert_eq!(features.physical_address_bits(), 44);
+        assert_eq!(features.virtual_address_bits(), 48);
+        assert_eq!(features.asid_bits(), 16);
+        assert!(features.supports_page_size(PageSize::FourKiB));
+        assert!(!features.supports_page_size(PageSize::SixteenKiB));
+        assert!(features.supports_64kib_pages());
+        assert!(!features.large_system_extensions());
+        assert!(!features.pointer_auth());
+        assert_eq!(
+            features.to_string(),
+            "implementer 0x41 part 0xd08 r0p3, 44-bit PA, 48-bit VA, 16-bit ASID, pages: 4K 64K, \
+             features: fp crc32 aes sha2"
+        );
+    }
+
+    #[test]
+    fn decode_optional_features() {
+        let features = Features::from_id_registers(IdRegisters {
+            isar0: (1 << 60) | (2 << 20),
+            isar1: 0x50,
+            mmfr0: (0xf << 28) | (1 << 20) | (0xf << 24) | 6,
+            mmfr1: 2,
+            mmfr2: 1 << 16,
+            pfr0: (1 << 60) | (0xf << 16) | (0xf << 20),
+            pfr1: 1,
+            ..IdRegisters::default()
+        });
+        assert!(!features.supports_page_size(PageSize::FourKiB));
+        assert!(features.supports_page_size(PageSize::SixteenKiB));
+        assert!(!features.supports_64kib_pages());
+        assert_eq!(features.physical_address_bits(), 52);
+        assert_eq!(features.virtual_address_bits(), 52);
+        assert_eq!(features.asid_bits(), 8);
+        assert!(features.branch_protection().pointer_auth);
+        assert_eq!(
+            features.names().collect::<alloc::vec::Vec<_>>(),
+            ["lse", "rng", "haf", "hdbs", "csv3", "pac", "bti"]
+        );
+    }
+}
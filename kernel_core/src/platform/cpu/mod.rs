@@ -0,0 +1,500 @@
+//! CPU management
+
+mod features;
+mod topology;
+pub use features::{Features, IdRegisters};
+pub use topology::{Placement, Topology, TopologyError, MAX_TOPOLOGY_CORES};
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use log::{debug, info, warn};
+
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use crate::{
+    memory::{PhysicalAddress, VirtualAddress},
+    platform::{
+        device_tree::{DeviceTree, NodeNotFoundSnafu, OwnedParseError},
+        power::{PowerManager, PowerManagerError},
+    },
+    time::{CounterReader, Ticks},
+};
+
+/// A unique identifier for a single CPU core.
+pub type Id = usize;
+
+/// Fetches the current CPU [`Id`].
+pub trait CpuIdReader: Sync {
+    /// The current CPU [`Id`] that is executing.
+    fn current_cpu() -> Id;
+}
+
+/// Errors that can occur during SMP bring-up.
+#[derive(Debug, Snafu)]
+pub enum BootAllCoresError {
+    /// An "enable-method" was given that is unsupported.
+    #[snafu(display("Unsupported CPU enable method: {method}"))]
+    UnsupportedEnableMethod {
+        /// The method that we don't support.
+        method: alloc::string::String,
+    },
+    /// Error occured actually starting a core.
+    Power {
+        /// Underlying error.
+        source: PowerManagerError,
+    },
+    /// Failed to allocate stack for new core thread.
+    Memory {
+        /// Underlying error.
+        source: crate::memory::Error,
+    },
+    /// The core was started, but did not report that it was online before the timeout.
+    #[snafu(display("Core did not come online within {timeout} ticks"))]
+    Timeout {
+        /// The number of counter ticks waited.
+        timeout: Ticks,
+    },
+}
+
+/// How far a core has got in booting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BootStatus {
+    /// The core has not been started.
+    Off,
+    /// The core has been started, but has not reported that it is running yet.
+    Starting,
+    /// The core is running the kernel.
+    Online,
+    /// The core could not be started, or did not come online in time.
+    Failed,
+}
+
+impl BootStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => BootStatus::Off,
+            1 => BootStatus::Starting,
+            2 => BootStatus::Online,
+            _ => BootStatus::Failed,
+        }
+    }
+}
+
+/// The boot status of every core in the system.
+///
+/// Only cores that are [`BootStatus::Online`] run threads.
+pub struct CoreSet {
+    cores: Vec<(Id, AtomicU8)>,
+}
+
+impl CoreSet {
+    /// Create a set of cores with the ids `ids`, none of which have been started.
+    pub fn new(ids: impl IntoIterator<Item = Id>) -> Self {
+        Self {
+            cores: ids
+                .into_iter()
+                .map(|id| (id, AtomicU8::new(BootStatus::Off as u8)))
+                .collect(),
+        }
+    }
+
+    fn get(&self, id: Id) -> Option<&AtomicU8> {
+        self.cores
+            .iter()
+            .find_map(|(core, status)| (*core == id).then_some(status))
+    }
+
+    /// The index of core `id` in the order the set was created with, or `None` if there is no
+    /// such core.
+    #[must_use]
+    pub fn index(&self, id: Id) -> Option<usize> {
+        self.cores.iter().position(|(core, _)| *core == id)
+    }
+
+    /// The boot status of core `id`, or `None` if there is no such core.
+    #[must_use]
+    pub fn status(&self, id: Id) -> Option<BootStatus> {
+        self.get(id)
+            .map(|status| BootStatus::from_u8(status.load(Ordering::Acquire)))
+    }
+
+    /// Change the status of core `id` from `from` to `to`, returning false if it was not `from`.
+    fn transition(&self, id: Id, from: BootStatus, to: BootStatus) -> bool {
+        self.get(id).is_some_and(|status| {
+            status
+                .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })
+    }
+
+    /// Record that core `id` is running the kernel. Called by each core as soon as it starts.
+    ///
+    /// Returns false if the core was already given up on because it took too long to start, in
+    /// which case it must stop as soon as possible. Its stack is never freed, since it may be
+    /// running on it.
+    #[must_use]
+    pub fn report_online(&self, id: Id) -> bool {
+        self.transition(id, BootStatus::Starting, BootStatus::Online)
+            || self.transition(id, BootStatus::Off, BootStatus::Online)
+    }
+
+    /// True if core `id` is running the kernel.
+    #[must_use]
+    pub fn is_online(&self, id: Id) -> bool {
+        self.status(id) == Some(BootStatus::Online)
+    }
+
+    /// The ids of the cores that are running the kernel.
+    pub fn online(&self) -> impl Iterator<Item = Id> + '_ {
+        self.cores
+            .iter()
+            .filter(|(id, _)| self.is_online(*id))
+            .map(|(id, _)| *id)
+    }
+}
+
+use alloc::vec::Vec;
+
+/// Information about a core from the device tree.
+pub struct CoreInfo<'dt> {
+    /// The CPU ID of the core.
+    pub id: Id,
+    /// The method required to enable the core at boot.
+    pub enable_method: &'dt [u8],
+}
+
+/// List all CPU cores in the system as described by the device tree.
+///
+/// # Errors
+/// Returns a device tree parse error if the device tree has missing or invalid information.
+pub fn list_cores<'a, 'dt: 'a>(
+    device_tree: &'dt DeviceTree<'a>,
+) -> Result<Vec<CoreInfo<'a>>, OwnedParseError> {
+    let cpu_nodes = device_tree
+        .iter_nodes_named(b"/cpus", b"cpu")
+        .context(NodeNotFoundSnafu { path: "/cpus/cpu*" })
+        .map_err(super::device_tree::ParseError::to_owned)?;
+
+    let mut cpus = Vec::new();
+
+    for cpu_node in cpu_nodes {
+        let mut cpu_id = None;
+        let mut enable_method = None;
+
+        for (name, value) in cpu_node.properties {
+            match name {
+                b"enable-method" => {
+                    enable_method = Some(
+                        value
+                            .as_bytes(name)
+                            .map_err(super::device_tree::ParseError::to_owned)?,
+                    );
+                }
+                b"reg" => {
+                    let ids = value
+                        .as_reg(name)
+                        .map_err(super::device_tree::ParseError::to_owned)?;
+                    let id = ids
+                        .iter()
+                        .next()
+                        .map(|(a, _)| a)
+                        .ok_or(OwnedParseError::PropertyNotFound { name: "reg" })?;
+                    cpu_id = Some(id);
+                }
+                _ => {}
+            }
+        }
+
+        let cpu_id = cpu_id.ok_or(OwnedParseError::PropertyNotFound { name: "reg" })?;
+        let enable_method = enable_method.ok_or(OwnedParseError::PropertyNotFound {
+            name: "enable-method",
+        })?;
+        cpus.push(CoreInfo {
+            id: cpu_id,
+            enable_method,
+        });
+    }
+
+    Ok(cpus)
+}
+
+/// Start core `id`, calling `allocate_stack` to allocate its stack.
+fn start_core<PM: PowerManager>(
+    CoreInfo { id, enable_method }: &CoreInfo,
+    power: &PM,
+    entry_point_address: PhysicalAddress,
+    allocate_stack: impl FnOnce(Id) -> Result<VirtualAddress, crate::memory::Error>,
+    free_stack: impl FnOnce(Id),
+) -> Result<(), BootAllCoresError> {
+    ensure!(
+        *enable_method == PM::enable_method_name(),
+        UnsupportedEnableMethodSnafu {
+            method: core::str::from_utf8(enable_method).unwrap_or("unknown")
+        }
+    );
+
+    let stack = allocate_stack(*id).context(MemorySnafu)?;
+
+    debug!("starting cpu@{id:x}, stack@{stack:?}");
+
+    let result = unsafe { power.start_core(*id, entry_point_address, stack.into()) };
+    if result.is_err() {
+        free_stack(*id);
+    }
+    result.context(PowerSnafu)
+}
+
+/// Power on all cores, calling `allocate_stack` to allocate the stack for each one, and wait up to
+/// `timeout` counter ticks for them to report that they are online in `status`.
+/// The `cores` slice is a list of `(CPU id, enable method)` pairs, as returned by [`list_cores()`].
+/// The stack allocator returns the initial stack pointer for the core, at the top of its stack.
+///
+/// A core that fails to start or does not come online in time is marked [`BootStatus::Failed`]
+/// and the system carries on without it. The stack of a core that failed to start is released with
+/// `free_stack`, but the stack of a core that timed out is kept forever, since the core may still
+/// be running on it.
+///
+/// Returns the cores that failed and why. See [`BootAllCoresError`] for details.
+pub fn boot_all_cores<PM: PowerManager, C: CounterReader>(
+    cores: &[CoreInfo],
+    status: &CoreSet,
+    power: &PM,
+    entry_point_address: PhysicalAddress,
+    timeout: Ticks,
+    mut allocate_stack: impl FnMut(Id) -> Result<VirtualAddress, crate::memory::Error>,
+    mut free_stack: impl FnMut(Id),
+) -> Vec<(Id, BootAllCoresError)> {
+    let mut failures = Vec::new();
+
+    for core in cores {
+        if core.id == 0 {
+            // this is the boot CPU that is currently running, it doesn't need to be started.
+            status.transition(core.id, BootStatus::Off, BootStatus::Online);
+            continue;
+        }
+
+        status.transition(core.id, BootStatus::Off, BootStatus::Starting);
+        if let Err(e) = start_core(
+            core,
+            power,
+            entry_point_address,
+            &mut allocate_stack,
+            &mut free_stack,
+        ) {
+            warn!("failed to start cpu@{:x}: {e}", core.id);
+            status.transition(core.id, BootStatus::Starting, BootStatus::Failed);
+            failures.push((core.id, e));
+        }
+    }
+
+    let deadline = C::read().saturating_add(timeout);
+    let is_starting = |core: &CoreInfo| status.status(core.id) == Some(BootStatus::Starting);
+    while cores.iter().any(is_starting) && C::read() < deadline {
+        core::hint::spin_loop();
+    }
+
+    for core in cores {
+        // the core may still come online at the last moment, or be started but late, so its stack
+        // can't be freed either way
+        if status.transition(core.id, BootStatus::Starting, BootStatus::Failed) {
+            warn!("cpu@{:x} did not come online in time", core.id);
+            failures.push((core.id, BootAllCoresError::Timeout { timeout }));
+        }
+    }
+
+    info!(
+        "Started {} of {} secondary cores!",
+        cores.len() - 1 - failures.len(),
+        cores.len() - 1
+    );
+
+    failures
+}
+
+#[cfg(test)]
+/// Tests for the CPU module, and [`CpuIdReader`] stubs for tests elsewhere.
+pub mod tests {
+    use core::{cell::Cell, sync::atomic::AtomicU64};
+    use std::sync::Arc;
+
+    use mockall::predicate::{eq, function};
+
+    use crate::platform::power::MockPowerManager;
+
+    use super::*;
+
+    /// A [`CpuIdReader`] that always reports core `ID`.
+    pub struct FixedCpu<const ID: Id>;
+
+    impl<const ID: Id> CpuIdReader for FixedCpu<ID> {
+        fn current_cpu() -> Id {
+            ID
+        }
+    }
+
+    /// A [`CpuIdReader`] that reports the core last given to [`TestCpu::set`] on this test
+    /// thread, or core 0 if it has not been set.
+    pub struct TestCpu;
+
+    std::thread_local! {
+        static CURRENT: Cell<Id> = const { Cell::new(0) };
+    }
+
+    impl TestCpu {
+        /// Make the current test thread appear to run on `core`.
+        pub fn set(core: Id) {
+            CURRENT.set(core);
+        }
+    }
+
+    impl CpuIdReader for TestCpu {
+        fn current_cpu() -> Id {
+            CURRENT.get()
+        }
+    }
+
+    /// A counter that advances by one tick every time it is read.
+    struct SteppingCounter;
+
+    impl CounterReader for SteppingCounter {
+        fn read() -> Ticks {
+            static NOW: AtomicU64 = AtomicU64::new(0);
+            NOW.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    /// This test tree blob was generated using QEMU:
+    ///
+    /// ```bash
+    /// $ qemu-system-aarch64 -machine virt,dumpdtb=kernel_core/src/platform/device_tree/test-tree-smp8.fdt -smp 8
+    /// ```
+    ///
+    /// This blob should be identical to the [`TEST_TREE_BLOB`], but have 8 cores.
+    const TEST_TREE_BLOB_SMP8: &[u8] = include_bytes!("../device_tree/test-tree-smp8.fdt");
+
+    fn test_tree_smp8() -> DeviceTree<'static> {
+        DeviceTree::from_bytes(TEST_TREE_BLOB_SMP8)
+    }
+
+    #[test]
+    fn boot_cores() {
+        env_logger::init();
+
+        let dt = test_tree_smp8();
+        let mut stacks_allocated = alloc::vec::Vec::new();
+        let allocate_stack = |id| {
+            stacks_allocated.push(id);
+            Ok(VirtualAddress::from(
+                0xffff_0000_00ee_0000usize + 4 * 1024 * 1024,
+            ))
+        };
+
+        let epa: usize = 0xbeef_feed;
+        let mut pm = MockPowerManager::new();
+        let cx = MockPowerManager::enable_method_name_context();
+        cx.expect().return_const(b"psci\0" as &'static [u8]);
+        let cores = list_cores(&dt).expect("list CPU cores");
+        assert_eq!(cores.len(), 8);
+        let status = Arc::new(CoreSet::new(cores.iter().map(|c| c.id)));
+        for i in 1..8 {
+            let status = status.clone();
+            pm.expect_start_core()
+                .once()
+                .with(
+                    eq(i),
+                    function(move |x: &PhysicalAddress| usize::from(*x) == epa),
+                    eq(0xffff_0000_00ee_0000usize + 4 * 1024 * 1024),
+                )
+                .returning(move |id, _, _| {
+                    assert!(status.report_online(id));
+                    Ok(())
+                });
+        }
+
+        let failures = boot_all_cores::<_, SteppingCounter>(
+            &cores,
+            &status,
+            &pm,
+            epa.into(),
+            1000,
+            allocate_stack,
+            |_| panic!("no stacks should be freed"),
+        );
+        assert!(failures.is_empty());
+        assert_eq!(stacks_allocated, (1..8).collect::<alloc::vec::Vec<_>>());
+        assert_eq!(
+            status.online().collect::<Vec<_>>(),
+            (0..8).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn boot_cores_degraded() {
+        let cores: Vec<_> = (0..4)
+            .map(|id| CoreInfo {
+                id,
+                enable_method: b"psci\0",
+            })
+            .collect();
+        let status = Arc::new(CoreSet::new(cores.iter().map(|c| c.id)));
+        let mut stacks_freed = Vec::new();
+
+        let mut pm = MockPowerManager::new();
+        let cx = MockPowerManager::enable_method_name_context();
+        cx.expect().return_const(b"psci\0" as &'static [u8]);
+        let online = status.clone();
+        pm.expect_start_core()
+            .with(
+                eq(1),
+                function(|_: &PhysicalAddress| true),
+                function(|_: &usize| true),
+            )
+            .returning(move |id, _, _| {
+                assert!(online.report_online(id));
+                Ok(())
+            });
+        // core 2 is rejected by the firmware
+        pm.expect_start_core()
+            .with(
+                eq(2),
+                function(|_: &PhysicalAddress| true),
+                function(|_: &usize| true),
+            )
+            .returning(|_, _, _| Err(PowerManagerError::Denied));
+        // core 3 starts, but never reports that it is online
+        pm.expect_start_core()
+            .with(
+                eq(3),
+                function(|_: &PhysicalAddress| true),
+                function(|_: &usize| true),
+            )
+            .returning(|_, _, _| Ok(()));
+
+        let failures = boot_all_cores::<_, SteppingCounter>(
+            &cores,
+            &status,
+            &pm,
+            0x1000.into(),
+            100,
+            |_| Ok(VirtualAddress::from(0xffff_0000_00ee_0000usize)),
+            |id| stacks_freed.push(id),
+        );
+
+        assert!(matches!(
+            failures.as_slice(),
+            [
+                (2, BootAllCoresError::Power { .. }),
+                (3, BootAllCoresError::Timeout { timeout: 100 })
+            ]
+        ));
+        // core 3 may still be running on its stack
+        assert_eq!(stacks_freed, [2]);
+        assert_eq!(status.online().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(status.status(3), Some(BootStatus::Failed));
+
+        // a core that was given up on must not come online late
+        assert!(!status.report_online(3));
+        assert!(!status.is_online(3));
+    }
+}
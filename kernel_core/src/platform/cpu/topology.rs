@@ -0,0 +1,373 @@
+//! The arrangement of cores into sockets, clusters and physical cores, from the device tree's
+//! `/cpus/cpu-map` node.
+//!
+//! The `cpu-map` node contains a hierarchy of `socketN`, `clusterN`, `coreN` and `threadN` nodes,
+//! where the leaves refer to a `/cpus/cpu@*` node with their `cpu` property. Cores in the same
+//! cluster usually share a cache, so moving a thread between them is cheaper than moving it further
+//! away. See the kernel's `Documentation/devicetree/bindings/cpu/cpu-topology.txt` in Linux.
+//!
+//! The topology is read before the kernel heap is available, so it is held in a fixed size table.
+use byteorder::{BigEndian, ByteOrder as _};
+use snafu::Snafu;
+
+use super::Id;
+use crate::{
+    memory::page_table::Shareability,
+    platform::device_tree::{fdt::Token, DeviceTree},
+};
+
+/// The most cores a [`Topology`] can describe.
+pub const MAX_TOPOLOGY_CORES: usize = 64;
+
+/// The deepest nesting of nodes under `/cpus` that is understood.
+const MAX_DEPTH: usize = 8;
+
+/// Errors that can occur reading the topology from the device tree.
+#[derive(Debug, Snafu)]
+pub enum TopologyError {
+    /// The system has more than [`MAX_TOPOLOGY_CORES`] cores.
+    #[snafu(display("More than {MAX_TOPOLOGY_CORES} cores in the system"))]
+    TooManyCores,
+    /// A `cpu` node had no `reg` property.
+    #[snafu(display("CPU node without a \"reg\" property"))]
+    MissingReg,
+    /// An entry in the `cpu-map` referred to a node that is not a CPU.
+    #[snafu(display("cpu-map refers to unknown CPU node {phandle:#x}"))]
+    UnknownCpu {
+        /// The phandle in the `cpu` property.
+        phandle: u32,
+    },
+}
+
+/// Where a core sits in the system.
+///
+/// Sockets, clusters and physical cores are numbered in device tree order across the whole system,
+/// so two cores share a cluster exactly when their `cluster` numbers are equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Placement {
+    /// The socket the core is in.
+    pub socket: u16,
+    /// The innermost cluster the core is in.
+    pub cluster: u16,
+    /// The physical core, which is shared by hardware threads of the same core.
+    pub core: u16,
+}
+
+/// The `cpu` nodes and `cpu-map` leaves found under `/cpus`.
+struct Scan {
+    /// The (id, phandle) of each `cpu` node.
+    cpus: [(Id, Option<u32>); MAX_TOPOLOGY_CORES],
+    num_cpus: usize,
+    /// The (phandle of the `cpu` node, placement) of each leaf of the `cpu-map`.
+    leaves: [(u32, Placement); MAX_TOPOLOGY_CORES],
+    num_leaves: usize,
+    has_map: bool,
+    /// The next unused socket, cluster and core numbers.
+    next: Placement,
+}
+
+impl Scan {
+    /// Walk the `/cpus` node of the device tree without allocating.
+    fn read(device_tree: &DeviceTree<'_>) -> Result<Self, TopologyError> {
+        let mut scan = Self {
+            cpus: [(0, None); MAX_TOPOLOGY_CORES],
+            num_cpus: 0,
+            leaves: [(0, Placement::default()); MAX_TOPOLOGY_CORES],
+            num_leaves: 0,
+            has_map: false,
+            next: Placement::default(),
+        };
+
+        // the name and placement of each node on the current path below `/cpus`
+        let mut path: [(&[u8], Placement); MAX_DEPTH] = [(&[], Placement::default()); MAX_DEPTH];
+        let mut depth = 0usize;
+        let mut in_cpus = false;
+        let mut address_cells = 1;
+        let mut cpu_reg = None;
+        let mut cpu_phandle = None;
+
+        let mut tokens = device_tree.iter_structure();
+        while let Some(token) = tokens.next() {
+            match token {
+                Token::StartNode(name) if !in_cpus => {
+                    if depth == 1 && name == b"cpus" {
+                        in_cpus = true;
+                        depth = 0;
+                    } else if depth >= 1 {
+                        tokens.skip_node();
+                    } else {
+                        depth += 1;
+                    }
+                }
+                Token::StartNode(name) => {
+                    let parent = if depth == 0 {
+                        Placement::default()
+                    } else {
+                        path[(depth - 1).min(MAX_DEPTH - 1)].1
+                    };
+                    let mut placement = parent;
+                    let kind = name.split(u8::is_ascii_digit).next().unwrap_or(name);
+                    if depth > 0 && path[0].0 == b"cpu-map" {
+                        let next = match kind {
+                            b"socket" => Some((&mut placement.socket, &mut scan.next.socket)),
+                            b"cluster" => Some((&mut placement.cluster, &mut scan.next.cluster)),
+                            b"core" => Some((&mut placement.core, &mut scan.next.core)),
+                            _ => None,
+                        };
+                        if let Some((field, next)) = next {
+                            *field = *next;
+                            *next += 1;
+                        }
+                    }
+                    if depth == 0 {
+                        scan.has_map |= name == b"cpu-map";
+                        cpu_reg = None;
+                        cpu_phandle = None;
+                    }
+                    path[depth.min(MAX_DEPTH - 1)] = (name, placement);
+                    depth += 1;
+                }
+                Token::Property { name, data } if in_cpus => match (depth, name) {
+                    (0, b"#address-cells") if data.len() >= 4 => {
+                        address_cells = BigEndian::read_u32(data);
+                    }
+                    (1, b"reg") if path[0].0.starts_with(b"cpu@") || path[0].0 == b"cpu" => {
+                        cpu_reg = match (address_cells, data.len()) {
+                            (2, 8..) => usize::try_from(BigEndian::read_u64(data)).ok(),
+                            (_, 4..) => usize::try_from(BigEndian::read_u32(data)).ok(),
+                            _ => None,
+                        };
+                    }
+                    (1, b"phandle") if data.len() >= 4 => {
+                        cpu_phandle = Some(BigEndian::read_u32(data));
+                    }
+                    (2.., b"cpu") if path[0].0 == b"cpu-map" && data.len() >= 4 => {
+                        let placement = path[(depth - 1).min(MAX_DEPTH - 1)].1;
+                        *scan
+                            .leaves
+                            .get_mut(scan.num_leaves)
+                            .ok_or(TopologyError::TooManyCores)? =
+                            (BigEndian::read_u32(data), placement);
+                        scan.num_leaves += 1;
+                    }
+                    _ => {}
+                },
+                Token::EndNode if in_cpus => {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                    let name = path[depth.min(MAX_DEPTH - 1)].0;
+                    if depth == 0 && (name.starts_with(b"cpu@") || name == b"cpu") {
+                        let id = cpu_reg.take().ok_or(TopologyError::MissingReg)?;
+                        *scan
+                            .cpus
+                            .get_mut(scan.num_cpus)
+                            .ok_or(TopologyError::TooManyCores)? = (id, cpu_phandle.take());
+                        scan.num_cpus += 1;
+                    }
+                }
+                Token::EndNode => depth = depth.saturating_sub(1),
+                Token::Property { .. } => {}
+            }
+        }
+        Ok(scan)
+    }
+}
+
+/// The placement of every core in the system.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    cores: [(Id, Placement); MAX_TOPOLOGY_CORES],
+    len: usize,
+}
+
+impl Topology {
+    /// A topology where every core is its own physical core in a single cluster.
+    ///
+    /// Cores past the first [`MAX_TOPOLOGY_CORES`] are ignored.
+    pub fn flat(ids: impl IntoIterator<Item = Id>) -> Self {
+        let mut topology = Self::empty();
+        for (i, id) in ids.into_iter().take(MAX_TOPOLOGY_CORES).enumerate() {
+            let core = u16::try_from(i).unwrap_or(u16::MAX);
+            let _ = topology.push(
+                id,
+                Placement {
+                    core,
+                    ..Placement::default()
+                },
+            );
+        }
+        topology
+    }
+
+    fn empty() -> Self {
+        Self {
+            cores: [(0, Placement::default()); MAX_TOPOLOGY_CORES],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, id: Id, placement: Placement) -> Result<(), TopologyError> {
+        let slot = self
+            .cores
+            .get_mut(self.len)
+            .ok_or(TopologyError::TooManyCores)?;
+        *slot = (id, placement);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Read the topology from the `/cpus/cpu-map` node of the device tree.
+    ///
+    /// If there is no `cpu-map`, every core is assumed to be in the same cluster. Cores that are
+    /// missing from the map are each put in a cluster of their own.
+    ///
+    /// # Errors
+    /// - [`TopologyError::TooManyCores`] if there are more than [`MAX_TOPOLOGY_CORES`] cores.
+    /// - [`TopologyError::MissingReg`] if a `cpu` node has no id.
+    /// - [`TopologyError::UnknownCpu`] if the map refers to a node that is not a `cpu` node.
+    pub fn from_device_tree(device_tree: &DeviceTree<'_>) -> Result<Self, TopologyError> {
+        let scan = Scan::read(device_tree)?;
+        let cpus = &scan.cpus[..scan.num_cpus];
+        if !scan.has_map {
+            return Ok(Self::flat(cpus.iter().map(|(id, _)| *id)));
+        }
+
+        let mut topology = Self::empty();
+        for (phandle, placement) in &scan.leaves[..scan.num_leaves] {
+            let id = cpus
+                .iter()
+                .find_map(|(id, p)| (*p == Some(*phandle)).then_some(*id))
+                .ok_or(TopologyError::UnknownCpu { phandle: *phandle })?;
+            topology.push(id, *placement)?;
+        }
+        let mut next = scan.next;
+        for (id, _) in cpus {
+            if topology.placement(*id).is_none() {
+                topology.push(*id, next)?;
+                next.cluster += 1;
+                next.core += 1;
+            }
+        }
+        Ok(topology)
+    }
+
+    /// The placement of every core.
+    pub fn cores(&self) -> impl Iterator<Item = (Id, Placement)> + '_ {
+        self.cores[..self.len].iter().copied()
+    }
+
+    /// The placement of core `id`, if it is known.
+    #[must_use]
+    pub fn placement(&self, id: Id) -> Option<Placement> {
+        self.cores()
+            .find_map(|(core, placement)| (core == id).then_some(placement))
+    }
+
+    /// True if the cores `a` and `b` are in the same cluster.
+    #[must_use]
+    pub fn same_cluster(&self, a: Id, b: Id) -> bool {
+        match (self.placement(a), self.placement(b)) {
+            (Some(a), Some(b)) => a.cluster == b.cluster,
+            _ => false,
+        }
+    }
+
+    /// The number of distinct clusters.
+    #[must_use]
+    pub fn cluster_count(&self) -> usize {
+        let cores = &self.cores[..self.len];
+        cores
+            .iter()
+            .enumerate()
+            .filter(|(i, (_, p))| !cores[..*i].iter().any(|(_, q)| q.cluster == p.cluster))
+            .count()
+    }
+
+    /// The relative cost of moving a thread from core `from` to core `to`, in units of threads
+    /// waiting to run: moving further away loses more of the thread's cached state.
+    #[must_use]
+    pub fn migration_cost(&self, from: Id, to: Id) -> usize {
+        if from == to {
+            return 0;
+        }
+        match (self.placement(from), self.placement(to)) {
+            (Some(a), Some(b)) if a.cluster == b.cluster && a.core == b.core => 1,
+            (Some(a), Some(b)) if a.cluster == b.cluster => 2,
+            (Some(a), Some(b)) if a.socket == b.socket => 4,
+            _ => 8,
+        }
+    }
+
+    /// The shareability that memory used by every core needs: if all of the cores are in one
+    /// cluster, only the caches within the cluster need to be kept coherent. If no cores are known,
+    /// the memory is shared with the whole system to be safe.
+    #[must_use]
+    pub fn shareability(&self) -> Shareability {
+        if self.cluster_count() == 1 {
+            Shareability::Cluster
+        } else {
+            Shareability::Global
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_TREE_BLOB_SMP8: &[u8] = include_bytes!("../device_tree/test-tree-smp8.fdt");
+    const TEST_TREE_BLOB: &[u8] = include_bytes!("../device_tree/test-tree.fdt");
+
+    #[test]
+    fn single_cluster_from_device_tree() {
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB_SMP8);
+        let topology = Topology::from_device_tree(&dt).unwrap();
+        assert_eq!(topology.cores().count(), 8);
+        for (i, (id, placement)) in topology.cores().enumerate() {
+            assert_eq!(id, i);
+            assert_eq!(placement.cluster, 0);
+            assert_eq!(usize::from(placement.core), i);
+        }
+        assert_eq!(topology.cluster_count(), 1);
+        assert!(topology.same_cluster(1, 6));
+        assert_eq!(topology.migration_cost(1, 6), 2);
+        assert!(matches!(topology.shareability(), Shareability::Cluster));
+    }
+
+    #[test]
+    fn no_map_is_flat() {
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB);
+        let topology = Topology::from_device_tree(&dt).unwrap();
+        assert_eq!(topology.cores().count(), 1);
+        assert_eq!(topology.cluster_count(), 1);
+
+        let unknown = Topology::flat([]);
+        assert_eq!(unknown.cluster_count(), 0);
+        assert!(matches!(unknown.shareability(), Shareability::Global));
+    }
+
+    #[test]
+    fn migration_costs() {
+        let mut topology = Topology::empty();
+        let place = |socket, cluster, core| Placement {
+            socket,
+            cluster,
+            core,
+        };
+        topology.push(0, place(0, 0, 0)).unwrap();
+        topology.push(1, place(0, 0, 0)).unwrap();
+        topology.push(2, place(0, 0, 1)).unwrap();
+        topology.push(3, place(0, 1, 2)).unwrap();
+        topology.push(4, place(1, 2, 3)).unwrap();
+        assert_eq!(topology.migration_cost(0, 0), 0);
+        assert_eq!(topology.migration_cost(0, 1), 1);
+        assert_eq!(topology.migration_cost(0, 2), 2);
+        assert_eq!(topology.migration_cost(0, 3), 4);
+        assert_eq!(topology.migration_cost(0, 4), 8);
+        assert_eq!(topology.migration_cost(0, 99), 8);
+        assert_eq!(topology.cluster_count(), 3);
+        assert!(matches!(topology.shareability(), Shareability::Global));
+    }
+}
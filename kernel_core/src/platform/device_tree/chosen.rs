@@ -0,0 +1,280 @@
+//! Producing a modified device tree blob with updated `/chosen` properties.
+//!
+//! The kernel passes information on to user space (like where the initrd was relocated to) through
+//! the `/chosen` node, so that the rest of the system only has to understand one format.
+use alloc::vec::Vec;
+
+use super::{fdt, DeviceTree};
+
+/// Version of the blob format written by [`ChosenBuilder::build`].
+const BLOB_VERSION: u32 = 17;
+/// Oldest version of the format that the written blobs are backwards compatible with.
+const BLOB_LAST_COMP_VERSION: u32 = 16;
+
+// raw values of the structure block tokens (see [`fdt::TokenType`])
+const BEGIN_NODE: u32 = 0x01;
+const END_NODE: u32 = 0x02;
+const PROP: u32 = 0x03;
+const END: u32 = 0x09;
+
+fn pad_to_4(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Builds a copy of a device tree blob with properties under `/chosen` added or replaced.
+///
+/// Everything outside of `/chosen` (and any properties of `/chosen` that are not set) is copied
+/// unchanged from the original tree. If the original tree has no `/chosen` node, one is created.
+pub struct ChosenBuilder<'a> {
+    dt: &'a DeviceTree<'a>,
+    properties: Vec<(&'a [u8], Vec<u8>)>,
+}
+
+impl<'a> ChosenBuilder<'a> {
+    /// Create a builder that modifies `dt`.
+    #[must_use]
+    pub fn new(dt: &'a DeviceTree<'a>) -> Self {
+        Self {
+            dt,
+            properties: Vec::new(),
+        }
+    }
+
+    /// Set the property `name` under `/chosen` to the raw bytes in `value`.
+    #[must_use]
+    pub fn with_property(mut self, name: &'a [u8], value: &[u8]) -> Self {
+        if let Some((_, v)) = self.properties.iter_mut().find(|(n, _)| *n == name) {
+            v.clear();
+            v.extend_from_slice(value);
+        } else {
+            self.properties.push((name, value.to_vec()));
+        }
+        self
+    }
+
+    /// Set the property `name` under `/chosen` to a 32-bit integer.
+    #[must_use]
+    pub fn with_u32(self, name: &'a [u8], value: u32) -> Self {
+        self.with_property(name, &value.to_be_bytes())
+    }
+
+    /// Set the property `name` under `/chosen` to a 64-bit integer.
+    #[must_use]
+    pub fn with_u64(self, name: &'a [u8], value: u64) -> Self {
+        self.with_property(name, &value.to_be_bytes())
+    }
+
+    /// Set the property `name` under `/chosen` to a string. The NUL terminator is added for you.
+    #[must_use]
+    pub fn with_string(self, name: &'a [u8], value: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(value.len() + 1);
+        data.extend_from_slice(value);
+        data.push(0);
+        self.with_property(name, &data)
+    }
+
+    /// Produce the modified device tree blob.
+    #[must_use]
+    pub fn build(&self) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        let mut depth = 0;
+        let mut in_chosen = false;
+        let mut found_chosen = false;
+
+        for token in self.dt.iter_structure() {
+            match token {
+                fdt::Token::StartNode(name) => {
+                    depth += 1;
+                    if depth == 2 && name == b"chosen" {
+                        in_chosen = true;
+                        found_chosen = true;
+                    }
+                    write_begin_node(&mut structure, name);
+                }
+                fdt::Token::EndNode => {
+                    if in_chosen && depth == 2 {
+                        self.write_properties(&mut structure, &mut strings);
+                        in_chosen = false;
+                    } else if depth == 1 && !found_chosen {
+                        write_begin_node(&mut structure, b"chosen");
+                        self.write_properties(&mut structure, &mut strings);
+                        push_u32(&mut structure, END_NODE);
+                    }
+                    push_u32(&mut structure, END_NODE);
+                    depth -= 1;
+                }
+                fdt::Token::Property { name, data } => {
+                    if in_chosen && depth == 2 && self.properties.iter().any(|(n, _)| *n == name) {
+                        continue;
+                    }
+                    write_property(&mut structure, &mut strings, name, data);
+                }
+            }
+        }
+        push_u32(&mut structure, END);
+
+        self.assemble(&structure, &strings)
+    }
+
+    fn write_properties(&self, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+        for (name, data) in &self.properties {
+            write_property(structure, strings, name, data);
+        }
+    }
+
+    /// Lay out the final blob: header, memory reservation block, structure block, then strings.
+    #[allow(clippy::cast_possible_truncation)]
+    fn assemble(&self, structure: &[u8], strings: &[u8]) -> Vec<u8> {
+        let mem_map_offset = fdt::HEADER_SIZE.next_multiple_of(8);
+        let structure_offset = mem_map_offset + self.dt.mem_map.len();
+        let strings_offset = structure_offset + structure.len();
+        let total_size = strings_offset + strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            fdt::HEADER_EXPECTED_MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            mem_map_offset as u32,
+            BLOB_VERSION,
+            BLOB_LAST_COMP_VERSION,
+            self.dt.header().boot_cpuid_phys(),
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            push_u32(&mut blob, field);
+        }
+        blob.resize(mem_map_offset, 0);
+        // the reservation block includes its terminating entry
+        blob.extend_from_slice(self.dt.mem_map);
+        blob.extend_from_slice(structure);
+        blob.extend_from_slice(strings);
+        blob
+    }
+}
+
+fn write_begin_node(structure: &mut Vec<u8>, name: &[u8]) {
+    push_u32(structure, BEGIN_NODE);
+    structure.extend_from_slice(name);
+    structure.push(0);
+    pad_to_4(structure);
+}
+
+/// Find the offset of `name` in the strings block, adding it if it isn't already present.
+fn string_offset(strings: &mut Vec<u8>, name: &[u8]) -> usize {
+    let mut offset = 0;
+    for s in strings.split(|b| *b == 0) {
+        if s == name && offset + s.len() < strings.len() {
+            return offset;
+        }
+        offset += s.len() + 1;
+    }
+    let offset = strings.len();
+    strings.extend_from_slice(name);
+    strings.push(0);
+    offset
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_property(structure: &mut Vec<u8>, strings: &mut Vec<u8>, name: &[u8], data: &[u8]) {
+    let name_offset = string_offset(strings, name);
+    push_u32(structure, PROP);
+    push_u32(structure, data.len() as u32);
+    push_u32(structure, name_offset as u32);
+    structure.extend_from_slice(data);
+    pad_to_4(structure);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{format, vec::Vec};
+
+    use byteorder::{BigEndian, ByteOrder as _};
+
+    use super::*;
+    use crate::{
+        init::initrd_region,
+        memory::PhysicalAddress,
+        platform::device_tree::{StringList, Value},
+    };
+
+    const TEST_TREE_BLOB: &[u8] = include_bytes!("test-tree.fdt");
+
+    #[test]
+    fn set_chosen_properties() {
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB);
+        let blob = ChosenBuilder::new(&dt)
+            .with_u64(b"linux,initrd-start", 0x4800_0000)
+            .with_u64(b"linux,initrd-end", 0x4801_0000)
+            .with_u32(b"cavern,boot-core", 3)
+            .with_string(b"stdout-path", b"/somewhere-else")
+            .build();
+        let new_dt = DeviceTree::from_bytes(&blob);
+
+        assert_eq!(
+            initrd_region(&new_dt).unwrap(),
+            Some((PhysicalAddress::from(0x4800_0000), 0x1_0000))
+        );
+        match new_dt.find_property(b"/chosen/cavern,boot-core") {
+            Some(Value::Bytes(b)) => assert_eq!(BigEndian::read_u32(b), 3),
+            v => panic!("unexpected boot core value: {v:?}"),
+        }
+        // replaced properties only appear once
+        assert_eq!(
+            new_dt
+                .iter_node_properties(b"/chosen")
+                .unwrap()
+                .filter(|(name, _)| *name == b"stdout-path")
+                .count(),
+            1
+        );
+        assert_eq!(
+            new_dt
+                .find_property(b"/chosen/stdout-path")
+                .unwrap()
+                .as_bytes(b"stdout-path")
+                .unwrap(),
+            b"/somewhere-else\0"
+        );
+
+        // the rest of the tree is unchanged
+        match new_dt.find_property(b"/pl011@9000000/compatible") {
+            Some(Value::StringList(StringList { data })) => {
+                assert!(data.starts_with(b"arm,pl011"));
+            }
+            v => panic!("unexpected compatible value: {v:?}"),
+        }
+        let regs: Vec<_> = new_dt
+            .find_property(b"/pl011@9000000/reg")
+            .unwrap()
+            .into_reg()
+            .unwrap()
+            .iter()
+            .collect();
+        assert_eq!(regs, [(0x900_0000, 0x1000)]);
+        assert!(new_dt
+            .iter_reserved_memory_regions()
+            .eq(dt.iter_reserved_memory_regions()));
+        assert_eq!(
+            new_dt.header().boot_cpuid_phys(),
+            dt.header().boot_cpuid_phys()
+        );
+    }
+
+    #[test]
+    fn unchanged_tree_round_trips() {
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB);
+        let blob = ChosenBuilder::new(&dt).build();
+        let new_dt = DeviceTree::from_bytes(&blob);
+        assert!(new_dt
+            .iter_structure()
+            .map(|t| format!("{t:?}"))
+            .eq(dt.iter_structure().map(|t| format!("{t:?}"))));
+    }
+}
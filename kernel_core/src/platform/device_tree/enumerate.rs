@@ -0,0 +1,318 @@
+//! Device inventory for user space, built by walking the device tree.
+//!
+//! The init process needs to know which devices are present to spawn drivers for them, but
+//! user space does not parse the device tree itself. Instead, [`enumerate`] produces an
+//! inventory of every device in a stable binary format that can be handed to user space and read
+//! back with [`Inventory`].
+//!
+//! # Format
+//! All integers are little endian, and every record is aligned to 8 bytes.
+//!
+//! The inventory starts with a 16 byte header:
+//!
+//! | Offset | Size | Description |
+//! |--------|------|-------------|
+//! | 0  | 4 | Magic value [`INVENTORY_MAGIC`] |
+//! | 4  | 4 | Format version, currently [`INVENTORY_VERSION`] |
+//! | 8  | 4 | Number of device records |
+//! | 12 | 4 | Reserved, zero |
+//!
+//! Each device record then follows in tree order:
+//!
+//! | Offset | Size | Description |
+//! |--------|------|-------------|
+//! | 0  | 4 | Total length of the record in bytes, including padding |
+//! | 4  | 2 | Length of the node name in bytes |
+//! | 6  | 2 | Length of the `compatible` string list in bytes |
+//! | 8  | 2 | Number of `reg` entries |
+//! | 10 | 2 | Number of `interrupts` cells |
+//! | 12 | 4 | Reserved, zero |
+//! | 16 | .. | Node name, then the `compatible` strings (each NUL terminated), padded to 8 bytes |
+//! | .. | 16 each | `reg` entries as (u64 address, u64 length) |
+//! | .. | 4 each | `interrupts` cells, padded to 8 bytes |
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder as _, LittleEndian};
+use snafu::{ensure, OptionExt as _, Snafu};
+
+use super::{fdt, DeviceTree, Registers, StringList};
+
+/// Magic value at the start of every inventory (`"DEVI"`).
+pub const INVENTORY_MAGIC: u32 = u32::from_le_bytes(*b"DEVI");
+
+/// The current version of the inventory format.
+pub const INVENTORY_VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 16;
+const RECORD_HEADER_SIZE: usize = 16;
+
+/// Errors that can occur reading an inventory.
+#[derive(Debug, Snafu)]
+pub enum InventoryError {
+    /// The inventory header is missing or has the wrong magic value or version.
+    BadHeader,
+    /// A record extends past the end of the inventory.
+    Truncated,
+}
+
+/// A node in the tree that is being walked.
+#[derive(Default)]
+struct NodeState<'dt> {
+    name: &'dt [u8],
+    compatible: Option<&'dt [u8]>,
+    reg: Option<&'dt [u8]>,
+    interrupts: Option<&'dt [u8]>,
+    disabled: bool,
+    address_cells: Option<u32>,
+    size_cells: Option<u32>,
+    /// True once the node has been written to the inventory (or skipped).
+    done: bool,
+}
+
+fn pad_to_8(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(8), 0);
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_record(out: &mut Vec<u8>, node: &NodeState, parent: &NodeState) {
+    let start = out.len();
+    let compatible = node.compatible.unwrap_or_default();
+    let registers = node.reg.map(|data| Registers {
+        data,
+        // defaults from the spec in section 2.3.5
+        address_cells: parent.address_cells.unwrap_or(2),
+        size_cells: parent.size_cells.unwrap_or(1),
+    });
+    let num_registers = registers.as_ref().map_or(0, |r| r.iter().count());
+    let interrupts = node.interrupts.unwrap_or_default();
+
+    out.extend_from_slice(&[0; RECORD_HEADER_SIZE]);
+    LittleEndian::write_u16(&mut out[start + 4..], node.name.len() as u16);
+    LittleEndian::write_u16(&mut out[start + 6..], compatible.len() as u16);
+    LittleEndian::write_u16(&mut out[start + 8..], num_registers as u16);
+    LittleEndian::write_u16(&mut out[start + 10..], (interrupts.len() / 4) as u16);
+    out.extend_from_slice(node.name);
+    out.extend_from_slice(compatible);
+    pad_to_8(out);
+    for (address, length) in registers.iter().flat_map(Registers::iter) {
+        out.extend_from_slice(&(address as u64).to_le_bytes());
+        out.extend_from_slice(&(length as u64).to_le_bytes());
+    }
+    for cell in interrupts.chunks_exact(4) {
+        out.extend_from_slice(&BigEndian::read_u32(cell).to_le_bytes());
+    }
+    pad_to_8(out);
+    let length = (out.len() - start) as u32;
+    LittleEndian::write_u32(&mut out[start..], length);
+}
+
+/// Walk `dt` and produce an inventory of every device in it.
+///
+/// Every node with a `compatible` property (other than the root) is a device, unless its
+/// `status` is `"disabled"`. See the [module documentation](self) for the format.
+#[must_use]
+pub fn enumerate(dt: &DeviceTree) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0; HEADER_SIZE]);
+    LittleEndian::write_u32(&mut out[0..], INVENTORY_MAGIC);
+    LittleEndian::write_u32(&mut out[4..], INVENTORY_VERSION);
+    let mut count: u32 = 0;
+
+    // the root node is never a device, but provides the cells for its children
+    let mut stack: Vec<NodeState> = Vec::new();
+    let mut finish_node = |stack: &mut Vec<NodeState>, out: &mut Vec<u8>| {
+        let Some((node, ancestors)) = stack.split_last_mut() else {
+            return;
+        };
+        if node.done {
+            return;
+        }
+        node.done = true;
+        if let Some(parent) = ancestors.last() {
+            if node.compatible.is_some() && !node.disabled {
+                write_record(out, node, parent);
+                count += 1;
+            }
+        }
+    };
+    for token in dt.iter_structure() {
+        match token {
+            fdt::Token::StartNode(name) => {
+                // properties always come before child nodes, so the parent is complete
+                finish_node(&mut stack, &mut out);
+                stack.push(NodeState {
+                    name,
+                    ..NodeState::default()
+                });
+            }
+            fdt::Token::EndNode => {
+                finish_node(&mut stack, &mut out);
+                stack.pop();
+            }
+            fdt::Token::Property { name, data } => {
+                let Some(node) = stack.last_mut() else {
+                    continue;
+                };
+                match name {
+                    b"compatible" => node.compatible = Some(data),
+                    b"reg" => node.reg = Some(data),
+                    b"interrupts" => node.interrupts = Some(data),
+                    b"status" => node.disabled = data.starts_with(b"disabled"),
+                    b"#address-cells" => node.address_cells = Some(BigEndian::read_u32(data)),
+                    b"#size-cells" => node.size_cells = Some(BigEndian::read_u32(data)),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    LittleEndian::write_u32(&mut out[8..], count);
+    out
+}
+
+/// A device record in an [`Inventory`].
+#[derive(Debug, Clone)]
+pub struct DeviceRecord<'b> {
+    /// The name of the device's node in the tree.
+    pub name: &'b [u8],
+    /// The device's `compatible` strings.
+    pub compatible: StringList<'b>,
+    registers: &'b [u8],
+    interrupts: &'b [u8],
+}
+
+impl<'b> DeviceRecord<'b> {
+    /// Iterate over the (address, length) regions in the device's `reg` property.
+    pub fn registers(&self) -> impl Iterator<Item = (u64, u64)> + 'b {
+        self.registers
+            .chunks_exact(16)
+            .map(|r| (LittleEndian::read_u64(r), LittleEndian::read_u64(&r[8..])))
+    }
+
+    /// Iterate over the cells of the device's `interrupts` property.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> + 'b {
+        self.interrupts.chunks_exact(4).map(LittleEndian::read_u32)
+    }
+}
+
+/// A device inventory produced by [`enumerate`].
+#[derive(Debug, Clone)]
+pub struct Inventory<'b> {
+    count: usize,
+    records: &'b [u8],
+}
+
+impl<'b> Inventory<'b> {
+    /// Read the inventory header in `bytes`.
+    ///
+    /// # Errors
+    /// - [`InventoryError::BadHeader`] if the header is missing or has the wrong magic or version.
+    pub fn parse(bytes: &'b [u8]) -> Result<Self, InventoryError> {
+        ensure!(
+            bytes.len() >= HEADER_SIZE
+                && LittleEndian::read_u32(bytes) == INVENTORY_MAGIC
+                && LittleEndian::read_u32(&bytes[4..]) == INVENTORY_VERSION,
+            BadHeaderSnafu
+        );
+        Ok(Self {
+            count: LittleEndian::read_u32(&bytes[8..]) as usize,
+            records: &bytes[HEADER_SIZE..],
+        })
+    }
+
+    /// The number of devices in the inventory.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// True if there are no devices in the inventory.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Iterate over the device records in the inventory.
+    pub fn devices(&self) -> impl Iterator<Item = Result<DeviceRecord<'b>, InventoryError>> {
+        let mut rest = self.records;
+        (0..self.count).map(move |_| {
+            let (record, next) = Self::parse_record(rest)?;
+            rest = next;
+            Ok(record)
+        })
+    }
+
+    fn parse_record(bytes: &'b [u8]) -> Result<(DeviceRecord<'b>, &'b [u8]), InventoryError> {
+        ensure!(bytes.len() >= RECORD_HEADER_SIZE, TruncatedSnafu);
+        let length = LittleEndian::read_u32(bytes) as usize;
+        let name_len = LittleEndian::read_u16(&bytes[4..]) as usize;
+        let compatible_len = LittleEndian::read_u16(&bytes[6..]) as usize;
+        let num_registers = LittleEndian::read_u16(&bytes[8..]) as usize;
+        let num_interrupts = LittleEndian::read_u16(&bytes[10..]) as usize;
+        let record = bytes.get(..length).context(TruncatedSnafu)?;
+        let strings_end = RECORD_HEADER_SIZE + name_len + compatible_len;
+        let registers_start = strings_end.next_multiple_of(8);
+        let interrupts_start = registers_start + num_registers * 16;
+        let interrupts_end = interrupts_start + num_interrupts * 4;
+        ensure!(interrupts_end <= length, TruncatedSnafu);
+        Ok((
+            DeviceRecord {
+                name: &record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + name_len],
+                compatible: StringList {
+                    data: &record[RECORD_HEADER_SIZE + name_len..strings_end],
+                },
+                registers: &record[registers_start..interrupts_start],
+                interrupts: &record[interrupts_start..interrupts_end],
+            },
+            &bytes[length..],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    const TEST_TREE_BLOB: &[u8] = include_bytes!("test-tree.fdt");
+
+    #[test]
+    fn enumerate_test_tree() {
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB);
+        let bytes = enumerate(&dt);
+        assert_eq!(bytes.len() % 8, 0);
+        let inventory = Inventory::parse(&bytes).unwrap();
+        let devices: Vec<_> = inventory.devices().collect::<Result<_, _>>().unwrap();
+        assert_eq!(devices.len(), inventory.len());
+
+        let uart = devices
+            .iter()
+            .find(|d| d.name == b"pl011@9000000")
+            .expect("UART in inventory");
+        assert!(uart.compatible.contains(b"arm,pl011"));
+        assert_eq!(uart.registers().collect::<Vec<_>>(), [(0x900_0000, 0x1000)]);
+        assert_eq!(uart.interrupts().collect::<Vec<_>>(), [0, 1, 4]);
+
+        // nested devices are included too
+        assert!(devices.iter().any(|d| d.name == b"v2m@8020000"));
+        // nodes without a compatible property are not devices
+        assert!(!devices
+            .iter()
+            .any(|d| d.name == b"cpus" || d.name == b"chosen"));
+    }
+
+    #[test]
+    fn reject_bad_inventory() {
+        assert!(matches!(
+            Inventory::parse(b"not an inventory"),
+            Err(InventoryError::BadHeader)
+        ));
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB);
+        let bytes = enumerate(&dt);
+        let inventory = Inventory::parse(&bytes[..HEADER_SIZE + 20]).unwrap();
+        assert!(matches!(
+            inventory.devices().next(),
+            Some(Err(InventoryError::Truncated))
+        ));
+    }
+}
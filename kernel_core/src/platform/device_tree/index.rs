@@ -0,0 +1,117 @@
+//! Index of node locations in the tree, to avoid re-scanning the blob for every lookup.
+//!
+//! The index needs the heap, so it is built on request with [`super::DeviceTree::build_index`]
+//! once allocation is available. Before then, lookups fall back to walking the tree.
+use alloc::{collections::BTreeMap, vec::Vec};
+use byteorder::{BigEndian, ByteOrder as _};
+
+use super::{fdt, iter, DeviceTree};
+
+/// Where a node starts in the structure block, and the cells needed to interpret its properties.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct NodeLocation {
+    /// Offset of the node's first property, just after its begin token and name.
+    pub offset: usize,
+    pub parent_address_cells: u32,
+    pub parent_size_cells: u32,
+}
+
+impl NodeLocation {
+    pub fn properties<'dt>(&self, dt: &'dt DeviceTree<'dt>) -> iter::NodePropertyIter<'dt> {
+        iter::NodePropertyIter {
+            cur: iter::FlattenedTreeIter {
+                dt,
+                current_offset: self.offset,
+            },
+            depth: 1,
+            parent_address_cells: self.parent_address_cells,
+            parent_size_cells: self.parent_size_cells,
+        }
+    }
+}
+
+/// Maps node paths and phandles to the node's location in the structure block.
+pub struct TreeIndex {
+    paths: BTreeMap<Vec<u8>, NodeLocation>,
+    phandles: BTreeMap<u32, NodeLocation>,
+}
+
+impl TreeIndex {
+    /// Build an index of every node in `dt`.
+    pub(super) fn new(dt: &DeviceTree) -> Self {
+        let mut paths = BTreeMap::new();
+        let mut phandles = BTreeMap::new();
+        // (path length before this node, cells defined by this node or inherited, node location)
+        let mut stack: Vec<(usize, Option<u32>, Option<u32>, NodeLocation)> = Vec::new();
+        let mut path: Vec<u8> = Vec::new();
+        let mut tokens = dt.iter_structure();
+        while let Some(token) = tokens.next() {
+            match token {
+                fdt::Token::StartNode(name) => {
+                    let (address_cells, size_cells) =
+                        stack.last().map_or((None, None), |(_, a, s, _)| (*a, *s));
+                    let location = NodeLocation {
+                        offset: tokens.current_offset,
+                        // defaults from the spec in section 2.3.5
+                        parent_address_cells: address_cells.unwrap_or(2),
+                        parent_size_cells: size_cells.unwrap_or(1),
+                    };
+                    let parent_len = path.len();
+                    if path.last() != Some(&b'/') {
+                        path.push(b'/');
+                    }
+                    path.extend_from_slice(name);
+                    paths.insert(path.clone(), location);
+                    stack.push((parent_len, address_cells, size_cells, location));
+                }
+                fdt::Token::EndNode => {
+                    if let Some((parent_len, ..)) = stack.pop() {
+                        path.truncate(parent_len);
+                    }
+                }
+                fdt::Token::Property { name, data } if data.len() >= 4 => {
+                    let Some((_, address_cells, size_cells, location)) = stack.last_mut() else {
+                        continue;
+                    };
+                    let value = BigEndian::read_u32(data);
+                    match name {
+                        b"phandle" => {
+                            phandles.insert(value, *location);
+                        }
+                        b"#address-cells" => *address_cells = Some(value),
+                        b"#size-cells" => *size_cells = Some(value),
+                        _ => {}
+                    }
+                }
+                fdt::Token::Property { .. } => {}
+            }
+        }
+        Self { paths, phandles }
+    }
+
+    /// Look up the node at `path`, ignoring any trailing `/`.
+    pub(super) fn node_at(&self, path: &[u8]) -> Option<NodeLocation> {
+        let path = match path.strip_suffix(b"/") {
+            Some(p) if !p.is_empty() => p,
+            _ => path,
+        };
+        self.paths.get(path).copied()
+    }
+
+    /// Look up the node with `phandle`.
+    pub(super) fn node_with_phandle(&self, phandle: u32) -> Option<NodeLocation> {
+        self.phandles.get(&phandle).copied()
+    }
+
+    /// The number of nodes in the index.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// True if the index contains no nodes.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
@@ -0,0 +1,385 @@
+//! Resolving device interrupts through the interrupt hierarchy.
+//!
+//! The `interrupts` property of a device only makes sense relative to its interrupt parent, which
+//! is given by the nearest `interrupt-parent` phandle on the path to the device. The parent may be
+//! an interrupt controller, or an interrupt nexus (like a PCI host bridge) that translates the
+//! specifier through its `interrupt-map` to a specifier for another parent. See section 2.4 of the
+//! specification.
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder as _};
+use log::trace;
+use snafu::{ensure, OptionExt as _, Snafu};
+
+use super::{fdt, DeviceTree};
+
+/// The maximum number of parents an interrupt can be routed through before it is assumed that the
+/// hierarchy contains a cycle.
+const MAX_DEPTH: usize = 16;
+
+/// Errors that can occur resolving interrupts.
+#[derive(Debug, Snafu)]
+pub enum InterruptError {
+    /// The node was not found in the tree.
+    NodeNotFound,
+    /// No node in the tree has the phandle.
+    UnknownPhandle {
+        /// The phandle that was referenced.
+        phandle: u32,
+    },
+    /// The node has no interrupt parent.
+    NoInterruptParent,
+    /// The interrupt parent is missing the `#interrupt-cells` property.
+    MissingInterruptCells,
+    /// A property's length is not a whole number of entries.
+    Malformed {
+        /// The name of the malformed property.
+        property: &'static str,
+    },
+    /// No entry in a nexus's `interrupt-map` matched the interrupt.
+    NoMapEntry,
+    /// The interrupt was routed through more than [`MAX_DEPTH`] parents.
+    TooDeep,
+}
+
+/// A fully resolved interrupt: a specifier in the format of the interrupt controller it refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedInterrupt {
+    /// Index of the interrupt controller node in the [`InterruptTree`].
+    pub controller: usize,
+    /// The interrupt specifier cells, interpreted by the controller.
+    pub specifier: Vec<u32>,
+}
+
+/// The properties of a node that are relevant to interrupt routing.
+#[derive(Default)]
+struct Node<'dt> {
+    path: Vec<u8>,
+    parent: Option<usize>,
+    phandle: Option<u32>,
+    interrupt_parent: Option<u32>,
+    interrupt_cells: Option<u32>,
+    address_cells: Option<u32>,
+    controller: bool,
+    reg: &'dt [u8],
+    interrupts: Option<&'dt [u8]>,
+    interrupts_extended: Option<&'dt [u8]>,
+    map: Option<&'dt [u8]>,
+    map_mask: Option<&'dt [u8]>,
+}
+
+fn cells(data: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    data.chunks_exact(4).map(BigEndian::read_u32)
+}
+
+/// The interrupt hierarchy of a device tree.
+///
+/// Building the tree walks the whole blob once, so it should be kept around if many interrupts
+/// will be resolved.
+pub struct InterruptTree<'dt> {
+    nodes: Vec<Node<'dt>>,
+}
+
+impl<'dt> InterruptTree<'dt> {
+    /// Collect the interrupt hierarchy of `dt`.
+    #[must_use]
+    pub fn new(dt: &'dt DeviceTree<'dt>) -> Self {
+        let mut nodes: Vec<Node<'dt>> = Vec::new();
+        let mut stack: Vec<usize> = Vec::new();
+        for token in dt.iter_structure() {
+            match token {
+                fdt::Token::StartNode(name) => {
+                    let parent = stack.last().copied();
+                    let mut path = parent.map_or_else(Vec::new, |p| nodes[p].path.clone());
+                    if path != b"/" {
+                        path.push(b'/');
+                    }
+                    path.extend_from_slice(name);
+                    stack.push(nodes.len());
+                    nodes.push(Node {
+                        path,
+                        parent,
+                        ..Node::default()
+                    });
+                }
+                fdt::Token::EndNode => {
+                    stack.pop();
+                }
+                fdt::Token::Property { name, data } => {
+                    let Some(node) = stack.last().map(|i| &mut nodes[*i]) else {
+                        continue;
+                    };
+                    let value = || (data.len() >= 4).then(|| BigEndian::read_u32(data));
+                    match name {
+                        b"phandle" => node.phandle = value(),
+                        b"interrupt-parent" => node.interrupt_parent = value(),
+                        b"#interrupt-cells" => node.interrupt_cells = value(),
+                        b"#address-cells" => node.address_cells = value(),
+                        b"interrupt-controller" => node.controller = true,
+                        b"reg" => node.reg = data,
+                        b"interrupts" => node.interrupts = Some(data),
+                        b"interrupts-extended" => node.interrupts_extended = Some(data),
+                        b"interrupt-map" => node.map = Some(data),
+                        b"interrupt-map-mask" => node.map_mask = Some(data),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Self { nodes }
+    }
+
+    /// Find the index of the node at `path`.
+    #[must_use]
+    pub fn find(&self, path: &[u8]) -> Option<usize> {
+        self.nodes.iter().position(|n| n.path == path)
+    }
+
+    /// The path of the node at `index`.
+    ///
+    /// # Panics
+    /// If `index` is not a node in the tree.
+    #[must_use]
+    pub fn path(&self, index: usize) -> &[u8] {
+        &self.nodes[index].path
+    }
+
+    fn by_phandle(&self, phandle: u32) -> Result<usize, InterruptError> {
+        self.nodes
+            .iter()
+            .position(|n| n.phandle == Some(phandle))
+            .context(UnknownPhandleSnafu { phandle })
+    }
+
+    /// The index of the interrupt parent of the node at `index`, which is inherited from the
+    /// nearest ancestor if the node doesn't specify one itself.
+    ///
+    /// # Errors
+    /// - [`InterruptError::NoInterruptParent`] if no node on the path has an `interrupt-parent`.
+    /// - [`InterruptError::UnknownPhandle`] if the parent phandle doesn't exist.
+    pub fn interrupt_parent(&self, index: usize) -> Result<usize, InterruptError> {
+        let mut current = Some(index);
+        while let Some(i) = current {
+            if let Some(phandle) = self.nodes[i].interrupt_parent {
+                return self.by_phandle(phandle);
+            }
+            current = self.nodes[i].parent;
+        }
+        NoInterruptParentSnafu.fail()
+    }
+
+    fn interrupt_cells(&self, index: usize) -> Result<usize, InterruptError> {
+        self.nodes[index]
+            .interrupt_cells
+            .map(|c| c as usize)
+            .context(MissingInterruptCellsSnafu)
+    }
+
+    /// Resolve every interrupt generated by the device at `path` to its interrupt controller.
+    ///
+    /// Both the `interrupts` and `interrupts-extended` properties are supported. A device with
+    /// neither has no interrupts.
+    ///
+    /// # Errors
+    /// - [`InterruptError::NodeNotFound`] if there is no node at `path`.
+    /// - Any error from [`Self::resolve`], or if the interrupt parents can't be found.
+    pub fn interrupts_of(&self, path: &[u8]) -> Result<Vec<ResolvedInterrupt>, InterruptError> {
+        let index = self.find(path).context(NodeNotFoundSnafu)?;
+        let node = &self.nodes[index];
+        let unit_address: Vec<u32> = cells(node.reg).collect();
+        let mut resolved = Vec::new();
+        if let Some(data) = node.interrupts_extended {
+            let data: Vec<u32> = cells(data).collect();
+            let mut rest = data.as_slice();
+            while let Some((phandle, specifier)) = rest.split_first() {
+                let parent = self.by_phandle(*phandle)?;
+                let count = self.interrupt_cells(parent)?;
+                ensure!(
+                    specifier.len() >= count,
+                    MalformedSnafu {
+                        property: "interrupts-extended"
+                    }
+                );
+                let (specifier, next) = specifier.split_at(count);
+                resolved.push(self.resolve(parent, &unit_address, specifier)?);
+                rest = next;
+            }
+        } else if let Some(data) = node.interrupts {
+            let parent = self.interrupt_parent(index)?;
+            let count = self.interrupt_cells(parent)?;
+            let data: Vec<u32> = cells(data).collect();
+            ensure!(
+                count > 0 && data.len().is_multiple_of(count),
+                MalformedSnafu {
+                    property: "interrupts"
+                }
+            );
+            for specifier in data.chunks_exact(count) {
+                resolved.push(self.resolve(parent, &unit_address, specifier)?);
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Resolve an interrupt `specifier` from a child with `unit_address` (the cells of its `reg`
+    /// property) that has the node at index `parent` as its interrupt parent.
+    ///
+    /// This is also useful for devices that are discovered by probing a bus rather than described
+    /// in the tree, like PCI functions.
+    ///
+    /// # Errors
+    /// - [`InterruptError::NoMapEntry`] if a nexus has no mapping for the interrupt.
+    /// - [`InterruptError::TooDeep`] if the hierarchy appears to have a cycle.
+    /// - [`InterruptError::Malformed`] if an `interrupt-map` can't be parsed.
+    pub fn resolve(
+        &self,
+        parent: usize,
+        unit_address: &[u32],
+        specifier: &[u32],
+    ) -> Result<ResolvedInterrupt, InterruptError> {
+        let mut parent = parent;
+        let mut unit_address = unit_address.to_vec();
+        let mut specifier = specifier.to_vec();
+        for _ in 0..MAX_DEPTH {
+            let node = &self.nodes[parent];
+            if node.controller {
+                return Ok(ResolvedInterrupt {
+                    controller: parent,
+                    specifier,
+                });
+            }
+            if node.map.is_some() {
+                let next;
+                (next, unit_address, specifier) =
+                    self.map_interrupt(parent, &unit_address, &specifier)?;
+                trace!(
+                    "interrupt mapped by {:?} to {:?}",
+                    core::str::from_utf8(&node.path),
+                    core::str::from_utf8(&self.nodes[next].path)
+                );
+                parent = next;
+            } else {
+                // neither a controller nor a nexus, so the interrupt passes through to its parent
+                parent = self.interrupt_parent(parent)?;
+            }
+        }
+        TooDeepSnafu.fail()
+    }
+
+    /// Translate an interrupt through the `interrupt-map` of the nexus at `nexus`, returning the
+    /// new parent, unit address and specifier.
+    #[allow(clippy::type_complexity)]
+    fn map_interrupt(
+        &self,
+        nexus: usize,
+        unit_address: &[u32],
+        specifier: &[u32],
+    ) -> Result<(usize, Vec<u32>, Vec<u32>), InterruptError> {
+        let node = &self.nodes[nexus];
+        // defaults from the spec in section 2.3.5
+        let address_cells = node.address_cells.unwrap_or(2) as usize;
+        let interrupt_cells = self.interrupt_cells(nexus)?;
+        let child_cells = address_cells + interrupt_cells;
+
+        let mut child: Vec<u32> = Vec::with_capacity(child_cells);
+        child.extend((0..address_cells).map(|i| unit_address.get(i).copied().unwrap_or(0)));
+        child.extend((0..interrupt_cells).map(|i| specifier.get(i).copied().unwrap_or(0)));
+        if let Some(mask) = node.map_mask {
+            for (c, m) in child.iter_mut().zip(cells(mask)) {
+                *c &= m;
+            }
+        }
+
+        let map: Vec<u32> = node.map.map(|m| cells(m).collect()).unwrap_or_default();
+        let mut rest = map.as_slice();
+        while !rest.is_empty() {
+            ensure!(
+                rest.len() > child_cells,
+                MalformedSnafu {
+                    property: "interrupt-map"
+                }
+            );
+            let (entry_child, after) = rest.split_at(child_cells);
+            let parent = self.by_phandle(after[0])?;
+            // the parent unit address is absent if the parent doesn't specify its size
+            let parent_address_cells = self.nodes[parent].address_cells.unwrap_or(0) as usize;
+            let parent_cells = parent_address_cells + self.interrupt_cells(parent)?;
+            ensure!(
+                after.len() > parent_cells,
+                MalformedSnafu {
+                    property: "interrupt-map"
+                }
+            );
+            let (parent_address, parent_specifier) =
+                after[1..=parent_cells].split_at(parent_address_cells);
+            if entry_child == child.as_slice() {
+                return Ok((parent, parent_address.to_vec(), parent_specifier.to_vec()));
+            }
+            rest = &after[1 + parent_cells..];
+        }
+        NoMapEntrySnafu.fail()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_TREE_BLOB: &[u8] = include_bytes!("test-tree.fdt");
+
+    #[test]
+    fn resolve_device_interrupts() {
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB);
+        let tree = InterruptTree::new(&dt);
+        let intc = tree.find(b"/intc@8000000").unwrap();
+        assert_eq!(
+            tree.interrupt_parent(tree.find(b"/").unwrap()).unwrap(),
+            intc
+        );
+        assert_eq!(tree.path(intc), b"/intc@8000000");
+
+        assert_eq!(
+            tree.interrupts_of(b"/pl011@9000000").unwrap(),
+            [ResolvedInterrupt {
+                controller: intc,
+                specifier: [0, 1, 4].to_vec()
+            }]
+        );
+        // the timer has several interrupts
+        assert_eq!(tree.interrupts_of(b"/timer").unwrap().len(), 4);
+        // devices without interrupts have none
+        assert!(tree.interrupts_of(b"/fw-cfg@9020000").unwrap().is_empty());
+        assert!(matches!(
+            tree.interrupts_of(b"/nothing"),
+            Err(InterruptError::NodeNotFound)
+        ));
+    }
+
+    #[test]
+    fn resolve_through_interrupt_map() {
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB);
+        let tree = InterruptTree::new(&dt);
+        let intc = tree.find(b"/intc@8000000").unwrap();
+        let pcie = tree.find(b"/pcie@10000000").unwrap();
+
+        // INTA of the function at device 1 on the root bus is swizzled to the second SPI
+        let device1 = 1 << 11;
+        assert_eq!(
+            tree.resolve(pcie, &[device1, 0, 0], &[1]).unwrap(),
+            ResolvedInterrupt {
+                controller: intc,
+                specifier: [0, 4, 4].to_vec()
+            }
+        );
+        // the function number and register are masked off
+        assert_eq!(
+            tree.resolve(pcie, &[device1 | 0x3ff, 0, 0], &[2])
+                .unwrap()
+                .specifier,
+            [0, 5, 4]
+        );
+        assert!(matches!(
+            tree.resolve(pcie, &[0, 0, 0], &[7]),
+            Err(InterruptError::NoMapEntry)
+        ));
+    }
+}
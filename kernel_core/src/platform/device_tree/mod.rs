@@ -15,9 +15,15 @@ use byteorder::{BigEndian, ByteOrder};
 use itertools::Itertools;
 use snafu::Snafu;
 
+pub mod chosen;
+pub mod enumerate;
 pub mod fdt;
+pub mod index;
+pub mod interrupts;
 pub mod iter;
 
+pub use enumerate::enumerate;
+
 /// A list of strings given as the value of a property.
 #[derive(Clone)]
 pub struct StringList<'dt> {
@@ -99,7 +105,6 @@ impl core::fmt::Debug for Registers<'_> {
 pub enum Value<'dt> {
     /// A 32-bit integer.
     U32(u32),
-    #[allow(unused)]
     /// A 64-bit integer.
     U64(u64),
     /// A `phandle` value that references another node.
@@ -128,6 +133,11 @@ impl<'dt> Value<'dt> {
             b"#address-cells" | b"#size-cells" | b"virtual-reg" => {
                 Value::U32(BigEndian::read_u32(bytes))
             }
+            b"linux,initrd-start" | b"linux,initrd-end" => match bytes.len() {
+                4 => Value::U32(BigEndian::read_u32(bytes)),
+                8 => Value::U64(BigEndian::read_u64(bytes)),
+                _ => Value::Bytes(bytes),
+            },
             b"reg" => Value::Reg(Registers {
                 data: bytes,
                 address_cells,
@@ -327,12 +337,16 @@ impl<'dt> Value<'dt> {
     }
 }
 
+/// The deepest nesting of nodes tracked when searching the tree without allocating.
+const MAX_UNINDEXED_DEPTH: usize = 16;
+
 /// A device tree blob in memory.
 pub struct DeviceTree<'a> {
     header: fdt::BlobHeader<'a>,
     strings: &'a [u8],
     structure: &'a [u8],
     mem_map: &'a [u8],
+    index: spin::Once<index::TreeIndex>,
 }
 
 impl DeviceTree<'_> {
@@ -404,6 +418,7 @@ impl DeviceTree<'_> {
             strings: &buf[str_start..str_end],
             structure: &buf[structs_start..structs_end],
             mem_map: &buf[header.off_mem_rsvmap() as usize..header.off_dt_struct() as usize],
+            index: spin::Once::new(),
         }
     }
 
@@ -413,6 +428,18 @@ impl DeviceTree<'_> {
         self.header
     }
 
+    /// Build an index of the tree so that later lookups by path or phandle don't need to scan the
+    /// whole blob. Requires the heap. Only the first call builds the index.
+    pub fn build_index(&self) -> &index::TreeIndex {
+        self.index.call_once(|| index::TreeIndex::new(self))
+    }
+
+    /// The index of the tree, if [`Self::build_index`] has been called.
+    #[must_use]
+    pub fn index(&self) -> Option<&index::TreeIndex> {
+        self.index.get()
+    }
+
     /// Iterate over the raw flattened tree blob structure.
     #[must_use]
     pub fn iter_structure(&self) -> iter::FlattenedTreeIter {
@@ -431,6 +458,9 @@ impl DeviceTree<'_> {
     /// An iterator over the properties of the node in the tree, if present.
     #[must_use]
     pub fn iter_node_properties(&self, path: &[u8]) -> Option<iter::NodePropertyIter> {
+        if let Some(index) = self.index() {
+            return index.node_at(path).map(|node| node.properties(self));
+        }
         let mut segments = path.split(|p| *p == b'/');
         let mut looking_for = segments.next()?;
         let mut tokens = self.iter_structure();
@@ -492,6 +522,18 @@ impl DeviceTree<'_> {
         path: &[u8],
         node_name: &'q [u8],
     ) -> Option<iter::NodesNamedIter<'_, 'q>> {
+        if let Some(index) = self.index() {
+            return index.node_at(path).map(|node| iter::NodesNamedIter {
+                cur: iter::FlattenedTreeIter {
+                    dt: self,
+                    current_offset: node.offset,
+                },
+                depth: 1,
+                node_name,
+                parent_address_cells: node.parent_address_cells,
+                parent_size_cells: node.parent_size_cells,
+            });
+        }
         let mut segments = path.split(|p| *p == b'/');
         let mut looking_for = segments.next()?;
         let mut tokens = self.iter_structure();
@@ -533,6 +575,57 @@ impl DeviceTree<'_> {
         None
     }
 
+    /// Iterate over the properties of the node with the given `phandle`, if present.
+    #[must_use]
+    pub fn iter_node_properties_by_phandle(
+        &self,
+        phandle: u32,
+    ) -> Option<iter::NodePropertyIter<'_>> {
+        if let Some(index) = self.index() {
+            return index
+                .node_with_phandle(phandle)
+                .map(|node| node.properties(self));
+        }
+        // Without the index, walk the tree without allocating, tracking the cells defined on the
+        // current path. Properties always come before child nodes, so the current node is the
+        // last one that started.
+        let mut cells: [(Option<u32>, Option<u32>); MAX_UNINDEXED_DEPTH] =
+            [(None, None); MAX_UNINDEXED_DEPTH];
+        let mut depth = 0usize;
+        let mut current = None;
+        let mut tokens = self.iter_structure();
+        while let Some(token) = tokens.next() {
+            match token {
+                fdt::Token::StartNode(_) => {
+                    let inherited = cells[depth.min(MAX_UNINDEXED_DEPTH - 1)];
+                    depth += 1;
+                    cells[depth.min(MAX_UNINDEXED_DEPTH - 1)] = inherited;
+                    current = Some(index::NodeLocation {
+                        offset: tokens.current_offset,
+                        // defaults from the spec in section 2.3.5
+                        parent_address_cells: inherited.0.unwrap_or(2),
+                        parent_size_cells: inherited.1.unwrap_or(1),
+                    });
+                }
+                fdt::Token::EndNode => depth = depth.saturating_sub(1),
+                fdt::Token::Property { name, data } if data.len() >= 4 => {
+                    let value = BigEndian::read_u32(data);
+                    let node_cells = &mut cells[depth.min(MAX_UNINDEXED_DEPTH - 1)];
+                    match name {
+                        b"phandle" if value == phandle => {
+                            return current.map(|node| node.properties(self));
+                        }
+                        b"#address-cells" => node_cells.0 = Some(value),
+                        b"#size-cells" => node_cells.1 = Some(value),
+                        _ => {}
+                    }
+                }
+                fdt::Token::Property { .. } => {}
+            }
+        }
+        None
+    }
+
     /// Find a property in the tree by path, if it is present.
     ///
     /// # Arguments
@@ -967,4 +1060,57 @@ mod tests {
             "Expected only one 'v2m' node under '/intc@8000000'"
         );
     }
+
+    #[test]
+    fn indexed_lookups_match_scans() {
+        let tree = test_tree();
+        let paths: [&[u8]; 5] = [
+            b"/",
+            b"/chosen",
+            b"/intc@8000000/v2m@8020000",
+            b"/pcie@10000000/",
+            b"/does-not-exist",
+        ];
+        let scan = |tree: &DeviceTree| {
+            paths
+                .iter()
+                .map(|p| {
+                    tree.iter_node_properties(p).map(|props| {
+                        (
+                            props.parent_address_cells(),
+                            props
+                                .map(|(n, v)| format!("{n:?}={v:?}"))
+                                .collect::<Vec<_>>(),
+                        )
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        let unindexed = scan(&tree);
+        let gic = tree
+            .iter_node_properties_by_phandle(0x8002)
+            .unwrap()
+            .map(|(n, _)| n)
+            .collect::<Vec<_>>();
+        assert!(gic.contains(&b"interrupt-controller".as_slice()));
+
+        assert!(tree.index().is_none());
+        assert!(tree.build_index().len() > paths.len());
+        assert!(tree.index().is_some());
+        assert_eq!(scan(&tree), unindexed);
+        assert_eq!(
+            tree.iter_node_properties_by_phandle(0x8002)
+                .unwrap()
+                .map(|(n, _)| n)
+                .collect::<Vec<_>>(),
+            gic
+        );
+        assert!(tree.iter_node_properties_by_phandle(0x1234).is_none());
+        let v2m = tree
+            .iter_nodes_named(b"/intc@8000000", b"v2m")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(v2m.unit_address, Some(b"8020000".as_slice()));
+    }
 }
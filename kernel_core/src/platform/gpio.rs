@@ -0,0 +1,430 @@
+//! General purpose I/O (GPIO) controller policy.
+//!
+//! A controller drives a bank of pins, each of which is either an input or an output. Inputs can
+//! raise an interrupt on a rising or falling edge, which the controller reports with a single
+//! interrupt for the whole bank. [`Gpio`] demultiplexes that interrupt to a handler for each pin,
+//! which is also where pin interrupts will be forwarded to user space drivers.
+//!
+//! Pins are referred to by other nodes in the device tree with properties like `gpios`, following
+//! the kernel's `Documentation/devicetree/bindings/gpio/gpio.txt` in Linux. An LED with the
+//! `panic-indicator` property under a `gpio-leds` node is blinked by the kernel after a panic.
+use alloc::{sync::Arc, vec::Vec};
+use byteorder::{BigEndian, ByteOrder as _};
+use snafu::{ensure, OptionExt as _, Snafu};
+
+use crate::{
+    platform::device_tree::{fdt::Token, DeviceTree, StringList},
+    sync::Mutex,
+};
+
+/// Whether a pin is read or driven by the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The pin's level is read.
+    Input,
+    /// The pin's level is driven.
+    Output,
+}
+
+/// The changes in level of an input that raise an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// The input goes from low to high.
+    Rising,
+    /// The input goes from high to low.
+    Falling,
+    /// Either.
+    Both,
+}
+
+/// Mechanism interface for the registers of a GPIO controller.
+///
+/// The methods that change the configuration of a pin are only called with the controller locked,
+/// since they may need to read and then write a register shared by every pin.
+pub trait GpioMechanism {
+    /// The number of pins the controller drives.
+    fn num_pins(&self) -> u32;
+
+    /// Whether `pin` is an input or an output.
+    fn direction(&self, pin: u32) -> Direction;
+
+    /// Make `pin` an input or an output.
+    fn set_direction(&self, pin: u32, direction: Direction);
+
+    /// Read the level of `pin`, true if it is high.
+    fn read(&self, pin: u32) -> bool;
+
+    /// Drive the output `pin` high or low.
+    ///
+    /// This is called without the controller locked, so that it can be used after a panic.
+    fn write(&self, pin: u32, high: bool);
+
+    /// Raise an interrupt on `edge` of the input `pin`, or stop raising interrupts for it if `edge`
+    /// is `None`.
+    fn set_interrupt(&self, pin: u32, edge: Option<Edge>);
+
+    /// The pins with a pending interrupt, as a bit mask.
+    fn pending_interrupts(&self) -> u32;
+
+    /// Clear the pending interrupts of the pins in the bit mask `pins`.
+    fn clear_interrupts(&self, pins: u32);
+}
+
+/// A function called with the pin number when an interrupt occurs on that pin.
+pub type PinHandler = dyn Fn(u32) + Send + Sync;
+
+/// Errors that arise using a GPIO controller.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The controller has no such pin.
+    #[snafu(display("GPIO pin {pin} does not exist"))]
+    InvalidPin {
+        /// The pin number.
+        pin: u32,
+    },
+    /// The pin already has an interrupt handler.
+    #[snafu(display("GPIO pin {pin} already has an interrupt handler"))]
+    AlreadyRegistered {
+        /// The pin number.
+        pin: u32,
+    },
+    /// The pin has no interrupt handler to remove.
+    #[snafu(display("GPIO pin {pin} has no interrupt handler"))]
+    NotRegistered {
+        /// The pin number.
+        pin: u32,
+    },
+}
+
+/// A GPIO controller, with a handler for the interrupts of each pin.
+pub struct Gpio<M> {
+    mech: M,
+    /// The pins with interrupt handlers. The lock is also held while configuring pins.
+    handlers: Mutex<Vec<(u32, Arc<PinHandler>)>>,
+}
+
+impl<M: GpioMechanism> Gpio<M> {
+    /// Create a new controller with no pin interrupts enabled.
+    pub fn new(mech: M) -> Self {
+        for pin in 0..mech.num_pins() {
+            mech.set_interrupt(pin, None);
+        }
+        mech.clear_interrupts(u32::MAX);
+        Self {
+            mech,
+            handlers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The number of pins the controller drives.
+    pub fn num_pins(&self) -> u32 {
+        self.mech.num_pins()
+    }
+
+    fn check(&self, pin: u32) -> Result<(), Error> {
+        ensure!(pin < self.mech.num_pins(), InvalidPinSnafu { pin });
+        Ok(())
+    }
+
+    /// Whether `pin` is an input or an output.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidPin`] if the controller has no such pin.
+    pub fn direction(&self, pin: u32) -> Result<Direction, Error> {
+        self.check(pin)?;
+        Ok(self.mech.direction(pin))
+    }
+
+    /// Make `pin` an input or an output.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidPin`] if the controller has no such pin.
+    pub fn set_direction(&self, pin: u32, direction: Direction) -> Result<(), Error> {
+        self.check(pin)?;
+        let _config = self.handlers.lock();
+        self.mech.set_direction(pin, direction);
+        Ok(())
+    }
+
+    /// Read the level of `pin`, true if it is high.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidPin`] if the controller has no such pin.
+    pub fn read(&self, pin: u32) -> Result<bool, Error> {
+        self.check(pin)?;
+        Ok(self.mech.read(pin))
+    }
+
+    /// Drive the output `pin` high or low. This never waits for the controller's lock.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidPin`] if the controller has no such pin.
+    pub fn write(&self, pin: u32, high: bool) -> Result<(), Error> {
+        self.check(pin)?;
+        self.mech.write(pin, high);
+        Ok(())
+    }
+
+    /// Make `pin` an input and call `handler` whenever `edge` occurs on it.
+    /// The controller's own interrupt must also be routed to [`Self::handle_interrupt`].
+    ///
+    /// # Errors
+    /// - [`Error::InvalidPin`] if the controller has no such pin.
+    /// - [`Error::AlreadyRegistered`] if the pin already has a handler.
+    pub fn on_edge(
+        &self,
+        pin: u32,
+        edge: Edge,
+        handler: impl Fn(u32) + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        self.check(pin)?;
+        let mut handlers = self.handlers.lock();
+        ensure!(
+            handlers.iter().all(|(p, _)| *p != pin),
+            AlreadyRegisteredSnafu { pin }
+        );
+        handlers.push((pin, Arc::new(handler)));
+        self.mech.set_direction(pin, Direction::Input);
+        self.mech.clear_interrupts(1 << pin);
+        self.mech.set_interrupt(pin, Some(edge));
+        Ok(())
+    }
+
+    /// Stop raising interrupts for `pin` and remove its handler.
+    ///
+    /// # Errors
+    /// - [`Error::NotRegistered`] if the pin has no handler.
+    pub fn remove_handler(&self, pin: u32) -> Result<(), Error> {
+        let mut handlers = self.handlers.lock();
+        let index = handlers
+            .iter()
+            .position(|(p, _)| *p == pin)
+            .context(NotRegisteredSnafu { pin })?;
+        self.mech.set_interrupt(pin, None);
+        handlers.swap_remove(index);
+        Ok(())
+    }
+
+    /// Handle the controller's interrupt by calling the handler of each pin with a pending
+    /// interrupt, returning the bit mask of those pins.
+    ///
+    /// The controller is not locked while the handlers run, so they can configure pins.
+    pub fn handle_interrupt(&self) -> u32 {
+        let pending = self.mech.pending_interrupts();
+        self.mech.clear_interrupts(pending);
+        let handlers: Vec<_> = self
+            .handlers
+            .lock()
+            .iter()
+            .filter(|(pin, _)| pending & (1 << pin) != 0)
+            .map(|(pin, handler)| (*pin, handler.clone()))
+            .collect();
+        for (pin, handler) in handlers {
+            handler(pin);
+        }
+        pending
+    }
+}
+
+/// A pin referred to by a property like `gpios`, for a controller with `#gpio-cells = <2>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinRef {
+    /// The phandle of the controller.
+    pub controller: u32,
+    /// The pin number within the controller.
+    pub pin: u32,
+    /// The pin is active when it is low.
+    pub active_low: bool,
+}
+
+impl PinRef {
+    /// Parse the first pin in the value of a `gpios` property.
+    #[must_use]
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 12 {
+            return None;
+        }
+        Some(Self {
+            controller: BigEndian::read_u32(data),
+            pin: BigEndian::read_u32(&data[4..]),
+            active_low: BigEndian::read_u32(&data[8..]) & 1 != 0,
+        })
+    }
+
+    /// The level to drive the pin to for it to be active or inactive.
+    #[must_use]
+    pub fn level(&self, active: bool) -> bool {
+        active != self.active_low
+    }
+}
+
+/// Find the LED that indicates a kernel panic: a child of a `gpio-leds` node with the
+/// `panic-indicator` property.
+#[must_use]
+pub fn panic_led_in_device_tree(device_tree: &DeviceTree) -> Option<PinRef> {
+    let mut depth = 0usize;
+    // the depth of the `gpio-leds` node being searched, if in one
+    let mut leds_depth = None;
+    let mut panic_indicator = false;
+    let mut gpios = None;
+    for token in device_tree.iter_structure() {
+        match token {
+            Token::StartNode(_) => {
+                depth += 1;
+                panic_indicator = false;
+                gpios = None;
+            }
+            Token::EndNode => {
+                if leds_depth.is_some_and(|d| d + 1 == depth) && panic_indicator {
+                    if let Some(led) = gpios.and_then(PinRef::parse) {
+                        return Some(led);
+                    }
+                }
+                if leds_depth == Some(depth) {
+                    leds_depth = None;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            Token::Property { name, data } => match name {
+                b"compatible" if (StringList { data }).contains(b"gpio-leds") => {
+                    leds_depth = Some(depth);
+                }
+                b"panic-indicator" => panic_indicator = true,
+                b"gpios" => gpios = Some(data),
+                _ => {}
+            },
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Registers of a fake 8 pin controller, where an interrupt is pending for a pin whenever it is
+    /// enabled and the pin is high.
+    #[derive(Default)]
+    struct FakeMechanism {
+        outputs: AtomicU32,
+        levels: AtomicU32,
+        enabled: AtomicU32,
+        cleared: AtomicU32,
+    }
+
+    impl GpioMechanism for FakeMechanism {
+        fn num_pins(&self) -> u32 {
+            8
+        }
+
+        fn direction(&self, pin: u32) -> Direction {
+            if self.outputs.load(Ordering::Relaxed) & (1 << pin) == 0 {
+                Direction::Input
+            } else {
+                Direction::Output
+            }
+        }
+
+        fn set_direction(&self, pin: u32, direction: Direction) {
+            match direction {
+                Direction::Input => self.outputs.fetch_and(!(1 << pin), Ordering::Relaxed),
+                Direction::Output => self.outputs.fetch_or(1 << pin, Ordering::Relaxed),
+            };
+        }
+
+        fn read(&self, pin: u32) -> bool {
+            self.levels.load(Ordering::Relaxed) & (1 << pin) != 0
+        }
+
+        fn write(&self, pin: u32, high: bool) {
+            if high {
+                self.levels.fetch_or(1 << pin, Ordering::Relaxed);
+            } else {
+                self.levels.fetch_and(!(1 << pin), Ordering::Relaxed);
+            }
+        }
+
+        fn set_interrupt(&self, pin: u32, edge: Option<Edge>) {
+            if edge.is_some() {
+                self.enabled.fetch_or(1 << pin, Ordering::Relaxed);
+            } else {
+                self.enabled.fetch_and(!(1 << pin), Ordering::Relaxed);
+            }
+        }
+
+        fn pending_interrupts(&self) -> u32 {
+            self.levels.load(Ordering::Relaxed) & self.enabled.load(Ordering::Relaxed)
+        }
+
+        fn clear_interrupts(&self, pins: u32) {
+            self.cleared.fetch_or(pins, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn read_and_write_pins() {
+        let gpio = Gpio::new(FakeMechanism::default());
+        assert_eq!(gpio.direction(3).unwrap(), Direction::Input);
+        gpio.set_direction(3, Direction::Output).unwrap();
+        assert_eq!(gpio.direction(3).unwrap(), Direction::Output);
+        gpio.write(3, true).unwrap();
+        assert!(gpio.read(3).unwrap());
+        assert!(!gpio.read(2).unwrap());
+        assert!(matches!(
+            gpio.write(8, true),
+            Err(Error::InvalidPin { pin: 8 })
+        ));
+    }
+
+    #[test]
+    fn edge_interrupts() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let gpio = Gpio::new(FakeMechanism::default());
+        gpio.set_direction(5, Direction::Output).unwrap();
+        gpio.on_edge(5, Edge::Rising, |pin| {
+            assert_eq!(pin, 5);
+            CALLS.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+        assert_eq!(gpio.direction(5).unwrap(), Direction::Input);
+        assert!(matches!(
+            gpio.on_edge(5, Edge::Both, |_| {}),
+            Err(Error::AlreadyRegistered { pin: 5 })
+        ));
+
+        // pin 6 is high too, but has no interrupt enabled
+        gpio.mech.levels.store(0b110_0000, Ordering::Relaxed);
+        assert_eq!(gpio.handle_interrupt(), 0b10_0000);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert_ne!(gpio.mech.cleared.load(Ordering::Relaxed) & 0b10_0000, 0);
+
+        gpio.remove_handler(5).unwrap();
+        assert_eq!(gpio.handle_interrupt(), 0);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            gpio.remove_handler(5),
+            Err(Error::NotRegistered { pin: 5 })
+        ));
+    }
+
+    #[test]
+    fn pin_references() {
+        let data = [0, 0, 0x80, 0x01, 0, 0, 0, 3, 0, 0, 0, 1];
+        let led = PinRef::parse(&data).unwrap();
+        assert_eq!(
+            led,
+            PinRef {
+                controller: 0x8001,
+                pin: 3,
+                active_low: true
+            }
+        );
+        assert!(!led.level(true));
+        assert_eq!(PinRef::parse(&data[..8]), None);
+
+        let tree = DeviceTree::from_bytes(include_bytes!("device_tree/test-tree.fdt"));
+        assert_eq!(panic_led_in_device_tree(&tree), None);
+    }
+}
@@ -0,0 +1,370 @@
+//! CPU idle states deeper than `wfi`, entered through PSCI `CPU_SUSPEND`.
+//!
+//! The states are described in the device tree by the nodes under `/cpus/idle-states` that each
+//! core's `cpu-idle-states` property refers to (see the Linux `arm,idle-state` binding). Entering
+//! a state costs its entry and exit latency, so the [`IdleGovernor`] only picks a state if the core
+//! is predicted to stay idle for longer than the state's target residency. The prediction comes
+//! from the next timer deadline, since that is the only wake up the kernel knows about in advance.
+//!
+//! Only retention states are used: power down states lose the core's context and resume at a
+//! new entry point, and states that stop the local timer would need another timer to wake the
+//! core. Neither is supported yet.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use byteorder::{BigEndian, ByteOrder as _};
+use log::debug;
+
+use super::{
+    cpu::Id as CpuId,
+    device_tree::{DeviceTree, OwnedParseError, ParseError, Value},
+};
+
+/// The bit in an original format PSCI power state parameter that marks a power down state.
+const POWER_STATE_TYPE_POWERDOWN: u32 = 1 << 16;
+
+/// An idle state from the device tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdleState {
+    /// The PSCI power state parameter passed to `CPU_SUSPEND` to enter the state.
+    pub power_state: u32,
+    /// The worst case time to enter the state, in microseconds.
+    pub entry_latency_us: u32,
+    /// The worst case time to leave the state, in microseconds.
+    pub exit_latency_us: u32,
+    /// The minimum time the core must stay in the state for it to save energy, in microseconds.
+    pub min_residency_us: u32,
+    /// The local timer stops while the core is in this state.
+    pub local_timer_stop: bool,
+}
+
+/// Read a single big endian `u32` from a property.
+fn read_u32<'dt>(name: &'dt [u8], value: &Value<'dt>) -> Result<u32, ParseError<'dt>> {
+    match value {
+        Value::U32(v) => Ok(*v),
+        Value::Bytes(b) if b.len() == 4 => Ok(BigEndian::read_u32(b)),
+        _ => Err(ParseError::UnexpectedType {
+            name,
+            value: value.clone(),
+            expected_type: "u32",
+        }),
+    }
+}
+
+impl IdleState {
+    /// Parse an idle state from the properties of its device tree node.
+    ///
+    /// # Errors
+    /// Returns an error if `arm,psci-suspend-param` or `entry-latency-us` is missing, or if a
+    /// property has the wrong type.
+    pub fn from_properties<'dt>(
+        properties: impl Iterator<Item = (&'dt [u8], Value<'dt>)>,
+    ) -> Result<Self, ParseError<'dt>> {
+        let mut power_state = None;
+        let mut entry_latency_us = None;
+        let mut state = Self::default();
+        for (name, value) in properties {
+            match name {
+                b"arm,psci-suspend-param" => power_state = Some(read_u32(name, &value)?),
+                b"entry-latency-us" => entry_latency_us = Some(read_u32(name, &value)?),
+                b"exit-latency-us" => state.exit_latency_us = read_u32(name, &value)?,
+                b"min-residency-us" => state.min_residency_us = read_u32(name, &value)?,
+                b"local-timer-stop" => state.local_timer_stop = true,
+                _ => {}
+            }
+        }
+        state.power_state = power_state.ok_or(ParseError::PropertyNotFound {
+            name: "arm,psci-suspend-param",
+        })?;
+        state.entry_latency_us = entry_latency_us.ok_or(ParseError::PropertyNotFound {
+            name: "entry-latency-us",
+        })?;
+        Ok(state)
+    }
+
+    /// The shortest time the core must be idle for entering this state to be worthwhile, in
+    /// nanoseconds.
+    #[must_use]
+    pub fn target_residency_nanos(&self) -> u64 {
+        let latency = u64::from(self.entry_latency_us) + u64::from(self.exit_latency_us);
+        latency.max(u64::from(self.min_residency_us)) * 1000
+    }
+
+    /// Returns true if the kernel can enter this state: it retains the core's context and keeps
+    /// the local timer running.
+    #[must_use]
+    pub fn is_supported(&self) -> bool {
+        self.power_state & POWER_STATE_TYPE_POWERDOWN == 0 && !self.local_timer_stop
+    }
+}
+
+/// Find the idle states that the cores in the system can enter.
+///
+/// The kernel treats every core alike, so the states referred to by the first core with a
+/// `cpu-idle-states` property are used. Returns no states if the device tree doesn't describe any,
+/// or if they are not entered with PSCI.
+///
+/// # Errors
+/// Returns an error if an idle state node is missing or invalid.
+pub fn idle_states_in_device_tree(
+    device_tree: &DeviceTree,
+) -> Result<Vec<IdleState>, OwnedParseError> {
+    let psci = device_tree
+        .find_property(b"/cpus/idle-states/entry-method")
+        .is_some_and(|m| match m {
+            Value::String(s) => s.to_bytes() == b"psci",
+            Value::Bytes(b) => b == b"psci\0",
+            _ => false,
+        });
+    if !psci {
+        debug!("no PSCI idle states in device tree");
+        return Ok(Vec::new());
+    }
+    let phandles = device_tree
+        .iter_nodes_named(b"/cpus", b"cpu")
+        .into_iter()
+        .flatten()
+        .find_map(|mut node| {
+            node.properties
+                .find_map(|(name, value)| (name == b"cpu-idle-states").then_some(value))
+        })
+        .and_then(Value::into_bytes)
+        .unwrap_or_default();
+    phandles
+        .chunks_exact(4)
+        .map(|phandle| {
+            let properties = device_tree
+                .iter_node_properties_by_phandle(BigEndian::read_u32(phandle))
+                .ok_or(OwnedParseError::NodeNotFound {
+                    path: "/cpus/idle-states/*",
+                })?;
+            IdleState::from_properties(properties).map_err(ParseError::to_owned)
+        })
+        .collect()
+}
+
+/// How much time a core has spent in an idle state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Residency {
+    /// The number of times the state was entered.
+    pub entries: u64,
+    /// The total time spent in the state, in nanoseconds.
+    pub nanos: u64,
+}
+
+/// Counters behind a [`Residency`].
+#[derive(Default)]
+struct ResidencyCounters {
+    entries: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// Chooses which idle state each core enters, and records how long it stays there.
+pub struct IdleGovernor {
+    /// The supported states, in increasing order of target residency.
+    states: Vec<IdleState>,
+    /// For each state, false once the firmware has rejected it.
+    enabled: Vec<AtomicBool>,
+    /// The id of each core, in the order of core indices.
+    cores: Vec<CpuId>,
+    /// For each core index, the residency of plain `wfi` followed by each state.
+    residency: Vec<ResidencyCounters>,
+}
+
+impl IdleGovernor {
+    /// Create a governor for the cores with ids `cores`, that chooses between `states` that the
+    /// kernel supports.
+    #[must_use]
+    pub fn new(states: &[IdleState], cores: &[CpuId]) -> Self {
+        let mut states: Vec<IdleState> = states
+            .iter()
+            .filter(|s| {
+                if !s.is_supported() {
+                    debug!("skipping unsupported idle state {s:x?}");
+                }
+                s.is_supported()
+            })
+            .copied()
+            .collect();
+        states.sort_by_key(IdleState::target_residency_nanos);
+        Self {
+            enabled: states.iter().map(|_| AtomicBool::new(true)).collect(),
+            residency: (0..cores.len() * (states.len() + 1))
+                .map(|_| ResidencyCounters::default())
+                .collect(),
+            states,
+            cores: cores.to_vec(),
+        }
+    }
+
+    /// The states the governor chooses between, indexed as by [`Self::select`].
+    #[must_use]
+    pub fn states(&self) -> &[IdleState] {
+        &self.states
+    }
+
+    /// The ids of the cores that the governor records residency for.
+    #[must_use]
+    pub fn cores(&self) -> &[CpuId] {
+        &self.cores
+    }
+
+    /// Choose the deepest enabled state that is worth entering if the core will be idle for
+    /// `predicted_idle_nanos`, where `None` means there is no known wake up. Returns `None` if the
+    /// core should just wait for an interrupt.
+    #[must_use]
+    pub fn select(&self, predicted_idle_nanos: Option<u64>) -> Option<usize> {
+        let predicted = predicted_idle_nanos.unwrap_or(u64::MAX);
+        self.states
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(i, s)| {
+                self.enabled[*i].load(Ordering::Relaxed) && s.target_residency_nanos() <= predicted
+            })
+            .map(|(i, _)| i)
+    }
+
+    /// Stop choosing state `state`, for instance because the firmware rejected it.
+    pub fn disable(&self, state: usize) {
+        self.enabled[state].store(false, Ordering::Relaxed);
+    }
+
+    fn counters(&self, core: CpuId, state: Option<usize>) -> Option<&ResidencyCounters> {
+        let core = self.cores.iter().position(|c| *c == core)?;
+        let state = match state {
+            Some(s) if s < self.states.len() => s + 1,
+            Some(_) => return None,
+            None => 0,
+        };
+        self.residency.get(core * (self.states.len() + 1) + state)
+    }
+
+    /// Record that core `core` spent `nanos` in `state`, or in `wfi` if `state` is `None`.
+    pub fn record(&self, core: CpuId, state: Option<usize>, nanos: u64) {
+        if let Some(counters) = self.counters(core, state) {
+            counters.entries.fetch_add(1, Ordering::Relaxed);
+            counters.nanos.fetch_add(nanos, Ordering::Relaxed);
+        }
+    }
+
+    /// How long core `core` has spent in `state`, or in `wfi` if `state` is `None`.
+    #[must_use]
+    pub fn residency(&self, core: CpuId, state: Option<usize>) -> Residency {
+        self.counters(core, state)
+            .map(|c| Residency {
+                entries: c.entries.load(Ordering::Relaxed),
+                nanos: c.nanos.load(Ordering::Relaxed),
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(power_state: u32, latency: u32, min_residency: u32) -> IdleState {
+        IdleState {
+            power_state,
+            entry_latency_us: latency,
+            exit_latency_us: latency,
+            min_residency_us: min_residency,
+            local_timer_stop: false,
+        }
+    }
+
+    #[test]
+    fn parse_idle_state() {
+        let properties = [
+            (&b"compatible"[..], Value::Bytes(b"arm,idle-state\0")),
+            (b"arm,psci-suspend-param", Value::Bytes(&[0, 0, 0, 1])),
+            (b"entry-latency-us", Value::Bytes(&[0, 0, 0, 40])),
+            (b"exit-latency-us", Value::Bytes(&[0, 0, 0, 100])),
+            (b"min-residency-us", Value::Bytes(&[0, 0, 0x01, 0x2c])),
+            (b"local-timer-stop", Value::Bytes(&[])),
+        ];
+        let s = IdleState::from_properties(properties.iter().cloned()).unwrap();
+        assert_eq!(
+            s,
+            IdleState {
+                power_state: 1,
+                entry_latency_us: 40,
+                exit_latency_us: 100,
+                min_residency_us: 300,
+                local_timer_stop: true,
+            }
+        );
+        assert_eq!(s.target_residency_nanos(), 300_000);
+        assert!(!s.is_supported());
+
+        assert!(matches!(
+            IdleState::from_properties(properties[2..].iter().cloned()),
+            Err(ParseError::PropertyNotFound {
+                name: "arm,psci-suspend-param"
+            })
+        ));
+        assert!(matches!(
+            IdleState::from_properties(
+                [(&b"arm,psci-suspend-param"[..], Value::Bytes(&[1, 2]))].into_iter()
+            ),
+            Err(ParseError::UnexpectedType { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tree_has_no_idle_states() {
+        let tree = DeviceTree::from_bytes(include_bytes!("device_tree/test-tree.fdt"));
+        assert!(idle_states_in_device_tree(&tree).unwrap().is_empty());
+    }
+
+    #[test]
+    fn select_deepest_worthwhile_state() {
+        let powerdown = state(POWER_STATE_TYPE_POWERDOWN | 2, 1, 1);
+        let gov = IdleGovernor::new(&[state(1, 500, 0), powerdown, state(0, 10, 50)], &[0, 1]);
+        assert_eq!(gov.states(), &[state(0, 10, 50), state(1, 500, 0)]);
+
+        assert_eq!(gov.select(Some(10_000)), None);
+        assert_eq!(gov.select(Some(50_000)), Some(0));
+        assert_eq!(gov.select(Some(999_999)), Some(0));
+        assert_eq!(gov.select(Some(1_000_000)), Some(1));
+        assert_eq!(gov.select(None), Some(1));
+
+        gov.disable(1);
+        assert_eq!(gov.select(None), Some(0));
+    }
+
+    #[test]
+    fn record_residency() {
+        let gov = IdleGovernor::new(&[state(0, 10, 50)], &[0, 4]);
+        gov.record(4, Some(0), 100);
+        gov.record(4, Some(0), 50);
+        gov.record(4, None, 7);
+        gov.record(0, None, 3);
+        // unknown cores and states are ignored
+        gov.record(9, None, 1);
+        gov.record(0, Some(3), 1);
+        assert_eq!(
+            gov.residency(4, Some(0)),
+            Residency {
+                entries: 2,
+                nanos: 150
+            }
+        );
+        assert_eq!(
+            gov.residency(4, None),
+            Residency {
+                entries: 1,
+                nanos: 7
+            }
+        );
+        assert_eq!(
+            gov.residency(0, None),
+            Residency {
+                entries: 1,
+                nanos: 3
+            }
+        );
+        assert_eq!(gov.residency(0, Some(0)), Residency::default());
+    }
+}
@@ -0,0 +1,259 @@
+//! Firmware independent description of the platform.
+//!
+//! Platforms describe their hardware to the kernel with either a device tree or ACPI tables. The
+//! [`PlatformInfo`] trait provides the information needed to boot from either, so the rest of the
+//! boot process doesn't need to care which was used.
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder as _};
+use snafu::OptionExt as _;
+
+use crate::{
+    exceptions::{interrupt::TriggerMode, InterruptId},
+    memory::PhysicalAddress,
+    platform::{
+        cpu::{list_cores, CoreInfo},
+        device_tree::{
+            interrupts::InterruptTree, DeviceTree, NodeNotFoundSnafu, OwnedParseError, ParseError,
+        },
+    },
+};
+
+/// The location of the GIC interrupt controller's registers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterruptControllerInfo {
+    /// The major version of the GIC architecture implemented by the controller.
+    pub version: u8,
+    /// Base address of the distributor registers.
+    pub distributor: PhysicalAddress,
+    /// Base address of the memory mapped CPU interface registers, if present.
+    pub cpu_interface: Option<PhysicalAddress>,
+    /// The (base address, length in bytes) of each region of redistributors (GIC version 3 and later).
+    pub redistributors: Vec<(PhysicalAddress, usize)>,
+    /// Base addresses of the `GICv2m` MSI frames.
+    pub msi_frames: Vec<PhysicalAddress>,
+}
+
+/// An interrupt used by the generic timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerInterrupt {
+    /// The interrupt ID.
+    pub id: InterruptId,
+    /// How the interrupt is triggered.
+    pub trigger: TriggerMode,
+}
+
+/// The interrupts used by the generic timer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimerInfo {
+    /// The secure EL1 physical timer, if it is visible to the kernel.
+    pub secure: Option<TimerInterrupt>,
+    /// The non-secure EL1 physical timer.
+    pub non_secure: TimerInterrupt,
+    /// The EL1 virtual timer.
+    pub virtual_timer: TimerInterrupt,
+    /// The EL2 physical timer, if present.
+    pub hypervisor: Option<TimerInterrupt>,
+}
+
+/// Source of information about the hardware in the system.
+pub trait PlatformInfo {
+    /// Errors that can occur reading the platform description.
+    type Error: core::fmt::Debug;
+
+    /// List the CPU cores in the system.
+    ///
+    /// # Errors
+    /// Returns an error if the platform description is missing or has invalid information.
+    fn cores(&self) -> Result<Vec<CoreInfo<'_>>, Self::Error>;
+
+    /// Locate the system interrupt controller.
+    ///
+    /// # Errors
+    /// Returns an error if the platform description is missing or has invalid information.
+    fn interrupt_controller(&self) -> Result<InterruptControllerInfo, Self::Error>;
+
+    /// Find the interrupts used by the generic timer.
+    ///
+    /// # Errors
+    /// Returns an error if the platform description is missing or has invalid information.
+    fn timer(&self) -> Result<TimerInfo, Self::Error>;
+}
+
+/// Decode a 3 cell GIC interrupt specifier from a device tree.
+fn gic_interrupt(specifier: &[u8]) -> Option<TimerInterrupt> {
+    let id = match BigEndian::read_u32(specifier) {
+        0 => 32 + BigEndian::read_u32(&specifier[4..]),
+        1 => 16 + BigEndian::read_u32(&specifier[4..]),
+        _ => return None,
+    };
+    let trigger = match BigEndian::read_u32(&specifier[8..]) & 0xf {
+        0b0001 | 0b0010 => TriggerMode::Edge,
+        0b0100 | 0b1000 => TriggerMode::Level,
+        _ => return None,
+    };
+    Some(TimerInterrupt { id, trigger })
+}
+
+fn unexpected(name: &[u8], reason: &'static str) -> OwnedParseError {
+    OwnedParseError::UnexpectedValue {
+        name: alloc::string::String::from_utf8_lossy(name).into_owned(),
+        value: alloc::string::String::new(),
+        reason,
+    }
+}
+
+impl PlatformInfo for DeviceTree<'_> {
+    type Error = OwnedParseError;
+
+    fn cores(&self) -> Result<Vec<CoreInfo<'_>>, Self::Error> {
+        list_cores(self)
+    }
+
+    fn interrupt_controller(&self) -> Result<InterruptControllerInfo, Self::Error> {
+        // the system interrupt controller is the interrupt parent of the root node
+        let tree = InterruptTree::new(self);
+        let path = tree
+            .find(b"/")
+            .and_then(|root| tree.interrupt_parent(root).ok())
+            .map(|intc| tree.path(intc))
+            .context(NodeNotFoundSnafu {
+                path: "interrupt-parent",
+            })
+            .map_err(ParseError::to_owned)?;
+        let props = self
+            .iter_node_properties(path)
+            .context(NodeNotFoundSnafu {
+                path: "interrupt-parent",
+            })
+            .map_err(ParseError::to_owned)?;
+
+        let mut version = 2;
+        let mut regs = Vec::new();
+        let mut redistributor_regions = 1;
+        for (name, value) in props {
+            match name {
+                b"compatible" => {
+                    let strings = value.as_strings(name).map_err(ParseError::to_owned)?;
+                    if strings.contains(b"arm,gic-v3") {
+                        version = 3;
+                    }
+                }
+                b"reg" => {
+                    regs = value
+                        .as_reg(name)
+                        .map_err(ParseError::to_owned)?
+                        .iter()
+                        .collect();
+                }
+                b"#redistributor-regions" => {
+                    redistributor_regions = value
+                        .into_bytes()
+                        .filter(|b| b.len() >= 4)
+                        .map_or(1, BigEndian::read_u32)
+                        as usize;
+                }
+                _ => {}
+            }
+        }
+
+        let distributor = regs
+            .first()
+            .ok_or_else(|| unexpected(b"reg", "missing distributor registers"))?
+            .0;
+        let (redistributors, cpu_interface) = if version >= 3 {
+            let end = (1 + redistributor_regions).min(regs.len());
+            (regs[1..end].to_vec(), regs.get(end))
+        } else {
+            (Vec::new(), regs.get(1))
+        };
+        let msi_frames = self
+            .iter_nodes_named(path, b"v2m")
+            .into_iter()
+            .flatten()
+            .filter_map(|node| {
+                node.properties
+                    .clone()
+                    .find(|(name, _)| *name == b"reg")
+                    .and_then(|(_, value)| value.into_reg())
+                    .and_then(|reg| reg.iter().next())
+                    .map(|(base, _)| PhysicalAddress::from(base))
+            })
+            .collect();
+
+        Ok(InterruptControllerInfo {
+            version,
+            distributor: PhysicalAddress::from(distributor),
+            cpu_interface: cpu_interface.map(|(base, _)| PhysicalAddress::from(*base)),
+            redistributors: redistributors
+                .into_iter()
+                .map(|(base, length)| (PhysicalAddress::from(base), length))
+                .collect(),
+            msi_frames,
+        })
+    }
+
+    fn timer(&self) -> Result<TimerInfo, Self::Error> {
+        let node = self
+            .iter_nodes_named(b"/", b"timer")
+            .and_then(|mut nodes| nodes.next())
+            .context(NodeNotFoundSnafu { path: "/timer" })
+            .map_err(ParseError::to_owned)?;
+        let (name, value) = node
+            .properties
+            .clone()
+            .find(|(name, _)| *name == b"interrupts")
+            .ok_or(OwnedParseError::PropertyNotFound { name: "interrupts" })?;
+        let data = value.as_bytes(name).map_err(ParseError::to_owned)?;
+        // the interrupts are given in the order: secure, non-secure, virtual, hypervisor
+        let mut interrupts = data.chunks_exact(12).map(gic_interrupt);
+        let mut next = || interrupts.next().flatten();
+        let secure = next();
+        let non_secure = next().ok_or_else(|| unexpected(name, "missing non-secure timer"))?;
+        let virtual_timer = next().ok_or_else(|| unexpected(name, "missing virtual timer"))?;
+        let hypervisor = next();
+        Ok(TimerInfo {
+            secure,
+            non_secure,
+            virtual_timer,
+            hypervisor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_TREE_BLOB: &[u8] = include_bytes!("device_tree/test-tree.fdt");
+
+    #[test]
+    fn device_tree_platform_info() {
+        let smp = DeviceTree::from_bytes(include_bytes!("device_tree/test-tree-smp8.fdt"));
+        assert_eq!(smp.cores().unwrap().len(), 8);
+
+        let dt = DeviceTree::from_bytes(TEST_TREE_BLOB);
+
+        let gic = dt.interrupt_controller().unwrap();
+        assert_eq!(
+            gic,
+            InterruptControllerInfo {
+                version: 2,
+                distributor: PhysicalAddress::from(0x800_0000),
+                cpu_interface: Some(PhysicalAddress::from(0x801_0000)),
+                redistributors: Vec::new(),
+                msi_frames: [PhysicalAddress::from(0x802_0000)].to_vec(),
+            }
+        );
+
+        let timer = dt.timer().unwrap();
+        assert_eq!(
+            timer.non_secure,
+            TimerInterrupt {
+                id: 30,
+                trigger: TriggerMode::Level
+            }
+        );
+        assert_eq!(timer.virtual_timer.id, 27);
+        assert_eq!(timer.hypervisor.map(|t| t.id), Some(26));
+    }
+}
@@ -1,5 +1,19 @@
 //! Definitions and drivers for the ARM platform.
 
+pub mod acpi;
+pub mod boot_args;
+pub mod branch_protection;
 pub mod cpu;
 pub mod device_tree;
+pub mod gpio;
+pub mod idle;
+pub mod info;
+pub mod power;
+pub mod qemu;
+pub mod rtc;
+pub mod semihosting;
+pub mod smmu;
 pub mod timer;
+pub mod uart;
+pub mod virtio;
+pub mod watchdog;
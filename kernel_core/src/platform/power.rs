@@ -0,0 +1,83 @@
+//! Power management for cores and the system as a whole.
+
+use core::convert::Infallible;
+
+#[cfg(test)]
+use mockall::automock;
+use snafu::Snafu;
+
+use crate::{memory::PhysicalAddress, platform::cpu::Id};
+
+/// Errors that occur due to power management operations.
+#[derive(Debug, Snafu)]
+pub enum PowerManagerError {
+    /// Target core ID is invalid, or some other parameter was rejected.
+    InvalidCoreId,
+    /// Entry point address is invalid.
+    InvalidAddress,
+    /// The target core is already on.
+    AlreadyOn,
+    /// The target core is still booting.
+    Pending,
+    /// The operation is not supported by the platform.
+    NotSupported,
+    /// The platform refused to perform the operation.
+    Denied,
+    /// A miscellaneous internal error has occured.
+    Internal,
+}
+
+/// Mechanism interface for managing core and system power state.
+#[cfg_attr(test, automock)]
+pub trait PowerManager {
+    /// Powers on a core that is currently off.
+    /// The core will start executing at `entry_point_address`, with `arg` passed as the argument.
+    ///
+    /// # Errors
+    /// Returns an error if the underlying hardware interface fails to start the core or rejects
+    /// the given parameters.
+    ///
+    /// # Safety
+    /// The entry point address must be valid or else undefined behavior will occur on the target core.
+    unsafe fn start_core(
+        &self,
+        target_core: Id,
+        entry_point_address: PhysicalAddress,
+        arg: usize,
+    ) -> Result<(), PowerManagerError>;
+
+    /// Suspend the current core in the platform specific low power state `power_state`.
+    ///
+    /// Shallow states return normally once the core wakes up, for instance due to an interrupt.
+    /// States that lose the core's context instead resume execution at `entry_point_address`, with
+    /// `arg` passed as the argument.
+    ///
+    /// # Errors
+    /// Returns an error if the power state or entry point are rejected by the platform.
+    ///
+    /// # Safety
+    /// The entry point address must be valid and able to restore the core's context if a
+    /// powerdown state is requested.
+    unsafe fn suspend_core(
+        &self,
+        power_state: u32,
+        entry_point_address: PhysicalAddress,
+        arg: usize,
+    ) -> Result<(), PowerManagerError>;
+
+    /// Power off the entire system. This only returns if powering off failed.
+    ///
+    /// # Errors
+    /// Returns the reason the system could not be powered off.
+    fn system_off(&self) -> Result<Infallible, PowerManagerError>;
+
+    /// Reset (reboot) the entire system. This only returns if the reset failed.
+    ///
+    /// # Errors
+    /// Returns the reason the system could not be reset.
+    fn system_reset(&self) -> Result<Infallible, PowerManagerError>;
+
+    /// The string value of the "enable-method" device tree property that indicates that a core can
+    /// be enabled with this interface.
+    fn enable_method_name() -> &'static [u8];
+}
@@ -0,0 +1,43 @@
+//! Terminating the QEMU emulator from kernel code, so that automated test runs can report their
+//! result through QEMU's exit status.
+//!
+//! This uses the `SYS_EXIT` operation of the ARM [semihosting](super::semihosting) interface,
+//! which QEMU implements when started with `-semihosting`.
+
+use super::semihosting::{Host, Semihosting};
+
+/// The semihosting operation number of `SYS_EXIT`.
+pub const SYS_EXIT: u32 = 0x18;
+
+/// The `SYS_EXIT` reason code for an application exiting normally, which QEMU turns into an exit
+/// status using the subcode.
+pub const ADP_STOPPED_APPLICATION_EXIT: usize = 0x2_0026;
+
+/// Terminate the emulator, which will exit with status `code`.
+///
+/// This only returns if the host ignored the request.
+pub fn exit(host: &Host<impl Semihosting>, code: u32) {
+    // on 64-bit ARM the parameter is a block holding the reason and the exit status
+    host.call(SYS_EXIT, &[ADP_STOPPED_APPLICATION_EXIT, code as usize]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::semihosting::MockSemihosting;
+
+    #[test]
+    fn exit_passes_code_in_block() {
+        let mut mech = MockSemihosting::new();
+        mech.expect_call()
+            .withf(|operation, parameter| {
+                // SAFETY: `exit` passes a pointer to a block on its stack, which is still live.
+                let block = unsafe { *(*parameter as *const [usize; 2]) };
+                *operation == SYS_EXIT && block == [ADP_STOPPED_APPLICATION_EXIT, 3]
+            })
+            .once()
+            .return_const(0usize);
+        let host = unsafe { Host::new(mech) };
+        exit(&host, 3);
+    }
+}
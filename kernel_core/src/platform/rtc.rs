@@ -0,0 +1,155 @@
+//! Real time clocks (RTCs), which keep the wall clock time while the system is off.
+//!
+//! The kernel reads the RTC once at boot to set the realtime offset of the system [`Clock`], and
+//! afterwards keeps time with the system counter. Setting the time writes both.
+use core::fmt;
+
+use crate::time::{clock::NANOS_PER_SECOND, Clock, CounterReader};
+
+/// Mechanism interface for the registers of a real time clock.
+pub trait RtcMechanism {
+    /// The current time, in seconds since the Unix epoch.
+    fn read_seconds(&self) -> u64;
+
+    /// Set the current time, in seconds since the Unix epoch.
+    fn write_seconds(&self, seconds: u64);
+}
+
+/// Set the realtime offset of `clock` from the time kept by `rtc`, returning that time in seconds
+/// since the Unix epoch.
+pub fn set_clock_from_rtc<C: CounterReader>(rtc: &impl RtcMechanism, clock: &Clock<C>) -> u64 {
+    let seconds = rtc.read_seconds();
+    clock.set_realtime(seconds.saturating_mul(NANOS_PER_SECOND));
+    seconds
+}
+
+/// Set both `rtc` and the realtime offset of `clock` to `nanos` since the Unix epoch. The RTC only
+/// keeps whole seconds.
+pub fn set_time<C: CounterReader>(rtc: &impl RtcMechanism, clock: &Clock<C>, nanos: u64) {
+    rtc.write_seconds(nanos / NANOS_PER_SECOND);
+    clock.set_realtime(nanos);
+}
+
+/// A date and time in UTC, in the proleptic Gregorian calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    /// The year.
+    pub year: u64,
+    /// The month, from 1 to 12.
+    pub month: u8,
+    /// The day of the month, from 1.
+    pub day: u8,
+    /// The hour, from 0 to 23.
+    pub hour: u8,
+    /// The minute, from 0 to 59.
+    pub minute: u8,
+    /// The second, from 0 to 59.
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert a number of seconds since the Unix epoch to a date and time.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        let days = seconds / 86_400;
+        let time = seconds % 86_400;
+        // days since 0000-03-01, so that the leap day is at the end of a year, split into 400
+        // year eras (see Howard Hinnant's `civil_from_days`)
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        Self {
+            year: era * 400 + year_of_era + u64::from(month <= 2),
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time % 3600 / 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            DateTime::from_unix_seconds(0).to_string(),
+            "1970-01-01 00:00:00 UTC"
+        );
+        assert_eq!(
+            DateTime::from_unix_seconds(951_825_600),
+            DateTime {
+                year: 2000,
+                month: 2,
+                day: 29,
+                hour: 12,
+                minute: 0,
+                second: 0
+            }
+        );
+        assert_eq!(
+            DateTime::from_unix_seconds(4_102_444_799).to_string(),
+            "2099-12-31 23:59:59 UTC"
+        );
+    }
+
+    #[test]
+    fn clock_follows_rtc() {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        struct Counter;
+        impl CounterReader for Counter {
+            fn read() -> u64 {
+                COUNTER.load(Ordering::Relaxed)
+            }
+        }
+        #[derive(Default)]
+        struct FakeRtc(AtomicU64);
+        impl RtcMechanism for FakeRtc {
+            fn read_seconds(&self) -> u64 {
+                self.0.load(Ordering::Relaxed)
+            }
+            fn write_seconds(&self, seconds: u64) {
+                self.0.store(seconds, Ordering::Relaxed);
+            }
+        }
+
+        let clock = Clock::<Counter>::new(1000);
+        let rtc = FakeRtc(AtomicU64::new(1_000_000));
+        COUNTER.store(5000, Ordering::Relaxed);
+        assert_eq!(set_clock_from_rtc(&rtc, &clock), 1_000_000);
+        assert_eq!(clock.realtime_nanos(), 1_000_000 * NANOS_PER_SECOND);
+        COUNTER.store(7000, Ordering::Relaxed);
+        assert_eq!(clock.realtime_nanos(), 1_000_002 * NANOS_PER_SECOND);
+
+        set_time(&rtc, &clock, 2_000_000 * NANOS_PER_SECOND + 500);
+        assert_eq!(rtc.read_seconds(), 2_000_000);
+        assert_eq!(clock.realtime_nanos(), 2_000_000 * NANOS_PER_SECOND + 500);
+    }
+}
@@ -0,0 +1,352 @@
+//! The ARM semihosting interface, which lets code running under QEMU or a JTAG debugger use the
+//! host's console and files.
+//!
+//! Semihosting calls trap to the host, so they are only available when a host is attached (for
+//! QEMU, when it is started with `-semihosting`). Without one, the trapping instruction is
+//! undefined. The kernel only uses semihosting when the boot arguments say a host is attached.
+//!
+//! Reference: <https://github.com/ARM-software/abi-aa/blob/main/semihosting/semihosting.rst>
+use alloc::{ffi::CString, vec::Vec};
+use core::ffi::CStr;
+
+#[cfg(test)]
+use mockall::automock;
+use snafu::{ensure, Snafu};
+
+use crate::logger::LogSink;
+
+/// The semihosting operation number of `SYS_OPEN`.
+pub const SYS_OPEN: u32 = 0x01;
+/// The semihosting operation number of `SYS_CLOSE`.
+pub const SYS_CLOSE: u32 = 0x02;
+/// The semihosting operation number of `SYS_WRITE`.
+pub const SYS_WRITE: u32 = 0x05;
+/// The semihosting operation number of `SYS_READ`.
+pub const SYS_READ: u32 = 0x06;
+/// The semihosting operation number of `SYS_FLEN`.
+pub const SYS_FLEN: u32 = 0x0c;
+
+/// The special file name that refers to the host's console.
+pub const CONSOLE_PATH: &CStr = c":tt";
+
+/// Mechanism interface for making semihosting calls.
+#[cfg_attr(test, automock)]
+pub trait Semihosting {
+    /// Make the semihosting call `operation` with `parameter` in `x1`, returning the value the
+    /// host placed in `x0`.
+    ///
+    /// # Safety
+    /// A semihosting host must be attached, and `parameter` must be valid for the operation, for
+    /// instance a pointer to a parameter block that is readable at the current exception level.
+    unsafe fn call(&self, operation: u32, parameter: usize) -> usize;
+}
+
+/// Errors reported by the semihosting host.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The host could not open the file.
+    #[snafu(display("semihosting host could not open {path:?}"))]
+    Open {
+        /// The path of the file.
+        path: CString,
+    },
+    /// The host reported an error for an operation on an open file.
+    #[snafu(display("semihosting operation {operation:#x} failed"))]
+    Operation {
+        /// The semihosting operation number.
+        operation: u32,
+    },
+    /// A file ended before its reported length was read.
+    #[snafu(display("file ended after {read} of {length} bytes"))]
+    UnexpectedEnd {
+        /// The number of bytes that were read.
+        read: usize,
+        /// The length reported by the host.
+        length: usize,
+    },
+}
+
+/// How a file is opened, using the `fopen` mode numbers from the specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum OpenMode {
+    /// Open for reading in binary mode (`"rb"`).
+    Read = 1,
+    /// Open for writing in text mode (`"w"`), truncating the file.
+    Write = 4,
+}
+
+/// A file open on the semihosting host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle(usize);
+
+/// A semihosting host that is known to be attached.
+pub struct Host<S> {
+    mechanism: S,
+}
+
+/// The value a host returns in `x0` to signal failure.
+const FAILED: usize = usize::MAX;
+
+impl<S: Semihosting> Host<S> {
+    /// Make semihosting calls with `mechanism`.
+    ///
+    /// # Safety
+    /// A semihosting host must be attached for as long as the returned value is used.
+    pub const unsafe fn new(mechanism: S) -> Self {
+        Self { mechanism }
+    }
+
+    /// Make the semihosting call `operation` with a parameter block.
+    pub(crate) fn call(&self, operation: u32, block: &[usize]) -> usize {
+        // SAFETY: the host is attached by the contract of `new`, and `block` is a valid parameter
+        // block for each operation this module makes.
+        unsafe { self.mechanism.call(operation, block.as_ptr() as usize) }
+    }
+
+    /// Open the file at `path` on the host.
+    ///
+    /// # Errors
+    /// - [`Error::Open`] if the host could not open the file.
+    pub fn open(&self, path: &CStr, mode: OpenMode) -> Result<Handle, Error> {
+        let result = self.call(
+            SYS_OPEN,
+            &[path.as_ptr() as usize, mode as usize, path.count_bytes()],
+        );
+        ensure!(result != FAILED, OpenSnafu { path });
+        Ok(Handle(result))
+    }
+
+    /// Close a file that was opened with [`Self::open`].
+    ///
+    /// # Errors
+    /// - [`Error::Operation`] if the host reported an error.
+    pub fn close(&self, handle: Handle) -> Result<(), Error> {
+        ensure!(
+            self.call(SYS_CLOSE, &[handle.0]) == 0,
+            OperationSnafu {
+                operation: SYS_CLOSE
+            }
+        );
+        Ok(())
+    }
+
+    /// Write `data` to a file, returning the number of bytes written.
+    ///
+    /// # Errors
+    /// - [`Error::Operation`] if the host reported an error.
+    pub fn write(&self, handle: Handle, data: &[u8]) -> Result<usize, Error> {
+        // the host returns the number of bytes that were *not* written
+        let unwritten = self.call(SYS_WRITE, &[handle.0, data.as_ptr() as usize, data.len()]);
+        data.len().checked_sub(unwritten).ok_or(Error::Operation {
+            operation: SYS_WRITE,
+        })
+    }
+
+    /// Read from a file into `buffer`, returning the number of bytes read, which is zero at the end
+    /// of the file.
+    ///
+    /// # Errors
+    /// - [`Error::Operation`] if the host reported an error.
+    pub fn read(&self, handle: Handle, buffer: &mut [u8]) -> Result<usize, Error> {
+        // the host returns the number of bytes that were *not* read
+        let unread = self.call(
+            SYS_READ,
+            &[handle.0, buffer.as_mut_ptr() as usize, buffer.len()],
+        );
+        buffer.len().checked_sub(unread).ok_or(Error::Operation {
+            operation: SYS_READ,
+        })
+    }
+
+    /// The length of a file in bytes.
+    ///
+    /// # Errors
+    /// - [`Error::Operation`] if the host reported an error.
+    pub fn length(&self, handle: Handle) -> Result<usize, Error> {
+        let length = self.call(SYS_FLEN, &[handle.0]);
+        ensure!(
+            length != FAILED,
+            OperationSnafu {
+                operation: SYS_FLEN
+            }
+        );
+        Ok(length)
+    }
+
+    /// Read the entire file at `path` on the host, for instance to load a test fixture.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be opened or read, or is shorter than the host claimed.
+    pub fn read_file(&self, path: &CStr) -> Result<Vec<u8>, Error> {
+        let handle = self.open(path, OpenMode::Read)?;
+        let result = self.length(handle).and_then(|length| {
+            let mut data = alloc::vec![0; length];
+            let mut read = 0;
+            while read < length {
+                let n = self.read(handle, &mut data[read..])?;
+                ensure!(n > 0, UnexpectedEndSnafu { read, length });
+                read += n;
+            }
+            Ok(data)
+        });
+        // a failure to close leaks a host file handle, which isn't worth failing the read over
+        let _ = self.close(handle);
+        result
+    }
+}
+
+/// A [`LogSink`] that writes to the host's console with `SYS_WRITE`.
+pub struct SemihostingConsole<'h, S> {
+    host: &'h Host<S>,
+    handle: Handle,
+}
+
+impl<'h, S: Semihosting> SemihostingConsole<'h, S> {
+    /// Open the host's console for writing.
+    ///
+    /// # Errors
+    /// - [`Error::Open`] if the host could not open its console.
+    pub fn open(host: &'h Host<S>) -> Result<Self, Error> {
+        let handle = host.open(CONSOLE_PATH, OpenMode::Write)?;
+        Ok(Self { host, handle })
+    }
+}
+
+impl<S: Semihosting> LogSink for SemihostingConsole<'_, S> {
+    fn accept(&mut self, mut chunk: &[u8]) {
+        while !chunk.is_empty() {
+            match self.host.write(self.handle, chunk) {
+                Ok(n) if n > 0 => chunk = &chunk[n..],
+                // the host console is gone, so there is nowhere to report this
+                _ => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Read the parameter block of `N` words at `parameter`.
+    fn block<const N: usize>(parameter: usize) -> [usize; N] {
+        // SAFETY: `Host::call` passes a pointer to a live parameter block of at least `N` words.
+        unsafe { *(parameter as *const [usize; N]) }
+    }
+
+    #[test]
+    fn read_file_in_pieces() {
+        let mut mech = MockSemihosting::new();
+        let contents = b"fixture data";
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_OPEN)
+            .once()
+            .returning(|_, p| {
+                let [path, mode, len] = block(p);
+                let path = unsafe { CStr::from_ptr(path as *const _) };
+                assert_eq!((path, mode, len), (c"fixture.bin", 1, 11));
+                7
+            });
+        mech.expect_call()
+            .withf(|op, p| *op == SYS_FLEN && block::<1>(*p) == [7])
+            .once()
+            .return_const(contents.len());
+        let offset = Arc::new(Mutex::new(0));
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_READ)
+            .times(2)
+            .returning(move |_, p| {
+                let [handle, buffer, len] = block(p);
+                assert_eq!(handle, 7);
+                // give the data back five bytes at a time, then the rest
+                let mut offset = offset.lock().unwrap();
+                let n = if *offset == 0 { 5 } else { len };
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        contents[*offset..].as_ptr(),
+                        buffer as *mut u8,
+                        n,
+                    );
+                }
+                *offset += n;
+                len - n
+            });
+        mech.expect_call()
+            .withf(|op, p| *op == SYS_CLOSE && block::<1>(*p) == [7])
+            .once()
+            .return_const(0usize);
+        let host = unsafe { Host::new(mech) };
+        assert_eq!(host.read_file(c"fixture.bin").unwrap(), contents);
+    }
+
+    #[test]
+    fn read_file_errors() {
+        let mut mech = MockSemihosting::new();
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_OPEN)
+            .once()
+            .return_const(FAILED);
+        let host = unsafe { Host::new(mech) };
+        assert!(matches!(
+            host.read_file(c"missing"),
+            Err(Error::Open { path }) if path == c"missing"
+        ));
+
+        let mut mech = MockSemihosting::new();
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_OPEN)
+            .return_const(3usize);
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_FLEN)
+            .return_const(10usize);
+        // the file ends immediately
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_READ)
+            .returning(|_, p| block::<3>(p)[2]);
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_CLOSE)
+            .once()
+            .return_const(0usize);
+        let host = unsafe { Host::new(mech) };
+        assert!(matches!(
+            host.read_file(c"short"),
+            Err(Error::UnexpectedEnd {
+                read: 0,
+                length: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn console_writes_every_byte() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut mech = MockSemihosting::new();
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_OPEN)
+            .once()
+            .returning(|_, p| {
+                let [path, mode, _] = block(p);
+                assert_eq!(unsafe { CStr::from_ptr(path as *const _) }, CONSOLE_PATH);
+                assert_eq!(mode, OpenMode::Write as usize);
+                1
+            });
+        let w = written.clone();
+        mech.expect_call()
+            .withf(|op, _| *op == SYS_WRITE)
+            .returning(move |_, p| {
+                let [handle, data, len] = block(p);
+                assert_eq!(handle, 1);
+                // accept at most four bytes per call
+                let n = len.min(4);
+                let data = unsafe { core::slice::from_raw_parts(data as *const u8, n) };
+                w.lock().unwrap().extend_from_slice(data);
+                len - n
+            });
+        let host = unsafe { Host::new(mech) };
+        let mut console = SemihostingConsole::open(&host).unwrap();
+        console.accept(b"hello, world");
+        assert_eq!(written.lock().unwrap().as_slice(), b"hello, world");
+    }
+}
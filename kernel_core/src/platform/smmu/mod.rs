@@ -0,0 +1,90 @@
+//! Drivers for the Arm System MMU, the IOMMU of Arm systems.
+//!
+//! The SMMU identifies each device by its stream ID, and decides how to handle its DMA from the
+//! configuration for that stream. Until a device is attached to an address space its DMA is
+//! aborted, except for the streams that the SMMU is told to let bypass translation when it is set
+//! up, which are those of the devices the kernel drives itself. Attached devices are translated
+//! through stage 1 page tables in the same format as the CPU's (see [`crate::memory::iommu`]), and
+//! detached devices are blocked again.
+//!
+//! Versions 2 and 3 of the architecture have entirely different programming interfaces, so each
+//! has its own driver: [`v2`] (for instance the MMU-500) and [`v3`] (for QEMU,
+//! `-machine virt,iommu=smmuv3`).
+use snafu::Snafu;
+
+pub mod v2;
+pub mod v3;
+
+/// The largest number of streams supported, which keeps the tables of the drivers small.
+pub const MAX_STREAMS: usize = 256;
+
+/// The number of times to poll the SMMU before giving up waiting for it.
+const POLL_LIMIT: usize = 1_000_000;
+
+/// Ranges of more than this many pages are invalidated by invalidating their whole address space.
+const MAX_INVALIDATE_PAGES: usize = 32;
+
+/// Mechanism interface for the registers of an SMMU.
+pub trait Registers {
+    /// Read the 32-bit register at `offset` bytes.
+    fn read(&self, offset: usize) -> u32;
+
+    /// Write `value` to the 32-bit register at `offset` bytes.
+    fn write(&self, offset: usize, value: u32);
+}
+
+/// Errors setting up an SMMU.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The SMMU lacks a feature the driver needs.
+    #[snafu(display("SMMU does not support {feature} (ID register {id:#x})"))]
+    Unsupported {
+        /// The missing feature.
+        feature: &'static str,
+        /// The value of the ID register that reports the feature.
+        id: u32,
+    },
+    /// The SMMU did not acknowledge a change to its configuration.
+    #[snafu(display("SMMU did not acknowledge CR0 {value:#x}"))]
+    Timeout {
+        /// The value written to CR0.
+        value: u32,
+    },
+    /// The SMMU did not complete the commands that clear its caches.
+    #[snafu(display("SMMU did not complete its initial invalidation"))]
+    Invalidate {
+        /// Underlying error.
+        source: crate::memory::iommu::Error,
+    },
+    /// A stream that should bypass translation can't be configured.
+    #[snafu(display("SMMU can't let stream {stream} bypass translation"))]
+    Bypass {
+        /// The stream ID.
+        stream: crate::memory::iommu::StreamId,
+    },
+    /// Memory for the SMMU's tables could not be allocated.
+    #[snafu(display("allocate SMMU tables"))]
+    Memory {
+        /// Underlying memory error.
+        source: crate::memory::Error,
+    },
+}
+
+/// Write the 64-bit register at `offset`, 32 bits at a time.
+// the value is split into halves
+#[allow(clippy::cast_possible_truncation)]
+fn write_u64(regs: &impl Registers, offset: usize, value: u64) {
+    regs.write(offset, value as u32);
+    regs.write(offset + 4, (value >> 32) as u32);
+}
+
+/// Poll `done` until it returns true, giving up after [`POLL_LIMIT`] tries.
+fn poll(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
@@ -0,0 +1,537 @@
+//! Driver for the Arm System MMU version 2, such as the MMU-500.
+//!
+//! The SMMU compares each device's stream ID with its stream match registers (SMRs), and the
+//! stream-to-context register (S2CR) paired with the matching SMR decides whether the DMA bypasses
+//! translation, faults, or is translated by a context bank. Each context bank holds the
+//! configuration of one address space. So every attached stream takes an SMR and the context bank
+//! with the same index, and every stream that bypasses translation takes an SMR. Streams that
+//! match no SMR fault.
+//!
+//! Only SMMUs with stream matching that can translate with 64-bit stage 1 page tables are
+//! supported. Each context bank has its own ASID, which is its index, and a range is invalidated
+//! by invalidating its whole context bank.
+//!
+//! Reference: Arm System Memory Management Unit Architecture Specification, SMMU architecture
+//! versions 1 and 2 (IHI 0062).
+use alloc::vec::Vec;
+
+use snafu::{ensure, ResultExt as _};
+
+use super::{
+    poll, write_u64, BypassSnafu, Error, InvalidateSnafu, Registers, UnsupportedSnafu, MAX_STREAMS,
+};
+use crate::{
+    memory::{
+        iommu::{self, IoMmu, StreamId},
+        page_table::MAIR_VALUE,
+        AddressSpaceId, PageSize, PhysicalAddress, VirtualAddress,
+    },
+    sync::Mutex,
+};
+
+/// Register offsets, in bytes.
+pub mod regs {
+    /// Configures the SMMU as a whole, in global register space 0.
+    pub const SCR0: usize = 0x000;
+    /// Identifies the stream matching and translation features of the SMMU.
+    pub const IDR0: usize = 0x020;
+    /// Identifies the layout of the register spaces and the number of context banks.
+    pub const IDR1: usize = 0x024;
+    /// Identifies the supported page table formats.
+    pub const IDR2: usize = 0x028;
+    /// Records global faults, which are cleared by writing back the bits that are set.
+    pub const SGFSR: usize = 0x048;
+    /// Invalidates every cached translation.
+    pub const TLBIALLNSNH: usize = 0x068;
+    /// Starts waiting for global invalidations to complete.
+    pub const TLBGSYNC: usize = 0x070;
+    /// Reports whether global invalidations are still in progress.
+    pub const TLBGSTATUS: usize = 0x074;
+    /// The first stream match register, followed by one for each of the others.
+    pub const SMR: usize = 0x800;
+    /// The first stream-to-context register, followed by one for each of the others.
+    pub const S2CR: usize = 0xc00;
+
+    /// The first context bank attribute register, in global register space 1.
+    pub const CBAR: usize = 0x000;
+    /// The first extended context bank attribute register, in global register space 1.
+    pub const CBA2R: usize = 0x800;
+
+    /// Enables translation by a context bank, in the bank's page.
+    pub const CB_SCTLR: usize = 0x000;
+    /// The physical address size of a context bank.
+    pub const CB_TCR2: usize = 0x010;
+    /// The root page table and ASID of a context bank (64 bits).
+    pub const CB_TTBR0: usize = 0x020;
+    /// How a context bank walks its page tables.
+    pub const CB_TCR: usize = 0x030;
+    /// The low half of the memory attributes of a context bank.
+    pub const CB_MAIR0: usize = 0x038;
+    /// The high half of the memory attributes of a context bank.
+    pub const CB_MAIR1: usize = 0x03c;
+    /// Records faults of a context bank, which are cleared by writing back the bits that are set.
+    pub const CB_FSR: usize = 0x058;
+    /// Invalidates the cached translations of an ASID in a context bank.
+    pub const CB_TLBIASID: usize = 0x610;
+    /// Starts waiting for the invalidations of a context bank to complete.
+    pub const CB_TLBSYNC: usize = 0x7f0;
+    /// Reports whether the invalidations of a context bank are still in progress.
+    pub const CB_TLBSTATUS: usize = 0x7f4;
+
+    /// [`IDR0`] bit set if stage 1 translation is supported.
+    pub const IDR0_S1TS: u32 = 1 << 30;
+    /// [`IDR0`] bit set if streams are matched with SMRs, rather than indexing the S2CRs.
+    pub const IDR0_SMS: u32 = 1 << 27;
+    /// [`IDR1`] bit set if the register spaces are 64KiB pages, rather than 4KiB.
+    pub const IDR1_PAGESIZE: u32 = 1 << 31;
+    /// [`IDR2`] bit set if 64-bit page tables with 4KiB pages are supported.
+    pub const IDR2_PTFS_4K: u32 = 1 << 12;
+    /// [`IDR2`] bit set if 64-bit page tables with 16KiB pages are supported.
+    pub const IDR2_PTFS_16K: u32 = 1 << 13;
+    /// [`SCR0`] bit that makes every stream bypass translation.
+    pub const SCR0_CLIENTPD: u32 = 1 << 0;
+    /// [`SCR0`] bit that makes streams that match no SMR fault, rather than bypass translation.
+    pub const SCR0_USFCFG: u32 = 1 << 10;
+    /// [`SMR`] bit set if the register is in use.
+    pub const SMR_VALID: u32 = 1 << 31;
+    /// [`S2CR`] type for streams translated by the context bank in the low bits.
+    pub const S2CR_TRANSLATE: u32 = 0b00 << 16;
+    /// [`S2CR`] type for streams that bypass translation.
+    pub const S2CR_BYPASS: u32 = 0b01 << 16;
+    /// [`S2CR`] type for streams that fault.
+    pub const S2CR_FAULT: u32 = 0b10 << 16;
+    /// [`CBAR`] type of a context bank that translates with stage 1 only.
+    pub const CBAR_S1_BYPASS_S2: u32 = 0b01 << 16;
+    /// [`CBA2R`] bit for context banks with 64-bit page tables.
+    pub const CBA2R_VA64: u32 = 1 << 0;
+    /// [`CB_SCTLR`] bit that enables translation.
+    pub const SCTLR_M: u32 = 1 << 0;
+    /// [`TLBGSTATUS`] and [`CB_TLBSTATUS`] bit set while invalidations are in progress.
+    pub const TLBSTATUS_ACTIVE: u32 = 1 << 0;
+}
+
+/// The use of an SMR, its S2CR, and the context bank with the same index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Free,
+    Bypass(StreamId),
+    Translate {
+        stream: StreamId,
+        /// The ASID the address space is known by outside the driver.
+        asid: AddressSpaceId,
+    },
+}
+
+impl Slot {
+    fn stream(self) -> Option<StreamId> {
+        match self {
+            Slot::Free => None,
+            Slot::Bypass(stream) | Slot::Translate { stream, .. } => Some(stream),
+        }
+    }
+}
+
+/// An SMMU version 2.
+pub struct Smmu<R> {
+    regs: R,
+    num_streams: usize,
+    /// The size of a page of the register spaces, in bytes.
+    register_page: usize,
+    /// The offset of the first context bank's page.
+    context_banks: usize,
+    page_size: PageSize,
+    slots: Mutex<Vec<Slot>>,
+}
+
+impl<R: Registers> Smmu<R> {
+    /// Set up the SMMU behind `regs`, with DMA from the `bypass` streams reaching physical memory
+    /// untranslated and DMA from every other stream faulting.
+    ///
+    /// # Errors
+    /// - [`Error::Unsupported`] if the SMMU can't match streams, or translate with 64-bit stage
+    ///   1 page tables with pages of `page_size`.
+    /// - [`Error::Bypass`] if one of the `bypass` streams is out of range, or there are not
+    ///   enough SMRs for them.
+    /// - [`Error::Invalidate`] if the SMMU's caches could not be cleared.
+    pub fn new(regs: R, page_size: PageSize, bypass: &[StreamId]) -> Result<Self, Error> {
+        let idr0 = regs.read(regs::IDR0);
+        ensure!(
+            idr0 & regs::IDR0_S1TS != 0,
+            UnsupportedSnafu {
+                feature: "stage 1 translation",
+                id: idr0
+            }
+        );
+        ensure!(
+            idr0 & regs::IDR0_SMS != 0,
+            UnsupportedSnafu {
+                feature: "stream matching",
+                id: idr0
+            }
+        );
+        let idr2 = regs.read(regs::IDR2);
+        let granule = match page_size {
+            PageSize::FourKiB => regs::IDR2_PTFS_4K,
+            PageSize::SixteenKiB => regs::IDR2_PTFS_16K,
+        };
+        ensure!(
+            idr2 & granule != 0,
+            UnsupportedSnafu {
+                feature: "AArch64 page tables with the kernel's page size",
+                id: idr2
+            }
+        );
+        let idr1 = regs.read(regs::IDR1);
+        let register_page = if idr1 & regs::IDR1_PAGESIZE == 0 {
+            0x1000
+        } else {
+            0x1_0000
+        };
+        // the global register spaces take 2^(NUMPAGENDXB + 1) pages
+        let context_banks = register_page << (((idr1 >> 28) & 0b111) + 1);
+        let num_slots = (idr0 & 0xff).min(idr1 & 0xff) as usize;
+        let num_streams = (1 << ((idr0 >> 9) & 0xf)).min(MAX_STREAMS);
+        if let Some(&stream) = bypass
+            .iter()
+            .enumerate()
+            .find(|(i, s)| **s as usize >= num_streams || *i >= num_slots)
+            .map(|(_, s)| s)
+        {
+            return BypassSnafu { stream }.fail();
+        }
+
+        let mut slots = alloc::vec![Slot::Free; num_slots];
+        for (slot, stream) in slots.iter_mut().zip(bypass) {
+            *slot = Slot::Bypass(*stream);
+        }
+        let smmu = Self {
+            regs,
+            num_streams,
+            register_page,
+            context_banks,
+            page_size,
+            slots: Mutex::new(slots),
+        };
+        smmu.enable()?;
+        Ok(smmu)
+    }
+
+    /// The number of streams the SMMU can translate.
+    #[must_use]
+    pub fn num_streams(&self) -> usize {
+        self.num_streams
+    }
+
+    /// Program the SMRs and S2CRs for the streams that bypass translation, disable every context
+    /// bank and clear the SMMU's caches, then enable translation.
+    fn enable(&self) -> Result<(), Error> {
+        // every stream bypasses translation while the SMMU is reprogrammed
+        self.regs.write(regs::SCR0, regs::SCR0_CLIENTPD);
+        self.regs.write(regs::SGFSR, self.regs.read(regs::SGFSR));
+        let slots = self.slots.lock();
+        for (index, slot) in slots.iter().enumerate() {
+            self.disable_bank(index);
+            self.regs
+                .write(self.context_bank(index) + regs::CB_FSR, u32::MAX);
+            match slot {
+                Slot::Bypass(stream) => self.set_stream(index, *stream, regs::S2CR_BYPASS),
+                _ => self.clear_stream(index),
+            }
+        }
+        self.regs.write(regs::TLBIALLNSNH, 0);
+        self.regs.write(regs::TLBGSYNC, 0);
+        if !poll(|| self.regs.read(regs::TLBGSTATUS) & regs::TLBSTATUS_ACTIVE == 0) {
+            return Err(iommu::Error::Timeout).context(InvalidateSnafu);
+        }
+        self.regs.write(regs::SCR0, regs::SCR0_USFCFG);
+        Ok(())
+    }
+
+    /// The offset of the page of the context bank `index`.
+    fn context_bank(&self, index: usize) -> usize {
+        self.context_banks + index * self.register_page
+    }
+
+    /// Make the SMR and S2CR `index` direct DMA from `stream` with the S2CR value `s2cr`.
+    fn set_stream(&self, index: usize, stream: StreamId, s2cr: u32) {
+        // the S2CR must be ready before the SMR matches
+        self.regs.write(regs::S2CR + index * 4, s2cr);
+        self.regs
+            .write(regs::SMR + index * 4, regs::SMR_VALID | stream);
+    }
+
+    /// Make the SMR `index` match nothing, so that the stream it matched faults.
+    fn clear_stream(&self, index: usize) {
+        self.regs.write(regs::SMR + index * 4, 0);
+        self.regs.write(regs::S2CR + index * 4, regs::S2CR_FAULT);
+    }
+
+    /// Stop the context bank `index` from translating.
+    fn disable_bank(&self, index: usize) {
+        self.regs
+            .write(self.context_bank(index) + regs::CB_SCTLR, 0);
+    }
+
+    /// Invalidate the cached translations of the context bank `index`, and wait for the
+    /// invalidation to complete.
+    fn invalidate_bank(&self, index: usize) -> Result<(), iommu::Error> {
+        let bank = self.context_bank(index);
+        // context banks fit in 8 bits
+        #[allow(clippy::cast_possible_truncation)]
+        self.regs.write(bank + regs::CB_TLBIASID, index as u32);
+        self.regs.write(bank + regs::CB_TLBSYNC, 0);
+        if poll(|| self.regs.read(bank + regs::CB_TLBSTATUS) & regs::TLBSTATUS_ACTIVE == 0) {
+            Ok(())
+        } else {
+            log::warn!("SMMU did not complete invalidation of context bank {index}");
+            Err(iommu::Error::Timeout)
+        }
+    }
+
+    /// Program the context bank `index` to translate through the page tables at `root`.
+    fn program_bank(&self, index: usize, root: PhysicalAddress) {
+        let bank = self.context_bank(index);
+        let granule = match self.page_size {
+            PageSize::FourKiB => 0b00,
+            PageSize::SixteenKiB => 0b10,
+        };
+        self.regs.write(
+            regs::CBAR + self.register_page + index * 4,
+            regs::CBAR_S1_BYPASS_S2,
+        );
+        self.regs.write(
+            regs::CBA2R + self.register_page + index * 4,
+            regs::CBA2R_VA64,
+        );
+        // 48-bit physical addresses
+        self.regs.write(bank + regs::CB_TCR2, 0b101);
+        let tcr = 16 // T0SZ, 48-bit addresses
+            | 0b01 << 8 // inner write-back table walks
+            | 0b01 << 10 // outer write-back table walks
+            | 0b11 << 12 // inner shareable table walks
+            | granule << 14
+            | 1 << 23; // no TTB1 walks
+        self.regs.write(bank + regs::CB_TCR, tcr);
+        write_u64(
+            &self.regs,
+            bank + regs::CB_TTBR0,
+            usize::from(root) as u64 & 0x0000_ffff_ffff_fff0 | (index as u64) << 48,
+        );
+        // the value is split into halves
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            self.regs.write(bank + regs::CB_MAIR0, MAIR_VALUE as u32);
+            self.regs
+                .write(bank + regs::CB_MAIR1, (MAIR_VALUE >> 32) as u32);
+        }
+    }
+
+    /// Check that `stream` can be matched.
+    fn check_stream(&self, stream: StreamId) -> Result<(), iommu::Error> {
+        if stream as usize >= self.num_streams {
+            return Err(iommu::Error::UnknownStream { stream });
+        }
+        Ok(())
+    }
+}
+
+impl<R: Registers> IoMmu for Smmu<R> {
+    fn attach(
+        &self,
+        stream: StreamId,
+        asid: AddressSpaceId,
+        root: PhysicalAddress,
+    ) -> Result<(), iommu::Error> {
+        self.check_stream(stream)?;
+        let mut slots = self.slots.lock();
+        let index = slots
+            .iter()
+            .position(|s| s.stream() == Some(stream))
+            .or_else(|| slots.iter().position(|s| *s == Slot::Free))
+            .ok_or(iommu::Error::Memory {
+                source: crate::memory::Error::OutOfMemory,
+            })?;
+        // block the stream while its context bank is replaced
+        self.clear_stream(index);
+        self.disable_bank(index);
+        self.program_bank(index, root);
+        // stale translations may remain from a previous user of the context bank
+        self.invalidate_bank(index)?;
+        self.regs
+            .write(self.context_bank(index) + regs::CB_SCTLR, regs::SCTLR_M);
+        // context banks fit in 8 bits
+        #[allow(clippy::cast_possible_truncation)]
+        self.set_stream(index, stream, regs::S2CR_TRANSLATE | index as u32);
+        slots[index] = Slot::Translate { stream, asid };
+        Ok(())
+    }
+
+    fn detach(&self, stream: StreamId) -> Result<(), iommu::Error> {
+        self.check_stream(stream)?;
+        let mut slots = self.slots.lock();
+        let Some(index) = slots.iter().position(|s| s.stream() == Some(stream)) else {
+            // streams that match no SMR already fault
+            return Ok(());
+        };
+        self.clear_stream(index);
+        self.disable_bank(index);
+        // the context bank is only reused once nothing is cached for it
+        self.invalidate_bank(index)?;
+        slots[index] = Slot::Free;
+        Ok(())
+    }
+
+    fn invalidate_range(
+        &self,
+        asid: AddressSpaceId,
+        _start: VirtualAddress,
+        _length: usize,
+    ) -> Result<(), iommu::Error> {
+        self.invalidate_asid(asid)
+    }
+
+    fn invalidate_asid(&self, asid: AddressSpaceId) -> Result<(), iommu::Error> {
+        let slots = self.slots.lock();
+        match slots
+            .iter()
+            .position(|s| matches!(s, Slot::Translate { asid: a, .. } if *a == asid))
+        {
+            Some(index) => self.invalidate_bank(index),
+            // nothing is cached for address spaces that are not attached
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use super::*;
+
+    /// The registers of a fake SMMU, whose invalidations complete immediately unless the status
+    /// register of a context bank is set to say otherwise.
+    #[derive(Default)]
+    struct FakeRegisters {
+        registers: Mutex<HashMap<usize, u32>>,
+    }
+
+    impl FakeRegisters {
+        fn new(idr0: u32, idr1: u32, idr2: u32) -> Self {
+            Self {
+                registers: Mutex::new(HashMap::from([
+                    (regs::IDR0, idr0),
+                    (regs::IDR1, idr1),
+                    (regs::IDR2, idr2),
+                ])),
+            }
+        }
+
+        fn register(&self, offset: usize) -> u32 {
+            self.registers
+                .lock()
+                .unwrap()
+                .get(&offset)
+                .copied()
+                .unwrap_or_default()
+        }
+
+        /// The SMR and S2CR with `index`.
+        fn stream(&self, index: usize) -> (u32, u32) {
+            (
+                self.register(regs::SMR + index * 4),
+                self.register(regs::S2CR + index * 4),
+            )
+        }
+    }
+
+    impl Registers for &FakeRegisters {
+        fn read(&self, offset: usize) -> u32 {
+            self.register(offset)
+        }
+
+        fn write(&self, offset: usize, value: u32) {
+            self.registers.lock().unwrap().insert(offset, value);
+        }
+    }
+
+    /// 16 streams, 4 SMRs and 4 context banks in 4KiB pages after two pages of global registers.
+    fn fake() -> FakeRegisters {
+        FakeRegisters::new(
+            regs::IDR0_S1TS | regs::IDR0_SMS | 4 << 9 | 4,
+            4,
+            regs::IDR2_PTFS_4K,
+        )
+    }
+
+    #[test]
+    fn streams_fault_until_attached() {
+        let regs = fake();
+        let smmu = Smmu::new(&regs, PageSize::FourKiB, &[9]).unwrap();
+        assert_eq!(smmu.num_streams(), 16);
+        assert_eq!(regs.register(regs::SCR0), regs::SCR0_USFCFG);
+        assert_eq!(regs.stream(0), (regs::SMR_VALID | 9, regs::S2CR_BYPASS));
+        for index in 1..4 {
+            assert_eq!(regs.stream(index), (0, regs::S2CR_FAULT));
+        }
+
+        smmu.attach(3, 42, PhysicalAddress::from(0x4000_0000))
+            .unwrap();
+        assert_eq!(
+            regs.stream(1),
+            (regs::SMR_VALID | 3, regs::S2CR_TRANSLATE | 1)
+        );
+        let bank = 0x2000 + 0x1000;
+        assert_eq!(regs.register(bank + regs::CB_SCTLR), regs::SCTLR_M);
+        assert_eq!(regs.register(bank + regs::CB_TTBR0), 0x4000_0000);
+        assert_eq!(regs.register(bank + regs::CB_TTBR0 + 4), 1 << 16);
+        assert_eq!(regs.register(0x1000 + regs::CBA2R + 4), regs::CBA2R_VA64);
+        smmu.invalidate_asid(42).unwrap();
+        assert_eq!(regs.register(bank + regs::CB_TLBIASID), 1);
+
+        for stream in [5, 6] {
+            smmu.attach(stream, 1, PhysicalAddress::from(0x4000_0000))
+                .unwrap();
+        }
+        assert!(matches!(
+            smmu.attach(7, 1, PhysicalAddress::from(0x4000_0000)),
+            Err(iommu::Error::Memory { .. })
+        ));
+        assert!(matches!(
+            smmu.attach(16, 1, PhysicalAddress::from(0x4000_0000)),
+            Err(iommu::Error::UnknownStream { stream: 16 })
+        ));
+
+        smmu.detach(3).unwrap();
+        assert_eq!(regs.stream(1), (0, regs::S2CR_FAULT));
+        assert_eq!(regs.register(bank + regs::CB_SCTLR), 0);
+        smmu.attach(7, 1, PhysicalAddress::from(0x4000_0000))
+            .unwrap();
+        assert_eq!(regs.stream(1).0, regs::SMR_VALID | 7);
+
+        // invalidations the SMMU never completes are reported
+        regs.registers
+            .lock()
+            .unwrap()
+            .insert(bank + regs::CB_TLBSTATUS, regs::TLBSTATUS_ACTIVE);
+        assert!(matches!(smmu.detach(7), Err(iommu::Error::Timeout)));
+    }
+
+    #[test]
+    fn requires_stream_matching() {
+        let regs = FakeRegisters::new(regs::IDR0_S1TS | 4, 4, regs::IDR2_PTFS_4K);
+        assert!(matches!(
+            Smmu::new(&regs, PageSize::FourKiB, &[]),
+            Err(Error::Unsupported { .. })
+        ));
+        let regs = fake();
+        assert!(matches!(
+            Smmu::new(&regs, PageSize::SixteenKiB, &[]),
+            Err(Error::Unsupported { .. })
+        ));
+        assert!(matches!(
+            Smmu::new(&regs, PageSize::FourKiB, &[1, 2, 3, 4, 5]),
+            Err(Error::Bypass { stream: 5 })
+        ));
+    }
+}
@@ -0,0 +1,576 @@
+//! Driver for the Arm System MMU version 3 (for QEMU, `-machine virt,iommu=smmuv3`).
+//!
+//! The SMMU looks up each device's stream ID in a stream table to decide how to handle its DMA,
+//! and changes to the tables are applied with commands sent through a queue in memory.
+//!
+//! Only a linear stream table of at most [`MAX_STREAMS`] entries is supported, and the event queue
+//! is not used, so translation faults are not reported.
+//!
+//! Reference: Arm System Memory Management Unit Architecture Specification, SMMU architecture
+//! version 3 (IHI 0070).
+use core::sync::atomic::{fence, Ordering};
+
+use snafu::{ensure, ResultExt as _};
+
+use super::{
+    poll, write_u64, BypassSnafu, Error, InvalidateSnafu, MemorySnafu, Registers, TimeoutSnafu,
+    UnsupportedSnafu, MAX_INVALIDATE_PAGES, MAX_STREAMS,
+};
+use crate::{
+    memory::{
+        iommu::{self, IoMmu, StreamId},
+        page_table::MAIR_VALUE,
+        AddressSpaceId, AllocationConstraints, DmaAllocator, DmaBuffer, PageAllocator, PageSize,
+        PhysicalAddress, VirtualAddress,
+    },
+    sync::Mutex,
+};
+
+/// Register offsets, in bytes.
+pub mod regs {
+    /// Identifies the features of the SMMU.
+    pub const IDR0: usize = 0x00;
+    /// Identifies the sizes of the tables and queues of the SMMU.
+    pub const IDR1: usize = 0x04;
+    /// Enables the SMMU and its queues.
+    pub const CR0: usize = 0x20;
+    /// Reads as the value of [`CR0`] once the SMMU has applied it.
+    pub const CR0ACK: usize = 0x24;
+    /// Cacheability and shareability of the SMMU's accesses to its tables and queues.
+    pub const CR1: usize = 0x28;
+    /// The physical address of the stream table (64 bits).
+    pub const STRTAB_BASE: usize = 0x80;
+    /// The format and size of the stream table.
+    pub const STRTAB_BASE_CFG: usize = 0x88;
+    /// The physical address and size of the command queue (64 bits).
+    pub const CMDQ_BASE: usize = 0x90;
+    /// The index of the next command the driver will write.
+    pub const CMDQ_PROD: usize = 0x98;
+    /// The index of the next command the SMMU will consume.
+    pub const CMDQ_CONS: usize = 0x9c;
+
+    /// [`IDR0`] bit set if stage 1 translation is supported.
+    pub const IDR0_S1P: u32 = 1 << 1;
+    /// [`CR0`] bit that enables translation.
+    pub const CR0_SMMUEN: u32 = 1 << 0;
+    /// [`CR0`] bit that enables the command queue.
+    pub const CR0_CMDQEN: u32 = 1 << 3;
+    /// [`CR1`] value making the tables and queues inner shareable, write-back cacheable memory.
+    pub const CR1_CACHEABLE: u32 = 0b11_01_01_11_01_01;
+}
+
+/// The size of a stream table entry (STE) and a context descriptor (CD), in bytes.
+const ENTRY_SIZE: usize = 64;
+
+/// The size of a command, in bytes.
+const COMMAND_SIZE: usize = 16;
+
+/// The largest command queue used, as a power of two number of commands.
+const MAX_QUEUE_LOG2: u32 = 8;
+
+/// Command opcodes.
+mod op {
+    pub const CFGI_STE: u64 = 0x03;
+    pub const CFGI_ALL: u64 = 0x04;
+    pub const CFGI_CD: u64 = 0x05;
+    pub const TLBI_NH_ASID: u64 = 0x11;
+    pub const TLBI_NH_VA: u64 = 0x12;
+    pub const TLBI_NSNH_ALL: u64 = 0x30;
+    pub const SYNC: u64 = 0x46;
+}
+
+/// How the SMMU handles DMA from a stream, the Config field of an STE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamConfig {
+    Abort = 0b000,
+    Bypass = 0b100,
+    Translate = 0b101,
+}
+
+struct State {
+    /// The stream table, one STE for each stream.
+    stream_table: DmaBuffer,
+    /// The context descriptors, one for each stream.
+    contexts: DmaBuffer,
+    /// The command queue.
+    commands: DmaBuffer,
+    /// The value of the command queue's producer index, including the wrap bit.
+    prod: u32,
+}
+
+// SAFETY: the tables and queue are owned by the driver, and only accessed with its lock held or
+// by the SMMU.
+unsafe impl Send for State {}
+
+/// An SMMU version 3.
+pub struct Smmu<R> {
+    regs: R,
+    num_streams: usize,
+    queue_log2: u32,
+    page_size: PageSize,
+    state: Mutex<State>,
+}
+
+/// The bits of `address` that the SMMU uses in table pointers.
+fn address_bits(address: PhysicalAddress) -> u64 {
+    usize::from(address) as u64 & 0x000f_ffff_ffff_ffff
+}
+
+impl<R: Registers> Smmu<R> {
+    /// Set up the SMMU behind `regs`, with DMA from the `bypass` streams reaching physical memory
+    /// untranslated and DMA from every other stream aborted.
+    ///
+    /// # Errors
+    /// - [`Error::Unsupported`] if the SMMU can't translate with stage 1 page tables.
+    /// - [`Error::Bypass`] if one of the `bypass` streams is not in the stream table.
+    /// - [`Error::Memory`] if the tables could not be allocated.
+    /// - [`Error::Timeout`] or [`Error::Invalidate`] if the SMMU could not be enabled.
+    pub fn new(
+        regs: R,
+        dma: &DmaAllocator<impl PageAllocator>,
+        page_size: PageSize,
+        bypass: &[StreamId],
+    ) -> Result<Self, Error> {
+        let idr0 = regs.read(regs::IDR0);
+        ensure!(
+            idr0 & regs::IDR0_S1P != 0,
+            UnsupportedSnafu {
+                feature: "stage 1 translation",
+                id: idr0
+            }
+        );
+        let idr1 = regs.read(regs::IDR1);
+        let stream_bits = (idr1 & 0x3f).min(MAX_STREAMS.ilog2());
+        let num_streams = 1 << stream_bits;
+        if let Some(&stream) = bypass.iter().find(|s| **s as usize >= num_streams) {
+            return BypassSnafu { stream }.fail();
+        }
+        let queue_log2 = ((idr1 >> 21) & 0x1f).min(MAX_QUEUE_LOG2);
+
+        let table_size = num_streams * ENTRY_SIZE;
+        let stream_table = dma
+            .allocate(
+                table_size,
+                &AllocationConstraints {
+                    alignment: table_size,
+                    ..AllocationConstraints::default()
+                },
+            )
+            .context(MemorySnafu)?;
+        let contexts = dma
+            .allocate(table_size, &AllocationConstraints::default())
+            .context(MemorySnafu)?;
+        let commands = dma
+            .allocate(
+                COMMAND_SIZE << queue_log2,
+                &AllocationConstraints {
+                    alignment: COMMAND_SIZE << queue_log2,
+                    ..AllocationConstraints::default()
+                },
+            )
+            .context(MemorySnafu)?;
+
+        let smmu = Self {
+            regs,
+            num_streams,
+            queue_log2,
+            page_size,
+            state: Mutex::new(State {
+                stream_table,
+                contexts,
+                commands,
+                prod: 0,
+            }),
+        };
+        smmu.enable(stream_bits, bypass)?;
+        Ok(smmu)
+    }
+
+    /// The number of streams the SMMU can translate.
+    #[must_use]
+    pub fn num_streams(&self) -> usize {
+        self.num_streams
+    }
+
+    /// Write `value` to CR0 and wait for the SMMU to acknowledge it.
+    fn set_cr0(&self, value: u32) -> Result<(), Error> {
+        self.regs.write(regs::CR0, value);
+        ensure!(
+            poll(|| self.regs.read(regs::CR0ACK) == value),
+            TimeoutSnafu { value }
+        );
+        Ok(())
+    }
+
+    /// Program the stream table and command queue, then enable translation.
+    fn enable(&self, stream_bits: u32, bypass: &[StreamId]) -> Result<(), Error> {
+        self.set_cr0(0)?;
+        self.regs.write(regs::CR1, regs::CR1_CACHEABLE);
+        {
+            let mut state = self.state.lock();
+            for stream in 0..self.num_streams {
+                let config = if bypass.iter().any(|s| *s as usize == stream) {
+                    StreamConfig::Bypass
+                } else {
+                    StreamConfig::Abort
+                };
+                Self::write_ste(&state, stream, config);
+            }
+            // read-allocate hint in bit 62
+            write_u64(
+                &self.regs,
+                regs::STRTAB_BASE,
+                1 << 62 | address_bits(state.stream_table.physical_address()) & !0x3f,
+            );
+            // linear format
+            self.regs.write(regs::STRTAB_BASE_CFG, stream_bits);
+            write_u64(
+                &self.regs,
+                regs::CMDQ_BASE,
+                1 << 62
+                    | address_bits(state.commands.physical_address()) & !0x1f
+                    | u64::from(self.queue_log2),
+            );
+            state.prod = 0;
+            self.regs.write(regs::CMDQ_PROD, 0);
+            self.regs.write(regs::CMDQ_CONS, 0);
+        }
+        self.set_cr0(regs::CR0_CMDQEN)?;
+        // the SMMU may have cached configuration from before it was reset
+        self.submit(&[[op::CFGI_ALL, 31], [op::TLBI_NSNH_ALL, 0]])
+            .context(InvalidateSnafu)?;
+        self.set_cr0(regs::CR0_CMDQEN | regs::CR0_SMMUEN)
+    }
+
+    /// Write the STE for `stream`, using its context descriptor if it is translated.
+    fn write_ste(state: &State, stream: usize, config: StreamConfig) {
+        let context =
+            address_bits(state.contexts.physical_address()) + (stream * ENTRY_SIZE) as u64;
+        // inner shareable, write-back context descriptor fetches, the incoming shareability for
+        // bypassed DMA, and DMA always treated as unprivileged so that it is limited to the pages
+        // accessible to user space
+        let word1 = 0b01 << 2 | 0b01 << 4 | 0b11 << 6 | 0b01 << 44 | 0b10 << 48;
+        let word0 = 1 | (config as u64) << 1 | context & 0x000f_ffff_ffff_ffc0;
+        let ste: *mut u64 = unsafe { state.stream_table.as_ptr().add(stream * ENTRY_SIZE) }.cast();
+        unsafe {
+            ste.add(1).write_volatile(word1);
+            // the SMMU may read the entry at any time, so it is only made valid once complete
+            fence(Ordering::Release);
+            ste.write_volatile(word0);
+        }
+    }
+
+    /// Write the context descriptor for `stream`, which translates through the page tables at
+    /// `root`.
+    fn write_cd(&self, state: &State, stream: usize, asid: AddressSpaceId, root: PhysicalAddress) {
+        let granule = match self.page_size {
+            PageSize::FourKiB => 0b00,
+            PageSize::SixteenKiB => 0b10,
+        };
+        let word0 = 16 // T0SZ, 48-bit addresses
+            | granule << 6
+            | 0b01 << 8 // inner write-back table walks
+            | 0b01 << 10 // outer write-back table walks
+            | 0b11 << 12 // inner shareable table walks
+            | 1 << 30 // no TTB1 walks
+            | 0b101 << 32 // 48-bit physical addresses
+            | 1 << 41 // AArch64 tables
+            | 1 << 45 // stall faulting transactions are not used, so abort them
+            | 1 << 46 // abort, rather than terminate with an error response
+            | 1 << 47 // ASID is not shared with the CPU
+            | u64::from(asid) << 48;
+        let cd: *mut u64 = unsafe { state.contexts.as_ptr().add(stream * ENTRY_SIZE) }.cast();
+        unsafe {
+            cd.write_volatile(0);
+            fence(Ordering::Release);
+            cd.add(1).write_volatile(address_bits(root) & !0xf);
+            cd.add(3).write_volatile(MAIR_VALUE);
+            fence(Ordering::Release);
+            cd.write_volatile(word0 | 1 << 31);
+        }
+    }
+
+    /// Submit `commands` followed by a sync, and wait for the SMMU to consume them.
+    ///
+    /// Each submission waits for the queue to drain, so it is never full.
+    ///
+    /// # Errors
+    /// Returns [`iommu::Error::Timeout`] if the SMMU did not consume the commands.
+    fn submit(&self, commands: &[[u64; 2]]) -> Result<(), iommu::Error> {
+        let mut state = self.state.lock();
+        let mask = (1 << self.queue_log2) - 1;
+        let queue: *mut [u64; 2] = state.commands.as_ptr().cast();
+        for command in commands.iter().chain(&[[op::SYNC, 0]]) {
+            let index = (state.prod & mask) as usize;
+            unsafe {
+                queue.add(index).write_volatile(*command);
+            }
+            // the index wraps through twice the queue size, the extra bit marking the wrap
+            state.prod = (state.prod + 1) & ((mask << 1) | 1);
+        }
+        fence(Ordering::Release);
+        self.regs.write(regs::CMDQ_PROD, state.prod);
+        let prod = state.prod;
+        if poll(|| self.regs.read(regs::CMDQ_CONS) & ((mask << 1) | 1) == prod) {
+            Ok(())
+        } else {
+            log::warn!("SMMU did not consume commands up to {prod}");
+            Err(iommu::Error::Timeout)
+        }
+    }
+
+    /// Check that `stream` is in the stream table.
+    fn stream_index(&self, stream: StreamId) -> Result<usize, iommu::Error> {
+        let index = stream as usize;
+        if index >= self.num_streams {
+            return Err(iommu::Error::UnknownStream { stream });
+        }
+        Ok(index)
+    }
+}
+
+impl<R: Registers> IoMmu for Smmu<R> {
+    fn attach(
+        &self,
+        stream: StreamId,
+        asid: AddressSpaceId,
+        root: PhysicalAddress,
+    ) -> Result<(), iommu::Error> {
+        let index = self.stream_index(stream)?;
+        {
+            let state = self.state.lock();
+            // block the stream while its context is replaced
+            Self::write_ste(&state, index, StreamConfig::Abort);
+        }
+        self.submit(&[[op::CFGI_STE | u64::from(stream) << 32, 1]])?;
+        {
+            let state = self.state.lock();
+            self.write_cd(&state, index, asid, root);
+            Self::write_ste(&state, index, StreamConfig::Translate);
+        }
+        self.submit(&[
+            [op::CFGI_CD | u64::from(stream) << 32, 1],
+            [op::CFGI_STE | u64::from(stream) << 32, 1],
+        ])
+    }
+
+    fn detach(&self, stream: StreamId) -> Result<(), iommu::Error> {
+        let index = self.stream_index(stream)?;
+        Self::write_ste(&self.state.lock(), index, StreamConfig::Abort);
+        self.submit(&[[op::CFGI_STE | u64::from(stream) << 32, 1]])
+    }
+
+    fn invalidate_range(
+        &self,
+        asid: AddressSpaceId,
+        start: VirtualAddress,
+        length: usize,
+    ) -> Result<(), iommu::Error> {
+        let page_size = usize::from(self.page_size);
+        let num_pages = length.div_ceil(page_size);
+        if num_pages > MAX_INVALIDATE_PAGES {
+            return self.invalidate_asid(asid);
+        }
+        let mut commands = [[0; 2]; MAX_INVALIDATE_PAGES];
+        for (i, command) in commands.iter_mut().take(num_pages).enumerate() {
+            let address = usize::from(start.byte_add(i * page_size)) as u64;
+            // only leaf entries changed
+            *command = [op::TLBI_NH_VA | u64::from(asid) << 48, address & !0xfff | 1];
+        }
+        self.submit(&commands[..num_pages])
+    }
+
+    fn invalidate_asid(&self, asid: AddressSpaceId) -> Result<(), iommu::Error> {
+        self.submit(&[[op::TLBI_NH_ASID | u64::from(asid) << 48, 0]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+        vec::Vec,
+    };
+
+    use super::*;
+    use crate::memory::{tests::MockPageAllocator, PageTables, PhysicalPointer};
+
+    /// Read the value at the physical `address`.
+    fn read<T>(address: u64) -> T {
+        let pointer: *mut T = PhysicalPointer::from(address as usize).into();
+        unsafe { pointer.read_volatile() }
+    }
+
+    /// The registers of a fake SMMU, which acknowledges every change to CR0 and consumes commands
+    /// as soon as they are submitted, unless it is stalled.
+    struct FakeRegisters {
+        registers: Mutex<HashMap<usize, u32>>,
+        /// The commands consumed, in order.
+        commands: Mutex<Vec<[u64; 2]>>,
+        stalled: AtomicBool,
+    }
+
+    impl FakeRegisters {
+        fn new(idr0: u32, idr1: u32) -> Self {
+            Self {
+                registers: Mutex::new(HashMap::from([(regs::IDR0, idr0), (regs::IDR1, idr1)])),
+                commands: Mutex::default(),
+                stalled: AtomicBool::new(false),
+            }
+        }
+
+        fn register(&self, offset: usize) -> u32 {
+            self.registers
+                .lock()
+                .unwrap()
+                .get(&offset)
+                .copied()
+                .unwrap_or_default()
+        }
+
+        fn register_u64(&self, offset: usize) -> u64 {
+            u64::from(self.register(offset + 4)) << 32 | u64::from(self.register(offset))
+        }
+
+        /// The opcodes of the commands consumed since the last call.
+        fn take_opcodes(&self) -> Vec<u64> {
+            self.commands
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|[word0, _]| word0 & 0xff)
+                .collect()
+        }
+
+        /// The STE for `stream`.
+        fn ste(&self, stream: usize) -> [u64; 2] {
+            let base = self.register_u64(regs::STRTAB_BASE) & 0x000f_ffff_ffff_ffc0;
+            read(base + (stream * ENTRY_SIZE) as u64)
+        }
+    }
+
+    impl Registers for &FakeRegisters {
+        fn read(&self, offset: usize) -> u32 {
+            self.register(offset)
+        }
+
+        fn write(&self, offset: usize, value: u32) {
+            self.registers.lock().unwrap().insert(offset, value);
+            match offset {
+                regs::CR0 => {
+                    self.registers.lock().unwrap().insert(regs::CR0ACK, value);
+                }
+                regs::CMDQ_PROD if !self.stalled.load(Ordering::Relaxed) => {
+                    let base = self.register_u64(regs::CMDQ_BASE);
+                    let log2 = (base & 0x1f) as u32;
+                    let mask = (1 << log2) - 1;
+                    let mut cons = self.register(regs::CMDQ_CONS);
+                    while cons != value {
+                        let command = read(
+                            (base & 0x000f_ffff_ffff_ffe0)
+                                + u64::from(cons & mask) * COMMAND_SIZE as u64,
+                        );
+                        self.commands.lock().unwrap().push(command);
+                        cons = (cons + 1) & ((mask << 1) | 1);
+                    }
+                    self.registers.lock().unwrap().insert(regs::CMDQ_CONS, cons);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn config(ste: [u64; 2]) -> u64 {
+        assert_eq!(ste[0] & 1, 1, "STE is valid");
+        (ste[0] >> 1) & 0b111
+    }
+
+    #[test]
+    fn streams_abort_until_attached() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let dma = DmaAllocator::new(&pa);
+        // 16 streams, 4 command queue entries
+        let regs = FakeRegisters::new(regs::IDR0_S1P, 2 << 21 | 4);
+        let smmu = Smmu::new(&regs, &dma, PageSize::FourKiB, &[4]).unwrap();
+        assert_eq!(smmu.num_streams(), 16);
+        assert_eq!(
+            regs.register(regs::CR0),
+            regs::CR0_CMDQEN | regs::CR0_SMMUEN
+        );
+        assert_eq!(regs.register(regs::STRTAB_BASE_CFG), 4);
+        assert_eq!(
+            regs.take_opcodes(),
+            [op::CFGI_ALL, op::TLBI_NSNH_ALL, op::SYNC]
+        );
+        for stream in 0..16 {
+            let expected = if stream == 4 {
+                StreamConfig::Bypass
+            } else {
+                StreamConfig::Abort
+            };
+            assert_eq!(config(regs.ste(stream)), expected as u64);
+        }
+
+        let tables = PageTables::empty(&pa).unwrap();
+        smmu.attach(3, 42, tables.physical_address()).unwrap();
+        assert_eq!(
+            regs.take_opcodes(),
+            [op::CFGI_STE, op::SYNC, op::CFGI_CD, op::CFGI_STE, op::SYNC]
+        );
+        let ste = regs.ste(3);
+        assert_eq!(config(ste), StreamConfig::Translate as u64);
+        let [cd0, ttb0]: [u64; 2] = read(ste[0] & 0x000f_ffff_ffff_ffc0);
+        assert_eq!(cd0 >> 48, 42);
+        assert_eq!(cd0 >> 31 & 1, 1, "CD is valid");
+        assert_eq!(ttb0, usize::from(tables.physical_address()) as u64);
+        assert_eq!(config(regs.ste(4)), StreamConfig::Bypass as u64);
+        assert!(matches!(
+            smmu.attach(16, 1, tables.physical_address()),
+            Err(iommu::Error::UnknownStream { stream: 16 })
+        ));
+
+        // commands wrap around the queue
+        smmu.invalidate_range(42, VirtualAddress::from(0x1_0000), 0x3000)
+            .unwrap();
+        let commands: Vec<_> = regs.commands.lock().unwrap().clone();
+        assert_eq!(commands.len(), 4);
+        assert_eq!(commands[1], [op::TLBI_NH_VA | 42 << 48, 0x1_1001]);
+        regs.take_opcodes();
+        smmu.invalidate_range(42, VirtualAddress::from(0), 0x100_0000)
+            .unwrap();
+        assert_eq!(regs.take_opcodes(), [op::TLBI_NH_ASID, op::SYNC]);
+
+        smmu.detach(3).unwrap();
+        assert_eq!(config(regs.ste(3)), StreamConfig::Abort as u64);
+        assert_eq!(regs.take_opcodes(), [op::CFGI_STE, op::SYNC]);
+
+        // commands the SMMU never consumes are reported
+        regs.stalled.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            smmu.invalidate_asid(42),
+            Err(iommu::Error::Timeout)
+        ));
+        drop(tables);
+    }
+
+    #[test]
+    fn requires_stage_1() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let dma = DmaAllocator::new(&pa);
+        let regs = FakeRegisters::new(0, 4);
+        assert!(matches!(
+            Smmu::new(&regs, &dma, PageSize::FourKiB, &[]),
+            Err(Error::Unsupported { id: 0, .. })
+        ));
+        let regs = FakeRegisters::new(regs::IDR0_S1P, 4);
+        assert!(matches!(
+            Smmu::new(&regs, &dma, PageSize::FourKiB, &[16]),
+            Err(Error::Bypass { stream: 16 })
+        ));
+        pa.end_check();
+    }
+}
@@ -1,11 +1,23 @@
-//! Interface for system timer mechanism used for time slicing.
+//! Interface for system timer mechanism used for time slicing and timer events.
 
-/// System timer mechanism used for time slicing.
+use crate::time::Ticks;
+
+/// System timer mechanism used for time slicing and timer events.
 #[cfg_attr(test, mockall::automock)]
 pub trait SystemTimer {
     /// The ID of the interrupt that is triggered when the timer expires.
     fn interrupt_id(&self) -> crate::exceptions::InterruptId;
 
-    /// Reset the timer after it has expired.
-    fn reset(&self);
+    /// The current value of the system counter.
+    fn now(&self) -> Ticks;
+
+    /// The length of a scheduler time slice, in counter ticks.
+    fn time_slice(&self) -> Ticks;
+
+    /// The deadline the timer on the current core is currently programmed with.
+    fn deadline(&self) -> Ticks;
+
+    /// Program the timer on the current core to expire once the counter reaches `deadline`.
+    /// A deadline of [`Ticks::MAX`] effectively stops the timer.
+    fn set_deadline(&self, deadline: Ticks);
 }
@@ -0,0 +1,316 @@
+//! Interrupt-driven serial port (UART) driver policy.
+//!
+//! Output is queued in a transmit buffer and fed to the hardware FIFO whenever it has room, which
+//! the hardware signals with an interrupt, so writers never have to wait for the device. Received
+//! bytes are moved out of the hardware FIFO into a receive buffer when they arrive, for the kernel
+//! console to read later.
+//!
+//! Until interrupts are enabled (and again after a panic, when interrupts can no longer be relied
+//! upon), the driver falls back to writing directly to the device, waiting for room in the FIFO.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{logger::LogSink, sync::Mutex};
+
+/// Mechanism interface for the registers of a UART.
+pub trait UartMechanism {
+    /// Returns true if the transmit FIFO has room for another byte.
+    fn can_transmit(&self) -> bool;
+
+    /// Write a byte to the transmit FIFO.
+    fn transmit(&self, byte: u8);
+
+    /// Returns true if there is a byte waiting in the receive FIFO.
+    fn can_receive(&self) -> bool;
+
+    /// Read a byte from the receive FIFO.
+    fn receive(&self) -> u8;
+
+    /// Choose which interrupts the UART raises: when the transmit FIFO has room (`transmit`), and
+    /// when bytes have been received (`receive`).
+    fn set_interrupts(&self, transmit: bool, receive: bool);
+
+    /// Clear any pending interrupts raised by the UART.
+    fn clear_interrupts(&self);
+}
+
+/// A fixed size queue of bytes.
+struct ByteRing<const N: usize> {
+    data: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> ByteRing<N> {
+    const fn new() -> Self {
+        Self {
+            data: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn free(&self) -> usize {
+        N - self.len
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.data[(self.start + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.start];
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// A UART driver with buffered, interrupt-driven transmit and receive.
+///
+/// By default each direction is buffered with 4KiB.
+pub struct Uart<M, const N: usize = 4096> {
+    mech: M,
+    tx: Mutex<ByteRing<N>>,
+    rx: Mutex<ByteRing<N>>,
+    interrupt_driven: AtomicBool,
+    rx_dropped: AtomicUsize,
+}
+
+impl<M: UartMechanism, const N: usize> Uart<M, N> {
+    /// Create a driver for the UART with registers `mech`, initially writing directly to the device.
+    pub const fn new(mech: M) -> Self {
+        Self {
+            mech,
+            tx: Mutex::new(ByteRing::new()),
+            rx: Mutex::new(ByteRing::new()),
+            interrupt_driven: AtomicBool::new(false),
+            rx_dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Switch to interrupt-driven operation. The UART's interrupt must be routed to
+    /// [`Self::handle_interrupt`].
+    pub fn enable_interrupts(&self) {
+        self.interrupt_driven.store(true, Ordering::Release);
+        self.mech.set_interrupts(false, true);
+    }
+
+    /// Switch back to writing directly to the device, sending any output that is still queued.
+    ///
+    /// This is used when interrupts can no longer be relied upon, for instance during a panic.
+    pub fn disable_interrupts(&self) {
+        self.interrupt_driven.store(false, Ordering::Release);
+        self.mech.set_interrupts(false, false);
+        // the queue may be locked by a core that has been halted, in which case it is abandoned
+        if let Some(mut tx) = self.tx.try_lock() {
+            while let Some(byte) = tx.pop() {
+                self.transmit_polled(byte);
+            }
+        }
+    }
+
+    fn transmit_polled(&self, byte: u8) {
+        while !self.mech.can_transmit() {
+            core::hint::spin_loop();
+        }
+        self.mech.transmit(byte);
+    }
+
+    /// Move queued bytes into the transmit FIFO until it is full.
+    fn fill_fifo(&self, tx: &mut ByteRing<N>) {
+        while self.mech.can_transmit() {
+            let Some(byte) = tx.pop() else {
+                break;
+            };
+            self.mech.transmit(byte);
+        }
+    }
+
+    /// Write `bytes` to the UART.
+    ///
+    /// When interrupt-driven, this queues as many bytes as there is room for and returns
+    /// immediately. Otherwise, this waits until every byte has been written to the device.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write(&self, bytes: &[u8]) -> usize {
+        if !self.interrupt_driven.load(Ordering::Acquire) {
+            for byte in bytes {
+                self.transmit_polled(*byte);
+            }
+            return bytes.len();
+        }
+        let mut tx = self.tx.lock();
+        let written = bytes.iter().take_while(|b| tx.push(**b)).count();
+        self.fill_fifo(&mut tx);
+        if tx.len > 0 {
+            // interrupt once the FIFO has room for the rest
+            self.mech.set_interrupts(true, true);
+        }
+        written
+    }
+
+    /// The number of bytes that can currently be written without any being dropped.
+    pub fn write_capacity(&self) -> usize {
+        if self.interrupt_driven.load(Ordering::Acquire) {
+            self.tx.lock().free()
+        } else {
+            usize::MAX
+        }
+    }
+
+    /// Read received bytes into `buf`, returning the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut rx = self.rx.lock();
+        buf.iter_mut()
+            .map_while(|b| rx.pop().map(|r| *b = r))
+            .count()
+    }
+
+    /// The number of received bytes that were dropped because the receive buffer was full.
+    pub fn receive_dropped(&self) -> usize {
+        self.rx_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Handle an interrupt from the UART, receiving any incoming bytes and refilling the transmit
+    /// FIFO.
+    ///
+    /// Returns true if any bytes were received.
+    pub fn handle_interrupt(&self) -> bool {
+        let mut received = false;
+        {
+            let mut rx = self.rx.lock();
+            while self.mech.can_receive() {
+                received = true;
+                if !rx.push(self.mech.receive()) {
+                    self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        {
+            let mut tx = self.tx.lock();
+            self.fill_fifo(&mut tx);
+            if tx.len == 0 {
+                self.mech.set_interrupts(false, true);
+            }
+        }
+        self.mech.clear_interrupts();
+        received
+    }
+}
+
+/// Log output can be sent to a UART. While interrupt-driven, the UART is only ready for more
+/// output if it has room to queue it.
+impl<M: UartMechanism, const N: usize> LogSink for &Uart<M, N> {
+    fn accept(&mut self, chunk: &[u8]) {
+        self.write(chunk);
+    }
+
+    fn is_ready(&mut self) -> bool {
+        self.write_capacity() > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, vec::Vec};
+
+    use super::*;
+
+    /// A UART with a small FIFO in each direction.
+    #[derive(Default)]
+    struct FakeUart {
+        tx_fifo: Mutex<Vec<u8>>,
+        rx_fifo: Mutex<VecDeque<u8>>,
+        interrupts: Mutex<(bool, bool)>,
+    }
+
+    const FIFO_SIZE: usize = 4;
+
+    impl FakeUart {
+        /// Simulate the device sending everything in its transmit FIFO.
+        fn send(&self) -> Vec<u8> {
+            core::mem::take(&mut *self.tx_fifo.lock())
+        }
+    }
+
+    impl UartMechanism for FakeUart {
+        fn can_transmit(&self) -> bool {
+            self.tx_fifo.lock().len() < FIFO_SIZE
+        }
+
+        fn transmit(&self, byte: u8) {
+            assert!(self.can_transmit());
+            self.tx_fifo.lock().push(byte);
+        }
+
+        fn can_receive(&self) -> bool {
+            !self.rx_fifo.lock().is_empty()
+        }
+
+        fn receive(&self) -> u8 {
+            self.rx_fifo.lock().pop_front().unwrap()
+        }
+
+        fn set_interrupts(&self, transmit: bool, receive: bool) {
+            *self.interrupts.lock() = (transmit, receive);
+        }
+
+        fn clear_interrupts(&self) {}
+    }
+
+    #[test]
+    fn interrupt_driven_transmit() {
+        let uart = Uart::<_, 8>::new(FakeUart::default());
+        uart.enable_interrupts();
+        assert_eq!(*uart.mech.interrupts.lock(), (false, true));
+
+        // only as much as fits in the buffer is written
+        assert_eq!(uart.write(b"hello world"), 8);
+        assert_eq!(*uart.mech.interrupts.lock(), (true, true));
+        assert_eq!(uart.mech.send(), b"hell");
+
+        uart.handle_interrupt();
+        assert_eq!(uart.mech.send(), b"o wo");
+        assert_eq!(*uart.mech.interrupts.lock(), (false, true));
+
+        assert_eq!(uart.write(b"rld"), 3);
+        assert_eq!(uart.mech.send(), b"rld");
+        assert_eq!(*uart.mech.interrupts.lock(), (false, true));
+    }
+
+    #[test]
+    fn disable_interrupts_flushes_queue() {
+        let uart = Uart::<_, 8>::new(FakeUart::default());
+        uart.enable_interrupts();
+        uart.write(b"abcdef");
+        uart.mech.send();
+        uart.disable_interrupts();
+        assert_eq!(uart.mech.send(), b"ef");
+        assert_eq!(*uart.mech.interrupts.lock(), (false, false));
+        assert_eq!(uart.write_capacity(), usize::MAX);
+    }
+
+    #[test]
+    fn receive() {
+        let uart = Uart::<_, 4>::new(FakeUart::default());
+        uart.enable_interrupts();
+        uart.mech.rx_fifo.lock().extend(b"abcdef");
+        assert!(uart.handle_interrupt());
+        assert_eq!(uart.receive_dropped(), 2);
+
+        let mut buf = [0; 3];
+        assert_eq!(uart.read(&mut buf), 3);
+        assert_eq!(&buf, b"abc");
+        assert_eq!(uart.read(&mut buf), 1);
+        assert_eq!(buf[0], b'd');
+        assert!(!uart.handle_interrupt());
+    }
+}
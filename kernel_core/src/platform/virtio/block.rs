@@ -0,0 +1,308 @@
+//! Driver for the virtio block device, a disk provided by the hypervisor (for QEMU, a disk image
+//! attached with `-device virtio-blk-device`).
+//!
+//! Each request is a chain of three buffers: a header saying what to do, the data, and a status
+//! byte written by the device. Headers and status bytes live in a buffer owned by the driver, one
+//! slot per request that can be in flight at once.
+use alloc::{sync::Arc, vec::Vec};
+use snafu::ResultExt;
+
+use super::{queue::SplitQueue, Device, Error, MemorySnafu, Transport};
+use crate::{
+    io::{self, check_request, BlockDevice, Completion, Status},
+    memory::{AllocationConstraints, DmaAllocator, DmaBuffer, PageAllocator, PhysicalAddress},
+    sync::Mutex,
+};
+
+/// The virtio device ID of a block device.
+pub const DEVICE_ID: u32 = 2;
+
+/// Feature bit indicating that the device is read only.
+pub const FEATURE_RO: u64 = 1 << 5;
+
+/// The offset of the capacity of the device, in sectors, in its configuration.
+const CONFIG_CAPACITY: usize = 0;
+
+/// The index of the only request queue.
+const REQUEST_QUEUE: u16 = 0;
+
+/// The number of descriptors in the request queue.
+const QUEUE_SIZE: u16 = 32;
+/// The number of requests that can be in flight at once, each of which takes three descriptors.
+const MAX_REQUESTS: usize = QUEUE_SIZE as usize / 3;
+
+/// The size of each request's slot in the header buffer: a 16 byte header followed by the status.
+const SLOT_SIZE: usize = 32;
+/// The offset of the status byte in a slot.
+const STATUS_OFFSET: usize = 16;
+
+/// Request type for reading from the device.
+const TYPE_IN: u32 = 0;
+/// Request type for writing to the device.
+const TYPE_OUT: u32 = 1;
+
+/// Status written by the device when a request succeeds.
+const STATUS_OK: u8 = 0;
+/// Status written by the device when a request fails.
+const STATUS_IOERR: u8 = 1;
+
+struct State {
+    queue: SplitQueue,
+    /// The headers and status bytes of each request slot.
+    slots: DmaBuffer,
+    /// The slots that aren't in use.
+    free_slots: Vec<usize>,
+    /// The completion of the request in each slot.
+    completions: Vec<Option<Arc<Completion>>>,
+}
+
+// SAFETY: the slots are owned by the driver, and only accessed with its lock held or by the
+// device.
+unsafe impl Send for State {}
+
+/// A virtio block device.
+pub struct VirtioBlock<T> {
+    device: Device<T>,
+    num_sectors: u64,
+    read_only: bool,
+    state: Mutex<State>,
+}
+
+impl<T: Transport> VirtioBlock<T> {
+    /// Set up `device`, which must be a block device.
+    ///
+    /// # Errors
+    /// - [`Error::WrongDevice`] if the device is not a block device.
+    /// - [`Error::Memory`] if the queue or request slots could not be allocated.
+    /// - Any error setting up the device.
+    pub fn new(device: Device<T>, dma: &DmaAllocator<impl PageAllocator>) -> Result<Self, Error> {
+        let found = device.device_id();
+        snafu::ensure!(
+            found == DEVICE_ID,
+            super::WrongDeviceSnafu {
+                expected: DEVICE_ID,
+                found
+            }
+        );
+        let features = device.initialize(FEATURE_RO)?;
+        let queue = SplitQueue::new(dma, QUEUE_SIZE).context(MemorySnafu)?;
+        device.add_queue(REQUEST_QUEUE, &queue)?;
+        let slots = dma
+            .allocate(MAX_REQUESTS * SLOT_SIZE, &AllocationConstraints::default())
+            .context(MemorySnafu)?;
+        device.start();
+        Ok(Self {
+            num_sectors: device.read_config_u64(CONFIG_CAPACITY),
+            read_only: features & FEATURE_RO != 0,
+            device,
+            state: Mutex::new(State {
+                queue,
+                slots,
+                free_slots: (0..MAX_REQUESTS).collect(),
+                completions: (0..MAX_REQUESTS).map(|_| None).collect(),
+            }),
+        })
+    }
+
+    /// Queue a request of `request_type` for the device.
+    // buffers and headers are much smaller than 4GiB
+    #[allow(clippy::cast_possible_truncation)]
+    fn submit(
+        &self,
+        request_type: u32,
+        sector: u64,
+        buffer: PhysicalAddress,
+        len: usize,
+        completion: Arc<Completion>,
+    ) -> Result<(), io::Error> {
+        check_request(self.num_sectors, sector, len)?;
+        let mut state = self.state.lock();
+        let slot = state.free_slots.pop().ok_or(io::Error::Busy)?;
+        let header = state.slots.physical_address().byte_add(slot * SLOT_SIZE);
+        let mut request = [0; STATUS_OFFSET + 1];
+        request[..4].copy_from_slice(&request_type.to_le_bytes());
+        request[8..16].copy_from_slice(&sector.to_le_bytes());
+        // a status the device never writes, in case it doesn't write one
+        request[STATUS_OFFSET] = u8::MAX;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                request.as_ptr(),
+                state.slots.as_ptr().add(slot * SLOT_SIZE),
+                request.len(),
+            );
+        }
+        // there are enough descriptors for every slot, so the queue is never full
+        let queued = state.queue.push_chain(
+            &[
+                (header, STATUS_OFFSET as u32, false),
+                (buffer, len as u32, request_type == TYPE_IN),
+                (header.byte_add(STATUS_OFFSET), 1, true),
+            ],
+            slot,
+        );
+        debug_assert!(queued);
+        state.completions[slot] = Some(completion);
+        drop(state);
+        self.device.notify(REQUEST_QUEUE);
+        Ok(())
+    }
+
+    /// Handle an interrupt from the device, completing the requests it has finished.
+    pub fn handle_interrupt(&self) {
+        self.device.acknowledge_interrupt();
+        let mut finished = Vec::new();
+        let mut state = self.state.lock();
+        while let Some((slot, _)) = state.queue.pop_used() {
+            let status = unsafe {
+                state
+                    .slots
+                    .as_ptr()
+                    .add(slot * SLOT_SIZE + STATUS_OFFSET)
+                    .read_volatile()
+            };
+            if let Some(completion) = state.completions[slot].take() {
+                finished.push((completion, status));
+            }
+            state.free_slots.push(slot);
+        }
+        drop(state);
+        // signal the waiters without holding the lock
+        for (completion, status) in finished {
+            completion.complete(match status {
+                STATUS_OK => Status::Done,
+                STATUS_IOERR => Status::Failed,
+                _ => Status::Unsupported,
+            });
+        }
+    }
+}
+
+impl<T: Transport + Send + Sync> BlockDevice for VirtioBlock<T> {
+    fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    unsafe fn read(
+        &self,
+        sector: u64,
+        buffer: PhysicalAddress,
+        len: usize,
+        completion: Arc<Completion>,
+    ) -> Result<(), io::Error> {
+        self.submit(TYPE_IN, sector, buffer, len, completion)
+    }
+
+    unsafe fn write(
+        &self,
+        sector: u64,
+        buffer: PhysicalAddress,
+        len: usize,
+        completion: Arc<Completion>,
+    ) -> Result<(), io::Error> {
+        if self.read_only {
+            return Err(io::Error::ReadOnly);
+        }
+        self.submit(TYPE_OUT, sector, buffer, len, completion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        io::SECTOR_SIZE,
+        ipc::Notification,
+        memory::{tests::MockPageAllocator, PageSize},
+        platform::virtio::{regs, tests::FakeTransport},
+    };
+
+    use super::*;
+
+    fn disk(pa: &MockPageAllocator, read_only: bool) -> VirtioBlock<FakeTransport> {
+        let fake = FakeTransport::new(DEVICE_ID, 64);
+        fake.set_register(regs::CONFIG, 16);
+        if read_only {
+            *fake.features.lock().unwrap() |= FEATURE_RO;
+        }
+        let device = Device::probe(fake).unwrap().unwrap();
+        VirtioBlock::new(device, &DmaAllocator::new(pa)).unwrap()
+    }
+
+    #[test]
+    fn read_sectors() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let disk = disk(&pa, false);
+        assert_eq!(disk.num_sectors(), 16);
+        assert!(!disk.read_only());
+        let fake = &disk.device.transport;
+
+        let mut data = alloc::vec![0u8; 2 * SECTOR_SIZE];
+        let buffer = PhysicalAddress::from(data.as_mut_ptr() as usize);
+        let notification = Arc::new(Notification::new());
+        let completion = Arc::new(Completion::new(notification.clone(), 1));
+        unsafe { disk.read(3, buffer, data.len(), completion.clone()) }.unwrap();
+        assert_eq!(fake.notifications.lock().unwrap().as_slice(), [0]);
+
+        let chains = fake.take_chains(0);
+        assert_eq!(chains.len(), 1);
+        let (head, buffers) = &chains[0];
+        assert_eq!(buffers.len(), 3);
+        let header = unsafe { core::slice::from_raw_parts(buffers[0].0, 16) };
+        assert_eq!(&header[..4], TYPE_IN.to_le_bytes());
+        assert_eq!(&header[8..], 3u64.to_le_bytes());
+        assert_eq!(buffers[1], (data.as_mut_ptr(), 2 * SECTOR_SIZE as u32));
+        assert_eq!(buffers[2].1, 1);
+
+        // the device fills the buffer and reports success
+        unsafe {
+            core::ptr::write_bytes(buffers[1].0, 0xab, 2 * SECTOR_SIZE);
+            buffers[2].0.write(STATUS_OK);
+        }
+        assert_eq!(completion.status(), Status::Pending);
+        fake.complete(0, *head, 2 * SECTOR_SIZE as u32 + 1);
+        disk.handle_interrupt();
+        assert_eq!(completion.status(), Status::Done);
+        assert_eq!(notification.pending(), 1);
+        assert!(data.iter().all(|b| *b == 0xab));
+    }
+
+    #[test]
+    fn reject_requests() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let disk = disk(&pa, true);
+        assert!(disk.read_only());
+        let notification = Arc::new(Notification::new());
+        let completion = Arc::new(Completion::new(notification, 1));
+        let buffer = PhysicalAddress::from(0x1000usize);
+        unsafe {
+            assert!(matches!(
+                disk.write(0, buffer, SECTOR_SIZE, completion.clone()),
+                Err(io::Error::ReadOnly)
+            ));
+            assert!(matches!(
+                disk.read(16, buffer, SECTOR_SIZE, completion.clone()),
+                Err(io::Error::OutOfRange { .. })
+            ));
+            for _ in 0..MAX_REQUESTS {
+                disk.read(0, buffer, SECTOR_SIZE, completion.clone())
+                    .unwrap();
+            }
+            assert!(matches!(
+                disk.read(0, buffer, SECTOR_SIZE, completion.clone()),
+                Err(io::Error::Busy)
+            ));
+        }
+
+        // a failed request frees its slot for the next one
+        let fake = &disk.device.transport;
+        let (head, buffers) = fake.take_chains(0).remove(0);
+        unsafe { buffers[2].0.write(STATUS_IOERR) };
+        fake.complete(0, head, 1);
+        disk.handle_interrupt();
+        assert_eq!(completion.status(), Status::Failed);
+        unsafe { disk.read(0, buffer, SECTOR_SIZE, completion) }.unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+//! Driver for the virtio console device, which can carry kernel log output and console input on
+//! platforms without a serial port.
+//!
+//! Only the first port of the console is used, and no device-specific features are negotiated.
+//! Received data and pending output are held in fixed size buffers shared with the device, so
+//! neither reading nor writing ever waits for the device.
+use alloc::vec::Vec;
+use snafu::ResultExt;
+
+use super::{queue::SplitQueue, Device, Error, MemorySnafu, Transport};
+use crate::{
+    logger::LogSink,
+    memory::{AllocationConstraints, DmaAllocator, DmaBuffer, PageAllocator},
+    sync::Mutex,
+};
+
+/// The virtio device ID of a console.
+pub const DEVICE_ID: u32 = 3;
+
+/// The index of the queue that receives input from the first port.
+const RECEIVE_QUEUE: u16 = 0;
+/// The index of the queue that transmits output to the first port.
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// The number of buffers in each direction.
+const QUEUE_SIZE: u16 = 16;
+/// The size of each buffer, in bytes.
+const BUFFER_SIZE: usize = 128;
+
+/// Received data that hasn't been read yet.
+#[derive(Clone, Copy)]
+struct Pending {
+    /// The buffer holding the data.
+    buffer: usize,
+    /// The range of unread bytes in the buffer.
+    start: usize,
+    end: usize,
+}
+
+struct State {
+    receive: SplitQueue,
+    transmit: SplitQueue,
+    /// The buffers that are shared with the device, with the receive buffers first.
+    buffers: DmaBuffer,
+    /// The transmit buffers that the device isn't using.
+    free_transmit: Vec<usize>,
+    /// The receive buffer currently being read from.
+    pending: Option<Pending>,
+}
+
+// SAFETY: the buffers are owned by the console, and only accessed with its lock held or by the
+// device.
+unsafe impl Send for State {}
+
+impl State {
+    fn buffer(&self, index: usize) -> *mut u8 {
+        unsafe { self.buffers.as_ptr().add(index * BUFFER_SIZE) }
+    }
+
+    /// Give receive buffer `index` to the device to fill.
+    // buffers are much smaller than 4GiB
+    #[allow(clippy::cast_possible_truncation)]
+    fn post_receive(&mut self, index: usize) {
+        let address = self
+            .buffers
+            .physical_address()
+            .byte_add(index * BUFFER_SIZE);
+        // there is a descriptor for every receive buffer, so the queue is never full
+        let posted = self.receive.push(address, BUFFER_SIZE as u32, true, index);
+        debug_assert!(posted);
+    }
+
+    /// Take back the transmit buffers the device has finished sending.
+    fn reclaim_transmit(&mut self) {
+        while let Some((index, _)) = self.transmit.pop_used() {
+            self.free_transmit.push(index);
+        }
+    }
+}
+
+/// A virtio console device.
+pub struct VirtioConsole<T> {
+    device: Device<T>,
+    state: Mutex<State>,
+}
+
+impl<T: Transport> VirtioConsole<T> {
+    /// Set up `device`, which must be a console, and start receiving input.
+    ///
+    /// # Errors
+    /// - [`Error::WrongDevice`] if the device is not a console.
+    /// - [`Error::Memory`] if the queues or buffers could not be allocated.
+    /// - Any error setting up the device.
+    pub fn new(device: Device<T>, dma: &DmaAllocator<impl PageAllocator>) -> Result<Self, Error> {
+        let found = device.device_id();
+        snafu::ensure!(
+            found == DEVICE_ID,
+            super::WrongDeviceSnafu {
+                expected: DEVICE_ID,
+                found
+            }
+        );
+        device.initialize(0)?;
+        let receive = SplitQueue::new(dma, QUEUE_SIZE).context(MemorySnafu)?;
+        let transmit = SplitQueue::new(dma, QUEUE_SIZE).context(MemorySnafu)?;
+        device.add_queue(RECEIVE_QUEUE, &receive)?;
+        device.add_queue(TRANSMIT_QUEUE, &transmit)?;
+        let buffers = dma
+            .allocate(
+                2 * usize::from(QUEUE_SIZE) * BUFFER_SIZE,
+                &AllocationConstraints::default(),
+            )
+            .context(MemorySnafu)?;
+        let mut state = State {
+            receive,
+            transmit,
+            buffers,
+            free_transmit: (usize::from(QUEUE_SIZE)..2 * usize::from(QUEUE_SIZE)).collect(),
+            pending: None,
+        };
+        for index in 0..usize::from(QUEUE_SIZE) {
+            state.post_receive(index);
+        }
+        device.start();
+        device.notify(RECEIVE_QUEUE);
+        Ok(Self {
+            device,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Queue as many of `bytes` as there is room for to be sent to the device.
+    ///
+    /// Returns the number of bytes queued.
+    // buffers are much smaller than 4GiB
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn write(&self, mut bytes: &[u8]) -> usize {
+        let mut state = self.state.lock();
+        state.reclaim_transmit();
+        let mut written = 0;
+        while !bytes.is_empty() {
+            let Some(index) = state.free_transmit.pop() else {
+                break;
+            };
+            let len = bytes.len().min(BUFFER_SIZE);
+            unsafe {
+                core::ptr::copy_nonoverlapping(bytes.as_ptr(), state.buffer(index), len);
+            }
+            let address = state
+                .buffers
+                .physical_address()
+                .byte_add(index * BUFFER_SIZE);
+            // there is a descriptor for every transmit buffer, so the queue is never full
+            let queued = state.transmit.push(address, len as u32, false, index);
+            debug_assert!(queued);
+            bytes = &bytes[len..];
+            written += len;
+        }
+        if written > 0 {
+            self.device.notify(TRANSMIT_QUEUE);
+        }
+        written
+    }
+
+    /// The number of bytes that can currently be written without any being dropped.
+    pub fn write_capacity(&self) -> usize {
+        let mut state = self.state.lock();
+        state.reclaim_transmit();
+        state.free_transmit.len() * BUFFER_SIZE
+    }
+
+    /// Read received bytes into `buf`, returning the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut state = self.state.lock();
+        let mut read = 0;
+        let mut reposted = false;
+        while read < buf.len() {
+            let mut pending = match state.pending.take() {
+                Some(pending) => pending,
+                None => match state.receive.pop_used() {
+                    Some((buffer, len)) => Pending {
+                        buffer,
+                        start: 0,
+                        end: (len as usize).min(BUFFER_SIZE),
+                    },
+                    None => break,
+                },
+            };
+            let len = (pending.end - pending.start).min(buf.len() - read);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    state.buffer(pending.buffer).add(pending.start),
+                    buf[read..].as_mut_ptr(),
+                    len,
+                );
+            }
+            read += len;
+            pending.start += len;
+            if pending.start == pending.end {
+                state.post_receive(pending.buffer);
+                reposted = true;
+            } else {
+                state.pending = Some(pending);
+            }
+        }
+        if reposted {
+            self.device.notify(RECEIVE_QUEUE);
+        }
+        read
+    }
+
+    /// Handle an interrupt from the device, taking back the transmit buffers that have been sent.
+    ///
+    /// Returns true if there is received data to read.
+    pub fn handle_interrupt(&self) -> bool {
+        self.device.acknowledge_interrupt();
+        let mut state = self.state.lock();
+        state.reclaim_transmit();
+        state.pending.is_some() || state.receive.has_used()
+    }
+}
+
+impl<T: Transport> LogSink for &VirtioConsole<T> {
+    fn accept(&mut self, chunk: &[u8]) {
+        self.write(chunk);
+    }
+
+    fn is_ready(&mut self) -> bool {
+        self.write_capacity() > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        memory::{tests::MockPageAllocator, PageSize},
+        platform::virtio::tests::FakeTransport,
+    };
+
+    use super::*;
+
+    fn console(pa: &MockPageAllocator) -> VirtioConsole<FakeTransport> {
+        let device = Device::probe(FakeTransport::new(DEVICE_ID, 64))
+            .unwrap()
+            .unwrap();
+        VirtioConsole::new(device, &DmaAllocator::new(pa)).unwrap()
+    }
+
+    #[test]
+    fn only_drives_consoles() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let device = Device::probe(FakeTransport::new(2, 64)).unwrap().unwrap();
+        assert!(matches!(
+            VirtioConsole::new(device, &DmaAllocator::new(&pa)),
+            Err(Error::WrongDevice {
+                expected: DEVICE_ID,
+                found: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn write_until_full() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let console = console(&pa);
+        let fake = &console.device.transport;
+
+        let message = [b'x'; BUFFER_SIZE + 10];
+        assert_eq!(console.write(&message), message.len());
+        assert_eq!(fake.notifications.lock().unwrap().last(), Some(&1));
+        let sent = fake.take_buffers(1);
+        assert_eq!(
+            sent.iter().map(|(_, _, len)| *len).collect::<Vec<_>>(),
+            [BUFFER_SIZE as u32, 10]
+        );
+        let data = unsafe { core::slice::from_raw_parts(sent[1].1, 10) };
+        assert_eq!(data, &message[..10]);
+
+        // fill the rest of the buffers, then the writes are dropped until the device catches up
+        let capacity = console.write_capacity();
+        assert_eq!(capacity, (usize::from(QUEUE_SIZE) - 2) * BUFFER_SIZE);
+        assert_eq!(console.write(&alloc::vec![0; capacity + 1]), capacity);
+        assert_eq!(console.write(b"dropped"), 0);
+        assert!(!(&console).is_ready());
+        fake.complete(1, sent[0].0, 0);
+        assert!(!console.handle_interrupt());
+        assert_eq!(console.write_capacity(), BUFFER_SIZE);
+        assert_eq!(console.write(b"hello"), 5);
+    }
+
+    #[test]
+    fn read_received_data() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let console = console(&pa);
+        let fake = &console.device.transport;
+
+        let mut buf = [0; 8];
+        assert_eq!(console.read(&mut buf), 0);
+
+        let buffers = fake.take_buffers(0);
+        assert_eq!(buffers.len(), usize::from(QUEUE_SIZE));
+        for (i, data) in [b"hello, ".as_slice(), b"world"].into_iter().enumerate() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(data.as_ptr(), buffers[i].1, data.len());
+            }
+            fake.complete(0, buffers[i].0, data.len() as u32);
+        }
+        assert!(console.handle_interrupt());
+
+        // reads can span and split the device's buffers
+        assert_eq!(console.read(&mut buf[..3]), 3);
+        assert_eq!(&buf[..3], b"hel");
+        assert_eq!(console.read(&mut buf), 8);
+        assert_eq!(&buf, b"lo, worl");
+        assert_eq!(console.read(&mut buf), 1);
+        assert_eq!(buf[0], b'd');
+        assert!(!console.handle_interrupt());
+
+        // both emptied buffers were given back to the device
+        assert_eq!(fake.take_buffers(0).len(), 2);
+    }
+}
@@ -0,0 +1,498 @@
+//! Virtio devices, as provided by QEMU and other hypervisors, using the MMIO transport.
+//!
+//! Only the modern (version 2) MMIO interface is supported. QEMU's `virt` board exposes its virtio
+//! devices with the legacy interface unless it is started with
+//! `-global virtio-mmio.force-legacy=false`.
+//!
+//! Reference: <https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html>, section 4.2.
+#[cfg(test)]
+use mockall::automock;
+use snafu::{ensure, Snafu};
+
+pub mod block;
+pub mod console;
+pub mod queue;
+pub mod rng;
+
+use queue::SplitQueue;
+
+/// Register offsets of the MMIO transport, in bytes.
+pub mod regs {
+    /// Magic value register, which reads as [`MAGIC_VALUE`].
+    pub const MAGIC: usize = 0x000;
+    /// Version of the MMIO interface.
+    pub const VERSION: usize = 0x004;
+    /// Which kind of device this is, or zero if there is no device behind this transport.
+    pub const DEVICE_ID: usize = 0x008;
+    /// The features offered by the device, 32 bits at a time.
+    pub const DEVICE_FEATURES: usize = 0x010;
+    /// Selects which 32 bits of the device features [`DEVICE_FEATURES`] reads.
+    pub const DEVICE_FEATURES_SEL: usize = 0x014;
+    /// The features accepted by the driver, 32 bits at a time.
+    pub const DRIVER_FEATURES: usize = 0x020;
+    /// Selects which 32 bits of the driver features [`DRIVER_FEATURES`] writes.
+    pub const DRIVER_FEATURES_SEL: usize = 0x024;
+    /// Selects the queue that the other queue registers refer to.
+    pub const QUEUE_SEL: usize = 0x030;
+    /// The largest size the selected queue can have, or zero if it doesn't exist.
+    pub const QUEUE_NUM_MAX: usize = 0x034;
+    /// The size of the selected queue.
+    pub const QUEUE_NUM: usize = 0x038;
+    /// Whether the device may use the selected queue.
+    pub const QUEUE_READY: usize = 0x044;
+    /// Written with a queue index to tell the device that new buffers are available.
+    pub const QUEUE_NOTIFY: usize = 0x050;
+    /// The reasons for a pending interrupt.
+    pub const INTERRUPT_STATUS: usize = 0x060;
+    /// Written with the reasons that have been handled.
+    pub const INTERRUPT_ACK: usize = 0x064;
+    /// The device status.
+    pub const STATUS: usize = 0x070;
+    /// Changes whenever the device configuration changes.
+    pub const CONFIG_GENERATION: usize = 0x0fc;
+    /// The start of the device-specific configuration.
+    pub const CONFIG: usize = 0x100;
+    /// Low 32 bits of the physical address of the selected queue's descriptor table.
+    pub const QUEUE_DESC_LOW: usize = 0x080;
+    /// High 32 bits of the physical address of the selected queue's descriptor table.
+    pub const QUEUE_DESC_HIGH: usize = 0x084;
+    /// Low 32 bits of the physical address of the selected queue's available ring.
+    pub const QUEUE_DRIVER_LOW: usize = 0x090;
+    /// High 32 bits of the physical address of the selected queue's available ring.
+    pub const QUEUE_DRIVER_HIGH: usize = 0x094;
+    /// Low 32 bits of the physical address of the selected queue's used ring.
+    pub const QUEUE_DEVICE_LOW: usize = 0x0a0;
+    /// High 32 bits of the physical address of the selected queue's used ring.
+    pub const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+
+    /// The value of the [`MAGIC`] register, "virt" in little endian.
+    pub const MAGIC_VALUE: u32 = 0x7472_6976;
+    /// The [`VERSION`] of the modern MMIO interface.
+    pub const MODERN_VERSION: u32 = 2;
+}
+
+/// Bits of the device status register.
+pub mod status {
+    /// The driver has noticed the device.
+    pub const ACKNOWLEDGE: u32 = 1;
+    /// The driver knows how to drive the device.
+    pub const DRIVER: u32 = 2;
+    /// The driver is set up and ready to drive the device.
+    pub const DRIVER_OK: u32 = 4;
+    /// The driver has finished negotiating features.
+    pub const FEATURES_OK: u32 = 8;
+    /// The driver has given up on the device.
+    pub const FAILED: u32 = 128;
+}
+
+/// Feature bit indicating compliance with version 1 of the specification, which every modern
+/// device offers and every driver must accept.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// Mechanism interface for the registers of a virtio device's MMIO transport.
+#[cfg_attr(test, automock)]
+pub trait Transport {
+    /// Read the 32-bit register at `offset` bytes.
+    fn read(&self, offset: usize) -> u32;
+
+    /// Write `value` to the 32-bit register at `offset` bytes.
+    fn write(&self, offset: usize, value: u32);
+}
+
+/// Errors setting up a virtio device.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The registers do not belong to a virtio MMIO transport.
+    #[snafu(display("not a virtio device (magic value {magic:#x})"))]
+    NotVirtio {
+        /// The value read from the magic register.
+        magic: u32,
+    },
+    /// The transport uses a version of the MMIO interface that is not supported.
+    #[snafu(display("unsupported virtio MMIO version {version}"))]
+    UnsupportedVersion {
+        /// The version reported by the transport.
+        version: u32,
+    },
+    /// The device did not accept the negotiated features.
+    #[snafu(display("device rejected features {features:#x}"))]
+    FeaturesRejected {
+        /// The features the driver tried to use.
+        features: u64,
+    },
+    /// The device does not have the requested queue, or it is already in use.
+    #[snafu(display("virtqueue {index} is unavailable"))]
+    QueueUnavailable {
+        /// The index of the queue.
+        index: u16,
+    },
+    /// The device can't use a queue as large as the driver's.
+    #[snafu(display("virtqueue {index} supports at most {max} entries"))]
+    QueueTooLarge {
+        /// The index of the queue.
+        index: u16,
+        /// The largest size supported by the device.
+        max: u32,
+    },
+    /// The device needs a different driver.
+    #[snafu(display("expected virtio device {expected}, found {found}"))]
+    WrongDevice {
+        /// The device ID the driver supports.
+        expected: u32,
+        /// The device ID of the device.
+        found: u32,
+    },
+    /// Memory for the virtqueues could not be allocated.
+    #[snafu(display("allocate virtqueue memory"))]
+    Memory {
+        /// Underlying memory error.
+        source: crate::memory::Error,
+    },
+}
+
+/// A virtio device behind an MMIO transport.
+pub struct Device<T> {
+    transport: T,
+}
+
+impl<T: Transport> Device<T> {
+    /// Check that `transport` is a supported virtio MMIO transport.
+    ///
+    /// Returns `None` if there is no device behind the transport, which is common since platforms
+    /// provide more transports than devices.
+    ///
+    /// # Errors
+    /// - [`Error::NotVirtio`] if the registers don't belong to a virtio transport.
+    /// - [`Error::UnsupportedVersion`] if the transport is not the modern interface.
+    pub fn probe(transport: T) -> Result<Option<Self>, Error> {
+        let magic = transport.read(regs::MAGIC);
+        ensure!(magic == regs::MAGIC_VALUE, NotVirtioSnafu { magic });
+        let version = transport.read(regs::VERSION);
+        ensure!(
+            version == regs::MODERN_VERSION,
+            UnsupportedVersionSnafu { version }
+        );
+        if transport.read(regs::DEVICE_ID) == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Self { transport }))
+    }
+
+    /// Which kind of device this is.
+    #[must_use]
+    pub fn device_id(&self) -> u32 {
+        self.transport.read(regs::DEVICE_ID)
+    }
+
+    /// Reset the device and negotiate features, using the features in `wanted` that the device
+    /// offers. [`FEATURE_VERSION_1`] is always used.
+    ///
+    /// Returns the negotiated features. The queues must be set up with [`Self::add_queue`] before
+    /// the device is started with [`Self::start`].
+    ///
+    /// # Errors
+    /// - [`Error::FeaturesRejected`] if the device doesn't accept the features. The device is
+    ///   marked as failed.
+    // the features are written 32 bits at a time
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn initialize(&self, wanted: u64) -> Result<u64, Error> {
+        self.transport.write(regs::STATUS, 0);
+        self.transport.write(regs::STATUS, status::ACKNOWLEDGE);
+        self.transport
+            .write(regs::STATUS, status::ACKNOWLEDGE | status::DRIVER);
+
+        let mut offered = 0;
+        for half in 0..2 {
+            self.transport.write(regs::DEVICE_FEATURES_SEL, half);
+            offered |= u64::from(self.transport.read(regs::DEVICE_FEATURES)) << (32 * half);
+        }
+        let features = offered & (wanted | FEATURE_VERSION_1);
+        for half in 0..2 {
+            self.transport.write(regs::DRIVER_FEATURES_SEL, half);
+            self.transport
+                .write(regs::DRIVER_FEATURES, (features >> (32 * half)) as u32);
+        }
+
+        let negotiated = status::ACKNOWLEDGE | status::DRIVER | status::FEATURES_OK;
+        self.transport.write(regs::STATUS, negotiated);
+        if features & FEATURE_VERSION_1 == 0
+            || self.transport.read(regs::STATUS) & status::FEATURES_OK == 0
+        {
+            self.transport.write(regs::STATUS, status::FAILED);
+            return FeaturesRejectedSnafu { features }.fail();
+        }
+        Ok(features)
+    }
+
+    /// Give the device the memory of queue number `index`.
+    ///
+    /// # Errors
+    /// - [`Error::QueueUnavailable`] if the device doesn't have the queue or it is already set up.
+    /// - [`Error::QueueTooLarge`] if `queue` is larger than the device supports.
+    // the addresses are written 32 bits at a time
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn add_queue(&self, index: u16, queue: &SplitQueue) -> Result<(), Error> {
+        self.transport.write(regs::QUEUE_SEL, u32::from(index));
+        let max = self.transport.read(regs::QUEUE_NUM_MAX);
+        ensure!(
+            max != 0 && self.transport.read(regs::QUEUE_READY) == 0,
+            QueueUnavailableSnafu { index }
+        );
+        ensure!(
+            u32::from(queue.size()) <= max,
+            QueueTooLargeSnafu { index, max }
+        );
+        self.transport
+            .write(regs::QUEUE_NUM, u32::from(queue.size()));
+        for (low, address) in [
+            (regs::QUEUE_DESC_LOW, queue.descriptor_table()),
+            (regs::QUEUE_DRIVER_LOW, queue.driver_area()),
+            (regs::QUEUE_DEVICE_LOW, queue.device_area()),
+        ] {
+            let address = usize::from(address) as u64;
+            self.transport.write(low, address as u32);
+            self.transport.write(low + 4, (address >> 32) as u32);
+        }
+        self.transport.write(regs::QUEUE_READY, 1);
+        Ok(())
+    }
+
+    /// Read the 64-bit value at `offset` bytes into the device-specific configuration.
+    #[must_use]
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        // the device may change its configuration between the two halves, so retry until a
+        // consistent value is read
+        loop {
+            let generation = self.transport.read(regs::CONFIG_GENERATION);
+            let low = self.transport.read(regs::CONFIG + offset);
+            let high = self.transport.read(regs::CONFIG + offset + 4);
+            if self.transport.read(regs::CONFIG_GENERATION) == generation {
+                return u64::from(high) << 32 | u64::from(low);
+            }
+        }
+    }
+
+    /// Tell the device that the driver is ready, after which the device may use its queues.
+    pub fn start(&self) {
+        let current = self.transport.read(regs::STATUS);
+        self.transport
+            .write(regs::STATUS, current | status::DRIVER_OK);
+    }
+
+    /// Tell the device that buffers were made available in queue number `index`.
+    pub fn notify(&self, index: u16) {
+        self.transport.write(regs::QUEUE_NOTIFY, u32::from(index));
+    }
+
+    /// Acknowledge the device's pending interrupt, returning the reasons it was raised.
+    pub fn acknowledge_interrupt(&self) -> u32 {
+        let reasons = self.transport.read(regs::INTERRUPT_STATUS);
+        self.transport.write(regs::INTERRUPT_ACK, reasons);
+        reasons
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{collections::HashMap, sync::Mutex, vec::Vec};
+
+    use super::*;
+
+    /// The configuration of a queue, as given to a [`FakeTransport`].
+    #[derive(Clone, Copy)]
+    struct FakeQueue {
+        size: u16,
+        descriptors: usize,
+        available: usize,
+        used: usize,
+        /// The next available ring entry to be taken by the device.
+        next_available: u16,
+        /// The next used ring entry to be filled by the device.
+        next_used: u16,
+    }
+
+    /// A virtio MMIO transport for a fake device, which tests drive by taking buffers from the
+    /// queues and completing them.
+    pub struct FakeTransport {
+        registers: Mutex<HashMap<usize, u32>>,
+        queues: Mutex<HashMap<u32, FakeQueue>>,
+        /// The queue indices that were written to the notify register, in order.
+        pub notifications: Mutex<Vec<u32>>,
+        /// The features offered by the device.
+        pub features: Mutex<u64>,
+    }
+
+    impl FakeTransport {
+        /// A transport for a device with ID `device_id` that has queues of at most `queue_max`
+        /// entries.
+        pub fn new(device_id: u32, queue_max: u32) -> Self {
+            Self {
+                registers: Mutex::new(HashMap::from([
+                    (regs::MAGIC, regs::MAGIC_VALUE),
+                    (regs::VERSION, regs::MODERN_VERSION),
+                    (regs::DEVICE_ID, device_id),
+                    (regs::QUEUE_NUM_MAX, queue_max),
+                ])),
+                queues: Mutex::default(),
+                notifications: Mutex::default(),
+                features: Mutex::new(FEATURE_VERSION_1),
+            }
+        }
+
+        /// The value last written to the register at `offset`.
+        pub fn register(&self, offset: usize) -> u32 {
+            self.registers
+                .lock()
+                .unwrap()
+                .get(&offset)
+                .copied()
+                .unwrap_or_default()
+        }
+
+        /// Set the value of the register at `offset`.
+        pub fn set_register(&self, offset: usize, value: u32) {
+            self.registers.lock().unwrap().insert(offset, value);
+        }
+
+        /// Take every buffer the driver has made available in queue `index` since the last call,
+        /// returning the descriptor ID, address and length of each.
+        ///
+        /// Only the first buffer of each chain is returned.
+        pub fn take_buffers(&self, index: u32) -> Vec<(u16, *mut u8, u32)> {
+            self.take_chains(index)
+                .into_iter()
+                .map(|(id, buffers)| (id, buffers[0].0, buffers[0].1))
+                .collect()
+        }
+
+        /// Take every chain of buffers the driver has made available in queue `index` since the
+        /// last call, returning the ID of the first descriptor and the address and length of each
+        /// buffer in the chain.
+        pub fn take_chains(&self, index: u32) -> Vec<(u16, Vec<(*mut u8, u32)>)> {
+            let mut queues = self.queues.lock().unwrap();
+            let queue = queues.get_mut(&index).expect("queue was set up");
+            let mut chains = Vec::new();
+            unsafe {
+                let available_index = ((queue.available + 2) as *const u16).read_volatile();
+                while queue.next_available != available_index {
+                    let slot = usize::from(queue.next_available % queue.size);
+                    let head = ((queue.available + 4 + 2 * slot) as *const u16).read_volatile();
+                    let mut buffers = Vec::new();
+                    let mut id = head;
+                    loop {
+                        let descriptor = queue.descriptors + 16 * usize::from(id);
+                        let address = (descriptor as *const u64).read_volatile();
+                        let len = ((descriptor + 8) as *const u32).read_volatile();
+                        buffers.push((address as *mut u8, len));
+                        if ((descriptor + 12) as *const u16).read_volatile() & 1 == 0 {
+                            break;
+                        }
+                        id = ((descriptor + 14) as *const u16).read_volatile();
+                    }
+                    chains.push((head, buffers));
+                    queue.next_available = queue.next_available.wrapping_add(1);
+                }
+            }
+            chains
+        }
+
+        /// Return the buffer with descriptor `id` in queue `index` to the driver, with `len` bytes
+        /// written to it.
+        pub fn complete(&self, index: u32, id: u16, len: u32) {
+            let mut queues = self.queues.lock().unwrap();
+            let queue = queues.get_mut(&index).expect("queue was set up");
+            let slot = usize::from(queue.next_used % queue.size);
+            queue.next_used = queue.next_used.wrapping_add(1);
+            unsafe {
+                let element = queue.used + 4 + 8 * slot;
+                (element as *mut u32).write_volatile(u32::from(id));
+                ((element + 4) as *mut u32).write_volatile(len);
+                ((queue.used + 2) as *mut u16).write_volatile(queue.next_used);
+            }
+        }
+    }
+
+    impl Transport for FakeTransport {
+        fn read(&self, offset: usize) -> u32 {
+            match offset {
+                // the device offers only the mandatory feature
+                regs::DEVICE_FEATURES => {
+                    (*self.features.lock().unwrap()
+                        >> (32 * self.register(regs::DEVICE_FEATURES_SEL)))
+                        as u32
+                }
+                regs::QUEUE_READY => u32::from(
+                    self.queues
+                        .lock()
+                        .unwrap()
+                        .contains_key(&self.register(regs::QUEUE_SEL)),
+                ),
+                _ => self.register(offset),
+            }
+        }
+
+        fn write(&self, offset: usize, value: u32) {
+            match offset {
+                regs::QUEUE_NOTIFY => self.notifications.lock().unwrap().push(value),
+                regs::QUEUE_READY if value == 1 => {
+                    let address = |low| {
+                        (u64::from(self.register(low + 4)) << 32 | u64::from(self.register(low)))
+                            as usize
+                    };
+                    self.queues.lock().unwrap().insert(
+                        self.register(regs::QUEUE_SEL),
+                        FakeQueue {
+                            size: self.register(regs::QUEUE_NUM) as u16,
+                            descriptors: address(regs::QUEUE_DESC_LOW),
+                            available: address(regs::QUEUE_DRIVER_LOW),
+                            used: address(regs::QUEUE_DEVICE_LOW),
+                            next_available: 0,
+                            next_used: 0,
+                        },
+                    );
+                }
+                _ => self.set_register(offset, value),
+            }
+        }
+    }
+
+    #[test]
+    fn probe_checks_transport() {
+        let mut mech = MockTransport::new();
+        mech.expect_read()
+            .with(mockall::predicate::eq(regs::MAGIC))
+            .return_const(0u32);
+        assert!(matches!(
+            Device::probe(mech),
+            Err(Error::NotVirtio { magic: 0 })
+        ));
+
+        let fake = FakeTransport::new(0, 16);
+        fake.set_register(regs::VERSION, 1);
+        assert!(matches!(
+            Device::probe(fake),
+            Err(Error::UnsupportedVersion { version: 1 })
+        ));
+
+        assert!(Device::probe(FakeTransport::new(0, 16)).unwrap().is_none());
+        let device = Device::probe(FakeTransport::new(3, 16)).unwrap().unwrap();
+        assert_eq!(device.device_id(), 3);
+    }
+
+    #[test]
+    fn negotiate_features() {
+        let device = Device::probe(FakeTransport::new(3, 16)).unwrap().unwrap();
+        // the device doesn't offer feature 0, so only the mandatory feature is used
+        assert_eq!(device.initialize(1).unwrap(), FEATURE_VERSION_1);
+        assert_eq!(device.transport.register(regs::DRIVER_FEATURES), 1);
+        assert_eq!(
+            device.transport.register(regs::STATUS),
+            status::ACKNOWLEDGE | status::DRIVER | status::FEATURES_OK
+        );
+        device.start();
+        assert_ne!(
+            device.transport.register(regs::STATUS) & status::DRIVER_OK,
+            0
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Split virtqueues, the rings of buffers shared between a driver and a virtio device.
+//!
+//! A queue's memory holds three parts: the descriptor table describing each buffer, the available
+//! ring where the driver places buffers for the device, and the used ring where the device returns
+//! them. A request to the device can be made of several buffers, which are chained together.
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::memory::{
+    AllocationConstraints, DmaAllocator, DmaBuffer, Error, PageAllocator, PhysicalAddress,
+};
+
+/// Descriptor flag marking a buffer that is followed by another in the same chain.
+const DESCRIPTOR_NEXT: u16 = 1;
+/// Descriptor flag marking a buffer that the device writes to, rather than reads from.
+const DESCRIPTOR_WRITE: u16 = 2;
+
+/// The size of an entry in the descriptor table, in bytes.
+const DESCRIPTOR_SIZE: usize = 16;
+
+/// A split virtqueue and the memory it occupies.
+pub struct SplitQueue {
+    memory: DmaBuffer,
+    size: u16,
+    /// The indices of descriptors that aren't in use.
+    free: Vec<u16>,
+    /// The token of each chain that the device has, by the index of its first descriptor.
+    tokens: Vec<Option<usize>>,
+    /// The descriptor that follows each descriptor in its chain, kept apart from the descriptor
+    /// table so that the device can't change which descriptors are freed.
+    links: Vec<Option<u16>>,
+    /// The index of the next available ring entry.
+    next_available: u16,
+    /// The index of the next used ring entry to be returned by [`Self::pop_used`].
+    next_used: u16,
+}
+
+impl SplitQueue {
+    /// Allocate a queue with room for `size` buffers, which must be a power of two.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidSize`] if `size` is not a power of two.
+    /// - [`Error::OutOfMemory`] if the memory for the queue could not be allocated.
+    pub fn new(dma: &DmaAllocator<impl PageAllocator>, size: u16) -> Result<Self, Error> {
+        if !size.is_power_of_two() {
+            return Err(Error::InvalidSize);
+        }
+        let memory = dma.allocate(
+            Self::used_offset(size) + 6 + 8 * usize::from(size),
+            &AllocationConstraints::default(),
+        )?;
+        Ok(Self {
+            memory,
+            size,
+            free: (0..size).rev().collect(),
+            tokens: alloc::vec![None; usize::from(size)],
+            links: alloc::vec![None; usize::from(size)],
+            next_available: 0,
+            next_used: 0,
+        })
+    }
+
+    /// Free the memory of the queue, once the device is no longer using it.
+    ///
+    /// # Errors
+    /// - [`Error::UnknownPtr`] if the queue was not allocated by `dma`.
+    pub fn free(self, dma: &DmaAllocator<impl PageAllocator>) -> Result<(), Error> {
+        dma.free(self.memory)
+    }
+
+    /// The number of buffers the queue can hold.
+    #[must_use]
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The physical address of the descriptor table.
+    #[must_use]
+    pub fn descriptor_table(&self) -> PhysicalAddress {
+        self.memory.physical_address()
+    }
+
+    /// The physical address of the available ring.
+    #[must_use]
+    pub fn driver_area(&self) -> PhysicalAddress {
+        self.memory
+            .physical_address()
+            .byte_add(Self::available_offset(self.size))
+    }
+
+    /// The physical address of the used ring.
+    #[must_use]
+    pub fn device_area(&self) -> PhysicalAddress {
+        self.memory
+            .physical_address()
+            .byte_add(Self::used_offset(self.size))
+    }
+
+    fn available_offset(size: u16) -> usize {
+        DESCRIPTOR_SIZE * usize::from(size)
+    }
+
+    fn used_offset(size: u16) -> usize {
+        // the used ring must be 4 byte aligned
+        (Self::available_offset(size) + 6 + 2 * usize::from(size)).next_multiple_of(4)
+    }
+
+    /// A pointer to the value at `offset` bytes into the queue's memory.
+    fn field<T>(&self, offset: usize) -> *mut T {
+        debug_assert!(offset + size_of::<T>() <= self.memory.len());
+        unsafe { self.memory.as_ptr().add(offset).cast() }
+    }
+
+    /// Make the buffer of `len` bytes at `address` available to the device, which will write to it
+    /// if `device_writable` is true and read from it otherwise.
+    ///
+    /// `token` is returned by [`Self::pop_used`] when the device is finished with the buffer. The
+    /// device must be notified of the new buffer separately.
+    ///
+    /// Returns false if the queue is full.
+    pub fn push(
+        &mut self,
+        address: PhysicalAddress,
+        len: u32,
+        device_writable: bool,
+        token: usize,
+    ) -> bool {
+        self.push_chain(&[(address, len, device_writable)], token)
+    }
+
+    /// Make a chain of buffers available to the device as a single request, like [`Self::push`].
+    /// Each buffer is given as its address, its length in bytes, and whether the device writes to
+    /// it.
+    ///
+    /// Returns false if there aren't enough free descriptors for every buffer in the chain.
+    pub fn push_chain(&mut self, buffers: &[(PhysicalAddress, u32, bool)], token: usize) -> bool {
+        if buffers.is_empty() || self.free.len() < buffers.len() {
+            return false;
+        }
+        let ids = self.free.split_off(self.free.len() - buffers.len());
+        for (i, &(address, len, device_writable)) in buffers.iter().enumerate() {
+            let descriptor = DESCRIPTOR_SIZE * usize::from(ids[i]);
+            let next = ids.get(i + 1).copied();
+            self.links[usize::from(ids[i])] = next;
+            let mut flags = if device_writable { DESCRIPTOR_WRITE } else { 0 };
+            if next.is_some() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            unsafe {
+                self.field::<u64>(descriptor)
+                    .write_volatile(usize::from(address) as u64);
+                self.field::<u32>(descriptor + 8).write_volatile(len);
+                self.field::<u16>(descriptor + 12).write_volatile(flags);
+                self.field::<u16>(descriptor + 14)
+                    .write_volatile(next.unwrap_or_default());
+            }
+        }
+        let head = ids[0];
+        self.tokens[usize::from(head)] = Some(token);
+        let available = Self::available_offset(self.size);
+        let slot = usize::from(self.next_available % self.size);
+        self.next_available = self.next_available.wrapping_add(1);
+        unsafe {
+            self.field::<u16>(available + 4 + 2 * slot)
+                .write_volatile(head);
+            // the device must see the descriptors before the new index
+            fence(Ordering::Release);
+            self.field::<u16>(available + 2)
+                .write_volatile(self.next_available);
+        }
+        true
+    }
+
+    /// Returns true if the device has finished with any buffers that haven't been taken by
+    /// [`Self::pop_used`].
+    #[must_use]
+    pub fn has_used(&self) -> bool {
+        let used = Self::used_offset(self.size);
+        unsafe { self.field::<u16>(used + 2).read_volatile() != self.next_used }
+    }
+
+    /// Take the next buffer that the device has finished with, returning its token and the number
+    /// of bytes the device wrote to it.
+    ///
+    /// Used ring entries that don't name a chain the device has are skipped, so a misbehaving
+    /// device can't make a descriptor be freed twice.
+    pub fn pop_used(&mut self) -> Option<(usize, u32)> {
+        let used = Self::used_offset(self.size);
+        while self.has_used() {
+            // the ring entry must be read after the index that covers it
+            fence(Ordering::Acquire);
+            let element = used + 4 + 8 * usize::from(self.next_used % self.size);
+            self.next_used = self.next_used.wrapping_add(1);
+            let (id, len) = unsafe {
+                (
+                    self.field::<u32>(element).read_volatile(),
+                    self.field::<u32>(element + 4).read_volatile(),
+                )
+            };
+            let Some(head) = u16::try_from(id).ok().filter(|id| *id < self.size) else {
+                continue;
+            };
+            let Some(token) = self.tokens[usize::from(head)].take() else {
+                continue;
+            };
+            let mut next = Some(head);
+            while let Some(id) = next {
+                self.free.push(id);
+                next = self.links[usize::from(id)].take();
+            }
+            return Some((token, len));
+        }
+        None
+    }
+}
+
+// SAFETY: the queue's memory is owned by the queue, and only accessed through `&mut self` or by
+// the device.
+unsafe impl Send for SplitQueue {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        memory::{tests::MockPageAllocator, PageSize},
+        platform::virtio::{tests::FakeTransport, Device},
+    };
+
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 4);
+        let dma = DmaAllocator::new(&pa);
+        assert!(matches!(SplitQueue::new(&dma, 12), Err(Error::InvalidSize)));
+        let queue = SplitQueue::new(&dma, 16).unwrap();
+        assert_eq!(queue.size(), 16);
+        let base = usize::from(queue.descriptor_table());
+        assert_eq!(usize::from(queue.driver_area()) - base, 256);
+        // 256 + 6 + 32 rounded up to a multiple of 4
+        assert_eq!(usize::from(queue.device_area()) - base, 296);
+        queue.free(&dma).unwrap();
+        pa.end_check();
+    }
+
+    #[test]
+    fn buffers_round_trip() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 4);
+        let dma = DmaAllocator::new(&pa);
+        let fake = FakeTransport::new(3, 4);
+        let device = Device::probe(fake).unwrap().unwrap();
+        let mut queue = SplitQueue::new(&dma, 4).unwrap();
+        device.add_queue(0, &queue).unwrap();
+        let fake = &device.transport;
+
+        for i in 0..4 {
+            assert!(queue.push(PhysicalAddress::from(0x1000 * (i + 1)), 8, i % 2 == 0, i));
+        }
+        assert!(!queue.push(PhysicalAddress::from(0x5000), 8, false, 4));
+        assert_eq!(queue.pop_used(), None);
+
+        let buffers = fake.take_buffers(0);
+        assert_eq!(buffers.len(), 4);
+        assert_eq!(buffers[2].1 as usize, 0x3000);
+        // complete them out of order
+        fake.complete(0, buffers[2].0, 5);
+        fake.complete(0, buffers[0].0, 1);
+        assert_eq!(queue.pop_used(), Some((2, 5)));
+        assert_eq!(queue.pop_used(), Some((0, 1)));
+        assert_eq!(queue.pop_used(), None);
+
+        // the freed descriptors can be used again, wrapping around the rings
+        assert!(queue.push(PhysicalAddress::from(0x6000), 8, true, 6));
+        assert!(queue.push(PhysicalAddress::from(0x7000), 8, true, 7));
+        assert!(!queue.push(PhysicalAddress::from(0x8000), 8, true, 8));
+        let buffers = fake.take_buffers(0);
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(buffers[1].1 as usize, 0x7000);
+        fake.complete(0, buffers[1].0, 0);
+        assert_eq!(queue.pop_used(), Some((7, 0)));
+
+        // a buffer returned twice, or one the device never had, is only freed once
+        fake.complete(0, buffers[1].0, 0);
+        fake.complete(0, 99, 0);
+        fake.complete(0, buffers[0].0, 3);
+        assert_eq!(queue.pop_used(), Some((6, 3)));
+        assert_eq!(queue.pop_used(), None);
+        // the device still has two of the first buffers
+        assert!(queue.push(PhysicalAddress::from(0x6000), 8, true, 6));
+        assert!(queue.push(PhysicalAddress::from(0x7000), 8, true, 7));
+        assert!(!queue.push(PhysicalAddress::from(0x8000), 8, true, 8));
+
+        queue.free(&dma).unwrap();
+        pa.end_check();
+    }
+}
@@ -0,0 +1,154 @@
+//! Driver for the virtio entropy device, a source of random bytes provided by the hypervisor (for
+//! QEMU, `-device virtio-rng-device`).
+//!
+//! The device fills buffers with random bytes as it is asked to, which are mixed into the kernel's
+//! entropy pool (see [`crate::rand`]).
+use snafu::ResultExt;
+
+use super::{queue::SplitQueue, Device, Error, MemorySnafu, Transport};
+use crate::{
+    memory::{AllocationConstraints, DmaAllocator, DmaBuffer, PageAllocator},
+    sync::Mutex,
+};
+
+/// The virtio device ID of an entropy source.
+pub const DEVICE_ID: u32 = 4;
+
+/// The index of the only request queue.
+const REQUEST_QUEUE: u16 = 0;
+
+/// The number of descriptors in the request queue. Only one request is in flight at a time.
+const QUEUE_SIZE: u16 = 2;
+
+/// The number of random bytes asked for in each request.
+const BUFFER_SIZE: usize = 64;
+
+struct State {
+    queue: SplitQueue,
+    /// The buffer the device writes random bytes to.
+    buffer: DmaBuffer,
+    /// True if the buffer has been given to the device.
+    in_flight: bool,
+}
+
+// SAFETY: the buffer is owned by the driver, and only accessed with its lock held or by the
+// device.
+unsafe impl Send for State {}
+
+/// A virtio entropy device.
+pub struct VirtioRng<T> {
+    device: Device<T>,
+    state: Mutex<State>,
+}
+
+impl<T: Transport> VirtioRng<T> {
+    /// Set up `device`, which must be an entropy device, and ask it for random bytes.
+    ///
+    /// # Errors
+    /// - [`Error::WrongDevice`] if the device is not an entropy device.
+    /// - [`Error::Memory`] if the queue or buffer could not be allocated.
+    /// - Any error setting up the device.
+    pub fn new(device: Device<T>, dma: &DmaAllocator<impl PageAllocator>) -> Result<Self, Error> {
+        let found = device.device_id();
+        snafu::ensure!(
+            found == DEVICE_ID,
+            super::WrongDeviceSnafu {
+                expected: DEVICE_ID,
+                found
+            }
+        );
+        device.initialize(0)?;
+        let queue = SplitQueue::new(dma, QUEUE_SIZE).context(MemorySnafu)?;
+        device.add_queue(REQUEST_QUEUE, &queue)?;
+        let buffer = dma
+            .allocate(BUFFER_SIZE, &AllocationConstraints::default())
+            .context(MemorySnafu)?;
+        device.start();
+        let rng = Self {
+            device,
+            state: Mutex::new(State {
+                queue,
+                buffer,
+                in_flight: false,
+            }),
+        };
+        rng.request();
+        Ok(rng)
+    }
+
+    /// Ask the device for more random bytes, unless a request is already in flight.
+    // the buffer is much smaller than 4GiB
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn request(&self) {
+        let mut state = self.state.lock();
+        if state.in_flight {
+            return;
+        }
+        let address = state.buffer.physical_address();
+        // the only request is never in flight twice, so the queue is never full
+        let queued = state.queue.push(address, BUFFER_SIZE as u32, true, 0);
+        debug_assert!(queued);
+        state.in_flight = true;
+        drop(state);
+        self.device.notify(REQUEST_QUEUE);
+    }
+
+    /// Handle an interrupt from the device, mixing the random bytes it has written into the
+    /// kernel's entropy pool.
+    ///
+    /// Returns the number of bytes received.
+    pub fn handle_interrupt(&self) -> usize {
+        self.device.acknowledge_interrupt();
+        let mut bytes = [0; BUFFER_SIZE];
+        let mut state = self.state.lock();
+        let Some((_, len)) = state.queue.pop_used() else {
+            return 0;
+        };
+        state.in_flight = false;
+        let len = (len as usize).min(BUFFER_SIZE);
+        unsafe {
+            core::ptr::copy_nonoverlapping(state.buffer.as_ptr(), bytes.as_mut_ptr(), len);
+        }
+        drop(state);
+        // the device is a true entropy source, so every byte counts
+        crate::rand::add_entropy(&bytes[..len], len);
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        memory::{tests::MockPageAllocator, PageSize},
+        platform::virtio::tests::FakeTransport,
+    };
+
+    use super::*;
+
+    #[test]
+    fn request_random_bytes() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        let device = Device::probe(FakeTransport::new(DEVICE_ID, 8))
+            .unwrap()
+            .unwrap();
+        let rng = VirtioRng::new(device, &DmaAllocator::new(&pa)).unwrap();
+        let fake = &rng.device.transport;
+        assert_eq!(fake.notifications.lock().unwrap().as_slice(), [0]);
+
+        // only one request is in flight at a time
+        rng.request();
+        let buffers = fake.take_buffers(0);
+        assert_eq!(buffers.len(), 1);
+        let (id, buffer, len) = buffers[0];
+        assert_eq!(len, BUFFER_SIZE as u32);
+        assert_eq!(rng.handle_interrupt(), 0);
+
+        unsafe { core::ptr::write_bytes(buffer, 0x5a, 16) };
+        fake.complete(0, id, 16);
+        assert_eq!(rng.handle_interrupt(), 16);
+
+        rng.request();
+        assert_eq!(fake.take_buffers(0).len(), 1);
+        assert_eq!(fake.notifications.lock().unwrap().as_slice(), [0, 0]);
+    }
+}
@@ -0,0 +1,129 @@
+//! Watchdog timers, which reset the system if the kernel stops responding.
+//!
+//! Once started, the hardware watchdog must be "petted" periodically or it will reset the system.
+//! The [`WatchdogPolicy`] only pets the watchdog if every core has shown signs of life since the
+//! last time it checked, so that a single core locking up also causes a reset. Idle cores are
+//! waiting for an interrupt rather than locked up, so they are exempt until they next wake up.
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+use log::warn;
+
+#[cfg(test)]
+use mockall::automock;
+
+use crate::{platform::cpu::Id as CpuId, time::Ticks};
+
+/// Mechanism interface for a hardware watchdog timer.
+#[cfg_attr(test, automock)]
+pub trait Watchdog {
+    /// Start the watchdog so that it resets the system if it is not petted within `timeout`.
+    fn start(&self, timeout: Duration);
+
+    /// Restart the watchdog's countdown.
+    fn pet(&self);
+}
+
+/// Policy for deciding when to pet the watchdog.
+pub struct WatchdogPolicy {
+    /// The id of each core, in the order of core indices.
+    cores: Vec<CpuId>,
+    /// The time each core last showed signs of life, by core index, or [`Ticks::MAX`] if it is idle.
+    last_heartbeat: Vec<AtomicU64>,
+    /// The time of the last check.
+    last_check: AtomicU64,
+}
+
+impl WatchdogPolicy {
+    /// Create a new policy for the cores with ids `cores`, as if every core had a heartbeat at `now`.
+    #[must_use]
+    pub fn new(cores: &[CpuId], now: Ticks) -> Self {
+        Self {
+            cores: cores.to_vec(),
+            last_heartbeat: cores.iter().map(|_| AtomicU64::new(now)).collect(),
+            last_check: AtomicU64::new(now),
+        }
+    }
+
+    /// Record that core `core` is still responsive at time `now`.
+    pub fn heartbeat(&self, core: CpuId, now: Ticks) {
+        if let Some(i) = self.cores.iter().position(|c| *c == core) {
+            self.last_heartbeat[i].store(now, Ordering::Release);
+        }
+    }
+
+    /// Record that core `core` is idle and waiting for an interrupt, so it does not need to have
+    /// a heartbeat until it wakes up.
+    pub fn idle(&self, core: CpuId) {
+        self.heartbeat(core, Ticks::MAX);
+    }
+
+    /// Check whether every core has had a heartbeat since the previous check, and if so pet
+    /// `watchdog`. Checks must be spaced far enough apart that every busy core will have been
+    /// interrupted at least once in between.
+    ///
+    /// Returns true if the watchdog was petted.
+    pub fn check(&self, watchdog: &impl Watchdog, now: Ticks) -> bool {
+        let last_check = self.last_check.swap(now, Ordering::AcqRel);
+        let mut healthy = true;
+        for (core, heartbeat) in self.cores.iter().zip(self.last_heartbeat.iter()) {
+            if heartbeat.load(Ordering::Acquire) < last_check {
+                warn!("core {core} has not responded since {last_check}");
+                healthy = false;
+            }
+        }
+        if healthy {
+            watchdog.pet();
+        }
+        healthy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pet_only_if_all_cores_alive() {
+        let mut wd = MockWatchdog::new();
+        wd.expect_pet().times(2).return_const(());
+        let p = WatchdogPolicy::new(&[0, 1], 0);
+
+        assert!(p.check(&wd, 10));
+
+        p.heartbeat(0, 12);
+        p.heartbeat(1, 15);
+        assert!(p.check(&wd, 20));
+
+        // core 1 has stopped responding
+        p.heartbeat(0, 25);
+        p.heartbeat(1, 19);
+        assert!(!p.check(&wd, 30));
+
+        // unknown cores are ignored
+        p.heartbeat(7, 35);
+        assert!(!p.check(&wd, 40));
+    }
+
+    #[test]
+    fn idle_cores_are_exempt() {
+        let mut wd = MockWatchdog::new();
+        wd.expect_pet().times(3).return_const(());
+        let p = WatchdogPolicy::new(&[0, 1], 0);
+        p.idle(1);
+        p.heartbeat(0, 5);
+        assert!(p.check(&wd, 10));
+        p.heartbeat(0, 15);
+        assert!(p.check(&wd, 20));
+
+        // once the core wakes up it must keep responding
+        p.heartbeat(1, 21);
+        p.heartbeat(0, 25);
+        assert!(p.check(&wd, 30));
+        p.heartbeat(0, 35);
+        assert!(!p.check(&wd, 40));
+    }
+}
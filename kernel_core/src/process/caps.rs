@@ -0,0 +1,551 @@
+//! Per-process capability tables.
+//!
+//! User space refers to kernel objects only through handles in its process' [`CapabilityTable`].
+//! Each handle grants a set of [`Rights`] to the object, which are checked every time the handle
+//! is resolved.
+use alloc::sync::Arc;
+use snafu::{ensure, OptionExt as _, Snafu};
+
+use crate::{
+    collections::HandleMap,
+    ipc::{Notification, Reply, WaitSet},
+    memory::{PageAllocator, PhysicalAddress},
+};
+
+use super::{names::NameRegistry, thread::Thread, Process};
+
+/// A handle in a process' capability table.
+pub type CapabilityHandle = u32;
+
+/// The maximum number of handles in a capability table. Handle values can be larger, since their
+/// upper bits hold a generation that changes when a handle is reused.
+pub const MAX_CAPABILITY_HANDLE: CapabilityHandle = 0xffff;
+
+/// A set of operations that a capability allows on its object.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rights(u32);
+
+impl Rights {
+    /// No rights.
+    pub const NONE: Self = Self(0);
+    /// Read the state of the object.
+    pub const READ: Self = Self(1 << 0);
+    /// Modify the state of the object.
+    pub const WRITE: Self = Self(1 << 1);
+    /// Map the object into an address space.
+    pub const MAP: Self = Self(1 << 2);
+    /// Control the lifecycle of the object, for example killing a process or thread.
+    pub const MANAGE: Self = Self(1 << 3);
+    /// Create a new handle to the object with the same or fewer rights.
+    pub const DUPLICATE: Self = Self(1 << 4);
+    /// Move the handle to another process' capability table.
+    pub const TRANSFER: Self = Self(1 << 5);
+    /// Every right.
+    pub const ALL: Self = Self(0b11_1111);
+
+    /// Returns true if every right in `other` is also in `self`.
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Rights {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// A region of physical memory that can be shared between processes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// The start of the region.
+    pub physical_start: PhysicalAddress,
+    /// The number of pages in the region.
+    pub num_pages: usize,
+}
+
+// The region is only a description of physical memory, and is never dereferenced through.
+unsafe impl Send for MemoryRegion {}
+unsafe impl Sync for MemoryRegion {}
+
+/// A kernel object that can be referred to by a capability.
+pub enum KernelObject<'pa, PA: PageAllocator> {
+    /// A process.
+    Process(Arc<Process<'pa, PA>>),
+    /// A thread.
+    Thread(Arc<Thread>),
+    /// A region of physical memory.
+    MemoryRegion(Arc<MemoryRegion>),
+    /// A set of event flags.
+    Notification(Arc<Notification>),
+    /// A set of names bound to capabilities.
+    NameRegistry(Arc<NameRegistry<'pa, PA>>),
+    /// The single-use capability to reply to a call.
+    Reply(Arc<Reply>),
+    /// A set of objects to wait on together.
+    WaitSet(Arc<WaitSet<'pa>>),
+}
+
+impl<PA: PageAllocator> Clone for KernelObject<'_, PA> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Process(p) => Self::Process(p.clone()),
+            Self::Thread(t) => Self::Thread(t.clone()),
+            Self::MemoryRegion(m) => Self::MemoryRegion(m.clone()),
+            Self::Notification(n) => Self::Notification(n.clone()),
+            Self::NameRegistry(r) => Self::NameRegistry(r.clone()),
+            Self::Reply(r) => Self::Reply(r.clone()),
+            Self::WaitSet(w) => Self::WaitSet(w.clone()),
+        }
+    }
+}
+
+impl<PA: PageAllocator> KernelObject<'_, PA> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Process(_) => "process",
+            Self::Thread(_) => "thread",
+            Self::MemoryRegion(_) => "memory region",
+            Self::Notification(_) => "notification",
+            Self::NameRegistry(_) => "name registry",
+            Self::Reply(_) => "reply",
+            Self::WaitSet(_) => "wait set",
+        }
+    }
+}
+
+/// A reference to a kernel object, together with the rights it grants.
+pub struct Capability<'pa, PA: PageAllocator> {
+    /// The object this capability refers to.
+    pub object: KernelObject<'pa, PA>,
+    /// The operations allowed on the object through this capability.
+    pub rights: Rights,
+}
+
+/// Errors that can occur resolving capabilities.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The handle does not refer to a capability.
+    #[snafu(display("invalid handle {handle}"))]
+    InvalidHandle {
+        /// The handle that was looked up.
+        handle: CapabilityHandle,
+    },
+    /// The capability does not grant the required rights.
+    #[snafu(display("handle {handle} lacks rights {required:?}"))]
+    InsufficientRights {
+        /// The handle that was looked up.
+        handle: CapabilityHandle,
+        /// The rights that were required.
+        required: Rights,
+    },
+    /// The capability refers to a different kind of object than was expected.
+    #[snafu(display("handle {handle} refers to a {actual}, expected a {expected}"))]
+    WrongType {
+        /// The handle that was looked up.
+        handle: CapabilityHandle,
+        /// The kind of object that was expected.
+        expected: &'static str,
+        /// The kind of object the handle refers to.
+        actual: &'static str,
+    },
+    /// There are no handles left in the table.
+    OutOfHandles,
+}
+
+/// The table of capabilities held by a process.
+pub struct CapabilityTable<'pa, PA: PageAllocator> {
+    capabilities: HandleMap<Capability<'pa, PA>>,
+}
+
+impl<'pa, PA: PageAllocator> CapabilityTable<'pa, PA> {
+    /// Create a new, empty capability table.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            capabilities: HandleMap::new(MAX_CAPABILITY_HANDLE),
+        }
+    }
+
+    /// Insert a new capability to `object` with `rights`, returning its handle.
+    ///
+    /// # Errors
+    /// - [`Error::OutOfHandles`] if the table is full.
+    pub fn insert(
+        &self,
+        object: KernelObject<'pa, PA>,
+        rights: Rights,
+    ) -> Result<CapabilityHandle, Error> {
+        self.capabilities
+            .insert(Arc::new(Capability { object, rights }))
+            .ok()
+            .context(OutOfHandlesSnafu)
+    }
+
+    /// Resolve `handle` to its capability, checking that it grants every right in `required`.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidHandle`] if the handle is not in the table.
+    /// - [`Error::InsufficientRights`] if the capability lacks some of the `required` rights.
+    pub fn get(
+        &self,
+        handle: CapabilityHandle,
+        required: Rights,
+    ) -> Result<Arc<Capability<'pa, PA>>, Error> {
+        let cap = self
+            .capabilities
+            .get(handle)
+            .context(InvalidHandleSnafu { handle })?;
+        ensure!(
+            cap.rights.contains(required),
+            InsufficientRightsSnafu { handle, required }
+        );
+        Ok(cap)
+    }
+
+    /// Resolve `handle` to a process, checking that it grants every right in `required`.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::WrongType`] if the handle does not refer to a process.
+    pub fn process(
+        &self,
+        handle: CapabilityHandle,
+        required: Rights,
+    ) -> Result<Arc<Process<'pa, PA>>, Error> {
+        match &self.get(handle, required)?.object {
+            KernelObject::Process(p) => Ok(p.clone()),
+            other => WrongTypeSnafu {
+                handle,
+                expected: "process",
+                actual: other.kind(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Resolve `handle` to a thread, checking that it grants every right in `required`.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::WrongType`] if the handle does not refer to a thread.
+    pub fn thread(&self, handle: CapabilityHandle, required: Rights) -> Result<Arc<Thread>, Error> {
+        match &self.get(handle, required)?.object {
+            KernelObject::Thread(t) => Ok(t.clone()),
+            other => WrongTypeSnafu {
+                handle,
+                expected: "thread",
+                actual: other.kind(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Resolve `handle` to a memory region, checking that it grants every right in `required`.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::WrongType`] if the handle does not refer to a memory region.
+    pub fn memory_region(
+        &self,
+        handle: CapabilityHandle,
+        required: Rights,
+    ) -> Result<Arc<MemoryRegion>, Error> {
+        match &self.get(handle, required)?.object {
+            KernelObject::MemoryRegion(m) => Ok(m.clone()),
+            other => WrongTypeSnafu {
+                handle,
+                expected: "memory region",
+                actual: other.kind(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Resolve `handle` to a notification, checking that it grants every right in `required`.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::WrongType`] if the handle does not refer to a notification.
+    pub fn notification(
+        &self,
+        handle: CapabilityHandle,
+        required: Rights,
+    ) -> Result<Arc<Notification>, Error> {
+        match &self.get(handle, required)?.object {
+            KernelObject::Notification(n) => Ok(n.clone()),
+            other => WrongTypeSnafu {
+                handle,
+                expected: "notification",
+                actual: other.kind(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Resolve `handle` to a wait set, checking that it grants every right in `required`.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::WrongType`] if the handle does not refer to a wait set.
+    pub fn wait_set(
+        &self,
+        handle: CapabilityHandle,
+        required: Rights,
+    ) -> Result<Arc<WaitSet<'pa>>, Error> {
+        match &self.get(handle, required)?.object {
+            KernelObject::WaitSet(w) => Ok(w.clone()),
+            other => WrongTypeSnafu {
+                handle,
+                expected: "wait set",
+                actual: other.kind(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Resolve `handle` to a name registry, checking that it grants every right in `required`.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::WrongType`] if the handle does not refer to a name registry.
+    pub fn name_registry(
+        &self,
+        handle: CapabilityHandle,
+        required: Rights,
+    ) -> Result<Arc<NameRegistry<'pa, PA>>, Error> {
+        match &self.get(handle, required)?.object {
+            KernelObject::NameRegistry(r) => Ok(r.clone()),
+            other => WrongTypeSnafu {
+                handle,
+                expected: "name registry",
+                actual: other.kind(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Take the reply capability `handle` out of the table, checking that it grants every right in
+    /// `required`, so that it can only be used once.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::WrongType`] if the handle does not refer to a reply capability. The handle stays
+    ///   in the table.
+    pub fn take_reply(
+        &self,
+        handle: CapabilityHandle,
+        required: Rights,
+    ) -> Result<Arc<Reply>, Error> {
+        let reply = match &self.get(handle, required)?.object {
+            KernelObject::Reply(r) => r.clone(),
+            other => {
+                return WrongTypeSnafu {
+                    handle,
+                    expected: "reply",
+                    actual: other.kind(),
+                }
+                .fail()
+            }
+        };
+        self.capabilities.remove(handle);
+        Ok(reply)
+    }
+
+    /// Create a new handle to the same object as `handle` with `rights`.
+    ///
+    /// The capability must have the [`Rights::DUPLICATE`] right, and the new rights must be a subset
+    /// of the existing rights.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::OutOfHandles`] if the table is full.
+    pub fn duplicate(
+        &self,
+        handle: CapabilityHandle,
+        rights: Rights,
+    ) -> Result<CapabilityHandle, Error> {
+        let cap = self.get(handle, Rights::DUPLICATE | rights)?;
+        self.insert(cap.object.clone(), rights)
+    }
+
+    /// Move the capability `handle` into the table `to`, returning its new handle there.
+    ///
+    /// The capability must have the [`Rights::TRANSFER`] right.
+    /// If `to` is full, the capability stays in this table.
+    ///
+    /// # Errors
+    /// - The same as [`Self::get`].
+    /// - [`Error::OutOfHandles`] if `to` is full.
+    pub fn transfer(
+        &self,
+        handle: CapabilityHandle,
+        to: &CapabilityTable<'pa, PA>,
+    ) -> Result<CapabilityHandle, Error> {
+        let cap = self.get(handle, Rights::TRANSFER)?;
+        let new_handle = to
+            .capabilities
+            .insert(cap)
+            .ok()
+            .context(OutOfHandlesSnafu)?;
+        self.capabilities.remove(handle);
+        Ok(new_handle)
+    }
+
+    /// Remove the capability `handle` from the table.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidHandle`] if the handle is not in the table.
+    pub fn remove(&self, handle: CapabilityHandle) -> Result<(), Error> {
+        self.capabilities
+            .remove(handle)
+            .map(|_| ())
+            .context(InvalidHandleSnafu { handle })
+    }
+
+    /// Remove every capability from the table, releasing the references to their objects.
+    pub fn clear(&self) {
+        for (handle, _) in &self.capabilities {
+            self.capabilities.remove(handle);
+        }
+    }
+}
+
+impl<PA: PageAllocator> Default for CapabilityTable<'_, PA> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ipc::{Call, MessageBlock, MessageQueue, ReceiveFlags},
+        memory::{tests::MockPageAllocator, PageSize},
+        process::thread::{MockScheduler, ProcessorState, State, MAX_THREAD_ID},
+    };
+
+    fn region() -> KernelObject<'static, MockPageAllocator> {
+        KernelObject::MemoryRegion(Arc::new(MemoryRegion {
+            physical_start: PhysicalAddress::from(0x4000_0000),
+            num_pages: 2,
+        }))
+    }
+
+    #[test]
+    fn resolve_with_rights() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let table = CapabilityTable::<MockPageAllocator>::new();
+        let t = table
+            .insert(KernelObject::Thread(thread.clone()), Rights::READ)
+            .unwrap();
+        let m = table.insert(region(), Rights::READ | Rights::MAP).unwrap();
+
+        assert_eq!(table.thread(t, Rights::READ).unwrap().id, thread.id);
+        assert_eq!(table.memory_region(m, Rights::MAP).unwrap().num_pages, 2);
+        assert!(matches!(
+            table.thread(t, Rights::MANAGE),
+            Err(Error::InsufficientRights { .. })
+        ));
+        assert!(matches!(
+            table.thread(m, Rights::READ),
+            Err(Error::WrongType {
+                expected: "thread",
+                actual: "memory region",
+                ..
+            })
+        ));
+        assert!(matches!(
+            table.process(t, Rights::NONE),
+            Err(Error::WrongType { .. })
+        ));
+
+        table.remove(t).unwrap();
+        assert!(matches!(
+            table.get(t, Rights::NONE),
+            Err(Error::InvalidHandle { .. })
+        ));
+        assert!(matches!(table.remove(t), Err(Error::InvalidHandle { .. })));
+    }
+
+    #[test]
+    fn duplicate_and_transfer() {
+        let a = CapabilityTable::<MockPageAllocator>::new();
+        let b = CapabilityTable::<MockPageAllocator>::new();
+        let h = a
+            .insert(
+                region(),
+                Rights::READ | Rights::MAP | Rights::DUPLICATE | Rights::TRANSFER,
+            )
+            .unwrap();
+
+        let ro = a.duplicate(h, Rights::READ).unwrap();
+        assert_eq!(a.get(ro, Rights::NONE).unwrap().rights, Rights::READ);
+        // rights can't be added by duplicating
+        assert!(matches!(
+            a.duplicate(h, Rights::WRITE),
+            Err(Error::InsufficientRights { .. })
+        ));
+        assert!(matches!(
+            a.duplicate(ro, Rights::READ),
+            Err(Error::InsufficientRights { .. })
+        ));
+        assert!(matches!(
+            a.transfer(ro, &b),
+            Err(Error::InsufficientRights { .. })
+        ));
+
+        let moved = a.transfer(h, &b).unwrap();
+        assert!(a.get(h, Rights::NONE).is_err());
+        assert!(b.memory_region(moved, Rights::MAP).is_ok());
+
+        b.clear();
+        assert!(b.get(moved, Rights::NONE).is_err());
+        assert!(a.get(ro, Rights::NONE).is_ok());
+
+        // slots that were reused have a later generation, which must be cleared too
+        let reused = a.insert(region(), Rights::READ).unwrap();
+        assert_ne!(reused, h);
+        a.clear();
+        assert!(a.get(reused, Rights::NONE).is_err());
+        assert!(a.get(ro, Rights::NONE).is_err());
+    }
+
+    #[test]
+    fn reply_is_single_use() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 8);
+        {
+            let queue = MessageQueue::new(&pa, 1).unwrap();
+            let call = Call::send(&queue, &[MessageBlock::default()]).unwrap();
+            let message = queue
+                .receive(&MockScheduler::new(), ReceiveFlags::default(), None)
+                .unwrap();
+            let table = CapabilityTable::<MockPageAllocator>::new();
+            let h = table
+                .insert(KernelObject::Reply(message.reply.unwrap()), Rights::WRITE)
+                .unwrap();
+            let m = table.insert(region(), Rights::WRITE).unwrap();
+            assert!(matches!(
+                table.take_reply(m, Rights::WRITE),
+                Err(Error::WrongType { .. })
+            ));
+            assert!(table.get(m, Rights::NONE).is_ok());
+
+            let reply = table.take_reply(h, Rights::WRITE).unwrap();
+            assert!(matches!(
+                table.take_reply(h, Rights::WRITE),
+                Err(Error::InvalidHandle { .. })
+            ));
+            reply.reply(&[MessageBlock::default()]).unwrap();
+            assert_eq!(call.take_reply().unwrap().len(), 1);
+        }
+        pa.end_check();
+    }
+}
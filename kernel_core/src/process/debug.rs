@@ -0,0 +1,757 @@
+//! Debugging of processes by other processes, in the manner of `ptrace`.
+//!
+//! A debugger process attaches to a target process that it supervises (see
+//! [`super::policy::check_debug`]), giving a [`Notification`] that is signaled
+//! whenever something happens to the target that the debugger should know about. While attached,
+//! the debugger can suspend and resume the target's threads, access the registers of stopped
+//! threads, read and write the RAM mapped into the target, and set software breakpoints. A thread
+//! stops once it has been suspended and has been switched out of the core it was running on.
+//!
+//! A breakpoint replaces the instruction at its address with a `brk` instruction. When a thread
+//! executes it, or faults, the kernel calls [`Process::report_debug_event`], which suspends the
+//! thread and queues a [`DebugEvent`] for the debugger to take with [`Process::take_debug_event`].
+//! The program counter of a thread stopped at a breakpoint is the address of the breakpoint, so
+//! the debugger must remove the breakpoint (or move the program counter past it) before resuming
+//! the thread.
+//!
+//! A suspended thread can also be stepped with [`Process::step_thread`], which resumes it for a
+//! single instruction. The kernel enables software step exceptions while the thread runs, and
+//! reports a [`DebugEventKind::Step`] once the instruction completes. If the thread is interrupted
+//! before the instruction completes, the step continues when the thread next runs.
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use log::trace;
+use snafu::{ensure, OptionExt as _, ResultExt as _};
+
+use super::{
+    policy, AlreadyDebuggedSnafu, AlreadyExitedSnafu, BreakpointExistsSnafu, Error, Id,
+    MisalignedBreakpointSnafu, NoBreakpointSnafu, NotDebuggerSnafu, NotSuspendedSnafu, PolicySnafu,
+    Process, StillRunningSnafu, ThreadId, UnknownThreadSnafu,
+};
+use crate::{
+    collections::HandleMap,
+    ipc::Notification,
+    memory::{MemoryManagmentUnit, PageAllocator, VirtualAddress},
+    process::thread::Thread,
+};
+
+/// The instruction written at the address of a breakpoint, `brk #0`.
+pub const BREAKPOINT_INSTRUCTION: u32 = 0xd420_0000;
+
+/// Something that happened to a thread of a process being debugged. The thread is suspended until
+/// the debugger resumes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugEvent {
+    /// The thread the event happened to.
+    pub thread: ThreadId,
+    /// What happened.
+    pub kind: DebugEventKind,
+}
+
+/// What happened to a thread of a process being debugged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugEventKind {
+    /// The thread executed a `brk` instruction.
+    Breakpoint {
+        /// The address of the instruction.
+        address: VirtualAddress,
+        /// The immediate value of the instruction.
+        immediate: u16,
+    },
+    /// The thread finished executing a single instruction after [`Process::step_thread`].
+    Step {
+        /// The address of the next instruction.
+        address: VirtualAddress,
+    },
+    /// The thread caused an exception that it can't recover from on its own.
+    Fault {
+        /// The address of the instruction that faulted.
+        address: VirtualAddress,
+        /// The address that was being accessed, if the fault was a memory access.
+        fault_address: VirtualAddress,
+        /// The value of the exception syndrome register.
+        syndrome: u64,
+    },
+}
+
+/// The user visible registers of a thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegisterSet {
+    /// The values of the `xN` registers in order.
+    pub x: [usize; 31],
+    /// The stack pointer.
+    pub stack_pointer: usize,
+    /// The program counter.
+    pub program_counter: usize,
+    /// The condition flags, in the same bit positions as the `NZCV` register.
+    pub condition_flags: u64,
+    /// The thread pointer (`TPIDR_EL0`).
+    pub thread_pointer: usize,
+}
+
+/// The state of a debugger attached to a process.
+pub(super) struct DebugState {
+    /// The process doing the debugging.
+    debugger: Id,
+    /// Signaled with `flags` when an event is queued.
+    notification: Arc<Notification>,
+    flags: u64,
+    /// Events that the debugger has not taken yet. Each one suspends a thread, so there can't be
+    /// more of these than there are threads.
+    events: VecDeque<DebugEvent>,
+    /// The breakpoints that are set, with the instruction each one replaced.
+    breakpoints: Vec<(VirtualAddress, u32)>,
+}
+
+impl<'pa, PA: PageAllocator> Process<'pa, PA> {
+    /// Attach the process `debugger` as the debugger of this process. Events are reported by
+    /// raising `flags` on `notification`.
+    ///
+    /// The debugger must supervise this process; its supervisors are looked up in `processes`.
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::Policy`] if `debugger` does not supervise this process.
+    /// - [`Error::AlreadyDebugged`] if another debugger is already attached.
+    pub fn attach_debugger(
+        &self,
+        processes: &HandleMap<Process<'pa, PA>>,
+        debugger: Id,
+        notification: Arc<Notification>,
+        flags: u64,
+    ) -> Result<(), Error> {
+        ensure!(
+            self.exit_code().is_none(),
+            AlreadyExitedSnafu { id: self.id }
+        );
+        policy::check_debug(processes, debugger, self).context(PolicySnafu)?;
+        let mut debug = self.debug.lock();
+        if let Some(state) = debug.as_ref() {
+            return AlreadyDebuggedSnafu {
+                id: self.id,
+                debugger: state.debugger,
+            }
+            .fail();
+        }
+        trace!("process {debugger} attached to process {}", self.id);
+        *debug = Some(DebugState {
+            debugger,
+            notification,
+            flags,
+            events: VecDeque::new(),
+            breakpoints: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// The process debugging this process, if any.
+    pub fn debugger(&self) -> Option<Id> {
+        self.debug.lock().as_ref().map(|state| state.debugger)
+    }
+
+    /// Detach `debugger` from this process, removing every breakpoint and resuming every thread.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - Any error from restoring the instructions replaced by breakpoints. The debugger is still
+    ///   detached.
+    pub fn detach_debugger(
+        &self,
+        debugger: Id,
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Result<(), Error> {
+        let state = {
+            let mut debug = self.debug.lock();
+            ensure!(
+                debug.as_ref().is_some_and(|s| s.debugger == debugger),
+                NotDebuggerSnafu {
+                    id: self.id,
+                    debugger
+                }
+            );
+            debug.take()
+        };
+        let mut result = Ok(());
+        for (address, instruction) in state.into_iter().flat_map(|s| s.breakpoints) {
+            if let Err(e) = self.write_code(address, &instruction.to_le_bytes(), mmu) {
+                result = Err(e);
+            }
+        }
+        for thread in self.threads.lock().iter() {
+            end_step(thread);
+            thread.resume();
+        }
+        trace!("process {debugger} detached from process {}", self.id);
+        result
+    }
+
+    /// Call `f` with the state of the debugger, checking that it is `debugger`.
+    fn with_debugger<R>(
+        &self,
+        debugger: Id,
+        f: impl FnOnce(&mut DebugState) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let mut debug = self.debug.lock();
+        let state =
+            debug
+                .as_mut()
+                .filter(|s| s.debugger == debugger)
+                .context(NotDebuggerSnafu {
+                    id: self.id,
+                    debugger,
+                })?;
+        f(state)
+    }
+
+    /// Find the thread `thread` of this process.
+    fn thread(&self, thread: ThreadId) -> Result<Arc<Thread>, Error> {
+        self.threads
+            .lock()
+            .iter()
+            .find(|t| t.id == thread)
+            .cloned()
+            .context(UnknownThreadSnafu {
+                id: self.id,
+                thread,
+            })
+    }
+
+    /// Find the thread `thread` of this process, checking that it has stopped: it has been
+    /// suspended, and its processor state has been saved because it is no longer running on a
+    /// core.
+    fn stopped_thread(&self, debugger: Id, thread: ThreadId) -> Result<Arc<Thread>, Error> {
+        self.with_debugger(debugger, |_| {
+            let thread = self.thread(thread)?;
+            ensure!(
+                thread.is_suspended(),
+                NotSuspendedSnafu { thread: thread.id }
+            );
+            ensure!(
+                !thread.is_on_core(),
+                StillRunningSnafu { thread: thread.id }
+            );
+            Ok(thread)
+        })
+    }
+
+    /// Suspend the thread `thread` of this process on behalf of `debugger` (see
+    /// [`Thread::suspend`]).
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::UnknownThread`] if the thread is not in this process.
+    pub fn suspend_thread(&self, debugger: Id, thread: ThreadId) -> Result<(), Error> {
+        self.with_debugger(debugger, |_| {
+            self.thread(thread)?.suspend();
+            Ok(())
+        })
+    }
+
+    /// Resume the thread `thread` of this process on behalf of `debugger` after it was suspended,
+    /// either by the debugger or because of a [`DebugEvent`].
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::UnknownThread`] if the thread is not in this process.
+    pub fn resume_thread(&self, debugger: Id, thread: ThreadId) -> Result<(), Error> {
+        self.with_debugger(debugger, |_| {
+            self.thread(thread)?.resume();
+            Ok(())
+        })
+    }
+
+    /// Resume the stopped thread `thread` of this process for a single instruction, after which
+    /// it is suspended again and a [`DebugEventKind::Step`] is reported.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::UnknownThread`] if the thread is not in this process.
+    /// - [`Error::NotSuspended`] if the thread is not suspended.
+    /// - [`Error::StillRunning`] if the thread has not stopped running yet.
+    pub fn step_thread(&self, debugger: Id, thread: ThreadId) -> Result<(), Error> {
+        let thread = self.stopped_thread(debugger, thread)?;
+        {
+            let mut state = thread.processor_state.lock();
+            state.single_step = true;
+            // the step is active but has not completed, so one instruction runs before the step
+            // exception is taken
+            state.spsr.set_ss(true);
+        }
+        thread.resume();
+        Ok(())
+    }
+
+    /// Read the registers of the stopped thread `thread` of this process.
+    ///
+    /// The registers are only saved when the thread is switched out, so the thread must not still
+    /// be running on a core.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::UnknownThread`] if the thread is not in this process.
+    /// - [`Error::NotSuspended`] if the thread is not suspended.
+    /// - [`Error::StillRunning`] if the thread has not stopped running yet.
+    pub fn read_registers(&self, debugger: Id, thread: ThreadId) -> Result<RegisterSet, Error> {
+        let thread = self.stopped_thread(debugger, thread)?;
+        let state = thread.processor_state.lock();
+        Ok(RegisterSet {
+            x: state.registers.x,
+            stack_pointer: state.stack_pointer.into(),
+            program_counter: state.program_counter.into(),
+            condition_flags: state.spsr.condition_flags(),
+            thread_pointer: state.thread_pointer.into(),
+        })
+    }
+
+    /// Write the registers of the stopped thread `thread` of this process.
+    ///
+    /// Only the condition flags of the program status can be changed, so that the thread can't be
+    /// made to run at a higher exception level.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::UnknownThread`] if the thread is not in this process.
+    /// - [`Error::NotSuspended`] if the thread is not suspended.
+    /// - [`Error::StillRunning`] if the thread has not stopped running yet.
+    pub fn write_registers(
+        &self,
+        debugger: Id,
+        thread: ThreadId,
+        registers: &RegisterSet,
+    ) -> Result<(), Error> {
+        let thread = self.stopped_thread(debugger, thread)?;
+        let mut state = thread.processor_state.lock();
+        state.registers.x = registers.x;
+        state.stack_pointer = registers.stack_pointer.into();
+        state.program_counter = registers.program_counter.into();
+        state.thread_pointer = registers.thread_pointer.into();
+        state.spsr.set_condition_flags(registers.condition_flags);
+        Ok(())
+    }
+
+    /// Write `data` at `address` in this process' address space, which may hold code.
+    fn write_code(
+        &self,
+        address: VirtualAddress,
+        data: &[u8],
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Result<(), Error> {
+        for (physical, length) in self.write_user_memory(address, data)? {
+            mmu.synchronize_instruction_cache(physical, length);
+        }
+        Ok(())
+    }
+
+    /// Read `buffer.len()` bytes at `address` in this process' address space into `buffer`.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::NotMapped`] if any of the bytes are not mapped to RAM. Nothing is read.
+    pub fn read_memory(
+        &self,
+        debugger: Id,
+        address: VirtualAddress,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.with_debugger(debugger, |_| self.read_user_memory(address, buffer))
+    }
+
+    /// Write `data` at `address` in this process' address space, even if it is mapped read only.
+    ///
+    /// Writing over a breakpoint changes the instruction that is restored when it is removed.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::NotMapped`] if any of the bytes are not mapped to RAM. Nothing is written.
+    pub fn write_memory(
+        &self,
+        debugger: Id,
+        address: VirtualAddress,
+        data: &[u8],
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Result<(), Error> {
+        self.with_debugger(debugger, |state| {
+            self.write_code(address, data, mmu)?;
+            // keep what was written as the instruction behind any breakpoint it covers
+            let written = usize::from(address)..usize::from(address) + data.len();
+            for (bp, instruction) in &mut state.breakpoints {
+                let bp_start = usize::from(*bp);
+                if written.start <= bp_start && bp_start + 4 <= written.end {
+                    let offset = bp_start - written.start;
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(&data[offset..offset + 4]);
+                    *instruction = u32::from_le_bytes(bytes);
+                    self.write_code(*bp, &BREAKPOINT_INSTRUCTION.to_le_bytes(), mmu)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Set a breakpoint at the instruction at `address` in this process.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::MisalignedBreakpoint`] if `address` is not aligned to an instruction.
+    /// - [`Error::BreakpointExists`] if there is already a breakpoint at `address`.
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::NotMapped`] if `address` is not mapped to RAM.
+    pub fn set_breakpoint(
+        &self,
+        debugger: Id,
+        address: VirtualAddress,
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Result<(), Error> {
+        self.with_debugger(debugger, |state| {
+            ensure!(
+                usize::from(address) % 4 == 0,
+                MisalignedBreakpointSnafu { address }
+            );
+            ensure!(
+                state.breakpoints.iter().all(|(a, _)| *a != address),
+                BreakpointExistsSnafu { address }
+            );
+            let mut instruction = [0; 4];
+            self.read_user_memory(address, &mut instruction)?;
+            self.write_code(address, &BREAKPOINT_INSTRUCTION.to_le_bytes(), mmu)?;
+            state
+                .breakpoints
+                .push((address, u32::from_le_bytes(instruction)));
+            trace!("set breakpoint at {address:?} in process {}", self.id);
+            Ok(())
+        })
+    }
+
+    /// Remove the breakpoint at `address` in this process, restoring the original instruction.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    /// - [`Error::NoBreakpoint`] if there is no breakpoint at `address`.
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::NotMapped`] if `address` is no longer mapped to RAM. The breakpoint is still removed.
+    pub fn remove_breakpoint(
+        &self,
+        debugger: Id,
+        address: VirtualAddress,
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Result<(), Error> {
+        self.with_debugger(debugger, |state| {
+            let index = state
+                .breakpoints
+                .iter()
+                .position(|(a, _)| *a == address)
+                .context(NoBreakpointSnafu { address })?;
+            let (_, instruction) = state.breakpoints.swap_remove(index);
+            trace!("removed breakpoint at {address:?} in process {}", self.id);
+            self.write_code(address, &instruction.to_le_bytes(), mmu)
+        })
+    }
+
+    /// Take the oldest event that has happened in this process since it was last taken.
+    ///
+    /// # Errors
+    /// - [`Error::NotDebugger`] if `debugger` is not attached to this process.
+    pub fn take_debug_event(&self, debugger: Id) -> Result<Option<DebugEvent>, Error> {
+        self.with_debugger(debugger, |state| Ok(state.events.pop_front()))
+    }
+
+    /// Report that `kind` happened to `thread`, which must be a thread of this process.
+    ///
+    /// If a debugger is attached, the thread is suspended until the debugger resumes it, the
+    /// debugger is notified, and this returns true. Otherwise this returns false, and the event
+    /// must be handled some other way, for instance by killing the process.
+    /// Any step the thread was taking ends, even if the event is not the end of the step.
+    pub fn report_debug_event(&self, thread: &Thread, kind: DebugEventKind) -> bool {
+        end_step(thread);
+        let mut debug = self.debug.lock();
+        let Some(state) = debug.as_mut() else {
+            return false;
+        };
+        trace!(
+            "thread {} of process {} stopped for debugger: {kind:?}",
+            thread.id,
+            self.id
+        );
+        thread.suspend();
+        state.events.push_back(DebugEvent {
+            thread: thread.id,
+            kind,
+        });
+        state.notification.signal(state.flags);
+        true
+    }
+}
+
+/// Stop single stepping `thread`, if it was being stepped.
+fn end_step(thread: &Thread) {
+    let mut state = thread.processor_state.lock();
+    state.single_step = false;
+    state.spsr.set_ss(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        memory::PageFrameDatabase,
+        memory::{page_table::MemoryProperties, tests::MockPageAllocator, PageSize},
+        process::{
+            exit,
+            mmio::MmioRegistry,
+            tests::{descheduler, new_process, RecordingMmu},
+            thread::{ProcessorState, State, MAX_THREAD_ID},
+        },
+    };
+
+    #[test]
+    fn suspend_and_access_registers() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let frames = PageFrameDatabase::new(PageSize::FourKiB, core::iter::empty());
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let root = new_process(&processes, &pa, None);
+        let debugger = new_process(&processes, &pa, Some(root.id));
+        let target = new_process(&processes, &pa, Some(debugger.id));
+        let thread = Thread::new(
+            &threads,
+            State::Running,
+            ProcessorState::new_for_user_thread(0x1000.into(), 0x8000.into(), 0.into()),
+        );
+        target.add_thread(thread.clone());
+
+        assert!(matches!(
+            target.suspend_thread(debugger.id, thread.id),
+            Err(Error::NotDebugger { .. })
+        ));
+        let notification = Arc::new(Notification::new());
+        assert!(matches!(
+            target.attach_debugger(&processes, target.id, notification.clone(), 1),
+            Err(Error::Policy { .. })
+        ));
+        target
+            .attach_debugger(&processes, debugger.id, notification.clone(), 0b10)
+            .unwrap();
+        assert!(matches!(
+            target.attach_debugger(&processes, root.id, notification.clone(), 1),
+            Err(Error::AlreadyDebugged { debugger: d, .. }) if d == debugger.id
+        ));
+        assert!(matches!(
+            target.read_registers(debugger.id, thread.id),
+            Err(Error::NotSuspended { .. })
+        ));
+        assert!(matches!(
+            target.suspend_thread(debugger.id, 999),
+            Err(Error::UnknownThread { thread: 999, .. })
+        ));
+
+        // a suspended thread that is still on a core has not saved its registers yet
+        thread.start_running(0);
+        target.suspend_thread(debugger.id, thread.id).unwrap();
+        assert_eq!(thread.state(), State::Suspended);
+        assert!(matches!(
+            target.read_registers(debugger.id, thread.id),
+            Err(Error::StillRunning { .. })
+        ));
+        thread.stop_running(0);
+        let mut registers = target.read_registers(debugger.id, thread.id).unwrap();
+        assert_eq!(registers.program_counter, 0x1000);
+        assert_eq!(registers.stack_pointer, 0x8000);
+        registers.x[0] = 42;
+        registers.program_counter = 0x1004;
+        registers.condition_flags = 0b0110 << 28;
+        target
+            .write_registers(debugger.id, thread.id, &registers)
+            .unwrap();
+        assert_eq!(
+            target.read_registers(debugger.id, thread.id).unwrap(),
+            registers
+        );
+        {
+            let spsr = &thread.processor_state.lock().spsr;
+            assert!(!spsr.n() && spsr.z() && spsr.c() && !spsr.v());
+            assert_eq!(spsr.el(), 0);
+        }
+
+        // a fault is reported to the debugger
+        target.resume_thread(debugger.id, thread.id).unwrap();
+        assert_eq!(thread.state(), State::Running);
+        let fault = DebugEventKind::Fault {
+            address: 0x1004.into(),
+            fault_address: 0.into(),
+            syndrome: 0,
+        };
+        assert!(target.report_debug_event(&thread, fault));
+        assert!(thread.is_suspended());
+        assert_eq!(notification.pending(), 0b10);
+        assert_eq!(
+            target.take_debug_event(debugger.id).unwrap(),
+            Some(DebugEvent {
+                thread: thread.id,
+                kind: fault
+            })
+        );
+        assert_eq!(target.take_debug_event(debugger.id).unwrap(), None);
+
+        // threads are resumed when the debugger exits
+        exit(
+            &processes,
+            &threads,
+            &sched,
+            &frames,
+            &mmio,
+            &mmu,
+            debugger.id,
+            0,
+        )
+        .unwrap();
+        assert_eq!(target.debugger(), None);
+        assert_eq!(thread.state(), State::Running);
+        assert!(!target.report_debug_event(&thread, fault));
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, target.id, 0,
+        )
+        .unwrap();
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, root.id, 0,
+        )
+        .unwrap();
+        drop((root, debugger, target, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn step_ends_with_event() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let frames = PageFrameDatabase::new(PageSize::FourKiB, core::iter::empty());
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let target = new_process(&processes, &pa, Some(1));
+        let thread = Thread::new(
+            &threads,
+            State::Running,
+            ProcessorState::new_for_user_thread(0x1000.into(), 0x8000.into(), 0.into()),
+        );
+        target.add_thread(thread.clone());
+        let notification = Arc::new(Notification::new());
+        target
+            .attach_debugger(&processes, 1, notification.clone(), 1)
+            .unwrap();
+
+        assert!(matches!(
+            target.step_thread(1, thread.id),
+            Err(Error::NotSuspended { .. })
+        ));
+        target.suspend_thread(1, thread.id).unwrap();
+        target.step_thread(1, thread.id).unwrap();
+        assert_eq!(thread.state(), State::Running);
+        {
+            let state = thread.processor_state.lock();
+            assert!(state.single_step && state.spsr.ss());
+        }
+
+        let step = DebugEventKind::Step {
+            address: 0x1004.into(),
+        };
+        assert!(target.report_debug_event(&thread, step));
+        assert_eq!(thread.state(), State::Suspended);
+        {
+            let state = thread.processor_state.lock();
+            assert!(!state.single_step && !state.spsr.ss());
+        }
+        assert_eq!(
+            target.take_debug_event(1).unwrap().map(|e| e.kind),
+            Some(step)
+        );
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, target.id, 0,
+        )
+        .unwrap();
+        drop((target, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn breakpoints_replace_instructions() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let pages = pa.allocate(2).unwrap();
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [(pages, 2 * usize::from(PageSize::FourKiB))].into_iter(),
+        );
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let target = new_process(&processes, &pa, Some(1));
+        let code = VirtualAddress::from(0x10_0000);
+        target
+            .map(&frames, code, pages, 2, &MemoryProperties::default())
+            .unwrap();
+        target
+            .attach_debugger(&processes, 1, Arc::new(Notification::new()), 1)
+            .unwrap();
+
+        // writes may cross pages
+        let nop = 0xd503_201f_u32.to_le_bytes();
+        let last = code.byte_add(0xffc);
+        let mut data = [0; 8];
+        data[..4].copy_from_slice(&nop);
+        data[4..].copy_from_slice(&nop);
+        target.write_memory(1, last, &data, &mmu).unwrap();
+        assert_eq!(
+            *mmu.synchronized.borrow(),
+            [(pages.byte_add(0xffc), 4), (pages.byte_add(0x1000), 4)]
+        );
+        assert!(matches!(
+            target.write_memory(1, code.byte_add(0x1ffc), &data, &mmu),
+            Err(Error::NotMapped { .. })
+        ));
+
+        target.set_breakpoint(1, last, &mmu).unwrap();
+        assert!(matches!(
+            target.set_breakpoint(1, last, &mmu),
+            Err(Error::BreakpointExists { .. })
+        ));
+        assert!(matches!(
+            target.set_breakpoint(1, code.byte_add(2), &mmu),
+            Err(Error::MisalignedBreakpoint { .. })
+        ));
+        let mut read = [0; 8];
+        target.read_memory(1, last, &mut read).unwrap();
+        assert_eq!(read[..4], BREAKPOINT_INSTRUCTION.to_le_bytes());
+        assert_eq!(read[4..], nop);
+
+        target.remove_breakpoint(1, last, &mmu).unwrap();
+        target.read_memory(1, last, &mut read).unwrap();
+        assert_eq!(read, data);
+        assert!(matches!(
+            target.remove_breakpoint(1, last, &mmu),
+            Err(Error::NoBreakpoint { .. })
+        ));
+
+        // detaching removes any breakpoints that are left
+        target.set_breakpoint(1, last, &mmu).unwrap();
+        target.detach_debugger(1, &mmu).unwrap();
+        let mut read = [0; 4];
+        target.read_user_memory(last, &mut read).unwrap();
+        assert_eq!(read, nop);
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, target.id, 0,
+        )
+        .unwrap();
+        drop((target, processes));
+        pa.end_check();
+    }
+}
@@ -0,0 +1,360 @@
+//! Delivery of faults to a handler in the faulting process, in the manner of POSIX signals.
+//!
+//! A process can register a [`FaultHandler`], giving an entry point and an alternate stack. When
+//! one of its threads causes a fault it may be able to recover from (see [`UserFault`]), the
+//! kernel calls [`Process::deliver_fault`], which writes a [`FaultFrame`] describing the fault and
+//! the thread's registers to the top of the alternate stack, and resumes the thread in the handler
+//! with:
+//! - `x0` holding the kind of fault (the value of [`UserFault`]),
+//! - `x1` and the stack pointer holding the address of the frame.
+//!
+//! The handler can then fix whatever caused the fault, change the registers in the frame (for
+//! instance to skip the faulting instruction), and return to the thread with
+//! [`Process::return_from_fault`].
+//!
+//! A fault in a thread that is already running on the alternate stack is not delivered, since the
+//! handler itself has faulted. Faults that can't be delivered should be reported to the debugger
+//! with [`Process::report_debug_event`], or kill the process if there is no debugger.
+use bytemuck::{Pod, Zeroable};
+use log::trace;
+use snafu::ensure;
+
+use super::{Error, FaultStackTooSmallSnafu, Process};
+use crate::{
+    exceptions::UserFault,
+    memory::{PageAllocator, VirtualAddress},
+    process::thread::Thread,
+};
+
+/// A handler in user space for faults caused by the threads of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultHandler {
+    /// The address the handler starts executing at.
+    pub entry: VirtualAddress,
+    /// The lowest address of the stack the handler runs on.
+    pub stack: VirtualAddress,
+    /// The size of the stack in bytes.
+    pub stack_size: usize,
+}
+
+impl FaultHandler {
+    /// The address just past the end of the stack.
+    fn stack_end(&self) -> usize {
+        usize::from(self.stack).saturating_add(self.stack_size)
+    }
+}
+
+/// A description of a fault written to the stack of a [`FaultHandler`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultFrame {
+    /// The kind of fault, as the value of [`UserFault`].
+    pub kind: u64,
+    /// The address that was being accessed, if the fault was a memory access.
+    pub fault_address: usize,
+    /// The value of the exception syndrome register.
+    pub syndrome: u64,
+    /// The condition flags of the thread, in the same bit positions as the `NZCV` register.
+    pub condition_flags: u64,
+    /// The values of the thread's `xN` registers in order.
+    pub x: [usize; 31],
+    /// The stack pointer of the thread.
+    pub stack_pointer: usize,
+    /// The program counter of the thread, which is the address of the faulting instruction.
+    pub program_counter: usize,
+}
+
+// SAFETY: the frame is made only of integers, all the same size, so it has no padding and any
+// bit pattern is valid.
+unsafe impl Zeroable for FaultFrame {}
+unsafe impl Pod for FaultFrame {}
+
+impl<PA: PageAllocator> Process<'_, PA> {
+    /// Deliver faults caused by the threads of this process to `handler`, or stop delivering
+    /// them if `handler` is `None`.
+    ///
+    /// # Errors
+    /// - [`Error::FaultStackTooSmall`] if the handler's stack can't hold a [`FaultFrame`].
+    pub fn set_fault_handler(&self, handler: Option<FaultHandler>) -> Result<(), Error> {
+        if let Some(handler) = &handler {
+            ensure!(
+                handler.stack_size >= size_of::<FaultFrame>() + 16,
+                FaultStackTooSmallSnafu {
+                    size: handler.stack_size
+                }
+            );
+        }
+        trace!("process {} set fault handler {handler:?}", self.id);
+        *self.fault_handler.lock() = handler;
+        Ok(())
+    }
+
+    /// The handler for faults caused by the threads of this process, if any.
+    pub fn fault_handler(&self) -> Option<FaultHandler> {
+        *self.fault_handler.lock()
+    }
+
+    /// Returns true if the `length` bytes at `address` are mapped writable by user space.
+    fn is_user_writable(&self, address: VirtualAddress, length: usize) -> bool {
+        let address_space = self.address_space.lock();
+        let Some(address_space) = address_space.as_ref() else {
+            return false;
+        };
+        let page_size = usize::from(self.page_allocator.page_size());
+        let start = usize::from(address);
+        let mut page = start - start % page_size;
+        while page < start + length {
+            let mapped = address_space.mappings.iter().any(|m| {
+                let mapping_start = usize::from(m.virtual_start);
+                m.properties.user_space_access
+                    && m.properties.writable
+                    && (mapping_start..mapping_start + m.num_pages * page_size).contains(&page)
+            });
+            if !mapped {
+                return false;
+            }
+            page += page_size;
+        }
+        true
+    }
+
+    /// Deliver `fault` caused by `thread` (a thread of this process) accessing `fault_address`
+    /// to the process' fault handler. The value of the exception syndrome register is `syndrome`.
+    ///
+    /// Returns true if the thread will resume in the handler. Returns false if there is no
+    /// handler, the thread is already running on the handler's stack, or the handler's stack is
+    /// not writable, in which case the fault must be handled some other way.
+    pub fn deliver_fault(
+        &self,
+        thread: &Thread,
+        fault: UserFault,
+        fault_address: VirtualAddress,
+        syndrome: u64,
+    ) -> bool {
+        let Some(handler) = self.fault_handler() else {
+            return false;
+        };
+        let mut state = thread.processor_state.lock();
+        let stack_pointer = usize::from(state.stack_pointer);
+        if (usize::from(handler.stack)..=handler.stack_end()).contains(&stack_pointer) {
+            trace!(
+                "thread {} of process {} faulted in its fault handler: {fault:?}",
+                thread.id,
+                self.id
+            );
+            return false;
+        }
+        let frame = FaultFrame {
+            kind: fault as u64,
+            fault_address: fault_address.into(),
+            syndrome,
+            condition_flags: state.spsr.condition_flags(),
+            x: state.registers.x,
+            stack_pointer,
+            program_counter: state.program_counter.into(),
+        };
+        // the stack pointer must stay 16 byte aligned
+        let frame_address =
+            VirtualAddress::from((handler.stack_end() - size_of::<FaultFrame>()) & !0xf);
+        if !self.is_user_writable(frame_address, size_of::<FaultFrame>())
+            || self
+                .write_user_memory(frame_address, bytemuck::bytes_of(&frame))
+                .is_err()
+        {
+            trace!(
+                "could not write fault frame for thread {} of process {} at {frame_address:?}",
+                thread.id,
+                self.id
+            );
+            return false;
+        }
+        trace!(
+            "delivering {fault:?} at {:?} to thread {} of process {}",
+            state.program_counter,
+            thread.id,
+            self.id
+        );
+        state.registers.x[0] = fault as usize;
+        state.registers.x[1] = frame_address.into();
+        state.stack_pointer = frame_address;
+        state.program_counter = handler.entry;
+        true
+    }
+
+    /// Resume `thread` (a thread of this process) with the registers in the [`FaultFrame`] at
+    /// `frame` in this process' address space, after its fault handler has finished.
+    ///
+    /// Only the condition flags of the program status can be changed, so that the thread can't be
+    /// made to run at a higher exception level.
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::NotMapped`] if the frame is not mapped. The thread's registers are unchanged.
+    pub fn return_from_fault(&self, thread: &Thread, frame: VirtualAddress) -> Result<(), Error> {
+        let mut bytes = [0; size_of::<FaultFrame>()];
+        self.read_user_memory(frame, &mut bytes)?;
+        let frame: FaultFrame = bytemuck::pod_read_unaligned(&bytes);
+        let mut state = thread.processor_state.lock();
+        state.registers.x = frame.x;
+        state.stack_pointer = frame.stack_pointer.into();
+        state.program_counter = frame.program_counter.into();
+        state.spsr.set_condition_flags(frame.condition_flags);
+        trace!(
+            "thread {} of process {} returned from fault handler to {:?}",
+            thread.id,
+            self.id,
+            state.program_counter
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        memory::{
+            page_table::MemoryProperties, tests::MockPageAllocator, PageFrameDatabase, PageSize,
+        },
+        process::{
+            exit,
+            mmio::MmioRegistry,
+            tests::{descheduler, new_process, RecordingMmu},
+            thread::{ProcessorState, State, MAX_THREAD_ID},
+        },
+    };
+
+    #[test]
+    fn faults_are_delivered_to_handler() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let pages = pa.allocate(1).unwrap();
+        let read_only = pa.allocate(1).unwrap();
+        let page_size = usize::from(PageSize::FourKiB);
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [(pages, page_size), (read_only, page_size)].into_iter(),
+        );
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let process = new_process(&processes, &pa, None);
+        let stack = VirtualAddress::from(0x20_0000);
+        process
+            .map(
+                &frames,
+                stack,
+                pages,
+                1,
+                &MemoryProperties {
+                    user_space_access: true,
+                    writable: true,
+                    ..MemoryProperties::default()
+                },
+            )
+            .unwrap();
+        process
+            .map(
+                &frames,
+                stack.byte_add(0x1000),
+                read_only,
+                1,
+                &MemoryProperties {
+                    user_space_access: true,
+                    ..MemoryProperties::default()
+                },
+            )
+            .unwrap();
+        let thread = Thread::new(
+            &threads,
+            State::Running,
+            ProcessorState::new_for_user_thread(0x1000.into(), 0x8000.into(), 0.into()),
+        );
+        process.add_thread(thread.clone());
+        thread.processor_state.lock().registers.x[5] = 55;
+        thread
+            .processor_state
+            .lock()
+            .spsr
+            .set_condition_flags(0b1001 << 28);
+
+        assert!(!process.deliver_fault(&thread, UserFault::MemoryAccess, 0x42.into(), 7));
+        assert!(matches!(
+            process.set_fault_handler(Some(FaultHandler {
+                entry: 0x3000.into(),
+                stack,
+                stack_size: 64,
+            })),
+            Err(Error::FaultStackTooSmall { size: 64 })
+        ));
+        let handler = FaultHandler {
+            entry: 0x3000.into(),
+            stack,
+            stack_size: 0x1000,
+        };
+        process.set_fault_handler(Some(handler)).unwrap();
+        assert_eq!(process.fault_handler(), Some(handler));
+
+        assert!(process.deliver_fault(&thread, UserFault::MemoryAccess, 0x42.into(), 7));
+        let frame_address = {
+            let state = thread.processor_state.lock();
+            assert_eq!(state.program_counter, handler.entry);
+            assert_eq!(state.registers.x[0], UserFault::MemoryAccess as usize);
+            assert_eq!(state.registers.x[1], usize::from(state.stack_pointer));
+            assert_eq!(usize::from(state.stack_pointer) % 16, 0);
+            state.stack_pointer
+        };
+        let mut bytes = [0; size_of::<FaultFrame>()];
+        process.read_user_memory(frame_address, &mut bytes).unwrap();
+        let mut frame: FaultFrame = bytemuck::pod_read_unaligned(&bytes);
+        assert_eq!(frame.kind, UserFault::MemoryAccess as u64);
+        assert_eq!(frame.fault_address, 0x42);
+        assert_eq!(frame.syndrome, 7);
+        assert_eq!(frame.x[5], 55);
+        assert_eq!(frame.program_counter, 0x1000);
+        assert_eq!(frame.stack_pointer, 0x8000);
+        assert_eq!(frame.condition_flags, 0b1001 << 28);
+
+        // a fault in the handler is not delivered again
+        assert!(!process.deliver_fault(&thread, UserFault::IllegalInstruction, 0.into(), 0));
+
+        // the handler skips the faulting instruction and returns
+        frame.program_counter += 4;
+        frame.condition_flags = u64::MAX;
+        process
+            .write_user_memory(frame_address, bytemuck::bytes_of(&frame))
+            .unwrap();
+        process.return_from_fault(&thread, frame_address).unwrap();
+        {
+            let state = thread.processor_state.lock();
+            assert_eq!(state.program_counter, 0x1004.into());
+            assert_eq!(state.stack_pointer, 0x8000.into());
+            assert_eq!(state.registers.x[5], 55);
+            assert_eq!(state.spsr.condition_flags(), 0xf << 28);
+            assert_eq!(state.spsr.el(), 0);
+        }
+        assert!(matches!(
+            process.return_from_fault(&thread, 0x9000.into()),
+            Err(Error::NotMapped { .. })
+        ));
+
+        // the frame can't be written to a read only stack
+        process
+            .set_fault_handler(Some(FaultHandler {
+                stack: stack.byte_add(0x1000),
+                ..handler
+            }))
+            .unwrap();
+        assert!(!process.deliver_fault(&thread, UserFault::Alignment, 0.into(), 0));
+        assert_eq!(thread.processor_state.lock().program_counter, 0x1004.into());
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, process.id, 0,
+        )
+        .unwrap();
+        drop((process, processes));
+        pa.end_check();
+    }
+}
@@ -0,0 +1,1061 @@
+//! Loading executable images into new process address spaces.
+//!
+//! Currently only statically linked little-endian ELF64 executables for `AArch64` are supported.
+use alloc::vec::Vec;
+use byteorder::{ByteOrder as _, LittleEndian};
+use log::trace;
+use snafu::{ensure, OptionExt as _, ResultExt as _, Snafu};
+
+use crate::{
+    fs::FileSystem,
+    memory::{
+        page_table::{self, MapBlockSize, MemoryProperties},
+        PageAllocator, PageTables, PhysicalAddress, VirtualAddress, STACK_GUARD_PAGES,
+    },
+};
+
+use super::{
+    startup::{MAX_STARTUP_INFO_SIZE, STARTUP_INFO_ADDRESS},
+    thread::ProcessorState,
+};
+
+/// The virtual address of the top of the main thread's stack in a newly loaded process.
+pub const STACK_TOP: usize = 0x0000_8000_0000_0000;
+
+/// The virtual address the time page (see [`crate::time::page`]) is mapped at, read only, in every
+/// process.
+pub const TIME_PAGE_ADDRESS: usize = 0x0000_7f00_0000_0000;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_TYPE_EXECUTABLE: u16 = 2;
+const ELF_MACHINE_AARCH64: u16 = 183;
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PROGRAM_TYPE_LOAD: u32 = 1;
+const PROGRAM_TYPE_INTERP: u32 = 3;
+const PROGRAM_TYPE_TLS: u32 = 7;
+
+/// The size of the thread control block that the thread pointer points to, which precedes the TLS
+/// data in each thread's TLS block.
+pub const THREAD_CONTROL_BLOCK_SIZE: usize = 16;
+
+/// Errors that can occur loading an executable image.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The image is not a valid ELF64 file.
+    #[snafu(display("invalid ELF image: {reason}"))]
+    BadFormat {
+        /// Description of what was wrong with the image.
+        reason: &'static str,
+    },
+    /// The image is an ELF file, but not one that can be run on this system.
+    #[snafu(display("unsupported ELF image: {reason}"))]
+    Unsupported {
+        /// Description of why the image is unsupported.
+        reason: &'static str,
+    },
+    /// The startup information is larger than [`MAX_STARTUP_INFO_SIZE`].
+    #[snafu(display("startup information of {size} bytes is too large"))]
+    StartupInfoTooLarge {
+        /// The size of the information in bytes.
+        size: usize,
+    },
+    /// A loadable segment overlaps with another segment, the stack or its guard pages.
+    #[snafu(display("segment at {address:?} overlaps another segment"))]
+    OverlappingSegment {
+        /// Virtual address of the segment.
+        address: VirtualAddress,
+    },
+    /// An error occurred allocating memory for the process.
+    Memory {
+        /// Cause of the error.
+        source: crate::memory::Error,
+    },
+    /// An error occurred mapping memory into the process' page tables.
+    Mapping {
+        /// Cause of the error.
+        source: page_table::Error,
+    },
+    /// An error occurred reading an executable or one of its dependencies from a filesystem.
+    #[snafu(display("read {}", path.escape_ascii()))]
+    FileSystem {
+        /// The path of the file being read.
+        path: Vec<u8>,
+        /// Cause of the error.
+        source: crate::fs::Error,
+    },
+}
+
+/// A loadable segment described by an ELF program header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Virtual address the segment should be loaded at.
+    pub virtual_address: usize,
+    /// Offset of the segment's data in the image.
+    pub file_offset: usize,
+    /// Number of bytes of data in the image.
+    pub file_size: usize,
+    /// Number of bytes the segment occupies in memory. Bytes past `file_size` are zeroed.
+    pub memory_size: usize,
+    /// Segment can be executed.
+    pub executable: bool,
+    /// Segment can be written.
+    pub writable: bool,
+}
+
+/// The initialization image for thread-local storage (TLS), described by the `PT_TLS` program header.
+///
+/// Each thread's TLS block starts with a [`THREAD_CONTROL_BLOCK_SIZE`] byte thread control block
+/// that the thread pointer points to, followed by a copy of this template at
+/// [`Self::data_offset`] from the thread pointer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsTemplate {
+    /// Offset of the initial TLS data in the image.
+    pub file_offset: usize,
+    /// Number of bytes of initial data in the image.
+    pub file_size: usize,
+    /// Number of bytes of TLS data for each thread. Bytes past `file_size` are zeroed.
+    pub memory_size: usize,
+    /// Required alignment of the TLS data.
+    pub alignment: usize,
+}
+
+impl TlsTemplate {
+    /// The offset of the TLS data from the thread pointer.
+    #[must_use]
+    pub fn data_offset(&self) -> usize {
+        THREAD_CONTROL_BLOCK_SIZE.next_multiple_of(self.alignment.max(1))
+    }
+
+    /// The total size of a thread's TLS block, including the thread control block.
+    #[must_use]
+    pub fn block_size(&self) -> usize {
+        self.data_offset() + self.memory_size
+    }
+}
+
+/// A parsed ELF64 executable image.
+#[derive(Debug, Clone)]
+pub struct ElfImage<'b> {
+    bytes: &'b [u8],
+    entry_point: usize,
+    program_headers_offset: usize,
+    program_header_size: usize,
+    program_header_count: usize,
+}
+
+fn to_usize(x: u64) -> Result<usize, Error> {
+    usize::try_from(x).ok().context(BadFormatSnafu {
+        reason: "value out of range",
+    })
+}
+
+impl<'b> ElfImage<'b> {
+    /// Parse the ELF header of an executable image in `bytes`.
+    ///
+    /// # Errors
+    /// - [`Error::BadFormat`] if the image is not a valid ELF64 image.
+    /// - [`Error::Unsupported`] if the image is not a little-endian `AArch64` executable.
+    pub fn parse(bytes: &'b [u8]) -> Result<Self, Error> {
+        ensure!(
+            bytes.len() >= ELF_HEADER_SIZE && bytes.starts_with(ELF_MAGIC),
+            BadFormatSnafu {
+                reason: "missing ELF header"
+            }
+        );
+        ensure!(
+            bytes[4] == ELF_CLASS_64,
+            UnsupportedSnafu {
+                reason: "not a 64-bit image"
+            }
+        );
+        ensure!(
+            bytes[5] == ELF_DATA_LITTLE_ENDIAN,
+            UnsupportedSnafu {
+                reason: "not little endian"
+            }
+        );
+        ensure!(
+            LittleEndian::read_u16(&bytes[16..]) == ELF_TYPE_EXECUTABLE,
+            UnsupportedSnafu {
+                reason: "not an executable"
+            }
+        );
+        ensure!(
+            LittleEndian::read_u16(&bytes[18..]) == ELF_MACHINE_AARCH64,
+            UnsupportedSnafu {
+                reason: "not an AArch64 image"
+            }
+        );
+
+        let image = Self {
+            bytes,
+            entry_point: to_usize(LittleEndian::read_u64(&bytes[24..]))?,
+            program_headers_offset: to_usize(LittleEndian::read_u64(&bytes[32..]))?,
+            program_header_size: LittleEndian::read_u16(&bytes[54..]) as usize,
+            program_header_count: LittleEndian::read_u16(&bytes[56..]) as usize,
+        };
+
+        ensure!(
+            image.program_header_size >= PROGRAM_HEADER_SIZE,
+            BadFormatSnafu {
+                reason: "program header too small"
+            }
+        );
+        ensure!(
+            image
+                .program_header_size
+                .checked_mul(image.program_header_count)
+                .and_then(|len| len.checked_add(image.program_headers_offset))
+                .is_some_and(|end| end <= bytes.len()),
+            BadFormatSnafu {
+                reason: "program headers out of bounds"
+            }
+        );
+
+        Ok(image)
+    }
+
+    /// The virtual address where execution of the image begins.
+    #[must_use]
+    pub fn entry_point(&self) -> VirtualAddress {
+        VirtualAddress::from(self.entry_point)
+    }
+
+    /// Iterate over the loadable segments in the image.
+    ///
+    /// Each segment is checked to make sure that its data is contained in the image.
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment, Error>> + '_ {
+        (0..self.program_header_count).filter_map(move |i| {
+            let header = &self.bytes[self.program_headers_offset + i * self.program_header_size..];
+            if LittleEndian::read_u32(header) != PROGRAM_TYPE_LOAD {
+                return None;
+            }
+            Some(self.parse_segment(header))
+        })
+    }
+
+    /// The template for each thread's thread-local storage, if the image has any.
+    ///
+    /// # Errors
+    /// - [`Error::BadFormat`] if the TLS program header is invalid.
+    pub fn tls_template(&self) -> Result<Option<TlsTemplate>, Error> {
+        let Some(header) = (0..self.program_header_count)
+            .map(|i| &self.bytes[self.program_headers_offset + i * self.program_header_size..])
+            .find(|header| LittleEndian::read_u32(header) == PROGRAM_TYPE_TLS)
+        else {
+            return Ok(None);
+        };
+        let template = TlsTemplate {
+            file_offset: to_usize(LittleEndian::read_u64(&header[8..]))?,
+            file_size: to_usize(LittleEndian::read_u64(&header[32..]))?,
+            memory_size: to_usize(LittleEndian::read_u64(&header[40..]))?,
+            alignment: to_usize(LittleEndian::read_u64(&header[48..]))?,
+        };
+        ensure!(
+            template
+                .file_offset
+                .checked_add(template.file_size)
+                .is_some_and(|end| end <= self.bytes.len()),
+            BadFormatSnafu {
+                reason: "TLS data out of bounds"
+            }
+        );
+        ensure!(
+            template.file_size <= template.memory_size,
+            BadFormatSnafu {
+                reason: "TLS file size larger than memory size"
+            }
+        );
+        ensure!(
+            template.alignment <= 1 || template.alignment.is_power_of_two(),
+            BadFormatSnafu {
+                reason: "TLS alignment not a power of two"
+            }
+        );
+        Ok(Some(template))
+    }
+
+    /// The path of the interpreter (dynamic linker) requested by the image, if it has one.
+    ///
+    /// # Errors
+    /// - [`Error::BadFormat`] if the interpreter program header is invalid.
+    pub fn interpreter(&self) -> Result<Option<&'b [u8]>, Error> {
+        let Some(header) = (0..self.program_header_count)
+            .map(|i| &self.bytes[self.program_headers_offset + i * self.program_header_size..])
+            .find(|header| LittleEndian::read_u32(header) == PROGRAM_TYPE_INTERP)
+        else {
+            return Ok(None);
+        };
+        let offset = to_usize(LittleEndian::read_u64(&header[8..]))?;
+        let size = to_usize(LittleEndian::read_u64(&header[32..]))?;
+        let path = offset
+            .checked_add(size)
+            .and_then(|end| self.bytes.get(offset..end))
+            .context(BadFormatSnafu {
+                reason: "interpreter path out of bounds",
+            })?;
+        // the path is NUL terminated
+        Ok(Some(path.split(|b| *b == 0).next().unwrap_or(path)))
+    }
+
+    fn parse_segment(&self, header: &[u8]) -> Result<Segment, Error> {
+        let flags = LittleEndian::read_u32(&header[4..]);
+        let segment = Segment {
+            file_offset: to_usize(LittleEndian::read_u64(&header[8..]))?,
+            virtual_address: to_usize(LittleEndian::read_u64(&header[16..]))?,
+            file_size: to_usize(LittleEndian::read_u64(&header[32..]))?,
+            memory_size: to_usize(LittleEndian::read_u64(&header[40..]))?,
+            executable: flags & 0x1 != 0,
+            writable: flags & 0x2 != 0,
+        };
+        ensure!(
+            segment
+                .file_offset
+                .checked_add(segment.file_size)
+                .is_some_and(|end| end <= self.bytes.len()),
+            BadFormatSnafu {
+                reason: "segment data out of bounds"
+            }
+        );
+        ensure!(
+            segment.file_size <= segment.memory_size,
+            BadFormatSnafu {
+                reason: "segment file size larger than memory size"
+            }
+        );
+        ensure!(
+            segment
+                .virtual_address
+                .checked_add(segment.memory_size)
+                .is_some_and(|end| end <= STACK_TOP),
+            BadFormatSnafu {
+                reason: "segment outside of user space"
+            }
+        );
+        Ok(segment)
+    }
+}
+
+/// A region of pages allocated for a loaded image and mapped into its address space.
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    /// The virtual address the region is mapped at.
+    pub virtual_start: VirtualAddress,
+    /// The physical address of the first page.
+    pub physical_start: PhysicalAddress,
+    /// The number of pages in the region.
+    pub num_pages: usize,
+    /// The properties the region is mapped with.
+    pub properties: MemoryProperties,
+}
+
+/// The result of loading an image into a new address space.
+///
+/// Owns the pages allocated for the image and stack, which are freed when this is dropped unless
+/// they have been taken with [`LoadedImage::into_parts`].
+pub struct LoadedImage<'pa, PA: PageAllocator> {
+    page_allocator: &'pa PA,
+    /// The page tables for the new address space.
+    pub page_tables: PageTables<'pa, PA>,
+    /// The initial processor state for the main thread.
+    pub initial_state: ProcessorState,
+    allocations: Vec<Allocation>,
+    stack_guard: (VirtualAddress, usize),
+}
+
+impl<'pa, PA: PageAllocator> LoadedImage<'pa, PA> {
+    /// The regions that were allocated for the image and stack.
+    #[must_use]
+    pub fn allocations(&self) -> &[Allocation] {
+        &self.allocations
+    }
+
+    /// The allocator the image's pages were allocated from.
+    #[must_use]
+    pub fn page_allocator(&self) -> &'pa PA {
+        self.page_allocator
+    }
+
+    /// Take the page tables, initial processor state and allocations of the image, so that the
+    /// allocated pages can be owned by something else, like a process.
+    #[must_use]
+    pub fn into_parts(self) -> (PageTables<'pa, PA>, ProcessorState, Vec<Allocation>) {
+        let this = core::mem::ManuallyDrop::new(self);
+        // SAFETY: `this` is never used or dropped again, so each field is moved out exactly once.
+        unsafe {
+            (
+                core::ptr::read(core::ptr::addr_of!(this.page_tables)),
+                core::ptr::read(core::ptr::addr_of!(this.initial_state)),
+                core::ptr::read(core::ptr::addr_of!(this.allocations)),
+            )
+        }
+    }
+
+    /// The region of unmapped guard pages below the main thread's stack, as (start, length in bytes).
+    ///
+    /// A fault in this region means that the stack has overflowed.
+    #[must_use]
+    pub fn stack_guard(&self) -> (VirtualAddress, usize) {
+        self.stack_guard
+    }
+
+    fn allocate_and_map(
+        &mut self,
+        virtual_start: usize,
+        num_pages: usize,
+        properties: &MemoryProperties,
+    ) -> Result<PhysicalAddress, Error> {
+        let pages = self
+            .page_allocator
+            .allocate_zeroed(num_pages)
+            .context(MemorySnafu)?;
+        self.allocations.push(Allocation {
+            virtual_start: VirtualAddress::from(virtual_start),
+            physical_start: pages,
+            num_pages,
+            properties: properties.clone(),
+        });
+        self.page_tables
+            .map(
+                VirtualAddress::from(virtual_start),
+                pages,
+                num_pages,
+                MapBlockSize::Page,
+                properties,
+            )
+            .context(MappingSnafu)?;
+        Ok(pages)
+    }
+
+    /// Map the shared `time_page` read only at [`TIME_PAGE_ADDRESS`].
+    fn map_time_page(&mut self, time_page: PhysicalAddress) -> Result<(), Error> {
+        trace!("mapping time page at {TIME_PAGE_ADDRESS:x}");
+        self.page_tables
+            .map(
+                VirtualAddress::from(TIME_PAGE_ADDRESS),
+                time_page,
+                1,
+                MapBlockSize::Page,
+                &MemoryProperties {
+                    user_space_access: true,
+                    writable: false,
+                    executable: false,
+                    ..MemoryProperties::default()
+                },
+            )
+            .context(MappingSnafu)
+    }
+
+    /// Map the startup information `info` (see [`super::startup`]) read only at
+    /// [`STARTUP_INFO_ADDRESS`], and pass its address and size to the main thread in `x0` and `x1`.
+    ///
+    /// # Errors
+    /// - [`Error::StartupInfoTooLarge`] if the information is larger than
+    ///   [`MAX_STARTUP_INFO_SIZE`].
+    /// - [`Error::Memory`] if memory could not be allocated.
+    /// - [`Error::Mapping`] if the memory could not be mapped, for instance if startup information
+    ///   was already mapped.
+    pub fn map_startup_info(&mut self, info: &[u8]) -> Result<(), Error> {
+        ensure!(
+            info.len() <= MAX_STARTUP_INFO_SIZE,
+            StartupInfoTooLargeSnafu { size: info.len() }
+        );
+        let page_size = usize::from(self.page_allocator.page_size());
+        let num_pages = info.len().div_ceil(page_size).max(1);
+        trace!("mapping {num_pages} pages of startup information at {STARTUP_INFO_ADDRESS:x}");
+        let pages = self.allocate_and_map(
+            STARTUP_INFO_ADDRESS,
+            num_pages,
+            &MemoryProperties {
+                user_space_access: true,
+                writable: false,
+                executable: false,
+                ..MemoryProperties::default()
+            },
+        )?;
+        unsafe {
+            let dst: *mut u8 = pages.cast().into();
+            core::ptr::copy_nonoverlapping(info.as_ptr(), dst, info.len());
+        }
+        self.initial_state.registers.x[0] = STARTUP_INFO_ADDRESS;
+        self.initial_state.registers.x[1] = info.len();
+        Ok(())
+    }
+
+    /// Map a TLS block for the main thread at `virtual_start`, initialized from the TLS `template`.
+    fn load_tls_block(
+        &mut self,
+        image: &ElfImage,
+        template: &TlsTemplate,
+        virtual_start: usize,
+        num_pages: usize,
+    ) -> Result<(), Error> {
+        trace!("mapping {num_pages} page TLS block at {virtual_start:x}");
+        let pages = self.allocate_and_map(
+            virtual_start,
+            num_pages,
+            &MemoryProperties {
+                user_space_access: true,
+                writable: true,
+                executable: false,
+                ..MemoryProperties::default()
+            },
+        )?;
+        unsafe {
+            let dst: *mut u8 = pages.byte_add(template.data_offset()).cast().into();
+            core::ptr::copy_nonoverlapping(
+                image.bytes[template.file_offset..].as_ptr(),
+                dst,
+                template.file_size,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<PA: PageAllocator> Drop for LoadedImage<'_, PA> {
+    fn drop(&mut self) {
+        for allocation in self.allocations.drain(..) {
+            self.page_allocator
+                .free(allocation.physical_start, allocation.num_pages)
+                .unwrap();
+        }
+    }
+}
+
+/// An executable read from a filesystem, along with the files it needs to run.
+#[derive(Debug, Clone)]
+pub struct Executable {
+    /// The contents of the executable, which are known to be a valid image for [`ElfImage::parse`].
+    pub image: Vec<u8>,
+    /// The path and contents of the interpreter requested by the executable, if it has one.
+    pub interpreter: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Read the executable at `path` from `fs`, along with its interpreter if it requests one.
+///
+/// Both files are checked to be valid images. The interpreter itself must not need another
+/// interpreter.
+///
+/// # Errors
+/// - [`Error::FileSystem`] if either file could not be read.
+/// - [`Error::BadFormat`] or [`Error::Unsupported`] if either file is not a valid image.
+/// - [`Error::Unsupported`] if the interpreter requests an interpreter of its own.
+pub fn read_executable(fs: &(impl FileSystem + ?Sized), path: &[u8]) -> Result<Executable, Error> {
+    let read = |path: &[u8]| fs.read_file(path).context(FileSystemSnafu { path });
+    let image = read(path)?;
+    let interpreter = match ElfImage::parse(&image)?.interpreter()? {
+        Some(interpreter_path) => {
+            let interpreter = read(interpreter_path)?;
+            ensure!(
+                ElfImage::parse(&interpreter)?.interpreter()?.is_none(),
+                UnsupportedSnafu {
+                    reason: "interpreter requests an interpreter"
+                }
+            );
+            Some((interpreter_path.to_vec(), interpreter))
+        }
+        None => None,
+    };
+    Ok(Executable { image, interpreter })
+}
+
+/// Load an executable `image` into a new address space with a stack of `stack_pages` pages.
+///
+/// Memory for each segment and the stack is allocated from `page_allocator` and mapped into new
+/// page tables. The returned processor state will start executing at the image's entry point at
+/// EL0, with the stack pointer at [`STACK_TOP`]. The [`STACK_GUARD_PAGES`] below the stack are
+/// left unmapped.
+///
+/// If the image has a TLS template, a TLS block for the main thread is mapped below the stack guard
+/// pages and the thread pointer is set to point to it.
+///
+/// The page at `time_page` is mapped read only at [`TIME_PAGE_ADDRESS`]. It is shared by every
+/// process, so it is not one of the image's allocations.
+///
+/// # Errors
+/// - [`Error::BadFormat`] if a segment or the TLS template is invalid.
+/// - [`Error::Unsupported`] if the TLS data must be aligned to more than a page.
+/// - [`Error::OverlappingSegment`] if two segments share the same page, or a segment overlaps the
+///   time page or the region reserved for startup information (see
+///   [`LoadedImage::map_startup_info`]).
+/// - [`Error::Memory`] if memory could not be allocated.
+/// - [`Error::Mapping`] if the memory could not be mapped.
+pub fn load_image<'pa, PA: PageAllocator>(
+    page_allocator: &'pa PA,
+    image: &ElfImage,
+    stack_pages: usize,
+    time_page: PhysicalAddress,
+) -> Result<LoadedImage<'pa, PA>, Error> {
+    let page_size = usize::from(page_allocator.page_size());
+
+    let stack_start = STACK_TOP - stack_pages * page_size;
+    let guard_start = stack_start - STACK_GUARD_PAGES * page_size;
+
+    let tls = image.tls_template()?;
+    let tls_pages = match &tls {
+        Some(tls) => {
+            ensure!(
+                tls.alignment <= page_size,
+                UnsupportedSnafu {
+                    reason: "TLS alignment larger than a page"
+                }
+            );
+            tls.block_size().div_ceil(page_size)
+        }
+        None => 0,
+    };
+    let tls_start = guard_start - tls_pages * page_size;
+
+    let mut loaded = LoadedImage {
+        page_allocator,
+        page_tables: PageTables::empty(page_allocator).context(MemorySnafu)?,
+        initial_state: ProcessorState::new_for_user_thread(
+            image.entry_point(),
+            VirtualAddress::from(STACK_TOP),
+            VirtualAddress::from(if tls.is_some() { tls_start } else { 0 }),
+        ),
+        allocations: Vec::new(),
+        stack_guard: (
+            VirtualAddress::from(guard_start),
+            STACK_GUARD_PAGES * page_size,
+        ),
+    };
+
+    loaded.map_time_page(time_page)?;
+    let mut mapped_ranges = Vec::from([
+        (TIME_PAGE_ADDRESS, TIME_PAGE_ADDRESS + page_size),
+        (
+            STARTUP_INFO_ADDRESS,
+            STARTUP_INFO_ADDRESS + MAX_STARTUP_INFO_SIZE.next_multiple_of(page_size),
+        ),
+    ]);
+
+    for segment in image.segments() {
+        let segment = segment?;
+        if segment.memory_size == 0 {
+            continue;
+        }
+        let start = segment.virtual_address & !(page_size - 1);
+        let end = (segment.virtual_address + segment.memory_size).next_multiple_of(page_size);
+        ensure!(
+            end <= tls_start && mapped_ranges.iter().all(|&(s, e)| end <= s || start >= e),
+            OverlappingSegmentSnafu {
+                address: VirtualAddress::from(segment.virtual_address)
+            }
+        );
+        mapped_ranges.push((start, end));
+
+        trace!("loading segment {segment:x?} into {start:x}..{end:x}");
+
+        let pages = loaded.allocate_and_map(
+            start,
+            (end - start) / page_size,
+            &MemoryProperties {
+                user_space_access: true,
+                writable: segment.writable,
+                executable: segment.executable,
+                ..MemoryProperties::default()
+            },
+        )?;
+
+        unsafe {
+            let dst: *mut u8 = pages
+                .byte_add(segment.virtual_address - start)
+                .cast()
+                .into();
+            core::ptr::copy_nonoverlapping(
+                image.bytes[segment.file_offset..].as_ptr(),
+                dst,
+                segment.file_size,
+            );
+        }
+    }
+
+    trace!("mapping {stack_pages} page stack at {stack_start:x}");
+    loaded.allocate_and_map(
+        stack_start,
+        stack_pages,
+        &MemoryProperties {
+            user_space_access: true,
+            writable: true,
+            executable: false,
+            ..MemoryProperties::default()
+        },
+    )?;
+
+    if let Some(tls) = tls {
+        loaded.load_tls_block(image, &tls, tls_start, tls_pages)?;
+    }
+
+    Ok(loaded)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::memory::{tests::MockPageAllocator, PageSize};
+
+    /// Build a minimal ELF executable with one program header per segment `(vaddr, flags, data, memsz)`.
+    pub(crate) fn build_elf(entry: u64, segments: &[(u64, u32, &[u8], u64)]) -> Vec<u8> {
+        let mut out = vec![0u8; ELF_HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE];
+        out[0..4].copy_from_slice(ELF_MAGIC);
+        out[4] = ELF_CLASS_64;
+        out[5] = ELF_DATA_LITTLE_ENDIAN;
+        out[6] = 1;
+        LittleEndian::write_u16(&mut out[16..], ELF_TYPE_EXECUTABLE);
+        LittleEndian::write_u16(&mut out[18..], ELF_MACHINE_AARCH64);
+        LittleEndian::write_u64(&mut out[24..], entry);
+        LittleEndian::write_u64(&mut out[32..], ELF_HEADER_SIZE as u64);
+        LittleEndian::write_u16(&mut out[54..], PROGRAM_HEADER_SIZE as u16);
+        LittleEndian::write_u16(&mut out[56..], segments.len() as u16);
+        for (i, (vaddr, flags, data, memsz)) in segments.iter().enumerate() {
+            let data_offset = out.len() as u64;
+            out.extend_from_slice(data);
+            let ph = &mut out[ELF_HEADER_SIZE + i * PROGRAM_HEADER_SIZE..];
+            LittleEndian::write_u32(ph, PROGRAM_TYPE_LOAD);
+            LittleEndian::write_u32(&mut ph[4..], *flags);
+            LittleEndian::write_u64(&mut ph[8..], data_offset);
+            LittleEndian::write_u64(&mut ph[16..], *vaddr);
+            LittleEndian::write_u64(&mut ph[32..], data.len() as u64);
+            LittleEndian::write_u64(&mut ph[40..], *memsz);
+        }
+        out
+    }
+
+    #[test]
+    fn parse_segments() {
+        let elf = build_elf(
+            0x40_0000,
+            &[
+                (0x40_0000, 0b101, &[1, 2, 3], 3),
+                (0x50_0000, 0b110, &[4], 0x2000),
+            ],
+        );
+        let image = ElfImage::parse(&elf).unwrap();
+        assert_eq!(image.entry_point(), VirtualAddress::from(0x40_0000));
+        let segments: Vec<_> = image.segments().collect::<Result<_, _>>().unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].virtual_address, 0x40_0000);
+        assert!(segments[0].executable && !segments[0].writable);
+        assert_eq!(segments[1].memory_size, 0x2000);
+        assert!(!segments[1].executable && segments[1].writable);
+    }
+
+    #[test]
+    fn reject_bad_images() {
+        assert!(matches!(
+            ElfImage::parse(b"not an elf"),
+            Err(Error::BadFormat { .. })
+        ));
+        let mut elf = build_elf(0, &[]);
+        LittleEndian::write_u16(&mut elf[18..], 62);
+        assert!(matches!(
+            ElfImage::parse(&elf),
+            Err(Error::Unsupported { .. })
+        ));
+        let mut elf = build_elf(0, &[(0x1000, 0b100, &[1, 2], 2)]);
+        // make the segment data extend past the end of the image
+        LittleEndian::write_u64(&mut elf[ELF_HEADER_SIZE + 32..], 0x1000);
+        let image = ElfImage::parse(&elf).unwrap();
+        assert!(matches!(
+            image.segments().next(),
+            Some(Err(Error::BadFormat { .. }))
+        ));
+    }
+
+    #[test]
+    fn load_maps_segments_and_stack() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let time_page = pa.allocate(1).unwrap();
+        {
+            let code = [0xaa; 0x10];
+            let elf = build_elf(
+                0x40_0008,
+                &[
+                    (0x40_0000, 0b101, &code, 0x10),
+                    (0x40_2010, 0b110, &[0xbb; 4], 0x1100),
+                ],
+            );
+            let image = ElfImage::parse(&elf).unwrap();
+            let loaded = load_image(&pa, &image, 2, time_page).unwrap();
+
+            assert_eq!(
+                loaded.initial_state.program_counter,
+                VirtualAddress::from(0x40_0008)
+            );
+            assert_eq!(
+                loaded.initial_state.stack_pointer,
+                VirtualAddress::from(STACK_TOP)
+            );
+            assert_eq!(loaded.initial_state.spsr.el(), 0);
+            assert_eq!(loaded.initial_state.thread_pointer, VirtualAddress::from(0));
+
+            let code_page = loaded
+                .page_tables
+                .physical_address_of(VirtualAddress::from(0x40_0000))
+                .expect("code mapped");
+            let code_bytes: *mut u8 = code_page.cast().into();
+            assert_eq!(
+                unsafe { core::slice::from_raw_parts(code_bytes, 0x10) },
+                &code
+            );
+
+            let data = loaded
+                .page_tables
+                .physical_address_of(VirtualAddress::from(0x40_2010))
+                .expect("data mapped");
+            let data_bytes: *mut u8 = data.cast().into();
+            assert_eq!(
+                unsafe { core::slice::from_raw_parts(data_bytes, 8) },
+                &[0xbb, 0xbb, 0xbb, 0xbb, 0, 0, 0, 0]
+            );
+            // the zeroed part of the data segment spans into a second page
+            assert!(loaded
+                .page_tables
+                .physical_address_of(VirtualAddress::from(0x40_3000))
+                .is_some());
+
+            assert!(loaded
+                .page_tables
+                .physical_address_of(VirtualAddress::from(STACK_TOP - 1))
+                .is_some());
+            assert!(loaded
+                .page_tables
+                .physical_address_of(VirtualAddress::from(STACK_TOP - 0x2001))
+                .is_none());
+            assert_eq!(loaded.allocations().len(), 3);
+            assert_eq!(
+                loaded
+                    .page_tables
+                    .physical_address_of(VirtualAddress::from(TIME_PAGE_ADDRESS + 8)),
+                Some(time_page.byte_add(8))
+            );
+        }
+        pa.free(time_page, 1).unwrap();
+        pa.end_check();
+    }
+
+    #[test]
+    fn overlapping_segments() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let time_page = pa.allocate(1).unwrap();
+        {
+            let elf = build_elf(
+                0x40_0000,
+                &[
+                    (0x40_0000, 0b101, &[0; 4], 4),
+                    (0x40_0800, 0b110, &[0; 4], 4),
+                ],
+            );
+            let image = ElfImage::parse(&elf).unwrap();
+            assert!(matches!(
+                load_image(&pa, &image, 1, time_page),
+                Err(Error::OverlappingSegment { .. })
+            ));
+
+            let elf = build_elf(
+                0x40_0000,
+                &[(TIME_PAGE_ADDRESS as u64 + 0x10, 0b110, &[0; 4], 4)],
+            );
+            let image = ElfImage::parse(&elf).unwrap();
+            assert!(matches!(
+                load_image(&pa, &image, 1, time_page),
+                Err(Error::OverlappingSegment { .. })
+            ));
+        }
+        pa.free(time_page, 1).unwrap();
+        pa.end_check();
+    }
+
+    #[test]
+    fn segment_in_stack_guard() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let time_page = pa.allocate(1).unwrap();
+        {
+            let guard = (STACK_TOP - 0x3000) as u64;
+            let elf = build_elf(0x40_0000, &[(guard, 0b110, &[0; 4], 4)]);
+            let image = ElfImage::parse(&elf).unwrap();
+            assert!(matches!(
+                load_image(&pa, &image, 2, time_page),
+                Err(Error::OverlappingSegment { .. })
+            ));
+
+            let elf = build_elf(0x40_0000, &[(guard - 0x1000, 0b110, &[0; 4], 4)]);
+            let image = ElfImage::parse(&elf).unwrap();
+            let loaded = load_image(&pa, &image, 2, time_page).unwrap();
+            assert_eq!(
+                loaded.stack_guard(),
+                (VirtualAddress::from(STACK_TOP - 0x3000), 0x1000)
+            );
+            assert!(loaded
+                .page_tables
+                .physical_address_of(VirtualAddress::from(STACK_TOP - 0x3000))
+                .is_none());
+        }
+        pa.free(time_page, 1).unwrap();
+        pa.end_check();
+    }
+
+    #[test]
+    fn load_tls_block() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let time_page = pa.allocate(1).unwrap();
+        {
+            let mut elf = build_elf(
+                0x40_0000,
+                &[
+                    (0x40_0000, 0b101, &[0; 4], 4),
+                    (0x40_1000, 0b100, &[1, 2, 3], 0x20),
+                ],
+            );
+            // turn the second segment into the TLS template
+            let ph = &mut elf[ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE..];
+            LittleEndian::write_u32(ph, PROGRAM_TYPE_TLS);
+            LittleEndian::write_u64(&mut ph[48..], 32);
+            let image = ElfImage::parse(&elf).unwrap();
+            assert_eq!(image.segments().count(), 1);
+            let tls = image.tls_template().unwrap().unwrap();
+            assert_eq!(tls.data_offset(), 32);
+            assert_eq!(tls.block_size(), 0x40);
+
+            let loaded = load_image(&pa, &image, 2, time_page).unwrap();
+            let tp = STACK_TOP - 0x4000;
+            assert_eq!(
+                loaded.initial_state.thread_pointer,
+                VirtualAddress::from(tp)
+            );
+            let block = loaded
+                .page_tables
+                .physical_address_of(VirtualAddress::from(tp))
+                .expect("TLS block mapped");
+            let block_bytes: *mut u8 = block.cast().into();
+            let block_bytes = unsafe { core::slice::from_raw_parts(block_bytes, 0x40) };
+            assert!(block_bytes[..32].iter().all(|b| *b == 0));
+            assert_eq!(&block_bytes[32..36], &[1, 2, 3, 0]);
+
+            LittleEndian::write_u64(&mut elf[ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE + 48..], 3);
+            assert!(matches!(
+                ElfImage::parse(&elf).unwrap().tls_template(),
+                Err(Error::BadFormat { .. })
+            ));
+        }
+        pa.free(time_page, 1).unwrap();
+        pa.end_check();
+    }
+
+    #[test]
+    fn read_executable_and_interpreter() {
+        use crate::{
+            fs::ArchiveFs,
+            init::archive::{tests::tar_entry, Archive},
+        };
+
+        let program = build_elf(0x40_0000, &[(0x40_0000, 0b101, &[0; 4], 4)]);
+        let mut dynamic = build_elf(
+            0x40_0000,
+            &[
+                (0x40_0000, 0b101, &[0; 4], 4),
+                (0, 0b100, b"/lib/ld.so\0", 11),
+            ],
+        );
+        // turn the second segment into the interpreter path
+        LittleEndian::write_u32(
+            &mut dynamic[ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE..],
+            PROGRAM_TYPE_INTERP,
+        );
+        assert_eq!(
+            ElfImage::parse(&dynamic).unwrap().interpreter().unwrap(),
+            Some(b"/lib/ld.so".as_slice())
+        );
+
+        let mut data = Vec::new();
+        tar_entry(&mut data, "bin/init", b'0', &program);
+        tar_entry(&mut data, "bin/dynamic", b'0', &dynamic);
+        tar_entry(&mut data, "bin/text", b'0', b"not an executable");
+        tar_entry(&mut data, "lib/ld.so", b'0', &program);
+        data.extend_from_slice(&[0; 1024]);
+        let fs = ArchiveFs::new(Archive::new(&data).unwrap());
+
+        let init = read_executable(&fs, b"/bin/init").unwrap();
+        assert_eq!(init.image, program);
+        assert!(init.interpreter.is_none());
+
+        let dynamic_exe = read_executable(&fs, b"/bin/dynamic").unwrap();
+        assert_eq!(dynamic_exe.image, dynamic);
+        assert_eq!(
+            dynamic_exe.interpreter,
+            Some((b"/lib/ld.so".to_vec(), program.clone()))
+        );
+
+        assert!(matches!(
+            read_executable(&fs, b"/bin/text"),
+            Err(Error::BadFormat { .. })
+        ));
+        assert!(matches!(
+            read_executable(&fs, b"/bin/sh"),
+            Err(Error::FileSystem { path, source: crate::fs::Error::NotFound }) if path == b"/bin/sh"
+        ));
+    }
+
+    #[test]
+    fn map_startup_info() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let time_page = pa.allocate(1).unwrap();
+        {
+            let elf = build_elf(0x40_0000, &[(0x40_0000, 0b101, &[0; 4], 4)]);
+            let image = ElfImage::parse(&elf).unwrap();
+            let mut loaded = load_image(&pa, &image, 1, time_page).unwrap();
+            let info = [7; 0x1800];
+            loaded.map_startup_info(&info).unwrap();
+            assert_eq!(loaded.initial_state.registers.x[0], STARTUP_INFO_ADDRESS);
+            assert_eq!(loaded.initial_state.registers.x[1], info.len());
+            let pages = loaded
+                .page_tables
+                .physical_address_of(VirtualAddress::from(STARTUP_INFO_ADDRESS))
+                .expect("startup information mapped");
+            let bytes: *mut u8 = pages.cast().into();
+            assert_eq!(
+                unsafe { core::slice::from_raw_parts(bytes, info.len()) },
+                &info
+            );
+            assert!(matches!(
+                loaded.map_startup_info(&[0; MAX_STARTUP_INFO_SIZE + 1]),
+                Err(Error::StartupInfoTooLarge { .. })
+            ));
+
+            let elf = build_elf(
+                0x40_0000,
+                &[(STARTUP_INFO_ADDRESS as u64 + 0x2000, 0b110, &[0; 4], 4)],
+            );
+            let image = ElfImage::parse(&elf).unwrap();
+            assert!(matches!(
+                load_image(&pa, &image, 1, time_page),
+                Err(Error::OverlappingSegment { .. })
+            ));
+        }
+        pa.free(time_page, 1).unwrap();
+        pa.end_check();
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let time_page = pa.allocate(1).unwrap();
+        {
+            let elf = build_elf(0x40_0000, &[(0x40_0000, 0b101, &[0; 4], 4)]);
+            let image = ElfImage::parse(&elf).unwrap();
+            assert!(matches!(
+                load_image(&pa, &image, 64, time_page),
+                Err(Error::Memory { .. })
+            ));
+        }
+        pa.free(time_page, 1).unwrap();
+        pa.end_check();
+    }
+}
@@ -0,0 +1,193 @@
+//! Granting driver processes access to device MMIO regions.
+use alloc::vec::Vec;
+use log::trace;
+use snafu::ensure;
+
+use super::{Error, Id, InvalidRegionSnafu, RegionClaimedSnafu};
+use crate::{
+    memory::{PageSize, PhysicalAddress},
+    sync::Mutex,
+};
+
+/// A region of MMIO claimed by a driver process.
+struct Claim {
+    owner: Id,
+    start: usize,
+    end: usize,
+}
+
+/// Tracks which driver process has claimed each region of physical MMIO.
+///
+/// Each region can only be claimed by one driver at a time, and regions of RAM or devices used by
+/// the kernel can never be claimed, so drivers can't interfere with each other or with the kernel.
+pub struct MmioRegistry {
+    page_size: PageSize,
+    /// Regions of physical memory that can't be claimed, as (start, end).
+    reserved: Mutex<Vec<(usize, usize)>>,
+    claims: Mutex<Vec<Claim>>,
+}
+
+impl MmioRegistry {
+    /// Create a new registry with no claims, for regions of pages of size `page_size`.
+    ///
+    /// The (start, length in bytes) regions in `reserved` (i.e. RAM) can never be claimed.
+    pub fn new(
+        page_size: PageSize,
+        reserved: impl Iterator<Item = (PhysicalAddress, usize)>,
+    ) -> Self {
+        Self {
+            page_size,
+            reserved: Mutex::new(
+                reserved
+                    .map(|(start, length)| (usize::from(start), usize::from(start) + length))
+                    .collect(),
+            ),
+            claims: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Reserve the `length` bytes starting at `start` so that they can never be claimed, because
+    /// the kernel uses the device there itself.
+    ///
+    /// Existing claims are not affected, so this should be done before any driver process starts.
+    pub fn reserve(&self, start: PhysicalAddress, length: usize) {
+        let start = usize::from(start);
+        trace!("reserved MMIO {start:x}..{:x}", start + length);
+        self.reserved.lock().push((start, start + length));
+    }
+
+    /// Claim the `length` bytes of MMIO starting at `start` for the process `owner`.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidRegion`] if the region is empty, not page aligned, or overlaps a reserved
+    ///   region.
+    /// - [`Error::RegionClaimed`] if part of the region has already been claimed by any process.
+    pub fn claim(&self, owner: Id, start: PhysicalAddress, length: usize) -> Result<(), Error> {
+        let start_addr = usize::from(start);
+        let end = start_addr.checked_add(length);
+        ensure!(
+            length > 0
+                && start.is_aligned_to(self.page_size.into())
+                && length.is_multiple_of(self.page_size.into())
+                && end.is_some_and(|end| self
+                    .reserved
+                    .lock()
+                    .iter()
+                    .all(|&(s, e)| end <= s || start_addr >= e)),
+            InvalidRegionSnafu { address: start }
+        );
+        let end = end.unwrap_or_default();
+        let mut claims = self.claims.lock();
+        if let Some(c) = claims.iter().find(|c| end > c.start && start_addr < c.end) {
+            return RegionClaimedSnafu {
+                address: start,
+                owner: c.owner,
+            }
+            .fail();
+        }
+        trace!("process {owner} claimed MMIO {start_addr:x}..{end:x}");
+        claims.push(Claim {
+            owner,
+            start: start_addr,
+            end,
+        });
+        Ok(())
+    }
+
+    /// The process that has claimed the MMIO at `address`, if any.
+    pub fn owner_of(&self, address: PhysicalAddress) -> Option<Id> {
+        let address = usize::from(address);
+        self.claims
+            .lock()
+            .iter()
+            .find(|c| (c.start..c.end).contains(&address))
+            .map(|c| c.owner)
+    }
+
+    /// Release the claim on the region starting at `start`, if it is owned by `owner`.
+    ///
+    /// Returns true if a claim was released.
+    pub fn release(&self, owner: Id, start: PhysicalAddress) -> bool {
+        let start = usize::from(start);
+        let mut claims = self.claims.lock();
+        let count = claims.len();
+        claims.retain(|c| c.owner != owner || c.start != start);
+        claims.len() != count
+    }
+
+    /// Release every claim owned by `owner`, returning how many there were.
+    pub fn release_all(&self, owner: Id) -> usize {
+        let mut claims = self.claims.lock();
+        let count = claims.len();
+        claims.retain(|c| c.owner != owner);
+        count - claims.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> MmioRegistry {
+        MmioRegistry::new(
+            PageSize::FourKiB,
+            [(PhysicalAddress::from(0x4000_0000), 0x1000_0000)].into_iter(),
+        )
+    }
+
+    #[test]
+    fn claims_do_not_overlap() {
+        let r = registry();
+        r.claim(1, PhysicalAddress::from(0x0900_0000), 0x2000)
+            .unwrap();
+        assert!(matches!(
+            r.claim(2, PhysicalAddress::from(0x0900_1000), 0x1000),
+            Err(Error::RegionClaimed { owner: 1, .. })
+        ));
+        r.claim(2, PhysicalAddress::from(0x0900_2000), 0x1000)
+            .unwrap();
+        assert_eq!(r.owner_of(PhysicalAddress::from(0x0900_1fff)), Some(1));
+        assert_eq!(r.owner_of(PhysicalAddress::from(0x0900_2000)), Some(2));
+        assert_eq!(r.owner_of(PhysicalAddress::from(0x0900_3000)), None);
+
+        assert!(!r.release(2, PhysicalAddress::from(0x0900_0000)));
+        assert!(r.release(1, PhysicalAddress::from(0x0900_0000)));
+        r.claim(2, PhysicalAddress::from(0x0900_1000), 0x1000)
+            .unwrap();
+        assert_eq!(r.release_all(2), 2);
+        assert_eq!(r.owner_of(PhysicalAddress::from(0x0900_2000)), None);
+    }
+
+    #[test]
+    fn invalid_regions() {
+        let r = registry();
+        for (start, length) in [
+            (0x0900_0000, 0),
+            (0x0900_0800, 0x1000),
+            (0x0900_0000, 0x800),
+            (0x3fff_f000, 0x2000),
+            (0x4800_0000, 0x1000),
+            (0xfeff_ffff_ffff_f000, usize::MAX & !0xfff),
+        ] {
+            assert!(
+                matches!(
+                    r.claim(1, PhysicalAddress::from(start), length),
+                    Err(Error::InvalidRegion { .. })
+                ),
+                "{start:x} {length:x}"
+            );
+        }
+    }
+
+    #[test]
+    fn kernel_devices_cannot_be_claimed() {
+        let r = registry();
+        r.reserve(PhysicalAddress::from(0x0900_0000), 0x100);
+        assert!(matches!(
+            r.claim(1, PhysicalAddress::from(0x0900_0000), 0x1000),
+            Err(Error::InvalidRegion { .. })
+        ));
+        r.claim(1, PhysicalAddress::from(0x0900_1000), 0x1000)
+            .unwrap();
+    }
+}
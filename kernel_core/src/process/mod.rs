@@ -1,5 +1,1736 @@
 //! Processes (and threads).
 
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+use snafu::{ensure, OptionExt as _, ResultExt as _, Snafu};
+
+use crate::{
+    collections::HandleMap,
+    ipc::PageTransfer,
+    memory::{
+        asid::{Activation, AsidContext},
+        iommu::{self, IoAddressSpace},
+        page_table::{self, MapBlockSize, MemoryKind, MemoryProperties},
+        AddressSpaceIdPool, MemoryManagmentUnit, PageAllocator, PageFrameDatabase, PageSize,
+        PageTables, PhysicalAddress, PhysicalPointer, VirtualAddress,
+    },
+    platform::branch_protection::Keys,
+    sync::{
+        futex::{self, FutexTable},
+        Mutex,
+    },
+    time::Ticks,
+};
+
+pub mod caps;
+pub mod debug;
+pub mod fault;
+pub mod loader;
+pub mod mmio;
+pub mod name;
+pub mod names;
+pub mod policy;
+pub mod startup;
+pub mod system_call;
 pub mod thread;
 
+use caps::CapabilityTable;
+use mmio::MmioRegistry;
+pub use name::Name;
+pub use policy::Privilege;
 pub use thread::Id as ThreadId;
+use thread::{wait::Timeout, Scheduler, State, Thread};
+
+/// An unique ID for a process.
+pub type Id = u32;
+
+/// The value a process exits with, which is reported to its supervisor.
+pub type ExitCode = u32;
+
+/// The code a process exits with when it is killed because the kernel ran out of memory.
+pub const OUT_OF_MEMORY_EXIT_CODE: ExitCode = ExitCode::MAX;
+
+/// Errors that can occur managing a process.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// No process exists with the given ID.
+    #[snafu(display("unknown process {id}"))]
+    UnknownProcess {
+        /// The ID that was looked up.
+        id: Id,
+    },
+    /// The process has already exited.
+    #[snafu(display("process {id} has already exited"))]
+    AlreadyExited {
+        /// The ID of the process.
+        id: Id,
+    },
+    /// The process is not a driver, so it can't access devices directly.
+    #[snafu(display("process {id} is not a driver"))]
+    NotDriver {
+        /// The ID of the process.
+        id: Id,
+    },
+    /// The MMIO region is invalid: it is empty, unaligned or overlaps RAM.
+    #[snafu(display("invalid MMIO region at {address:?}"))]
+    InvalidRegion {
+        /// The start of the region.
+        address: PhysicalAddress,
+    },
+    /// Part of the MMIO region has already been claimed by a driver.
+    #[snafu(display("MMIO region at {address:?} overlaps a region claimed by process {owner}"))]
+    RegionClaimed {
+        /// The start of the region.
+        address: PhysicalAddress,
+        /// The process that claimed the overlapping region.
+        owner: Id,
+    },
+    /// The region is not one that was mapped into the process.
+    #[snafu(display("no region mapped at {address:?}"))]
+    NotMapped {
+        /// The start of the region.
+        address: VirtualAddress,
+    },
+    /// The process is already being debugged by another process.
+    #[snafu(display("process {id} is already being debugged by process {debugger}"))]
+    AlreadyDebugged {
+        /// The ID of the process.
+        id: Id,
+        /// The process debugging it.
+        debugger: Id,
+    },
+    /// The process is not being debugged by the process that tried to debug it.
+    #[snafu(display("process {id} is not being debugged by process {debugger}"))]
+    NotDebugger {
+        /// The ID of the process.
+        id: Id,
+        /// The process that tried to debug it.
+        debugger: Id,
+    },
+    /// The thread does not belong to the process.
+    #[snafu(display("thread {thread} is not in process {id}"))]
+    UnknownThread {
+        /// The ID of the process.
+        id: Id,
+        /// The ID of the thread.
+        thread: ThreadId,
+    },
+    /// The thread must be suspended before its registers can be accessed.
+    #[snafu(display("thread {thread} is not suspended"))]
+    NotSuspended {
+        /// The ID of the thread.
+        thread: ThreadId,
+    },
+    /// The thread has been suspended, but is still running on a core until that core's next time
+    /// slice, so its registers can't be accessed yet.
+    #[snafu(display("thread {thread} is still running"))]
+    StillRunning {
+        /// The ID of the thread.
+        thread: ThreadId,
+    },
+    /// There is already a breakpoint at the address.
+    #[snafu(display("breakpoint already set at {address:?}"))]
+    BreakpointExists {
+        /// The address of the breakpoint.
+        address: VirtualAddress,
+    },
+    /// Breakpoints must be aligned to an instruction.
+    #[snafu(display("breakpoint at {address:?} is not aligned to an instruction"))]
+    MisalignedBreakpoint {
+        /// The address of the breakpoint.
+        address: VirtualAddress,
+    },
+    /// There is no breakpoint at the address.
+    #[snafu(display("no breakpoint set at {address:?}"))]
+    NoBreakpoint {
+        /// The address of the breakpoint.
+        address: VirtualAddress,
+    },
+    /// The stack of a fault handler is too small to hold a fault frame.
+    #[snafu(display("fault handler stack of {size} bytes is too small"))]
+    FaultStackTooSmall {
+        /// The size of the stack in bytes.
+        size: usize,
+    },
+    /// An error occurred updating the process' page tables.
+    PageTables {
+        /// Underlying error.
+        source: page_table::Error,
+    },
+    /// An error occurred tracking the physical pages of the process.
+    Memory {
+        /// Underlying error.
+        source: crate::memory::Error,
+    },
+    /// An error occurred granting a device access to the process' memory.
+    Dma {
+        /// Underlying error.
+        source: iommu::Error,
+    },
+    /// The security model does not allow the interaction.
+    Policy {
+        /// Underlying error.
+        source: policy::Error,
+    },
+}
+
+/// A region of physical pages mapped into a process' address space.
+#[derive(Clone)]
+struct Mapping {
+    virtual_start: VirtualAddress,
+    physical_start: PhysicalAddress,
+    num_pages: usize,
+    properties: MemoryProperties,
+    /// The region is device MMIO rather than RAM, so its pages are not reference counted.
+    device: bool,
+}
+
+// The mapping only describes where physical memory is mapped, and is never dereferenced through.
+unsafe impl Send for Mapping {}
+
+impl<PA: PageAllocator> AddressSpace<'_, PA> {
+    /// The number of pages of RAM mapped, not counting device MMIO.
+    fn resident_pages(&self) -> usize {
+        self.mappings
+            .iter()
+            .filter(|m| !m.device)
+            .map(|m| m.num_pages)
+            .sum()
+    }
+
+    /// Map the region of `mapping`, whose pages are `page_size` bytes, and record it.
+    ///
+    /// The region must not overlap any other region. If it can't be mapped, whatever part of it
+    /// was mapped is unmapped again, so nothing refers to its pages.
+    fn insert(&mut self, mapping: Mapping, page_size: PageSize) -> Result<(), Error> {
+        let page_size = usize::from(page_size);
+        let start = usize::from(mapping.virtual_start);
+        let end = start + mapping.num_pages * page_size;
+        if let Some(other) = self.mappings.iter().find(|m| {
+            let other_start = usize::from(m.virtual_start);
+            other_start < end && start < other_start + m.num_pages * page_size
+        }) {
+            return Err(page_table::Error::AlreadyMapped {
+                address: other.virtual_start,
+            })
+            .context(PageTablesSnafu);
+        }
+        let mapped = self.page_tables.map(
+            mapping.virtual_start,
+            mapping.physical_start,
+            mapping.num_pages,
+            MapBlockSize::Page,
+            &mapping.properties,
+        );
+        if let Err(e) = mapped {
+            // nothing else is mapped in the region, so any page that is mapped is part of it
+            for i in 0..mapping.num_pages {
+                let _ = self.page_tables.unmap(
+                    mapping.virtual_start.byte_add(i * page_size),
+                    1,
+                    MapBlockSize::Page,
+                );
+            }
+            return Err(e).context(PageTablesSnafu);
+        }
+        self.mappings.push(mapping);
+        Ok(())
+    }
+
+    /// The physical address that `address` is mapped to, if it is in a region of RAM rather than
+    /// device MMIO, which the kernel must not access on the process' behalf.
+    fn ram_address_of(&self, address: VirtualAddress, page_size: usize) -> Option<PhysicalAddress> {
+        self.mappings.iter().filter(|m| !m.device).find_map(|m| {
+            let offset = usize::from(address).checked_sub(usize::from(m.virtual_start))?;
+            (offset < m.num_pages * page_size).then(|| m.physical_start.byte_add(offset))
+        })
+    }
+}
+
+/// The memory owned by a running process.
+struct AddressSpace<'pa, PA: PageAllocator> {
+    page_tables: PageTables<'pa, PA>,
+    mappings: Vec<Mapping>,
+}
+
+/// A user-space process, which owns an address space and a set of threads.
+pub struct Process<'pa, PA: PageAllocator> {
+    /// The unique ID for this process.
+    pub id: Id,
+
+    /// A short name for the process, which is shown with its ID in logs.
+    name: Mutex<Name>,
+
+    /// The process that is notified when this process exits, if any.
+    pub supervisor: Option<Id>,
+
+    /// The address space ID that tags this process' TLB entries, allocated from an
+    /// [`AddressSpaceIdPool`] when the process is activated on a core (see [`Process::activate`]).
+    pub asid: AsidContext,
+
+    /// The privilege level of the process, which limits the processes it can interact with. See
+    /// [`policy`].
+    pub privilege: Privilege,
+
+    /// The pointer authentication keys shared by the process' threads, which must be copied into
+    /// the processor state of each new thread.
+    pub pointer_auth_keys: Keys,
+
+    page_allocator: &'pa PA,
+
+    /// The address space, or `None` once the process has exited and its memory has been freed.
+    address_space: Mutex<Option<AddressSpace<'pa, PA>>>,
+
+    threads: Mutex<Vec<Arc<Thread>>>,
+
+    /// Counter ticks spent running by threads that have been removed from `threads`.
+    exited_runtime: AtomicU64,
+
+    /// The capabilities held by this process, through which it refers to kernel objects.
+    pub capabilities: CapabilityTable<'pa, PA>,
+
+    exit_code: Mutex<Option<ExitCode>>,
+
+    /// Child processes that have exited but have not been reaped yet, in the order they exited.
+    exited_children: Mutex<Vec<(Id, ExitCode)>>,
+
+    /// Threads of this process waiting on futexes.
+    futexes: FutexTable,
+
+    /// The debugger attached to this process, if any.
+    debug: Mutex<Option<debug::DebugState>>,
+
+    /// The handler for faults caused by this process' threads, if any.
+    fault_handler: Mutex<Option<fault::FaultHandler>>,
+}
+
+impl<'pa, PA: PageAllocator> Process<'pa, PA> {
+    /// Create a new process called `name` that uses `page_tables` for its address space, and
+    /// insert it into `store`.
+    ///
+    /// # Panics
+    /// Panics if there are no process IDs left.
+    pub fn new(
+        store: &HandleMap<Process<'pa, PA>>,
+        name: Name,
+        supervisor: Option<Id>,
+        privilege: Privilege,
+        page_allocator: &'pa PA,
+        page_tables: PageTables<'pa, PA>,
+    ) -> Arc<Self> {
+        store
+            .insert_self_referential(|id| {
+                log::trace!("creating process id={id} name={name:?}");
+                Arc::new(Self {
+                    id,
+                    name: Mutex::new(name),
+                    supervisor,
+                    asid: AsidContext::new(),
+                    privilege,
+                    pointer_auth_keys: Keys::random(crate::rand::next_u64),
+                    page_allocator,
+                    address_space: Mutex::new(Some(AddressSpace {
+                        page_tables,
+                        mappings: Vec::new(),
+                    })),
+                    threads: Mutex::new(Vec::new()),
+                    exited_runtime: AtomicU64::new(0),
+                    capabilities: CapabilityTable::new(),
+                    exit_code: Mutex::new(None),
+                    exited_children: Mutex::new(Vec::new()),
+                    futexes: FutexTable::new(),
+                    debug: Mutex::new(None),
+                    fault_handler: Mutex::new(None),
+                })
+            })
+            .expect("process ids not exhausted")
+            .1
+    }
+
+    /// Create a new process called `name` that runs the loaded `image`, and insert it into
+    /// `store`. The process takes ownership of the image's pages, taking a reference to each in
+    /// `frames`, so they are released when it exits.
+    ///
+    /// Returns the process and the initial processor state of its main thread, which must be given
+    /// the process' [`Self::pointer_auth_keys`] before it runs.
+    ///
+    /// # Errors
+    /// - [`Error::Memory`] if the image's pages are not tracked by `frames`. The image is freed.
+    ///
+    /// # Panics
+    /// Panics if there are no process IDs left.
+    pub fn from_image(
+        store: &HandleMap<Process<'pa, PA>>,
+        name: Name,
+        supervisor: Option<Id>,
+        privilege: Privilege,
+        frames: &PageFrameDatabase,
+        image: loader::LoadedImage<'pa, PA>,
+    ) -> Result<(Arc<Self>, thread::ProcessorState), Error> {
+        let page_allocator = image.page_allocator();
+        let page_size = usize::from(page_allocator.page_size());
+        let pages = image
+            .allocations()
+            .iter()
+            .flat_map(|a| (0..a.num_pages).map(move |i| a.physical_start.byte_add(i * page_size)));
+        for (taken, page) in pages.clone().enumerate() {
+            if let Err(e) = frames.get(page) {
+                for page in pages.take(taken) {
+                    let _ = frames.put(page);
+                }
+                return Err(e).context(MemorySnafu);
+            }
+        }
+        let (page_tables, state, allocations) = image.into_parts();
+        let process = Self::new(
+            store,
+            name,
+            supervisor,
+            privilege,
+            page_allocator,
+            page_tables,
+        );
+        process
+            .address_space
+            .lock()
+            .as_mut()
+            .expect("new process has not exited")
+            .mappings
+            .extend(allocations.into_iter().map(|a| Mapping {
+                virtual_start: a.virtual_start,
+                physical_start: a.physical_start,
+                num_pages: a.num_pages,
+                properties: a.properties,
+                device: false,
+            }));
+        Ok((process, state))
+    }
+
+    /// The name of the process, which is empty if it has not been named.
+    pub fn name(&self) -> Name {
+        *self.name.lock()
+    }
+
+    /// Change the name of the process.
+    pub fn set_name(&self, name: Name) {
+        *self.name.lock() = name;
+    }
+
+    /// Returns true if the process is a driver, which is allowed to map device MMIO regions.
+    pub fn is_driver(&self) -> bool {
+        self.privilege == Privilege::Driver
+    }
+
+    /// Add a thread to the process, which will be torn down when the process exits.
+    pub fn add_thread(&self, thread: Arc<Thread>) {
+        thread.set_process(self.id);
+        self.threads.lock().push(thread);
+    }
+
+    /// Make the process' address space the one user space runs in on the core with index `core`,
+    /// allocating it an ASID from `pool` if it doesn't have a current one.
+    ///
+    /// If the pool rolled over, the TLB is flushed, and the returned activation says so, so that
+    /// the other cores can be told to flush theirs too. Returns `None` if the process has exited,
+    /// in which case nothing is activated.
+    ///
+    /// # Safety
+    /// This must only be called with the core's interrupts masked, when switching to one of the
+    /// process' threads, and the core must not access user space through the previous tables
+    /// afterwards.
+    pub unsafe fn activate(
+        &self,
+        pool: &AddressSpaceIdPool,
+        core: usize,
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Option<Activation> {
+        let address_space = self.address_space.lock();
+        let page_tables = &address_space.as_ref()?.page_tables;
+        let activation = pool.activate(core, &self.asid);
+        if activation.flush_local {
+            mmu.invalidate_all();
+        }
+        mmu.activate_page_tables(page_tables, activation.asid);
+        Some(activation)
+    }
+
+    /// Map `num_pages` physical pages starting at `physical_start` into the process' address space
+    /// at `virtual_start`, taking a reference to each page in `frames`.
+    ///
+    /// The pages will be released when the process exits, and freed if no other mappings refer to them.
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::Memory`] if the pages are not tracked by `frames`.
+    /// - [`Error::PageTables`] if the pages could not be mapped, or overlap a region that is
+    ///   already mapped.
+    ///
+    /// If an error occurs, nothing is mapped and no references to the pages are kept.
+    pub fn map(
+        &self,
+        frames: &PageFrameDatabase,
+        virtual_start: VirtualAddress,
+        physical_start: PhysicalAddress,
+        num_pages: usize,
+        properties: &MemoryProperties,
+    ) -> Result<(), Error> {
+        let mut address_space = self.address_space.lock();
+        let address_space = address_space
+            .as_mut()
+            .context(AlreadyExitedSnafu { id: self.id })?;
+        let page_size = self.page_allocator.page_size();
+        let pages = (0..num_pages).map(|i| physical_start.byte_add(i * page_size));
+        // give back the references taken so far if the pages can't be mapped after all
+        let put_back = |count: usize| {
+            for page in pages.clone().take(count) {
+                // the reference was just taken, so the page is tracked
+                let _ = frames.put(page);
+            }
+        };
+        for (taken, page) in pages.clone().enumerate() {
+            if let Err(e) = frames.get(page) {
+                put_back(taken);
+                return Err(e).context(MemorySnafu);
+            }
+        }
+        let inserted = address_space.insert(
+            Mapping {
+                virtual_start,
+                physical_start,
+                num_pages,
+                properties: properties.clone(),
+                device: false,
+            },
+            page_size,
+        );
+        if inserted.is_err() {
+            put_back(num_pages);
+        }
+        inserted
+    }
+
+    /// Unmap the `num_pages` pages mapped at `source` so they can be moved to another process with
+    /// a message, without copying them.
+    ///
+    /// The region must be exactly a region that was mapped with [`Self::map`]. The returned
+    /// transfer owns this process' references to the pages in the page frame database. The region
+    /// is flushed from the TLB before this returns, so this process can't observe or modify the
+    /// pages after they have been detached.
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::NotMapped`] if `source` is not the start of a region of `num_pages` pages.
+    /// - [`Error::PageTables`] if the pages could not be unmapped.
+    pub fn detach_pages(
+        &self,
+        source: VirtualAddress,
+        num_pages: usize,
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Result<PageTransfer, Error> {
+        let mut address_space = self.address_space.lock();
+        let address_space = address_space
+            .as_mut()
+            .context(AlreadyExitedSnafu { id: self.id })?;
+        let index = address_space
+            .mappings
+            .iter()
+            .position(|m| m.virtual_start == source && m.num_pages == num_pages && !m.device)
+            .context(NotMappedSnafu { address: source })?;
+        let flush = address_space
+            .page_tables
+            .unmap(source, num_pages, MapBlockSize::Page)
+            .context(PageTablesSnafu)?;
+        if let Some(asid) = self.asid.asid() {
+            flush.apply(mmu, asid);
+        }
+        let mapping = address_space.mappings.swap_remove(index);
+        log::trace!(
+            "detached {num_pages} pages at {source:?} from process id={}",
+            self.id
+        );
+        Ok(PageTransfer {
+            physical_start: mapping.physical_start,
+            num_pages,
+        })
+    }
+
+    /// Map pages that were detached from another process into this process at `destination`
+    /// with `properties`, taking ownership of the references to them.
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::PageTables`] if the pages could not be mapped.
+    ///
+    /// If an error occurs the pages are not attached, and the caller still owns the references.
+    pub fn attach_pages(
+        &self,
+        pages: &PageTransfer,
+        destination: VirtualAddress,
+        properties: &MemoryProperties,
+    ) -> Result<(), Error> {
+        self.address_space
+            .lock()
+            .as_mut()
+            .context(AlreadyExitedSnafu { id: self.id })?
+            .insert(
+                Mapping {
+                    virtual_start: destination,
+                    physical_start: pages.physical_start,
+                    num_pages: pages.num_pages,
+                    properties: properties.clone(),
+                    device: false,
+                },
+                self.page_allocator.page_size(),
+            )?;
+        log::trace!(
+            "attached {pages:?} at {destination:?} to process id={}",
+            self.id
+        );
+        Ok(())
+    }
+
+    /// Move the `num_pages` pages mapped at `source` in this process directly to `destination`
+    /// in the address space of `receiver`, mapped with `properties`, without copying them.
+    ///
+    /// See [`Self::detach_pages`] and [`Self::attach_pages`].
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyExited`] if either process has exited.
+    /// - [`Error::NotMapped`] if `source` is not the start of a region of `num_pages` pages.
+    /// - [`Error::PageTables`] if the pages could not be remapped. If the error occurred mapping
+    ///   the pages into the receiver, they are mapped back into this process.
+    pub fn move_pages(
+        &self,
+        receiver: &Process<'pa, PA>,
+        source: VirtualAddress,
+        num_pages: usize,
+        destination: VirtualAddress,
+        properties: &MemoryProperties,
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Result<(), Error> {
+        ensure!(
+            receiver.address_space.lock().is_some(),
+            AlreadyExitedSnafu { id: receiver.id }
+        );
+        let original_properties = self
+            .address_space
+            .lock()
+            .as_ref()
+            .and_then(|a| a.mappings.iter().find(|m| m.virtual_start == source))
+            .map(|m| m.properties.clone())
+            .unwrap_or_default();
+        // only hold one address space lock at a time, so moves in opposite directions can't deadlock
+        let pages = self.detach_pages(source, num_pages, mmu)?;
+        let result = receiver.attach_pages(&pages, destination, properties);
+        if result.is_err() {
+            self.attach_pages(&pages, source, &original_properties)?;
+        }
+        result
+    }
+
+    /// Map the `length` bytes of device MMIO starting at `physical_start` into the process'
+    /// address space at `virtual_start`, claiming the region in `registry` for this process.
+    ///
+    /// Only drivers can map MMIO. The region is mapped as [`MemoryKind::Device`] memory that is
+    /// readable and writable from user space, and it stays claimed until the process exits.
+    ///
+    /// # Errors
+    /// - [`Error::NotDriver`] if the process is not a driver.
+    /// - [`Error::InvalidRegion`] or [`Error::RegionClaimed`] if the region can't be claimed.
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::PageTables`] if the region could not be mapped.
+    pub fn map_device(
+        &self,
+        registry: &MmioRegistry,
+        physical_start: PhysicalAddress,
+        length: usize,
+        virtual_start: VirtualAddress,
+    ) -> Result<(), Error> {
+        ensure!(self.is_driver(), NotDriverSnafu { id: self.id });
+        let mut address_space = self.address_space.lock();
+        let address_space = address_space
+            .as_mut()
+            .context(AlreadyExitedSnafu { id: self.id })?;
+        registry.claim(self.id, physical_start, length)?;
+        let page_size = self.page_allocator.page_size();
+        let result = address_space.insert(
+            Mapping {
+                virtual_start,
+                physical_start,
+                num_pages: length / page_size,
+                properties: MemoryProperties {
+                    kind: MemoryKind::Device,
+                    user_space_access: true,
+                    writable: true,
+                    executable: false,
+                    ..MemoryProperties::default()
+                },
+                device: true,
+            },
+            page_size,
+        );
+        if result.is_err() {
+            registry.release(self.id, physical_start);
+        }
+        result
+    }
+
+    /// Give the device of `space` access to the `num_pages` pages mapped at `virtual_start` in
+    /// the process, at the I/O address `io_start`.
+    ///
+    /// Only drivers can grant DMA access, and only to RAM they have mapped, so a driver can't use
+    /// its device to reach memory that belongs to anyone else. The device can write to the pages
+    /// only if the process can. The pages stay referenced by `space` until the grant is revoked,
+    /// even if the process unmaps them or exits.
+    ///
+    /// # Errors
+    /// - [`Error::NotDriver`] if the process is not a driver.
+    /// - [`Error::AlreadyExited`] if the process has exited.
+    /// - [`Error::NotMapped`] if the pages are not all in one region of RAM mapped into the
+    ///   process.
+    /// - [`Error::Dma`] if the pages could not be mapped for the device.
+    pub fn grant_dma(
+        &self,
+        space: &mut IoAddressSpace<'pa, PA>,
+        frames: &PageFrameDatabase,
+        virtual_start: VirtualAddress,
+        num_pages: usize,
+        io_start: VirtualAddress,
+    ) -> Result<(), Error> {
+        ensure!(self.is_driver(), NotDriverSnafu { id: self.id });
+        let address_space = self.address_space.lock();
+        let address_space = address_space
+            .as_ref()
+            .context(AlreadyExitedSnafu { id: self.id })?;
+        let page_size = usize::from(self.page_allocator.page_size());
+        let start = usize::from(virtual_start);
+        let end = start.saturating_add(num_pages.saturating_mul(page_size));
+        // mappings are physically contiguous, so the region can be granted all at once
+        let mapping = address_space
+            .mappings
+            .iter()
+            .find(|m| {
+                let mapped = usize::from(m.virtual_start);
+                !m.device && start >= mapped && end <= mapped + m.num_pages * page_size
+            })
+            .context(NotMappedSnafu {
+                address: virtual_start,
+            })?;
+        let physical_start = mapping
+            .physical_start
+            .byte_add(start - usize::from(mapping.virtual_start));
+        space
+            .grant(
+                frames,
+                io_start,
+                physical_start,
+                num_pages,
+                mapping.properties.writable,
+            )
+            .context(DmaSnafu)?;
+        log::trace!(
+            "granted stream {} access to {num_pages} pages at {virtual_start:?} of process id={}",
+            space.stream(),
+            self.id
+        );
+        Ok(())
+    }
+
+    /// Read the 32-bit word at `address` in the process' address space, or `None` if it is not
+    /// mapped to RAM. Device MMIO is never read, since reading a register can have side effects.
+    fn read_u32(&self, address: VirtualAddress) -> Option<u32> {
+        let address_space = self.address_space.lock();
+        let physical = address_space
+            .as_ref()?
+            .ram_address_of(address, self.page_allocator.page_size().into())?;
+        let word: *mut u32 = PhysicalPointer::<u32>::from(usize::from(physical)).into();
+        // SAFETY: the word is mapped into the process, and user space may be accessing it
+        // concurrently, so it is only accessed atomically.
+        Some(unsafe { AtomicU32::from_ptr(word) }.load(Ordering::Acquire))
+    }
+
+    /// Call `f` with a kernel pointer to each part of the `length` bytes at `address` in the
+    /// process' address space that lies in a single page, along with the offset of that part.
+    /// If any of the bytes are not mapped to RAM, `f` is never called.
+    ///
+    /// Returns the physical address and length of each part.
+    fn access_memory(
+        &self,
+        address: VirtualAddress,
+        length: usize,
+        mut f: impl FnMut(usize, *mut u8, usize),
+    ) -> Result<Vec<(PhysicalAddress, usize)>, Error> {
+        let address_space = self.address_space.lock();
+        let address_space = address_space
+            .as_ref()
+            .context(AlreadyExitedSnafu { id: self.id })?;
+        let page_size = usize::from(self.page_allocator.page_size());
+        let mut parts = Vec::new();
+        let mut offset = 0;
+        while offset < length {
+            let start = address.byte_add(offset);
+            let part_length = (page_size - usize::from(start) % page_size).min(length - offset);
+            let physical = address_space
+                .ram_address_of(start, page_size)
+                .context(NotMappedSnafu { address: start })?;
+            parts.push((physical, part_length));
+            offset += part_length;
+        }
+        offset = 0;
+        for (physical, part_length) in &parts {
+            let ptr: *mut u8 = PhysicalPointer::<u8>::from(usize::from(*physical)).into();
+            f(offset, ptr, *part_length);
+            offset += part_length;
+        }
+        Ok(parts)
+    }
+
+    /// Read `buffer.len()` bytes at `address` in the process' address space into `buffer`.
+    /// Nothing is read if any of the bytes are not mapped to RAM.
+    fn read_user_memory(&self, address: VirtualAddress, buffer: &mut [u8]) -> Result<(), Error> {
+        self.access_memory(address, buffer.len(), |offset, ptr, length| {
+            for (i, byte) in buffer[offset..offset + length].iter_mut().enumerate() {
+                // SAFETY: the byte is mapped into the process, and user space may be accessing
+                // it concurrently, so it is accessed with volatile reads.
+                *byte = unsafe { ptr.add(i).read_volatile() };
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Write `data` at `address` in the process' address space, even if it is mapped read only.
+    /// Nothing is written if any of the bytes are not mapped to RAM.
+    ///
+    /// Returns the physical address and length of each part written, so that caches can be
+    /// synchronized if the memory holds code.
+    fn write_user_memory(
+        &self,
+        address: VirtualAddress,
+        data: &[u8],
+    ) -> Result<Vec<(PhysicalAddress, usize)>, Error> {
+        self.access_memory(address, data.len(), |offset, ptr, length| {
+            for (i, byte) in data[offset..offset + length].iter().enumerate() {
+                // SAFETY: the byte is mapped into the process, and user space may be accessing
+                // it concurrently, so it is accessed with volatile writes.
+                unsafe { ptr.add(i).write_volatile(*byte) };
+            }
+        })
+    }
+
+    /// Block the current thread (given by `scheduler`) on the futex at `address` in this process
+    /// if it holds `expected`, until it is woken by [`Process::futex_wake`] or the `timeout`
+    /// passes. See [`FutexTable::wait`].
+    ///
+    /// # Errors
+    /// Returns an error if the address is invalid, the futex does not hold `expected`, or the
+    /// current thread's waits have been cancelled.
+    pub fn futex_wait(
+        &self,
+        scheduler: &impl Scheduler,
+        address: VirtualAddress,
+        expected: u32,
+        timeout: Option<Timeout>,
+    ) -> Result<(), futex::Error> {
+        self.futexes
+            .wait(scheduler, address, expected, |a| self.read_u32(a), timeout)
+    }
+
+    /// Wake up to `count` threads waiting on the futex at `address` in this process, returning
+    /// the number woken.
+    pub fn futex_wake(&self, address: VirtualAddress, count: usize) -> usize {
+        self.futexes.wake(address, count)
+    }
+
+    /// The number of pages of RAM mapped into the process, not counting device MMIO.
+    pub fn resident_pages(&self) -> usize {
+        self.address_space
+            .lock()
+            .as_ref()
+            .map_or(0, AddressSpace::resident_pages)
+    }
+
+    /// The total number of counter ticks the threads of this process have spent running.
+    pub fn runtime(&self) -> Ticks {
+        self.exited_runtime.load(Ordering::Relaxed)
+            + self
+                .threads
+                .lock()
+                .iter()
+                .map(|t| t.runtime())
+                .sum::<Ticks>()
+    }
+
+    /// The code this process exited with, or `None` if it is still running.
+    pub fn exit_code(&self) -> Option<ExitCode> {
+        *self.exit_code.lock()
+    }
+
+    /// Tear down the process: every thread is exited and removed from `threads` and `scheduler`,
+    /// every capability is released, and the address space is unmapped. Pages that are no longer referenced by any
+    /// mapping in `frames` are freed, and MMIO regions claimed in `mmio` are released, once the
+    /// process' ASID has been flushed from the TLB.
+    ///
+    /// The process remains a zombie until it is reaped by its supervisor with [`reap`].
+    /// Use [`exit`] to also notify the supervisor.
+    ///
+    /// # Errors
+    /// - [`Error::AlreadyExited`] if the process has already exited.
+    /// - [`Error::PageTables`] or [`Error::Memory`] if the address space could not be torn down.
+    ///   The process has still exited, but some of its memory may have been leaked.
+    pub fn exit(
+        &self,
+        code: ExitCode,
+        threads: &HandleMap<Thread>,
+        scheduler: &impl Scheduler,
+        frames: &PageFrameDatabase,
+        mmio: &MmioRegistry,
+        mmu: &impl MemoryManagmentUnit,
+    ) -> Result<(), Error> {
+        {
+            let mut exit_code = self.exit_code.lock();
+            ensure!(exit_code.is_none(), AlreadyExitedSnafu { id: self.id });
+            *exit_code = Some(code);
+        }
+        log::trace!(
+            "process id={} exiting with code {code} after running for {} ticks",
+            self.id,
+            self.runtime()
+        );
+
+        for thread in self.threads.lock().drain(..) {
+            // end any wait the thread is blocked in, so nothing is left waiting on its behalf
+            thread.cancel_waits();
+            thread.set_state(State::Exited);
+            scheduler.remove_thread(thread.id);
+            self.exited_runtime
+                .fetch_add(thread.runtime(), Ordering::Relaxed);
+            threads.remove(thread.id);
+        }
+        self.capabilities.clear();
+        self.debug.lock().take();
+
+        let Some(mut address_space) = self.address_space.lock().take() else {
+            return Ok(());
+        };
+        let mut to_free = Vec::new();
+        let mut result = Ok(());
+        for mapping in &address_space.mappings {
+            let mut released = Vec::new();
+            let unmapped = address_space.page_tables.unmap_each(
+                mapping.virtual_start,
+                mapping.num_pages,
+                MapBlockSize::Page,
+                |page| match frames.put(page) {
+                    _ if mapping.device => {}
+                    Ok(0) => released.push(page),
+                    Ok(_) => {}
+                    Err(e) => result = Err(e).context(MemorySnafu),
+                },
+            );
+            if let Err(e) = unmapped {
+                result = Err(e).context(PageTablesSnafu);
+            }
+            // free the whole allocation at once if nothing else refers to it
+            if mapping.device {
+                continue;
+            }
+            if released.len() == mapping.num_pages {
+                to_free.push((mapping.physical_start, mapping.num_pages));
+            } else {
+                to_free.extend(released.into_iter().map(|p| (p, 1)));
+            }
+        }
+
+        // the whole address space is going away, so flush it all at once
+        if let Some(asid) = self.asid.asid() {
+            mmu.invalidate_asid(asid);
+        }
+        mmio.release_all(self.id);
+        for (pages, num_pages) in to_free {
+            if let Err(e) = self.page_allocator.free(pages, num_pages) {
+                result = Err(e).context(MemorySnafu);
+            }
+        }
+        result
+    }
+
+    /// Record that the child process `child` has exited with `code`, so it can be reaped.
+    ///
+    /// Returns false if this process has exited itself, so it will never reap the child.
+    fn notify_child_exited(&self, child: Id, code: ExitCode) -> bool {
+        let mut exited = self.exited_children.lock();
+        // an exiting supervisor sets its exit code before it forgets its exited children, so
+        // checking with their lock held means that no child is left behind
+        if self.exit_code().is_some() {
+            return false;
+        }
+        exited.push((child, code));
+        true
+    }
+}
+
+impl<PA: PageAllocator> fmt::Display for Process<'_, PA> {
+    /// Shows the process' ID, followed by its name in parentheses if it has one.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        name::write_labelled(f, self.id, self.name())
+    }
+}
+
+/// Exit the process `id` with `code` (see [`Process::exit`]) and notify its supervisor.
+///
+/// If the process has no supervisor (or the supervisor no longer exists or has exited), nothing
+/// can reap the process, so it is removed from `processes` immediately. The same goes for the
+/// children of the process that have exited but were not reaped. If the process was debugging
+/// other processes, it is detached from them.
+///
+/// # Errors
+/// - [`Error::UnknownProcess`] if there is no process `id`.
+/// - Any error from [`Process::exit`]. The supervisor is still notified if the process has exited.
+#[allow(clippy::too_many_arguments)]
+pub fn exit<PA: PageAllocator>(
+    processes: &HandleMap<Process<'_, PA>>,
+    threads: &HandleMap<Thread>,
+    scheduler: &impl Scheduler,
+    frames: &PageFrameDatabase,
+    mmio: &MmioRegistry,
+    mmu: &impl MemoryManagmentUnit,
+    id: Id,
+    code: ExitCode,
+) -> Result<(), Error> {
+    let process = processes.get(id).context(UnknownProcessSnafu { id })?;
+    let result = process.exit(code, threads, scheduler, frames, mmio, mmu);
+    if matches!(result, Err(Error::AlreadyExited { .. })) {
+        return result;
+    }
+    let notified = process
+        .supervisor
+        .and_then(|s| processes.get(s))
+        .is_some_and(|supervisor| supervisor.notify_child_exited(id, code));
+    if !notified {
+        processes.remove(id);
+    }
+    for (child, _) in process.exited_children.lock().drain(..) {
+        log::trace!("reaped orphaned process id={child}");
+        processes.remove(child);
+    }
+    for (_, target) in processes {
+        if target.debugger() == Some(id) {
+            if let Err(e) = target.detach_debugger(id, mmu) {
+                log::warn!(
+                    "error detaching process {id} from process {}: {e}",
+                    target.id
+                );
+            }
+        }
+    }
+    result
+}
+
+/// Kill the running process with the most pages mapped to free memory, as the last resort of an
+/// [`OomPolicy`](crate::memory::OomPolicy). Drivers are never killed.
+///
+/// The process exits with [`OUT_OF_MEMORY_EXIT_CODE`]. Returns the number of pages it had mapped,
+/// which may be more than are actually freed if some of them are shared.
+///
+/// This runs wherever an allocation fails, so processes whose address space is locked are passed
+/// over: the lock may be held by the allocating code, and exiting would wait on it forever.
+pub fn oom_kill<PA: PageAllocator>(
+    processes: &HandleMap<Process<'_, PA>>,
+    threads: &HandleMap<Thread>,
+    scheduler: &impl Scheduler,
+    frames: &PageFrameDatabase,
+    mmio: &MmioRegistry,
+    mmu: &impl MemoryManagmentUnit,
+) -> usize {
+    let victim = processes
+        .iter()
+        .filter(|(_, p)| !p.is_driver() && p.exit_code().is_none())
+        .filter_map(|(_, p)| {
+            let pages = p
+                .address_space
+                .try_lock()?
+                .as_ref()
+                .map_or(0, AddressSpace::resident_pages);
+            Some((pages, p))
+        })
+        .max_by_key(|(pages, _)| *pages);
+    let Some((pages, victim)) = victim else {
+        log::error!("out of memory, but there is no process that can be killed");
+        return 0;
+    };
+    log::error!(
+        "out of memory, killing process {} to free {pages} pages",
+        victim.id
+    );
+    if let Err(e) = exit(
+        processes,
+        threads,
+        scheduler,
+        frames,
+        mmio,
+        mmu,
+        victim.id,
+        OUT_OF_MEMORY_EXIT_CODE,
+    ) {
+        log::error!("error killing process {}: {e}", victim.id);
+    }
+    pages
+}
+
+/// Reap the oldest exited child of `supervisor`, removing it from `processes`.
+///
+/// Returns the ID and exit code of the child, or `None` if no children have exited since they
+/// were last reaped.
+pub fn reap<PA: PageAllocator>(
+    processes: &HandleMap<Process<'_, PA>>,
+    supervisor: &Process<'_, PA>,
+) -> Option<(Id, ExitCode)> {
+    let mut exited = supervisor.exited_children.lock();
+    if exited.is_empty() {
+        return None;
+    }
+    let (id, code) = exited.remove(0);
+    processes.remove(id);
+    log::trace!("reaped process id={id}");
+    Some((id, code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::{iommu::MockIoMmu, tests::MockPageAllocator, AddressSpaceId},
+        process::thread::{MockScheduler, ProcessorState, MAX_THREAD_ID},
+    };
+
+    /// A scheduler that the threads of exiting processes can be removed from.
+    pub(super) fn descheduler() -> MockScheduler {
+        let mut sched = MockScheduler::new();
+        sched.expect_remove_thread().return_const(());
+        sched
+    }
+
+    #[derive(Default)]
+    pub(super) struct RecordingMmu {
+        asids: std::cell::RefCell<std::vec::Vec<AddressSpaceId>>,
+        ranges: std::cell::RefCell<std::vec::Vec<(Option<AddressSpaceId>, VirtualAddress, usize)>>,
+        pub(super) synchronized: std::cell::RefCell<std::vec::Vec<(PhysicalAddress, usize)>>,
+    }
+
+    impl MemoryManagmentUnit for RecordingMmu {
+        unsafe fn activate_page_tables<PA: PageAllocator>(
+            &self,
+            _tables: &PageTables<'_, PA>,
+            _asid: AddressSpaceId,
+        ) {
+        }
+
+        fn invalidate_asid(&self, asid: AddressSpaceId) {
+            self.asids.borrow_mut().push(asid);
+        }
+
+        fn invalidate_va_range(
+            &self,
+            asid: Option<AddressSpaceId>,
+            start: VirtualAddress,
+            length: usize,
+        ) {
+            self.ranges.borrow_mut().push((asid, start, length));
+        }
+
+        fn invalidate_all(&self) {}
+
+        fn synchronize_tables(&self) {}
+
+        fn synchronize_instruction_cache(&self, start: PhysicalAddress, length: usize) {
+            self.synchronized.borrow_mut().push((start, length));
+        }
+    }
+
+    pub(super) fn new_process<'pa>(
+        processes: &HandleMap<Process<'pa, MockPageAllocator>>,
+        pa: &'pa MockPageAllocator,
+        supervisor: Option<Id>,
+    ) -> Arc<Process<'pa, MockPageAllocator>> {
+        Process::new(
+            processes,
+            Name::EMPTY,
+            supervisor,
+            Privilege::Unprivileged,
+            pa,
+            PageTables::empty(pa).unwrap(),
+        )
+    }
+
+    #[test]
+    fn process_owns_loaded_image() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let time_page = pa.allocate(1).unwrap();
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let elf = loader::tests::build_elf(0x40_0000, &[(0x40_0000, 0b101, &[1; 8], 8)]);
+        let image = loader::ElfImage::parse(&elf).unwrap();
+        let load = || loader::load_image(&pa, &image, 2, time_page).unwrap();
+
+        // the image is freed if its pages can't be tracked
+        let untracked = PageFrameDatabase::new(PageSize::FourKiB, core::iter::empty());
+        assert!(matches!(
+            Process::from_image(
+                &processes,
+                Name::EMPTY,
+                None,
+                Privilege::Unprivileged,
+                &untracked,
+                load()
+            ),
+            Err(Error::Memory { .. })
+        ));
+
+        let loaded = load();
+        let allocations = loaded.allocations().to_vec();
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            allocations
+                .iter()
+                .map(|a| (a.physical_start, a.num_pages * PageSize::FourKiB)),
+        );
+        let (proc, state) = Process::from_image(
+            &processes,
+            Name::EMPTY,
+            None,
+            Privilege::Unprivileged,
+            &frames,
+            loaded,
+        )
+        .unwrap();
+        assert_eq!(state.program_counter, VirtualAddress::from(0x40_0000));
+        for a in &allocations {
+            assert_eq!(frames.frame(a.physical_start).unwrap().ref_count(), 1);
+        }
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, proc.id, 0,
+        )
+        .unwrap();
+        for a in &allocations {
+            assert_eq!(frames.frame(a.physical_start).unwrap().ref_count(), 0);
+        }
+        pa.free(time_page, 1).unwrap();
+        drop((proc, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn exit_frees_memory_and_threads() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let pages = pa.allocate(4).unwrap();
+        let shared = pa.allocate(1).unwrap();
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [
+                (pages, 4 * PageSize::FourKiB),
+                (shared, PageSize::FourKiB.into()),
+            ]
+            .into_iter(),
+        );
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let mut sched = MockScheduler::new();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+
+        let proc = new_process(&processes, &pa, None);
+        let props = MemoryProperties::default();
+        proc.map(&frames, VirtualAddress::from(0x1000), pages, 4, &props)
+            .unwrap();
+        proc.map(&frames, VirtualAddress::from(0x8000), shared, 1, &props)
+            .unwrap();
+        // another address space also refers to the shared page
+        frames.get(shared).unwrap();
+
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        proc.add_thread(thread.clone());
+        sched
+            .expect_remove_thread()
+            .with(mockall::predicate::eq(thread.id))
+            .times(1)
+            .return_const(());
+        assert_eq!(thread.process(), Some(proc.id));
+        thread.start_running(10);
+        thread.stop_running(25);
+        assert_eq!(proc.runtime(), 15);
+        let pool = AddressSpaceIdPool::new(8, 1);
+        assert_eq!(unsafe { proc.activate(&pool, 0, &mmu) }.unwrap().asid, 1);
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, proc.id, 7,
+        )
+        .unwrap();
+
+        assert_eq!(thread.state(), State::Exited);
+        assert_eq!(proc.runtime(), 15);
+        assert!(threads.get(thread.id).is_none());
+        assert_eq!(*mmu.asids.borrow(), [1]);
+        // an exited process has no address space to activate
+        assert!(unsafe { proc.activate(&pool, 0, &mmu) }.is_none());
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 0);
+        assert_eq!(frames.frame(shared).unwrap().ref_count(), 1);
+        assert_eq!(proc.exit_code(), Some(7));
+        // no supervisor, so it has been removed right away
+        assert!(processes.get(proc.id).is_none());
+        assert!(matches!(
+            proc.exit(1, &threads, &sched, &frames, &mmio, &mmu),
+            Err(Error::AlreadyExited { .. })
+        ));
+
+        assert_eq!(frames.put(shared).unwrap(), 0);
+        pa.free(shared, 1).unwrap();
+        drop((proc, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn failed_map_keeps_nothing() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let pages = pa.allocate(4).unwrap();
+        // only the first two pages are tracked
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [(pages, 2 * PageSize::FourKiB)].into_iter(),
+        );
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let proc = new_process(&processes, &pa, None);
+        let props = MemoryProperties::default();
+        let va = VirtualAddress::from(0x1000);
+
+        assert!(matches!(
+            proc.map(&frames, va, pages, 4, &props),
+            Err(Error::Memory { .. })
+        ));
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 0);
+        assert_eq!(proc.resident_pages(), 0);
+
+        proc.map(&frames, va, pages, 2, &props).unwrap();
+        // overlapping regions are refused without touching the existing one
+        assert!(matches!(
+            proc.map(&frames, va.byte_add(0x1000), pages, 1, &props),
+            Err(Error::PageTables { .. })
+        ));
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 1);
+        assert_eq!(physical_address_of(&proc, va), Some(pages));
+
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let mmu = RecordingMmu::default();
+        // keep the pages, which were allocated together, from being freed separately
+        frames.get(pages).unwrap();
+        frames.get(pages.byte_add(0x1000)).unwrap();
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, proc.id, 0,
+        )
+        .unwrap();
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 1);
+    }
+
+    fn physical_address_of(
+        process: &Process<'_, MockPageAllocator>,
+        address: VirtualAddress,
+    ) -> Option<PhysicalAddress> {
+        process
+            .address_space
+            .lock()
+            .as_ref()
+            .and_then(|a| a.page_tables.physical_address_of(address))
+    }
+
+    #[test]
+    fn move_pages_between_processes() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let pages = pa.allocate(2).unwrap();
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [(pages, 2 * usize::from(PageSize::FourKiB))].into_iter(),
+        );
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+
+        let sender = new_process(&processes, &pa, None);
+        let receiver = new_process(&processes, &pa, None);
+        let pool = AddressSpaceIdPool::new(8, 1);
+        unsafe { sender.activate(&pool, 0, &mmu) }.unwrap();
+        let props = MemoryProperties {
+            user_space_access: true,
+            writable: true,
+            ..MemoryProperties::default()
+        };
+        let source = VirtualAddress::from(0x10_0000);
+        let destination = VirtualAddress::from(0x20_0000);
+        sender.map(&frames, source, pages, 2, &props).unwrap();
+
+        assert!(matches!(
+            sender.move_pages(&receiver, source, 1, destination, &props, &mmu),
+            Err(Error::NotMapped { .. })
+        ));
+        sender
+            .move_pages(&receiver, source, 2, destination, &props, &mmu)
+            .unwrap();
+
+        assert!(physical_address_of(&sender, source).is_none());
+        assert_eq!(
+            physical_address_of(&receiver, destination.byte_add(0x1000)),
+            Some(pages.byte_add(0x1000))
+        );
+        assert_eq!(*mmu.ranges.borrow(), [(Some(1), source, 0x2000)]);
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 1);
+
+        // the sender no longer owns the pages, so they are freed when the receiver exits
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, sender.id, 0,
+        )
+        .unwrap();
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 1);
+        assert!(matches!(
+            receiver.move_pages(&sender, destination, 2, source, &props, &mmu),
+            Err(Error::AlreadyExited { .. })
+        ));
+
+        // pages can also be detached to be sent with a message, and attached somewhere else
+        let transfer = receiver.detach_pages(destination, 2, &mmu).unwrap();
+        assert!(physical_address_of(&receiver, destination).is_none());
+        assert!(matches!(
+            sender.attach_pages(&transfer, destination, &props),
+            Err(Error::AlreadyExited { .. })
+        ));
+        receiver.attach_pages(&transfer, source, &props).unwrap();
+        assert_eq!(physical_address_of(&receiver, source), Some(pages));
+        exit(
+            &processes,
+            &threads,
+            &sched,
+            &frames,
+            &mmio,
+            &mmu,
+            receiver.id,
+            0,
+        )
+        .unwrap();
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 0);
+
+        drop((sender, receiver, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn drivers_map_mmio() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let frames = PageFrameDatabase::new(PageSize::FourKiB, core::iter::empty());
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(
+            PageSize::FourKiB,
+            [(PhysicalAddress::from(0x4000_0000), 0x1000_0000)].into_iter(),
+        );
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+
+        let driver = Process::new(
+            &processes,
+            Name::new("driver"),
+            None,
+            Privilege::Driver,
+            &pa,
+            PageTables::empty(&pa).unwrap(),
+        );
+        let other = new_process(&processes, &pa, None);
+        let uart = PhysicalAddress::from(0x0900_0000);
+        let va = VirtualAddress::from(0x10_0000);
+
+        assert!(matches!(
+            other.map_device(&mmio, uart, 0x1000, va),
+            Err(Error::NotDriver { .. })
+        ));
+        assert!(matches!(
+            driver.map_device(&mmio, PhysicalAddress::from(0x4000_0000), 0x1000, va),
+            Err(Error::InvalidRegion { .. })
+        ));
+        driver.map_device(&mmio, uart, 0x2000, va).unwrap();
+        assert_eq!(
+            physical_address_of(&driver, va.byte_add(0x1000)),
+            Some(uart.byte_add(0x1000))
+        );
+        assert_eq!(mmio.owner_of(uart), Some(driver.id));
+        // device memory can't be moved to other processes
+        assert!(matches!(
+            driver.detach_pages(va, 2, &mmu),
+            Err(Error::NotMapped { .. })
+        ));
+        // or accessed by the kernel on the process' behalf
+        assert!(matches!(
+            driver.read_user_memory(va, &mut [0; 4]),
+            Err(Error::NotMapped { .. })
+        ));
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, driver.id, 0,
+        )
+        .unwrap();
+        assert_eq!(mmio.owner_of(uart), None);
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, other.id, 0,
+        )
+        .unwrap();
+
+        drop((driver, other, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn drivers_grant_dma() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let pages = pa.allocate(4).unwrap();
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [(pages, 4 * PageSize::FourKiB)].into_iter(),
+        );
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let mut iommu = MockIoMmu::new();
+        iommu.expect_attach().returning(|_, _, _| Ok(()));
+        iommu.expect_invalidate_asid().returning(|_| Ok(()));
+        iommu.expect_detach().times(1).returning(|_| Ok(()));
+
+        let driver = Process::new(
+            &processes,
+            Name::new("driver"),
+            None,
+            Privilege::Driver,
+            &pa,
+            PageTables::empty(&pa).unwrap(),
+        );
+        let other = new_process(&processes, &pa, None);
+        let va = VirtualAddress::from(0x10_0000);
+        let props = MemoryProperties {
+            user_space_access: true,
+            writable: true,
+            ..MemoryProperties::default()
+        };
+        driver.map(&frames, va, pages, 4, &props).unwrap();
+
+        let mut space = IoAddressSpace::new(&pa, &iommu, 5, 9).unwrap();
+        let io = VirtualAddress::from(0x8000_0000);
+        assert!(matches!(
+            other.grant_dma(&mut space, &frames, va, 1, io),
+            Err(Error::NotDriver { .. })
+        ));
+        // only memory the driver has mapped can be granted
+        assert!(matches!(
+            driver.grant_dma(&mut space, &frames, va.byte_add(0x2000), 4, io),
+            Err(Error::NotMapped { .. })
+        ));
+        driver.grant_dma(&mut space, &frames, va, 4, io).unwrap();
+        assert_eq!(
+            space.translate(io.byte_add(0x1000)),
+            Some(pages.byte_add(0x1000))
+        );
+        assert_eq!(space.translate(io.byte_add(0x4000)), None);
+        assert!(matches!(
+            driver.grant_dma(&mut space, &frames, va, 1, io),
+            Err(Error::Dma { .. })
+        ));
+
+        // the device keeps the pages alive after the driver has exited
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, driver.id, 0,
+        )
+        .unwrap();
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 1);
+        assert!(matches!(
+            driver.grant_dma(&mut space, &frames, va, 1, io),
+            Err(Error::AlreadyExited { .. })
+        ));
+        // releasing the device's address space frees the pages
+        space.release(&frames, &iommu).unwrap();
+        assert_eq!(frames.frame(pages).unwrap().ref_count(), 0);
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, other.id, 0,
+        )
+        .unwrap();
+        drop((driver, other, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn oom_kill_chooses_largest_non_driver() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 64);
+        let small_pages = pa.allocate(1).unwrap();
+        let large_pages = pa.allocate(8).unwrap();
+        let driver_pages = pa.allocate(16).unwrap();
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [
+                (small_pages, usize::from(PageSize::FourKiB)),
+                (large_pages, 8 * PageSize::FourKiB),
+                (driver_pages, 16 * PageSize::FourKiB),
+            ]
+            .into_iter(),
+        );
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+
+        let props = MemoryProperties::default();
+        let va = VirtualAddress::from(0x1000);
+        let small = new_process(&processes, &pa, None);
+        small.map(&frames, va, small_pages, 1, &props).unwrap();
+        let large = new_process(&processes, &pa, Some(small.id));
+        large.map(&frames, va, large_pages, 8, &props).unwrap();
+        let driver = Process::new(
+            &processes,
+            Name::new("driver"),
+            None,
+            Privilege::Driver,
+            &pa,
+            PageTables::empty(&pa).unwrap(),
+        );
+        driver.map(&frames, va, driver_pages, 16, &props).unwrap();
+        assert_eq!(large.resident_pages(), 8);
+
+        assert_eq!(
+            oom_kill(&processes, &threads, &sched, &frames, &mmio, &mmu),
+            8
+        );
+        assert_eq!(large.exit_code(), Some(OUT_OF_MEMORY_EXIT_CODE));
+        assert_eq!(large.resident_pages(), 0);
+        assert_eq!(
+            reap(&processes, &small),
+            Some((large.id, OUT_OF_MEMORY_EXIT_CODE))
+        );
+        assert_eq!(
+            oom_kill(&processes, &threads, &sched, &frames, &mmio, &mmu),
+            1
+        );
+        assert_eq!(
+            oom_kill(&processes, &threads, &sched, &frames, &mmio, &mmu),
+            0
+        );
+        assert_eq!(driver.exit_code(), None);
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, driver.id, 0,
+        )
+        .unwrap();
+        drop((small, large, driver, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn oom_kill_passes_over_locked_address_spaces() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let frames = PageFrameDatabase::new(PageSize::FourKiB, core::iter::empty());
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let proc = new_process(&processes, &pa, None);
+
+        let held = proc.address_space.lock();
+        oom_kill(&processes, &threads, &sched, &frames, &mmio, &mmu);
+        assert_eq!(proc.exit_code(), None);
+        drop(held);
+
+        oom_kill(&processes, &threads, &sched, &frames, &mmio, &mmu);
+        assert_eq!(proc.exit_code(), Some(OUT_OF_MEMORY_EXIT_CODE));
+        drop((proc, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn supervisor_reaps_children() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let frames = PageFrameDatabase::new(PageSize::FourKiB, core::iter::empty());
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+
+        let parent = new_process(&processes, &pa, None);
+        let a = new_process(&processes, &pa, Some(parent.id));
+        let b = new_process(&processes, &pa, Some(parent.id));
+        let (a_id, b_id) = (a.id, b.id);
+        drop((a, b));
+
+        assert_eq!(reap(&processes, &parent), None);
+        exit(&processes, &threads, &sched, &frames, &mmio, &mmu, b_id, 2).unwrap();
+        exit(&processes, &threads, &sched, &frames, &mmio, &mmu, a_id, 1).unwrap();
+        // zombies stay around until they are reaped
+        assert_eq!(processes.get(b_id).unwrap().exit_code(), Some(2));
+
+        assert_eq!(reap(&processes, &parent), Some((b_id, 2)));
+        assert!(processes.get(b_id).is_none());
+        assert!(processes.get(a_id).is_some());
+        assert_eq!(reap(&processes, &parent), Some((a_id, 1)));
+        assert_eq!(reap(&processes, &parent), None);
+        assert!(matches!(
+            exit(&processes, &threads, &sched, &frames, &mmio, &mmu, a_id, 0),
+            Err(Error::UnknownProcess { .. })
+        ));
+
+        exit(
+            &processes, &threads, &sched, &frames, &mmio, &mmu, parent.id, 0,
+        )
+        .unwrap();
+        drop((parent, processes));
+        pa.end_check();
+    }
+
+    #[test]
+    fn orphans_are_reaped() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let frames = PageFrameDatabase::new(PageSize::FourKiB, core::iter::empty());
+        let mmu = RecordingMmu::default();
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = descheduler();
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let exit = |id| exit(&processes, &threads, &sched, &frames, &mmio, &mmu, id, 0);
+
+        let root = new_process(&processes, &pa, None);
+        let parent = new_process(&processes, &pa, Some(root.id));
+        let zombie = new_process(&processes, &pa, Some(parent.id)).id;
+        let running = new_process(&processes, &pa, Some(parent.id)).id;
+
+        exit(zombie).unwrap();
+        assert!(processes.get(zombie).is_some());
+        // the parent will never reap its children once it has exited
+        exit(parent.id).unwrap();
+        assert!(processes.get(zombie).is_none());
+        assert!(processes.get(parent.id).is_some());
+        exit(running).unwrap();
+        assert!(processes.get(running).is_none());
+
+        assert_eq!(reap(&processes, &root), Some((parent.id, 0)));
+        exit(root.id).unwrap();
+        drop((root, parent, processes));
+        pa.end_check();
+    }
+}
@@ -0,0 +1,132 @@
+//! Short names for threads and processes, so that logs stay readable with many of them.
+use core::fmt;
+use snafu::{ensure, ResultExt as _, Snafu};
+
+/// Errors that can occur making a name from bytes supplied by user space.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The name is longer than [`Name::CAPACITY`] bytes.
+    #[snafu(display("name of {length} bytes is too long"))]
+    TooLong {
+        /// The length of the name in bytes.
+        length: usize,
+    },
+    /// The name is not valid UTF-8.
+    NotUtf8 {
+        /// Underlying error.
+        source: core::str::Utf8Error,
+    },
+}
+
+/// A short UTF-8 name, stored inline so that naming a thread never allocates.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Name {
+    len: u8,
+    bytes: [u8; Name::CAPACITY],
+}
+
+impl Name {
+    /// The maximum length of a name in bytes.
+    pub const CAPACITY: usize = 31;
+
+    /// The empty name, for threads and processes that have not been named.
+    pub const EMPTY: Self = Self {
+        len: 0,
+        bytes: [0; Self::CAPACITY],
+    };
+
+    /// Create a name from `name`, truncated to at most [`Self::CAPACITY`] bytes at a character
+    /// boundary.
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        let mut len = name.len().min(Self::CAPACITY);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; Self::CAPACITY];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        #[allow(clippy::cast_possible_truncation)] // at most CAPACITY
+        Self {
+            len: len as u8,
+            bytes,
+        }
+    }
+
+    /// The name as a string.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // only ever filled from a `str`, cut at a character boundary
+        core::str::from_utf8(&self.bytes[..usize::from(self.len)]).unwrap_or_default()
+    }
+
+    /// Returns true if the name is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl TryFrom<&[u8]> for Name {
+    type Error = Error;
+
+    /// Check a name supplied by user space, which is not truncated.
+    fn try_from(bytes: &[u8]) -> Result<Self, Error> {
+        ensure!(
+            bytes.len() <= Self::CAPACITY,
+            TooLongSnafu {
+                length: bytes.len()
+            }
+        );
+        Ok(Self::new(
+            core::str::from_utf8(bytes).context(NotUtf8Snafu)?,
+        ))
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Write `id`, followed by `name` in parentheses if it is not empty.
+pub(crate) fn write_labelled(f: &mut fmt::Formatter<'_>, id: u32, name: Name) -> fmt::Result {
+    if name.is_empty() {
+        write!(f, "{id}")
+    } else {
+        write!(f, "{id} ({name})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_truncated_at_characters() {
+        assert_eq!(Name::new("idle").as_str(), "idle");
+        assert!(Name::new("").is_empty());
+        assert_eq!(Name::EMPTY, Name::default());
+        let long = "a-very-long-thread-name-for-the-block-driver";
+        assert_eq!(Name::new(long).as_str(), &long[..Name::CAPACITY]);
+        // a multi-byte character that would be cut in half is dropped
+        let accented = "é".repeat(16);
+        assert_eq!(Name::new(&accented).as_str(), "é".repeat(15));
+
+        assert_eq!(Name::try_from(&b"init"[..]).unwrap().as_str(), "init");
+        assert!(matches!(
+            Name::try_from(long.as_bytes()),
+            Err(Error::TooLong { length: 44 })
+        ));
+        assert!(matches!(
+            Name::try_from(&[0xff, 0xfe][..]),
+            Err(Error::NotUtf8 { .. })
+        ));
+    }
+}
@@ -0,0 +1,445 @@
+//! Name registries, through which supervisors publish services to the processes in their scope.
+//!
+//! A [`NameRegistry`] binds names to capabilities. Each registry belongs to a supervisor scope
+//! (see [`policy`]), and can only be used by processes in the subtree of that supervisor. A
+//! supervisor starts a registry for its own subtree with [`create_scope`]. Names bound there shadow
+//! the names of the registry it was created from, and every other name resolves in that registry,
+//! so a supervisor decides which services the processes it supervises can discover.
+//!
+//! The rights of a handle to a registry decide what can be done with it:
+//! - [`Rights::READ`] resolves names with [`resolve`].
+//! - [`Rights::WRITE`] binds and unbinds names with [`bind`] and [`unbind`].
+//! - [`Rights::MANAGE`] starts a new scope with [`create_scope`].
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use snafu::{ensure, OptionExt as _, ResultExt as _, Snafu};
+
+use super::{
+    caps::{self, CapabilityHandle, KernelObject, Rights},
+    policy, Id, Process,
+};
+use crate::{collections::HandleMap, memory::PageAllocator, sync::Mutex};
+
+/// The maximum length of a name in bytes.
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// The maximum number of supervisors between a process and the scope of a registry it uses.
+const MAX_SCOPE_DEPTH: usize = 64;
+
+/// Errors that can occur using a name registry.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The name is empty or longer than [`MAX_NAME_LENGTH`].
+    #[snafu(display("invalid name of {length} bytes"))]
+    InvalidName {
+        /// The length of the name in bytes.
+        length: usize,
+    },
+    /// The name is already bound in the registry.
+    AlreadyBound,
+    /// The name is not bound in the registry or the registries it falls back to.
+    NotFound,
+    /// The process is not in the subtree of the registry's supervisor.
+    #[snafu(display("process {process} is outside the scope of supervisor {scope:?}"))]
+    OutOfScope {
+        /// The process that used the registry.
+        process: Id,
+        /// The supervisor of the registry's scope, or `None` for the top level scope.
+        scope: Option<Id>,
+    },
+    /// Registries can't be bound to names, since a registry bound in itself would never be freed.
+    CannotBindRegistry,
+    /// A handle could not be resolved, or the capability table is full.
+    Capability {
+        /// Underlying error.
+        source: caps::Error,
+    },
+    /// The security model does not allow the process to receive the bound capability.
+    Policy {
+        /// Underlying error.
+        source: policy::Error,
+    },
+}
+
+/// A capability bound to a name, which is copied into the table of each process that resolves it.
+struct Binding<'pa, PA: PageAllocator> {
+    object: KernelObject<'pa, PA>,
+    rights: Rights,
+}
+
+/// A set of names bound to capabilities, for the processes in a supervisor's subtree.
+pub struct NameRegistry<'pa, PA: PageAllocator> {
+    scope: Option<Id>,
+    parent: Option<Arc<NameRegistry<'pa, PA>>>,
+    names: Mutex<BTreeMap<Vec<u8>, Binding<'pa, PA>>>,
+}
+
+impl<'pa, PA: PageAllocator> NameRegistry<'pa, PA> {
+    /// Create the registry for the top level scope, which every process can use.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            scope: None,
+            parent: None,
+            names: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The supervisor of this registry's scope, or `None` for the top level scope.
+    pub fn scope(&self) -> Option<Id> {
+        self.scope
+    }
+
+    /// Returns true if `process` is in the subtree of this registry's supervisor.
+    ///
+    /// Supervisors are looked up in `processes`, so processes whose supervisor no longer exists are
+    /// only in the top level scope and their own.
+    pub fn in_scope(
+        &self,
+        processes: &HandleMap<Process<'pa, PA>>,
+        process: &Process<'pa, PA>,
+    ) -> bool {
+        let Some(scope) = self.scope else {
+            return true;
+        };
+        let mut current = process.id;
+        let mut supervisor = process.supervisor;
+        for _ in 0..MAX_SCOPE_DEPTH {
+            if current == scope {
+                return true;
+            }
+            let Some(s) = supervisor.and_then(|s| processes.get(s)) else {
+                return false;
+            };
+            current = s.id;
+            supervisor = s.supervisor;
+        }
+        false
+    }
+
+    /// Bind `name` to `object` in this registry. Resolving the name grants `rights` to the object.
+    ///
+    /// # Errors
+    /// - [`Error::InvalidName`] if the name is empty or too long.
+    /// - [`Error::CannotBindRegistry`] if the object is a name registry.
+    /// - [`Error::AlreadyBound`] if the name is already bound in this registry. Names bound in the
+    ///   registries this one falls back to can be shadowed.
+    pub fn bind(
+        &self,
+        name: &[u8],
+        object: KernelObject<'pa, PA>,
+        rights: Rights,
+    ) -> Result<(), Error> {
+        ensure!(
+            (1..=MAX_NAME_LENGTH).contains(&name.len()),
+            InvalidNameSnafu { length: name.len() }
+        );
+        ensure!(
+            !matches!(object, KernelObject::NameRegistry(_)),
+            CannotBindRegistrySnafu
+        );
+        let mut names = self.names.lock();
+        ensure!(!names.contains_key(name), AlreadyBoundSnafu);
+        names.insert(name.to_vec(), Binding { object, rights });
+        Ok(())
+    }
+
+    /// Remove the binding for `name` from this registry.
+    ///
+    /// # Errors
+    /// - [`Error::NotFound`] if the name is not bound in this registry.
+    pub fn unbind(&self, name: &[u8]) -> Result<(), Error> {
+        self.names
+            .lock()
+            .remove(name)
+            .map(|_| ())
+            .context(NotFoundSnafu)
+    }
+
+    /// Look up `name` in this registry, and then in the registries it falls back to, returning the
+    /// bound object and the rights to grant to it.
+    ///
+    /// # Errors
+    /// - [`Error::NotFound`] if the name is not bound in any of the registries.
+    pub fn lookup(&self, name: &[u8]) -> Result<(KernelObject<'pa, PA>, Rights), Error> {
+        let mut registry = self;
+        loop {
+            if let Some(binding) = registry.names.lock().get(name) {
+                return Ok((binding.object.clone(), binding.rights));
+            }
+            registry = registry.parent.as_deref().context(NotFoundSnafu)?;
+        }
+    }
+}
+
+impl<PA: PageAllocator> Default for NameRegistry<'_, PA> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resolve `handle` in the table of `process` to a name registry that grants `required`, checking
+/// that the process is in the registry's scope.
+fn registry<'pa, PA: PageAllocator>(
+    processes: &HandleMap<Process<'pa, PA>>,
+    process: &Process<'pa, PA>,
+    handle: CapabilityHandle,
+    required: Rights,
+) -> Result<Arc<NameRegistry<'pa, PA>>, Error> {
+    let registry = process
+        .capabilities
+        .name_registry(handle, required)
+        .context(CapabilitySnafu)?;
+    ensure!(
+        registry.in_scope(processes, process),
+        OutOfScopeSnafu {
+            process: process.id,
+            scope: registry.scope
+        }
+    );
+    Ok(registry)
+}
+
+/// Bind `name` in the registry `registry` to the object of the capability `object`, both handles in
+/// the table of `process`.
+///
+/// The registry handle must have the [`Rights::WRITE`] right. Binding copies the object's
+/// capability, so it must have the [`Rights::DUPLICATE`] right, and processes that resolve the name
+/// receive the same rights.
+///
+/// # Errors
+/// - [`Error::Capability`] if a handle is invalid or lacks the required rights.
+/// - [`Error::OutOfScope`] if the process is not in the registry's scope.
+/// - Any error from [`NameRegistry::bind`].
+pub fn bind<'pa, PA: PageAllocator>(
+    processes: &HandleMap<Process<'pa, PA>>,
+    process: &Process<'pa, PA>,
+    registry_handle: CapabilityHandle,
+    name: &[u8],
+    object: CapabilityHandle,
+) -> Result<(), Error> {
+    let registry = registry(processes, process, registry_handle, Rights::WRITE)?;
+    let cap = process
+        .capabilities
+        .get(object, Rights::DUPLICATE)
+        .context(CapabilitySnafu)?;
+    registry.bind(name, cap.object.clone(), cap.rights)
+}
+
+/// Remove the binding for `name` from the registry `registry`, a handle in the table of `process`
+/// with the [`Rights::WRITE`] right.
+///
+/// # Errors
+/// - [`Error::Capability`] if the handle is invalid or lacks the right.
+/// - [`Error::OutOfScope`] if the process is not in the registry's scope.
+/// - [`Error::NotFound`] if the name is not bound in the registry.
+pub fn unbind<'pa, PA: PageAllocator>(
+    processes: &HandleMap<Process<'pa, PA>>,
+    process: &Process<'pa, PA>,
+    registry_handle: CapabilityHandle,
+    name: &[u8],
+) -> Result<(), Error> {
+    registry(processes, process, registry_handle, Rights::WRITE)?.unbind(name)
+}
+
+/// Resolve `name` in the registry `registry`, a handle in the table of `process` with the
+/// [`Rights::READ`] right, returning a new handle in the process' table to the bound object.
+///
+/// # Errors
+/// - [`Error::Capability`] if the handle is invalid or lacks the right, or the table is full.
+/// - [`Error::OutOfScope`] if the process is not in the registry's scope.
+/// - [`Error::NotFound`] if the name is not bound.
+/// - [`Error::Policy`] if the name is bound to a process that `process` may not send to.
+pub fn resolve<'pa, PA: PageAllocator>(
+    processes: &HandleMap<Process<'pa, PA>>,
+    process: &Process<'pa, PA>,
+    registry_handle: CapabilityHandle,
+    name: &[u8],
+) -> Result<CapabilityHandle, Error> {
+    let (object, rights) =
+        registry(processes, process, registry_handle, Rights::READ)?.lookup(name)?;
+    if let KernelObject::Process(target) = &object {
+        policy::check_send(processes, process, target).context(PolicySnafu)?;
+    }
+    process
+        .capabilities
+        .insert(object, rights)
+        .context(CapabilitySnafu)
+}
+
+/// Start a registry for the subtree of `process`, which falls back to the registry `registry`, a
+/// handle in the table of `process` with the [`Rights::MANAGE`] right. Returns a handle with every
+/// right to the new registry, which the process can pass on with fewer rights to the processes it
+/// supervises.
+///
+/// # Errors
+/// - [`Error::Capability`] if the handle is invalid or lacks the right, or the table is full.
+/// - [`Error::OutOfScope`] if the process is not in the registry's scope.
+pub fn create_scope<'pa, PA: PageAllocator>(
+    processes: &HandleMap<Process<'pa, PA>>,
+    process: &Process<'pa, PA>,
+    registry_handle: CapabilityHandle,
+) -> Result<CapabilityHandle, Error> {
+    let parent = registry(processes, process, registry_handle, Rights::MANAGE)?;
+    let registry = NameRegistry {
+        scope: Some(process.id),
+        parent: Some(parent),
+        names: Mutex::new(BTreeMap::new()),
+    };
+    process
+        .capabilities
+        .insert(KernelObject::NameRegistry(Arc::new(registry)), Rights::ALL)
+        .context(CapabilitySnafu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ipc::Notification,
+        memory::{tests::MockPageAllocator, PageSize},
+        process::{tests::new_process, thread::MAX_THREAD_ID},
+    };
+
+    fn give<'pa>(
+        process: &Process<'pa, MockPageAllocator>,
+        registry: &Arc<NameRegistry<'pa, MockPageAllocator>>,
+        rights: Rights,
+    ) -> CapabilityHandle {
+        process
+            .capabilities
+            .insert(KernelObject::NameRegistry(registry.clone()), rights)
+            .unwrap()
+    }
+
+    #[test]
+    fn names_resolve_within_scopes() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        {
+            let processes = HandleMap::new(MAX_THREAD_ID);
+            // init supervises a, which supervises b; c is another top level process
+            let init = new_process(&processes, &pa, None);
+            let a = new_process(&processes, &pa, Some(init.id));
+            let b = new_process(&processes, &pa, Some(a.id));
+            let c = new_process(&processes, &pa, None);
+            let root = Arc::<NameRegistry<_>>::default();
+            let console = Arc::new(Notification::new());
+            let console_handle = init
+                .capabilities
+                .insert(
+                    KernelObject::Notification(console.clone()),
+                    Rights::READ | Rights::DUPLICATE,
+                )
+                .unwrap();
+
+            let r = give(&init, &root, Rights::ALL);
+            bind(&processes, &init, r, b"console", console_handle).unwrap();
+            assert!(matches!(
+                bind(&processes, &init, r, b"console", console_handle),
+                Err(Error::AlreadyBound)
+            ));
+            assert!(matches!(
+                bind(
+                    &processes,
+                    &init,
+                    r,
+                    &[b'x'; MAX_NAME_LENGTH + 1],
+                    console_handle
+                ),
+                Err(Error::InvalidName { .. })
+            ));
+            assert!(matches!(
+                bind(&processes, &init, r, b"registry", r),
+                Err(Error::CannotBindRegistry)
+            ));
+
+            // a starts its own scope, where it replaces the console
+            let ar = give(&a, &root, Rights::READ | Rights::MANAGE);
+            let scoped = create_scope(&processes, &a, ar).unwrap();
+            let own_console = a
+                .capabilities
+                .insert(
+                    KernelObject::Notification(Arc::new(Notification::new())),
+                    Rights::DUPLICATE,
+                )
+                .unwrap();
+            bind(&processes, &a, scoped, b"console", own_console).unwrap();
+            let cap = a.capabilities.get(scoped, Rights::NONE).unwrap();
+            let br = b
+                .capabilities
+                .insert(cap.object.clone(), Rights::READ)
+                .unwrap();
+            let h = resolve(&processes, &b, br, b"console").unwrap();
+            assert!(!Arc::ptr_eq(
+                &b.capabilities.notification(h, Rights::DUPLICATE).unwrap(),
+                &console
+            ));
+            // the parent registry's names are still visible through the fallback
+            unbind(&processes, &a, scoped, b"console").unwrap();
+            let h = resolve(&processes, &b, br, b"console").unwrap();
+            assert!(Arc::ptr_eq(
+                &b.capabilities.notification(h, Rights::READ).unwrap(),
+                &console
+            ));
+            assert!(matches!(
+                resolve(&processes, &b, br, b"missing"),
+                Err(Error::NotFound)
+            ));
+            // b can't change the names
+            assert!(matches!(
+                unbind(&processes, &b, br, b"console"),
+                Err(Error::Capability {
+                    source: caps::Error::InsufficientRights { .. }
+                })
+            ));
+
+            // the scope of a's registry doesn't include processes outside a's subtree
+            let cr = c
+                .capabilities
+                .insert(cap.object.clone(), Rights::READ)
+                .unwrap();
+            assert!(matches!(
+                resolve(&processes, &c, cr, b"console"),
+                Err(Error::OutOfScope { process, scope }) if process == c.id && scope == Some(a.id)
+            ));
+            let cr = give(&c, &root, Rights::READ);
+            assert!(resolve(&processes, &c, cr, b"console").is_ok());
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn resolved_processes_follow_policy() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        {
+            let processes = HandleMap::new(MAX_THREAD_ID);
+            // init supervises a, which supervises b, so only a can send to init
+            let init = new_process(&processes, &pa, None);
+            let a = new_process(&processes, &pa, Some(init.id));
+            let b = new_process(&processes, &pa, Some(a.id));
+            let root = Arc::<NameRegistry<_>>::default();
+            let server = init
+                .capabilities
+                .insert(KernelObject::Process(init.clone()), Rights::DUPLICATE)
+                .unwrap();
+            let r = give(&init, &root, Rights::ALL);
+            bind(&processes, &init, r, b"init", server).unwrap();
+
+            let ar = give(&a, &root, Rights::READ);
+            let h = resolve(&processes, &a, ar, b"init").unwrap();
+            assert_eq!(a.capabilities.process(h, Rights::NONE).unwrap().id, init.id);
+            let br = give(&b, &root, Rights::READ);
+            assert!(matches!(
+                resolve(&processes, &b, br, b"init"),
+                Err(Error::Policy {
+                    source: policy::Error::SendDenied { .. }
+                })
+            ));
+            // the registry holds a reference to init until the name is unbound
+            unbind(&processes, &init, r, b"init").unwrap();
+            a.capabilities.clear();
+            b.capabilities.clear();
+            init.capabilities.clear();
+        }
+        pa.end_check();
+    }
+}
@@ -0,0 +1,326 @@
+//! The security model that decides which processes may interact with each other.
+//!
+//! Processes are grouped into scopes by their supervisor: the scope of a supervisor is the
+//! supervisor itself and every process it supervises. Processes with no supervisor form the top
+//! level scope. Each process has a [`Privilege`] level that decides how far outside its own scope
+//! it can reach (see `spec/kernel.md`):
+//! - An [`Privilege::Unprivileged`] process can send messages to processes in its supervisor's
+//!   scope, and to the processes it supervises itself.
+//! - A [`Privilege::Privileged`] process can also send messages to processes in its supervisor's
+//!   supervisor's scope.
+//! - A [`Privilege::Driver`] process can send messages to any process.
+//!
+//! Capabilities are passed in messages, so granting one requires being able to send to the
+//! receiver. A capability for a process must not let the receiver reach a process it couldn't
+//! send to itself.
+//!
+//! A spawned process is either supervised by the same process as its parent, or by its parent,
+//! starting a new scope. It can't be more privileged than its parent.
+//!
+//! A process can only be debugged by one of its supervisors, directly or through the supervisors
+//! of its supervisor.
+use snafu::{ensure, Snafu};
+
+use super::{caps::KernelObject, Id, Process};
+use crate::{collections::HandleMap, memory::PageAllocator};
+
+/// The privilege level of a process, in increasing order of privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    /// Can only interact with processes in its supervisor's scope.
+    Unprivileged,
+    /// Can also interact with processes in its supervisor's supervisor's scope.
+    Privileged,
+    /// Can interact with any process, map device MMIO regions, and set the wall clock.
+    Driver,
+}
+
+/// Ways that an interaction between processes can violate the security model.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The sender may not send messages to the receiver.
+    #[snafu(display("process {sender} may not send to process {receiver}"))]
+    SendDenied {
+        /// The sending process.
+        sender: Id,
+        /// The receiving process.
+        receiver: Id,
+    },
+    /// The capability would let the receiver reach a process it can't send messages to.
+    #[snafu(display(
+        "process {granter} may not grant process {receiver} a capability for process {target}"
+    ))]
+    GrantDenied {
+        /// The process granting the capability.
+        granter: Id,
+        /// The process receiving the capability.
+        receiver: Id,
+        /// The process the capability refers to.
+        target: Id,
+    },
+    /// A process tried to spawn a process more privileged than itself.
+    #[snafu(display("a {parent:?} process may not spawn a {requested:?} process"))]
+    PrivilegeTooHigh {
+        /// The privilege of the parent.
+        parent: Privilege,
+        /// The privilege requested for the child.
+        requested: Privilege,
+    },
+    /// A process tried to spawn a process in a scope other than its own or a new one.
+    #[snafu(display("process {parent} may not spawn a process supervised by {supervisor:?}"))]
+    InvalidSupervisor {
+        /// The parent process.
+        parent: Id,
+        /// The supervisor requested for the child.
+        supervisor: Option<Id>,
+    },
+    /// A process tried to debug a process it doesn't supervise.
+    #[snafu(display("process {debugger} may not debug process {target}"))]
+    DebugDenied {
+        /// The process that tried to debug the target.
+        debugger: Id,
+        /// The process it tried to debug.
+        target: Id,
+    },
+}
+
+/// Returns true if `process` is in the scope of the supervisor `scope`, where `None` is the top
+/// level scope.
+fn in_scope<PA: PageAllocator>(scope: Option<Id>, process: &Process<'_, PA>) -> bool {
+    process.supervisor == scope || scope == Some(process.id)
+}
+
+/// Check that `sender` may send messages to `receiver`.
+///
+/// The supervisor of a privileged sender is looked up in `processes`. If it no longer exists, the
+/// sender is limited to its supervisor's scope.
+///
+/// # Errors
+/// - [`Error::SendDenied`] if the sender may not send to the receiver.
+pub fn check_send<PA: PageAllocator>(
+    processes: &HandleMap<Process<'_, PA>>,
+    sender: &Process<'_, PA>,
+    receiver: &Process<'_, PA>,
+) -> Result<(), Error> {
+    let allowed = sender.id == receiver.id
+        || receiver.supervisor == Some(sender.id)
+        || in_scope(sender.supervisor, receiver)
+        || match sender.privilege {
+            Privilege::Unprivileged => false,
+            Privilege::Privileged => sender
+                .supervisor
+                .and_then(|s| processes.get(s))
+                .is_some_and(|s| in_scope(s.supervisor, receiver)),
+            Privilege::Driver => true,
+        };
+    ensure!(
+        allowed,
+        SendDeniedSnafu {
+            sender: sender.id,
+            receiver: receiver.id
+        }
+    );
+    Ok(())
+}
+
+/// Check that `granter` may grant `receiver` a capability for `object`.
+///
+/// # Errors
+/// - [`Error::SendDenied`] if the granter may not send to the receiver.
+/// - [`Error::GrantDenied`] if the object is a process that the receiver may not send to.
+pub fn check_grant<PA: PageAllocator>(
+    processes: &HandleMap<Process<'_, PA>>,
+    granter: &Process<'_, PA>,
+    receiver: &Process<'_, PA>,
+    object: &KernelObject<'_, PA>,
+) -> Result<(), Error> {
+    check_send(processes, granter, receiver)?;
+    if let KernelObject::Process(target) = object {
+        ensure!(
+            check_send(processes, receiver, target).is_ok(),
+            GrantDeniedSnafu {
+                granter: granter.id,
+                receiver: receiver.id,
+                target: target.id
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Check that `parent` may spawn a process with `privilege` that is supervised by `supervisor`.
+///
+/// # Errors
+/// - [`Error::PrivilegeTooHigh`] if the child would be more privileged than the parent.
+/// - [`Error::InvalidSupervisor`] if the supervisor is neither the parent nor the parent's
+///   supervisor.
+pub fn check_spawn<PA: PageAllocator>(
+    parent: &Process<'_, PA>,
+    privilege: Privilege,
+    supervisor: Option<Id>,
+) -> Result<(), Error> {
+    ensure!(
+        privilege <= parent.privilege,
+        PrivilegeTooHighSnafu {
+            parent: parent.privilege,
+            requested: privilege
+        }
+    );
+    ensure!(
+        supervisor == parent.supervisor || supervisor == Some(parent.id),
+        InvalidSupervisorSnafu {
+            parent: parent.id,
+            supervisor
+        }
+    );
+    Ok(())
+}
+
+/// Check that `debugger` may debug `target`, because it supervises the target either directly or
+/// through the target's supervisors, which are looked up in `processes`.
+///
+/// # Errors
+/// - [`Error::DebugDenied`] if the debugger does not supervise the target.
+pub fn check_debug<PA: PageAllocator>(
+    processes: &HandleMap<Process<'_, PA>>,
+    debugger: Id,
+    target: &Process<'_, PA>,
+) -> Result<(), Error> {
+    let mut supervisor = target.supervisor;
+    while let Some(id) = supervisor {
+        if id == debugger {
+            return Ok(());
+        }
+        supervisor = processes.get(id).and_then(|s| s.supervisor);
+    }
+    DebugDeniedSnafu {
+        debugger,
+        target: target.id,
+    }
+    .fail()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::*;
+    use crate::{
+        memory::{tests::MockPageAllocator, PageSize, PageTables},
+        process::{thread::MAX_THREAD_ID, Name},
+    };
+
+    fn spawn<'pa>(
+        processes: &HandleMap<Process<'pa, MockPageAllocator>>,
+        pa: &'pa MockPageAllocator,
+        supervisor: Option<Id>,
+        privilege: Privilege,
+    ) -> Arc<Process<'pa, MockPageAllocator>> {
+        Process::new(
+            processes,
+            Name::EMPTY,
+            supervisor,
+            privilege,
+            pa,
+            PageTables::empty(pa).unwrap(),
+        )
+    }
+
+    #[test]
+    fn sends_are_limited_by_scope() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        {
+            let processes = HandleMap::new(MAX_THREAD_ID);
+            // root supervises a, which supervises b and c; d is another top level process
+            let root = spawn(&processes, &pa, None, Privilege::Driver);
+            let a = spawn(&processes, &pa, Some(root.id), Privilege::Privileged);
+            let b = spawn(&processes, &pa, Some(a.id), Privilege::Unprivileged);
+            let c = spawn(&processes, &pa, Some(a.id), Privilege::Privileged);
+            let d = spawn(&processes, &pa, None, Privilege::Unprivileged);
+            let allowed = |s: &Process<_>, r: &Process<_>| check_send(&processes, s, r).is_ok();
+
+            // b can reach its supervisor and siblings, but nothing further
+            assert!(allowed(&b, &a) && allowed(&b, &c) && allowed(&b, &b));
+            assert!(!allowed(&b, &root) && !allowed(&b, &d));
+            // c can also reach its supervisor's scope
+            assert!(allowed(&c, &root));
+            assert!(!allowed(&c, &d));
+            // supervisors can reach the processes they supervise
+            assert!(allowed(&a, &b) && allowed(&root, &a));
+            // a's supervisor's supervisor is the top level scope
+            assert!(allowed(&a, &d));
+            assert!(allowed(&root, &d) && allowed(&d, &root));
+            assert!(matches!(
+                check_send(&processes, &b, &d),
+                Err(Error::SendDenied { sender, receiver }) if sender == b.id && receiver == d.id
+            ));
+
+            // without the supervisor, a privileged process is limited to its own scope
+            processes.remove(root.id);
+            let e = spawn(&processes, &pa, Some(c.id), Privilege::Privileged);
+            assert!(allowed(&e, &c));
+            processes.remove(c.id);
+            assert!(!allowed(&e, &b));
+
+            assert!(check_grant(&processes, &a, &b, &KernelObject::Process(c.clone())).is_ok());
+            assert!(matches!(
+                check_grant(&processes, &a, &b, &KernelObject::Process(root.clone())),
+                Err(Error::GrantDenied { .. })
+            ));
+            assert!(matches!(
+                check_grant(&processes, &b, &d, &KernelObject::Process(a.clone())),
+                Err(Error::SendDenied { .. })
+            ));
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn spawns_are_limited_by_privilege_and_scope() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        {
+            let processes = HandleMap::new(MAX_THREAD_ID);
+            let parent = spawn(&processes, &pa, Some(7), Privilege::Privileged);
+            assert!(check_spawn(&parent, Privilege::Privileged, Some(7)).is_ok());
+            assert!(check_spawn(&parent, Privilege::Unprivileged, Some(parent.id)).is_ok());
+            assert!(matches!(
+                check_spawn(&parent, Privilege::Driver, Some(7)),
+                Err(Error::PrivilegeTooHigh {
+                    parent: Privilege::Privileged,
+                    requested: Privilege::Driver
+                })
+            ));
+            assert!(matches!(
+                check_spawn(&parent, Privilege::Unprivileged, None),
+                Err(Error::InvalidSupervisor {
+                    supervisor: None,
+                    ..
+                })
+            ));
+        }
+        pa.end_check();
+    }
+
+    #[test]
+    fn only_supervisors_may_debug() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        {
+            let processes = HandleMap::new(MAX_THREAD_ID);
+            let root = spawn(&processes, &pa, None, Privilege::Driver);
+            let a = spawn(&processes, &pa, Some(root.id), Privilege::Driver);
+            let b = spawn(&processes, &pa, Some(a.id), Privilege::Unprivileged);
+            let c = spawn(&processes, &pa, Some(a.id), Privilege::Unprivileged);
+            assert!(check_debug(&processes, a.id, &b).is_ok());
+            assert!(check_debug(&processes, root.id, &b).is_ok());
+            // siblings and supervised processes can't debug, whatever their privilege
+            assert!(matches!(
+                check_debug(&processes, c.id, &b),
+                Err(Error::DebugDenied { debugger, target }) if debugger == c.id && target == b.id
+            ));
+            assert!(check_debug(&processes, b.id, &a).is_err());
+            assert!(check_debug(&processes, a.id, &a).is_err());
+            assert!(check_debug(&processes, root.id, &root).is_err());
+        }
+        pa.end_check();
+    }
+}
@@ -0,0 +1,369 @@
+//! The startup information passed to a newly spawned process: its arguments, environment, and
+//! initial capabilities.
+//!
+//! The information is mapped read only at [`STARTUP_INFO_ADDRESS`] in the new process, and the
+//! main thread starts with its address in `x0` and its size in `x1`.
+//!
+//! # Layout
+//! The layout is a stable ABI. All fields are little-endian `u32`s, and all offsets are from the
+//! start of the information. It starts with a header:
+//!
+//! | Offset | Field                  | Meaning                                              |
+//! |--------|------------------------|------------------------------------------------------|
+//! | 0      | `version`              | The layout version, [`STARTUP_INFO_VERSION`].        |
+//! | 4      | `size`                 | The size of the whole information in bytes.          |
+//! | 8      | `argument_count`       | The number of arguments.                             |
+//! | 12     | `arguments_offset`     | The offset of the argument table.                    |
+//! | 16     | `environment_count`    | The number of environment entries.                   |
+//! | 20     | `environment_offset`   | The offset of the environment table.                 |
+//! | 24     | `capability_count`     | The number of capabilities.                          |
+//! | 28     | `capabilities_offset`  | The offset of the array of capability handles.       |
+//!
+//! The argument and environment tables hold an `(offset, length)` pair for each string, in order.
+//! Strings are arbitrary bytes with no terminator. By convention environment entries have the
+//! form `NAME=VALUE`, like `envp`. The capability array holds a handle in the new process'
+//! capability table for each capability passed to it.
+use alloc::{vec, vec::Vec};
+use byteorder::{ByteOrder as _, LittleEndian};
+use snafu::{ensure, OptionExt as _, ResultExt as _, Snafu};
+
+use super::{
+    caps::{self, CapabilityHandle, Rights},
+    policy, Process,
+};
+use crate::{collections::HandleMap, memory::PageAllocator};
+
+/// The virtual address the startup information is mapped at in a new process.
+pub const STARTUP_INFO_ADDRESS: usize = 0x0000_7f00_0001_0000;
+
+/// The maximum size of the startup information in bytes.
+pub const MAX_STARTUP_INFO_SIZE: usize = 0x1_0000;
+
+/// The version of the startup information layout described in the
+/// [module documentation](self).
+pub const STARTUP_INFO_VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 32;
+
+/// Errors that can occur building or reading startup information.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The information is larger than [`MAX_STARTUP_INFO_SIZE`].
+    #[snafu(display("startup information of {size} bytes is too large"))]
+    TooLarge {
+        /// The size of the information in bytes.
+        size: usize,
+    },
+    /// The information does not have a valid layout.
+    #[snafu(display("invalid startup information: {reason}"))]
+    BadFormat {
+        /// Description of what was wrong with the information.
+        reason: &'static str,
+    },
+    /// A capability could not be passed to the new process.
+    Capability {
+        /// Underlying error.
+        source: caps::Error,
+    },
+    /// The security model does not allow a capability to be passed to the new process.
+    Policy {
+        /// Underlying error.
+        source: policy::Error,
+    },
+}
+
+/// Serialize `arguments`, `environment` and `capabilities` (handles in the new process) into
+/// startup information, in the layout described in the [module documentation](self).
+///
+/// # Errors
+/// - [`Error::TooLarge`] if the information would be larger than [`MAX_STARTUP_INFO_SIZE`].
+pub fn encode(
+    arguments: &[&[u8]],
+    environment: &[&[u8]],
+    capabilities: &[CapabilityHandle],
+) -> Result<Vec<u8>, Error> {
+    let arguments_offset = HEADER_SIZE;
+    let environment_offset = arguments_offset + arguments.len() * 8;
+    let capabilities_offset = environment_offset + environment.len() * 8;
+    let strings_offset = capabilities_offset + capabilities.len() * 4;
+    let size = strings_offset
+        + arguments
+            .iter()
+            .chain(environment)
+            .map(|s| s.len())
+            .sum::<usize>();
+    ensure!(size <= MAX_STARTUP_INFO_SIZE, TooLargeSnafu { size });
+
+    // every value fits in a u32 since the size is bounded
+    #[allow(clippy::cast_possible_truncation)]
+    let word = |x: usize| x as u32;
+    let mut info = vec![0; size];
+    LittleEndian::write_u32_into(
+        &[
+            STARTUP_INFO_VERSION,
+            word(size),
+            word(arguments.len()),
+            word(arguments_offset),
+            word(environment.len()),
+            word(environment_offset),
+            word(capabilities.len()),
+            word(capabilities_offset),
+        ],
+        &mut info[..HEADER_SIZE],
+    );
+    let mut string_offset = strings_offset;
+    for (i, string) in arguments.iter().chain(environment).enumerate() {
+        let entry = &mut info[arguments_offset + i * 8..];
+        LittleEndian::write_u32(entry, word(string_offset));
+        LittleEndian::write_u32(&mut entry[4..], word(string.len()));
+        info[string_offset..string_offset + string.len()].copy_from_slice(string);
+        string_offset += string.len();
+    }
+    LittleEndian::write_u32_into(capabilities, &mut info[capabilities_offset..strings_offset]);
+    Ok(info)
+}
+
+/// Startup information read from its serialized form.
+#[derive(Debug, Clone)]
+pub struct StartupInfo<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> StartupInfo<'b> {
+    /// Read the startup information in `bytes`.
+    ///
+    /// # Errors
+    /// - [`Error::BadFormat`] if the information has an unknown version or any of its tables or
+    ///   strings are out of bounds.
+    pub fn parse(bytes: &'b [u8]) -> Result<Self, Error> {
+        ensure!(
+            bytes.len() >= HEADER_SIZE,
+            BadFormatSnafu {
+                reason: "missing header"
+            }
+        );
+        let info = Self { bytes };
+        ensure!(
+            info.field(0) == STARTUP_INFO_VERSION,
+            BadFormatSnafu {
+                reason: "unknown version"
+            }
+        );
+        let size = info.field(1) as usize;
+        ensure!(
+            size <= bytes.len(),
+            BadFormatSnafu {
+                reason: "size larger than data"
+            }
+        );
+        let info = Self {
+            bytes: &bytes[..size],
+        };
+        for (count, offset, entry_size) in [(2, 3, 8), (4, 5, 8), (6, 7, 4)] {
+            info.table(count, offset, entry_size)?;
+        }
+        for (offset, length) in info.table_entries(2).chain(info.table_entries(4)) {
+            ensure!(
+                offset
+                    .checked_add(length)
+                    .is_some_and(|end| end <= info.bytes.len()),
+                BadFormatSnafu {
+                    reason: "string out of bounds"
+                }
+            );
+        }
+        Ok(info)
+    }
+
+    /// The `index`th field of the header.
+    fn field(&self, index: usize) -> u32 {
+        LittleEndian::read_u32(&self.bytes[index * 4..])
+    }
+
+    /// The table whose length is in header field `count` and offset is in header field `offset`.
+    fn table(&self, count: usize, offset: usize, entry_size: usize) -> Result<&'b [u8], Error> {
+        let start = self.field(offset) as usize;
+        (self.field(count) as usize)
+            .checked_mul(entry_size)
+            .and_then(|length| start.checked_add(length))
+            .and_then(|end| self.bytes.get(start..end))
+            .context(BadFormatSnafu {
+                reason: "table out of bounds",
+            })
+    }
+
+    /// The `(offset, length)` entries of the string table whose length is in header field `count`.
+    fn table_entries(&self, count: usize) -> impl Iterator<Item = (usize, usize)> + 'b {
+        self.table(count, count + 1, 8)
+            .unwrap_or_default()
+            .chunks_exact(8)
+            .map(|entry| {
+                (
+                    LittleEndian::read_u32(entry) as usize,
+                    LittleEndian::read_u32(&entry[4..]) as usize,
+                )
+            })
+    }
+
+    fn strings(&self, count: usize) -> impl Iterator<Item = &'b [u8]> + 'b {
+        let bytes = self.bytes;
+        self.table_entries(count)
+            .map(move |(offset, length)| &bytes[offset..offset + length])
+    }
+
+    /// The arguments, in order.
+    pub fn arguments(&self) -> impl Iterator<Item = &'b [u8]> + 'b {
+        self.strings(2)
+    }
+
+    /// The environment entries, in order.
+    pub fn environment(&self) -> impl Iterator<Item = &'b [u8]> + 'b {
+        self.strings(4)
+    }
+
+    /// The handles of the capabilities passed to the process, in order.
+    pub fn capabilities(&self) -> impl Iterator<Item = CapabilityHandle> + 'b {
+        self.table(6, 7, 4)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(LittleEndian::read_u32)
+    }
+}
+
+/// Move the capabilities `handles` from `parent` to its newly spawned `child`, returning their
+/// handles in the child's capability table in the same order, to be passed to [`encode`].
+///
+/// Every capability must have the [`Rights::TRANSFER`] right, and the security model must allow
+/// the parent to grant it to the child (see [`policy::check_grant`]). These are checked before any
+/// capability is moved.
+///
+/// # Errors
+/// - [`Error::Capability`] if a handle is invalid or lacks the transfer right, or the child's table
+///   is full. If the table is full, the capabilities before the one that failed have been moved.
+/// - [`Error::Policy`] if the parent may not grant a capability to the child.
+pub fn pass_capabilities<'pa, PA: PageAllocator>(
+    processes: &HandleMap<Process<'pa, PA>>,
+    parent: &Process<'pa, PA>,
+    child: &Process<'pa, PA>,
+    handles: &[CapabilityHandle],
+) -> Result<Vec<CapabilityHandle>, Error> {
+    for handle in handles {
+        let cap = parent
+            .capabilities
+            .get(*handle, Rights::TRANSFER)
+            .context(CapabilitySnafu)?;
+        policy::check_grant(processes, parent, child, &cap.object).context(PolicySnafu)?;
+    }
+    handles
+        .iter()
+        .map(|handle| {
+            parent
+                .capabilities
+                .transfer(*handle, &child.capabilities)
+                .context(CapabilitySnafu)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::*;
+    use crate::{
+        ipc::Notification,
+        memory::{tests::MockPageAllocator, PageSize},
+        process::{caps::KernelObject, tests::new_process, thread::MAX_THREAD_ID},
+    };
+
+    #[test]
+    fn encode_and_parse() {
+        let info = encode(&[b"init", b"--verbose"], &[b"HOME=/", b"EMPTY="], &[3, 9]).unwrap();
+        assert_eq!(LittleEndian::read_u32(&info[4..]) as usize, info.len());
+        let parsed = StartupInfo::parse(&info).unwrap();
+        assert!(parsed.arguments().eq([&b"init"[..], b"--verbose"]));
+        assert!(parsed.environment().eq([&b"HOME=/"[..], b"EMPTY="]));
+        assert!(parsed.capabilities().eq([3, 9]));
+
+        let empty = encode(&[], &[], &[]).unwrap();
+        assert_eq!(empty.len(), HEADER_SIZE);
+        let parsed = StartupInfo::parse(&empty).unwrap();
+        assert_eq!(parsed.arguments().count(), 0);
+        assert_eq!(parsed.capabilities().count(), 0);
+
+        let big = [0; MAX_STARTUP_INFO_SIZE];
+        assert!(matches!(
+            encode(&[&big], &[], &[]),
+            Err(Error::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_bad_info() {
+        assert!(matches!(
+            StartupInfo::parse(&[0; 8]),
+            Err(Error::BadFormat { .. })
+        ));
+        let info = encode(&[b"arg"], &[], &[1]).unwrap();
+        let mut bad_version = info.clone();
+        bad_version[0] = 2;
+        assert!(StartupInfo::parse(&bad_version).is_err());
+        let mut bad_string = info.clone();
+        // make the argument extend past the end
+        LittleEndian::write_u32(&mut bad_string[HEADER_SIZE + 4..], 100);
+        assert!(matches!(
+            StartupInfo::parse(&bad_string),
+            Err(Error::BadFormat {
+                reason: "string out of bounds"
+            })
+        ));
+        let mut bad_table = info;
+        LittleEndian::write_u32(&mut bad_table[24..], u32::MAX);
+        assert!(matches!(
+            StartupInfo::parse(&bad_table),
+            Err(Error::BadFormat {
+                reason: "table out of bounds"
+            })
+        ));
+    }
+
+    #[test]
+    fn capabilities_move_to_child() {
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        {
+            let processes = HandleMap::new(MAX_THREAD_ID);
+            let parent = new_process(&processes, &pa, None);
+            let child = new_process(&processes, &pa, Some(parent.id));
+            let notification = Arc::new(Notification::new());
+            let a = parent
+                .capabilities
+                .insert(
+                    KernelObject::Notification(notification.clone()),
+                    Rights::ALL,
+                )
+                .unwrap();
+            let b = parent
+                .capabilities
+                .insert(KernelObject::Notification(notification), Rights::READ)
+                .unwrap();
+
+            // nothing moves if any capability can't be passed
+            assert!(matches!(
+                pass_capabilities(&processes, &parent, &child, &[a, b]),
+                Err(Error::Capability {
+                    source: caps::Error::InsufficientRights { .. }
+                })
+            ));
+            assert!(parent.capabilities.get(a, Rights::NONE).is_ok());
+
+            let handles = pass_capabilities(&processes, &parent, &child, &[a]).unwrap();
+            assert_eq!(handles.len(), 1);
+            assert!(parent.capabilities.get(a, Rights::NONE).is_err());
+            assert!(child
+                .capabilities
+                .notification(handles[0], Rights::ALL)
+                .is_ok());
+        }
+        pa.end_check();
+    }
+}
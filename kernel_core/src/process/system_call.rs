@@ -0,0 +1,406 @@
+//! System calls made by user space threads.
+//!
+//! A call is made with an `svc` instruction whose immediate selects the call, with its arguments in
+//! `x0` onwards. The result is returned in `x0`: zero on success, or an [`ErrorCode`]. A call that
+//! blocks the thread gets its result once the thread resumes, from [`complete_wait`].
+use super::{
+    thread::{wait::Timeout, Registers, Thread, WaitOutcome},
+    PageAllocator, Process,
+};
+use crate::{
+    memory::VirtualAddress,
+    process::thread::Scheduler,
+    sync::futex,
+    time::{Ticks, TimerQueue},
+};
+
+/// The `svc` immediate for [`SystemCall::FutexWait`], with the address of the futex in `x0`, the
+/// expected value in `x1` and the deadline in `x2`, or zero to wait forever.
+pub const SVC_FUTEX_WAIT: u16 = 0x40;
+/// The `svc` immediate for [`SystemCall::FutexWake`], with the address of the futex in `x0` and the
+/// maximum number of threads to wake in `x1`.
+pub const SVC_FUTEX_WAKE: u16 = 0x41;
+/// The `svc` immediate for [`SystemCall::SetTime`], with the time in `x0`.
+pub const SVC_SET_TIME: u16 = 0x42;
+
+/// The errors returned by system calls, as described in the spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ErrorCode {
+    /// The specified process, thread, or handler ID was unknown.
+    NotFound = 1,
+    /// The provided data was incorrectly formatted.
+    BadFormat,
+    /// The receiving process's message queue is full.
+    InboxFull,
+    /// The specified length was invalid.
+    InvalidLength,
+    /// An unknown or invalid combination of flags was passed.
+    InvalidFlags,
+    /// A pointer provided was null, invalid, or otherwise could not be used.
+    InvalidPointer,
+    /// There is not enough memory to complete the operation.
+    OutOfMemory,
+    /// The specified address or memory region was out of range.
+    OutOfBounds,
+    /// The operation would block the calling thread.
+    WouldBlock,
+    /// The requested resource is already in use.
+    InUse,
+    /// The deadline passed before the thread was woken.
+    TimedOut,
+    /// The thread's waits were cancelled, because it is exiting.
+    Cancelled,
+}
+
+impl From<futex::Error> for ErrorCode {
+    fn from(value: futex::Error) -> Self {
+        match value {
+            futex::Error::Unaligned { .. } | futex::Error::InvalidAddress { .. } => {
+                Self::InvalidPointer
+            }
+            futex::Error::ValueChanged => Self::WouldBlock,
+            futex::Error::Cancelled => Self::Cancelled,
+        }
+    }
+}
+
+/// A request made by a user space thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemCall {
+    /// Block until the futex is woken, if it holds the expected value. See
+    /// [`Process::futex_wait`].
+    FutexWait {
+        /// The address of the futex.
+        address: VirtualAddress,
+        /// The value the futex must hold for the thread to block.
+        expected: u32,
+        /// The time to give up waiting at, if any.
+        deadline: Option<Ticks>,
+    },
+    /// Wake threads waiting on a futex. See [`Process::futex_wake`].
+    FutexWake {
+        /// The address of the futex.
+        address: VirtualAddress,
+        /// The maximum number of threads to wake.
+        count: usize,
+    },
+    /// Set the wall clock time, saving it in the real time clock if there is one. Only drivers
+    /// may make this call.
+    SetTime {
+        /// The time in nanoseconds since the Unix epoch.
+        nanos: u64,
+    },
+}
+
+impl SystemCall {
+    /// Decode a call from the immediate of the `svc` instruction and the registers of the calling
+    /// thread. Returns `None` for an unknown immediate.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn decode(immediate: u16, registers: &super::thread::Registers) -> Option<Self> {
+        match immediate {
+            SVC_FUTEX_WAIT => Some(Self::FutexWait {
+                address: VirtualAddress::from(registers.x[0]),
+                expected: registers.x[1] as u32,
+                deadline: Some(registers.x[2] as Ticks).filter(|d| *d != 0),
+            }),
+            SVC_FUTEX_WAKE => Some(Self::FutexWake {
+                address: VirtualAddress::from(registers.x[0]),
+                count: registers.x[1],
+            }),
+            SVC_SET_TIME => Some(Self::SetTime {
+                nanos: registers.x[0] as u64,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns true if `process` may make this call. A process that makes a call it isn't
+    /// permitted to make faults instead.
+    #[must_use]
+    pub fn is_permitted<PA: PageAllocator>(&self, process: &Process<'_, PA>) -> bool {
+        match self {
+            Self::FutexWait { .. } | Self::FutexWake { .. } => true,
+            Self::SetTime { .. } => process.is_driver(),
+        }
+    }
+}
+
+/// Handle a system `call` made by the current thread (given by `scheduler`) of `process`, returning
+/// the value for the thread's `x0`. The call must be permitted (see [`SystemCall::is_permitted`]).
+/// The wall clock is set with `set_time`, since only the kernel knows which real time clock to save
+/// it in.
+///
+/// If the call blocks the thread, the returned value is only provisional, and [`complete_wait`]
+/// replaces it once the thread resumes.
+pub fn handle<PA: PageAllocator>(
+    process: &Process<'_, PA>,
+    scheduler: &impl Scheduler,
+    timers: &'static TimerQueue,
+    set_time: impl FnOnce(u64),
+    call: SystemCall,
+) -> usize {
+    let result = match call {
+        SystemCall::FutexWait {
+            address,
+            expected,
+            deadline,
+        } => process.futex_wait(
+            scheduler,
+            address,
+            expected,
+            deadline.map(|d| Timeout::new(timers, d)),
+        ),
+        SystemCall::FutexWake { address, count } => {
+            return process.futex_wake(address, count);
+        }
+        SystemCall::SetTime { nanos } => {
+            set_time(nanos);
+            Ok(())
+        }
+    };
+    result.map_or_else(|e| ErrorCode::from(e) as usize, |()| 0)
+}
+
+/// Set the result in `registers` of the system call that blocked `thread`, from how its wait
+/// ended. Does nothing unless the thread's wait has ended since the last time this was called.
+///
+/// This must be called each time a user thread is about to return to user space, with the
+/// registers it will return with.
+pub fn complete_wait(thread: &Thread, registers: &mut Registers) {
+    let Some(outcome) = thread.take_wait_outcome() else {
+        return;
+    };
+    registers.x[0] = match outcome {
+        WaitOutcome::Signaled => 0,
+        WaitOutcome::TimedOut => ErrorCode::TimedOut as usize,
+        WaitOutcome::Cancelled => ErrorCode::Cancelled as usize,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        memory::{
+            page_table::MemoryProperties, tests::MockPageAllocator, PageFrameDatabase, PageSize,
+            PageTables, PhysicalPointer,
+        },
+        process::{
+            mmio::MmioRegistry,
+            thread::{MockScheduler, ProcessorState, Registers, State, Thread, MAX_THREAD_ID},
+            Name, Privilege,
+        },
+    };
+    use alloc::boxed::Box;
+
+    #[test]
+    fn decode_calls() {
+        let mut registers = Registers::default();
+        registers.x[0] = 0x1000;
+        registers.x[1] = 7;
+        assert_eq!(
+            SystemCall::decode(SVC_FUTEX_WAIT, &registers),
+            Some(SystemCall::FutexWait {
+                address: VirtualAddress::from(0x1000),
+                expected: 7,
+                deadline: None
+            })
+        );
+        registers.x[2] = 99;
+        assert_eq!(
+            SystemCall::decode(SVC_FUTEX_WAIT, &registers),
+            Some(SystemCall::FutexWait {
+                address: VirtualAddress::from(0x1000),
+                expected: 7,
+                deadline: Some(99)
+            })
+        );
+        assert_eq!(
+            SystemCall::decode(SVC_FUTEX_WAKE, &registers),
+            Some(SystemCall::FutexWake {
+                address: VirtualAddress::from(0x1000),
+                count: 7
+            })
+        );
+        assert_eq!(
+            SystemCall::decode(SVC_SET_TIME, &registers),
+            Some(SystemCall::SetTime { nanos: 0x1000 })
+        );
+        assert_eq!(SystemCall::decode(0, &registers), None);
+    }
+
+    #[test]
+    fn only_drivers_set_time() {
+        static TIMERS: TimerQueue = TimerQueue::new();
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let spawn = |privilege| {
+            Process::new(
+                &processes,
+                Name::EMPTY,
+                None,
+                privilege,
+                &pa,
+                PageTables::empty(&pa).unwrap(),
+            )
+        };
+        let driver = spawn(Privilege::Driver);
+        let user = spawn(Privilege::Unprivileged);
+        let call = SystemCall::SetTime { nanos: 7 };
+        assert!(call.is_permitted(&driver));
+        assert!(!call.is_permitted(&user));
+        let wake = SystemCall::FutexWake {
+            address: VirtualAddress::from(0x1000),
+            count: 1,
+        };
+        assert!(wake.is_permitted(&user));
+
+        let sched = MockScheduler::new();
+        let mut set = None;
+        assert_eq!(
+            handle(&driver, &sched, &TIMERS, |nanos| set = Some(nanos), call),
+            0
+        );
+        assert_eq!(set, Some(7));
+    }
+
+    #[test]
+    fn futexes_in_ram_only() {
+        static TIMERS: TimerQueue = TimerQueue::new();
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let page = pa.allocate(1).unwrap();
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [(page, usize::from(PageSize::FourKiB))].into_iter(),
+        );
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let proc = Process::new(
+            &processes,
+            Name::EMPTY,
+            None,
+            Privilege::Driver,
+            &pa,
+            PageTables::empty(&pa).unwrap(),
+        );
+        let mmio = MmioRegistry::new(PageSize::FourKiB, core::iter::empty());
+        let ram = VirtualAddress::from(0x1000);
+        let device = VirtualAddress::from(0x2000);
+        proc.map(&frames, ram, page, 1, &MemoryProperties::default())
+            .unwrap();
+        proc.map_device(&mmio, page, PageSize::FourKiB.into(), device)
+            .unwrap();
+        let word: *mut u32 = PhysicalPointer::<u32>::from(usize::from(page)).into();
+        unsafe { word.write(3) };
+
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let mut sched = MockScheduler::new();
+        let current = thread.clone();
+        sched
+            .expect_current_thread()
+            .returning(move || current.clone());
+        sched.expect_next_time_slice().return_const(());
+        let wait = |address, expected| SystemCall::FutexWait {
+            address,
+            expected,
+            deadline: None,
+        };
+
+        assert_eq!(
+            handle(&proc, &sched, &TIMERS, |_| {}, wait(device, 3)),
+            ErrorCode::InvalidPointer as usize
+        );
+        assert_eq!(
+            handle(&proc, &sched, &TIMERS, |_| {}, wait(ram, 4)),
+            ErrorCode::WouldBlock as usize
+        );
+        assert_eq!(handle(&proc, &sched, &TIMERS, |_| {}, wait(ram, 3)), 0);
+        assert_eq!(thread.state(), State::Blocked);
+        assert_eq!(
+            handle(
+                &proc,
+                &sched,
+                &TIMERS,
+                |_| {},
+                SystemCall::FutexWake {
+                    address: ram,
+                    count: 2
+                }
+            ),
+            1
+        );
+        assert_eq!(thread.state(), State::Running);
+        let mut registers = Registers::default();
+        registers.x[0] = 99;
+        complete_wait(&thread, &mut registers);
+        assert_eq!(registers.x[0], 0);
+    }
+
+    #[test]
+    fn futex_wait_times_out() {
+        let timers: &'static TimerQueue = Box::leak(Box::new(TimerQueue::new()));
+        let pa = MockPageAllocator::new(PageSize::FourKiB, 16);
+        let page = pa.allocate(1).unwrap();
+        let frames = PageFrameDatabase::new(
+            PageSize::FourKiB,
+            [(page, usize::from(PageSize::FourKiB))].into_iter(),
+        );
+        let processes = HandleMap::new(MAX_THREAD_ID);
+        let proc = Process::new(
+            &processes,
+            Name::EMPTY,
+            None,
+            Privilege::Driver,
+            &pa,
+            PageTables::empty(&pa).unwrap(),
+        );
+        let ram = VirtualAddress::from(0x1000);
+        proc.map(&frames, ram, page, 1, &MemoryProperties::default())
+            .unwrap();
+        let word: *mut u32 = PhysicalPointer::<u32>::from(usize::from(page)).into();
+        unsafe { word.write(0) };
+
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let mut sched = MockScheduler::new();
+        let current = thread.clone();
+        sched
+            .expect_current_thread()
+            .returning(move || current.clone());
+        sched.expect_next_time_slice().return_const(());
+
+        let mut registers = Registers::default();
+        registers.x[0] = handle(
+            &proc,
+            &sched,
+            timers,
+            |_| {},
+            SystemCall::FutexWait {
+                address: ram,
+                expected: 0,
+                deadline: Some(100),
+            },
+        );
+        assert_eq!(registers.x[0], 0);
+        assert_eq!(thread.state(), State::Blocked);
+        complete_wait(&thread, &mut registers);
+        assert_eq!(registers.x[0], 0);
+
+        let (_, wake) = timers.pop_expired(100).unwrap();
+        wake();
+        assert_eq!(thread.state(), State::Running);
+        complete_wait(&thread, &mut registers);
+        assert_eq!(registers.x[0], ErrorCode::TimedOut as usize);
+        // the outcome is only reported once
+        registers.x[0] = 0;
+        complete_wait(&thread, &mut registers);
+        assert_eq!(registers.x[0], 0);
+    }
+}
@@ -0,0 +1,488 @@
+//! Threads that run kernel code at EL1 with their own stacks.
+//!
+//! Kernel threads do work that should not happen inside an exception handler, like work that may
+//! block or take a long time. They are scheduled like any other thread. Because the scheduler can
+//! only switch threads while handling an exception, kernel threads exit, join other kernel threads,
+//! sleep, park, wait for notifications and yield by making a kernel thread call with an `svc`
+//! instruction. The call is then handled on
+//! the core's own stack, so an exited thread's stack is never in use after the call returns.
+//!
+//! Kernel threads run with interrupts masked, like the rest of the kernel, so they are never
+//! preempted and only give up the core when they make a kernel thread call.
+use alloc::{sync::Arc, vec::Vec};
+use hashbrown::HashMap;
+use log::trace;
+
+use super::{
+    wait::{block_current, ThreadWaits, Timeout},
+    Id, ProcessorState, Registers, Scheduler, State, Thread, WaitReason,
+};
+use crate::{
+    collections::HandleMap,
+    ipc::{Notification, ReceiveFlags},
+    memory::VirtualAddress,
+    process::Name,
+    sync::Mutex,
+    time::{Ticks, TimerQueue},
+};
+
+/// The `svc` immediate for [`KernelThreadCall::Exit`].
+pub const SVC_EXIT: u16 = 0;
+/// The `svc` immediate for [`KernelThreadCall::Join`], with the thread id in `x0`.
+pub const SVC_JOIN: u16 = 1;
+/// The `svc` immediate for [`KernelThreadCall::Yield`].
+pub const SVC_YIELD: u16 = 2;
+/// The `svc` immediate for [`KernelThreadCall::Sleep`], with the deadline in `x0`.
+pub const SVC_SLEEP: u16 = 3;
+/// The `svc` immediate for [`KernelThreadCall::Park`].
+pub const SVC_PARK: u16 = 4;
+/// The `svc` immediate for [`KernelThreadCall::Wait`], with the address of the notification in
+/// `x0`, the mask in `x1` and the deadline in `x2`.
+pub const SVC_WAIT: u16 = 5;
+
+/// A request made by a kernel thread that needs the scheduler to switch threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelThreadCall {
+    /// Exit the current thread. It will never run again.
+    Exit,
+    /// Block until the kernel thread with this id exits.
+    Join(Id),
+    /// Let other threads run for the rest of the time slice.
+    Yield,
+    /// Block until the time reaches this deadline.
+    Sleep(Ticks),
+    /// Block until the thread is unparked by [`KernelThreads::unpark`], unless it already has been
+    /// since it last parked.
+    Park,
+    /// Block until one of the flags in `mask` is raised on a notification, or the time reaches
+    /// `deadline`. The flags are not consumed if the thread blocks, so the caller checks again once
+    /// it resumes.
+    Wait {
+        /// The address of the [`Notification`], which the calling thread keeps alive for the call.
+        notification: usize,
+        /// The flags to wait for.
+        mask: u64,
+        /// The time to give up waiting at.
+        deadline: Ticks,
+    },
+}
+
+impl KernelThreadCall {
+    /// Decode a call from the immediate of the `svc` instruction and the registers of the calling
+    /// thread. Returns `None` for an unknown immediate.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn decode(immediate: u16, registers: &Registers) -> Option<Self> {
+        match immediate {
+            SVC_EXIT => Some(Self::Exit),
+            SVC_JOIN => Some(Self::Join(registers.x[0] as Id)),
+            SVC_YIELD => Some(Self::Yield),
+            SVC_SLEEP => Some(Self::Sleep(registers.x[0] as Ticks)),
+            SVC_PARK => Some(Self::Park),
+            SVC_WAIT => Some(Self::Wait {
+                notification: registers.x[0],
+                mask: registers.x[1] as u64,
+                deadline: registers.x[2] as Ticks,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The kernel threads that have been spawned, along with the stacks they run on.
+///
+/// The stack type is whatever the kernel uses to keep track of the memory to free when a thread has
+/// exited.
+#[allow(clippy::module_name_repetitions)]
+pub struct KernelThreads<Stack> {
+    /// Threads that have not exited yet, by id.
+    live: Mutex<HashMap<Id, (Arc<Thread>, Stack)>>,
+    /// Threads that have exited, but have not been reaped yet.
+    exited: Mutex<Vec<(Arc<Thread>, Stack)>>,
+    waits: ThreadWaits,
+}
+
+impl<Stack> Default for KernelThreads<Stack> {
+    fn default() -> Self {
+        Self {
+            live: Mutex::default(),
+            exited: Mutex::default(),
+            waits: ThreadWaits::new(),
+        }
+    }
+}
+
+impl<Stack> KernelThreads<Stack> {
+    /// Create an empty set of kernel threads.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new kernel thread called `name` that starts executing at `entry_point` with
+    /// `argument` in `x0`, using the stack with its initial stack pointer at `stack_top`, and add it
+    /// to `scheduler`.
+    ///
+    /// The entry point must never return. Instead, it must make a [`KernelThreadCall::Exit`] call.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn(
+        &self,
+        threads: &HandleMap<Thread>,
+        scheduler: &impl Scheduler,
+        name: Name,
+        stack: Stack,
+        stack_top: VirtualAddress,
+        entry_point: VirtualAddress,
+        argument: usize,
+    ) -> Arc<Thread> {
+        let thread = Thread::new(
+            threads,
+            State::Running,
+            ProcessorState::new_for_kernel_thread(entry_point, stack_top, argument),
+        );
+        thread.set_name(name);
+        trace!("spawned kernel thread {thread} at {entry_point:?}");
+        self.live.lock().insert(thread.id, (thread.clone(), stack));
+        scheduler.add_thread(thread.clone());
+        thread
+    }
+
+    /// Returns true if `id` is a kernel thread that has not exited.
+    pub fn is_running(&self, id: Id) -> bool {
+        self.live.lock().contains_key(&id)
+    }
+
+    /// Wake the kernel thread `id` if it is parked, or make its next park return immediately if it
+    /// is not (see [`Thread::unpark`]). Interrupt handlers must unpark the thread directly instead,
+    /// since this takes a lock to find it.
+    ///
+    /// Does nothing if `id` is not a running kernel thread.
+    pub fn unpark(&self, id: Id) {
+        let thread = self.live.lock().get(&id).map(|(t, _)| t.clone());
+        if let Some(thread) = thread {
+            if thread.unpark() {
+                trace!("unparked kernel thread {thread}");
+            }
+        }
+    }
+
+    /// Handle a call made by the current thread in `scheduler`, advancing the scheduler to the next
+    /// time slice if the current thread can't continue. Sleeping threads are woken by a timer in
+    /// `timers`.
+    ///
+    /// Joining a thread that is not a running kernel thread returns immediately.
+    ///
+    /// # Panics
+    /// If the current thread tries to exit but is not a kernel thread.
+    pub fn handle_call(
+        &self,
+        scheduler: &impl Scheduler,
+        timers: &'static TimerQueue,
+        call: KernelThreadCall,
+    ) {
+        match call {
+            KernelThreadCall::Exit => {
+                let current = scheduler.current_thread();
+                let entry = self
+                    .live
+                    .lock()
+                    .remove(&current.id)
+                    .expect("only kernel threads can exit");
+                self.waits.exit(scheduler, &current);
+                self.exited.lock().push(entry);
+                scheduler.next_time_slice();
+            }
+            KernelThreadCall::Join(id) => {
+                let target = self.live.lock().get(&id).map(|(t, _)| t.clone());
+                if let Some(target) = target {
+                    self.waits.join(scheduler, &target, None);
+                }
+            }
+            KernelThreadCall::Yield => scheduler.next_time_slice(),
+            KernelThreadCall::Sleep(deadline) => {
+                self.waits.sleep_until(scheduler, timers, deadline);
+            }
+            KernelThreadCall::Park => {
+                if let Some((thread, token)) = block_current(scheduler, WaitReason::Park, None) {
+                    if thread.park(token) {
+                        trace!("parked kernel thread {thread}");
+                        scheduler.next_time_slice();
+                    }
+                }
+            }
+            KernelThreadCall::Wait {
+                notification,
+                mask,
+                deadline,
+            } => {
+                // SAFETY: kernel thread calls are only made by kernel code, which passes the
+                // address of a notification that it borrows until the call returns
+                let notification = unsafe { &*(notification as *const Notification) };
+                // however the wait ends, the caller checks the notification again
+                let _ = notification.wait(
+                    scheduler,
+                    mask,
+                    ReceiveFlags::default(),
+                    Some(Timeout::new(timers, deadline)),
+                );
+            }
+        }
+    }
+
+    /// Free the resources of every kernel thread that has exited, removing it from `threads` and
+    /// passing its stack to `free`. Returns the number of threads reaped.
+    pub fn reap(&self, threads: &HandleMap<Thread>, mut free: impl FnMut(Stack)) -> usize {
+        let exited = core::mem::take(&mut *self.exited.lock());
+        let count = exited.len();
+        for (thread, stack) in exited {
+            trace!("reaping kernel thread {}", thread.id);
+            threads.remove(thread.id);
+            free(stack);
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, string::ToString as _};
+
+    use super::*;
+    use crate::{
+        platform::cpu::tests::FixedCpu,
+        process::thread::{scheduler::RoundRobinScheduler, MAX_THREAD_ID},
+    };
+
+    fn setup() -> (HandleMap<Thread>, RoundRobinScheduler<FixedCpu<0>>) {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let idle = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let sched = RoundRobinScheduler::new(&[(0, idle)]);
+        (threads, sched)
+    }
+
+    #[test]
+    fn decode_calls() {
+        let mut registers = Registers::default();
+        registers.x[0] = 42;
+        assert_eq!(
+            KernelThreadCall::decode(SVC_EXIT, &registers),
+            Some(KernelThreadCall::Exit)
+        );
+        assert_eq!(
+            KernelThreadCall::decode(SVC_JOIN, &registers),
+            Some(KernelThreadCall::Join(42))
+        );
+        assert_eq!(
+            KernelThreadCall::decode(SVC_YIELD, &registers),
+            Some(KernelThreadCall::Yield)
+        );
+        assert_eq!(
+            KernelThreadCall::decode(SVC_SLEEP, &registers),
+            Some(KernelThreadCall::Sleep(42))
+        );
+        assert_eq!(
+            KernelThreadCall::decode(SVC_PARK, &registers),
+            Some(KernelThreadCall::Park)
+        );
+        registers.x[1] = 0b10;
+        registers.x[2] = 100;
+        assert_eq!(
+            KernelThreadCall::decode(SVC_WAIT, &registers),
+            Some(KernelThreadCall::Wait {
+                notification: 42,
+                mask: 0b10,
+                deadline: 100
+            })
+        );
+        assert_eq!(KernelThreadCall::decode(0x99, &registers), None);
+    }
+
+    #[test]
+    fn spawned_thread_starts_at_entry_in_el1() {
+        let (threads, sched) = setup();
+        let kthreads = KernelThreads::new();
+        let thread = kthreads.spawn(
+            &threads,
+            &sched,
+            Name::new("worker"),
+            (),
+            VirtualAddress::from(0xffff_8000_0001_0000),
+            VirtualAddress::from(0xffff_0000_4100_1234),
+            7,
+        );
+        assert!(kthreads.is_running(thread.id));
+        assert_eq!(thread.to_string(), alloc::format!("{} (worker)", thread.id));
+
+        sched.next_time_slice();
+        assert!(Arc::ptr_eq(&sched.current_thread(), &thread));
+        let state = thread.processor_state.lock();
+        assert_eq!(state.spsr.el(), 1);
+        assert!(!state.spsr.sp());
+        assert_eq!(
+            state.program_counter,
+            VirtualAddress::from(0xffff_0000_4100_1234)
+        );
+        assert_eq!(
+            state.stack_pointer,
+            VirtualAddress::from(0xffff_8000_0001_0000)
+        );
+        assert_eq!(state.registers.x[0], 7);
+    }
+
+    #[test]
+    fn exit_wakes_joiner_and_stack_is_reaped() {
+        let (threads, sched) = setup();
+        let kthreads = KernelThreads::new();
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let top = VirtualAddress::from(0x8000);
+        let joiner = kthreads.spawn(&threads, &sched, Name::EMPTY, 1, top, 0x1000.into(), 0);
+        let worker = kthreads.spawn(&threads, &sched, Name::EMPTY, 2, top, 0x1000.into(), 0);
+
+        sched.next_time_slice();
+        assert!(Arc::ptr_eq(&sched.current_thread(), &joiner));
+        kthreads.handle_call(&sched, timers, KernelThreadCall::Join(worker.id));
+        assert_eq!(joiner.state(), State::Blocked);
+        assert!(Arc::ptr_eq(&sched.current_thread(), &worker));
+
+        // nothing to reap until the worker exits
+        assert_eq!(kthreads.reap(&threads, |_| panic!("nothing exited")), 0);
+
+        kthreads.handle_call(&sched, timers, KernelThreadCall::Exit);
+        assert_eq!(worker.state(), State::Exited);
+        assert!(!kthreads.is_running(worker.id));
+        assert_eq!(joiner.state(), State::Running);
+        assert!(Arc::ptr_eq(&sched.current_thread(), &joiner));
+
+        let mut freed = Vec::new();
+        assert_eq!(kthreads.reap(&threads, |s| freed.push(s)), 1);
+        assert_eq!(freed, [2]);
+        assert!(threads.get(worker.id).is_none());
+
+        // joining a thread that has already exited returns immediately
+        kthreads.handle_call(&sched, timers, KernelThreadCall::Join(worker.id));
+        assert_eq!(joiner.state(), State::Running);
+    }
+
+    #[test]
+    fn sleep_blocks_until_deadline() {
+        let (threads, sched) = setup();
+        let kthreads = KernelThreads::new();
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let sleeper = kthreads.spawn(
+            &threads,
+            &sched,
+            Name::EMPTY,
+            (),
+            0x8000.into(),
+            0x1000.into(),
+            0,
+        );
+
+        sched.next_time_slice();
+        assert!(Arc::ptr_eq(&sched.current_thread(), &sleeper));
+        kthreads.handle_call(&sched, timers, KernelThreadCall::Sleep(100));
+        assert_eq!(sleeper.state(), State::Blocked);
+        assert!(!Arc::ptr_eq(&sched.current_thread(), &sleeper));
+
+        let (_, wake) = timers.pop_expired(100).unwrap();
+        wake();
+        assert_eq!(sleeper.state(), State::Running);
+    }
+
+    #[test]
+    fn unpark_before_park_is_not_lost() {
+        let (threads, sched) = setup();
+        let kthreads = KernelThreads::new();
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let worker = kthreads.spawn(
+            &threads,
+            &sched,
+            Name::EMPTY,
+            (),
+            0x8000.into(),
+            0x1000.into(),
+            0,
+        );
+        sched.next_time_slice();
+        assert!(Arc::ptr_eq(&sched.current_thread(), &worker));
+
+        kthreads.handle_call(&sched, timers, KernelThreadCall::Park);
+        assert_eq!(worker.state(), State::Blocked);
+        assert_eq!(worker.wait_reason(), Some(WaitReason::Park));
+        kthreads.unpark(worker.id);
+        assert_eq!(worker.state(), State::Running);
+        sched.next_time_slice();
+        assert!(Arc::ptr_eq(&sched.current_thread(), &worker));
+
+        // unparks don't accumulate
+        kthreads.unpark(worker.id);
+        kthreads.unpark(worker.id);
+        kthreads.handle_call(&sched, timers, KernelThreadCall::Park);
+        assert_eq!(worker.state(), State::Running);
+        kthreads.handle_call(&sched, timers, KernelThreadCall::Park);
+        assert_eq!(worker.state(), State::Blocked);
+
+        // interrupt handlers unpark the thread directly
+        assert!(worker.unpark());
+        assert_eq!(worker.state(), State::Running);
+        assert!(!worker.unpark());
+
+        // threads that aren't kernel threads are ignored
+        kthreads.unpark(worker.id + 1);
+    }
+
+    #[test]
+    fn wait_blocks_until_signaled_or_deadline() {
+        let (threads, sched) = setup();
+        let kthreads = KernelThreads::new();
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let waiter = kthreads.spawn(
+            &threads,
+            &sched,
+            Name::EMPTY,
+            (),
+            0x8000.into(),
+            0x1000.into(),
+            0,
+        );
+        sched.next_time_slice();
+        let notification = Notification::new();
+        let wait = KernelThreadCall::Wait {
+            notification: core::ptr::addr_of!(notification) as usize,
+            mask: 0b1,
+            deadline: 100,
+        };
+
+        kthreads.handle_call(&sched, timers, wait);
+        assert_eq!(waiter.state(), State::Blocked);
+        notification.signal(0b1);
+        assert_eq!(waiter.state(), State::Running);
+        // the flag is left for the waiter to see
+        assert_eq!(notification.pending(), 0b1);
+
+        // a raised flag is consumed without blocking
+        sched.next_time_slice();
+        assert!(Arc::ptr_eq(&sched.current_thread(), &waiter));
+        kthreads.handle_call(&sched, timers, wait);
+        assert_eq!(waiter.state(), State::Running);
+        assert_eq!(notification.pending(), 0);
+
+        kthreads.handle_call(&sched, timers, wait);
+        assert_eq!(waiter.state(), State::Blocked);
+        while let Some((_, wake)) = timers.pop_expired(100) {
+            wake();
+        }
+        assert_eq!(waiter.state(), State::Running);
+    }
+
+    #[test]
+    #[should_panic(expected = "only kernel threads can exit")]
+    fn idle_thread_cannot_exit() {
+        let (_threads, sched) = setup();
+        let kthreads = KernelThreads::<()>::new();
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        kthreads.handle_call(&sched, timers, KernelThreadCall::Exit);
+    }
+}
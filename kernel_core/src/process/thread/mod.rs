@@ -1,21 +1,47 @@
 //! Threads
-use core::sync::atomic::AtomicU64;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
 
 use alloc::sync::Arc;
 use bytemuck::Contiguous;
 #[cfg(test)]
 use mockall::automock;
-use spin::Mutex;
 
-use crate::{collections::HandleMap, memory::VirtualAddress};
+use crate::{
+    collections::HandleMap,
+    memory::VirtualAddress,
+    platform::{branch_protection::Keys, cpu::Id as CpuId},
+    process::name::{self, Name},
+    sync::Mutex,
+    time::{Ticks, TimerId, TimerQueue},
+};
 
+pub mod kernel_thread;
 pub mod scheduler;
+pub mod switch;
+pub mod wait;
+
+use scheduler::events::{self, EventKind};
 
 /// An unique ID for a thread.
 pub type Id = u32;
-/// The largest possible thread ID in the system.
+/// The maximum number of threads in the system. Thread IDs can be larger, since their upper bits
+/// hold a generation that changes when an ID is reused.
 pub const MAX_THREAD_ID: u32 = 0xffff;
 
+/// The scheduling priority of a thread. Larger values are higher priority.
+pub type Priority = u8;
+
+/// The priority of threads that run interrupt handlers, which is the ceiling for every other
+/// thread: their base priority is at most [`MAX_PRIORITY`], so they can only reach it by inheriting
+/// it from an interrupt thread that is waiting on them.
+pub const INTERRUPT_PRIORITY: Priority = Priority::MAX;
+
+/// The highest base priority of a thread that is not an interrupt thread.
+pub const MAX_PRIORITY: Priority = INTERRUPT_PRIORITY - 1;
+
 bitfield::bitfield! {
     /// The value of the SPSR (Saved Program Status) register.
     ///
@@ -68,6 +94,22 @@ bitfield::bitfield! {
 }
 
 impl SavedProgramStatus {
+    /// The bits that hold the condition flags, which are in the same positions as in the `NZCV`
+    /// register.
+    pub const CONDITION_FLAGS: u64 = 0xf << 28;
+
+    /// The condition flags, in the same bit positions as the `NZCV` register.
+    #[must_use]
+    pub fn condition_flags(&self) -> u64 {
+        self.0 & Self::CONDITION_FLAGS
+    }
+
+    /// Set the condition flags from `flags`, in the same bit positions as the `NZCV` register.
+    /// The other bits of `flags` are ignored, so the rest of the program status is unchanged.
+    pub fn set_condition_flags(&mut self, flags: u64) {
+        self.0 = self.0 & !Self::CONDITION_FLAGS | flags & Self::CONDITION_FLAGS;
+    }
+
     /// Creates a suitable SPSR value for a thread running at EL0 (using the `SP_EL0` stack pointer).
     #[must_use]
     pub fn initial_for_el0() -> SavedProgramStatus {
@@ -76,10 +118,15 @@ impl SavedProgramStatus {
 
     /// Creates a suitable SPSR value for a thread running at EL1 with its own stack using the
     /// `SP_EL0` stack pointer.
+    ///
+    /// IRQs and FIQs are masked, because kernel threads take the same spin locks as interrupt
+    /// handlers. An interrupt taken while one of those locks was held would deadlock the core.
     #[must_use]
     pub fn initial_for_el1() -> SavedProgramStatus {
         let mut spsr = SavedProgramStatus(0);
         spsr.set_el(1);
+        spsr.set_i(true);
+        spsr.set_f(true);
         spsr
     }
 }
@@ -92,6 +139,19 @@ pub struct Registers {
     pub x: [usize; 31],
 }
 
+/// A stored version of the floating point and SIMD registers, laid out as the exception vector
+/// saves them.
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C, align(16))]
+pub struct FloatingPointRegisters {
+    /// The values of the `qN` registers in order.
+    pub q: [u128; 32],
+    /// The floating point control register (`FPCR`).
+    pub fpcr: u64,
+    /// The floating point status register (`FPSR`).
+    pub fpsr: u64,
+}
+
 /// Processor state of a thread.
 #[derive(Debug)]
 pub struct ProcessorState {
@@ -103,6 +163,16 @@ pub struct ProcessorState {
     pub stack_pointer: VirtualAddress,
     /// The current value of the `xN` registers.
     pub registers: Registers,
+    /// The current value of the floating point and SIMD registers.
+    pub fp: FloatingPointRegisters,
+    /// The thread pointer (`TPIDR_EL0`), which user space uses to find its thread-local storage.
+    pub thread_pointer: VirtualAddress,
+    /// True if the thread is being single stepped by a debugger, so software step exceptions must
+    /// be enabled while it runs. Whether the current step has completed is held in `spsr.ss`.
+    pub single_step: bool,
+    /// The pointer authentication keys of the thread's process, loaded while user threads run.
+    /// Kernel threads use the kernel's keys instead.
+    pub pointer_auth_keys: Keys,
 }
 
 impl ProcessorState {
@@ -120,6 +190,58 @@ impl ProcessorState {
             program_counter: VirtualAddress::from(0),
             stack_pointer: VirtualAddress::from(0),
             registers: Registers::default(),
+            fp: FloatingPointRegisters::default(),
+            thread_pointer: VirtualAddress::from(0),
+            single_step: false,
+            pointer_auth_keys: Keys::default(),
+        }
+    }
+
+    /// Create the initial processor state for a new user-space thread that will start executing
+    /// at `entry_point` in EL0 with its stack pointer at `stack_pointer`.
+    ///
+    /// `thread_pointer` is loaded into `TPIDR_EL0`, and should point to the thread control block
+    /// at the start of the thread's TLS block, or be zero if the thread does not use TLS.
+    #[must_use]
+    pub fn new_for_user_thread(
+        entry_point: VirtualAddress,
+        stack_pointer: VirtualAddress,
+        thread_pointer: VirtualAddress,
+    ) -> Self {
+        Self {
+            spsr: SavedProgramStatus::initial_for_el0(),
+            program_counter: entry_point,
+            stack_pointer,
+            registers: Registers::default(),
+            fp: FloatingPointRegisters::default(),
+            thread_pointer,
+            single_step: false,
+            pointer_auth_keys: Keys::default(),
+        }
+    }
+
+    /// Create the initial processor state for a new kernel thread that will start executing at
+    /// `entry_point` in EL1 with its stack pointer at `stack_pointer`.
+    ///
+    /// The thread uses the `SP_EL0` stack pointer so that exceptions are handled on the core's own
+    /// stack. `argument` is passed to the entry point in `x0`.
+    #[must_use]
+    pub fn new_for_kernel_thread(
+        entry_point: VirtualAddress,
+        stack_pointer: VirtualAddress,
+        argument: usize,
+    ) -> Self {
+        let mut registers = Registers::default();
+        registers.x[0] = argument;
+        Self {
+            spsr: SavedProgramStatus::initial_for_el1(),
+            program_counter: entry_point,
+            stack_pointer,
+            registers,
+            fp: FloatingPointRegisters::default(),
+            thread_pointer: VirtualAddress::from(0),
+            single_step: false,
+            pointer_auth_keys: Keys::default(),
         }
     }
 }
@@ -133,6 +255,11 @@ pub enum State {
     Running,
     /// Thread is blocked.
     Blocked,
+    /// Thread has finished executing and will never run again.
+    Exited,
+    /// Thread has been suspended by a debugger, and won't run until it is resumed.
+    /// See [`Thread::suspend`].
+    Suspended,
 }
 
 impl From<u8> for State {
@@ -150,7 +277,135 @@ impl From<State> for u8 {
 bitfield::bitfield! {
     struct ThreadProperties(u64);
     impl Debug;
-    u8, from into State, state, set_state: 8, 0;
+    u8, from into State, state, set_state: 3, 0;
+    u8, wait_kind, set_wait_kind: 7, 4;
+    u8, wait_outcome, set_wait_outcome: 9, 8;
+    cancelled, set_cancelled: 10;
+    u32, wait_sequence, set_wait_sequence: 30, 11;
+    suspended, set_suspended: 31;
+    // thread ids are at most `MAX_THREAD_ID`, so the target of a join fits in 16 bits
+    u32, wait_target, set_wait_target: 47, 32;
+    u8, priority, set_priority: 55, 48;
+    u8, inherited_priority, set_inherited_priority: 63, 56;
+}
+
+/// Why a thread is blocked, for blocking operations that need to be able to tell later whether a
+/// thread is still waiting for the same thing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaitReason {
+    /// Sleeping until a deadline passes.
+    Sleep,
+    /// Waiting for the thread with this id to exit.
+    Join(Id),
+    /// Waiting in a [`WaitQueue`](crate::sync::WaitQueue).
+    Queue,
+    /// Waiting to receive a message.
+    Message,
+    /// Waiting for a notification flag to be raised.
+    Notification,
+    /// Waiting on a [futex](crate::sync::futex).
+    Futex,
+    /// A kernel thread waiting to be unparked, for instance to handle an interrupt.
+    Park,
+}
+
+const WAIT_KIND_NONE: u8 = 0;
+const WAIT_KIND_SLEEP: u8 = 1;
+const WAIT_KIND_JOIN: u8 = 2;
+const WAIT_KIND_QUEUE: u8 = 3;
+const WAIT_KIND_MESSAGE: u8 = 4;
+const WAIT_KIND_NOTIFICATION: u8 = 5;
+const WAIT_KIND_FUTEX: u8 = 6;
+const WAIT_KIND_PARK: u8 = 7;
+
+/// How a blocking wait ended.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// The event the thread was waiting for happened.
+    Signaled,
+    /// The deadline of the wait passed first.
+    TimedOut,
+    /// The wait was cancelled, for instance because the thread is being terminated.
+    Cancelled,
+}
+
+const WAIT_OUTCOME_NONE: u8 = 0;
+const WAIT_OUTCOME_SIGNALED: u8 = 1;
+const WAIT_OUTCOME_TIMED_OUT: u8 = 2;
+const WAIT_OUTCOME_CANCELLED: u8 = 3;
+
+/// Identifies one particular wait of a thread, so that a late wake up (for instance from a timeout
+/// that expired after the thread was signaled) does not end a later wait of the same thread.
+///
+/// Tokens are taken from a sequence that wraps, but it is long enough that a token won't be reused
+/// while a wake up for it could still be pending.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WaitToken(u32);
+
+/// The park state of a thread that is neither parked nor has a pending unpark. Any other value
+/// apart from [`PARK_UNPARKED`] is the [`WaitToken`] of the wait the thread is parked in.
+const PARK_NONE: u32 = u32::MAX;
+/// The park state of a thread that was unparked while it was not parked.
+const PARK_UNPARKED: u32 = u32::MAX - 1;
+
+/// The wait sequence number that follows `sequence`, wrapping to fit in [`ThreadProperties`].
+fn next_wait_sequence(sequence: u32) -> u32 {
+    (sequence + 1) & ((1 << 20) - 1)
+}
+
+impl ThreadProperties {
+    fn wait_reason(&self) -> Option<WaitReason> {
+        match self.wait_kind() {
+            WAIT_KIND_SLEEP => Some(WaitReason::Sleep),
+            WAIT_KIND_JOIN => Some(WaitReason::Join(self.wait_target())),
+            WAIT_KIND_QUEUE => Some(WaitReason::Queue),
+            WAIT_KIND_MESSAGE => Some(WaitReason::Message),
+            WAIT_KIND_NOTIFICATION => Some(WaitReason::Notification),
+            WAIT_KIND_FUTEX => Some(WaitReason::Futex),
+            WAIT_KIND_PARK => Some(WaitReason::Park),
+            _ => None,
+        }
+    }
+
+    fn set_wait_reason(&mut self, reason: Option<WaitReason>) {
+        let (kind, target) = match reason {
+            None => (WAIT_KIND_NONE, 0),
+            Some(WaitReason::Sleep) => (WAIT_KIND_SLEEP, 0),
+            Some(WaitReason::Join(id)) => (WAIT_KIND_JOIN, id),
+            Some(WaitReason::Queue) => (WAIT_KIND_QUEUE, 0),
+            Some(WaitReason::Message) => (WAIT_KIND_MESSAGE, 0),
+            Some(WaitReason::Notification) => (WAIT_KIND_NOTIFICATION, 0),
+            Some(WaitReason::Futex) => (WAIT_KIND_FUTEX, 0),
+            Some(WaitReason::Park) => (WAIT_KIND_PARK, 0),
+        };
+        self.set_wait_kind(kind);
+        self.set_wait_target(target);
+    }
+
+    fn wait_outcome_value(&self) -> Option<WaitOutcome> {
+        match self.wait_outcome() {
+            WAIT_OUTCOME_SIGNALED => Some(WaitOutcome::Signaled),
+            WAIT_OUTCOME_TIMED_OUT => Some(WaitOutcome::TimedOut),
+            WAIT_OUTCOME_CANCELLED => Some(WaitOutcome::Cancelled),
+            _ => None,
+        }
+    }
+
+    fn set_wait_outcome_value(&mut self, outcome: Option<WaitOutcome>) {
+        self.set_wait_outcome(match outcome {
+            None => WAIT_OUTCOME_NONE,
+            Some(WaitOutcome::Signaled) => WAIT_OUTCOME_SIGNALED,
+            Some(WaitOutcome::TimedOut) => WAIT_OUTCOME_TIMED_OUT,
+            Some(WaitOutcome::Cancelled) => WAIT_OUTCOME_CANCELLED,
+        });
+    }
+
+    /// Finish the current wait, making the thread runnable.
+    fn end_wait(&mut self, outcome: WaitOutcome) {
+        self.set_state(State::Running);
+        self.set_wait_reason(None);
+        self.set_wait_outcome_value(Some(outcome));
+    }
 }
 
 impl ThreadProperties {
@@ -166,13 +421,39 @@ pub struct Thread {
     /// The unique id for this thread.
     pub id: Id,
 
+    /// A short name for the thread, which is shown with its id in logs.
+    name: Mutex<Name>,
+
     /// Thread status, etc
     properties: AtomicU64,
 
     /// The current processor state of the thread.
     pub processor_state: Mutex<ProcessorState>,
+
+    /// Whether the thread is parked (see [`Thread::park`]).
+    park: AtomicU32,
+
+    /// The timer that ends the thread's current wait, if it has a timeout.
+    wait_timer: Mutex<Option<(&'static TimerQueue, TimerId)>>,
+
+    /// Counter ticks spent running, up to the last time the thread stopped running.
+    runtime: AtomicU64,
+
+    /// The value of the counter when the thread last started running.
+    running_since: AtomicU64,
+
+    /// True while the thread is running on a core, from [`Thread::start_running`] until
+    /// [`Thread::stop_running`].
+    on_core: AtomicBool,
+
+    /// The process the thread belongs to, or [`NO_PROCESS`] for kernel threads.
+    process: AtomicU64,
 }
 
+/// The value of [`Thread::process`] for a thread that doesn't belong to a process, which is
+/// outside of the range of process IDs.
+const NO_PROCESS: u64 = u64::MAX;
+
 impl Thread {
     /// Create a new Thread.
     ///
@@ -188,18 +469,415 @@ impl Thread {
                 log::trace!("creating thread id={id}");
                 Arc::new(Self {
                     id,
+                    name: Mutex::new(Name::EMPTY),
                     properties: AtomicU64::new(ThreadProperties::new(initial_state).0),
                     processor_state: Mutex::new(initial_processor_state),
+                    park: AtomicU32::new(PARK_NONE),
+                    wait_timer: Mutex::new(None),
+                    runtime: AtomicU64::new(0),
+                    running_since: AtomicU64::new(0),
+                    on_core: AtomicBool::new(false),
+                    process: AtomicU64::new(NO_PROCESS),
                 })
             })
             .expect("thread ids not exhausted")
             .1
     }
 
+    /// The name of the thread, which is empty if it has not been named.
+    pub fn name(&self) -> Name {
+        *self.name.lock()
+    }
+
+    /// Display the thread the same way as its [`fmt::Display`] implementation, but leave out its
+    /// name if the name is locked rather than waiting for it, so that it can be shown while
+    /// reporting a panic.
+    pub fn display_without_waiting(&self) -> impl fmt::Display + '_ {
+        DisplayWithoutWaiting(self)
+    }
+
+    /// Change the name of the thread.
+    pub fn set_name(&self, name: Name) {
+        *self.name.lock() = name;
+    }
+
+    /// The process the thread belongs to, or `None` for a kernel thread.
+    pub fn process(&self) -> Option<super::Id> {
+        super::Id::try_from(self.process.load(Ordering::Acquire)).ok()
+    }
+
+    /// Record that the thread belongs to the process `id`. See [`super::Process::add_thread`].
+    pub(super) fn set_process(&self, id: super::Id) {
+        self.process.store(u64::from(id), Ordering::Release);
+    }
+
     /// Load current thread state.
+    ///
+    /// A thread that is suspended is [`State::Suspended`] even if it is also blocked, unless it
+    /// has exited.
     pub fn state(&self) -> State {
-        let props = ThreadProperties(self.properties.load(core::sync::atomic::Ordering::Acquire));
-        props.state()
+        let props = self.load_properties();
+        match props.state() {
+            State::Running | State::Blocked if props.suspended() => State::Suspended,
+            state => state,
+        }
+    }
+
+    fn load_properties(&self) -> ThreadProperties {
+        ThreadProperties(self.properties.load(Ordering::Acquire))
+    }
+
+    /// Atomically update the properties with `f`, returning the previous properties.
+    fn update_properties(&self, f: impl Fn(&mut ThreadProperties)) -> ThreadProperties {
+        let mut updated = 0;
+        // the update closure always returns `Some`, so this can never fail
+        let (Ok(previous) | Err(previous)) =
+            self.properties
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |p| {
+                    let mut props = ThreadProperties(p);
+                    f(&mut props);
+                    updated = props.0;
+                    Some(props.0)
+                });
+        self.record_state_change(&ThreadProperties(previous), &ThreadProperties(updated));
+        ThreadProperties(previous)
+    }
+
+    /// Atomically update the properties with `f`, unless it returns false.
+    ///
+    /// Returns the previous properties if they were updated.
+    fn try_update_properties(
+        &self,
+        mut f: impl FnMut(&mut ThreadProperties) -> bool,
+    ) -> Option<ThreadProperties> {
+        let mut updated = 0;
+        let previous = self
+            .properties
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |p| {
+                let mut props = ThreadProperties(p);
+                let update = f(&mut props);
+                updated = props.0;
+                update.then_some(props.0)
+            })
+            .ok()
+            .map(ThreadProperties)?;
+        self.record_state_change(&previous, &ThreadProperties(updated));
+        Some(previous)
+    }
+
+    /// Record a scheduling event if the thread blocked or woke up.
+    fn record_state_change(&self, previous: &ThreadProperties, updated: &ThreadProperties) {
+        match (previous.state(), updated.state()) {
+            (State::Running, State::Blocked) => {
+                events::record(EventKind::Block, self.id, 0);
+            }
+            (State::Blocked, State::Running) => {
+                events::record(EventKind::Wake, self.id, 0);
+            }
+            _ => {}
+        }
+    }
+
+    /// Atomically change the current thread state.
+    pub fn set_state(&self, new_state: State) {
+        self.update_properties(|props| props.set_state(new_state));
+    }
+
+    /// The reason the thread is blocked, if it is blocked by [`Thread::block_for`].
+    pub fn wait_reason(&self) -> Option<WaitReason> {
+        self.load_properties().wait_reason()
+    }
+
+    /// Atomically block the thread, recording `reason` as the reason why, and start a new wait.
+    ///
+    /// Returns a token identifying the wait, or `None` without blocking if the thread's waits have
+    /// been cancelled by [`Thread::cancel_waits`]. The outcome of the wait is then
+    /// [`WaitOutcome::Cancelled`].
+    pub fn block_for(&self, reason: WaitReason) -> Option<WaitToken> {
+        let previous = self.update_properties(|props| {
+            if props.cancelled() {
+                props.set_wait_outcome_value(Some(WaitOutcome::Cancelled));
+            } else {
+                props.set_state(State::Blocked);
+                props.set_wait_reason(Some(reason));
+                props.set_wait_outcome_value(None);
+                props.set_wait_sequence(next_wait_sequence(props.wait_sequence()));
+            }
+        });
+        (!previous.cancelled()).then(|| WaitToken(next_wait_sequence(previous.wait_sequence())))
+    }
+
+    /// Returns true if the thread is still blocked in the wait identified by `token`.
+    pub fn is_waiting(&self, token: WaitToken) -> bool {
+        let props = self.load_properties();
+        props.state() == State::Blocked && props.wait_sequence() == token.0
+    }
+
+    /// Atomically unblock the thread, but only if it is still blocked for `reason`.
+    /// The outcome of the wait is [`WaitOutcome::Signaled`].
+    ///
+    /// Returns true if the thread was woken.
+    pub fn wake_from(&self, reason: WaitReason) -> bool {
+        let woken = self
+            .try_update_properties(|props| {
+                if props.state() != State::Blocked || props.wait_reason() != Some(reason) {
+                    return false;
+                }
+                props.end_wait(WaitOutcome::Signaled);
+                true
+            })
+            .is_some();
+        if woken {
+            self.cancel_wait_timer();
+        }
+        woken
+    }
+
+    /// Atomically unblock the thread with `outcome`, but only if it is still blocked in the wait
+    /// identified by `token`.
+    ///
+    /// Returns true if the thread was woken. A wait can only end once, so at most one of the
+    /// signaling, timeout and cancellation paths succeeds.
+    pub fn end_wait(&self, token: WaitToken, outcome: WaitOutcome) -> bool {
+        let ended = self
+            .try_update_properties(|props| {
+                if props.state() != State::Blocked || props.wait_sequence() != token.0 {
+                    return false;
+                }
+                props.end_wait(outcome);
+                true
+            })
+            .is_some();
+        if ended {
+            if outcome == WaitOutcome::TimedOut {
+                // the timer has already expired
+                self.wait_timer.lock().take();
+            } else {
+                self.cancel_wait_timer();
+            }
+        }
+        ended
+    }
+
+    /// Record that the timer `id` in `timers` ends the wait identified by `token`, so that the
+    /// timer is cancelled if the wait ends another way first.
+    pub fn set_wait_timer(&self, token: WaitToken, timers: &'static TimerQueue, id: TimerId) {
+        *self.wait_timer.lock() = Some((timers, id));
+        // the wait may have been ended before the timer was recorded
+        if !self.is_waiting(token) {
+            self.cancel_wait_timer();
+        }
+    }
+
+    /// Cancel the timer that ends the thread's current wait, if there is one.
+    fn cancel_wait_timer(&self) {
+        let timer = self.wait_timer.lock().take();
+        if let Some((timers, id)) = timer {
+            timers.cancel(id);
+        }
+    }
+
+    /// Park the thread in the wait identified by `token`, so that it stays blocked until it is
+    /// unparked with [`Thread::unpark`]. If it was unparked since it last parked, the wait ends
+    /// immediately instead.
+    ///
+    /// Returns true if the thread is parked.
+    pub fn park(&self, token: WaitToken) -> bool {
+        // only the thread itself parks, so the state is either none or a pending unpark
+        if self
+            .park
+            .compare_exchange(PARK_NONE, token.0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            return true;
+        }
+        self.park.store(PARK_NONE, Ordering::Release);
+        self.end_wait(token, WaitOutcome::Signaled);
+        false
+    }
+
+    /// Wake the thread if it is parked, or make its next park return immediately if it is not.
+    /// Unparks don't accumulate, so a thread that is unparked several times before it parks only
+    /// skips one park.
+    ///
+    /// This neither locks nor allocates, so it can be called by interrupt handlers.
+    ///
+    /// Returns true if the thread was parked and has been woken.
+    pub fn unpark(&self) -> bool {
+        let previous = self
+            .park
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |p| {
+                Some(if p == PARK_NONE || p == PARK_UNPARKED {
+                    PARK_UNPARKED
+                } else {
+                    PARK_NONE
+                })
+            })
+            .unwrap_or_else(|p| p);
+        if previous == PARK_NONE || previous == PARK_UNPARKED {
+            return false;
+        }
+        if self.end_wait(WaitToken(previous), WaitOutcome::Signaled) {
+            return true;
+        }
+        // the park was already ended some other way, so the next one should return immediately
+        let _ = self.park.compare_exchange(
+            PARK_NONE,
+            PARK_UNPARKED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        false
+    }
+
+    /// Cancel the current wait of the thread (if it is blocked) and every future wait, for
+    /// instance because the thread is being terminated.
+    ///
+    /// Returns true if the thread was blocked and has been woken.
+    pub fn cancel_waits(&self) -> bool {
+        let previous = self.update_properties(|props| {
+            props.set_cancelled(true);
+            if props.state() == State::Blocked {
+                props.end_wait(WaitOutcome::Cancelled);
+            }
+        });
+        self.cancel_wait_timer();
+        previous.state() == State::Blocked
+    }
+
+    /// Returns true if the thread's waits have been cancelled by [`Thread::cancel_waits`].
+    pub fn waits_cancelled(&self) -> bool {
+        self.load_properties().cancelled()
+    }
+
+    /// Take the outcome of the thread's last wait, if it has ended and the outcome hasn't been
+    /// taken yet.
+    pub fn take_wait_outcome(&self) -> Option<WaitOutcome> {
+        let previous = self.try_update_properties(|props| {
+            if props.state() == State::Blocked || props.wait_outcome_value().is_none() {
+                return false;
+            }
+            props.set_wait_outcome_value(None);
+            true
+        })?;
+        previous.wait_outcome_value()
+    }
+
+    /// Suspend the thread, so that it is not scheduled until [`Thread::resume`] is called.
+    /// A thread that is running on a core stops being scheduled at that core's next time slice.
+    ///
+    /// A suspended thread can still be blocked and woken, and it resumes in whatever state it was
+    /// left in. Returns false if the thread was already suspended or has exited.
+    pub fn suspend(&self) -> bool {
+        self.try_update_properties(|props| {
+            if props.suspended() || props.state() == State::Exited {
+                return false;
+            }
+            props.set_suspended(true);
+            true
+        })
+        .is_some()
+    }
+
+    /// Resume the thread after it was suspended by [`Thread::suspend`].
+    ///
+    /// Returns false if the thread was not suspended.
+    pub fn resume(&self) -> bool {
+        self.try_update_properties(|props| {
+            if !props.suspended() {
+                return false;
+            }
+            props.set_suspended(false);
+            true
+        })
+        .is_some()
+    }
+
+    /// Returns true if the thread has been suspended by [`Thread::suspend`].
+    pub fn is_suspended(&self) -> bool {
+        self.load_properties().suspended()
+    }
+
+    /// Load the base priority of the thread.
+    pub fn priority(&self) -> Priority {
+        self.load_properties().priority()
+    }
+
+    /// Atomically change the base priority of the thread, which is limited to [`MAX_PRIORITY`].
+    pub fn set_priority(&self, priority: Priority) {
+        self.update_properties(|props| props.set_priority(priority.min(MAX_PRIORITY)));
+    }
+
+    /// Atomically raise the base priority of the thread to [`INTERRUPT_PRIORITY`], because it runs
+    /// interrupt handlers.
+    pub fn set_interrupt_priority(&self) {
+        self.update_properties(|props| props.set_priority(INTERRUPT_PRIORITY));
+    }
+
+    /// The priority the thread should be scheduled at, which is the larger of its base priority
+    /// and any priority it has inherited.
+    pub fn effective_priority(&self) -> Priority {
+        let props = self.load_properties();
+        props.priority().max(props.inherited_priority())
+    }
+
+    /// Raise the inherited priority of this thread to at least `priority`, for instance because a
+    /// higher priority thread is waiting on it.
+    pub fn inherit_priority(&self, priority: Priority) {
+        self.update_properties(|props| {
+            let p = props.inherited_priority().max(priority);
+            props.set_inherited_priority(p);
+        });
+    }
+
+    /// Clear any priority this thread has inherited.
+    pub fn clear_inherited_priority(&self) {
+        self.update_properties(|props| props.set_inherited_priority(0));
+    }
+
+    /// Record that the thread started running on a core when the counter read `now`.
+    pub fn start_running(&self, now: Ticks) {
+        self.running_since.store(now, Ordering::Relaxed);
+        self.on_core.store(true, Ordering::Release);
+    }
+
+    /// Record that the thread stopped running when the counter read `now`, adding the time since
+    /// [`Thread::start_running`] was last called to its runtime.
+    pub fn stop_running(&self, now: Ticks) {
+        let since = self.running_since.swap(now, Ordering::Relaxed);
+        self.runtime
+            .fetch_add(now.saturating_sub(since), Ordering::Relaxed);
+        // the thread's processor state has already been saved, so it is visible once this is
+        self.on_core.store(false, Ordering::Release);
+    }
+
+    /// Returns true if the thread is running on a core, so its saved processor state may be out of
+    /// date.
+    pub fn is_on_core(&self) -> bool {
+        self.on_core.load(Ordering::Acquire)
+    }
+
+    /// The number of counter ticks the thread has spent running, up to the last time it stopped
+    /// running.
+    pub fn runtime(&self) -> Ticks {
+        self.runtime.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Thread {
+    /// Shows the thread's id, followed by its name in parentheses if it has one.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        name::write_labelled(f, self.id, self.name())
+    }
+}
+
+/// A thread shown without waiting for its name (see [`Thread::display_without_waiting`]).
+struct DisplayWithoutWaiting<'t>(&'t Thread);
+
+impl fmt::Display for DisplayWithoutWaiting<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.0.name.try_lock().map_or(Name::EMPTY, |name| *name);
+        name::write_labelled(f, self.0.id, name)
     }
 }
 
@@ -211,4 +889,35 @@ pub trait Scheduler: Sync {
 
     /// Update the scheduler for a new time slice, potentially scheduling a new current thread.
     fn next_time_slice(&self);
+
+    /// True if the current core is running its idle thread and has nothing else to run, so it
+    /// does not need a time slice tick.
+    ///
+    /// A core that is idle will not notice threads that become runnable on it until it is next
+    /// interrupted, for instance by the reschedule IPI sent when another core gives it a thread.
+    fn is_idle(&self) -> bool;
+
+    /// Add a new thread to the scheduler so that it will be run.
+    fn add_thread(&self, thread: Arc<Thread>);
+
+    /// Remove a thread from the scheduler so that it will no longer be run.
+    /// If the thread is currently running, it will stop being scheduled at the next time slice of
+    /// its CPU, which is interrupted if it is another one.
+    fn remove_thread(&self, id: Id);
+
+    /// Stop placing threads on the core `cpu` because it is offline, for instance because it
+    /// failed to boot. Threads waiting to run on it are moved to the other cores.
+    ///
+    /// The core must not be running anything but its idle thread.
+    fn remove_core(&self, cpu: CpuId);
+
+    /// Block a thread so that it is not scheduled until [`Scheduler::unblock`] is called.
+    fn block(&self, thread: &Thread) {
+        thread.set_state(State::Blocked);
+    }
+
+    /// Unblock a thread so that it can be scheduled again.
+    fn unblock(&self, thread: &Thread) {
+        thread.set_state(State::Running);
+    }
 }
@@ -0,0 +1,258 @@
+//! A trace of scheduling events, kept in a ring buffer for each core.
+//!
+//! Whenever the scheduler switches threads, a thread blocks or wakes, or a thread is placed on a
+//! core's run queue, an [`Event`] is recorded on the current core with a timestamp from the system
+//! counter. Only the most recent events are kept, so tracing can be left on and the rings read
+//! back after a latency problem has been noticed.
+//!
+//! Events are read out in a binary format meant to be decoded by a tool on the host. Each event
+//! is [`EVENT_LEN`] bytes, with all fields little endian:
+//!
+//! | Offset | Size | Field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 1    | [`EVENT_MAGIC`]                              |
+//! | 1      | 1    | Kind (see [`EventKind`])                     |
+//! | 2      | 2    | Reserved, zero                               |
+//! | 4      | 4    | Core ID                                      |
+//! | 8      | 4    | Thread ID                                    |
+//! | 12     | 4    | Argument, depending on the kind              |
+//! | 16     | 8    | Timestamp in system counter ticks            |
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use spin::Once;
+
+use crate::{
+    platform::cpu::{CpuIdReader, Id as CpuId},
+    process::thread::Id as ThreadId,
+    time::{CounterReader, Ticks},
+    trace::ring::PerCoreRings,
+};
+
+/// The first byte of every encoded event, so that a decoder can tell if the stream is corrupted.
+pub const EVENT_MAGIC: u8 = 0x5e;
+
+/// The size of an encoded event in bytes.
+pub const EVENT_LEN: usize = 24;
+
+/// What happened in a scheduling event.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// The core switched to running the thread. The argument is the thread that was running before.
+    Switch = 1,
+    /// The thread blocked.
+    Block = 2,
+    /// The thread was woken by the core the event was recorded on.
+    Wake = 3,
+    /// The thread was queued to run on the core given by the argument.
+    Migrate = 4,
+}
+
+impl EventKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Switch),
+            2 => Some(Self::Block),
+            3 => Some(Self::Wake),
+            4 => Some(Self::Migrate),
+            _ => None,
+        }
+    }
+}
+
+/// A single scheduling event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// The value of the system counter when the event happened.
+    pub timestamp: Ticks,
+    /// What happened.
+    pub kind: EventKind,
+    /// The core the event was recorded on.
+    pub cpu: u32,
+    /// The thread the event happened to.
+    pub thread: ThreadId,
+    /// Extra information, depending on the kind of event.
+    pub argument: u32,
+}
+
+impl Event {
+    /// Encode the event in the binary format.
+    #[must_use]
+    pub fn encode(&self) -> [u8; EVENT_LEN] {
+        let mut data = [0; EVENT_LEN];
+        data[0] = EVENT_MAGIC;
+        data[1] = self.kind as u8;
+        data[4..8].copy_from_slice(&self.cpu.to_le_bytes());
+        data[8..12].copy_from_slice(&self.thread.to_le_bytes());
+        data[12..16].copy_from_slice(&self.argument.to_le_bytes());
+        data[16..24].copy_from_slice(&self.timestamp.to_le_bytes());
+        data
+    }
+
+    /// Decode an event from the start of `data`, or return `None` if it doesn't hold a valid event.
+    #[must_use]
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..EVENT_LEN)?;
+        if data[0] != EVENT_MAGIC {
+            return None;
+        }
+        Some(Self {
+            timestamp: LittleEndian::read_u64(&data[16..]),
+            kind: EventKind::from_u8(data[1])?,
+            cpu: LittleEndian::read_u32(&data[4..]),
+            thread: LittleEndian::read_u32(&data[8..]),
+            argument: LittleEndian::read_u32(&data[12..]),
+        })
+    }
+}
+
+/// Records scheduling events into a ring buffer for each core.
+pub struct Tracer {
+    rings: PerCoreRings<Event>,
+}
+
+impl Tracer {
+    /// Create a tracer that keeps the last `capacity` events of each of the cores `cpus`, reading
+    /// the current core with `C` and timestamps with `R`.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is zero.
+    pub fn new<C: CpuIdReader, R: CounterReader>(
+        cpus: impl IntoIterator<Item = CpuId>,
+        capacity: usize,
+    ) -> Self {
+        Self {
+            rings: PerCoreRings::new::<C, R>(cpus, capacity),
+        }
+    }
+
+    /// Record an event of `kind` for `thread` on the current core.
+    ///
+    /// If the ring is full, the oldest event is discarded. If the ring is being read, for instance
+    /// because the event happened in an interrupt, the new event is discarded instead.
+    pub fn record(&self, kind: EventKind, thread: ThreadId, argument: u32) {
+        self.rings.push(|cpu, timestamp| Event {
+            timestamp,
+            kind,
+            cpu,
+            thread,
+            argument,
+        });
+    }
+
+    /// The events recorded on the core `cpu`, oldest first, without removing them.
+    #[must_use]
+    pub fn events(&self, cpu: CpuId) -> Vec<Event> {
+        self.rings.records(cpu)
+    }
+
+    /// Remove as many of the events recorded on the core `cpu` as fit in `buffer`, oldest first,
+    /// encoding them in the binary format. Returns the number of bytes written.
+    pub fn read(&self, cpu: CpuId, buffer: &mut [u8]) -> usize {
+        self.rings.read(cpu, buffer, Event::encode)
+    }
+
+    /// The number of old events that have been discarded on the core `cpu` to make room for new
+    /// ones.
+    #[must_use]
+    pub fn dropped(&self, cpu: CpuId) -> u64 {
+        self.rings.dropped(cpu)
+    }
+}
+
+/// The kernel's tracer, shared by the schedulers and threads.
+static TRACER: Once<Tracer> = Once::new();
+
+/// Start tracing scheduling events on the cores `cpus` for the whole kernel, keeping the last
+/// `capacity` events of each core.
+pub fn init<C: CpuIdReader, R: CounterReader>(
+    cpus: impl IntoIterator<Item = CpuId>,
+    capacity: usize,
+) -> &'static Tracer {
+    TRACER.call_once(|| Tracer::new::<C, R>(cpus, capacity))
+}
+
+/// The kernel's tracer, if [`init`] has been called.
+pub fn global() -> Option<&'static Tracer> {
+    TRACER.get()
+}
+
+/// Record an event with the kernel's tracer, if tracing has been started.
+pub fn record(kind: EventKind, thread: ThreadId, argument: u32) {
+    if let Some(tracer) = TRACER.get() {
+        tracer.record(kind, thread, argument);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::cpu::tests::TestCpu;
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static COUNTER: Cell<Ticks> = const { Cell::new(0) };
+    }
+
+    struct TestCounter;
+
+    impl CounterReader for TestCounter {
+        fn read() -> Ticks {
+            COUNTER.get()
+        }
+    }
+
+    #[test]
+    fn events_are_recorded_per_core() {
+        let tracer = Tracer::new::<TestCpu, TestCounter>([0, 1], 2);
+        COUNTER.set(10);
+        tracer.record(EventKind::Switch, 3, 1);
+        TestCpu::set(1);
+        COUNTER.set(20);
+        tracer.record(EventKind::Block, 4, 0);
+        tracer.record(EventKind::Wake, 5, 0);
+        tracer.record(EventKind::Migrate, 5, 0);
+        // unknown cores are ignored
+        TestCpu::set(7);
+        tracer.record(EventKind::Wake, 5, 0);
+
+        assert_eq!(
+            tracer.events(0),
+            [Event {
+                timestamp: 10,
+                kind: EventKind::Switch,
+                cpu: 0,
+                thread: 3,
+                argument: 1,
+            }]
+        );
+        let kinds: Vec<_> = tracer.events(1).iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [EventKind::Wake, EventKind::Migrate]);
+        assert_eq!(tracer.dropped(0), 0);
+        assert_eq!(tracer.dropped(1), 1);
+        assert!(tracer.events(7).is_empty());
+    }
+
+    #[test]
+    fn read_encodes_and_removes_events() {
+        let tracer = Tracer::new::<TestCpu, TestCounter>([0], 8);
+        for i in 0..3 {
+            COUNTER.set(0x1_0000_0000 + i);
+            tracer.record(EventKind::Switch, 2, 1);
+        }
+
+        // only whole events are written
+        let mut buffer = [0; EVENT_LEN * 2 + 5];
+        assert_eq!(tracer.read(0, &mut buffer), EVENT_LEN * 2);
+        assert_eq!(buffer[0], EVENT_MAGIC);
+        assert_eq!(buffer[1], EventKind::Switch as u8);
+        let second = Event::decode(&buffer[EVENT_LEN..]).unwrap();
+        assert_eq!(second.timestamp, 0x1_0000_0001);
+        assert_eq!((second.thread, second.argument), (2, 1));
+        assert!(Event::decode(&buffer[EVENT_LEN * 2..]).is_none());
+
+        assert_eq!(tracer.events(0).len(), 1);
+        assert_eq!(tracer.read(0, &mut buffer), EVENT_LEN);
+        assert_eq!(tracer.read(0, &mut buffer), 0);
+    }
+}
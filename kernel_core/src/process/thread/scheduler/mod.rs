@@ -0,0 +1,439 @@
+//! Thread scheduler implementation.
+use core::marker::PhantomData;
+
+use super::{Id as ThreadId, Scheduler, State, Thread};
+use crate::collections::ArcSwap;
+use crate::platform::cpu::{CpuIdReader, Id as CpuId, Topology};
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crossbeam::queue::SegQueue;
+use hashbrown::{HashMap, HashSet};
+use log::trace;
+
+pub mod events;
+pub mod priority;
+pub use priority::PriorityScheduler;
+
+use events::EventKind;
+
+/// Scheduler state for a single CPU.
+struct PerCpu {
+    /// Threads waiting to run on this CPU, excluding the current thread and the idle thread.
+    queue: SegQueue<Arc<Thread>>,
+    current_thread: ArcSwap<Thread>,
+    idle_thread: Arc<Thread>,
+    /// False once the CPU has been removed, so that no more threads are placed on it.
+    online: AtomicBool,
+}
+
+/// A simple round-robin thread scheduler.
+///
+/// Each CPU has its own run queue. The idle thread for a CPU only runs when no other thread in
+/// its queue is runnable.
+pub struct RoundRobinScheduler<C: CpuIdReader> {
+    cpus: HashMap<CpuId, PerCpu>,
+    /// Threads that were removed while they were running, and should not be re-queued.
+    removed_threads: Mutex<HashSet<ThreadId>>,
+    /// The arrangement of the CPUs, used to keep new threads close to the CPU that added them.
+    topology: Option<Topology>,
+    /// Interrupts an idle CPU that was given a thread, since it has no tick to notice it by.
+    wake: Option<fn(CpuId)>,
+    cpu_id_reader: PhantomData<C>,
+}
+
+impl<C: CpuIdReader> RoundRobinScheduler<C> {
+    /// Create a new scheduler.
+    ///
+    /// The vector `cpus` contains a set of CPU id, idle thread pairs for each core in the system.
+    /// Each idle thread must be distinct, and will run first.
+    /// The CPU ids must match those provided by [`CpuIdReader::current_cpu()`] given `C`.
+    #[must_use]
+    pub fn new(cpus: &[(CpuId, Arc<Thread>)]) -> Self {
+        trace!("Creating RoundRobinScheduler for {} cpus", cpus.len());
+        RoundRobinScheduler {
+            cpus: cpus
+                .iter()
+                .map(|(id, idle_thread)| {
+                    (
+                        *id,
+                        PerCpu {
+                            queue: SegQueue::new(),
+                            current_thread: ArcSwap::new(idle_thread.clone()),
+                            idle_thread: idle_thread.clone(),
+                            online: AtomicBool::new(true),
+                        },
+                    )
+                })
+                .collect(),
+            removed_threads: Mutex::new(HashSet::new()),
+            topology: None,
+            wake: None,
+            cpu_id_reader: PhantomData,
+        }
+    }
+
+    /// Call `wake` with the id of an idle CPU whenever a thread is placed on it by another CPU, so
+    /// that it can be interrupted (for instance with a reschedule IPI) and start running the thread.
+    /// CPUs running a thread that another CPU removes are woken the same way, to stop running it.
+    #[must_use]
+    pub fn with_wake(mut self, wake: fn(CpuId)) -> Self {
+        self.wake = Some(wake);
+        self
+    }
+
+    /// Prefer placing new threads on CPUs in the same cluster as the CPU that adds them, according
+    /// to `topology`.
+    #[must_use]
+    pub fn with_topology(mut self, topology: Topology) -> Self {
+        self.topology = Some(topology);
+        self
+    }
+
+    /// The thread running on the CPU `cpu`, read without taking any lock, so that it can be found
+    /// even if that CPU is stuck. Returns `None` if the CPU is unknown.
+    #[must_use]
+    pub fn current_thread_on(&self, cpu: CpuId) -> Option<Arc<Thread>> {
+        Some(self.cpus.get(&cpu)?.current_thread.load())
+    }
+
+    fn current_cpu(&self) -> &PerCpu {
+        self.cpus.get(&C::current_cpu()).expect("cpu has state")
+    }
+
+    /// Find the next runnable thread in the queue, keeping any blocked threads in the queue.
+    fn next_runnable(queue: &SegQueue<Arc<Thread>>) -> Option<Arc<Thread>> {
+        for _ in 0..queue.len() {
+            let t = queue.pop()?;
+            if t.state() == State::Running {
+                return Some(t);
+            }
+            queue.push(t);
+        }
+        None
+    }
+}
+
+/// The cost of placing a thread on CPU `to` instead of the current CPU, or zero if the topology is
+/// unknown.
+fn migration_cost<C: CpuIdReader>(topology: Option<&Topology>, to: CpuId) -> usize {
+    topology.map_or(0, |t| t.migration_cost(C::current_cpu(), to))
+}
+
+impl<C: CpuIdReader> Scheduler for RoundRobinScheduler<C> {
+    fn current_thread(&self) -> Arc<Thread> {
+        self.current_cpu().current_thread.load()
+    }
+
+    fn next_time_slice(&self) {
+        let cpu = self.current_cpu();
+        let current = cpu.current_thread.load();
+        let current_is_idle = Arc::ptr_eq(&current, &cpu.idle_thread);
+        let current_removed = !current_is_idle && self.removed_threads.lock().remove(&current.id);
+
+        let next_thread = match Self::next_runnable(&cpu.queue) {
+            Some(t) => t,
+            // keep running the current thread if nothing else can run
+            None if !current_removed && current.state() == State::Running => return,
+            None if current_is_idle => return,
+            None => cpu.idle_thread.clone(),
+        };
+
+        trace!("switching from thread {current} to {next_thread}");
+        events::record(EventKind::Switch, next_thread.id, current.id);
+        let last_thread = cpu.current_thread.swap(next_thread);
+        if !current_is_idle && !current_removed {
+            cpu.queue.push(last_thread);
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        let cpu = self.current_cpu();
+        // blocked threads in the queue could be unblocked at any time, so only an empty queue counts
+        Arc::ptr_eq(&cpu.current_thread.load(), &cpu.idle_thread) && cpu.queue.is_empty()
+    }
+
+    fn add_thread(&self, thread: Arc<Thread>) {
+        // place the thread on the least busy CPU, counting the cost of moving away from this one
+        let (cpu_id, cpu) = self
+            .cpus
+            .iter()
+            .filter(|(_, cpu)| cpu.online.load(Ordering::Acquire))
+            .min_by_key(|(id, cpu)| {
+                cpu.queue.len() + migration_cost::<C>(self.topology.as_ref(), **id)
+            })
+            .expect("at least one online cpu");
+        trace!("adding thread {thread} to cpu {cpu_id}");
+        events::record(
+            EventKind::Migrate,
+            thread.id,
+            u32::try_from(*cpu_id).unwrap_or(u32::MAX),
+        );
+        let was_idle =
+            Arc::ptr_eq(&cpu.current_thread.load(), &cpu.idle_thread) && cpu.queue.is_empty();
+        cpu.queue.push(thread);
+        if was_idle && *cpu_id != C::current_cpu() {
+            if let Some(wake) = self.wake {
+                wake(*cpu_id);
+            }
+        }
+    }
+
+    fn remove_thread(&self, id: ThreadId) {
+        trace!("removing thread {id}");
+        for (cpu_id, cpu) in &self.cpus {
+            for _ in 0..cpu.queue.len() {
+                match cpu.queue.pop() {
+                    Some(t) if t.id == id => {}
+                    Some(t) => cpu.queue.push(t),
+                    None => break,
+                }
+            }
+            if cpu.current_thread.load().id == id {
+                self.removed_threads.lock().insert(id);
+                if *cpu_id != C::current_cpu() {
+                    if let Some(wake) = self.wake {
+                        wake(*cpu_id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_core(&self, cpu_id: CpuId) {
+        let Some(cpu) = self.cpus.get(&cpu_id) else {
+            return;
+        };
+        trace!("removing cpu {cpu_id}");
+        cpu.online.store(false, Ordering::Release);
+        while let Some(t) = cpu.queue.pop() {
+            self.add_thread(t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        platform::cpu::tests::FixedCpu,
+        process::thread::{ProcessorState, MAX_THREAD_ID},
+    };
+
+    fn new_thread(threads: &HandleMap<Thread>) -> Arc<Thread> {
+        Thread::new(threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        })
+    }
+
+    fn setup() -> (
+        HandleMap<Thread>,
+        Arc<Thread>,
+        RoundRobinScheduler<FixedCpu<0>>,
+    ) {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let idle = new_thread(&threads);
+        let sched = RoundRobinScheduler::new(&[(0, idle.clone())]);
+        (threads, idle, sched)
+    }
+
+    #[test]
+    fn idle_runs_when_empty() {
+        let (_threads, idle, sched) = setup();
+        assert_eq!(sched.current_thread().id, idle.id);
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, idle.id);
+        assert!(sched.is_idle());
+    }
+
+    #[test]
+    fn not_idle_with_queued_thread() {
+        let (threads, _idle, sched) = setup();
+        let t = new_thread(&threads);
+        sched.add_thread(t.clone());
+        assert!(!sched.is_idle());
+        sched.next_time_slice();
+        assert!(!sched.is_idle());
+        sched.remove_thread(t.id);
+        sched.next_time_slice();
+        assert!(sched.is_idle());
+    }
+
+    #[test]
+    fn fairness() {
+        let (threads, idle, sched) = setup();
+        let ts: Vec<_> = (0..3).map(|_| new_thread(&threads)).collect();
+        for t in &ts {
+            sched.add_thread(t.clone());
+        }
+        let mut counts = std::collections::HashMap::new();
+        for _ in 0..30 {
+            sched.next_time_slice();
+            *counts.entry(sched.current_thread().id).or_insert(0) += 1;
+        }
+        assert!(!counts.contains_key(&idle.id), "idle thread should not run");
+        for t in &ts {
+            assert_eq!(counts[&t.id], 10);
+        }
+    }
+
+    #[test]
+    fn single_thread_keeps_running() {
+        let (threads, _idle, sched) = setup();
+        let t = new_thread(&threads);
+        sched.add_thread(t.clone());
+        for _ in 0..5 {
+            sched.next_time_slice();
+            assert_eq!(sched.current_thread().id, t.id);
+        }
+        assert_eq!(sched.current_thread_on(0).map(|c| c.id), Some(t.id));
+        assert!(sched.current_thread_on(1).is_none());
+    }
+
+    #[test]
+    fn block_and_unblock() {
+        let (threads, idle, sched) = setup();
+        let a = new_thread(&threads);
+        let b = new_thread(&threads);
+        sched.add_thread(a.clone());
+        sched.add_thread(b.clone());
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, a.id);
+
+        sched.block(&b);
+        for _ in 0..3 {
+            sched.next_time_slice();
+            assert_eq!(sched.current_thread().id, a.id);
+        }
+
+        // blocking the running thread with nothing else to run switches to idle
+        sched.block(&a);
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, idle.id);
+
+        sched.unblock(&b);
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, b.id);
+        sched.unblock(&a);
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, a.id);
+    }
+
+    #[test]
+    fn remove_queued_and_running() {
+        let (threads, idle, sched) = setup();
+        let a = new_thread(&threads);
+        let b = new_thread(&threads);
+        sched.add_thread(a.clone());
+        sched.add_thread(b.clone());
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, a.id);
+
+        sched.remove_thread(b.id);
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, a.id);
+
+        sched.remove_thread(a.id);
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, idle.id);
+        sched.next_time_slice();
+        assert_eq!(sched.current_thread().id, idle.id);
+    }
+
+    #[test]
+    fn add_thread_balances_cpus() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = RoundRobinScheduler::<FixedCpu<0>>::new(&[
+            (0, new_thread(&threads)),
+            (1, new_thread(&threads)),
+        ]);
+        for _ in 0..4 {
+            sched.add_thread(new_thread(&threads));
+        }
+        assert_eq!(sched.cpus[&0].queue.len(), 2);
+        assert_eq!(sched.cpus[&1].queue.len(), 2);
+    }
+
+    #[test]
+    fn add_thread_wakes_idle_cpus() {
+        use core::sync::atomic::AtomicUsize;
+
+        static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = RoundRobinScheduler::<FixedCpu<0>>::new(&[
+            (0, new_thread(&threads)),
+            (1, new_thread(&threads)),
+        ])
+        .with_wake(|id| {
+            assert_eq!(id, 1, "only other cpus are woken");
+            WOKEN.fetch_add(1, Ordering::Relaxed);
+        });
+        for _ in 0..4 {
+            sched.add_thread(new_thread(&threads));
+        }
+        // cpu 1 is only idle until it is given its first thread
+        assert_eq!(WOKEN.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn removing_thread_wakes_its_cpu() {
+        use core::sync::atomic::AtomicUsize;
+
+        static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = RoundRobinScheduler::<FixedCpu<0>>::new(&[
+            (0, new_thread(&threads)),
+            (1, new_thread(&threads)),
+        ])
+        .with_wake(|id| {
+            assert_eq!(id, 1, "only other cpus are woken");
+            WOKEN.fetch_add(1, Ordering::Relaxed);
+        });
+        let a = new_thread(&threads);
+        let b = new_thread(&threads);
+        sched.cpus[&0].current_thread.swap(a.clone());
+        sched.cpus[&1].current_thread.swap(b.clone());
+        sched.remove_thread(a.id);
+        assert_eq!(WOKEN.load(Ordering::Relaxed), 0);
+        sched.remove_thread(b.id);
+        assert_eq!(WOKEN.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn add_thread_prefers_nearby_cpus() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = RoundRobinScheduler::<FixedCpu<1>>::new(&[
+            (0, new_thread(&threads)),
+            (1, new_thread(&threads)),
+        ])
+        .with_topology(Topology::flat([0, 1]));
+        for _ in 0..4 {
+            sched.add_thread(new_thread(&threads));
+        }
+        // staying on the current CPU is cheaper than moving within the cluster
+        assert_eq!(sched.cpus[&1].queue.len(), 3);
+        assert_eq!(sched.cpus[&0].queue.len(), 1);
+    }
+
+    #[test]
+    fn removed_cpu_gets_no_threads() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = RoundRobinScheduler::<FixedCpu<0>>::new(&[
+            (0, new_thread(&threads)),
+            (1, new_thread(&threads)),
+        ]);
+        for _ in 0..4 {
+            sched.add_thread(new_thread(&threads));
+        }
+        sched.remove_core(1);
+        assert_eq!(sched.cpus[&0].queue.len(), 4);
+        assert!(sched.cpus[&1].queue.is_empty());
+        sched.add_thread(new_thread(&threads));
+        assert_eq!(sched.cpus[&0].queue.len(), 5);
+    }
+}
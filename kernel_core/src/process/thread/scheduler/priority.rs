@@ -0,0 +1,471 @@
+//! Priority-based preemptive scheduler.
+use core::marker::PhantomData;
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use hashbrown::HashMap;
+use log::trace;
+
+use crate::platform::cpu::{CpuIdReader, Id as CpuId, Topology};
+use crate::process::thread::{Id as ThreadId, Priority, Scheduler, State, Thread};
+use crate::sync::Mutex;
+
+use super::{
+    events::{self, EventKind},
+    migration_cost,
+};
+
+/// The default number of time slices a thread must wait before it is promoted by one priority level.
+pub const DEFAULT_AGING_INTERVAL: usize = 8;
+
+struct QueueEntry {
+    thread: Arc<Thread>,
+    /// The time slice at which the thread was queued.
+    queued_at: usize,
+}
+
+struct RunQueues {
+    /// Queues of threads for each priority level, indexed by level.
+    levels: Vec<VecDeque<QueueEntry>>,
+    current_thread: Arc<Thread>,
+    idle_thread: Arc<Thread>,
+    /// Number of time slices that have elapsed on this CPU.
+    ticks: usize,
+    /// The current thread was removed and should not be re-queued.
+    current_removed: bool,
+    /// False once the CPU has been removed, so that no more threads are placed on it.
+    online: bool,
+}
+
+impl RunQueues {
+    fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    fn remove(&mut self, id: ThreadId) -> Option<Arc<Thread>> {
+        for level in &mut self.levels {
+            if let Some(i) = level.iter().position(|e| e.thread.id == id) {
+                return level.remove(i).map(|e| e.thread);
+            }
+        }
+        None
+    }
+}
+
+/// A preemptive scheduler that always runs the highest priority runnable thread.
+///
+/// Threads of the same priority are scheduled round-robin.
+/// To avoid starvation, threads that have been waiting to run are aged: each
+/// `aging_interval` time slices spent waiting raise the priority a thread is considered at by one level.
+/// The priority of a thread is given by [`Thread::effective_priority`], clamped to the number of levels.
+pub struct PriorityScheduler<C: CpuIdReader> {
+    cpus: HashMap<CpuId, Mutex<RunQueues>>,
+    num_levels: usize,
+    aging_interval: usize,
+    /// The arrangement of the CPUs, used to keep new threads close to the CPU that added them.
+    topology: Option<Topology>,
+    /// Interrupts an idle CPU that was given a thread, since it has no tick to notice it by.
+    wake: Option<fn(CpuId)>,
+    cpu_id_reader: PhantomData<C>,
+}
+
+impl<C: CpuIdReader> PriorityScheduler<C> {
+    /// Create a new scheduler with `num_levels` priority levels.
+    ///
+    /// The vector `cpus` contains a set of CPU id, idle thread pairs for each core in the system.
+    /// Each idle thread must be distinct, and will run first.
+    /// The CPU ids must match those provided by [`CpuIdReader::current_cpu()`] given `C`.
+    ///
+    /// # Panics
+    /// Panics if `num_levels` or `aging_interval` is zero, or if `num_levels` is greater than the
+    /// number of possible priority values.
+    #[must_use]
+    pub fn new(cpus: &[(CpuId, Arc<Thread>)], num_levels: usize, aging_interval: usize) -> Self {
+        assert!(num_levels > 0 && num_levels <= usize::from(Priority::MAX) + 1);
+        assert!(aging_interval > 0);
+        trace!(
+            "Creating PriorityScheduler for {} cpus with {num_levels} levels",
+            cpus.len()
+        );
+        Self {
+            cpus: cpus
+                .iter()
+                .map(|(id, idle_thread)| {
+                    (
+                        *id,
+                        Mutex::new(RunQueues {
+                            levels: (0..num_levels).map(|_| VecDeque::new()).collect(),
+                            current_thread: idle_thread.clone(),
+                            idle_thread: idle_thread.clone(),
+                            ticks: 0,
+                            current_removed: false,
+                            online: true,
+                        }),
+                    )
+                })
+                .collect(),
+            num_levels,
+            aging_interval,
+            topology: None,
+            wake: None,
+            cpu_id_reader: PhantomData,
+        }
+    }
+
+    /// Call `wake` with the id of an idle CPU whenever a thread is placed on it by another CPU, so
+    /// that it can be interrupted (for instance with a reschedule IPI) and start running the thread.
+    /// CPUs running a thread that another CPU removes are woken the same way, to stop running it.
+    #[must_use]
+    pub fn with_wake(mut self, wake: fn(CpuId)) -> Self {
+        self.wake = Some(wake);
+        self
+    }
+
+    /// Prefer placing new threads on CPUs in the same cluster as the CPU that adds them, according
+    /// to `topology`.
+    #[must_use]
+    pub fn with_topology(mut self, topology: Topology) -> Self {
+        self.topology = Some(topology);
+        self
+    }
+
+    fn level_for(&self, thread: &Thread) -> usize {
+        usize::from(thread.effective_priority()).min(self.num_levels - 1)
+    }
+
+    fn enqueue(&self, rq: &mut RunQueues, thread: Arc<Thread>) {
+        let level = self.level_for(&thread);
+        rq.levels[level].push_back(QueueEntry {
+            thread,
+            queued_at: rq.ticks,
+        });
+    }
+
+    /// Find the runnable thread with the highest aged priority, returning its level, index in that level and aged priority.
+    fn best_candidate(&self, rq: &RunQueues) -> Option<(usize, usize, usize)> {
+        let mut best: Option<(usize, usize, usize)> = None;
+        for (level, queue) in rq.levels.iter().enumerate().rev() {
+            // the first runnable thread in a level has been waiting the longest
+            let Some((index, entry)) = queue
+                .iter()
+                .enumerate()
+                .find(|(_, e)| e.thread.state() == State::Running)
+            else {
+                continue;
+            };
+            let aged = (level + (rq.ticks - entry.queued_at) / self.aging_interval)
+                .min(self.num_levels - 1);
+            if best.is_none_or(|(_, _, p)| aged > p) {
+                best = Some((level, index, aged));
+            }
+        }
+        best
+    }
+
+    /// Called when the priority of a thread changes (for instance due to priority inheritance) so
+    /// that it is queued at the correct level.
+    pub fn priority_changed(&self, thread: &Thread) {
+        for rq in self.cpus.values() {
+            let mut rq = rq.lock();
+            if let Some(t) = rq.remove(thread.id) {
+                self.enqueue(&mut rq, t);
+                return;
+            }
+        }
+    }
+
+    /// Raise the priority of `thread` to at least that of `waiter`, because `waiter` is blocked
+    /// waiting for `thread` to do something, i.e. reply to a message.
+    pub fn inherit_priority(&self, thread: &Thread, waiter: &Thread) {
+        thread.inherit_priority(waiter.effective_priority());
+        self.priority_changed(thread);
+    }
+
+    /// Return `thread` to its base priority after it no longer has any waiters.
+    pub fn restore_priority(&self, thread: &Thread) {
+        thread.clear_inherited_priority();
+        self.priority_changed(thread);
+    }
+}
+
+impl<C: CpuIdReader> Scheduler for PriorityScheduler<C> {
+    fn current_thread(&self) -> Arc<Thread> {
+        self.cpus
+            .get(&C::current_cpu())
+            .expect("cpu has run queues")
+            .lock()
+            .current_thread
+            .clone()
+    }
+
+    fn next_time_slice(&self) {
+        let mut rq = self
+            .cpus
+            .get(&C::current_cpu())
+            .expect("cpu has run queues")
+            .lock();
+        rq.ticks += 1;
+
+        let current_is_idle = Arc::ptr_eq(&rq.current_thread, &rq.idle_thread);
+        let current_runnable =
+            !current_is_idle && !rq.current_removed && rq.current_thread.state() == State::Running;
+
+        let next = match self.best_candidate(&rq) {
+            // the current thread keeps running if it has a strictly higher priority
+            Some((_, _, p)) if current_runnable && self.level_for(&rq.current_thread) > p => None,
+            Some((level, index, _)) => rq.levels[level].remove(index).map(|e| e.thread),
+            None if current_runnable || current_is_idle => None,
+            None => Some(rq.idle_thread.clone()),
+        };
+
+        if let Some(next) = next {
+            trace!("switching from thread {} to {next}", rq.current_thread);
+            events::record(EventKind::Switch, next.id, rq.current_thread.id);
+            let last = core::mem::replace(&mut rq.current_thread, next);
+            if !current_is_idle && !rq.current_removed {
+                self.enqueue(&mut rq, last);
+            }
+            rq.current_removed = false;
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        let rq = self
+            .cpus
+            .get(&C::current_cpu())
+            .expect("cpu has run queues")
+            .lock();
+        Arc::ptr_eq(&rq.current_thread, &rq.idle_thread) && rq.len() == 0
+    }
+
+    fn add_thread(&self, thread: Arc<Thread>) {
+        let (cpu_id, rq) = self
+            .cpus
+            .iter()
+            .filter(|(_, rq)| rq.lock().online)
+            .min_by_key(|(id, rq)| {
+                rq.lock().len() + migration_cost::<C>(self.topology.as_ref(), **id)
+            })
+            .expect("at least one online cpu");
+        trace!("adding thread {thread} to cpu {cpu_id}");
+        events::record(
+            EventKind::Migrate,
+            thread.id,
+            u32::try_from(*cpu_id).unwrap_or(u32::MAX),
+        );
+        let was_idle = {
+            let mut rq = rq.lock();
+            let was_idle = Arc::ptr_eq(&rq.current_thread, &rq.idle_thread) && rq.len() == 0;
+            self.enqueue(&mut rq, thread);
+            was_idle
+        };
+        if was_idle && *cpu_id != C::current_cpu() {
+            if let Some(wake) = self.wake {
+                wake(*cpu_id);
+            }
+        }
+    }
+
+    fn remove_thread(&self, id: ThreadId) {
+        trace!("removing thread {id}");
+        for (cpu_id, rq) in &self.cpus {
+            let mut rq = rq.lock();
+            rq.remove(id);
+            if rq.current_thread.id == id {
+                rq.current_removed = true;
+                if *cpu_id != C::current_cpu() {
+                    if let Some(wake) = self.wake {
+                        wake(*cpu_id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_core(&self, cpu: CpuId) {
+        let Some(rq) = self.cpus.get(&cpu) else {
+            return;
+        };
+        trace!("removing cpu {cpu}");
+        let queued: Vec<_> = {
+            let mut rq = rq.lock();
+            rq.online = false;
+            rq.levels
+                .iter_mut()
+                .flat_map(|level| level.drain(..).map(|e| e.thread))
+                .collect()
+        };
+        for thread in queued {
+            self.add_thread(thread);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        platform::cpu::tests::FixedCpu,
+        process::thread::{ProcessorState, INTERRUPT_PRIORITY, MAX_PRIORITY, MAX_THREAD_ID},
+    };
+
+    fn new_thread(threads: &HandleMap<Thread>, priority: Priority) -> Arc<Thread> {
+        let t = Thread::new(threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        t.set_priority(priority);
+        t
+    }
+
+    fn setup(
+        aging_interval: usize,
+    ) -> (
+        HandleMap<Thread>,
+        Arc<Thread>,
+        PriorityScheduler<FixedCpu<0>>,
+    ) {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let idle = new_thread(&threads, 0);
+        let sched = PriorityScheduler::new(&[(0, idle.clone())], 4, aging_interval);
+        (threads, idle, sched)
+    }
+
+    fn run(sched: &PriorityScheduler<FixedCpu<0>>, slices: usize) -> Vec<ThreadId> {
+        (0..slices)
+            .map(|_| {
+                sched.next_time_slice();
+                sched.current_thread().id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn highest_priority_runs() {
+        let (threads, _idle, sched) = setup(1000);
+        let low = new_thread(&threads, 1);
+        let high = new_thread(&threads, 3);
+        sched.add_thread(low.clone());
+        sched.add_thread(high.clone());
+        assert!(run(&sched, 10).iter().all(|id| *id == high.id));
+
+        // once the high priority thread blocks, the low priority thread gets to run
+        sched.block(&high);
+        assert_eq!(run(&sched, 1), [low.id]);
+
+        // and when it unblocks, it preempts the low priority thread
+        sched.unblock(&high);
+        assert_eq!(run(&sched, 1), [high.id]);
+    }
+
+    #[test]
+    fn idle() {
+        let (threads, _idle, sched) = setup(1000);
+        assert!(sched.is_idle());
+        let t = new_thread(&threads, 1);
+        sched.add_thread(t.clone());
+        assert!(!sched.is_idle());
+        run(&sched, 1);
+        assert!(!sched.is_idle());
+        sched.remove_thread(t.id);
+        run(&sched, 1);
+        assert!(sched.is_idle());
+    }
+
+    #[test]
+    fn round_robin_within_level() {
+        let (threads, _idle, sched) = setup(1000);
+        let a = new_thread(&threads, 2);
+        let b = new_thread(&threads, 2);
+        sched.add_thread(a.clone());
+        sched.add_thread(b.clone());
+        assert_eq!(run(&sched, 4), [a.id, b.id, a.id, b.id]);
+    }
+
+    #[test]
+    fn priority_clamped_to_levels() {
+        let (threads, _idle, sched) = setup(1000);
+        let a = new_thread(&threads, 200);
+        let b = new_thread(&threads, 3);
+        sched.add_thread(a.clone());
+        sched.add_thread(b.clone());
+        assert_eq!(run(&sched, 2), [a.id, b.id]);
+    }
+
+    #[test]
+    fn aging_prevents_starvation() {
+        let (threads, _idle, sched) = setup(2);
+        let low = new_thread(&threads, 0);
+        let high = new_thread(&threads, 3);
+        sched.add_thread(low.clone());
+        sched.add_thread(high.clone());
+        let ids = run(&sched, 20);
+        assert!(ids.contains(&low.id), "low priority thread starved");
+        assert!(ids.iter().filter(|id| **id == high.id).count() > 10);
+    }
+
+    #[test]
+    fn idle_only_when_nothing_runnable() {
+        let (threads, idle, sched) = setup(1000);
+        assert_eq!(run(&sched, 2), [idle.id, idle.id]);
+        let a = new_thread(&threads, 0);
+        sched.add_thread(a.clone());
+        assert_eq!(run(&sched, 2), [a.id, a.id]);
+        sched.remove_thread(a.id);
+        assert_eq!(run(&sched, 2), [idle.id, idle.id]);
+    }
+
+    #[test]
+    fn inherited_priority() {
+        let (threads, _idle, sched) = setup(1000);
+        let server = new_thread(&threads, 0);
+        let client = new_thread(&threads, 3);
+        let other = new_thread(&threads, 1);
+        sched.add_thread(server.clone());
+        sched.add_thread(other.clone());
+        assert_eq!(run(&sched, 1), [other.id]);
+
+        // a high priority client blocks waiting for the server
+        sched.inherit_priority(&server, &client);
+        assert_eq!(server.effective_priority(), 3);
+        assert_eq!(run(&sched, 2), [server.id, server.id]);
+
+        sched.restore_priority(&server);
+        assert_eq!(server.effective_priority(), 0);
+        assert_eq!(run(&sched, 2), [other.id, other.id]);
+    }
+
+    #[test]
+    fn interrupt_priority_is_ceiling() {
+        let (threads, _idle, sched) = setup(1000);
+        let irq = new_thread(&threads, 0);
+        irq.set_interrupt_priority();
+        let other = new_thread(&threads, Priority::MAX);
+        assert_eq!(other.priority(), MAX_PRIORITY);
+        assert_eq!(irq.effective_priority(), INTERRUPT_PRIORITY);
+
+        // only an interrupt thread waiting on a thread can raise it to the ceiling
+        sched.inherit_priority(&other, &irq);
+        assert_eq!(other.effective_priority(), INTERRUPT_PRIORITY);
+        sched.restore_priority(&other);
+        assert_eq!(other.effective_priority(), MAX_PRIORITY);
+    }
+
+    #[test]
+    fn removed_cpu_gets_no_threads() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let sched = PriorityScheduler::<FixedCpu<0>>::new(
+            &[(0, new_thread(&threads, 0)), (1, new_thread(&threads, 0))],
+            4,
+            DEFAULT_AGING_INTERVAL,
+        );
+        for priority in 0..4 {
+            sched.add_thread(new_thread(&threads, priority));
+        }
+        sched.remove_core(1);
+        sched.add_thread(new_thread(&threads, 0));
+        assert_eq!(sched.cpus[&0].lock().len(), 5);
+        assert_eq!(sched.cpus[&1].lock().len(), 0);
+    }
+}
@@ -0,0 +1,272 @@
+//! Switching the thread running on a core while handling an exception.
+//!
+//! The exception vector saves the general purpose, floating point and SIMD registers of the
+//! interrupted thread in an exception frame on the kernel stack, and restores them from the frame when the exception
+//! returns. The rest of a thread's processor state is held in system registers. Switching threads
+//! is then a matter of saving both into the current thread before the exception is handled, and
+//! loading the state of whichever thread the scheduler chose afterwards.
+use alloc::sync::Arc;
+use log::trace;
+
+#[cfg(test)]
+use mockall::automock;
+
+use super::{FloatingPointRegisters, ProcessorState, Registers, Scheduler};
+use crate::time::{Clock, CounterReader};
+
+/// The system registers that hold the part of a thread's processor state that is not saved in the
+/// exception frame.
+#[cfg_attr(test, automock)]
+pub trait ExceptionContext {
+    /// Copy the interrupted thread's program status, program counter, stack pointer and thread
+    /// pointer into `state`.
+    fn save(&self, state: &mut ProcessorState);
+
+    /// Load the program status, program counter, stack pointer and thread pointer in `state`, so
+    /// that the thread resumes when the exception returns.
+    ///
+    /// This must only be called just before returning from an exception.
+    fn restore(&self, state: &ProcessorState);
+}
+
+/// Handle an exception by calling `handle`, switching threads if the scheduler chose a different
+/// thread to run while it was handled.
+///
+/// The `registers` and `fp` registers are the exception frame, which is saved into the current
+/// thread beforehand and replaced with the registers of the thread that should run next afterwards.
+/// The time since the last switch, including the time spent handling the exception, is added to
+/// the runtime of the current thread, as read from `clock`.
+/// Returns true if a different thread will run when the exception returns.
+///
+/// # Panics
+/// If the processor state of either thread is locked.
+pub fn switch_threads<S: Scheduler + ?Sized, C: CounterReader>(
+    scheduler: &S,
+    clock: &Clock<C>,
+    context: &impl ExceptionContext,
+    registers: &mut Registers,
+    fp: &mut FloatingPointRegisters,
+    handle: impl FnOnce(),
+) -> bool {
+    let previous = scheduler.current_thread();
+    {
+        let mut state = previous
+            .processor_state
+            .try_lock()
+            .expect("no locks on current thread's execution state");
+        context.save(&mut state);
+        state.registers = *registers;
+        state.fp = *fp;
+        trace!(
+            "saved processor state of thread#{}, pc={:?}",
+            previous.id,
+            state.program_counter
+        );
+    }
+
+    handle();
+
+    let next = scheduler.current_thread();
+    let now = clock.now();
+    previous.stop_running(now);
+    next.start_running(now);
+
+    let state = next
+        .processor_state
+        .try_lock()
+        .expect("no locks on next thread's execution state");
+    *registers = state.registers;
+    *fp = state.fp;
+    context.restore(&state);
+    trace!(
+        "restored processor state of thread#{}, pc={:?}",
+        next.id,
+        state.program_counter
+    );
+    !Arc::ptr_eq(&previous, &next)
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::function;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        memory::VirtualAddress,
+        platform::cpu::tests::FixedCpu,
+        process::thread::{
+            scheduler::RoundRobinScheduler, SavedProgramStatus, State, Thread, MAX_THREAD_ID,
+        },
+        time::Ticks,
+    };
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static COUNTER: Cell<Ticks> = const { Cell::new(0) };
+    }
+
+    struct TestCounter;
+
+    impl CounterReader for TestCounter {
+        fn read() -> Ticks {
+            COUNTER.get()
+        }
+    }
+
+    fn registers(fill: usize) -> Registers {
+        Registers { x: [fill; 31] }
+    }
+
+    #[test]
+    fn switch_from_idle_to_user_thread() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let idle = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let user = Thread::new(
+            &threads,
+            State::Running,
+            ProcessorState::new_for_user_thread(0x1000.into(), 0x8000.into(), 0.into()),
+        );
+        user.processor_state.lock().registers = registers(2);
+        user.processor_state.lock().fp.q[31] = 2;
+        let sched = RoundRobinScheduler::<FixedCpu<0>>::new(&[(0, idle.clone())]);
+        sched.add_thread(user.clone());
+
+        let mut context = MockExceptionContext::new();
+        context.expect_save().once().returning(|state| {
+            state.spsr = SavedProgramStatus::initial_for_el1();
+            state.program_counter = VirtualAddress::from(0xffff_0000_4100_0000);
+        });
+        context
+            .expect_restore()
+            .once()
+            .with(function(|state: &ProcessorState| {
+                state.program_counter == VirtualAddress::from(0x1000)
+                    && state.stack_pointer == VirtualAddress::from(0x8000)
+            }))
+            .return_const(());
+
+        let mut frame = registers(1);
+        let mut fp = FloatingPointRegisters {
+            fpcr: 1,
+            ..Default::default()
+        };
+        assert!(switch_threads(
+            &sched,
+            &Clock::<TestCounter>::new(1),
+            &context,
+            &mut frame,
+            &mut fp,
+            || sched.next_time_slice()
+        ));
+        assert_eq!(frame.x, registers(2).x);
+        assert_eq!((fp.q[31], fp.fpcr), (2, 0));
+
+        // the idle thread resumes where it was interrupted
+        let idle_state = idle.processor_state.lock();
+        assert_eq!(idle_state.registers.x, registers(1).x);
+        assert_eq!(idle_state.fp.fpcr, 1);
+        assert_eq!(idle_state.spsr.el(), 1);
+        assert_eq!(
+            idle_state.program_counter,
+            VirtualAddress::from(0xffff_0000_4100_0000)
+        );
+    }
+
+    #[test]
+    fn no_switch_restores_same_thread() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let idle = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let sched = RoundRobinScheduler::<FixedCpu<0>>::new(&[(0, idle)]);
+
+        let mut context = MockExceptionContext::new();
+        context
+            .expect_save()
+            .once()
+            .returning(|state| state.stack_pointer = VirtualAddress::from(0x1234));
+        context
+            .expect_restore()
+            .once()
+            .with(function(|state: &ProcessorState| {
+                state.stack_pointer == VirtualAddress::from(0x1234)
+            }))
+            .return_const(());
+
+        let mut frame = registers(7);
+        assert!(!switch_threads(
+            &sched,
+            &Clock::<TestCounter>::new(1),
+            &context,
+            &mut frame,
+            &mut FloatingPointRegisters::default(),
+            || sched.next_time_slice()
+        ));
+        assert_eq!(frame.x, registers(7).x);
+    }
+
+    #[test]
+    fn time_is_charged_to_previous_thread() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let idle = Thread::new(&threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        });
+        let user = Thread::new(
+            &threads,
+            State::Running,
+            ProcessorState::new_for_user_thread(0x1000.into(), 0x8000.into(), 0.into()),
+        );
+        let sched = RoundRobinScheduler::<FixedCpu<0>>::new(&[(0, idle.clone())]);
+        sched.add_thread(user.clone());
+        let clock = Clock::<TestCounter>::new(1);
+        let mut context = MockExceptionContext::new();
+        context.expect_save().return_const(());
+        context.expect_restore().return_const(());
+        let mut frame = registers(0);
+        let mut fp = FloatingPointRegisters::default();
+
+        // the idle thread has been running since the counter started
+        COUNTER.set(100);
+        assert!(switch_threads(
+            &sched,
+            &clock,
+            &context,
+            &mut frame,
+            &mut fp,
+            || {
+                sched.next_time_slice();
+            }
+        ));
+        assert_eq!(idle.runtime(), 100);
+        assert_eq!(user.runtime(), 0);
+
+        COUNTER.set(130);
+        assert!(!switch_threads(
+            &sched,
+            &clock,
+            &context,
+            &mut frame,
+            &mut fp,
+            || {}
+        ));
+        assert_eq!(user.runtime(), 30);
+
+        COUNTER.set(150);
+        assert!(switch_threads(
+            &sched,
+            &clock,
+            &context,
+            &mut frame,
+            &mut fp,
+            || {
+                sched.block(&user);
+                sched.next_time_slice();
+            }
+        ));
+        assert_eq!(user.runtime(), 50);
+        assert_eq!(idle.runtime(), 100);
+    }
+}
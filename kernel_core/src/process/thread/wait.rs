@@ -0,0 +1,351 @@
+//! Blocking a thread until an event happens, a deadline passes or the wait is cancelled.
+//!
+//! Every blocking wait goes through [`Thread::block_for`], which gives the wait a
+//! [`WaitToken`](super::WaitToken). Whichever of the waking paths ends the wait first (the event
+//! being signaled, the timeout expiring or the thread being cancelled) records the
+//! [`WaitOutcome`] and makes the thread runnable, and the others find that the wait has already
+//! ended and do nothing. Threads can therefore be left behind in a wait structure after they are
+//! woken another way, so wait structures skip and discard waiters that are no longer waiting.
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use hashbrown::HashMap;
+use log::trace;
+
+use super::{Id, Scheduler, State, Thread, WaitOutcome, WaitReason, WaitToken};
+use crate::{
+    sync::Mutex,
+    time::{Ticks, TimerId, TimerQueue},
+};
+
+/// A thread blocked in a wait, along with the token identifying the wait.
+pub type Waiter = (Arc<Thread>, WaitToken);
+
+/// A deadline for a blocking wait, after which the wait ends with [`WaitOutcome::TimedOut`].
+#[derive(Clone, Copy)]
+pub struct Timeout {
+    timers: &'static TimerQueue,
+    deadline: Ticks,
+}
+
+impl Timeout {
+    /// A timeout at `deadline`, using a timer in `timers` to wake the thread.
+    #[must_use]
+    pub fn new(timers: &'static TimerQueue, deadline: Ticks) -> Self {
+        Self { timers, deadline }
+    }
+
+    /// The time the wait ends at.
+    #[must_use]
+    pub fn deadline(&self) -> Ticks {
+        self.deadline
+    }
+
+    /// Arm the timer that ends the wait `token` of `thread`.
+    ///
+    /// The timer is recorded with the thread, and cancelled if the wait ends another way.
+    pub fn arm(&self, thread: &Arc<Thread>, token: WaitToken) -> TimerId {
+        let waiter = thread.clone();
+        let id = self.timers.arm(
+            self.deadline,
+            Box::new(move || {
+                if waiter.end_wait(token, WaitOutcome::TimedOut) {
+                    trace!("thread {} timed out", waiter.id);
+                }
+            }),
+        );
+        thread.set_wait_timer(token, self.timers, id);
+        id
+    }
+}
+
+/// Block the current thread (given by `scheduler`) for `reason`, arming `timeout` if there is one.
+///
+/// Returns the thread and the token for the wait, which the caller should record so that the
+/// thread can be signaled, or `None` without blocking if the thread's waits have been cancelled.
+/// The caller must advance the scheduler to the next time slice once it has recorded the waiter.
+pub fn block_current(
+    scheduler: &impl Scheduler,
+    reason: WaitReason,
+    timeout: Option<Timeout>,
+) -> Option<Waiter> {
+    let current_thread = scheduler.current_thread();
+    let token = current_thread.block_for(reason)?;
+    if let Some(timeout) = timeout {
+        trace!(
+            "thread {} waiting for {reason:?} until {}",
+            current_thread.id,
+            timeout.deadline
+        );
+        timeout.arm(&current_thread, token);
+    }
+    Some((current_thread, token))
+}
+
+/// Tracks threads that are waiting for other threads to exit.
+#[derive(Default)]
+pub struct ThreadWaits {
+    /// Threads waiting on each thread, by the id of the thread they are waiting for.
+    joiners: Mutex<HashMap<Id, Vec<Waiter>>>,
+}
+
+impl ThreadWaits {
+    /// Create a new, empty set of waits.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Block the current thread (given by `scheduler`) until the time reaches `deadline`, using
+    /// a timer in `timers` to wake it. The scheduler is advanced to the next time slice.
+    ///
+    /// The sleep ends with [`WaitOutcome::TimedOut`] unless it is cancelled first.
+    pub fn sleep_until(
+        &self,
+        scheduler: &impl Scheduler,
+        timers: &'static TimerQueue,
+        deadline: Ticks,
+    ) {
+        if block_current(
+            scheduler,
+            WaitReason::Sleep,
+            Some(Timeout::new(timers, deadline)),
+        )
+        .is_some()
+        {
+            scheduler.next_time_slice();
+        }
+    }
+
+    /// Block the current thread (given by `scheduler`) until `target` exits or the `timeout`
+    /// passes, advancing the scheduler to the next time slice.
+    ///
+    /// Returns false without blocking if `target` has already exited, or the current thread's
+    /// waits have been cancelled.
+    pub fn join(
+        &self,
+        scheduler: &impl Scheduler,
+        target: &Thread,
+        timeout: Option<Timeout>,
+    ) -> bool {
+        let mut joiners = self.joiners.lock();
+        if target.state() == State::Exited {
+            return false;
+        }
+        let Some(waiter) = block_current(scheduler, WaitReason::Join(target.id), timeout) else {
+            return false;
+        };
+        trace!("thread {} joining thread {}", waiter.0.id, target.id);
+        let waiters = joiners.entry(target.id).or_default();
+        waiters.retain(|(thread, token)| thread.is_waiting(*token));
+        waiters.push(waiter);
+        drop(joiners);
+        scheduler.next_time_slice();
+        true
+    }
+
+    /// Mark `thread` as exited, removing it from `scheduler` and waking every thread that was
+    /// joining it.
+    pub fn exit(&self, scheduler: &impl Scheduler, thread: &Thread) {
+        let mut joiners = self.joiners.lock();
+        trace!("thread {} exited", thread.id);
+        thread.set_state(State::Exited);
+        scheduler.remove_thread(thread.id);
+        for (joiner, token) in joiners.remove(&thread.id).into_iter().flatten() {
+            joiner.end_wait(token, WaitOutcome::Signaled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        process::thread::{
+            MockScheduler, ProcessorState, INTERRUPT_PRIORITY, MAX_PRIORITY, MAX_THREAD_ID,
+        },
+    };
+
+    fn new_thread(threads: &HandleMap<Thread>) -> Arc<Thread> {
+        Thread::new(threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        })
+    }
+
+    fn scheduler_running(thread: &Arc<Thread>) -> MockScheduler {
+        let mut sched = MockScheduler::new();
+        let t = thread.clone();
+        sched
+            .expect_current_thread()
+            .once()
+            .returning(move || t.clone());
+        sched.expect_next_time_slice().once().return_const(());
+        sched
+    }
+
+    #[test]
+    fn sleep_wakes_at_deadline() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = new_thread(&threads);
+        let sched = scheduler_running(&thread);
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let waits = ThreadWaits::new();
+
+        waits.sleep_until(&sched, timers, 100);
+        assert_eq!(thread.state(), State::Blocked);
+        assert_eq!(thread.wait_reason(), Some(WaitReason::Sleep));
+        assert!(timers.pop_expired(99).is_none());
+
+        let (_, wake) = timers.pop_expired(100).unwrap();
+        wake();
+        assert_eq!(thread.state(), State::Running);
+        assert_eq!(thread.wait_reason(), None);
+        assert_eq!(thread.take_wait_outcome(), Some(WaitOutcome::TimedOut));
+        assert_eq!(thread.take_wait_outcome(), None);
+    }
+
+    #[test]
+    fn sleep_timer_ignores_thread_blocked_for_other_reason() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = new_thread(&threads);
+        let sched = scheduler_running(&thread);
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let waits = ThreadWaits::new();
+
+        waits.sleep_until(&sched, timers, 100);
+        thread.block_for(WaitReason::Join(7));
+        let (_, wake) = timers.pop_expired(100).unwrap();
+        wake();
+        assert_eq!(thread.state(), State::Blocked);
+        assert_eq!(thread.wait_reason(), Some(WaitReason::Join(7)));
+    }
+
+    #[test]
+    fn wait_target_and_priorities_share_properties() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = new_thread(&threads);
+        thread.set_priority(MAX_PRIORITY);
+        thread.inherit_priority(INTERRUPT_PRIORITY);
+        thread.block_for(WaitReason::Join(MAX_THREAD_ID));
+        assert_eq!(thread.wait_reason(), Some(WaitReason::Join(MAX_THREAD_ID)));
+        assert_eq!(thread.priority(), MAX_PRIORITY);
+        assert_eq!(thread.effective_priority(), INTERRUPT_PRIORITY);
+        thread.clear_inherited_priority();
+        assert_eq!(thread.effective_priority(), MAX_PRIORITY);
+        assert_eq!(thread.wait_reason(), Some(WaitReason::Join(MAX_THREAD_ID)));
+    }
+
+    #[test]
+    fn join_wakes_on_exit() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let waiter = new_thread(&threads);
+        let target = new_thread(&threads);
+        let mut sched = scheduler_running(&waiter);
+        sched
+            .expect_remove_thread()
+            .once()
+            .with(eq(target.id))
+            .return_const(());
+        let waits = ThreadWaits::new();
+
+        assert!(waits.join(&sched, &target, None));
+        assert_eq!(waiter.state(), State::Blocked);
+        assert_eq!(waiter.wait_reason(), Some(WaitReason::Join(target.id)));
+
+        waits.exit(&sched, &target);
+        assert_eq!(target.state(), State::Exited);
+        assert_eq!(waiter.state(), State::Running);
+        assert_eq!(waiter.wait_reason(), None);
+        assert_eq!(waiter.take_wait_outcome(), Some(WaitOutcome::Signaled));
+    }
+
+    #[test]
+    fn join_times_out_before_exit() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let waiter = new_thread(&threads);
+        let target = new_thread(&threads);
+        let mut sched = MockScheduler::new();
+        let t = waiter.clone();
+        sched
+            .expect_current_thread()
+            .times(2)
+            .returning(move || t.clone());
+        sched.expect_next_time_slice().times(2).return_const(());
+        sched.expect_remove_thread().once().return_const(());
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let waits = ThreadWaits::new();
+
+        assert!(waits.join(&sched, &target, Some(Timeout::new(timers, 50))));
+        let (_, expire) = timers.pop_expired(50).unwrap();
+        expire();
+        assert_eq!(waiter.state(), State::Running);
+        assert_eq!(waiter.take_wait_outcome(), Some(WaitOutcome::TimedOut));
+
+        // joining again without a timeout is only ended by the exit
+        assert!(waits.join(&sched, &target, None));
+        waits.exit(&sched, &target);
+        assert_eq!(waiter.state(), State::Running);
+        assert_eq!(waiter.take_wait_outcome(), Some(WaitOutcome::Signaled));
+    }
+
+    #[test]
+    fn cancel_ends_wait_and_prevents_blocking() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let thread = new_thread(&threads);
+        let mut sched = MockScheduler::new();
+        let t = thread.clone();
+        sched
+            .expect_current_thread()
+            .times(2)
+            .returning(move || t.clone());
+        sched.expect_next_time_slice().once().return_const(());
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let waits = ThreadWaits::new();
+
+        waits.sleep_until(&sched, timers, 100);
+        assert!(thread.cancel_waits());
+        assert_eq!(thread.state(), State::Running);
+        assert_eq!(thread.take_wait_outcome(), Some(WaitOutcome::Cancelled));
+
+        // the timer was cancelled with the wait
+        assert!(timers.pop_expired(100).is_none());
+        assert_eq!(thread.take_wait_outcome(), None);
+
+        // later waits end immediately
+        waits.sleep_until(&sched, timers, 200);
+        assert_eq!(thread.state(), State::Running);
+        assert!(thread.waits_cancelled());
+        assert_eq!(thread.take_wait_outcome(), Some(WaitOutcome::Cancelled));
+        assert!(!thread.cancel_waits());
+    }
+
+    #[test]
+    fn signaled_wait_cancels_timer() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let waiter = new_thread(&threads);
+        let target = new_thread(&threads);
+        let mut sched = scheduler_running(&waiter);
+        sched.expect_remove_thread().once().return_const(());
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let waits = ThreadWaits::new();
+
+        assert!(waits.join(&sched, &target, Some(Timeout::new(timers, 50))));
+        assert_eq!(timers.next_deadline(), Some(50));
+        waits.exit(&sched, &target);
+        assert_eq!(waiter.take_wait_outcome(), Some(WaitOutcome::Signaled));
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn join_exited_thread_does_not_block() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let target = new_thread(&threads);
+        let mut sched = MockScheduler::new();
+        sched.expect_remove_thread().once().return_const(());
+        let waits = ThreadWaits::new();
+
+        waits.exit(&sched, &target);
+        assert!(!waits.join(&sched, &target, None));
+    }
+}
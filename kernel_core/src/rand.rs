@@ -0,0 +1,273 @@
+//! The kernel's entropy pool and random number generator.
+//!
+//! Entropy from the hardware (the virtio entropy device, the seed the bootloader provides for
+//! KASLR, and as a fallback the jitter in timing a loop with the system counter) is mixed into a
+//! pool, and random bytes are generated from the pool with `ChaCha20`. The key is replaced after
+//! every request ("fast key erasure"), so that earlier outputs can't be recovered from the state
+//! of the generator.
+//!
+//! Randomness is used for the location of the physical memory mapping, stack canaries and address space IDs, and will be given to user
+//! space by a system call.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::Mutex;
+
+/// The number of bytes of entropy that must be credited before the pool counts as seeded.
+pub const SEED_BYTES: usize = 32;
+
+/// The number of counter samples taken by [`add_jitter`].
+const JITTER_SAMPLES: usize = 64;
+
+/// "expand 32-byte k", the `ChaCha` constant.
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// The size of a `ChaCha20` block, in bytes.
+const BLOCK_SIZE: usize = 64;
+
+/// Compute the `ChaCha20` block for `key`, `counter` and `nonce`.
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut initial = [0; 16];
+    initial[..4].copy_from_slice(&CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(nonce);
+
+    let mut state = initial;
+    let quarter_round = |s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize| {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    };
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, initial) in state.iter_mut().zip(initial) {
+        *word = word.wrapping_add(initial);
+    }
+    state
+}
+
+/// A pool of entropy that random bytes are generated from.
+pub struct EntropyPool {
+    /// The `ChaCha20` key, which holds all of the entropy in the pool.
+    key: [u32; 8],
+    /// The number of blocks generated with the current key.
+    counter: u64,
+    /// The number of bytes of entropy credited to the pool, up to [`SEED_BYTES`].
+    credited: usize,
+}
+
+impl EntropyPool {
+    /// Create an empty pool.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            key: [0; 8],
+            counter: 0,
+            credited: 0,
+        }
+    }
+
+    /// Generate the next block of the keystream.
+    // the counter is split across the counter word and the first nonce word
+    #[allow(clippy::cast_possible_truncation)]
+    fn next_block(&mut self) -> [u32; 16] {
+        let block = chacha20_block(
+            &self.key,
+            self.counter as u32,
+            &[(self.counter >> 32) as u32, 0, 0],
+        );
+        self.counter += 1;
+        block
+    }
+
+    /// Replace the key with the next output of the generator.
+    fn rekey(&mut self) {
+        let block = self.next_block();
+        self.key.copy_from_slice(&block[..8]);
+        self.counter = 0;
+    }
+
+    /// Mix `data` into the pool, crediting it with `credit` bytes of entropy.
+    ///
+    /// Data with little or no entropy can be mixed in without harm, as long as it is credited
+    /// honestly.
+    pub fn add_entropy(&mut self, data: &[u8], credit: usize) {
+        for chunk in data.chunks(size_of_val(&self.key)) {
+            for (i, byte) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= u32::from(*byte) << (8 * (i % 4));
+            }
+            self.rekey();
+        }
+        self.credited = self.credited.saturating_add(credit).min(SEED_BYTES);
+    }
+
+    /// Returns true once at least [`SEED_BYTES`] of entropy have been added to the pool.
+    #[must_use]
+    pub fn is_seeded(&self) -> bool {
+        self.credited >= SEED_BYTES
+    }
+
+    /// Fill `buf` with random bytes.
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(BLOCK_SIZE) {
+            let block = self.next_block();
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = block[i / 4].to_le_bytes()[i % 4];
+            }
+        }
+        self.rekey();
+    }
+}
+
+impl Default for EntropyPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The kernel's entropy pool.
+static POOL: Mutex<EntropyPool> = Mutex::new(EntropyPool::new());
+
+/// Set once the kernel's pool has been seeded, so that it can be checked without the lock.
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+/// Mix `data` into the kernel's entropy pool, crediting it with `credit` bytes of entropy.
+pub fn add_entropy(data: &[u8], credit: usize) {
+    let mut pool = POOL.lock();
+    pool.add_entropy(data, credit);
+    if pool.is_seeded() {
+        SEEDED.store(true, Ordering::Release);
+    }
+}
+
+/// Mix the jitter in timing a small amount of work with `read_counter` into the kernel's entropy
+/// pool.
+///
+/// This is a fallback for systems without a hardware entropy source, so it is credited with only
+/// one bit of entropy for each sample.
+pub fn add_jitter(mut read_counter: impl FnMut() -> u64) {
+    let mut samples = [0; JITTER_SAMPLES * 8];
+    let mut scratch = EntropyPool::new();
+    for sample in samples.chunks_mut(8) {
+        let start = read_counter();
+        scratch.rekey();
+        let delta = read_counter().wrapping_sub(start);
+        sample.copy_from_slice(&(start ^ delta.rotate_left(32)).to_le_bytes());
+    }
+    add_entropy(&samples, JITTER_SAMPLES / 8);
+}
+
+/// Returns true once enough entropy has been added to the kernel's pool for its output to be
+/// unpredictable.
+#[must_use]
+pub fn is_seeded() -> bool {
+    SEEDED.load(Ordering::Acquire)
+}
+
+/// Fill `buf` with random bytes from the kernel's entropy pool.
+///
+/// This never waits for entropy, so callers that need unpredictable output must make sure the
+/// pool has been seeded first (see [`is_seeded`]).
+pub fn fill_bytes(buf: &mut [u8]) {
+    POOL.lock().fill_bytes(buf);
+}
+
+/// A random `u64` from the kernel's entropy pool (see [`fill_bytes`]).
+#[must_use]
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chacha20_test_vector() {
+        // RFC 8439, section 2.3.2
+        let key = core::array::from_fn(|i| {
+            let b = (i * 4) as u32;
+            b | (b + 1) << 8 | (b + 2) << 16 | (b + 3) << 24
+        });
+        let block = chacha20_block(&key, 1, &[0x0900_0000, 0x4a00_0000, 0]);
+        assert_eq!(
+            block,
+            [
+                0xe4e7_f110,
+                0x1559_3bd1,
+                0x1fdd_0f50,
+                0xc471_20a3,
+                0xc7f4_d1c7,
+                0x0368_c033,
+                0x9aaa_2204,
+                0x4e6c_d4c3,
+                0x4664_82d2,
+                0x09aa_9f07,
+                0x05d7_c214,
+                0xa202_8bd9,
+                0xd19c_12b5,
+                0xb94e_16de,
+                0xe883_d0cb,
+                0x4e3c_50a2,
+            ]
+        );
+    }
+
+    #[test]
+    fn output_depends_on_entropy() {
+        let mut a = EntropyPool::new();
+        let mut b = EntropyPool::new();
+        a.add_entropy(b"same seed", 4);
+        b.add_entropy(b"same seed", 4);
+        let (mut x, mut y) = ([0; 100], [0; 100]);
+        a.fill_bytes(&mut x);
+        b.fill_bytes(&mut y);
+        assert_eq!(x, y);
+
+        // the key is replaced after each request, so the output doesn't repeat
+        a.fill_bytes(&mut y);
+        assert_ne!(x, y);
+
+        b.add_entropy(&[1], 0);
+        a.fill_bytes(&mut x);
+        b.fill_bytes(&mut y);
+        assert_ne!(x, y);
+    }
+
+    #[test]
+    fn seeded_after_enough_credit() {
+        let mut pool = EntropyPool::new();
+        pool.add_entropy(&[0; 100], 0);
+        assert!(!pool.is_seeded());
+        pool.add_entropy(&[1; 16], 16);
+        assert!(!pool.is_seeded());
+        pool.add_entropy(&[2; 16], 16);
+        assert!(pool.is_seeded());
+    }
+
+    #[test]
+    fn jitter_reads_counter() {
+        let mut reads = 0;
+        add_jitter(|| {
+            reads += 1;
+            reads * 7
+        });
+        assert_eq!(reads, 2 * JITTER_SAMPLES as u64);
+        assert_ne!(next_u64(), next_u64());
+    }
+}
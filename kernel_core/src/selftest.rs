@@ -0,0 +1,137 @@
+//! Running on-target self tests and reporting their results in a machine-readable format.
+//!
+//! The tests themselves live in the kernel, since they exercise the real hardware paths. This
+//! module only runs them and formats the report, which has one line per event:
+//!
+//! ```text
+//! SELFTEST BEGIN count=<number of tests>
+//! SELFTEST PASS <name>
+//! SELFTEST FAIL <name>: <reason>
+//! SELFTEST END passed=<count> failed=<count>
+//! ```
+use alloc::{format, string::String};
+use core::fmt::Write;
+
+/// The reason a self test failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure(pub String);
+
+impl<T: core::fmt::Display> From<T> for Failure {
+    fn from(value: T) -> Self {
+        Self(format!("{value}"))
+    }
+}
+
+/// Fail the current self test with a formatted message unless `cond` is true.
+#[macro_export]
+macro_rules! self_test_ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err($crate::selftest::Failure(alloc::format!($($arg)+)));
+        }
+    };
+}
+
+/// A single self test.
+pub struct SelfTest {
+    /// The name of the test in the report. Must not contain whitespace.
+    pub name: &'static str,
+    /// Runs the test.
+    pub run: fn() -> Result<(), Failure>,
+}
+
+/// The number of tests that passed and failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// The number of tests that passed.
+    pub passed: usize,
+    /// The number of tests that failed.
+    pub failed: usize,
+}
+
+impl Summary {
+    /// Returns true if no test failed.
+    #[must_use]
+    pub fn succeeded(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+/// Run each of `tests` in order, writing the report to `out`.
+///
+/// Each line of the report is written with a single call to [`Write::write_str`] so that other
+/// output on the same device can't split a line. Errors writing the report are ignored.
+pub fn run(tests: &[SelfTest], out: &mut impl Write) -> Summary {
+    let mut summary = Summary::default();
+    let _ = out.write_str(&format!("SELFTEST BEGIN count={}\n", tests.len()));
+    for test in tests {
+        let line = match (test.run)() {
+            Ok(()) => {
+                summary.passed += 1;
+                format!("SELFTEST PASS {}\n", test.name)
+            }
+            Err(Failure(reason)) => {
+                summary.failed += 1;
+                // keep the report one line per test
+                let reason = reason.replace('\n', " ");
+                format!("SELFTEST FAIL {}: {reason}\n", test.name)
+            }
+        };
+        let _ = out.write_str(&line);
+    }
+    let _ = out.write_str(&format!(
+        "SELFTEST END passed={} failed={}\n",
+        summary.passed, summary.failed
+    ));
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passes() -> Result<(), Failure> {
+        Ok(())
+    }
+
+    fn fails() -> Result<(), Failure> {
+        self_test_ensure!(1 + 1 == 3, "expected {}\ngot {}", 3, 1 + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn report_format() {
+        let tests = [
+            SelfTest {
+                name: "first",
+                run: passes,
+            },
+            SelfTest {
+                name: "second",
+                run: fails,
+            },
+            SelfTest {
+                name: "third",
+                run: || Err("oops".into()),
+            },
+        ];
+        let mut out = String::new();
+        let summary = run(&tests, &mut out);
+        assert_eq!(
+            summary,
+            Summary {
+                passed: 1,
+                failed: 2
+            }
+        );
+        assert!(!summary.succeeded());
+        assert_eq!(
+            out,
+            "SELFTEST BEGIN count=3\n\
+             SELFTEST PASS first\n\
+             SELFTEST FAIL second: expected 3 got 2\n\
+             SELFTEST FAIL third: oops\n\
+             SELFTEST END passed=1 failed=2\n"
+        );
+    }
+}
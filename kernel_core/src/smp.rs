@@ -0,0 +1,300 @@
+//! Cross-core coordination using inter-processor interrupts (IPIs).
+//!
+//! A single software-generated interrupt is reserved for IPIs. The sender places an [`IpiMessage`]
+//! in the mailbox of each target core and then raises the interrupt on those cores, which drain their
+//! mailboxes when they handle the interrupt.
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+use crossbeam::queue::SegQueue;
+use log::trace;
+
+#[cfg(test)]
+use mockall::automock;
+
+use crate::{
+    exceptions::interrupt::{Controller, Id, IpiTarget},
+    memory::{page_table::TlbFlush, AddressSpaceId},
+    platform::cpu::{CpuIdReader, Id as CpuId},
+};
+
+/// A request sent from one core to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiMessage {
+    /// Stop executing entirely, for instance because another core panicked.
+    Halt,
+    /// Invalidate stale TLB entries for a region of an address space.
+    TlbShootdown {
+        /// The region to flush.
+        flush: TlbFlush,
+        /// The address space the region belongs to.
+        asid: AddressSpaceId,
+    },
+    /// Invalidate the entire TLB, for instance because ASIDs are being reused after the ASID pool
+    /// rolled over.
+    FlushTlb,
+    /// Run the scheduler to pick a new thread, for instance because a higher priority thread became runnable.
+    Reschedule,
+    /// Log the state of the thread running on the core, for instance because it seems to be locked
+    /// up.
+    DumpState,
+}
+
+/// Mechanisms needed to carry out IPI requests on the current core.
+pub trait IpiMechanism: Sync {
+    /// Stop the current core permanently.
+    fn halt(&self) -> !;
+
+    /// Invalidate the TLB entries for `flush` on the current core only.
+    fn flush_tlb(&self, flush: &TlbFlush, asid: AddressSpaceId);
+
+    /// Invalidate every TLB entry on the current core only.
+    fn flush_all_tlb(&self);
+
+    /// Log the registers and backtrace of the thread that was running on the current core when it
+    /// was interrupted.
+    fn dump_state(&self);
+}
+
+/// Receives inter-processor interrupts for the current core.
+#[cfg_attr(test, automock)]
+pub trait IpiReceiver {
+    /// The ID of the interrupt used to signal IPIs.
+    fn interrupt_id(&self) -> Id;
+
+    /// Process all messages pending for the current core.
+    ///
+    /// Returns true if one of the messages requested that the scheduler run.
+    fn handle_pending(&self) -> bool;
+}
+
+/// Dispatches [`IpiMessage`]s between cores.
+pub struct IpiDispatcher<'m, C: CpuIdReader, M: IpiMechanism> {
+    interrupt_id: Id,
+    /// The id of each core, in the order of core indices.
+    cores: Vec<CpuId>,
+    /// Pending messages for each core, by core index.
+    mailboxes: Vec<SegQueue<IpiMessage>>,
+    mechanism: &'m M,
+    cpu_id_reader: PhantomData<C>,
+}
+
+impl<'m, C: CpuIdReader, M: IpiMechanism> IpiDispatcher<'m, C, M> {
+    /// Create a new dispatcher that signals messages with software-generated interrupt `interrupt_id`.
+    ///
+    /// The `cores` are the ids of each core in the system, in order of core index (i.e. the order
+    /// given by [`crate::platform::cpu::list_cores`]).
+    #[must_use]
+    pub fn new(interrupt_id: Id, cores: &[CpuId], mechanism: &'m M) -> Self {
+        Self {
+            interrupt_id,
+            cores: cores.to_vec(),
+            mailboxes: cores.iter().map(|_| SegQueue::new()).collect(),
+            mechanism,
+            cpu_id_reader: PhantomData,
+        }
+    }
+
+    /// The index of the core with id `id`, for use with [`IpiTarget::Core`].
+    #[must_use]
+    pub fn core_index(&self, id: CpuId) -> Option<usize> {
+        self.cores.iter().position(|c| *c == id)
+    }
+
+    fn current_core_index(&self) -> usize {
+        self.core_index(C::current_cpu())
+            .expect("current core is known to dispatcher")
+    }
+
+    /// Send `message` to the `target` cores, interrupting them with `controller`.
+    ///
+    /// # Panics
+    /// Panics if the target core index is out of range.
+    pub fn send(&self, controller: &impl Controller, target: IpiTarget, message: IpiMessage) {
+        trace!("sending IPI {message:?} to {target:?}");
+        match target {
+            IpiTarget::Core(index) => self.mailboxes[index].push(message),
+            IpiTarget::AllButSelf => {
+                let current = self.current_core_index();
+                for (index, mailbox) in self.mailboxes.iter().enumerate() {
+                    if index != current {
+                        mailbox.push(message);
+                    }
+                }
+            }
+        }
+        controller.send_ipi(self.interrupt_id, target);
+    }
+}
+
+impl<C: CpuIdReader, M: IpiMechanism> IpiReceiver for IpiDispatcher<'_, C, M> {
+    fn interrupt_id(&self) -> Id {
+        self.interrupt_id
+    }
+
+    fn handle_pending(&self) -> bool {
+        let mailbox = &self.mailboxes[self.current_core_index()];
+        let mut reschedule = false;
+        while let Some(message) = mailbox.pop() {
+            trace!("received IPI {message:?}");
+            match message {
+                IpiMessage::Halt => self.mechanism.halt(),
+                IpiMessage::TlbShootdown { flush, asid } => self.mechanism.flush_tlb(&flush, asid),
+                IpiMessage::FlushTlb => self.mechanism.flush_all_tlb(),
+                IpiMessage::Reschedule => reschedule = true,
+                IpiMessage::DumpState => self.mechanism.dump_state(),
+            }
+        }
+        reschedule
+    }
+}
+
+/// How a core entering the panic handler relates to any other panic in progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicEntry {
+    /// This is the first panic in the system, so this core is responsible for reporting it.
+    First,
+    /// This core panicked again while already handling a panic.
+    Recursive,
+    /// Another core is already handling a panic.
+    Concurrent,
+}
+
+/// Records which core is handling a panic so that only one core reports it.
+pub struct PanicLatch {
+    /// The id of the core handling the panic, or [`Self::NONE`].
+    core: AtomicUsize,
+}
+
+impl PanicLatch {
+    const NONE: usize = usize::MAX;
+
+    /// Create a new latch with no panic in progress.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            core: AtomicUsize::new(Self::NONE),
+        }
+    }
+
+    /// Record that core `core` has entered the panic handler.
+    pub fn enter(&self, core: CpuId) -> PanicEntry {
+        match self
+            .core
+            .compare_exchange(Self::NONE, core, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => PanicEntry::First,
+            Err(c) if c == core => PanicEntry::Recursive,
+            Err(_) => PanicEntry::Concurrent,
+        }
+    }
+}
+
+impl Default for PanicLatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::exceptions::interrupt::MockController;
+    use crate::platform::cpu::tests::FixedCpu;
+
+    #[derive(Default)]
+    struct TestMechanism {
+        flushes: spin::Mutex<Vec<(TlbFlush, AddressSpaceId)>>,
+        full_flushes: AtomicUsize,
+        dumps: AtomicUsize,
+    }
+
+    impl IpiMechanism for TestMechanism {
+        fn halt(&self) -> ! {
+            panic!("halted")
+        }
+
+        fn flush_tlb(&self, flush: &TlbFlush, asid: AddressSpaceId) {
+            self.flushes.lock().push((*flush, asid));
+        }
+
+        fn flush_all_tlb(&self) {
+            self.full_flushes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn dump_state(&self) {
+            self.dumps.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn all_but_self_skips_sender() {
+        let mech = TestMechanism::default();
+        let mut controller = MockController::new();
+        controller
+            .expect_send_ipi()
+            .once()
+            .with(eq(0), eq(IpiTarget::AllButSelf))
+            .return_const(());
+        let d = IpiDispatcher::<FixedCpu<1>, _>::new(0, &[0, 1, 2], &mech);
+        d.send(&controller, IpiTarget::AllButSelf, IpiMessage::Reschedule);
+        assert_eq!(d.mailboxes[0].pop(), Some(IpiMessage::Reschedule));
+        assert!(d.mailboxes[1].is_empty());
+        assert_eq!(d.mailboxes[2].pop(), Some(IpiMessage::Reschedule));
+    }
+
+    #[test]
+    fn handle_pending_messages() {
+        let flush = TlbFlush {
+            virtual_start: 0x1000.into(),
+            length: 0x1000,
+            global: false,
+        };
+        let mech = TestMechanism::default();
+        let mut controller = MockController::new();
+        controller
+            .expect_send_ipi()
+            .times(4)
+            .with(eq(7), eq(IpiTarget::Core(1)))
+            .return_const(());
+        let d = IpiDispatcher::<FixedCpu<1>, _>::new(7, &[0, 1], &mech);
+        assert!(!d.handle_pending());
+        d.send(
+            &controller,
+            IpiTarget::Core(1),
+            IpiMessage::TlbShootdown { flush, asid: 3 },
+        );
+        d.send(&controller, IpiTarget::Core(1), IpiMessage::FlushTlb);
+        d.send(&controller, IpiTarget::Core(1), IpiMessage::Reschedule);
+        d.send(&controller, IpiTarget::Core(1), IpiMessage::DumpState);
+        assert!(d.handle_pending());
+        assert!(d.mailboxes[1].is_empty());
+        assert_eq!(*mech.flushes.lock(), [(flush, 3)]);
+        assert_eq!(mech.full_flushes.load(Ordering::Relaxed), 1);
+        assert_eq!(mech.dumps.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "halted")]
+    fn halt() {
+        let mech = TestMechanism::default();
+        let mut controller = MockController::new();
+        controller.expect_send_ipi().return_const(());
+        let d = IpiDispatcher::<FixedCpu<1>, _>::new(0, &[0, 1], &mech);
+        d.send(&controller, IpiTarget::Core(1), IpiMessage::Halt);
+        d.handle_pending();
+    }
+
+    #[test]
+    fn panic_latch() {
+        let latch = PanicLatch::new();
+        assert_eq!(latch.enter(2), PanicEntry::First);
+        assert_eq!(latch.enter(0), PanicEntry::Concurrent);
+        assert_eq!(latch.enter(2), PanicEntry::Recursive);
+    }
+}
@@ -0,0 +1,267 @@
+//! Futexes, which let user space build mutexes and condition variables out of plain words of memory.
+//!
+//! A futex is any aligned 32-bit word in a process' address space. Threads only enter the kernel
+//! when they need to block or wake other threads, so locks that are not contended never make a
+//! system call, and no kernel object needs to be created for each lock.
+//!
+//! Waiting threads are kept in a small hash table of buckets per process, keyed on the user
+//! virtual address of the futex.
+use super::Mutex;
+use alloc::vec::Vec;
+use log::trace;
+use snafu::{ensure, OptionExt as _, Snafu};
+
+use crate::{
+    memory::VirtualAddress,
+    process::thread::{
+        wait::{block_current, Timeout, Waiter},
+        Scheduler, WaitOutcome, WaitReason,
+    },
+};
+
+/// Number of buckets of waiting threads in each [`FutexTable`].
+const BUCKET_COUNT: usize = 32;
+
+/// Errors that can occur operating on a futex.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The address of the futex is not aligned to 4 bytes.
+    #[snafu(display("futex address {address:?} is not aligned"))]
+    Unaligned {
+        /// The address of the futex.
+        address: VirtualAddress,
+    },
+    /// The address of the futex is not mapped in the process.
+    #[snafu(display("futex address {address:?} is not mapped"))]
+    InvalidAddress {
+        /// The address of the futex.
+        address: VirtualAddress,
+    },
+    /// The value of the futex was not the expected value, so the thread did not block.
+    ValueChanged,
+    /// The current thread's waits have been cancelled, so it can't block.
+    Cancelled,
+}
+
+/// The threads of a single process that are waiting on futexes.
+pub struct FutexTable {
+    /// Waiting threads along with the address they are waiting on, bucketed by a hash of the
+    /// address. Each bucket is kept in the order the threads started waiting.
+    buckets: [Mutex<Vec<(VirtualAddress, Waiter)>>; BUCKET_COUNT],
+}
+
+impl Default for FutexTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FutexTable {
+    /// Create a table with no waiting threads.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            buckets: core::array::from_fn(|_| Mutex::default()),
+        }
+    }
+
+    fn bucket(&self, address: VirtualAddress) -> &Mutex<Vec<(VirtualAddress, Waiter)>> {
+        // futexes are often packed together, so mix the bits above the alignment
+        let hash = (usize::from(address) >> 2).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.buckets[hash >> (usize::BITS - BUCKET_COUNT.ilog2())]
+    }
+
+    /// Block the current thread (given by `scheduler`) on the futex at `address`, but only if the
+    /// futex still holds `expected`, advancing the scheduler to the next time slice.
+    ///
+    /// The value is read with `read`, which returns `None` if the address is not mapped. It is read
+    /// while the futex's bucket is locked, so a wake that happens after another thread changes the
+    /// value can't be missed. The thread waits until it is woken or the `timeout` passes. Once it
+    /// resumes, [`Thread::take_wait_outcome`](crate::process::thread::Thread::take_wait_outcome)
+    /// tells how the wait ended.
+    ///
+    /// # Errors
+    /// - [`Error::Unaligned`] if `address` is not aligned to 4 bytes.
+    /// - [`Error::InvalidAddress`] if `read` fails.
+    /// - [`Error::ValueChanged`] if the futex does not hold `expected`.
+    /// - [`Error::Cancelled`] if the current thread's waits have been cancelled.
+    pub fn wait(
+        &self,
+        scheduler: &impl Scheduler,
+        address: VirtualAddress,
+        expected: u32,
+        read: impl FnOnce(VirtualAddress) -> Option<u32>,
+        timeout: Option<Timeout>,
+    ) -> Result<(), Error> {
+        ensure!(
+            address.is_aligned_to(size_of::<u32>()),
+            UnalignedSnafu { address }
+        );
+        let mut waiters = self.bucket(address).lock();
+        let value = read(address).context(InvalidAddressSnafu { address })?;
+        ensure!(value == expected, ValueChangedSnafu);
+        let waiter =
+            block_current(scheduler, WaitReason::Futex, timeout).context(CancelledSnafu)?;
+        trace!("thread {} waiting on futex {address:?}", waiter.0.id);
+        // forget threads whose waits ended another way
+        waiters.retain(|(_, (thread, token))| thread.is_waiting(*token));
+        waiters.push((address, waiter));
+        drop(waiters);
+        scheduler.next_time_slice();
+        Ok(())
+    }
+
+    /// Wake up to `count` of the threads waiting on the futex at `address`, in the order they
+    /// started waiting. Returns the number of threads woken.
+    pub fn wake(&self, address: VirtualAddress, count: usize) -> usize {
+        let mut woken = 0;
+        self.bucket(address).lock().retain(|(a, (thread, token))| {
+            if *a != address || woken == count {
+                return thread.is_waiting(*token);
+            }
+            if thread.end_wait(*token, WaitOutcome::Signaled) {
+                trace!("waking thread {} from futex {address:?}", thread.id);
+                woken += 1;
+            }
+            false
+        });
+        woken
+    }
+
+    /// The number of threads waiting on the futex at `address`.
+    pub fn waiting(&self, address: VirtualAddress) -> usize {
+        self.bucket(address)
+            .lock()
+            .iter()
+            .filter(|(a, (thread, token))| *a == address && thread.is_waiting(*token))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+    use std::vec;
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        process::thread::{MockScheduler, ProcessorState, State, Thread, MAX_THREAD_ID},
+        time::TimerQueue,
+    };
+
+    fn new_threads(threads: &HandleMap<Thread>, count: usize) -> Vec<Arc<Thread>> {
+        (0..count)
+            .map(|_| {
+                Thread::new(threads, State::Running, unsafe {
+                    ProcessorState::new_for_idle_thread()
+                })
+            })
+            .collect()
+    }
+
+    /// A scheduler whose current thread is each of `threads` in turn.
+    fn scheduler(threads: &[Arc<Thread>]) -> MockScheduler {
+        let mut sched = MockScheduler::new();
+        let mut current: VecDeque<_> = threads.iter().cloned().collect();
+        sched
+            .expect_current_thread()
+            .times(threads.len())
+            .returning(move || current.pop_front().unwrap());
+        sched.expect_next_time_slice().return_const(());
+        sched
+    }
+
+    #[test]
+    fn wait_only_blocks_on_expected_value() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let ts = new_threads(&threads, 1);
+        let sched = scheduler(&ts);
+        let futexes = FutexTable::new();
+        let address = VirtualAddress::from(0x1000);
+
+        assert!(matches!(
+            futexes.wait(&sched, address, 1, |_| Some(0), None),
+            Err(Error::ValueChanged)
+        ));
+        assert!(matches!(
+            futexes.wait(&sched, address, 1, |_| None, None),
+            Err(Error::InvalidAddress { .. })
+        ));
+        assert!(matches!(
+            futexes.wait(&sched, address.byte_add(2), 1, |_| Some(1), None),
+            Err(Error::Unaligned { .. })
+        ));
+        assert_eq!(ts[0].state(), State::Running);
+
+        futexes
+            .wait(&sched, address, 1, |a| (a == address).then_some(1), None)
+            .unwrap();
+        assert_eq!(ts[0].state(), State::Blocked);
+        assert_eq!(ts[0].wait_reason(), Some(WaitReason::Futex));
+        assert_eq!(futexes.waiting(address), 1);
+    }
+
+    #[test]
+    fn wake_count_in_order_per_address() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let ts = new_threads(&threads, 4);
+        let sched = scheduler(&ts);
+        let futexes = FutexTable::new();
+        let a = VirtualAddress::from(0x1000);
+        let b = VirtualAddress::from(0x1004);
+
+        for (t, address) in ts.iter().zip([a, b, a, a]) {
+            futexes.wait(&sched, address, 0, |_| Some(0), None).unwrap();
+            assert_eq!(t.state(), State::Blocked);
+        }
+
+        assert_eq!(futexes.wake(a, 2), 2);
+        let states: Vec<_> = ts.iter().map(|t| t.state()).collect();
+        assert_eq!(
+            states,
+            vec![
+                State::Running,
+                State::Blocked,
+                State::Running,
+                State::Blocked
+            ]
+        );
+        assert_eq!(futexes.wake(a, usize::MAX), 1);
+        assert_eq!(futexes.wake(a, 1), 0);
+        assert_eq!(futexes.waiting(b), 1);
+        assert_eq!(futexes.wake(b, 1), 1);
+        assert!(ts
+            .iter()
+            .all(|t| t.take_wait_outcome() == Some(WaitOutcome::Signaled)));
+    }
+
+    #[test]
+    fn timed_out_waiter_is_not_woken() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let ts = new_threads(&threads, 2);
+        let sched = scheduler(&ts);
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let futexes = FutexTable::new();
+        let address = VirtualAddress::from(0x2000);
+
+        futexes
+            .wait(
+                &sched,
+                address,
+                0,
+                |_| Some(0),
+                Some(Timeout::new(timers, 5)),
+            )
+            .unwrap();
+        futexes.wait(&sched, address, 0, |_| Some(0), None).unwrap();
+        let (_, expire) = timers.pop_expired(5).unwrap();
+        expire();
+        assert_eq!(ts[0].take_wait_outcome(), Some(WaitOutcome::TimedOut));
+
+        // the single wake goes to the thread that is still waiting
+        assert_eq!(futexes.wake(address, 1), 1);
+        assert_eq!(ts[1].take_wait_outcome(), Some(WaitOutcome::Signaled));
+        assert_eq!(futexes.waiting(address), 0);
+    }
+}
@@ -0,0 +1,100 @@
+//! Masking interrupts on the current core while a spin lock is held.
+//!
+//! Most kernel code runs with interrupts masked, but interrupt threads (see
+//! [`crate::exceptions::interrupt::threaded`]) let them in so that a slow device handler doesn't
+//! delay the rest of the system. If such a thread were interrupted while holding a lock that the
+//! interrupt handler also takes, the core would deadlock. So the locks in [`super`] mask
+//! interrupts for as long as they are held, and restore the previous mask when they are released.
+//!
+//! The platform provides the functions that change the mask with [`set_masking`]. Until then,
+//! and in tests, nothing is masked.
+use spin::once::Once;
+
+/// Functions that change the interrupt mask of the current core.
+struct Masking {
+    /// Mask interrupts, returning true if they were already masked.
+    mask: fn() -> bool,
+    /// Unmask interrupts again.
+    unmask: fn(),
+}
+
+static MASKING: Once<Masking> = Once::new();
+
+/// Use `mask` and `unmask` to change the interrupt mask of the current core while locks are held.
+/// `mask` must return true if interrupts were already masked.
+///
+/// Only the first call has any effect.
+pub fn set_masking(mask: fn() -> bool, unmask: fn()) {
+    MASKING.call_once(|| Masking { mask, unmask });
+}
+
+/// Interrupts are masked on the current core until this guard is dropped, unless they were
+/// already masked when it was created.
+#[must_use]
+pub struct MaskGuard {
+    /// True if interrupts must be unmasked when the guard is dropped.
+    unmask: bool,
+}
+
+/// Mask interrupts on the current core until the returned guard is dropped.
+///
+/// Locks that are not from [`super`], such as those that can't take part in lock dependency
+/// tracking, must take this guard before they are locked if an interrupt handler may take them.
+pub fn mask() -> MaskGuard {
+    MaskGuard {
+        unmask: MASKING.get().is_some_and(|m| !(m.mask)()),
+    }
+}
+
+impl Drop for MaskGuard {
+    fn drop(&mut self) {
+        if self.unmask {
+            if let Some(m) = MASKING.get() {
+                (m.unmask)();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::sync::Mutex;
+
+    std::thread_local! {
+        pub static MASKED: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub fn mask_test_thread() -> bool {
+        MASKED.replace(true)
+    }
+
+    pub fn unmask_test_thread() {
+        MASKED.set(false);
+    }
+
+    #[test]
+    fn masked_while_locks_are_held() {
+        set_masking(mask_test_thread, unmask_test_thread);
+        let a = Mutex::new(1);
+        let b = Mutex::new(2);
+        {
+            let _a = a.lock();
+            assert!(MASKED.get());
+            {
+                let _b = b.lock();
+                assert!(MASKED.get());
+            }
+            // still masked, since `a` masked them first
+            assert!(MASKED.get());
+        }
+        assert!(!MASKED.get());
+
+        // a failed attempt to take a lock leaves the mask as it was
+        let _a = a.lock();
+        assert!(a.try_lock().is_none());
+        assert!(MASKED.get());
+    }
+}
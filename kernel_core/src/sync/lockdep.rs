@@ -0,0 +1,391 @@
+//! Lock dependency tracking, to catch lock ordering bugs before they cause deadlocks.
+//!
+//! Every [`Mutex`](super::Mutex) belongs to a lock class, which is the place in the source where
+//! it was created. Whenever a lock is taken while others are held, the order of their classes is
+//! recorded in a [`LockGraph`]. Taking locks in an order that contradicts an earlier one, even
+//! through a chain of other locks, is reported as a [`Violation`], whether or not the two orders
+//! ever actually race.
+//!
+//! Tracking is only enabled with the `lockdep` feature, after [`init`] is called. Nothing here
+//! allocates, so the locks used by the heap can be tracked too.
+use core::{
+    panic::Location,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+use snafu::Snafu;
+
+/// A lock class, which is the place where the locks in the class are created.
+pub type Class = &'static Location<'static>;
+
+/// The maximum number of lock classes that can be tracked. Locks in classes past the limit are
+/// ignored.
+pub const MAX_CLASSES: usize = 256;
+
+/// The maximum number of locks that can be tracked as held at once in a single context.
+pub const MAX_HELD: usize = 16;
+
+/// Number of words in each row of the dependency matrix.
+const WORDS: usize = MAX_CLASSES / 64;
+
+/// Taking a lock would create a cycle in the lock order.
+#[derive(Debug, Snafu)]
+#[snafu(display(
+    "lock order cycle: taking lock created at {acquiring} while holding lock created at {held}, \
+     but they have been taken in the opposite order before"
+))]
+pub struct Violation {
+    /// The class of the lock that was already held.
+    pub held: Class,
+    /// The class of the lock being taken.
+    pub acquiring: Class,
+}
+
+/// The lock classes that are held in some context, in the order they were taken.
+#[derive(Debug, Default)]
+pub struct HeldLocks {
+    classes: [u16; MAX_HELD],
+    depth: usize,
+    /// True while a violation is being reported, so that locks taken to report it are not tracked.
+    #[cfg(feature = "lockdep")]
+    reporting: bool,
+}
+
+impl HeldLocks {
+    /// Create an empty set of held locks.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            classes: [0; MAX_HELD],
+            depth: 0,
+            #[cfg(feature = "lockdep")]
+            reporting: false,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.classes[..self.depth].iter().map(|c| usize::from(*c))
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn push(&mut self, index: usize) {
+        // locks past the limit are not tracked, and releasing them is ignored
+        if self.depth < MAX_HELD {
+            self.classes[self.depth] = index as u16;
+            self.depth += 1;
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        // locks can be released in any order
+        let held = &self.classes[..self.depth];
+        if let Some(i) = held.iter().rposition(|c| usize::from(*c) == index) {
+            self.classes.copy_within(i + 1..self.depth, i);
+            self.depth -= 1;
+        }
+    }
+}
+
+/// The order that lock classes have been taken in, as a graph with an edge from each class to
+/// every class that was taken while it was held.
+pub struct LockGraph {
+    /// Table of known classes, which are assigned an index by hashing their location.
+    classes: [AtomicPtr<Location<'static>>; MAX_CLASSES],
+    /// Dependency matrix, where bit `j` of row `i` is set if class `j` was taken while holding `i`.
+    after: [[AtomicU64; WORDS]; MAX_CLASSES],
+}
+
+impl Default for LockGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockGraph {
+    /// Create a graph with no known classes.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            classes: [const { AtomicPtr::new(null_mut()) }; MAX_CLASSES],
+            after: [const { [const { AtomicU64::new(0) }; WORDS] }; MAX_CLASSES],
+        }
+    }
+
+    /// Find the index of `class`, adding it to the table if it is new. Returns `None` if the
+    /// table is full.
+    fn index_of(&self, class: Class) -> Option<usize> {
+        let start = (class.line() as usize)
+            .wrapping_mul(31)
+            .wrapping_add(class.column() as usize);
+        let new = core::ptr::from_ref(class).cast_mut();
+        for i in (0..MAX_CLASSES).map(|i| (start + i) % MAX_CLASSES) {
+            let mut slot = self.classes[i].load(Ordering::Acquire);
+            if slot.is_null() {
+                match self.classes[i].compare_exchange(
+                    null_mut(),
+                    new,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return Some(i),
+                    Err(s) => slot = s,
+                }
+            }
+            // SAFETY: only `'static` locations are put in the table.
+            // The same location may have more than one copy, so compare them by value.
+            if unsafe { *slot == *class } {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    fn class(&self, index: usize) -> Class {
+        // SAFETY: only `'static` locations are put in the table, and indices come from `index_of`.
+        unsafe { &*self.classes[index].load(Ordering::Acquire) }
+    }
+
+    /// Returns true if there is a path from class `from` to class `to`.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = [0u64; WORDS];
+        let mut stack = [0u16; MAX_CLASSES];
+        let mut top = 0;
+        let mut visit = |i: usize, stack: &mut [u16], top: &mut usize| {
+            if visited[i / 64] & (1 << (i % 64)) == 0 {
+                visited[i / 64] |= 1 << (i % 64);
+                #[allow(clippy::cast_possible_truncation)]
+                {
+                    stack[*top] = i as u16;
+                }
+                *top += 1;
+            }
+        };
+        visit(from, &mut stack, &mut top);
+        while top > 0 {
+            top -= 1;
+            let i = usize::from(stack[top]);
+            if i == to {
+                return true;
+            }
+            for (w, word) in self.after[i].iter().enumerate() {
+                let mut bits = word.load(Ordering::Relaxed);
+                while bits != 0 {
+                    visit(
+                        w * 64 + bits.trailing_zeros() as usize,
+                        &mut stack,
+                        &mut top,
+                    );
+                    bits &= bits - 1;
+                }
+            }
+        }
+        false
+    }
+
+    /// Record that a lock of `class` is about to be taken in the context that holds `held`.
+    ///
+    /// The lock is added to `held` even if taking it is a violation, since the caller will most
+    /// likely take it anyway. Taking a lock of a class that is already held is not checked, since
+    /// many objects of the same type are often locked together.
+    ///
+    /// # Errors
+    /// Returns a [`Violation`] if taking the lock contradicts the order locks were taken in before.
+    pub fn acquire(&self, held: &mut HeldLocks, class: Class) -> Result<(), Violation> {
+        let Some(new) = self.index_of(class) else {
+            return Ok(());
+        };
+        let conflict = held.iter().find(|h| *h != new && self.reaches(new, *h));
+        if let Some(h) = conflict {
+            let violation = Violation {
+                held: self.class(h),
+                acquiring: class,
+            };
+            held.push(new);
+            return Err(violation);
+        }
+        for h in held.iter().filter(|h| *h != new) {
+            self.after[h][new / 64].fetch_or(1 << (new % 64), Ordering::Relaxed);
+        }
+        held.push(new);
+        Ok(())
+    }
+
+    /// Record that a lock of `class` that was taken without waiting (so it could not deadlock) is
+    /// now held in the context that holds `held`. The order is not checked or recorded.
+    pub fn acquired_without_waiting(&self, held: &mut HeldLocks, class: Class) {
+        if let Some(i) = self.index_of(class) {
+            held.push(i);
+        }
+    }
+
+    /// Record that a lock of `class` was released in the context that holds `held`.
+    pub fn release(&self, held: &mut HeldLocks, class: Class) {
+        if let Some(i) = self.index_of(class) {
+            held.remove(i);
+        }
+    }
+}
+
+/// What to do when a lock order violation is found.
+#[cfg(feature = "lockdep")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Panic with the violation.
+    Panic,
+    /// Log the violation as an error and continue.
+    Log,
+}
+
+/// The maximum number of CPUs that locks are tracked on. Locks taken on CPUs with larger ids are
+/// not tracked.
+#[cfg(feature = "lockdep")]
+pub const MAX_CPUS: usize = 64;
+
+#[cfg(feature = "lockdep")]
+static GRAPH: LockGraph = LockGraph::new();
+
+/// The locks held on each CPU.
+#[cfg(feature = "lockdep")]
+static HELD: [spin::Mutex<HeldLocks>; MAX_CPUS] =
+    [const { spin::Mutex::new(HeldLocks::new()) }; MAX_CPUS];
+
+#[cfg(feature = "lockdep")]
+static CONFIG: spin::Once<(fn() -> crate::platform::cpu::Id, Policy)> = spin::Once::new();
+
+/// Start tracking locks, using `C` to find the current CPU, and handle violations with `policy`.
+#[cfg(feature = "lockdep")]
+pub fn init<C: crate::platform::cpu::CpuIdReader>(policy: Policy) {
+    CONFIG.call_once(|| (C::current_cpu, policy));
+}
+
+/// Run `f` with the locks held on the current CPU, if tracking is enabled.
+#[cfg(feature = "lockdep")]
+fn with_held(f: impl FnOnce(&mut HeldLocks) -> Result<(), Violation>) {
+    let Some((current_cpu, policy)) = CONFIG.get() else {
+        return;
+    };
+    // the held locks may already be locked if an interrupt arrived while they were being updated
+    let Some(mut held) = HELD.get(current_cpu()).and_then(spin::Mutex::try_lock) else {
+        return;
+    };
+    if held.reporting {
+        return;
+    }
+    if let Err(violation) = f(&mut held) {
+        match policy {
+            Policy::Panic => {
+                drop(held);
+                panic!("{violation}");
+            }
+            Policy::Log => {
+                held.reporting = true;
+                drop(held);
+                log::error!("{violation}");
+                if let Some(mut held) = HELD.get(current_cpu()).and_then(spin::Mutex::try_lock) {
+                    held.reporting = false;
+                }
+            }
+        }
+    }
+}
+
+/// Check and record that the current CPU is about to wait to take a lock of `class`.
+#[cfg(feature = "lockdep")]
+pub(super) fn acquire(class: Class) {
+    with_held(|held| GRAPH.acquire(held, class));
+}
+
+/// Record that the current CPU took a lock of `class` without waiting.
+#[cfg(feature = "lockdep")]
+pub(super) fn acquired_without_waiting(class: Class) {
+    with_held(|held| {
+        GRAPH.acquired_without_waiting(held, class);
+        Ok(())
+    });
+}
+
+/// Record that the current CPU released a lock of `class`.
+#[cfg(feature = "lockdep")]
+pub(super) fn release(class: Class) {
+    with_held(|held| {
+        GRAPH.release(held, class);
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn class() -> Class {
+        Location::caller()
+    }
+
+    #[test]
+    fn consistent_order_is_allowed() {
+        let graph = LockGraph::new();
+        let (a, b) = (class(), class());
+        for _ in 0..2 {
+            let mut held = HeldLocks::new();
+            graph.acquire(&mut held, a).unwrap();
+            graph.acquire(&mut held, b).unwrap();
+            // releasing out of order is fine
+            graph.release(&mut held, a);
+            graph.release(&mut held, b);
+            assert_eq!(held.depth, 0);
+        }
+        // taking b on its own later is fine too
+        let mut held = HeldLocks::new();
+        graph.acquire(&mut held, b).unwrap();
+        graph.acquire(&mut held, b).unwrap();
+    }
+
+    #[test]
+    fn reversed_order_is_a_violation() {
+        let graph = LockGraph::new();
+        let (a, b) = (class(), class());
+        let mut held = HeldLocks::new();
+        graph.acquire(&mut held, a).unwrap();
+        graph.acquire(&mut held, b).unwrap();
+
+        let mut other = HeldLocks::new();
+        graph.acquire(&mut other, b).unwrap();
+        let violation = graph.acquire(&mut other, a).unwrap_err();
+        assert_eq!(violation.held, b);
+        assert_eq!(violation.acquiring, a);
+        // the lock is still tracked as held, so releasing it works
+        assert_eq!(other.depth, 2);
+        graph.release(&mut other, a);
+        graph.release(&mut other, b);
+        assert_eq!(other.depth, 0);
+    }
+
+    #[test]
+    fn transitive_cycle_is_a_violation() {
+        let graph = LockGraph::new();
+        let (a, b, c) = (class(), class(), class());
+        for (first, second) in [(a, b), (b, c)] {
+            let mut held = HeldLocks::new();
+            graph.acquire(&mut held, first).unwrap();
+            graph.acquire(&mut held, second).unwrap();
+        }
+        let mut held = HeldLocks::new();
+        graph.acquire(&mut held, c).unwrap();
+        assert!(graph.acquire(&mut held, a).is_err());
+    }
+
+    #[test]
+    fn taking_without_waiting_is_not_ordered() {
+        let graph = LockGraph::new();
+        let (a, b) = (class(), class());
+        let mut held = HeldLocks::new();
+        graph.acquire(&mut held, a).unwrap();
+        graph.acquired_without_waiting(&mut held, b);
+        graph.release(&mut held, b);
+        graph.release(&mut held, a);
+
+        graph.acquire(&mut held, b).unwrap();
+        graph.acquire(&mut held, a).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//! Synchronization primitives for the kernel, both for blocking threads and for locks that spin.
+pub mod futex;
+pub mod interrupts;
+pub mod lockdep;
+mod mutex;
+pub mod rcu;
+pub mod rwlock;
+pub mod seqlock;
+
+pub use mutex::{Mutex, MutexGuard};
+
+use alloc::{collections::VecDeque, sync::Arc};
+use log::trace;
+
+use crate::process::thread::{
+    wait::{block_current, Timeout, Waiter},
+    Scheduler, Thread, WaitOutcome, WaitReason,
+};
+
+/// A queue of threads blocked waiting for some event, such as a message arriving or an interrupt
+/// occurring, which can be woken one at a time or all at once.
+#[derive(Default)]
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Waiter>>,
+}
+
+impl WaitQueue {
+    /// Create a new queue with no waiting threads.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the current thread (given by `scheduler`) on this queue until it is woken or the
+    /// `timeout` passes, advancing the scheduler to the next time slice.
+    pub fn wait(&self, scheduler: &impl Scheduler, timeout: Option<Timeout>) {
+        self.wait_if(scheduler, timeout, || true);
+    }
+
+    /// Block the current thread (given by `scheduler`) on this queue, but only if `condition`
+    /// returns true. The condition is checked while the queue is locked, so a wake up that happens
+    /// after the condition is checked cannot be missed.
+    ///
+    /// Returns true if the thread was blocked. It is not blocked if its waits have been cancelled.
+    /// Once the thread resumes, [`Thread::take_wait_outcome`] tells how the wait ended.
+    pub fn wait_if(
+        &self,
+        scheduler: &impl Scheduler,
+        timeout: Option<Timeout>,
+        condition: impl FnOnce() -> bool,
+    ) -> bool {
+        let mut waiters = self.waiters.lock();
+        if !condition() {
+            return false;
+        }
+        let Some(waiter) = block_current(scheduler, WaitReason::Queue, timeout) else {
+            return false;
+        };
+        trace!("thread {} waiting", waiter.0.id);
+        // forget threads whose waits ended another way
+        waiters.retain(|(thread, token)| thread.is_waiting(*token));
+        waiters.push_back(waiter);
+        drop(waiters);
+        scheduler.next_time_slice();
+        true
+    }
+
+    /// Wake the thread that has been waiting the longest, returning it if there was one.
+    pub fn wake_one(&self) -> Option<Arc<Thread>> {
+        let mut waiters = self.waiters.lock();
+        while let Some((thread, token)) = waiters.pop_front() {
+            if thread.end_wait(token, WaitOutcome::Signaled) {
+                trace!("waking thread {}", thread.id);
+                return Some(thread);
+            }
+        }
+        None
+    }
+
+    /// Wake every waiting thread, returning the number of threads woken.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        waiters
+            .into_iter()
+            .filter(|(thread, token)| {
+                let woken = thread.end_wait(*token, WaitOutcome::Signaled);
+                if woken {
+                    trace!("waking thread {}", thread.id);
+                }
+                woken
+            })
+            .count()
+    }
+
+    /// The number of threads currently waiting.
+    pub fn len(&self) -> usize {
+        self.waiters
+            .lock()
+            .iter()
+            .filter(|(thread, token)| thread.is_waiting(*token))
+            .count()
+    }
+
+    /// True if no threads are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{boxed::Box, vec::Vec};
+
+    use super::*;
+    use crate::{
+        collections::HandleMap,
+        process::thread::{MockScheduler, ProcessorState, State, MAX_THREAD_ID},
+        time::TimerQueue,
+    };
+
+    fn new_thread(threads: &HandleMap<Thread>) -> Arc<Thread> {
+        Thread::new(threads, State::Running, unsafe {
+            ProcessorState::new_for_idle_thread()
+        })
+    }
+
+    /// A scheduler whose current thread is each of `threads` in turn.
+    fn scheduler(threads: &[Arc<Thread>]) -> MockScheduler {
+        let mut sched = MockScheduler::new();
+        let mut current: VecDeque<_> = threads.iter().cloned().collect();
+        sched
+            .expect_current_thread()
+            .times(threads.len())
+            .returning(move || current.pop_front().unwrap());
+        sched.expect_next_time_slice().return_const(());
+        sched
+    }
+
+    #[test]
+    fn wake_one_in_order() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let ts: Vec<_> = (0..2).map(|_| new_thread(&threads)).collect();
+        let sched = scheduler(&ts);
+        let q = WaitQueue::new();
+
+        q.wait(&sched, None);
+        q.wait(&sched, None);
+        assert_eq!(q.len(), 2);
+        assert!(ts.iter().all(|t| t.state() == State::Blocked));
+
+        assert_eq!(q.wake_one().unwrap().id, ts[0].id);
+        assert_eq!(ts[0].state(), State::Running);
+        assert_eq!(ts[1].state(), State::Blocked);
+        assert_eq!(q.wake_one().unwrap().id, ts[1].id);
+        assert!(q.wake_one().is_none());
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn wake_all() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let ts: Vec<_> = (0..3).map(|_| new_thread(&threads)).collect();
+        let sched = scheduler(&ts);
+        let q = WaitQueue::new();
+
+        for _ in &ts {
+            q.wait(&sched, None);
+        }
+        assert_eq!(q.wake_all(), 3);
+        assert!(ts.iter().all(|t| t.state() == State::Running));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn wait_if_false_does_not_block() {
+        let sched = scheduler(&[]);
+        let q = WaitQueue::new();
+        assert!(!q.wait_if(&sched, None, || false));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn timed_out_waiter_is_skipped() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let ts: Vec<_> = (0..2).map(|_| new_thread(&threads)).collect();
+        let sched = scheduler(&ts);
+        let timers = Box::leak(Box::new(TimerQueue::new()));
+        let q = WaitQueue::new();
+
+        q.wait(&sched, Some(Timeout::new(timers, 10)));
+        q.wait(&sched, None);
+        let (_, expire) = timers.pop_expired(10).unwrap();
+        expire();
+        assert_eq!(ts[0].state(), State::Running);
+        assert_eq!(ts[0].take_wait_outcome(), Some(WaitOutcome::TimedOut));
+        assert_eq!(q.len(), 1);
+
+        // the signal goes to the thread that is still waiting
+        assert_eq!(q.wake_one().unwrap().id, ts[1].id);
+        assert_eq!(ts[1].take_wait_outcome(), Some(WaitOutcome::Signaled));
+        assert!(q.wake_one().is_none());
+    }
+
+    #[test]
+    fn cancelled_thread_does_not_wait() {
+        let threads = HandleMap::new(MAX_THREAD_ID);
+        let t = new_thread(&threads);
+        let sched = scheduler(core::slice::from_ref(&t));
+        let q = WaitQueue::new();
+
+        t.cancel_waits();
+        assert!(!q.wait_if(&sched, None, || true));
+        assert_eq!(t.state(), State::Running);
+        assert_eq!(t.take_wait_outcome(), Some(WaitOutcome::Cancelled));
+        assert!(q.is_empty());
+    }
+}
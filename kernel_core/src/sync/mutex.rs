@@ -0,0 +1,127 @@
+//! A spin lock that takes part in [lock dependency tracking](super::lockdep).
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+use super::interrupts::{self, MaskGuard};
+#[cfg(feature = "lockdep")]
+use super::lockdep::{self, Class};
+
+/// A mutual exclusion spin lock protecting a `T`.
+///
+/// This behaves exactly like [`spin::Mutex`], except that interrupts are
+/// [masked](super::interrupts) on the current core while it is held, and with the `lockdep`
+/// feature the order locks are taken in is checked. Each lock's class is the place where it was
+/// created.
+pub struct Mutex<T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    class: Class,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Create a new unlocked lock protecting `value`.
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(feature = "lockdep")]
+            class: core::panic::Location::caller(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    /// Consume the lock, returning the protected value.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Take the lock, spinning until it is available.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let interrupts = interrupts::mask();
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.class);
+        MutexGuard {
+            #[cfg(feature = "lockdep")]
+            class: self.class,
+            guard: self.inner.lock(),
+            _interrupts: interrupts,
+        }
+    }
+
+    /// Take the lock if it is available right now.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let interrupts = interrupts::mask();
+        let guard = self.inner.try_lock()?;
+        #[cfg(feature = "lockdep")]
+        lockdep::acquired_without_waiting(self.class);
+        Some(MutexGuard {
+            #[cfg(feature = "lockdep")]
+            class: self.class,
+            guard,
+            _interrupts: interrupts,
+        })
+    }
+
+    /// Returns true if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Get a mutable reference to the protected value, which needs no locking because the lock is
+    /// borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+/// Exclusive access to the value protected by a [`Mutex`], which releases the lock on drop.
+pub struct MutexGuard<'l, T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    class: Class,
+    guard: spin::MutexGuard<'l, T>,
+    /// Dropped after `guard`, so that interrupts stay masked until the lock is released.
+    _interrupts: MaskGuard,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.guard.fmt(f)
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.class);
+    }
+}
@@ -0,0 +1,233 @@
+//! Deferred reclamation for data structures that are read without locks (read-copy-update).
+//!
+//! Readers of a lock-free structure may still be using an object after a writer has unlinked it.
+//! Instead of freeing the object right away, the writer waits for a grace period: the time until
+//! every core has passed a quiescent point, where it can't be in the middle of a read. After that,
+//! no reader can still see the object, so it can be freed.
+//!
+//! The kernel reports a quiescent point whenever it finishes handling an exception that interrupted
+//! user space or an idle core. Readers must therefore not be preempted in the middle of a read, so
+//! each read happens while holding a [`ReadGuard`] from [`read`], which masks interrupts on the
+//! current core. Idle cores may not be interrupted for a long time, so they don't hold up grace
+//! periods at all until they next handle an exception.
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use hashbrown::HashMap;
+use log::trace;
+use spin::Once;
+
+use super::{interrupts, Mutex};
+use crate::platform::cpu::Id as CpuId;
+
+/// A point in time that a grace period must pass before reclaiming an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GracePeriod(u64);
+
+/// The epoch recorded for an idle core, which can't be reading anything.
+const IDLE: u64 = u64::MAX;
+
+/// A function that frees an object once its grace period has elapsed.
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Tracks the quiescent points of each core to tell when grace periods have elapsed.
+pub struct Rcu {
+    /// Incremented to start each grace period.
+    epoch: AtomicU64,
+    /// The epoch each core saw at its most recent quiescent point, or [`IDLE`] if it is idle.
+    seen: HashMap<CpuId, AtomicU64>,
+    /// Functions to run once their grace periods have elapsed, in the order they were deferred.
+    deferred: Mutex<VecDeque<(GracePeriod, Callback)>>,
+}
+
+impl Rcu {
+    /// Create a new tracker for the cores `cpus`.
+    ///
+    /// Every core must pass quiescent points regularly, or no grace period will ever elapse.
+    pub fn new(cpus: impl IntoIterator<Item = CpuId>) -> Self {
+        Self {
+            epoch: AtomicU64::new(0),
+            seen: cpus.into_iter().map(|id| (id, AtomicU64::new(0))).collect(),
+            deferred: Mutex::default(),
+        }
+    }
+
+    /// Start a grace period. Objects unlinked before this call can be freed once it has elapsed.
+    pub fn start_grace_period(&self) -> GracePeriod {
+        GracePeriod(self.epoch.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    /// The most recent grace period that has elapsed on every core.
+    fn completed(&self) -> GracePeriod {
+        GracePeriod(
+            self.seen
+                .values()
+                .map(|e| e.load(Ordering::SeqCst))
+                .min()
+                .unwrap_or(IDLE),
+        )
+    }
+
+    /// Returns true if every core has passed a quiescent point since `period` started.
+    pub fn has_elapsed(&self, period: GracePeriod) -> bool {
+        period <= self.completed()
+    }
+
+    /// Run `f` once a grace period started now has elapsed.
+    pub fn defer(&self, f: impl FnOnce() + Send + 'static) {
+        let mut deferred = self.deferred.lock();
+        // start the grace period while locked so that the queue stays in order
+        let period = self.start_grace_period();
+        deferred.push_back((period, Box::new(f)));
+    }
+
+    /// Record that the core `cpu` has passed a quiescent point, running any deferred functions
+    /// whose grace periods have now elapsed. Returns the number of functions run.
+    ///
+    /// Unknown cores are ignored.
+    pub fn quiescent(&self, cpu: CpuId) -> usize {
+        let Some(seen) = self.seen.get(&cpu) else {
+            return 0;
+        };
+        seen.store(self.epoch.load(Ordering::SeqCst), Ordering::SeqCst);
+        let completed = self.completed();
+        let ready: Vec<_> = {
+            let mut deferred = self.deferred.lock();
+            let count = deferred.partition_point(|(p, _)| *p <= completed);
+            deferred.drain(..count).collect()
+        };
+        if !ready.is_empty() {
+            trace!("running {} deferred functions on core {cpu}", ready.len());
+        }
+        let count = ready.len();
+        for (_, f) in ready {
+            f();
+        }
+        count
+    }
+
+    /// Record that the core `cpu` is about to go idle, so it won't read anything until
+    /// [`Rcu::exit_idle`] is called.
+    pub fn enter_idle(&self, cpu: CpuId) {
+        if let Some(seen) = self.seen.get(&cpu) {
+            seen.store(IDLE, Ordering::SeqCst);
+        }
+    }
+
+    /// Record that the core `cpu` is no longer idle, and may start reading.
+    pub fn exit_idle(&self, cpu: CpuId) {
+        if let Some(seen) = self.seen.get(&cpu) {
+            seen.store(self.epoch.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+    }
+
+    /// The number of deferred functions that are still waiting for their grace periods.
+    pub fn pending(&self) -> usize {
+        self.deferred.lock().len()
+    }
+}
+
+/// A read-side critical section. The current core can't be preempted, and so can't pass a
+/// quiescent point, until this guard is dropped.
+#[must_use]
+pub struct ReadGuard {
+    _mask: interrupts::MaskGuard,
+}
+
+/// Start a read-side critical section, which lasts until the returned guard is dropped. Objects
+/// found while it lasts are not freed until after it ends.
+///
+/// This masks interrupts, so that an interrupt thread can't be preempted in the middle of a read.
+pub fn read() -> ReadGuard {
+    ReadGuard {
+        _mask: interrupts::mask(),
+    }
+}
+
+/// The kernel's tracker, shared by every lock-free structure.
+static RCU: Once<Rcu> = Once::new();
+
+/// Start tracking grace periods on the cores `cpus` for the whole kernel.
+///
+/// Until this is called, objects retired by kernel structures are freed immediately, which is only
+/// safe while a single core is running.
+pub fn init(cpus: impl IntoIterator<Item = CpuId>) -> &'static Rcu {
+    RCU.call_once(|| Rcu::new(cpus))
+}
+
+/// The kernel's tracker, if [`init`] has been called.
+pub fn global() -> Option<&'static Rcu> {
+    RCU.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+
+    #[test]
+    fn grace_period_waits_for_every_core() {
+        let rcu = Rcu::new([0, 1, 2]);
+        let period = rcu.start_grace_period();
+        assert!(!rcu.has_elapsed(period));
+        rcu.quiescent(0);
+        rcu.quiescent(2);
+        assert!(!rcu.has_elapsed(period));
+        rcu.quiescent(1);
+        assert!(rcu.has_elapsed(period));
+
+        // quiescent points before a grace period starts don't count
+        let next = rcu.start_grace_period();
+        assert!(!rcu.has_elapsed(next));
+        assert!(rcu.has_elapsed(period));
+    }
+
+    #[test]
+    fn idle_cores_do_not_hold_up_grace_periods() {
+        let rcu = Rcu::new([0, 1]);
+        rcu.enter_idle(1);
+        let period = rcu.start_grace_period();
+        rcu.quiescent(0);
+        assert!(rcu.has_elapsed(period));
+
+        // once it wakes up, the core must pass a quiescent point again
+        rcu.exit_idle(1);
+        let next = rcu.start_grace_period();
+        rcu.quiescent(0);
+        assert!(!rcu.has_elapsed(next));
+        rcu.quiescent(1);
+        assert!(rcu.has_elapsed(next));
+    }
+
+    #[test]
+    fn readers_mask_interrupts() {
+        use crate::sync::interrupts::tests::{mask_test_thread, unmask_test_thread, MASKED};
+        interrupts::set_masking(mask_test_thread, unmask_test_thread);
+        {
+            let _read = read();
+            assert!(MASKED.get());
+        }
+        assert!(!MASKED.get());
+    }
+
+    #[test]
+    fn deferred_functions_run_in_order_after_grace_period() {
+        let rcu = Rcu::new([0, 1]);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..2 {
+            let order = order.clone();
+            rcu.defer(move || order.lock().push(i));
+        }
+        assert_eq!(rcu.quiescent(0), 0);
+        assert_eq!(rcu.quiescent(7), 0);
+        assert_eq!(rcu.pending(), 2);
+        assert_eq!(rcu.quiescent(1), 2);
+        assert_eq!(*order.lock(), [0, 1]);
+
+        let order2 = order.clone();
+        rcu.defer(move || order2.lock().push(2));
+        assert_eq!(rcu.quiescent(1), 0);
+        assert_eq!(rcu.quiescent(0), 1);
+        assert_eq!(*order.lock(), [0, 1, 2]);
+        assert_eq!(rcu.pending(), 0);
+    }
+}
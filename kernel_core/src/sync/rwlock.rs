@@ -0,0 +1,253 @@
+//! A reader-writer spin lock that prefers writers.
+//!
+//! Any number of readers can hold the lock at once, but a writer has exclusive access. Once a
+//! writer starts waiting, no new readers can take the lock, so a steady stream of readers can't
+//! starve writers out. Each lock counts how often it was contended, so hot locks can be found.
+//! Interrupts are [masked](super::interrupts) on the current core while the lock is held.
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::interrupts::{self, MaskGuard};
+
+/// Set in the lock state while a writer holds the lock.
+const WRITER: usize = 1;
+/// Added to the lock state for each reader holding the lock.
+const READER: usize = 2;
+
+/// A reader-writer spin lock protecting a `T`, which prefers writers over readers.
+pub struct RwSpinLock<T: ?Sized> {
+    /// The number of readers holding the lock times [`READER`], plus [`WRITER`] if a writer holds it.
+    state: AtomicUsize,
+    /// The number of writers waiting to take the lock.
+    waiting_writers: AtomicUsize,
+    /// The number of times a thread had to spin to take the lock.
+    contended: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the lock hands out `&T` to many threads at once and `&mut T` to one thread at a time,
+// just like `std::sync::RwLock`.
+unsafe impl<T: ?Sized + Send> Send for RwSpinLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinLock<T> {}
+
+impl<T> RwSpinLock<T> {
+    /// Create a new unlocked lock protecting `value`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+            contended: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consume the lock, returning the protected value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for RwSpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> RwSpinLock<T> {
+    /// Take the lock for reading, spinning while a writer holds the lock or is waiting for it.
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        if let Some(guard) = self.try_read() {
+            return guard;
+        }
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        loop {
+            core::hint::spin_loop();
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+        }
+    }
+
+    /// Take the lock for reading if no writer holds the lock or is waiting for it.
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        let interrupts = interrupts::mask();
+        if self.waiting_writers.load(Ordering::Relaxed) > 0 {
+            return None;
+        }
+        let state = self.state.load(Ordering::Relaxed);
+        if state & WRITER != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| ReadGuard {
+                lock: self,
+                _interrupts: interrupts,
+            })
+    }
+
+    /// Take the lock for writing, spinning until every other reader and writer has released it.
+    ///
+    /// New readers can't take the lock while this waits.
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        loop {
+            core::hint::spin_loop();
+            if let Some(guard) = self.try_write() {
+                self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+                return guard;
+            }
+        }
+    }
+
+    /// Take the lock for writing if no other reader or writer holds it.
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        let interrupts = interrupts::mask();
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| WriteGuard {
+                lock: self,
+                _interrupts: interrupts,
+            })
+    }
+
+    /// Get a mutable reference to the protected value, which needs no locking because the lock is
+    /// borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// The number of times a reader or writer had to spin because the lock was not available.
+    pub fn contention(&self) -> usize {
+        self.contended.load(Ordering::Relaxed)
+    }
+}
+
+/// Shared access to the value protected by a [`RwSpinLock`], which releases the lock on drop.
+pub struct ReadGuard<'l, T: ?Sized> {
+    lock: &'l RwSpinLock<T>,
+    _interrupts: MaskGuard,
+}
+
+impl<T: ?Sized> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: no writer can hold the lock while this guard exists.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+/// Exclusive access to the value protected by a [`RwSpinLock`], which releases the lock on drop.
+pub struct WriteGuard<'l, T: ?Sized> {
+    lock: &'l RwSpinLock<T>,
+    _interrupts: MaskGuard,
+}
+
+impl<T: ?Sized> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: no other reader or writer can hold the lock while this guard exists.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: no other reader or writer can hold the lock while this guard exists.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn readers_share_and_writers_exclude() {
+        let lock = RwSpinLock::new(1);
+        let a = lock.read();
+        let b = lock.read();
+        assert_eq!(*a + *b, 2);
+        assert!(lock.try_write().is_none());
+        drop((a, b));
+
+        let mut w = lock.write();
+        *w = 5;
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(w);
+
+        assert_eq!(*lock.read(), 5);
+        assert_eq!(lock.contention(), 0);
+        assert_eq!(lock.into_inner(), 5);
+    }
+
+    #[test]
+    fn waiting_writer_blocks_new_readers() {
+        let lock = RwSpinLock::new(0);
+        let reader = lock.read();
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write() += 1);
+            while lock.waiting_writers.load(Ordering::Relaxed) == 0 {
+                core::hint::spin_loop();
+            }
+            assert!(lock.try_read().is_none());
+            drop(reader);
+            writer.join().unwrap();
+        });
+        assert_eq!(*lock.read(), 1);
+        assert_eq!(lock.contention(), 1);
+    }
+
+    #[test]
+    fn stress_readers_never_see_partial_writes() {
+        const WRITERS: usize = 4;
+        const WRITES: usize = 2000;
+        let lock = RwSpinLock::new((0usize, 0usize));
+        thread::scope(|s| {
+            for _ in 0..WRITERS {
+                s.spawn(|| {
+                    for _ in 0..WRITES {
+                        let mut w = lock.write();
+                        w.0 += 1;
+                        core::hint::spin_loop();
+                        w.1 += 1;
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..WRITES {
+                        let r = lock.read();
+                        assert_eq!(r.0, r.1);
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.read(), (WRITERS * WRITES, WRITERS * WRITES));
+    }
+}
@@ -0,0 +1,171 @@
+//! A sequence lock, for small values that are read much more often than they are written.
+//!
+//! Readers never write to shared memory, so they don't contend with each other. Instead, they copy
+//! the value and retry if a writer changed it in the meantime. Writers are serialized with each
+//! other and never wait for readers.
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+/// A sequence lock protecting a copyable `T`.
+///
+/// The layout is fixed so that the lock can be shared with user space (see
+/// [`crate::time::page`]): the sequence, then the contention count, then the value.
+#[repr(C)]
+pub struct SeqLock<T: Copy> {
+    /// Incremented before and after each write, so it is odd while a write is in progress.
+    sequence: AtomicUsize,
+    /// The number of times a writer had to wait for another write to finish. Readers don't count
+    /// their retries here, since that would write to shared memory.
+    contended: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: readers only ever copy the value out, and writers are serialized by the sequence.
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy> SeqLock<T> {
+    /// Create a new lock protecting `value`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            sequence: AtomicUsize::new(0),
+            contended: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Read a copy of the value, retrying until no write happened during the read.
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                // SAFETY: a writer may be changing the value concurrently, so it is read
+                // volatile and only returned if the sequence shows no write happened meanwhile.
+                let value = unsafe { self.value.get().read_volatile() };
+                fence(Ordering::Acquire);
+                if self.sequence.load(Ordering::Relaxed) == before {
+                    return value;
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Replace the value with `value`.
+    pub fn write(&self, value: T) {
+        self.update(|v| *v = value);
+    }
+
+    /// Change the value in place with `f`, waiting for any other writer to finish first.
+    ///
+    /// Readers retry until `f` returns, so it should be quick. Interrupts are
+    /// [masked](super::interrupts) meanwhile, so that a reader in an interrupt handler can't spin
+    /// forever on the write it interrupted.
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let _interrupts = super::interrupts::mask();
+        let mut sequence = self.sequence.load(Ordering::Relaxed);
+        let mut waited = false;
+        loop {
+            if sequence & 1 == 0 {
+                match self.sequence.compare_exchange_weak(
+                    sequence,
+                    sequence + 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    // a weak exchange can fail spuriously, which isn't another writer
+                    Err(s) => {
+                        waited |= s != sequence;
+                        sequence = s;
+                    }
+                }
+            } else {
+                waited = true;
+                sequence = self.sequence.load(Ordering::Relaxed);
+            }
+            core::hint::spin_loop();
+        }
+        if waited {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+        }
+        // make sure readers see the odd sequence before any of the writes to the value
+        fence(Ordering::Release);
+        // SAFETY: the odd sequence excludes other writers, and readers discard what they read.
+        let mut value = unsafe { self.value.get().read_volatile() };
+        f(&mut value);
+        unsafe { self.value.get().write_volatile(value) };
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    /// The number of times a writer had to wait for another write to finish.
+    pub fn contention(&self) -> usize {
+        self.contended.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn read_sees_latest_write() {
+        let lock = SeqLock::new(3u64);
+        assert_eq!(lock.read(), 3);
+        lock.write(7);
+        assert_eq!(lock.read(), 7);
+        lock.update(|v| *v *= 2);
+        assert_eq!(lock.read(), 14);
+        assert_eq!(lock.sequence.load(Ordering::Relaxed), 4);
+        assert_eq!(lock.contention(), 0);
+    }
+
+    #[test]
+    fn contention_is_counted_once_per_wait() {
+        let lock = SeqLock::new(0u64);
+        // hold the lock as another writer would, for long enough that the update spins many times
+        lock.sequence.store(1, Ordering::Relaxed);
+        thread::scope(|s| {
+            let writer = s.spawn(|| lock.update(|v| *v += 1));
+            thread::sleep(std::time::Duration::from_millis(50));
+            lock.sequence.store(2, Ordering::Release);
+            writer.join().unwrap();
+        });
+        assert_eq!(lock.read(), 1);
+        assert_eq!(lock.contention(), 1);
+    }
+
+    #[test]
+    fn stress_reads_are_never_torn() {
+        const WRITERS: u64 = 4;
+        const WRITES: u64 = 5000;
+        let lock = SeqLock::new([0u64; 4]);
+        thread::scope(|s| {
+            for _ in 0..WRITERS {
+                s.spawn(|| {
+                    for _ in 0..WRITES {
+                        lock.update(|v| v.iter_mut().for_each(|x| *x += 1));
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..WRITES {
+                        let v = lock.read();
+                        assert!(v.iter().all(|x| *x == v[0]), "torn read {v:?}");
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.read(), [WRITERS * WRITES; 4]);
+    }
+}
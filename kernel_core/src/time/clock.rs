@@ -0,0 +1,192 @@
+//! Monotonic and wall clock time.
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+use super::{Ticks, TimePage, TimeParameters};
+use crate::sync::Mutex;
+
+/// Number of nanoseconds in a second.
+pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Reads the free-running system counter (for instance the ARM generic timer's `CNTPCT_EL0`).
+pub trait CounterReader {
+    /// The current value of the counter.
+    fn read() -> Ticks;
+}
+
+/// The kernel's source of time.
+///
+/// Monotonic time counts nanoseconds since the counter started and never goes backwards.
+/// Realtime (wall clock) time counts nanoseconds since the Unix epoch, and is derived from
+/// monotonic time plus an offset that can be adjusted at any time.
+///
+/// The clock's parameters can be published to a [`TimePage`], which is kept up to date as the
+/// realtime offset changes.
+pub struct Clock<C: CounterReader> {
+    /// Counter ticks per second.
+    frequency: u64,
+    /// Nanoseconds to add to monotonic time to get realtime.
+    realtime_offset: AtomicI64,
+    /// The page the parameters are published to, and the offset from the virtual counter that
+    /// user space reads to this clock's counter. Locked while the page is updated, so that
+    /// updates don't race.
+    time_page: Mutex<Option<(&'static TimePage, Ticks)>>,
+    counter: PhantomData<C>,
+}
+
+impl<C: CounterReader> Clock<C> {
+    /// Create a new clock for a counter that increments `frequency` times per second.
+    /// Realtime starts out equal to monotonic time until it is set.
+    ///
+    /// # Panics
+    /// Panics if `frequency` is zero.
+    #[must_use]
+    pub fn new(frequency: u64) -> Self {
+        assert!(frequency > 0);
+        Self {
+            frequency,
+            realtime_offset: AtomicI64::new(0),
+            time_page: Mutex::new(None),
+            counter: PhantomData,
+        }
+    }
+
+    /// Counter ticks per second.
+    #[must_use]
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Convert a number of counter ticks into nanoseconds.
+    #[must_use]
+    pub fn ticks_to_nanos(&self, ticks: Ticks) -> u64 {
+        (u128::from(ticks) * u128::from(NANOS_PER_SECOND) / u128::from(self.frequency))
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Convert a number of nanoseconds into counter ticks, rounding up.
+    #[must_use]
+    pub fn nanos_to_ticks(&self, nanos: u64) -> Ticks {
+        (u128::from(nanos) * u128::from(self.frequency))
+            .div_ceil(u128::from(NANOS_PER_SECOND))
+            .try_into()
+            .unwrap_or(Ticks::MAX)
+    }
+
+    /// The current value of the counter.
+    #[must_use]
+    pub fn now(&self) -> Ticks {
+        C::read()
+    }
+
+    /// Nanoseconds since the counter started.
+    #[must_use]
+    pub fn monotonic_nanos(&self) -> u64 {
+        self.ticks_to_nanos(C::read())
+    }
+
+    /// Nanoseconds since the Unix epoch, according to the current realtime offset.
+    #[must_use]
+    pub fn realtime_nanos(&self) -> u64 {
+        self.monotonic_nanos()
+            .saturating_add_signed(self.realtime_offset.load(Ordering::Acquire))
+    }
+
+    /// Set the realtime clock so that it currently reads `nanos` since the Unix epoch.
+    pub fn set_realtime(&self, nanos: u64) {
+        let offset = i128::from(nanos) - i128::from(self.monotonic_nanos());
+        let offset = i64::try_from(offset).unwrap_or(if offset < 0 { i64::MIN } else { i64::MAX });
+        self.realtime_offset.store(offset, Ordering::Release);
+        self.publish();
+    }
+
+    /// Move the realtime clock forwards (or backwards, if negative) by `delta` nanoseconds.
+    pub fn adjust_realtime(&self, delta: i64) {
+        self.realtime_offset.fetch_add(delta, Ordering::AcqRel);
+        self.publish();
+    }
+
+    /// Publish the parameters of this clock to `page` now and whenever they change.
+    /// User space reads the virtual counter, which is `counter_offset` ticks behind this clock's
+    /// counter.
+    pub fn attach_time_page(&self, page: &'static TimePage, counter_offset: Ticks) {
+        *self.time_page.lock() = Some((page, counter_offset));
+        self.publish();
+    }
+
+    /// Write the current parameters to the time page, if there is one.
+    fn publish(&self) {
+        let time_page = self.time_page.lock();
+        if let Some((page, counter_offset)) = *time_page {
+            page.update(&TimeParameters {
+                frequency: self.frequency,
+                counter_offset,
+                // read while locked, so the last update always has the latest offset
+                realtime_offset: self.realtime_offset.load(Ordering::Acquire),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicU64;
+
+    use super::*;
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    struct TestCounter;
+
+    impl CounterReader for TestCounter {
+        fn read() -> Ticks {
+            COUNTER.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        let c = Clock::<TestCounter>::new(62_500_000);
+        assert_eq!(c.ticks_to_nanos(62_500_000), NANOS_PER_SECOND);
+        assert_eq!(c.ticks_to_nanos(1), 16);
+        assert_eq!(c.nanos_to_ticks(16), 1);
+        assert_eq!(c.nanos_to_ticks(17), 2);
+        assert_eq!(c.ticks_to_nanos(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn monotonic_and_realtime() {
+        // this is the only test that changes the counter
+        let c = Clock::<TestCounter>::new(1_000);
+        COUNTER.store(2_000, Ordering::Relaxed);
+        assert_eq!(c.monotonic_nanos(), 2 * NANOS_PER_SECOND);
+        assert_eq!(c.realtime_nanos(), 2 * NANOS_PER_SECOND);
+        c.set_realtime(100 * NANOS_PER_SECOND);
+        COUNTER.store(3_000, Ordering::Relaxed);
+        assert_eq!(c.monotonic_nanos(), 3 * NANOS_PER_SECOND);
+        assert_eq!(c.realtime_nanos(), 101 * NANOS_PER_SECOND);
+        c.adjust_realtime(-(NANOS_PER_SECOND as i64));
+        assert_eq!(c.realtime_nanos(), 100 * NANOS_PER_SECOND);
+    }
+
+    #[test]
+    fn time_page_follows_realtime() {
+        let c = Clock::<TestCounter>::new(1_000);
+        let page = std::boxed::Box::leak(std::boxed::Box::new(TimePage::new()));
+        c.adjust_realtime(7);
+        c.attach_time_page(page, 5);
+        assert_eq!(
+            page.read(),
+            TimeParameters {
+                frequency: 1_000,
+                counter_offset: 5,
+                realtime_offset: 7,
+            }
+        );
+        c.adjust_realtime(-10);
+        assert_eq!(page.read().realtime_offset, -3);
+    }
+}
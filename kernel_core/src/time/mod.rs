@@ -0,0 +1,11 @@
+//! Timekeeping and timer events.
+
+pub mod clock;
+pub mod page;
+pub mod timer_queue;
+pub use clock::{Clock, CounterReader};
+pub use page::{TimePage, TimeParameters};
+pub use timer_queue::{TimerId, TimerQueue};
+
+/// A point in time, measured in ticks of the system counter since it started.
+pub type Ticks = u64;
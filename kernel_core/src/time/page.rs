@@ -0,0 +1,161 @@
+//! The time page, which is mapped read only into every process so that user space can read the
+//! time without making a system call.
+//!
+//! # Layout
+//! The layout of the page is a stable ABI. All fields are little-endian, and the page starts with
+//! a [`TimePage`]:
+//!
+//! | Offset | Type  | Field             | Meaning                                               |
+//! |--------|-------|-------------------|-------------------------------------------------------|
+//! | 0      | `u32` | `version`         | The layout version, [`TIME_PAGE_VERSION`].            |
+//! | 4      | `u32` |                   | Reserved.                                             |
+//! | 8      | `u64` | `sequence`        | Odd while the kernel is updating the page.            |
+//! | 16     | `u64` |                   | Reserved for the kernel.                              |
+//! | 24     | `u64` | `frequency`       | Counter ticks per second.                             |
+//! | 32     | `u64` | `counter_offset`  | Ticks to add to `CNTVCT_EL0` to get monotonic ticks.  |
+//! | 40     | `i64` | `realtime_offset` | Nanoseconds to add to monotonic time to get realtime. |
+//!
+//! Fields may be added after these in later versions, but existing fields will never move.
+//!
+//! # Reading the time
+//! The kernel updates the page while user space may be reading it. The fields from `sequence` on
+//! are a [`SeqLock`] around a [`TimeParameters`], so readers must use the sequence number to get a
+//! consistent snapshot:
+//! 1. Read `sequence`, retrying while it is odd.
+//! 2. Read the parameters. Readers must not write to the page, which is read only to them.
+//! 3. Read `sequence` again, and start over if it changed.
+//!
+//! Monotonic time in nanoseconds is then `(CNTVCT_EL0 + counter_offset) * 10^9 / frequency`, and
+//! realtime (nanoseconds since the Unix epoch) is monotonic time plus `realtime_offset`. See
+//! [`TimeParameters`].
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{clock::NANOS_PER_SECOND, Ticks};
+use crate::sync::seqlock::SeqLock;
+
+/// The version of the time page layout described in the [module documentation](self).
+pub const TIME_PAGE_VERSION: u32 = 1;
+
+/// The contents of the time page. See the [module documentation](self) for the layout.
+#[repr(C)]
+pub struct TimePage {
+    version: AtomicU32,
+    _reserved: u32,
+    parameters: SeqLock<TimeParameters>,
+}
+
+/// A consistent snapshot of the parameters in the time page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct TimeParameters {
+    /// Counter ticks per second.
+    pub frequency: u64,
+    /// Ticks to add to the virtual counter (`CNTVCT_EL0`) to get monotonic ticks.
+    pub counter_offset: Ticks,
+    /// Nanoseconds to add to monotonic time to get realtime.
+    pub realtime_offset: i64,
+}
+
+impl TimeParameters {
+    /// Nanoseconds of monotonic time, given the value of the virtual counter.
+    #[must_use]
+    pub fn monotonic_nanos(&self, virtual_counter: Ticks) -> u64 {
+        let ticks = virtual_counter.wrapping_add(self.counter_offset);
+        (u128::from(ticks) * u128::from(NANOS_PER_SECOND) / u128::from(self.frequency.max(1)))
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Nanoseconds since the Unix epoch, given the value of the virtual counter.
+    #[must_use]
+    pub fn realtime_nanos(&self, virtual_counter: Ticks) -> u64 {
+        self.monotonic_nanos(virtual_counter)
+            .saturating_add_signed(self.realtime_offset)
+    }
+}
+
+impl TimePage {
+    /// Create a time page with every parameter zeroed.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            version: AtomicU32::new(TIME_PAGE_VERSION),
+            _reserved: 0,
+            parameters: SeqLock::new(TimeParameters {
+                frequency: 0,
+                counter_offset: 0,
+                realtime_offset: 0,
+            }),
+        }
+    }
+
+    /// The layout version of the page.
+    #[must_use]
+    pub fn version(&self) -> u32 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    /// Write new parameters to the page.
+    pub fn update(&self, parameters: &TimeParameters) {
+        self.parameters.write(*parameters);
+    }
+
+    /// Read a consistent snapshot of the parameters in the page.
+    #[must_use]
+    pub fn read(&self) -> TimeParameters {
+        self.parameters.read()
+    }
+}
+
+impl Default for TimePage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the `u64` at `offset` bytes into `page`, the way user space would.
+    fn word_at(page: &TimePage, offset: usize) -> u64 {
+        unsafe {
+            core::ptr::from_ref(page)
+                .byte_add(offset)
+                .cast::<u64>()
+                .read()
+        }
+    }
+
+    #[test]
+    fn layout_is_stable() {
+        let page = TimePage::new();
+        page.update(&TimeParameters {
+            frequency: 1_000,
+            counter_offset: 500,
+            realtime_offset: -7,
+        });
+        assert_eq!(word_at(&page, 0) as u32, TIME_PAGE_VERSION);
+        assert_eq!(word_at(&page, 8), 2);
+        assert_eq!(word_at(&page, 24), 1_000);
+        assert_eq!(word_at(&page, 32), 500);
+        assert_eq!(word_at(&page, 40) as i64, -7);
+    }
+
+    #[test]
+    fn update_and_compute_time() {
+        let page = TimePage::new();
+        assert_eq!(page.version(), TIME_PAGE_VERSION);
+        let parameters = TimeParameters {
+            frequency: 1_000,
+            counter_offset: 500,
+            realtime_offset: -(NANOS_PER_SECOND as i64),
+        };
+        page.update(&parameters);
+        let read = page.read();
+        assert_eq!(read, parameters);
+        assert_eq!(read.monotonic_nanos(2_500), 3 * NANOS_PER_SECOND);
+        assert_eq!(read.realtime_nanos(2_500), 2 * NANOS_PER_SECOND);
+        assert_eq!(read.realtime_nanos(0), 0);
+    }
+}
@@ -0,0 +1,149 @@
+//! One-shot timer events, ordered by deadline.
+use alloc::{boxed::Box, collections::BinaryHeap};
+use core::{
+    cmp::{Ordering, Reverse},
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+};
+
+use super::Ticks;
+use crate::sync::Mutex;
+
+/// Identifies an armed timer so that it can be cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+/// The default timer event, a callback run when the timer expires.
+pub type Callback = Box<dyn FnOnce() + Send>;
+
+struct Entry<E> {
+    deadline: Ticks,
+    id: TimerId,
+    event: E,
+}
+
+// Entries are ordered by deadline, with ties broken by the order they were armed in.
+impl<E> Entry<E> {
+    fn key(&self) -> (Ticks, TimerId) {
+        (self.deadline, self.id)
+    }
+}
+
+impl<E> PartialEq for Entry<E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<E> Eq for Entry<E> {}
+
+impl<E> PartialOrd for Entry<E> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E> Ord for Entry<E> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// A queue of one-shot timers (for instance for sleeps and timeouts), kept as a min-heap ordered by
+/// deadline so that the hardware timer can be programmed to fire at the earliest one.
+pub struct TimerQueue<E = Callback> {
+    heap: Mutex<BinaryHeap<Reverse<Entry<E>>>>,
+    next_id: AtomicU64,
+}
+
+impl<E> TimerQueue<E> {
+    /// Create a new, empty timer queue.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            heap: Mutex::new(BinaryHeap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Arm a timer that delivers `event` once the time reaches `deadline`.
+    pub fn arm(&self, deadline: Ticks, event: E) -> TimerId {
+        let id = TimerId(self.next_id.fetch_add(1, AtomicOrdering::Relaxed));
+        self.heap.lock().push(Reverse(Entry {
+            deadline,
+            id,
+            event,
+        }));
+        id
+    }
+
+    /// Cancel a timer before it expires, returning its event.
+    ///
+    /// Returns `None` if the timer has already expired or was cancelled.
+    pub fn cancel(&self, id: TimerId) -> Option<E> {
+        let mut heap = self.heap.lock();
+        let mut entries = core::mem::take(&mut *heap).into_vec();
+        let event = entries
+            .iter()
+            .position(|Reverse(e)| e.id == id)
+            .map(|i| entries.swap_remove(i).0.event);
+        *heap = entries.into();
+        event
+    }
+
+    /// The earliest deadline of any armed timer.
+    pub fn next_deadline(&self) -> Option<Ticks> {
+        self.heap.lock().peek().map(|Reverse(e)| e.deadline)
+    }
+
+    /// Remove the timer with the earliest deadline if it has expired by `now`.
+    ///
+    /// Call repeatedly until it returns `None` to collect every expired timer in deadline order.
+    pub fn pop_expired(&self, now: Ticks) -> Option<(TimerId, E)> {
+        let mut heap = self.heap.lock();
+        if heap.peek()?.0.deadline > now {
+            return None;
+        }
+        heap.pop().map(|Reverse(e)| (e.id, e.event))
+    }
+}
+
+impl<E> Default for TimerQueue<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn expire_in_deadline_order() {
+        let q = TimerQueue::new();
+        q.arm(30, 'c');
+        q.arm(10, 'a');
+        q.arm(20, 'b');
+        q.arm(10, 'd');
+        assert_eq!(q.next_deadline(), Some(10));
+        assert!(q.pop_expired(9).is_none());
+        let expired: Vec<_> = core::iter::from_fn(|| q.pop_expired(20))
+            .map(|(_, e)| e)
+            .collect();
+        assert_eq!(expired, ['a', 'd', 'b']);
+        assert_eq!(q.next_deadline(), Some(30));
+    }
+
+    #[test]
+    fn cancel() {
+        let q = TimerQueue::new();
+        let a = q.arm(10, 'a');
+        let b = q.arm(20, 'b');
+        assert_eq!(q.cancel(a), Some('a'));
+        assert_eq!(q.cancel(a), None);
+        assert_eq!(q.next_deadline(), Some(20));
+        assert_eq!(q.pop_expired(100), Some((b, 'b')));
+        assert_eq!(q.next_deadline(), None);
+    }
+}
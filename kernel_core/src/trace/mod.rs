@@ -0,0 +1,350 @@
+//! Static tracepoints, for instrumenting the kernel with events that can be analyzed later.
+//!
+//! Each subsystem declares its tracepoints with [`tracepoints!`](crate::tracepoints), which also
+//! lists them in a `TRACEPOINTS` static so that they can be found by name with [`all`].
+//! Tracepoints start out disabled, and cost only a relaxed load each time they are hit until they
+//! are enabled. When an enabled tracepoint is hit, a record is added to a ring buffer for the
+//! current core, with the time and two arguments whose meaning depends on the tracepoint.
+//!
+//! Records are read out in a binary format meant to be decoded by a tool on the host. Each record
+//! is [`RECORD_LEN`] bytes, with all fields little endian:
+//!
+//! | Offset | Size | Field                                                |
+//! |--------|------|------------------------------------------------------|
+//! | 0      | 1    | [`RECORD_MAGIC`]                                     |
+//! | 1      | 3    | Reserved, zero                                       |
+//! | 4      | 4    | Tracepoint ID (see [`tracepoint_id`])                |
+//! | 8      | 8    | Timestamp in system counter ticks                    |
+//! | 16     | 4    | Core ID                                              |
+//! | 20     | 4    | Reserved, zero                                       |
+//! | 24     | 8    | First argument                                       |
+//! | 32     | 8    | Second argument                                      |
+//!
+//! Tracepoint names are replaced by a hash to keep records small; the decoder is expected to know
+//! the names of the tracepoints in the kernel. Scheduling events have their own trace, in
+//! [`crate::process::thread::scheduler::events`].
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+
+use crate::{
+    logger::binary::module_id,
+    platform::cpu::{CpuIdReader, Id as CpuId},
+    time::{CounterReader, Ticks},
+};
+
+pub mod ring;
+
+use ring::PerCoreRings;
+
+/// The first byte of every encoded record, so that a decoder can tell if the stream is corrupted.
+pub const RECORD_MAGIC: u8 = 0x7c;
+
+/// The size of an encoded record in bytes.
+pub const RECORD_LEN: usize = 40;
+
+/// Compute the ID of the tracepoint named `name`, which is hashed the same way as module paths in
+/// the binary log format (see [`module_id`]), so that a decoder can look both up the same way.
+#[must_use]
+pub const fn tracepoint_id(name: &str) -> u32 {
+    module_id(name)
+}
+
+/// A point in the kernel that can record an event when it is hit.
+pub struct Tracepoint {
+    name: &'static str,
+    id: u32,
+    enabled: AtomicBool,
+}
+
+impl Tracepoint {
+    /// Create a disabled tracepoint named `name`.
+    ///
+    /// Use [`tracepoints!`](crate::tracepoints) to declare tracepoints instead of calling this
+    /// directly, so that they can be found with [`all`].
+    #[must_use]
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            id: tracepoint_id(name),
+            enabled: AtomicBool::new(false),
+        }
+    }
+
+    /// The full name of the tracepoint, which is the path of the module that declared it
+    /// followed by its own name.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The ID of the tracepoint in encoded records.
+    #[must_use]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns true if hitting the tracepoint records an event.
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enable or disable the tracepoint.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Record an event with the kernel's tracer if the tracepoint is enabled.
+    #[inline]
+    pub fn hit(&self, arg0: u64, arg1: u64) {
+        if self.is_enabled() {
+            if let Some(tracer) = TRACER.get() {
+                tracer.record(self, arg0, arg1);
+            }
+        }
+    }
+}
+
+/// Declare tracepoints in the current module, along with a `TRACEPOINTS` static that lists them.
+///
+/// Each tracepoint is named after the module path and its identifier, and its documentation
+/// should say what its arguments mean:
+///
+/// ```
+/// kernel_core::tracepoints! {
+///     /// A widget was frobbed. Arguments: widget ID, frob count.
+///     WIDGET_FROBBED;
+/// }
+///
+/// WIDGET_FROBBED.hit(7, 1);
+/// assert_eq!(TRACEPOINTS.len(), 1);
+/// ```
+#[macro_export]
+macro_rules! tracepoints {
+    ($($(#[$attr:meta])* $name:ident;)*) => {
+        $(
+            $(#[$attr])*
+            pub static $name: $crate::trace::Tracepoint = $crate::trace::Tracepoint::new(
+                concat!(module_path!(), "::", stringify!($name))
+            );
+        )*
+
+        /// Every tracepoint declared in this module.
+        pub static TRACEPOINTS: &[&$crate::trace::Tracepoint] = &[$(&$name),*];
+    };
+}
+
+/// The tracepoints declared by each subsystem.
+static SUBSYSTEMS: [&[&Tracepoint]; 3] = [
+    crate::exceptions::interrupt::TRACEPOINTS,
+    crate::ipc::TRACEPOINTS,
+    crate::memory::TRACEPOINTS,
+];
+
+/// Iterate over every tracepoint in the kernel.
+pub fn all() -> impl Iterator<Item = &'static Tracepoint> {
+    SUBSYSTEMS.iter().flat_map(|tps| tps.iter().copied())
+}
+
+/// Enable or disable every tracepoint whose name starts with `prefix`, returning the number of
+/// tracepoints changed.
+#[must_use]
+pub fn set_enabled(prefix: &str, enabled: bool) -> usize {
+    all()
+        .filter(|tp| tp.name().starts_with(prefix))
+        .inspect(|tp| tp.set_enabled(enabled))
+        .count()
+}
+
+/// A single event recorded at a tracepoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// The ID of the tracepoint that was hit.
+    pub tracepoint: u32,
+    /// The value of the system counter when the tracepoint was hit.
+    pub timestamp: Ticks,
+    /// The core the tracepoint was hit on.
+    pub cpu: u32,
+    /// The arguments given when the tracepoint was hit.
+    pub args: [u64; 2],
+}
+
+impl Record {
+    /// Encode the record in the binary format.
+    #[must_use]
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut data = [0; RECORD_LEN];
+        data[0] = RECORD_MAGIC;
+        LittleEndian::write_u32(&mut data[4..], self.tracepoint);
+        LittleEndian::write_u64(&mut data[8..], self.timestamp);
+        LittleEndian::write_u32(&mut data[16..], self.cpu);
+        LittleEndian::write_u64(&mut data[24..], self.args[0]);
+        LittleEndian::write_u64(&mut data[32..], self.args[1]);
+        data
+    }
+
+    /// Decode a record from the start of `data`, or return `None` if it doesn't hold a record.
+    #[must_use]
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..RECORD_LEN)?;
+        if data[0] != RECORD_MAGIC {
+            return None;
+        }
+        Some(Self {
+            tracepoint: LittleEndian::read_u32(&data[4..]),
+            timestamp: LittleEndian::read_u64(&data[8..]),
+            cpu: LittleEndian::read_u32(&data[16..]),
+            args: [
+                LittleEndian::read_u64(&data[24..]),
+                LittleEndian::read_u64(&data[32..]),
+            ],
+        })
+    }
+}
+
+/// Records tracepoint events into a ring buffer for each core.
+pub struct Tracer {
+    rings: PerCoreRings<Record>,
+}
+
+impl Tracer {
+    /// Create a tracer that keeps the last `capacity` records of each of the cores `cpus`,
+    /// reading the current core with `C` and timestamps with `R`.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is zero.
+    pub fn new<C: CpuIdReader, R: CounterReader>(
+        cpus: impl IntoIterator<Item = CpuId>,
+        capacity: usize,
+    ) -> Self {
+        Self {
+            rings: PerCoreRings::new::<C, R>(cpus, capacity),
+        }
+    }
+
+    /// Record that `tracepoint` was hit on the current core, whether or not it is enabled.
+    ///
+    /// If the buffer is full, the oldest record is discarded. If the buffer is being read, for
+    /// instance because the tracepoint was hit in an interrupt, the new record is discarded
+    /// instead.
+    pub fn record(&self, tracepoint: &Tracepoint, arg0: u64, arg1: u64) {
+        self.rings.push(|cpu, timestamp| Record {
+            tracepoint: tracepoint.id(),
+            timestamp,
+            cpu,
+            args: [arg0, arg1],
+        });
+    }
+
+    /// The records of the core `cpu`, oldest first, without removing them.
+    #[must_use]
+    pub fn records(&self, cpu: CpuId) -> Vec<Record> {
+        self.rings.records(cpu)
+    }
+
+    /// Remove as many of the records of the core `cpu` as fit in `buffer`, oldest first, encoding
+    /// them in the binary format. Returns the number of bytes written.
+    pub fn read(&self, cpu: CpuId, buffer: &mut [u8]) -> usize {
+        self.rings.read(cpu, buffer, Record::encode)
+    }
+
+    /// The number of old records that have been discarded on the core `cpu` to make room for new
+    /// ones.
+    #[must_use]
+    pub fn dropped(&self, cpu: CpuId) -> u64 {
+        self.rings.dropped(cpu)
+    }
+}
+
+/// The kernel's tracer, shared by every tracepoint.
+static TRACER: Once<Tracer> = Once::new();
+
+/// Start recording hits of enabled tracepoints on the cores `cpus`, keeping the last `capacity`
+/// records of each core.
+pub fn init<C: CpuIdReader, R: CounterReader>(
+    cpus: impl IntoIterator<Item = CpuId>,
+    capacity: usize,
+) -> &'static Tracer {
+    TRACER.call_once(|| Tracer::new::<C, R>(cpus, capacity))
+}
+
+/// The kernel's tracer, if [`init`] has been called.
+pub fn global() -> Option<&'static Tracer> {
+    TRACER.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::cpu::tests::FixedCpu;
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static COUNTER: Cell<Ticks> = const { Cell::new(0) };
+    }
+
+    struct TestCounter;
+
+    impl CounterReader for TestCounter {
+        fn read() -> Ticks {
+            COUNTER.get()
+        }
+    }
+
+    mod declared {
+        crate::tracepoints! {
+            /// A test tracepoint.
+            FIRST;
+            /// Another test tracepoint.
+            SECOND;
+        }
+    }
+
+    #[test]
+    fn tracepoints_are_named_after_their_module() {
+        let names: Vec<_> = declared::TRACEPOINTS.iter().map(|tp| tp.name()).collect();
+        assert_eq!(
+            names,
+            [
+                "kernel_core::trace::tests::declared::FIRST",
+                "kernel_core::trace::tests::declared::SECOND"
+            ]
+        );
+        assert_eq!(
+            declared::FIRST.id(),
+            crate::logger::binary::module_id(declared::FIRST.name())
+        );
+        assert!(!declared::SECOND.is_enabled());
+        assert!(all().any(|tp| tp.name().starts_with("kernel_core::ipc::")));
+    }
+
+    #[test]
+    fn records_are_kept_per_core_and_encoded() {
+        let tracer = Tracer::new::<FixedCpu<0>, TestCounter>([0], 2);
+        for i in 0..3 {
+            COUNTER.set(100 + i);
+            tracer.record(&declared::FIRST, i, u64::MAX - i);
+        }
+        let records = tracer.records(0);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].timestamp, 101);
+        assert!(tracer.records(1).is_empty());
+
+        let mut buffer = [0; RECORD_LEN * 3];
+        assert_eq!(tracer.read(0, &mut buffer), RECORD_LEN * 2);
+        let record = Record::decode(&buffer[RECORD_LEN..]).unwrap();
+        assert_eq!(
+            record,
+            Record {
+                tracepoint: declared::FIRST.id(),
+                timestamp: 102,
+                cpu: 0,
+                args: [2, u64::MAX - 2],
+            }
+        );
+        assert!(Record::decode(&buffer[RECORD_LEN * 2..]).is_none());
+        assert_eq!(tracer.read(0, &mut buffer), 0);
+    }
+}
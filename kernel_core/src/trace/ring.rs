@@ -0,0 +1,111 @@
+//! Ring buffers that keep the most recent records made on each core, which the kernel's tracers
+//! record into.
+use alloc::{collections::VecDeque, vec::Vec};
+use hashbrown::HashMap;
+
+use crate::{
+    platform::cpu::{CpuIdReader, Id as CpuId},
+    sync::Mutex,
+    time::{CounterReader, Ticks},
+};
+
+/// The most recent records made on one core.
+struct Ring<T> {
+    records: VecDeque<T>,
+    /// The number of old records discarded to make room for new ones.
+    dropped: u64,
+}
+
+/// A ring buffer for each core, each keeping the last records made on that core.
+pub struct PerCoreRings<T> {
+    rings: HashMap<CpuId, Mutex<Ring<T>>>,
+    capacity: usize,
+    current_cpu: fn() -> CpuId,
+    now: fn() -> Ticks,
+}
+
+impl<T: Copy> PerCoreRings<T> {
+    /// Create rings that keep the last `capacity` records of each of the cores `cpus`, reading the
+    /// current core with `C` and timestamps with `R`.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is zero.
+    pub fn new<C: CpuIdReader, R: CounterReader>(
+        cpus: impl IntoIterator<Item = CpuId>,
+        capacity: usize,
+    ) -> Self {
+        assert!(capacity > 0);
+        Self {
+            rings: cpus
+                .into_iter()
+                .map(|id| {
+                    let ring = Ring {
+                        records: VecDeque::with_capacity(capacity),
+                        dropped: 0,
+                    };
+                    (id, Mutex::new(ring))
+                })
+                .collect(),
+            capacity,
+            current_cpu: C::current_cpu,
+            now: R::read,
+        }
+    }
+
+    /// Add the record that `make` creates from the current core's ID and the time to the current
+    /// core's ring. Records made on unknown cores are ignored.
+    ///
+    /// If the ring is full, the oldest record is discarded. If the ring is being read, for instance
+    /// because the record is made in an interrupt, the new record is discarded instead.
+    pub fn push(&self, make: impl FnOnce(u32, Ticks) -> T) {
+        let cpu = (self.current_cpu)();
+        let Some(mut ring) = self.rings.get(&cpu).and_then(Mutex::try_lock) else {
+            return;
+        };
+        if ring.records.len() == self.capacity {
+            ring.records.pop_front();
+            ring.dropped += 1;
+        }
+        let record = make(u32::try_from(cpu).unwrap_or(u32::MAX), (self.now)());
+        ring.records.push_back(record);
+    }
+
+    /// The records made on the core `cpu`, oldest first, without removing them.
+    #[must_use]
+    pub fn records(&self, cpu: CpuId) -> Vec<T> {
+        self.rings
+            .get(&cpu)
+            .map(|ring| ring.lock().records.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Remove as many of the records made on the core `cpu` as fit in `buffer`, oldest first,
+    /// encoding each of them with `encode`. Returns the number of bytes written.
+    pub fn read<const N: usize>(
+        &self,
+        cpu: CpuId,
+        buffer: &mut [u8],
+        encode: impl Fn(&T) -> [u8; N],
+    ) -> usize {
+        let Some(ring) = self.rings.get(&cpu) else {
+            return 0;
+        };
+        let mut ring = ring.lock();
+        let mut written = 0;
+        for chunk in buffer.chunks_exact_mut(N) {
+            let Some(record) = ring.records.pop_front() else {
+                break;
+            };
+            chunk.copy_from_slice(&encode(&record));
+            written += N;
+        }
+        written
+    }
+
+    /// The number of old records that have been discarded on the core `cpu` to make room for new
+    /// ones.
+    #[must_use]
+    pub fn dropped(&self, cpu: CpuId) -> u64 {
+        self.rings.get(&cpu).map_or(0, |ring| ring.lock().dropped)
+    }
+}